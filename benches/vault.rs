@@ -0,0 +1,61 @@
+/// Criterion benchmarks of the hot paths, against a local vault and a
+/// remote vault served on localhost. See src/bench.rs.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use monovault::bandwidth::BandwidthMeter;
+use monovault::bench::{self, Fixture};
+use monovault::events::EventBus;
+use monovault::local_vault::LocalVault;
+use monovault::types::*;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::runtime::Builder;
+
+const FILES: u64 = 1000;
+const LARGE_SIZE: u64 = 16 << 20;
+
+fn local_vault(store: &Path) -> LocalVault {
+    LocalVault::new("bench", store, EventBus::new(), MissingDataPolicy::Repair).unwrap()
+}
+
+fn hot_paths(c: &mut Criterion, group: &str, vault: &mut impl Vault, fixture: &Fixture) {
+    let mut group = c.benchmark_group(group);
+    group.bench_function("readdir", |b| {
+        b.iter(|| vault.readdir(fixture.dir).unwrap())
+    });
+    group.bench_function("attr", |b| b.iter(|| vault.attr(fixture.files[0]).unwrap()));
+    let data = vec![0xcd; 4096];
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("small write", |b| {
+        b.iter(|| {
+            let file = fixture.files[0];
+            vault.open(file, OpenMode::RW).unwrap();
+            vault.write(file, 0, &data).unwrap();
+            vault.close(file).unwrap();
+        })
+    });
+    group.throughput(Throughput::Bytes(fixture.large_size));
+    group.sample_size(10);
+    group.bench_function("read", |b| {
+        b.iter(|| bench::read(vault, fixture, GRPC_DATA_CHUNK_SIZE as u64).unwrap())
+    });
+    group.finish();
+}
+
+fn local(c: &mut Criterion) {
+    let store = tempfile::tempdir().unwrap();
+    let mut vault = local_vault(store.path());
+    let fixture = bench::setup(&mut vault, FILES, LARGE_SIZE).unwrap();
+    hot_paths(c, "local", &mut vault, &fixture);
+}
+
+fn remote(c: &mut Criterion) {
+    let store = tempfile::tempdir().unwrap();
+    let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
+    let meter = BandwidthMeter::new(&store.path().join("bandwidth.json")).unwrap();
+    let mut vault = bench::loopback(local_vault(store.path()), runtime, meter).unwrap();
+    let fixture = bench::setup(&mut vault, FILES, LARGE_SIZE).unwrap();
+    hot_paths(c, "remote", &mut vault, &fixture);
+}
+
+criterion_group!(benches, local, remote);
+criterion_main!(benches);
@@ -1,9 +1,13 @@
+use crate::events::{Event, EventBus};
 use crate::local_vault::FdMap;
+use crate::store_health;
 use crate::types::*;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
@@ -13,12 +17,36 @@ pub type BackgroundLog = Arc<Mutex<Vec<BackgroundOp>>>;
 pub struct BackgroundWorker {
     fd_map: Arc<FdMap>,
     remote: VaultRef,
+    /// New operations submitted by the caching vault.
     log: BackgroundLog,
-    pending_log: Vec<BackgroundOp>,
+    /// Operations collected from `log` but not yet performed. Shared
+    /// with the caching vault so it can report them.
+    pending_log: BackgroundLog,
+    /// If true, collect operations but don't perform them.
+    dry_run: Arc<AtomicBool>,
+    /// Where we report uploads, conflicts and connectivity.
+    events: EventBus,
+    /// Whether the last operation failed because the remote is
+    /// unreachable.
+    offline: bool,
     graveyard: PathBuf,
 }
 
+/// Operations waiting to be performed on a remote vault, see
+/// `CachingVault::pending`.
 #[derive(Debug, Clone)]
+pub struct PendingOps {
+    /// Name of the remote vault.
+    pub vault: String,
+    /// Whether the background worker is in dry-run mode.
+    pub dry_run: bool,
+    /// The operations, in the order they will be performed.
+    pub ops: Vec<BackgroundOp>,
+    /// Total size of the files to upload.
+    pub upload_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BackgroundOp {
     /// Delete file.
     Delete(Inode),
@@ -33,18 +61,26 @@ impl BackgroundWorker {
     /// log and performs them. Make sure to use _different_ `remote`
     /// for the background worker and the remote vault used by FUSE!
     /// This way background operation (like uploading large files)
-    /// don't block FUSE operations.
+    /// don't block FUSE operations. While `dry_run` is true, the
+    /// worker keeps collecting operations into `pending_log` without
+    /// performing them.
     pub fn new(
         fd_map: Arc<FdMap>,
         remote: VaultRef,
         log: BackgroundLog,
+        pending_log: BackgroundLog,
+        dry_run: Arc<AtomicBool>,
+        events: EventBus,
         graveyard: &Path,
     ) -> BackgroundWorker {
         BackgroundWorker {
             fd_map,
             remote,
             log,
-            pending_log: vec![],
+            pending_log,
+            dry_run,
+            events,
+            offline: false,
             graveyard: graveyard.to_path_buf(),
         }
     }
@@ -52,29 +88,29 @@ impl BackgroundWorker {
     /// Run the background worker, this never returns.
     pub fn run(&mut self) {
         // In each iteration, we collect new operations, append them
-        // to the log, remove unnecessary ones, and try to perform
-        // each one-by-one. If network error occurs, we save the
-        // unfinished ones, and sleep for the next iteration.
+        // to the pending log, remove unnecessary ones, and try to
+        // perform each one-by-one. An operation stays in the pending
+        // log until it's performed. If network error occurs, we
+        // sleep and continue from there in the next iteration.
         loop {
             thread::sleep(time::Duration::new(3, 0));
-            // We resume from sleep,
-            let mut new_log = {
-                let mut shared_log = self.log.lock().unwrap();
-                let log_copy = shared_log.clone();
-                *shared_log = vec![];
-                log_copy
-            };
-            // Collect new logs.
-            self.pending_log.append(&mut new_log);
-            // Remove unnecessary operations.
-            let log = coalesce_ops(&self.pending_log);
-            self.pending_log = vec![];
+            // We resume from sleep, collect new logs.
+            {
+                let mut new_log = std::mem::take(&mut *self.log.lock().unwrap());
+                let mut pending_log = self.pending_log.lock().unwrap();
+                pending_log.append(&mut new_log);
+                // Remove unnecessary operations.
+                *pending_log = coalesce_ops(&pending_log);
+            }
 
             // Perform each ops.
-            let mut idx = 0;
-            'sleep: while idx < log.len() {
+            while !self.dry_run.load(SeqCst) {
+                let op = match self.pending_log.lock().unwrap().first() {
+                    Some(op) => op.clone(),
+                    None => break,
+                };
                 // Perform the operation
-                let res = match log[idx] {
+                let res = match op {
                     BackgroundOp::Delete(file) => self.handle_delete(file),
                     BackgroundOp::Create(parent, ref name, kind) => {
                         self.handle_create(parent, name, kind)
@@ -85,21 +121,62 @@ impl BackgroundWorker {
                 };
                 // If operation success or fail, move to next, if
                 // connection broke, wait for a while and try again.
-                match res {
-                    Ok(_) => {
-                        idx += 1;
+                let vault = self.remote.lock().unwrap().name();
+                if let Err(VaultError::RpcError(_)) = res {
+                    if !self.offline {
+                        self.offline = true;
+                        self.events.emit(Event::Offline { vault });
                     }
+                } else if self.offline {
+                    self.offline = false;
+                    self.events.emit(Event::Online { vault });
+                }
+                match res {
+                    Ok(_) => (),
                     Err(VaultError::RpcError(_)) => {
                         info!(
                             "Vault {} disconnected, retry in a sec",
                             self.remote.lock().unwrap().name()
                         );
-                        // Add the unfinished ops to pending log, so
-                        // next time when we wake up we continue from
-                        // here.
-                        self.pending_log = log[idx..].to_vec();
-
-                        break 'sleep;
+                        break;
+                    }
+                    // Keep the operations until the config is fixed.
+                    Err(VaultError::PeerMismatch(_, _)) => break,
+                    // The peer is out of space or busy, try again
+                    // later rather than dropping the operation.
+                    Err(VaultError::RemoteFailure(category, ref msg)) if category.retryable() => {
+                        info!(
+                            "Vault {} failed ({}), retry in a sec",
+                            self.remote.lock().unwrap().name(),
+                            msg
+                        );
+                        break;
+                    }
+                    // The upload was corrupted on the way, send it
+                    // again next time.
+                    Err(VaultError::ChunkMismatch(_, _)) => break,
+                    // The remote was sealed or made read-only, keep
+                    // the operations in pending rather than lose
+                    // them.
+                    Err(VaultError::ReadOnly(_)) => {
+                        info!(
+                            "Vault {} is read-only, keeping pending operations",
+                            self.remote.lock().unwrap().name()
+                        );
+                        break;
+                    }
+                    // Our store is failing, keep the operation until
+                    // it's back.
+                    Err(ref err)
+                        if matches!(err, VaultError::StoreUnwritable(_))
+                            || store_health::unwritable(err) =>
+                    {
+                        info!(
+                            "Store failed ({:?}), retry upload to {} in a sec",
+                            err,
+                            self.remote.lock().unwrap().name()
+                        );
+                        break;
                     }
                     Err(err) => {
                         error!(
@@ -107,9 +184,9 @@ impl BackgroundWorker {
                             self.remote.lock().unwrap().name(),
                             err
                         );
-                        idx += 1
                     }
                 };
+                self.pending_log.lock().unwrap().remove(0);
             }
         }
     }
@@ -129,32 +206,85 @@ impl BackgroundWorker {
     }
 
     fn handle_upload(&mut self, file: Inode, name: &str, version: FileVersion) -> VaultResult<()> {
-        let vault_name = self.remote.lock().unwrap().name();
-        info!("handle_upload({}) to {}", file, &vault_name);
-        let graveyard_file_path = self.graveyard.join(format!(
-            "vault({})name({})inode({})",
-            vault_name, name, file
-        ));
-        // At this point the read copy has the latest content, because
-        // when closing the file we copied the write copy to the read
-        // copy. (See `FdMap::close`.)
-        let from_path = self.fd_map.compose_path(file, false);
-        std::fs::copy(&from_path, &graveyard_file_path)?;
-        debug!("copy to {}", graveyard_file_path.to_string_lossy());
-        // FIXME: read by chunk.
-        let mut buf = vec![];
-        let mut fd = File::open(&graveyard_file_path)?;
-        debug!(
-            "file size: {}",
-            std::fs::metadata(&graveyard_file_path)?.len()
-        );
-        fd.read_to_end(&mut buf)?;
-        let mut remote = self.remote.lock().unwrap();
-        unpack_to_remote(&mut remote)?.submit(file, &buf, version)?;
-        Ok(())
+        upload(
+            &self.fd_map,
+            &self.remote,
+            &self.events,
+            &self.graveyard,
+            file,
+            name,
+            version,
+        )
     }
 }
 
+/// Upload our copy of `file` to `remote` as `version`, and report to
+/// `events` whether the remote accepted it. We send a copy made in
+/// `graveyard`, named after `name`, so writes while we upload don't
+/// mix in. If the store doesn't take writes, there are no writes to
+/// mix in and we send our copy itself.
+pub fn upload(
+    fd_map: &FdMap,
+    remote: &VaultRef,
+    events: &EventBus,
+    graveyard: &Path,
+    file: Inode,
+    name: &str,
+    version: FileVersion,
+) -> VaultResult<()> {
+    let vault_name = remote.lock().unwrap().name();
+    info!("upload({}) to {}", file, &vault_name);
+    let graveyard_file_path = graveyard.join(format!(
+        "vault({})name({})inode({})",
+        vault_name, name, file
+    ));
+    // At this point the read copy has the latest content, because
+    // when closing the file we copied the write copy to the read
+    // copy. (See `FdMap::close`.)
+    let from_path = fd_map.compose_path(file, false);
+    // The file keeps its modification time on the remote: when it
+    // was closed, or what `set_times` gave it since, see
+    // `CachingVault::set_times`.
+    let mtime = std::fs::metadata(&from_path)?
+        .modified()?
+        .duration_since(time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let send_path = match std::fs::copy(&from_path, &graveyard_file_path) {
+        Ok(_) => {
+            debug!("copy to {}", graveyard_file_path.to_string_lossy());
+            graveyard_file_path
+        }
+        Err(err) if store_health::unwritable_io(&err) => {
+            warn!("upload({}) => can't copy to the graveyard: {}", file, err);
+            from_path
+        }
+        Err(err) => return Err(err.into()),
+    };
+    // FIXME: read by chunk.
+    let mut buf = vec![];
+    let mut fd = File::open(&send_path)?;
+    debug!("file size: {}", std::fs::metadata(&send_path)?.len());
+    fd.read_to_end(&mut buf)?;
+    let mut remote = remote.lock().unwrap();
+    let accepted = unpack_to_remote(&mut remote)?.submit(file, &buf, version, mtime)?;
+    let vault = vault_name;
+    events.emit(if accepted {
+        Event::Synced {
+            vault,
+            file,
+            version,
+        }
+    } else {
+        Event::Conflict {
+            vault,
+            file,
+            version,
+        }
+    });
+    Ok(())
+}
+
 /// Remote unnecessary operations in `ops`. For example, the write in
 /// [write(A), delete(A)] can be removed.
 fn coalesce_ops(ops: &[BackgroundOp]) -> Vec<BackgroundOp> {
@@ -0,0 +1,119 @@
+/// Count the bytes of the requests we make to each peer, and keep the
+/// counts across restarts. Requests peers make to us aren't counted.
+/// Also limit the traffic with a peer, see `Throttle`.
+use crate::types::*;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Traffic a throttle lets through without waiting after the link
+/// was idle, in seconds at the limit. It also makes up for the time
+/// a transfer itself took, which the throttle only learns about
+/// afterwards.
+const THROTTLE_BURST: Duration = Duration::from_secs(1);
+
+/// Cumulative traffic with a peer, in bytes of RPC payload.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Traffic {
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// Shared by all the remote vaults. Cloning a meter gives a handle to
+/// the same counters.
+#[derive(Debug, Clone)]
+pub struct BandwidthMeter {
+    /// Maps peer (vault) name to its traffic.
+    counters: Arc<Mutex<HashMap<VaultName, Traffic>>>,
+    /// The file we save counters to.
+    path: PathBuf,
+}
+
+impl BandwidthMeter {
+    /// Return a meter that saves counters to `path`, starting from
+    /// the counters already saved there, if any.
+    pub fn new(path: &Path) -> VaultResult<BandwidthMeter> {
+        Ok(BandwidthMeter {
+            counters: Arc::new(Mutex::new(BandwidthMeter::load(path)?)),
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Return the counters saved in `path`, or nothing if `path`
+    /// doesn't exist.
+    pub fn load(path: &Path) -> VaultResult<HashMap<VaultName, Traffic>> {
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content).map_err(std::io::Error::from)?)
+    }
+
+    /// Add `sent` and `received` bytes to `peer`'s counters.
+    pub fn record(&self, peer: &str, sent: u64, received: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        let traffic = counters.entry(peer.to_string()).or_default();
+        traffic.sent = traffic.sent.saturating_add(sent);
+        traffic.received = traffic.received.saturating_add(received);
+    }
+
+    /// Return the current counters.
+    pub fn snapshot(&self) -> HashMap<VaultName, Traffic> {
+        self.counters.lock().unwrap().clone()
+    }
+
+    /// Save the counters. We write to a temporary file and rename it
+    /// over, so a crash doesn't leave a half-written file.
+    pub fn save(&self) -> VaultResult<()> {
+        let content =
+            serde_json::to_string_pretty(&self.snapshot()).map_err(std::io::Error::from)?;
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, content)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        debug!("saved bandwidth counters to {:?}", &self.path);
+        Ok(())
+    }
+}
+
+/// Keeps the average traffic with a peer under a limit, by making
+/// whoever goes over it wait.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    /// When the traffic so far would be through at the limit.
+    ready_at: Mutex<Instant>,
+}
+
+impl Throttle {
+    /// Return a throttle letting through `bytes_per_sec` bytes per
+    /// second, which must be positive. It starts out idle, with a
+    /// burst saved up.
+    pub fn new(bytes_per_sec: u64) -> Throttle {
+        let now = Instant::now();
+        Throttle {
+            bytes_per_sec,
+            ready_at: Mutex::new(now.checked_sub(THROTTLE_BURST).unwrap_or(now)),
+        }
+    }
+
+    /// Count `bytes` of traffic, and sleep until it fits the limit.
+    pub fn pass(&self, bytes: u64) {
+        let now = Instant::now();
+        let wait = {
+            let mut ready_at = self.ready_at.lock().unwrap();
+            // Idle time saves up at most a burst.
+            let idle_start = now.checked_sub(THROTTLE_BURST).unwrap_or(now);
+            let start = std::cmp::max(*ready_at, idle_start);
+            *ready_at = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            ready_at.saturating_duration_since(now)
+        };
+        if !wait.is_zero() {
+            debug!("Throttled for {:?}", wait);
+            thread::sleep(wait);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+/// Keep misbehaving peers away from the vault server: allow and deny
+/// lists of peer addresses, and temporary bans for peers that send
+/// too many malformed requests, or too many requests too fast.
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+fn default_max_strikes() -> u32 {
+    10
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+fn default_ban_secs() -> u64 {
+    600
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BanConfig {
+    /// If not empty, only peers with these addresses are served.
+    #[serde(default)]
+    pub allow: Vec<IpAddr>,
+    /// Peers with these addresses are never served.
+    #[serde(default)]
+    pub deny: Vec<IpAddr>,
+    /// Ban a peer that sends this many malformed requests within
+    /// `window_secs`. 0 means never.
+    #[serde(default = "default_max_strikes")]
+    pub max_strikes: u32,
+    /// Ban a peer that sends more than this many requests within
+    /// `window_secs`. 0 means no limit.
+    #[serde(default)]
+    pub max_requests: u32,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// How long a ban lasts.
+    #[serde(default = "default_ban_secs")]
+    pub ban_secs: u64,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        BanConfig {
+            allow: vec![],
+            deny: vec![],
+            max_strikes: default_max_strikes(),
+            max_requests: 0,
+            window_secs: default_window_secs(),
+            ban_secs: default_ban_secs(),
+        }
+    }
+}
+
+/// A banned peer.
+#[derive(Debug, Clone)]
+pub struct Ban {
+    pub addr: IpAddr,
+    pub reason: String,
+    /// Seconds until the ban is lifted.
+    pub remaining_secs: u64,
+}
+
+/// What a peer did in the current window.
+struct Record {
+    window_start: time::Instant,
+    requests: u32,
+    strikes: u32,
+}
+
+#[derive(Default)]
+struct GuardState {
+    records: HashMap<IpAddr, Record>,
+    /// Maps address to (end of ban, reason).
+    bans: HashMap<IpAddr, (time::Instant, String)>,
+    /// When we last dropped records and bans that are over, see
+    /// `PeerGuard::prune`.
+    pruned: Option<time::Instant>,
+}
+
+/// Return `addr` with IPv4 addresses mapped into IPv6 (::ffff:a.b.c.d,
+/// what dual-stack listeners see) as plain IPv4, so a peer has one
+/// address whichever way it connects.
+pub fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Decides whether to serve a peer. Clones share state, so the
+/// interceptor and the server see the same bans.
+#[derive(Clone)]
+pub struct PeerGuard {
+    config: Arc<BanConfig>,
+    state: Arc<Mutex<GuardState>>,
+}
+
+impl PeerGuard {
+    pub fn new(mut config: BanConfig) -> PeerGuard {
+        for list in [&mut config.allow, &mut config.deny] {
+            for addr in list.iter_mut() {
+                *addr = canonical(*addr);
+            }
+        }
+        PeerGuard {
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(GuardState::default())),
+        }
+    }
+
+    /// Called on each request from `addr`. Return the reason if we
+    /// shouldn't serve it.
+    pub fn admit(&self, addr: IpAddr) -> Result<(), String> {
+        self.admit_1(addr, false)
+    }
+
+    /// Like `admit`, for requests that redeem a share link. Those are
+    /// for people who aren't peers, so the allow list doesn't apply;
+    /// the deny list and bans do.
+    pub fn admit_share(&self, addr: IpAddr) -> Result<(), String> {
+        self.admit_1(addr, true)
+    }
+
+    fn admit_1(&self, addr: IpAddr, share: bool) -> Result<(), String> {
+        let addr = canonical(addr);
+        if self.config.deny.contains(&addr) {
+            return Err(format!("{} is denied", addr));
+        }
+        if !share && !self.config.allow.is_empty() && !self.config.allow.contains(&addr) {
+            return Err(format!("{} is not allowed", addr));
+        }
+        let now = time::Instant::now();
+        let mut state = self.state.lock().unwrap();
+        self.prune(&mut state, now);
+        if let Some((until, reason)) = state.bans.get(&addr) {
+            if *until > now {
+                return Err(format!("{} is banned: {}", addr, reason));
+            }
+            state.bans.remove(&addr);
+        }
+        let record = self.record(&mut state, addr, now);
+        record.requests += 1;
+        if self.config.max_requests > 0 && record.requests > self.config.max_requests {
+            let reason = format!(
+                "more than {} requests in {} seconds",
+                self.config.max_requests, self.config.window_secs
+            );
+            self.ban(&mut state, addr, reason.clone(), now);
+            return Err(format!("{} is banned: {}", addr, reason));
+        }
+        Ok(())
+    }
+
+    /// Record a malformed request from `addr`, and ban it if it sent
+    /// too many.
+    pub fn strike(&self, addr: IpAddr, what: &str) {
+        let addr = canonical(addr);
+        warn!("Malformed request from {}: {}", addr, what);
+        let now = time::Instant::now();
+        let mut state = self.state.lock().unwrap();
+        self.prune(&mut state, now);
+        let record = self.record(&mut state, addr, now);
+        record.strikes += 1;
+        if self.config.max_strikes > 0 && record.strikes >= self.config.max_strikes {
+            let reason = format!(
+                "{} malformed requests in {} seconds, last one: {}",
+                self.config.max_strikes, self.config.window_secs, what
+            );
+            self.ban(&mut state, addr, reason, now);
+        }
+    }
+
+    /// Return the bans in effect.
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = time::Instant::now();
+        let state = self.state.lock().unwrap();
+        let mut bans: Vec<Ban> = state
+            .bans
+            .iter()
+            .filter(|(_, (until, _))| *until > now)
+            .map(|(addr, (until, reason))| Ban {
+                addr: *addr,
+                reason: reason.clone(),
+                remaining_secs: until.duration_since(now).as_secs(),
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.addr);
+        bans
+    }
+
+    /// Lift the ban on `addr`, or all bans if None, and forget what
+    /// they did. Return the number of bans lifted.
+    pub fn clear(&self, addr: Option<IpAddr>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        match addr.map(canonical) {
+            Some(addr) => {
+                state.records.remove(&addr);
+                state.bans.remove(&addr).map_or(0, |_| 1)
+            }
+            None => {
+                state.records.clear();
+                state.bans.drain().count()
+            }
+        }
+    }
+
+    /// Return the number of addresses we keep a record or a ban of.
+    pub fn tracked(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .records
+            .keys()
+            .chain(
+                state
+                    .bans
+                    .keys()
+                    .filter(|addr| !state.records.contains_key(addr)),
+            )
+            .count()
+    }
+
+    /// Drop the records whose window is over and the bans that ended,
+    /// so peers that come and go don't pile up. We prune at most once
+    /// a window, so a record outlives its window by at most another.
+    fn prune(&self, state: &mut GuardState, now: time::Instant) {
+        let window = time::Duration::from_secs(self.config.window_secs);
+        if matches!(state.pruned, Some(pruned) if now.duration_since(pruned) < window) {
+            return;
+        }
+        state
+            .records
+            .retain(|_, record| now.duration_since(record.window_start) < window);
+        state.bans.retain(|_, (until, _)| *until > now);
+        state.pruned = Some(now);
+    }
+
+    /// Return the record of `addr`, starting a new window if the
+    /// current one is over.
+    fn record<'a>(
+        &self,
+        state: &'a mut GuardState,
+        addr: IpAddr,
+        now: time::Instant,
+    ) -> &'a mut Record {
+        let window = time::Duration::from_secs(self.config.window_secs);
+        let record = state.records.entry(addr).or_insert(Record {
+            window_start: now,
+            requests: 0,
+            strikes: 0,
+        });
+        if now.duration_since(record.window_start) >= window {
+            record.window_start = now;
+            record.requests = 0;
+            record.strikes = 0;
+        }
+        record
+    }
+
+    fn ban(&self, state: &mut GuardState, addr: IpAddr, reason: String, now: time::Instant) {
+        // Never lock out the host itself, admin commands come from
+        // there.
+        if addr.is_loopback() {
+            return;
+        }
+        warn!(
+            "Banning {} for {} seconds: {}",
+            addr, self.config.ban_secs, reason
+        );
+        let until = now + time::Duration::from_secs(self.config.ban_secs);
+        state.bans.insert(addr, (until, reason));
+        state.records.remove(&addr);
+    }
+}
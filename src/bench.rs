@@ -0,0 +1,231 @@
+/// Measure the hot paths of a vault: metadata operations per second
+/// and streaming throughput. Used by the "bench" subcommand and by
+/// the criterion benchmarks in benches/vault.rs.
+use crate::bandwidth::BandwidthMeter;
+use crate::bans::{BanConfig, PeerGuard};
+use crate::connections::ConnectionLog;
+use crate::local_vault::LocalVault;
+use crate::remote_vault::RemoteVault;
+use crate::types::*;
+use crate::vault_server::run_server;
+use log::info;
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+use tokio::runtime::Runtime;
+
+/// Name of the directory we create in the vault root to work in.
+pub const BENCH_DIR: &str = "monovault-bench";
+
+/// The result of one benchmark.
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub name: &'static str,
+    /// Number of operations performed.
+    pub ops: u64,
+    /// Number of bytes read or written.
+    pub bytes: u64,
+    pub elapsed: time::Duration,
+}
+
+impl BenchResult {
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Throughput in MiB per second.
+    pub fn mib_per_sec(&self) -> f64 {
+        self.bytes as f64 / (1 << 20) as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// What `setup` created.
+#[derive(Debug, Clone)]
+pub struct Fixture {
+    /// The directory holding everything below.
+    pub dir: Inode,
+    /// Empty files, for metadata operations.
+    pub files: Vec<Inode>,
+    /// A large file, for streaming.
+    pub large: Inode,
+    pub large_size: u64,
+}
+
+/// Create BENCH_DIR in the root of `vault` with `count` empty files
+/// and one file of `large_size` bytes. Remove what a previous run
+/// left first.
+pub fn setup(vault: &mut impl Vault, count: u64, large_size: u64) -> VaultResult<Fixture> {
+    info!("bench setup(count={}, large_size={})", count, large_size);
+    if let Some(old) = vault
+        .readdir(1)?
+        .into_iter()
+        .find(|info| info.name == BENCH_DIR)
+    {
+        vault.delete_tree(old.inode, MAX_TREE_DELETE)?;
+    }
+    let dir = vault.create(1, BENCH_DIR, VaultFileType::Directory)?;
+    let mut files = vec![];
+    for idx in 0..count {
+        let file = vault.create(dir, &format!("file-{}", idx), VaultFileType::File)?;
+        // Create also opens the file.
+        vault.close(file)?;
+        files.push(file);
+    }
+    let large = vault.create(dir, "large", VaultFileType::File)?;
+    let chunk = vec![0xab; GRPC_DATA_CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < large_size {
+        let len = std::cmp::min(chunk.len() as u64, large_size - offset);
+        vault.write(large, offset as i64, &chunk[..len as usize])?;
+        offset += len;
+    }
+    vault.close(large)?;
+    Ok(Fixture {
+        dir,
+        files,
+        large,
+        large_size,
+    })
+}
+
+/// Remove what `setup` created.
+pub fn teardown(vault: &mut impl Vault, fixture: &Fixture) -> VaultResult<()> {
+    vault.delete_tree(fixture.dir, MAX_TREE_DELETE)?;
+    Ok(())
+}
+
+/// List the fixture directory `rounds` times.
+pub fn readdir(vault: &mut impl Vault, fixture: &Fixture, rounds: u64) -> VaultResult<BenchResult> {
+    let start = time::Instant::now();
+    for _ in 0..rounds {
+        vault.readdir(fixture.dir)?;
+    }
+    Ok(BenchResult {
+        name: "readdir",
+        ops: rounds,
+        bytes: 0,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Get the attributes of each file in the fixture.
+pub fn attr(vault: &mut impl Vault, fixture: &Fixture) -> VaultResult<BenchResult> {
+    let start = time::Instant::now();
+    for &file in fixture.files.iter() {
+        vault.attr(file)?;
+    }
+    Ok(BenchResult {
+        name: "attr",
+        ops: fixture.files.len() as u64,
+        bytes: 0,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Open each file in the fixture, write `size` bytes and close it,
+/// like saving many small files.
+pub fn small_writes(
+    vault: &mut impl Vault,
+    fixture: &Fixture,
+    size: usize,
+) -> VaultResult<BenchResult> {
+    let data = vec![0xcd; size];
+    let start = time::Instant::now();
+    for &file in fixture.files.iter() {
+        vault.open(file, OpenMode::RW)?;
+        vault.write(file, 0, &data)?;
+        vault.close(file)?;
+    }
+    Ok(BenchResult {
+        name: "small writes",
+        ops: fixture.files.len() as u64,
+        bytes: (fixture.files.len() * size) as u64,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Read the large file from start to end in reads of `chunk` bytes.
+pub fn read(vault: &mut impl Vault, fixture: &Fixture, chunk: u64) -> VaultResult<BenchResult> {
+    let start = time::Instant::now();
+    vault.open(fixture.large, OpenMode::R)?;
+    let mut offset = 0;
+    let mut ops = 0;
+    while offset < fixture.large_size {
+        let data = vault.read(fixture.large, offset as i64, chunk)?;
+        if data.is_empty() {
+            break;
+        }
+        offset += data.len() as u64;
+        ops += 1;
+    }
+    vault.close(fixture.large)?;
+    Ok(BenchResult {
+        name: "read",
+        ops,
+        bytes: offset,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Run all the benchmarks on `vault` with `count` small files and a
+/// large file of `large_size` bytes, and clean up afterwards.
+pub fn run_all(
+    vault: &mut impl Vault,
+    count: u64,
+    large_size: u64,
+) -> VaultResult<Vec<BenchResult>> {
+    let fixture = setup(vault, count, large_size)?;
+    let results = vec![
+        readdir(vault, &fixture, 100)?,
+        attr(vault, &fixture)?,
+        small_writes(vault, &fixture, 4096)?,
+        read(vault, &fixture, GRPC_DATA_CHUNK_SIZE as u64)?,
+    ];
+    teardown(vault, &fixture)?;
+    Ok(results)
+}
+
+/// Serve `vault` on a free port on localhost, and return a remote
+/// vault talking to it, so benchmarks include the RPC overhead.
+pub fn loopback(
+    vault: LocalVault,
+    runtime: Arc<Runtime>,
+    meter: BandwidthMeter,
+) -> VaultResult<RemoteVault> {
+    let name = vault.name();
+    let address = TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string();
+    let mut vault_map = HashMap::new();
+    vault_map.insert(
+        name.clone(),
+        Arc::new(Mutex::new(GenericVault::Local(vault))),
+    );
+    {
+        let address = address.clone();
+        let name = name.clone();
+        let runtime = Arc::clone(&runtime);
+        let _ = thread::spawn(move || {
+            run_server(
+                &address,
+                &name,
+                vault_map,
+                runtime,
+                PeerGuard::new(BanConfig::default()),
+                None,
+                ConnectionLog::new(),
+            )
+        });
+    }
+    let deadline = time::Instant::now() + time::Duration::from_secs(10);
+    while TcpStream::connect(&address).is_err() {
+        if time::Instant::now() > deadline {
+            return Err(VaultError::RpcError(format!(
+                "bench server at {} didn't start",
+                address
+            )));
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    }
+    RemoteVault::new(&format!("http://{}", address), &name, runtime, meter)
+}
@@ -0,0 +1,68 @@
+/// Decide how a caching vault caches a file, by the file's name. Some
+/// files are a bad fit for caching whole: VM images and databases are
+/// large and constantly rewritten, so fetching and uploading the whole
+/// file on every open and close keeps the cache (and the network)
+/// busy for nothing.
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CachePolicy {
+    /// Fetch the whole file on open, write to our copy and upload it
+    /// in the background after the last close. Works offline.
+    #[default]
+    Whole,
+    /// Fetch only the parts of the file that are read, and keep them
+    /// until the file changes on the remote. Writes go straight to
+    /// the remote. Doesn't work offline.
+    Chunked,
+    /// Don't cache, every read and write goes to the remote. Doesn't
+    /// work offline.
+    Passthrough,
+}
+
+/// Files whose name matches `pattern` are cached with `policy`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheRule {
+    /// A glob pattern matched against the file name (not the path):
+    /// "*" matches any run of characters and "?" any one character.
+    pub pattern: String,
+    pub policy: CachePolicy,
+}
+
+/// Return true if `name` matches glob `pattern`.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where to resume after the last "*": (pattern index after the
+    // star, name index the star matched up to).
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the star eat one more character.
+            p = star_p;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Return the policy of the first rule in `rules` matching `name`,
+/// or `CachePolicy::Whole` if none does.
+pub fn policy_for(rules: &[CacheRule], name: &str) -> CachePolicy {
+    rules
+        .iter()
+        .find(|rule| glob_match(&rule.pattern, name))
+        .map(|rule| rule.policy)
+        .unwrap_or_default()
+}
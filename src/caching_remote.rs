@@ -1,31 +1,91 @@
-use crate::background_worker::{BackgroundLog, BackgroundOp, BackgroundWorker};
-use crate::database::Database;
+use crate::background_worker::{self, BackgroundLog, BackgroundOp, BackgroundWorker, PendingOps};
+use crate::cache_policy::{policy_for, CachePolicy, CacheRule};
+use crate::database::{Database, Intent};
+use crate::download::{self, Downloads};
+use crate::events::{Event, EventBus};
 use crate::local_vault;
 /// The caching vault first replicates data locally and send read/write
 /// request to remote vault in the background.
-use crate::local_vault::{FdMap, LocalVault, RefCounter};
+use crate::local_vault::{FdMap, RefCounter};
+use crate::locks::{FileLock, LockKind, LockTable};
+use crate::manifest::Manifest;
+use crate::seal::{sealed_stats, SealState};
+use crate::store_health::StoreHealth;
 use crate::types::*;
-use log::{debug, info};
-use std::collections::HashMap;
-use std::path::Path;
+use crate::version::VersionTracker;
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
 use std::sync::{Arc, Mutex};
 use std::{thread, time};
 
+/// Chunked files are fetched in chunks of this size.
+const CACHE_CHUNK_SIZE: u64 = GRPC_DATA_CHUNK_SIZE as u64;
+
 pub struct CachingVault {
     /// Name of this vault, should be the same as the remote vault.
     name: String,
     ref_count: RefCounter,
-    mod_track: RefCounter,
-    fork_track: RefCounter,
+    versions: VersionTracker,
     database: Database,
     fd_map: Arc<FdMap>,
     /// The remote vault we are using.
     remote_map: HashMap<String, VaultRef>,
     log: BackgroundLog,
+    /// Operations the background worker collected but not yet
+    /// performed.
+    pending_log: BackgroundLog,
+    /// If true, the background worker doesn't perform operations.
+    dry_run: Arc<AtomicBool>,
     /// Whether allow disconnected delete.
     allow_disconnected_delete: bool,
     /// Whether to allow disconnected create.
     allow_disconnected_create: bool,
+    /// Evict cached content not accessed in this many days, 0 means
+    /// never.
+    max_age_days: u64,
+    /// Where we report creations, modifications and deletions.
+    events: EventBus,
+    /// Maps file to the newest version of it we reported outdated,
+    /// see `saw_version`.
+    outdated: HashMap<Inode, FileVersion>,
+    /// Decide how each file is cached, by its name.
+    cache_rules: Vec<CacheRule>,
+    /// Open files that aren't cached whole, and their policy. The
+    /// policy is decided on the first open and kept until the last
+    /// close.
+    open_policies: HashMap<Inode, CachePolicy>,
+    /// For each chunked file, the indices of the chunks in our copy.
+    chunks: HashMap<Inode, HashSet<u64>>,
+    /// Files whose content we are downloading, see download.rs.
+    downloads: Downloads,
+    /// Files cached whole that are only open for reading and weren't
+    /// read yet, so we haven't fetched their content, see `open`.
+    deferred: HashSet<Inode>,
+    /// Where `save_pending` saves operations not yet performed.
+    pending_path: PathBuf,
+    /// Where uploads are copied to before sending, see
+    /// `background_worker::upload`.
+    graveyard: PathBuf,
+    /// If true, refuse changes, see `set_read_only`.
+    read_only: bool,
+    /// Whether the remote sealed its vault, shared with the remote
+    /// vault, which learns it when it connects, see src/seal.rs.
+    seal_state: SealState,
+    /// Whether our database records the seal, so we go on refusing
+    /// changes after a restart while the remote is unreachable.
+    seal_recorded: bool,
+    /// Whether the store takes writes, see `set_store_health`.
+    health: StoreHealth,
+    /// Peers whose caches we download from before the remote, see
+    /// `set_near_peers`.
+    near_peers: Vec<VaultName>,
+    /// Locks taken while the remote is unreachable, they only keep
+    /// programs on this host apart.
+    locks: LockTable,
 }
 
 /*** CachingVault methods */
@@ -37,20 +97,39 @@ impl CachingVault {
     /// `remote_name` is the name of the vault this caching remote
     /// represents. `store_path` is the path to where we store
     /// database and data files. `remote_map` should contain all
-    /// the remotes.
+    /// the remotes. Cached content not accessed in `max_age_days`
+    /// days is evicted by `evict_stale`. If `dry_run` is true, the
+    /// background worker starts paused, see `set_dry_run`. Changes
+    /// are reported to `events`. `missing_data` decides what to do
+    /// with cached files whose data file is lost. `cache_rules`
+    /// decide which files aren't cached whole.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         remote_name: &str,
         remote_map: HashMap<String, VaultRef>,
         store_path: &Path,
         allow_disconnected_delete: bool,
         allow_disconnected_create: bool,
+        max_age_days: u64,
+        dry_run: bool,
+        events: EventBus,
+        missing_data: MissingDataPolicy,
+        cache_rules: Vec<CacheRule>,
     ) -> VaultResult<CachingVault> {
         // Produce arguments for the background worker.
         let graveyard = store_path.join("graveyard");
         if !graveyard.exists() {
             std::fs::create_dir(&graveyard)?
         }
-        let log = Arc::new(Mutex::new(vec![]));
+        let db_dir = store_path.join("db");
+        if !db_dir.exists() {
+            std::fs::create_dir(&db_dir)?
+        }
+        // Pick up operations saved when we last stopped.
+        let pending_path = db_dir.join(format!("{}-pending.json", remote_name));
+        let log = Arc::new(Mutex::new(load_pending(&pending_path)?));
+        let pending_log = Arc::new(Mutex::new(vec![]));
+        let dry_run = Arc::new(AtomicBool::new(dry_run));
         let our_remote = remote_map
             .get(remote_name)
             .ok_or(VaultError::CannotFindVaultByName(remote_name.to_string()))?;
@@ -58,41 +137,313 @@ impl CachingVault {
         if !data_file_dir.exists() {
             std::fs::create_dir(&data_file_dir)?
         }
-        let fd_map = Arc::new(FdMap::new(remote_name, &data_file_dir));
+        let fd_map = Arc::new(FdMap::new(remote_name, &data_file_dir, true, missing_data));
         let mut background_worker = BackgroundWorker::new(
             Arc::clone(&fd_map),
             Arc::clone(our_remote),
             Arc::clone(&log),
+            Arc::clone(&pending_log),
+            Arc::clone(&dry_run),
+            events.clone(),
             &graveyard,
         );
         let _handler = thread::spawn(move || background_worker.run());
-        // Create CachingVault.
-
-        let db_dir = store_path.join("db");
-        if !db_dir.exists() {
-            std::fs::create_dir(&db_dir)?
+        let mut database = Database::new(&db_dir, remote_name)?;
+        // Files synced but left open when we stopped, upload them
+        // like their last close would have.
+        for (file, intent) in database.journaled()? {
+            if intent != Intent::Synced {
+                continue;
+            }
+            if let Some(version) = local_vault::recover_synced(file, &mut database, &fd_map)? {
+                let name = database.attr(file)?.name;
+                log.lock()
+                    .unwrap()
+                    .push(BackgroundOp::Upload(file, name, version));
+            }
+            database.settle(file)?;
+        }
+        if database.usage_stale() {
+            // Our copies can be partial, the sizes we recorded are
+            // those of the remote.
+            database.rebuild_usage(|_, recorded| recorded)?;
+        }
+        let seal_state = match &*our_remote.lock().unwrap() {
+            GenericVault::Remote(remote) => remote.seal_state(),
+            _ => SealState::new(),
+        };
+        let sealed = database.sealed()?;
+        if let Some(time) = sealed {
+            seal_state.seal(time);
         }
+        // Create CachingVault.
         Ok(CachingVault {
             name: remote_name.to_string(),
             ref_count: RefCounter::new(),
-            mod_track: RefCounter::new(),
-            fork_track: RefCounter::new(),
+            versions: VersionTracker::new(),
             fd_map,
-            database: Database::new(&db_dir, remote_name)?,
+            database,
             remote_map,
             log,
+            pending_log,
+            dry_run,
             allow_disconnected_delete,
             allow_disconnected_create,
+            max_age_days,
+            events,
+            cache_rules,
+            open_policies: HashMap::new(),
+            chunks: HashMap::new(),
+            downloads: HashMap::new(),
+            deferred: HashSet::new(),
+            pending_path,
+            graveyard,
+            read_only: false,
+            seal_state,
+            seal_recorded: sealed.is_some(),
+            health: StoreHealth::new(store_path),
+            near_peers: vec![],
+            locks: LockTable::new(),
+            outdated: HashMap::new(),
         })
     }
 
+    /// Record `info`, an entry the remote listed in `dir`, in the
+    /// cache: add it if it's new, follow it if it was renamed or
+    /// linked. Return true if it's new.
+    fn merge_entry(&mut self, dir: Inode, info: FileInfo) -> VaultResult<bool> {
+        if !local_vault::has_file(info.inode, &mut self.database)? {
+            // Create an empty file.
+            if let VaultFileType::File = info.kind {
+                self.fd_map.get(info.inode, false)?;
+            }
+            // Set version to 0 so file is fetched on open.
+            self.database.add_file(
+                dir,
+                info.inode,
+                &info.name,
+                info.kind,
+                info.atime,
+                info.mtime,
+                (0, 0),
+                info.mode,
+            )?;
+            if let Some((uid, gid)) = info.owner {
+                self.database.set_owner(info.inode, uid, gid)?;
+            }
+            self.database.set_generation(info.inode, info.generation)?;
+            return Ok(true);
+        }
+        self.saw_version(info.inode, info.version)?;
+        let known = self.database.attr(info.inode)?;
+        // Files we created were recorded before the remote told us
+        // their generation.
+        if known.generation != info.generation {
+            self.database.set_generation(info.inode, info.generation)?;
+        }
+        let old_parent = self.database.parent(info.inode)?;
+        let old_name = known.name;
+        if old_parent != Some(dir) || old_name != info.name {
+            let linked = self
+                .database
+                .links_in(dir)?
+                .contains(&(info.inode, info.name.clone()));
+            if info.nlink > 1 {
+                // Another name of a file we know.
+                if !linked {
+                    self.database.add_link(info.inode, dir, &info.name)?;
+                }
+            } else {
+                // Renamed on the remote since we cached it, or its
+                // other names are gone.
+                if linked {
+                    self.database.remove_name(info.inode, dir, &info.name)?;
+                }
+                self.database.move_file(info.inode, dir, &info.name)?;
+                if let Some(old_parent) = old_parent {
+                    self.vanished(info.inode, old_parent, old_name);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Note that the remote has `version` of `file`. If it's newer
+    /// than ours and we didn't report it yet, report our copy
+    /// outdated, so the kernel drops what it cached of the file.
+    fn saw_version(&mut self, file: Inode, version: FileVersion) -> VaultResult<()> {
+        let ours = self.database.attr(file)?.version;
+        if ours.0 >= version.0 || self.outdated.get(&file) == Some(&version) {
+            return Ok(());
+        }
+        self.outdated.insert(file, version);
+        self.events.emit(Event::Outdated {
+            vault: self.name(),
+            file,
+            version,
+        });
+        Ok(())
+    }
+
+    /// Report that `name` in `parent`, a name of `file`, is gone from
+    /// the remote.
+    fn vanished(&self, file: Inode, parent: Inode, name: String) {
+        self.events.emit(Event::Vanished {
+            vault: self.name(),
+            file,
+            parent,
+            name,
+        });
+    }
+
     fn main(&self) -> VaultRef {
         Arc::clone(self.remote_map.get(&self.name).unwrap())
     }
 
-    /// Mark `file` as forked, so next change will bump major version.
-    fn mark_forked(&mut self, file: Inode) {
-        self.fork_track.incf(file);
+    /// Return how `file` should be cached.
+    fn policy_of(&self, file: Inode) -> VaultResult<CachePolicy> {
+        if self.cache_rules.is_empty() {
+            return Ok(CachePolicy::Whole);
+        }
+        Ok(policy_for(
+            &self.cache_rules,
+            &self.database.attr(file)?.name,
+        ))
+    }
+
+    /// First open of `file` that isn't cached whole: open it on the
+    /// remote, where reads and writes go. For a chunked file, drop
+    /// the chunks we have if the remote has another version.
+    fn open_direct(&mut self, file: Inode, mode: OpenMode, policy: CachePolicy) -> VaultResult<()> {
+        let remote_lck = self.main();
+        let mut remote = remote_lck.lock().unwrap();
+        remote.open(file, mode)?;
+        if let CachePolicy::Chunked = policy {
+            let info = match remote.attr(file) {
+                Ok(info) => info,
+                Err(err) => {
+                    remote.close(file)?;
+                    return Err(err);
+                }
+            };
+            if self.database.attr(file)?.version != info.version {
+                debug!("open({}) => remote changed, dropping chunks", file);
+                self.chunks.remove(&file);
+                self.fd_map.replace(file, &[])?;
+                self.database
+                    .set_attr(file, None, None, None, Some(info.version))?;
+                self.database.set_size(file, info.size)?;
+            }
+        }
+        self.open_policies.insert(file, policy);
+        Ok(())
+    }
+
+    /// Make sure the chunks of chunked `file` covering `size` bytes at
+    /// `offset` are in our copy, fetching those that aren't.
+    fn fetch_chunks(&mut self, file: Inode, offset: i64, size: u64) -> VaultResult<()> {
+        let start = check_range(offset, size)?;
+        if size == 0 {
+            return Ok(());
+        }
+        let remote = self.main();
+        let fd_lck = self.fd_map.get(file, false)?;
+        let fetched = self.chunks.entry(file).or_default();
+        for idx in start / CACHE_CHUNK_SIZE..=(start + size - 1) / CACHE_CHUNK_SIZE {
+            if fetched.contains(&idx) {
+                continue;
+            }
+            debug!("fetch_chunks({}) => fetching chunk {}", file, idx);
+            let data = remote.lock().unwrap().read(
+                file,
+                (idx * CACHE_CHUNK_SIZE) as i64,
+                CACHE_CHUNK_SIZE,
+            )?;
+            let mut fd = fd_lck.lock().unwrap();
+            fd.seek(SeekFrom::Start(idx * CACHE_CHUNK_SIZE))?;
+            fd.write_all(&data)?;
+            fetched.insert(idx);
+            // Nothing after EOF.
+            if (data.len() as u64) < CACHE_CHUNK_SIZE {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the chunks of `file` covering `len` bytes at `offset`,
+    /// so they are fetched again.
+    fn invalidate_chunks(&mut self, file: Inode, offset: u64, len: u64) {
+        if let Some(fetched) = self.chunks.get_mut(&file) {
+            if len > 0 {
+                for idx in offset / CACHE_CHUNK_SIZE..=(offset + len - 1) / CACHE_CHUNK_SIZE {
+                    fetched.remove(&idx);
+                }
+            }
+        }
+    }
+
+    /// If `file` is being downloaded, wait for the download to stop,
+    /// and make the partial file our copy if it completed.
+    fn settle(&mut self, file: Inode) -> VaultResult<()> {
+        let download = match self.downloads.get(&file) {
+            Some(download) => Arc::clone(download),
+            None => return Ok(()),
+        };
+        let result = download.wait();
+        self.downloads.remove(&file);
+        result?;
+        let manifest = &download.manifest;
+        OpenOptions::new()
+            .write(true)
+            .open(&download.partial_path)?
+            .set_len(manifest.size)?;
+        std::fs::rename(
+            &download.partial_path,
+            self.fd_map.compose_path(file, false),
+        )?;
+        self.database
+            .set_attr(file, None, None, None, Some(manifest.version))?;
+        self.database.set_size(file, manifest.size)?;
+        self.database.set_manifest(file, manifest)?;
+        Ok(())
+    }
+
+    /// If a read of `file` would wait for its download, return the
+    /// download, see `read_unlocked`.
+    fn unfinished_download(&mut self, file: Inode) -> VaultResult<Option<Arc<download::Download>>> {
+        if self.open_policies.get(&file).copied().unwrap_or_default() != CachePolicy::Whole {
+            return Ok(None);
+        }
+        self.fetch_deferred(file)?;
+        Ok(self
+            .downloads
+            .get(&file)
+            .filter(|download| !download.finished())
+            .cloned())
+    }
+
+    /// If `file` is being downloaded, return (bytes downloaded, bytes
+    /// in total).
+    pub fn fetch_progress(&self, file: Inode) -> Option<(u64, u64)> {
+        self.downloads
+            .get(&file)
+            .map(|download| download.progress())
+    }
+
+    /// If we can serve a savage request for `file`, return the
+    /// (version, size) we would give, see `search_in_cache`.
+    pub fn cached_version(&mut self, file: Inode) -> VaultResult<(FileVersion, u64)> {
+        if self.policy_of(file)? != CachePolicy::Whole {
+            return Err(VaultError::FileNotExist(file));
+        }
+        let unsynced = self.unsynced(file);
+        let info = local_vault::attr(file, &mut self.database, &self.fd_map, unsynced)?;
+        // We never fetched it, or it is evicted.
+        if info.version.0 == 0 {
+            return Err(VaultError::FileNotExist(file));
+        }
+        Ok((info.version, info.size))
     }
 
     /// If someone comes savaging for `file`, look in our cache and
@@ -100,37 +451,404 @@ impl CachingVault {
     /// other error occurs, just return those errors. This is the
     /// function called by VaultServer to serve a savage request.
     pub fn search_in_cache(&mut self, file: Inode) -> VaultResult<(Vec<u8>, FileVersion)> {
-        let info = local_vault::attr(file, &mut self.database, &mut self.fd_map)?;
-        let data = local_vault::read(file, 0, info.size as u32, &mut self.fd_map)?;
-        self.mark_forked(file);
+        // We only have part of the file, if any.
+        if self.policy_of(file)? != CachePolicy::Whole {
+            return Err(VaultError::FileNotExist(file));
+        }
+        let unsynced = self.unsynced(file);
+        let info = local_vault::attr(file, &mut self.database, &self.fd_map, unsynced)?;
+        let data = local_vault::read(file, 0, info.size, &self.fd_map)?;
+        self.versions.fork(file);
         Ok((data, info.version))
     }
 
-    /// Savage for the file from other remote vaults.
+    /// Remove the cached metadata of `file`, and its data file if
+    /// nobody has it open.
+    fn remove_local(&mut self, file: Inode) -> VaultResult<()> {
+        let kind = self.database.attr(file)?.kind;
+        self.database.remove_file(file)?;
+        // A download still running writes to a removed file.
+        self.downloads.remove(&file);
+        self.outdated.remove(&file);
+        let partial_path = self.fd_map.partial_path(file);
+        if partial_path.exists() {
+            std::fs::remove_file(partial_path)?;
+        }
+        // FIXME: delete_queue like local_vault.
+        if let VaultFileType::File = kind {
+            if self.ref_count.count(file) == 0 {
+                std::fs::remove_file(self.fd_map.compose_path(file, false))?;
+            }
+        }
+        self.events.emit(Event::Deleted {
+            vault: self.name(),
+            file,
+        });
+        Ok(())
+    }
+
+    /// Return the operations waiting to be performed on the remote
+    /// by the background worker.
+    pub fn pending(&self) -> PendingOps {
+        let mut ops = self.pending_log.lock().unwrap().clone();
+        ops.extend(self.log.lock().unwrap().iter().cloned());
+        let upload_size = ops
+            .iter()
+            .map(|op| match op {
+                BackgroundOp::Upload(file, _, _) => self.fd_map.data_size(*file),
+                _ => 0,
+            })
+            .sum();
+        PendingOps {
+            vault: self.name(),
+            dry_run: self.dry_run.load(SeqCst),
+            ops,
+            upload_size,
+        }
+    }
+
+    /// Save the operations the background worker hasn't performed
+    /// yet, so the next CachingVault of this vault performs them.
+    /// Call it when we stop. The worker may still perform some of
+    /// them, and they are performed again later, which is harmless:
+    /// an upload sends the same content again, and creates and
+    /// deletes that are already done fail. Return the number of
+    /// operations saved. Until then, the operations loaded on start
+    /// stay saved, in case we die first.
+    pub fn save_pending(&self) -> VaultResult<usize> {
+        let ops = self.pending().ops;
+        if ops.is_empty() {
+            if self.pending_path.exists() {
+                std::fs::remove_file(&self.pending_path)?;
+            }
+            return Ok(0);
+        }
+        let content = serde_json::to_string(&ops).map_err(std::io::Error::from)?;
+        // Replace the file we loaded in one go, a crash halfway
+        // leaves one or the other.
+        let tmp_path = self.pending_path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, content)?;
+        std::fs::rename(&tmp_path, &self.pending_path)?;
+        info!(
+            "{}: saved {} pending operations to {:?}",
+            self.name(),
+            ops.len(),
+            &self.pending_path
+        );
+        Ok(ops.len())
+    }
+
+    /// When `dry_run` is true, the background worker keeps collecting
+    /// operations but doesn't perform them, until `dry_run` is set
+    /// back to false.
+    pub fn set_dry_run(&self, dry_run: bool) {
+        info!("{}: set_dry_run({})", self.name(), dry_run);
+        self.dry_run.store(dry_run, SeqCst);
+    }
+
+    /// When `read_only` is true, refuse every change to the vault with
+    /// VaultError::ReadOnly, so we never have anything to upload.
+    /// Reading still fetches from the remote.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        info!("{}: set_read_only({})", self.name(), read_only);
+        self.read_only = read_only;
+    }
+
+    /// Refuse changes while `health` says the store doesn't take
+    /// writes, and tell it when a change fails that way. Uploads of
+    /// changes made before go on, see `background_worker::upload`.
+    pub fn set_store_health(&mut self, health: StoreHealth) {
+        self.health = health;
+    }
+
+    /// Return the health of our store, see `set_store_health`.
+    pub fn store_health(&self) -> &StoreHealth {
+        &self.health
+    }
+
+    /// Return the path of `file` in the vault, as far as we know it,
+    /// see `Database::path_of`.
+    pub fn path_of(&self, file: Inode) -> VaultResult<String> {
+        self.database.path_of(file)
+    }
+
+    /// Refresh the statistics of the database of our copy and vacuum
+    /// it if needed, see `Database::maintain`.
+    pub fn maintain_database(&mut self) -> VaultResult<Option<u64>> {
+        self.database.maintain()
+    }
+
+    /// Download file content from the caches of `peers` when they
+    /// have the version the remote has, and from the remote only what
+    /// they don't have. For peers that are faster to reach than the
+    /// remote, eg, on the same LAN when the remote is across a WAN.
+    pub fn set_near_peers(&mut self, peers: Vec<VaultName>) {
+        info!("{}: set_near_peers({:?})", self.name(), peers);
+        self.near_peers = peers;
+    }
+
+    /// Return the remote vaults of our near peers.
+    fn near(&self) -> Vec<VaultRef> {
+        self.near_peers
+            .iter()
+            .filter(|peer| **peer != self.name)
+            .filter_map(|peer| self.remote_map.get(peer).map(Arc::clone))
+            .collect()
+    }
+
+    /// Return true if `file` has changes we haven't uploaded yet, see
+    /// `unsynced`.
+    fn unsynced(&self, file: Inode) -> bool {
+        unsynced(file, &self.versions, [&self.log, &self.pending_log])
+    }
+
+    /// Return when the remote sealed its vault, None if it didn't or
+    /// we don't know yet, see src/seal.rs.
+    pub fn sealed(&self) -> Option<u64> {
+        self.seal_state.sealed()
+    }
+
+    fn check_writable(&mut self) -> VaultResult<()> {
+        if self.read_only {
+            return Err(VaultError::ReadOnly(self.name()));
+        }
+        if let Some(time) = self.seal_state.sealed() {
+            if !self.seal_recorded {
+                self.seal_recorded = true;
+                if let Err(err) = self.database.seal(time) {
+                    warn!("{}: cannot record the seal: {:?}", self.name(), err);
+                }
+            }
+            return Err(VaultError::ReadOnly(self.name()));
+        }
+        self.health.check(&self.name)
+    }
+
+    /// Make our copy of extended attribute `name` of cached `file`
+    /// `value`, None meaning it has no such attribute.
+    fn keep_xattr(&mut self, file: Inode, name: &str, value: Option<&[u8]>) -> VaultResult<()> {
+        match value {
+            Some(value) => self.database.set_xattr(file, name, value),
+            None => match self.database.remove_xattr(file, name) {
+                Ok(()) | Err(VaultError::XattrNotExist(_, _)) => Ok(()),
+                Err(err) => Err(err),
+            },
+        }
+    }
+
+    /// Drop the cached content of files that nobody accessed in
+    /// `max_age_days` days, so they are fetched again on next open.
+    /// We only evict files that aren't open, have no local changes,
+    /// and whose version matches the remote, ie, are fully uploaded.
+    /// Return the number of files evicted.
+    pub fn evict_stale(&mut self) -> VaultResult<u64> {
+        if self.max_age_days == 0 {
+            return Ok(0);
+        }
+        let current_time = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)?
+            .as_secs();
+        let cutoff = current_time.saturating_sub(self.max_age_days * 24 * 60 * 60);
+        let mut count = 0;
+        for file in self.database.files_accessed_before(cutoff)? {
+            if self.ref_count.count(file) != 0
+                || self.versions.dirty(file)
+                || self.downloads.contains_key(&file)
+            {
+                continue;
+            }
+            let our_version = self.database.attr(file)?.version;
+            match self.main().lock().unwrap().attr(file) {
+                Ok(info) if info.version == our_version => (),
+                // Not uploaded yet, or we are out-of-date anyway.
+                Ok(_) => continue,
+                // Can't tell if the remote has it, keep our copy.
+                Err(VaultError::RpcError(_)) => return Ok(count),
+                Err(VaultError::FileNotExist(_)) => continue,
+                Err(err) => return Err(err),
+            }
+            debug!("evict_stale: evicting {}", file);
+            // Keep an empty data file, because metadata exists =>
+            // data file exists. Version 0 means "not fetched".
+            std::fs::File::create(self.fd_map.compose_path(file, false))?;
+            self.database
+                .set_attr(file, None, None, None, Some((0, 0)))?;
+            self.database.set_size(file, 0)?;
+            count += 1;
+        }
+        info!("{}: evict_stale() => {} files", self.name(), count);
+        Ok(count)
+    }
+
+    /// Make our copy of `file`, cached whole, up to date: start
+    /// downloading it if the remote has a newer version, use what we
+    /// have if the remote is unreachable, or savage it from other
+    /// peers if we have nothing.
+    fn fetch(&mut self, file: Inode) -> VaultResult<()> {
+        // A download started by an earlier open may still be going,
+        // let it finish first. If it failed, we start another one
+        // below.
+        if let Err(err) = self.settle(file) {
+            debug!("open({}) => last download failed: {:?}", file, err);
+        }
+        let unsynced = self.unsynced(file);
+        let result = match connected_case(
+            self.main(),
+            file,
+            &mut self.database,
+            &self.fd_map,
+            &mut self.downloads,
+            self.near(),
+            unsynced,
+        ) {
+            Ok(()) => Ok(()),
+            Err(VaultError::RpcError(_)) => {
+                match disconnected_case(file, &mut self.database, &self.fd_map, unsynced) {
+                    Ok(_) => Ok(()),
+                    Err(_) => self.savage(file),
+                }
+            }
+            Err(err) => Err(err),
+        };
+        return result;
+        // Start downloading remote content if we are out-of-date.
+        fn connected_case(
+            remote_ref: VaultRef,
+            file: Inode,
+            database: &mut Database,
+            fd_map: &FdMap,
+            downloads: &mut Downloads,
+            near: Vec<VaultRef>,
+            unsynced: bool,
+        ) -> VaultResult<()> {
+            let mut remote = remote_ref.lock().unwrap();
+            let remote_meta = remote.attr(file)?;
+            let our_version = local_vault::attr(file, database, fd_map, unsynced)?.version;
+            debug!(
+                "open({}) => local ver {:?}, remote ver {:?}",
+                file, our_version, remote_meta.version
+            );
+            if our_version.0 < remote_meta.version.0 {
+                // FIXME: What if: we made change, not yet submitted,
+                // someone open the file, we fetch the remote newer
+                // version, now our work is lost!
+                debug!("pulling from remote");
+                let remote_name = remote.name();
+                let remote = unpack_to_remote(&mut remote)?;
+                match remote.manifest(file) {
+                    Ok(manifest) => {
+                        let ours = our_manifest(file, database, fd_map, manifest.algorithm)?;
+                        let download = download::start(
+                            Arc::clone(&remote_ref),
+                            file,
+                            manifest,
+                            fd_map.partial_path(file),
+                            fd_map.compose_path(file, false),
+                            ours,
+                            near,
+                        );
+                        downloads.insert(file, download);
+                    }
+                    // Peers that don't serve manifests.
+                    Err(VaultError::RemoteError(_)) => {
+                        let (data, version) = remote.savage(&remote_name, file, None)?;
+                        fd_map.replace(file, &data)?;
+                        database.set_attr(file, None, None, None, Some(version))?;
+                        database.set_size(file, data.len() as u64)?;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(())
+        }
+        // If remote is disconnected, use the local version if we have
+        // one, report error if we don't. Version 0 means we never
+        // fetched the content or it's evicted.
+        fn disconnected_case(
+            file: Inode,
+            database: &mut Database,
+            fd_map: &FdMap,
+            unsynced: bool,
+        ) -> VaultResult<()> {
+            let result = match local_vault::attr(file, database, fd_map, unsynced) {
+                Ok(info) if info.version.0 == 0 => Err(VaultError::FileNotExist(file)),
+                result => result,
+            };
+            match &result {
+                Ok(_) => info!(
+                    "open({}) => remote disconnected, but we have a local copy",
+                    file
+                ),
+                Err(_) => info!(
+                    "open({}) => remote disconnected, we don't have a local copy",
+                    file
+                ),
+            };
+            result?;
+            Ok(())
+        }
+    }
+
+    /// If `file` is deferred, see `open`, fetch it now.
+    fn fetch_deferred(&mut self, file: Inode) -> VaultResult<()> {
+        if !self.deferred.contains(&file) {
+            return Ok(());
+        }
+        debug!("fetch_deferred({})", file);
+        // Stay deferred if it fails, the next read tries again.
+        self.fetch(file)?;
+        self.deferred.remove(&file);
+        Ok(())
+    }
+
+    /// Savage for the file from other remote vaults. We first ask
+    /// each of them which version they have, and fetch from the one
+    /// with the newest, so we don't copy a whole old version when
+    /// another peer has a newer one.
     fn savage(&mut self, file: Inode) -> VaultResult<()> {
         info!("savage({})", file);
         let my_name = self.name();
+        // (version offered, vault name). Peers running older versions
+        // don't make offers, they are tried last.
+        let mut candidates: Vec<(Option<FileVersion>, VaultName)> = vec![];
         // TODO: make parallel.
         for (vault_name, remote) in self.remote_map.iter() {
-            if *vault_name != my_name {
-                let result = unpack_to_remote(&mut remote.lock().unwrap())?.savage(&my_name, file);
-                match result {
-                    Ok((data, version)) => {
-                        debug!(
-                            "Savage from {} succeeded, version={:?}",
-                            vault_name, version
-                        );
-                        local_vault::write(file, 0, &data, &mut self.fd_map)?;
-                        // Make sure written to data file.
-                        self.fd_map.close(file, true)?;
-                        self.database
-                            .set_attr(file, None, None, None, Some(version))?;
-                        // We succeeded, return.
-                        return Ok(());
-                    }
-                    Err(_) => {
-                        debug!("Savage from {} failed", vault_name);
-                    }
+            if *vault_name == my_name {
+                continue;
+            }
+            match unpack_to_remote(&mut remote.lock().unwrap())?.savage_offer(&my_name, file) {
+                Ok(Some((version, size))) => {
+                    debug!(
+                        "{} offers version {:?} ({} bytes)",
+                        vault_name, version, size
+                    );
+                    candidates.push((Some(version), vault_name.clone()));
+                }
+                Ok(None) => candidates.push((None, vault_name.clone())),
+                Err(_) => debug!("{} has nothing to offer", vault_name),
+            }
+        }
+        // Newest first, None (no offer) sorts last.
+        candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        for (version, vault_name) in candidates {
+            let remote = Arc::clone(self.remote_map.get(&vault_name).unwrap());
+            let result =
+                unpack_to_remote(&mut remote.lock().unwrap())?.savage(&my_name, file, version);
+            match result {
+                Ok((data, version)) => {
+                    debug!(
+                        "Savage from {} succeeded, version={:?}",
+                        vault_name, version
+                    );
+                    self.fd_map.replace(file, &data)?;
+                    self.database
+                        .set_attr(file, None, None, None, Some(version))?;
+                    self.database.set_size(file, data.len() as u64)?;
+                    // We succeeded, return.
+                    return Ok(());
+                }
+                Err(_) => {
+                    debug!("Savage from {} failed", vault_name);
                 }
             }
         }
@@ -139,6 +857,136 @@ impl CachingVault {
     }
 }
 
+/// Return the operations saved in `path` by `save_pending`, and
+/// remove it, we don't want to perform them twice.
+/// Read like `Vault::read` on `vault_lck`, but if the data comes from
+/// a download still going, wait for it without holding the vault, so
+/// other requests to the vault, eg, of files already cached, go on
+/// meanwhile.
+pub fn read_unlocked(
+    vault_lck: &VaultRef,
+    file: Inode,
+    offset: i64,
+    size: u64,
+) -> VaultResult<Vec<u8>> {
+    let download = match &mut *vault_lck.lock().unwrap() {
+        GenericVault::Caching(caching) => caching.unfinished_download(file)?,
+        _ => None,
+    };
+    if let Some(download) = download {
+        match download.read(offset, size) {
+            // Settled while we waited, the partial file is our copy
+            // now.
+            Err(VaultError::IOError(err)) if err.kind() == std::io::ErrorKind::NotFound => (),
+            result => return result,
+        }
+    }
+    vault_lck.lock().unwrap().read(file, offset, size)
+}
+
+/// How many entries of the tree `bootstrap` records at a time,
+/// between which the vault is free for others.
+const BOOTSTRAP_BATCH_SIZE: usize = 1024;
+
+/// If the cache of `vault_lck`, a caching vault, has nothing but the
+/// root, fetch the whole tree of its remote in one request and record
+/// it, so the vault can be browsed, even offline, without listing
+/// each directory from the remote first. Entries are recorded as
+/// they come, the vault and the remote are free for others between
+/// batches. Contents are still fetched on open. Return the number of
+/// files recorded; 0 if the cache isn't new or the remote runs an
+/// older version that can't send its tree.
+pub fn bootstrap(vault_lck: &VaultRef) -> VaultResult<u64> {
+    let remote_lck = {
+        let mut vault = vault_lck.lock().unwrap();
+        let vault = unpack_to_caching(&mut vault)?;
+        if vault.database.largest_inode() != 1 {
+            return Ok(0);
+        }
+        vault.main()
+    };
+    let mut tree = {
+        let mut remote = remote_lck.lock().unwrap();
+        let remote = unpack_to_remote(&mut remote)?;
+        match remote.tree(1)? {
+            Some(tree) => tree,
+            None => {
+                warn!(
+                    "{} can't send its tree, the cache fills as directories are listed",
+                    remote.name()
+                );
+                return Ok(0);
+            }
+        }
+    };
+    let mut count = 0;
+    loop {
+        let entries = {
+            let mut remote = remote_lck.lock().unwrap();
+            match unpack_to_remote(&mut remote)?.next_entries(&mut tree)? {
+                Some(entries) => entries,
+                None => return Ok(count),
+            }
+        };
+        let mut entries = entries.into_iter().peekable();
+        while entries.peek().is_some() {
+            let mut vault = vault_lck.lock().unwrap();
+            let vault = unpack_to_caching(&mut vault)?;
+            for (dir, info) in entries.by_ref().take(BOOTSTRAP_BATCH_SIZE) {
+                if vault.merge_entry(dir, info)? {
+                    count += 1;
+                }
+            }
+        }
+    }
+}
+
+fn load_pending(path: &Path) -> VaultResult<Vec<BackgroundOp>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let content = std::fs::read_to_string(path)?;
+    let ops: Vec<BackgroundOp> = serde_json::from_str(&content).map_err(std::io::Error::from)?;
+    // The file stays until `save_pending` replaces it, so the ops
+    // aren't lost if we die before saving them again.
+    info!("loaded {} pending operations from {:?}", ops.len(), path);
+    Ok(ops)
+}
+
+/// Return the manifest of our copy of `file`, in `algorithm`, the
+/// algorithm of the remote's. It's recorded if we downloaded it in
+/// that algorithm, otherwise compute it.
+fn our_manifest(
+    file: Inode,
+    database: &mut Database,
+    fd_map: &FdMap,
+    algorithm: HashAlgorithm,
+) -> VaultResult<Manifest> {
+    let our_version = database.attr(file)?.version;
+    match database.manifest(file)? {
+        Some(recorded)
+            if recorded.version == our_version
+                && recorded.size == fd_map.data_size(file)
+                && recorded.algorithm == algorithm =>
+        {
+            Ok(recorded)
+        }
+        _ => Manifest::of_file(&fd_map.compose_path(file, false), our_version, algorithm),
+    }
+}
+
+/// Return true if `file` has changes we haven't uploaded yet: written
+/// since the last close, or waiting in one of `logs` for the
+/// background worker. Repairing its data file would lose them.
+fn unsynced(file: Inode, versions: &VersionTracker, logs: [&BackgroundLog; 2]) -> bool {
+    let uploading =
+        |op: &BackgroundOp| matches!(op, BackgroundOp::Upload(inode, _, _) if *inode == file);
+    versions.dirty(file)
+        || logs
+            .iter()
+            .any(|log| log.lock().unwrap().iter().any(uploading))
+}
+
 /*** Vault implementation of CachingVault */
 
 impl Vault for CachingVault {
@@ -148,21 +996,29 @@ impl Vault for CachingVault {
 
     fn attr(&mut self, file: Inode) -> VaultResult<FileInfo> {
         debug!("{}: attr({})", self.name(), file);
-        match self.main().lock().unwrap().attr(file) {
+        let result = self.main().lock().unwrap().attr(file);
+        match result {
             // Connected.
-            Ok(info) => Ok(info),
+            Ok(info) => {
+                if local_vault::has_file(file, &mut self.database)? {
+                    self.saw_version(file, info.version)?;
+                }
+                Ok(info)
+            }
             // Disconnected.
             Err(VaultError::RpcError(_)) => {
-                local_vault::attr(file, &mut self.database, &mut self.fd_map)
+                let unsynced = self.unsynced(file);
+                local_vault::attr(file, &mut self.database, &self.fd_map, unsynced)
             }
             // File is gone on remote.
             Err(VaultError::FileNotExist(file)) => {
-                let kind = self.database.attr(file)?.kind;
-                self.database.remove_file(file)?;
-                // FIXME: delete_queue like local_vaule.
-                if self.ref_count.count(file) == 0 {
-                    std::fs::remove_file(self.fd_map.compose_path(file, false))?;
+                if local_vault::has_file(file, &mut self.database)? {
+                    if let Some(parent) = self.database.parent(file)? {
+                        let name = self.database.attr(file)?.name;
+                        self.vanished(file, parent, name);
+                    }
                 }
+                self.remove_local(file)?;
                 Err(VaultError::FileNotExist(file))
             }
             // Other error.
@@ -170,7 +1026,7 @@ impl Vault for CachingVault {
         }
     }
 
-    fn read(&mut self, file: Inode, offset: i64, size: u32) -> VaultResult<Vec<u8>> {
+    fn read(&mut self, file: Inode, offset: i64, size: u64) -> VaultResult<Vec<u8>> {
         info!(
             "{}: read(file={}, offset={}, size={})",
             self.name(),
@@ -178,11 +1034,28 @@ impl Vault for CachingVault {
             offset,
             size
         );
-        // Data is guaranteed to exist locally, because we fetch on open.
-        local_vault::read(file, offset, size, &mut self.fd_map)
+        match self.open_policies.get(&file).copied() {
+            Some(CachePolicy::Passthrough) => self.main().lock().unwrap().read(file, offset, size),
+            Some(CachePolicy::Chunked) => {
+                self.fetch_chunks(file, offset, size)?;
+                local_vault::read(file, offset, size, &self.fd_map)
+            }
+            // Data is guaranteed to exist locally, because we fetch on
+            // open or here, unless the download is still going.
+            _ => {
+                self.fetch_deferred(file)?;
+                if let Some(download) = self.downloads.get(&file).cloned() {
+                    if !download.finished() {
+                        return download.read(offset, size);
+                    }
+                    self.settle(file)?;
+                }
+                local_vault::read(file, offset, size, &self.fd_map)
+            }
+        }
     }
 
-    fn write(&mut self, file: Inode, offset: i64, data: &[u8]) -> VaultResult<u32> {
+    fn write(&mut self, file: Inode, offset: i64, data: &[u8]) -> VaultResult<u64> {
         info!(
             "{}: write(file={}, offset={}, size={})",
             self.name(),
@@ -190,9 +1063,83 @@ impl Vault for CachingVault {
             offset,
             data.len()
         );
-        let size = local_vault::write(file, offset, data, &mut self.fd_map)?;
-        self.mod_track.incf(file)?;
-        Ok(size)
+        self.check_writable()?;
+        match self.open_policies.get(&file).copied() {
+            Some(CachePolicy::Passthrough) => self.main().lock().unwrap().write(file, offset, data),
+            Some(CachePolicy::Chunked) => {
+                let start = check_range(offset, data.len() as u64)?;
+                let size = self.main().lock().unwrap().write(file, offset, data)?;
+                self.invalidate_chunks(file, start, data.len() as u64);
+                Ok(size)
+            }
+            _ => {
+                self.fetch_deferred(file)?;
+                self.settle(file)?;
+                let size = local_vault::write(file, offset, data, &self.fd_map)?;
+                self.versions.write(file);
+                Ok(size)
+            }
+        }
+    }
+
+    /// Chunked and passthrough files append on the remote. Whole
+    /// files append to our copy, which is uploaded whole like after
+    /// any write, so appends of two peers to their copies at once
+    /// conflict rather than merge.
+    fn append(&mut self, file: Inode, data: &[u8]) -> VaultResult<u64> {
+        info!(
+            "{}: append(file={}, size={})",
+            self.name(),
+            file,
+            data.len()
+        );
+        self.check_writable()?;
+        match self.open_policies.get(&file).copied() {
+            Some(CachePolicy::Passthrough) => self.main().lock().unwrap().append(file, data),
+            Some(CachePolicy::Chunked) => {
+                // We don't know where the data landed, fetch the
+                // chunks again.
+                let size = self.main().lock().unwrap().append(file, data)?;
+                self.chunks.remove(&file);
+                Ok(size)
+            }
+            _ => {
+                self.fetch_deferred(file)?;
+                self.settle(file)?;
+                let size = local_vault::append(file, data, &self.fd_map)?;
+                self.versions.write(file);
+                Ok(size)
+            }
+        }
+    }
+
+    fn truncate(&mut self, file: Inode, size: u64) -> VaultResult<()> {
+        info!("{}: truncate(file={}, size={})", self.name(), file, size);
+        self.check_writable()?;
+        let policy = self.policy_of(file)?;
+        if policy != CachePolicy::Whole {
+            self.main().lock().unwrap().truncate(file, size)?;
+            if let CachePolicy::Chunked = policy {
+                // Our copy may have data past the new end.
+                self.chunks.remove(&file);
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(self.fd_map.compose_path(file, false))?
+                    .set_len(0)?;
+            }
+            return Ok(());
+        }
+        // Like a write, truncate our copy and upload it on the last
+        // close. Opening fetches the content first.
+        self.open(file, OpenMode::RW)?;
+        let result = self
+            .settle(file)
+            .and_then(|_| local_vault::truncate(file, size, &self.fd_map));
+        if result.is_ok() {
+            self.versions.write(file);
+        }
+        self.close(file)?;
+        result
     }
 
     fn open(&mut self, file: Inode, mode: OpenMode) -> VaultResult<()> {
@@ -206,9 +1153,16 @@ impl Vault for CachingVault {
         );
         // We use open/close of local vault to track ref_count.
         self.ref_count.incf(file)?;
-        // Invariant: if ref_count > 0, then we have local copy.
+        // Invariant: if ref_count > 0, then we have local copy, or
+        // the file is deferred.
         if count > 0 {
-            // Already opened.
+            // Already opened. Writing needs the content.
+            if let OpenMode::RW = mode {
+                if let Err(err) = self.fetch_deferred(file) {
+                    self.ref_count.decf(file)?;
+                    return Err(err);
+                }
+            }
             return Ok(());
         }
         // Not already opened. But at this point the file meta must
@@ -218,70 +1172,40 @@ impl Vault for CachingVault {
         // either not fetched (version = 0), or out-of-date (version
         // too low), or up-to-date, or even more up-to-date, if we
         // have local changes not yet pushed to remote.
-        match connected_case(self.main(), file, &mut self.database, &mut self.fd_map) {
-            Ok(()) => return Ok(()),
-            Err(VaultError::RpcError(_)) => {
-                match disconnected_case(file, &mut self.database, &mut self.fd_map) {
-                    Ok(_) => return Ok(()),
-                    Err(_) => match self.savage(file) {
-                        Ok(_) => return Ok(()),
-                        Err(err) => return Err(err),
-                    },
-                }
-            }
-            Err(err) => return Err(err),
+        // Record the access for evict_stale. Not worth failing the
+        // open over, the store may not take writes.
+        let current_time = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)?
+            .as_secs();
+        if let Err(err) =
+            self.health.note(
+                self.database
+                    .set_attr(file, None, Some(current_time), None, None),
+            )
+        {
+            debug!("open({}) => recording the access failed: {:?}", file, err);
         }
-        // Download remote content if we are out-of-date.
-        fn connected_case(
-            remote: VaultRef,
-            file: Inode,
-            database: &mut Database,
-            fd_map: &FdMap,
-        ) -> VaultResult<()> {
-            let mut remote = remote.lock().unwrap();
-            let remote_meta = remote.attr(file)?;
-            let our_version = local_vault::attr(file, database, fd_map)?.version;
-            debug!(
-                "open({}) => local ver {:?}, remote ver {:?}",
-                file, our_version, remote_meta.version
-            );
-            if our_version.0 < remote_meta.version.0 {
-                // FIXME: What if: we made change, not yet submitted,
-                // someone open the file, we fetch the remote newer
-                // version, now our work is lost!
-
-                // TODO: read by chunk.
-                debug!("pulling from remote");
-                let remote_name = remote.name();
-                let (data, version) = unpack_to_remote(&mut remote)?.savage(&remote_name, file)?;
-                local_vault::write(file, 0, &data, fd_map)?;
-                // Close to make sure change is written to data file.
-                fd_map.close(file, true)?;
-                database.set_attr(file, None, None, None, Some(version))?;
+        let policy = self.policy_of(file)?;
+        if policy != CachePolicy::Whole {
+            if let Err(err) = self.open_direct(file, mode, policy) {
+                self.ref_count.decf(file)?;
+                return Err(err);
             }
-            Ok(())
+            return Ok(());
         }
-        // If remote is disconnected, use the local version if we have
-        // one, report error if we don't.
-        fn disconnected_case(
-            file: Inode,
-            database: &mut Database,
-            fd_map: &FdMap,
-        ) -> VaultResult<()> {
-            let result = local_vault::attr(file, database, fd_map);
-            match &result {
-                Ok(_) => info!(
-                    "open({}) => remote disconnected, but we have a local copy",
-                    file
-                ),
-                Err(_) => info!(
-                    "open({}) => remote disconnected, we don't have a local copy",
-                    file
-                ),
-            };
-            result?;
-            Ok(())
+        // Programs often open a file only to stat or lock it, don't
+        // download it until they read it.
+        if let OpenMode::R = mode {
+            debug!("open({}) => deferring the fetch to the first read", file);
+            self.deferred.insert(file);
+            return Ok(());
+        }
+        let result = self.fetch(file);
+        // We don't have a copy, so it isn't open.
+        if result.is_err() {
+            self.ref_count.decf(file)?;
         }
+        result
     }
 
     fn close(&mut self, file: Inode) -> VaultResult<()> {
@@ -301,21 +1225,40 @@ impl Vault for CachingVault {
             return Ok(());
         }
         // Yes, perform close.
-        let modified = self.mod_track.nonzero(file);
+        if self.deferred.remove(&file) {
+            debug!("close({}) => never read, never fetched", file);
+            return Ok(());
+        }
+        if self.open_policies.remove(&file).is_some() {
+            self.fd_map.close(file, false)?;
+            return self.main().lock().unwrap().close(file);
+        }
+        let modified = self.versions.dirty(file);
         if modified {
-            self.mod_track.zero(file);
-            let info = local_vault::attr(file, &mut self.database, &mut self.fd_map)?;
+            let info = local_vault::attr(file, &mut self.database, &self.fd_map, modified)?;
             debug!(
                 "modified, write: inode={}, name={}, size={} (not accurate), atime={}, mtime={}, kind={:?}",
                 file, info.name, info.size, info.atime, info.mtime, info.kind
             );
             // Increment the version so we don't fetch the remote
             // version upon next open.
-            let new_version =
-                local_vault::calculate_version(file, info.version, modified, &mut self.fork_track);
+            let new_version = self
+                .versions
+                .commit(file, info.version)
+                .unwrap_or(info.version);
+            let current_time = time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)?
+                .as_secs();
             self.database
-                .set_attr(file, None, None, None, Some(new_version))?;
+                .set_attr(file, None, None, Some(current_time), Some(new_version))?;
             self.fd_map.close(file, modified)?;
+            self.database.settle_intent(file, Intent::Synced)?;
+            self.database.set_size(file, self.fd_map.data_size(file))?;
+            self.events.emit(Event::Modified {
+                vault: self.name(),
+                file,
+                version: new_version,
+            });
             // Add the op to background queue.
             self.log
                 .lock()
@@ -335,10 +1278,23 @@ impl Vault for CachingVault {
             name,
             kind
         );
-        let inode = match self.main().lock().unwrap().create(parent, name, kind) {
+        self.check_writable()?;
+        let result = self.main().lock().unwrap().create(parent, name, kind);
+        let inode = match result {
             // Connected.
             Ok(inode) => {
                 if let VaultFileType::File = kind {
+                    match policy_for(&self.cache_rules, name) {
+                        // Create also opens the file on the remote,
+                        // but we write to our copy and upload on
+                        // close, so release the remote's.
+                        CachePolicy::Whole => self.main().lock().unwrap().close(inode)?,
+                        // Reads and writes go to the remote, keep it
+                        // open as our open.
+                        policy => {
+                            self.open_policies.insert(inode, policy);
+                        }
+                    }
                     self.fd_map.get(inode, false)?;
                 }
                 let current_time = time::SystemTime::now()
@@ -352,11 +1308,20 @@ impl Vault for CachingVault {
                     current_time,
                     current_time,
                     (1, 0),
+                    default_mode(kind),
                 )?;
                 self.ref_count.incf(inode)?;
+                self.events.emit(Event::Created {
+                    vault: self.name(),
+                    file: inode,
+                    parent,
+                    name: name.to_string(),
+                    kind,
+                });
                 Ok(inode)
             }
             // Disconnected.
+            #[allow(clippy::overly_complex_bool_expr)]
             Err(VaultError::RpcError(_)) if self.allow_disconnected_create && false => {
                 // FIXME: We don't allow disconnected create for now,
                 // because that requires dealing with allocating
@@ -370,87 +1335,421 @@ impl Vault for CachingVault {
             // Other error.
             Err(err) => Err(err),
         }?;
-        // Readdir will fetch meta for the new file.
+        // Readdir will fetch meta for the new file, and record its
+        // generation on the remote.
         self.readdir(parent)?;
         Ok(inode)
     }
 
+    fn fsync(&mut self, file: Inode) -> VaultResult<()> {
+        info!("{}: fsync({})", self.name(), file);
+        self.fd_map.sync(file)?;
+        if self.fd_map.writing(file) && local_vault::has_file(file, &mut self.database)? {
+            self.database.journal(file, Intent::Synced)?;
+        }
+        // Uploads of `file` waiting for the background worker, which
+        // we do now instead. Changes to `file` while it's open wait
+        // for the last close, like always.
+        if self.dry_run.load(SeqCst) {
+            return Ok(());
+        }
+        let ops = self.pending().ops;
+        // Operations go in order, the upload may need a file created
+        // before it.
+        if ops
+            .iter()
+            .any(|op| !matches!(op, BackgroundOp::Upload(_, _, _)))
+        {
+            debug!("fsync({}) => other operations go first", file);
+            return Ok(());
+        }
+        let upload = ops
+            .into_iter()
+            .filter_map(|op| match op {
+                BackgroundOp::Upload(inode, name, version) if inode == file => {
+                    Some((name, version))
+                }
+                _ => None,
+            })
+            .last();
+        let (name, version) = match upload {
+            Some(upload) => upload,
+            None => return Ok(()),
+        };
+        match background_worker::upload(
+            &self.fd_map,
+            &self.main(),
+            &self.events,
+            &self.graveyard,
+            file,
+            &name,
+            version,
+        ) {
+            Ok(()) => (),
+            // Our copy is safe on disk, the background worker uploads
+            // it when the remote is back.
+            Err(VaultError::RpcError(err)) => {
+                info!("fsync({}) => remote unreachable: {:?}", file, err);
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        }
+        let uploaded = |op: &BackgroundOp| match op {
+            BackgroundOp::Upload(inode, _, op_version) => *inode == file && *op_version <= version,
+            _ => false,
+        };
+        self.log.lock().unwrap().retain(|op| !uploaded(op));
+        // The background worker may be uploading the first one
+        // already, it removes it when done.
+        let mut pending = self.pending_log.lock().unwrap();
+        let mut idx = 0;
+        pending.retain(|op| {
+            idx += 1;
+            idx == 1 || !uploaded(op)
+        });
+        Ok(())
+    }
+
+    fn set_mode(&mut self, file: Inode, mode: u32) -> VaultResult<()> {
+        info!("{}: set_mode(file={}, mode={:o})", self.name(), file, mode);
+        self.check_writable()?;
+        // Permission changes go straight to the remote, like create
+        // and delete, so we don't need to reconcile them later.
+        self.main().lock().unwrap().set_mode(file, mode)?;
+        self.database.set_mode(file, mode)
+    }
+
+    fn set_owner(&mut self, file: Inode, uid: u32, gid: u32) -> VaultResult<()> {
+        info!(
+            "{}: set_owner(file={}, uid={}, gid={})",
+            self.name(),
+            file,
+            uid,
+            gid
+        );
+        self.check_writable()?;
+        self.main().lock().unwrap().set_owner(file, uid, gid)?;
+        if local_vault::has_file(file, &mut self.database)? {
+            self.database.set_owner(file, uid, gid)?;
+        }
+        Ok(())
+    }
+
+    fn set_times(
+        &mut self,
+        file: Inode,
+        atime: Option<u64>,
+        mtime: Option<u64>,
+    ) -> VaultResult<()> {
+        info!(
+            "{}: set_times(file={}, atime={:?}, mtime={:?})",
+            self.name(),
+            file,
+            atime,
+            mtime
+        );
+        self.check_writable()?;
+        self.main().lock().unwrap().set_times(file, atime, mtime)?;
+        if local_vault::has_file(file, &mut self.database)? {
+            self.database.set_attr(file, None, atime, mtime, None)?;
+            // An upload still waiting sends the modification time of
+            // our copy, see `background_worker::upload`.
+            let path = self.fd_map.compose_path(file, false);
+            if let (Some(mtime), true) = (mtime, path.exists()) {
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_modified(time::UNIX_EPOCH + time::Duration::from_secs(mtime))?;
+            }
+        }
+        Ok(())
+    }
+
     fn delete(&mut self, file: Inode) -> VaultResult<()> {
         info!("{}: delete({})", self.name(), file);
+        self.check_writable()?;
         // We don't wait for when ref_count reaches 0. Remote and
         // local vault will handle that.
         match self.main().lock().unwrap().delete(file) {
             // Connected.
             Ok(_) => {
                 debug!("delete({}) => remote online", file);
-                let kind = self.database.attr(file)?.kind;
-                // FIXME: delete_queue and refactor.
-                self.database.remove_file(file)?;
-                if let VaultFileType::File = kind {
-                    if self.ref_count.count(file) == 0 {
-                        std::fs::remove_file(self.fd_map.compose_path(file, false))?;
-                    }
-                }
-                Ok(())
+                self.remove_local(file)
             }
             // Disconnected.
             Err(VaultError::RpcError(_)) if self.allow_disconnected_delete => {
                 info!("delete({}) => remote disconnected, deleting locally", file);
                 self.log.lock().unwrap().push(BackgroundOp::Delete(file));
-                // FIXME: delete_queue and refactor.
-                let kind = self.database.attr(file)?.kind;
-                self.database.remove_file(file)?;
-                if let VaultFileType::File = kind {
-                    if self.ref_count.count(file) == 0 {
-                        std::fs::remove_file(self.fd_map.compose_path(file, false))?;
-                    }
+                self.remove_local(file)
+            }
+            // Other error.
+            Err(err) => Err(err),
+        }
+    }
+
+    fn delete_tree(&mut self, dir: Inode, limit: u64) -> VaultResult<u64> {
+        info!("{}: delete_tree(dir={}, limit={})", self.name(), dir, limit);
+        self.check_writable()?;
+        // Unlike delete, we don't support disconnected delete_tree,
+        // the remote is the one checking the limit.
+        let count = self.main().lock().unwrap().delete_tree(dir, limit)?;
+        if local_vault::has_file(dir, &mut self.database)? {
+            for (file, _) in self.database.subtree(dir)? {
+                self.remove_local(file)?;
+            }
+        }
+        Ok(count)
+    }
+
+    fn rename(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!(
+            "{}: rename(file={}, parent={}, name={})",
+            self.name(),
+            file,
+            parent,
+            name
+        );
+        self.check_writable()?;
+        // Like create, renames go straight to the remote, there is no
+        // disconnected rename.
+        self.main().lock().unwrap().rename(file, parent, name)?;
+        if !local_vault::has_file(file, &mut self.database)? {
+            return Ok(());
+        }
+        if !local_vault::has_file(parent, &mut self.database)? {
+            // We never listed the new parent, forget `file` until we
+            // do.
+            for (file, _) in self.database.subtree(file)? {
+                self.remove_local(file)?;
+            }
+            return Ok(());
+        }
+        // The remote replaced the entry with `name`, if any.
+        let (_, _, children) = self.database.readdir(parent)?;
+        for child in children {
+            if child != file && self.database.attr(child)?.name == name {
+                for (file, _) in self.database.subtree(child)? {
+                    self.remove_local(file)?;
+                }
+            }
+        }
+        self.database.move_file(file, parent, name)?;
+        self.events.emit(Event::Renamed {
+            vault: self.name(),
+            file,
+            parent,
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    fn link(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!(
+            "{}: link(file={}, parent={}, name={})",
+            self.name(),
+            file,
+            parent,
+            name
+        );
+        self.check_writable()?;
+        self.main().lock().unwrap().link(file, parent, name)?;
+        // Otherwise the next listing of `parent` picks it up.
+        if local_vault::has_file(file, &mut self.database)?
+            && local_vault::has_file(parent, &mut self.database)?
+        {
+            self.database.add_link(file, parent, name)?;
+            self.events.emit(Event::Linked {
+                vault: self.name(),
+                file,
+                parent,
+                name: name.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn unlink(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!(
+            "{}: unlink(file={}, parent={}, name={})",
+            self.name(),
+            file,
+            parent,
+            name
+        );
+        self.check_writable()?;
+        self.main().lock().unwrap().unlink(file, parent, name)?;
+        if !local_vault::has_file(file, &mut self.database)? {
+            return Ok(());
+        }
+        let info = self.database.attr(file)?;
+        if info.nlink > 1 {
+            match self.database.remove_name(file, parent, name) {
+                // We didn't know that name.
+                Ok(()) | Err(VaultError::FileNotExist(_)) => (),
+                Err(err) => return Err(err),
+            }
+        } else if self.database.parent(file)? == Some(parent) && info.name == name {
+            // The remote may keep it under names we don't know of,
+            // the next listing brings it back then.
+            self.remove_local(file)?;
+        }
+        Ok(())
+    }
+
+    // We keep a copy of the extended attributes of cached files, so
+    // they are there when disconnected. Changes go straight to the
+    // remote, like set_mode.
+
+    fn xattr(&mut self, file: Inode, name: &str) -> VaultResult<Option<Vec<u8>>> {
+        debug!("{}: xattr(file={}, name={})", self.name(), file, name);
+        match self.main().lock().unwrap().xattr(file, name) {
+            // Connected.
+            Ok(value) => {
+                if local_vault::has_file(file, &mut self.database)? {
+                    self.keep_xattr(file, name, value.as_deref())?;
+                }
+                Ok(value)
+            }
+            // Disconnected.
+            Err(VaultError::RpcError(err)) => {
+                if local_vault::has_file(file, &mut self.database)? {
+                    self.database.xattr(file, name)
+                } else {
+                    Err(VaultError::RpcError(err))
                 }
-                Ok(())
             }
             // Other error.
             Err(err) => Err(err),
         }
     }
 
+    fn xattr_names(&mut self, file: Inode) -> VaultResult<Vec<String>> {
+        debug!("{}: xattr_names({})", self.name(), file);
+        match self.main().lock().unwrap().xattr_names(file) {
+            // Connected.
+            Ok(names) => Ok(names),
+            // Disconnected.
+            Err(VaultError::RpcError(err)) => {
+                if local_vault::has_file(file, &mut self.database)? {
+                    self.database.xattr_names(file)
+                } else {
+                    Err(VaultError::RpcError(err))
+                }
+            }
+            // Other error.
+            Err(err) => Err(err),
+        }
+    }
+
+    fn set_xattr(&mut self, file: Inode, name: &str, value: &[u8]) -> VaultResult<()> {
+        info!("{}: set_xattr(file={}, name={})", self.name(), file, name);
+        self.check_writable()?;
+        self.main().lock().unwrap().set_xattr(file, name, value)?;
+        if local_vault::has_file(file, &mut self.database)? {
+            self.keep_xattr(file, name, Some(value))?;
+        }
+        Ok(())
+    }
+
+    fn remove_xattr(&mut self, file: Inode, name: &str) -> VaultResult<()> {
+        info!(
+            "{}: remove_xattr(file={}, name={})",
+            self.name(),
+            file,
+            name
+        );
+        self.check_writable()?;
+        self.main().lock().unwrap().remove_xattr(file, name)?;
+        if local_vault::has_file(file, &mut self.database)? {
+            self.keep_xattr(file, name, None)?;
+        }
+        Ok(())
+    }
+
+    fn info(&mut self, dir: Inode) -> VaultResult<DirInfo> {
+        debug!("{}: info({})", self.name(), dir);
+        match self.main().lock().unwrap().info(dir) {
+            // Connected.
+            Ok(info) => Ok(info),
+            // Disconnected, our cached copy is the best we have.
+            Err(VaultError::RpcError(_)) => self.database.usage(dir),
+            // Other error.
+            Err(err) => Err(err),
+        }
+    }
+
+    fn getlk(&mut self, file: Inode, lock: &FileLock) -> VaultResult<Option<FileLock>> {
+        debug!("{}: getlk(file={}, lock={:?})", self.name(), file, lock);
+        match self.main().lock().unwrap().getlk(file, lock) {
+            // Disconnected, only our own locks are known.
+            Err(VaultError::RpcError(_)) => Ok(self.locks.conflict(file, lock)),
+            result => result,
+        }
+    }
+
+    fn setlk(&mut self, file: Inode, lock: &FileLock) -> VaultResult<()> {
+        info!("{}: setlk(file={}, lock={:?})", self.name(), file, lock);
+        // The lock may have been taken while disconnected.
+        if lock.kind == LockKind::Unlock {
+            self.locks.set(file, lock.clone())?;
+        }
+        match self.main().lock().unwrap().setlk(file, lock) {
+            Err(VaultError::RpcError(_)) => self.locks.set(file, lock.clone()),
+            result => result,
+        }
+    }
+
+    fn statfs(&mut self) -> VaultResult<FsStats> {
+        debug!("{}: statfs()", self.name());
+        match self.main().lock().unwrap().statfs() {
+            // Disconnected, report the disk our copies are on.
+            Err(VaultError::RpcError(_)) if self.sealed().is_some() => {
+                Ok(sealed_stats(self.fd_map.disk_stats()?))
+            }
+            Err(VaultError::RpcError(_)) => self.fd_map.disk_stats(),
+            result => result,
+        }
+    }
+
     fn readdir(&mut self, dir: Inode) -> VaultResult<Vec<FileInfo>> {
         debug!("{}: readdir({})", self.name(), dir);
         match self.main().lock().unwrap().readdir(dir) {
             // Remote is accessible.
             Ok(entries) => {
                 debug!("readdir({}) => remote online", dir);
+                let names: Vec<(Inode, String)> = entries
+                    .iter()
+                    .map(|info| (info.inode, info.name.clone()))
+                    .collect();
+                // Obviously DIR is already in the local vault,
+                // otherwise userspace wouldn't call readdir on it.
+                // (Remote doesn't necessarily have it anymore, in
+                // that case we just return FNE.)
                 for info in entries {
-                    // Obviously DIR is already in the local vault,
-                    // otherwise userspace wouldn't call readdir on
-                    // it. (Remote doesn't necessarily have it
-                    // anymore, in that case we just return FNE.) Now,
-                    // for each of its children, check if it exists in
-                    // the cache and add it if not.
-                    if !local_vault::has_file(info.inode, &mut self.database)? {
-                        // Create an empty file.
-                        if let VaultFileType::File = info.kind {
-                            self.fd_map.get(info.inode, false)?;
-                        }
-                        // Set version to 0 so file is fetched on open.
-                        self.database.add_file(
-                            dir,
-                            info.inode,
-                            &info.name,
-                            info.kind,
-                            info.atime,
-                            info.mtime,
-                            (0, 0),
-                        )?;
+                    self.merge_entry(dir, info)?;
+                }
+                // Links removed on the remote.
+                for link in self.database.links_in(dir)? {
+                    if !names.contains(&link) {
+                        self.database.remove_name(link.0, dir, &link.1)?;
+                        self.vanished(link.0, dir, link.1);
                     }
                 }
                 // Now we have everything in the local database, just
                 // use that.
-                local_vault::readdir(dir, &mut self.database, &mut self.fd_map)
+                let (versions, logs) = (&self.versions, [&self.log, &self.pending_log]);
+                local_vault::readdir(dir, &mut self.database, &self.fd_map, |file| {
+                    unsynced(file, versions, logs)
+                })
             }
             // Disconnected.
             Err(VaultError::RpcError(_)) => {
                 debug!("readdir({}) => remote offline", dir);
                 // Use local database if exists, otherwise return FNE.
-                local_vault::readdir(dir, &mut self.database, &mut self.fd_map)
+                let (versions, logs) = (&self.versions, [&self.log, &self.pending_log]);
+                local_vault::readdir(dir, &mut self.database, &self.fd_map, |file| {
+                    unsynced(file, versions, logs)
+                })
             }
             // Other error, report upward.
             Err(err) => Err(err),
@@ -0,0 +1,157 @@
+/// Clocks of peers. Vault servers put their time in the headers of
+/// every response, and remote vaults compare it with ours to tell how
+/// far ahead (or behind) each peer's clock is, see
+/// `ConnectionLog::note_clock`. Times a peer reports, like mtimes, are
+/// moved to our clock before we show or compare them, and times we
+/// send are moved to the peer's.
+use crate::connections::{now_ms, ConnectionLog, MAX_CLOCK_SKEW_MS};
+use crate::types::*;
+use tonic::codegen::{http, BoxFuture, Context, Poll, Service};
+use tower_layer::Layer;
+
+/// The response header vault servers put their time in, milliseconds
+/// since UNIX epoch.
+pub const SERVER_TIME_HEADER: &str = "monovault-time";
+
+/// Responses that took longer than this to come back don't tell
+/// much about the peer's clock (a long upload only gets its response
+/// at the end), they are ignored.
+pub const MAX_CLOCK_SAMPLE_RTT_MS: u64 = 1000;
+
+/// Return how far ahead of ours the clock of a peer is, in
+/// milliseconds, given when we sent a request, the time in the
+/// response and when it came back. The peer is taken to have answered
+/// halfway. Return None if the response took too long to tell.
+pub fn estimate_offset(sent_ms: u64, server_ms: u64, received_ms: u64) -> Option<i64> {
+    let rtt = received_ms.checked_sub(sent_ms)?;
+    if rtt > MAX_CLOCK_SAMPLE_RTT_MS {
+        return None;
+    }
+    Some(server_ms as i64 - (sent_ms + rtt / 2) as i64)
+}
+
+/// Return the offset to move times by for a peer whose clock is
+/// `offset_ms` ahead of ours: none while within MAX_CLOCK_SKEW_MS.
+/// Estimates of a peer in sync jitter by the round trip, shifting by
+/// them would make its times flicker by a second from one listing to
+/// the next.
+pub fn correction(offset_ms: i64) -> i64 {
+    if offset_ms.abs() <= MAX_CLOCK_SKEW_MS {
+        0
+    } else {
+        offset_ms
+    }
+}
+
+/// Return `time`, in seconds since UNIX epoch, on a clock `offset_ms`
+/// ahead. 0 means no time and stays 0.
+pub fn shift(time: u64, offset_ms: i64) -> u64 {
+    if time == 0 {
+        return 0;
+    }
+    // Round to the nearest second, times are in whole seconds.
+    let offset = (offset_ms as f64 / 1000.0).round() as i64;
+    std::cmp::max(time as i64 + offset, 1) as u64
+}
+
+/// Return the time in the headers of a response, if it has one.
+fn server_time(headers: &http::HeaderMap) -> Option<u64> {
+    headers.get(SERVER_TIME_HEADER)?.to_str().ok()?.parse().ok()
+}
+
+/// Wraps the server's routes in `ServerTime`.
+#[derive(Debug, Clone, Default)]
+pub struct ServerTimeLayer;
+
+impl<S> Layer<S> for ServerTimeLayer {
+    type Service = ServerTime<S>;
+
+    fn layer(&self, inner: S) -> ServerTime<S> {
+        ServerTime { inner }
+    }
+}
+
+/// Puts our time in the headers of each response.
+#[derive(Debug, Clone)]
+pub struct ServerTime<S> {
+    inner: S,
+}
+
+impl<S, B, R> Service<http::Request<B>> for ServerTime<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>> + 'static,
+    S::Future: Send + 'static,
+    B: 'static,
+    R: 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<S::Response, S::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = response.await?;
+            response
+                .headers_mut()
+                .insert(SERVER_TIME_HEADER, http::HeaderValue::from(now_ms()));
+            Ok(response)
+        })
+    }
+}
+
+/// The client side of `ServerTime`: notes the clock of `peer` in
+/// `log` from the time in each response.
+#[derive(Debug, Clone)]
+pub struct TimedChannel<S> {
+    inner: S,
+    log: ConnectionLog,
+    peer: VaultName,
+}
+
+impl<S> TimedChannel<S> {
+    pub fn new(inner: S, log: ConnectionLog, peer: &str) -> TimedChannel<S> {
+        TimedChannel {
+            inner,
+            log,
+            peer: peer.to_string(),
+        }
+    }
+}
+
+impl<S, B, R> Service<http::Request<B>> for TimedChannel<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>> + 'static,
+    S::Future: Send + 'static,
+    B: 'static,
+    R: 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<S::Response, S::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let sent = now_ms();
+        let response = self.inner.call(request);
+        let log = self.log.clone();
+        let peer = self.peer.clone();
+        Box::pin(async move {
+            let response = response.await?;
+            // Peers running older versions don't send their time.
+            if let Some(server_ms) = server_time(response.headers()) {
+                if let Some(offset) = estimate_offset(sent, server_ms, now_ms()) {
+                    log.note_clock(&peer, offset);
+                }
+            }
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,93 @@
+/// Versions of the configuration file. Each configuration records the
+/// version of the schema it was written for in "config_version";
+/// files written before the field existed are version 0. We read
+/// every older version: the file is migrated one version at a time to
+/// the current one before it's parsed, so a deployment keeps working
+/// when the schema changes, eg, when peers become objects instead of
+/// plain addresses. `upgrade` returns the migrated file for the user
+/// to save in place of the old one.
+use crate::types::*;
+use serde_json::{Map, Value};
+
+/// The version of the configuration schema this build writes.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// A migration rewrites a configuration of version N, the index of
+/// the migration in MIGRATIONS, into version N + 1.
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [v0_to_v1, v1_to_v2];
+
+/// Version 1 only adds "config_version", which `migrate` sets.
+fn v0_to_v1(_config: &mut Map<String, Value>) -> Result<(), String> {
+    Ok(())
+}
+
+/// Version 2 makes each peer an object, the address "peers" mapped it
+/// to becomes its "address". Peers already written as objects are
+/// left alone.
+fn v1_to_v2(config: &mut Map<String, Value>) -> Result<(), String> {
+    if let Some(Value::Object(peers)) = config.get_mut("peers") {
+        for (name, peer) in peers.iter_mut() {
+            let address = match peer {
+                Value::String(address) => address.clone(),
+                Value::Object(_) => continue,
+                _ => return Err(format!("the address of peer {} isn't a string", name)),
+            };
+            *peer = serde_json::json!({ "address": address });
+        }
+    }
+    Ok(())
+}
+
+/// Return the version recorded in `config`, 0 if there is none.
+fn version_of(config: &Map<String, Value>) -> Result<u32, String> {
+    match config.get("config_version") {
+        None => Ok(0),
+        Some(value) => value
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| format!("config_version {} isn't a version number", value)),
+    }
+}
+
+/// Parse `content` and bring it to CONFIG_VERSION. Return the
+/// migrated configuration and the version it was written for.
+fn migrate(content: &str) -> Result<(Map<String, Value>, u32), String> {
+    let mut config = match serde_json::from_str(content).map_err(|err| err.to_string())? {
+        Value::Object(config) => config,
+        _ => return Err("the configuration isn't a JSON object".to_string()),
+    };
+    let version = version_of(&config)?;
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "config_version {} is newer than this build of monovault understands ({})",
+            version, CONFIG_VERSION
+        ));
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(&mut config)
+            .map_err(|err| format!("cannot upgrade from config_version {}: {}", from, err))?;
+    }
+    config.insert("config_version".to_string(), Value::from(CONFIG_VERSION));
+    Ok((config, version))
+}
+
+/// Parse the configuration file content `content`, of any version up
+/// to CONFIG_VERSION. Return the configuration and the version the
+/// file was written for.
+pub fn parse(content: &str) -> Result<(Config, u32), String> {
+    let (config, version) = migrate(content)?;
+    let config = serde_json::from_value(Value::Object(config)).map_err(|err| err.to_string())?;
+    Ok((config, version))
+}
+
+/// Return `content` upgraded to CONFIG_VERSION, as pretty-printed
+/// JSON. Settings left out of the file stay left out, so they keep
+/// following the defaults. Fail if the upgraded file doesn't parse.
+pub fn upgrade(content: &str) -> Result<String, String> {
+    let (config, _) = migrate(content)?;
+    let config = Value::Object(config);
+    serde_json::from_value::<Config>(config.clone()).map_err(|err| err.to_string())?;
+    Ok(serde_json::to_string_pretty(&config).unwrap())
+}
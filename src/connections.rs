@@ -0,0 +1,231 @@
+/// A log of connection events with each peer: when we connected,
+/// when the connection went down and how. Remote vaults report how
+/// each RPC went, and the log keeps the changes, the last few for
+/// each peer, so a peer that keeps coming and going shows up in the
+/// "connections" command without digging through logs. It also keeps
+/// how far off each peer's clock is, see src/clock.rs.
+use crate::types::*;
+use log::{debug, error, info};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many events we keep for each peer, older ones are dropped.
+pub const CONNECTION_LOG_SIZE: usize = 64;
+
+/// A peer whose clock is further off ours than this, in milliseconds,
+/// gets a "skewed" event.
+pub const MAX_CLOCK_SKEW_MS: i64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEventKind {
+    /// An RPC went through after the peer was down, or for the first
+    /// time.
+    Connected,
+    /// An RPC failed in transit after the peer was up.
+    Disconnected,
+    /// An RPC failed in transit before the peer was ever up.
+    Unreachable,
+    /// An RPC timed out.
+    Timeout,
+    /// The peer's clock went further off ours than MAX_CLOCK_SKEW_MS.
+    Skewed,
+}
+
+impl ConnectionEventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConnectionEventKind::Connected => "connected",
+            ConnectionEventKind::Disconnected => "disconnected",
+            ConnectionEventKind::Unreachable => "unreachable",
+            ConnectionEventKind::Timeout => "timeout",
+            ConnectionEventKind::Skewed => "skewed",
+        }
+    }
+
+    /// The reverse of `name`.
+    pub fn from_name(name: &str) -> Option<ConnectionEventKind> {
+        match name {
+            "connected" => Some(ConnectionEventKind::Connected),
+            "disconnected" => Some(ConnectionEventKind::Disconnected),
+            "unreachable" => Some(ConnectionEventKind::Unreachable),
+            "timeout" => Some(ConnectionEventKind::Timeout),
+            "skewed" => Some(ConnectionEventKind::Skewed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionEvent {
+    pub peer: VaultName,
+    pub kind: ConnectionEventKind,
+    /// Milliseconds since UNIX epoch.
+    pub time_ms: u64,
+    /// The error, if the connection went down.
+    pub detail: String,
+}
+
+#[derive(Debug, Default)]
+struct PeerLog {
+    /// Whether the last RPC went through, None before the first one.
+    up: Option<bool>,
+    events: VecDeque<ConnectionEvent>,
+    /// How far ahead of ours the peer's clock is, in milliseconds, as
+    /// of the last response that told.
+    clock_offset: Option<i64>,
+}
+
+/// Shared by all the remote vaults, like `BandwidthMeter`. Cloning a
+/// log gives a handle to the same events.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLog {
+    /// Maps peer (vault) name to its events.
+    peers: Arc<Mutex<HashMap<VaultName, PeerLog>>>,
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl ConnectionLog {
+    pub fn new() -> ConnectionLog {
+        ConnectionLog::default()
+    }
+
+    /// Note how an RPC to `peer` went and return its `result`. Only
+    /// network errors count as the connection going down, any other
+    /// answer means the peer is up.
+    pub fn observe<T>(&self, peer: &str, result: VaultResult<T>) -> VaultResult<T> {
+        match &result {
+            Err(VaultError::RpcError(err)) => self.down(peer, err),
+            _ => self.up(peer),
+        }
+        result
+    }
+
+    fn up(&self, peer: &str) {
+        let mut peers = self.peers.lock().unwrap();
+        let log = peers.entry(peer.to_string()).or_default();
+        if log.up != Some(true) {
+            debug!("Connected to {}", peer);
+            log.up = Some(true);
+            push(log, peer, ConnectionEventKind::Connected, "");
+        }
+    }
+
+    fn down(&self, peer: &str, err: &str) {
+        let mut peers = self.peers.lock().unwrap();
+        let log = peers.entry(peer.to_string()).or_default();
+        if log.up == Some(false) {
+            return;
+        }
+        let lowered = err.to_lowercase();
+        let kind = if lowered.contains("timed out") || lowered.contains("deadline") {
+            ConnectionEventKind::Timeout
+        } else if log.up == Some(true) {
+            ConnectionEventKind::Disconnected
+        } else {
+            ConnectionEventKind::Unreachable
+        };
+        info!("Connection to {}: {} ({})", peer, kind.name(), err);
+        log.up = Some(false);
+        push(log, peer, kind, err);
+    }
+
+    /// Note that the clock of `peer` is `offset` milliseconds ahead of
+    /// ours, see `clock::estimate_offset`. Log an event, and an error,
+    /// when it goes further off than MAX_CLOCK_SKEW_MS.
+    pub fn note_clock(&self, peer: &str, offset: i64) {
+        let mut peers = self.peers.lock().unwrap();
+        let log = peers.entry(peer.to_string()).or_default();
+        let was_skewed = log
+            .clock_offset
+            .map_or(false, |old| old.abs() > MAX_CLOCK_SKEW_MS);
+        log.clock_offset = Some(offset);
+        if offset.abs() <= MAX_CLOCK_SKEW_MS {
+            if was_skewed {
+                info!(
+                    "Clock of {} is back within {}ms of ours",
+                    peer,
+                    offset.abs()
+                );
+            }
+            return;
+        }
+        if was_skewed {
+            return;
+        }
+        let detail = format!(
+            "clock {}.{:03}s {}",
+            offset.abs() / 1000,
+            offset.abs() % 1000,
+            if offset > 0 { "ahead" } else { "behind" }
+        );
+        error!(
+            "Clock of {} is off ours by more than {}s ({}), fix its time: mtimes from it are shifted to our clock meanwhile",
+            peer,
+            MAX_CLOCK_SKEW_MS / 1000,
+            detail
+        );
+        push(log, peer, ConnectionEventKind::Skewed, &detail);
+    }
+
+    /// Return whether the last RPC to `peer` went through, or None if
+    /// there was none yet.
+    pub fn is_up(&self, peer: &str) -> Option<bool> {
+        self.peers.lock().unwrap().get(peer)?.up
+    }
+
+    /// Return when the connection to `peer` last went down, by
+    /// disconnecting or timing out, in milliseconds since UNIX epoch,
+    /// or None if it didn't as far as the log remembers.
+    pub fn last_down(&self, peer: &str) -> Option<u64> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(peer)?
+            .events
+            .iter()
+            .rev()
+            .find(|event| {
+                matches!(
+                    event.kind,
+                    ConnectionEventKind::Disconnected | ConnectionEventKind::Timeout
+                )
+            })
+            .map(|event| event.time_ms)
+    }
+
+    /// Return how far ahead of ours the clock of `peer` is, in
+    /// milliseconds, or None if it never told us.
+    pub fn clock_offset(&self, peer: &str) -> Option<i64> {
+        self.peers.lock().unwrap().get(peer)?.clock_offset
+    }
+
+    /// Return the events of every peer, oldest first.
+    pub fn events(&self) -> Vec<ConnectionEvent> {
+        let peers = self.peers.lock().unwrap();
+        let mut events: Vec<ConnectionEvent> = peers
+            .values()
+            .flat_map(|log| log.events.iter().cloned())
+            .collect();
+        events.sort_by_key(|event| event.time_ms);
+        events
+    }
+}
+
+fn push(log: &mut PeerLog, peer: &str, kind: ConnectionEventKind, detail: &str) {
+    if log.events.len() == CONNECTION_LOG_SIZE {
+        log.events.pop_front();
+    }
+    log.events.push_back(ConnectionEvent {
+        peer: peer.to_string(),
+        kind,
+        time_ms: now_ms(),
+        detail: detail.to_string(),
+    });
+}
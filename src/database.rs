@@ -1,23 +1,112 @@
+use crate::manifest::Manifest;
 use crate::types::*;
 use log::{debug, info};
-use rusqlite::params;
+use rusqlite::{params, Transaction, TransactionBehavior};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// Database is used for maintaining meta information, eg, which files
 /// are contained in a directory, what's the type of each file
-/// (regular file or directory). The database has two tables, HasChild
-/// table records parent-child relationships, Type table records file
-/// name and type (file/directory).
+/// (regular file or directory). HasChild table records parent-child
+/// relationships, Type table records file name and type
+/// (file/directory), and Usage table records the cumulative size and
+/// number of entries under each directory. Stats table records the
+/// totals of the whole vault, see `Database::stats`. Manifest table
+/// records the chunk hashes of regular files, see `manifest`.
+/// Journal table records operations on data files in progress, see
+/// `Database::journal`. Generation table counts how many times the
+/// database was opened to add files, see `Database::generation`. Seal table
+/// records when the vault was sealed, see src/seal.rs.
 #[derive(Debug)]
 pub struct Database {
     /// The sqlite database connection.
     db: rusqlite::Connection,
     /// The path containing the database file and cache files.
     db_path: PathBuf,
+    /// True if the Usage table was just created for an existing
+    /// database, or the Stats table was never counted, and they need
+    /// to be rebuilt.
+    usage_stale: bool,
+    /// The generation of files added since the database was opened,
+    /// None until the first is added.
+    generation: Option<u64>,
 }
 
-/// Setup the database if not already set up.
-fn setup_db(connection: &mut rusqlite::Connection) -> VaultResult<()> {
+/// How many times we wait for another connection to let go of the
+/// database before giving up with DatabaseBusy, see `busy_backoff`.
+pub const BUSY_RETRIES: i32 = 24;
+
+/// The longest we wait between two tries, in milliseconds.
+const MAX_BUSY_BACKOFF_MS: u64 = 256;
+
+/// Called by sqlite when another connection holds the lock we need,
+/// for the `attempt`th time (from 0). Wait a bit longer each time and
+/// return true to try again, or false to give up, after about 4
+/// seconds.
+fn busy_backoff(attempt: i32) -> bool {
+    if attempt >= BUSY_RETRIES {
+        return false;
+    }
+    let wait = std::cmp::min(1u64 << std::cmp::min(attempt, 16), MAX_BUSY_BACKOFF_MS);
+    debug!("busy_backoff(attempt={}) => waiting {}ms", attempt, wait);
+    std::thread::sleep(Duration::from_millis(wait));
+    true
+}
+
+/// Start a transaction that changes the database. It takes the write
+/// lock right away, so waiting for it goes through `busy_backoff`; a
+/// transaction that reads first and then writes can't wait, sqlite
+/// fails it as soon as another writer is in the way.
+fn begin(connection: &mut rusqlite::Connection) -> VaultResult<Transaction<'_>> {
+    Ok(connection.transaction_with_behavior(TransactionBehavior::Immediate)?)
+}
+
+/// `Database::maintain` vacuums when more than 1/VACUUM_FREE_SHARE of
+/// the pages are free, and at least VACUUM_MIN_FREE_PAGES of them.
+const VACUUM_FREE_SHARE: u64 = 4;
+const VACUUM_MIN_FREE_PAGES: u64 = 1024;
+
+/// The longest file name we store, in bytes.
+pub const MAX_NAME_LEN: usize = 100;
+
+/// Return an error if `name` is too long to store.
+pub fn check_name(name: &str) -> VaultResult<()> {
+    // We want to count bytes, so len() is correct here.
+    if name.len() > MAX_NAME_LEN {
+        return Err(VaultError::FileNameTooLong(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Limits on extended attributes we store, same as Linux's: the
+/// longest name and value, in bytes, and the most attributes a file
+/// can have, so that listing their names fits in Linux's 64 KiB.
+pub const XATTR_NAME_MAX: usize = 255;
+pub const XATTR_SIZE_MAX: usize = 64 * 1024;
+pub const XATTR_COUNT_MAX: usize = 256;
+
+/// Return an error if extended attribute `name` or its `value` is
+/// too large to store.
+pub fn check_xattr(name: &str, value: &[u8]) -> VaultResult<()> {
+    if name.is_empty() || name.len() > XATTR_NAME_MAX {
+        return Err(VaultError::InvalidArgument(format!(
+            "extended attribute name must be 1 to {} bytes",
+            XATTR_NAME_MAX
+        )));
+    }
+    if value.len() > XATTR_SIZE_MAX {
+        return Err(VaultError::InvalidArgument(format!(
+            "extended attribute {} is larger than {} bytes",
+            name, XATTR_SIZE_MAX
+        )));
+    }
+    Ok(())
+}
+
+/// Setup the database if not already set up. Return true if the
+/// Usage and Stats tables need to be rebuilt.
+fn setup_db(connection: &mut rusqlite::Connection) -> VaultResult<bool> {
     // Create tables.
     connection.execute(
         "create table if not exists HasChild (
@@ -37,7 +126,119 @@ atime int,
 mtime int,
 major_version int,
 minor_version int,
+mode int,
+uid int,
+gid int,
+primary key (file)
+);",
+        [],
+    )?;
+    connection.execute(
+        "create table if not exists Manifest (
+file int,
+major_version int,
+minor_version int,
+size int,
+hashes blob,
 primary key (file)
+);",
+        [],
+    )?;
+    // Names of regular files besides the one in Type, ie, hard
+    // links. Usage counts a file once, under its name in Type.
+    connection.execute(
+        "create table if not exists Link (
+parent int,
+name char(100),
+file int,
+primary key (parent, name)
+);",
+        [],
+    )?;
+    // Extended attributes, see `Vault::set_xattr`.
+    connection.execute(
+        "create table if not exists Xattr (
+file int,
+name text,
+value blob,
+primary key (file, name)
+);",
+        [],
+    )?;
+    // Operations on data files in progress, see `Database::journal`.
+    connection.execute(
+        "create table if not exists Journal (
+file int,
+intent int,
+primary key (file)
+);",
+        [],
+    )?;
+    let usage_existed = has_table(connection, "Usage")?;
+    connection.execute(
+        "create table if not exists Usage (
+dir int,
+size int,
+count int,
+primary key (dir)
+);",
+        [],
+    )?;
+    // A single row, missing until the totals are counted.
+    connection.execute(
+        "create table if not exists Stats (
+files int,
+directories int,
+size int
+);",
+        [],
+    )?;
+    // Databases created before we stored permission bits don't have
+    // the mode column, add it and fill in the permissions we used to
+    // report for everything.
+    if !has_column(connection, "Type", "mode")? {
+        connection.execute("alter table Type add column mode int", [])?;
+        connection.execute(
+            "update Type set mode=? where type=0",
+            [default_mode(VaultFileType::File)],
+        )?;
+        connection.execute(
+            "update Type set mode=? where type=1",
+            [default_mode(VaultFileType::Directory)],
+        )?;
+    }
+    // Owners, NULL for files created before we stored them.
+    if !has_column(connection, "Type", "uid")? {
+        connection.execute("alter table Type add column uid int", [])?;
+        connection.execute("alter table Type add column gid int", [])?;
+    }
+    // The recorded size of regular files, used for usage accounting.
+    if !has_column(connection, "Type", "size")? {
+        connection.execute("alter table Type add column size int default 0", [])?;
+    }
+    // Generations, 0 for files added before we stored them.
+    if !has_column(connection, "Type", "generation")? {
+        connection.execute("alter table Type add column generation int default 0", [])?;
+    }
+    // The hash algorithm of manifests, see `HashAlgorithm::code`.
+    // Manifests recorded before were all SHA-256, code 0.
+    if !has_column(connection, "Manifest", "algorithm")? {
+        connection.execute(
+            "alter table Manifest add column algorithm int default 0",
+            [],
+        )?;
+    }
+    // A single row, missing until the database is first opened.
+    connection.execute(
+        "create table if not exists Generation (
+value int
+);",
+        [],
+    )?;
+    // A single row, missing unless the vault is sealed.
+    connection.execute(
+        "create table if not exists Seal (
+time int
 );",
         [],
     )?;
@@ -45,31 +246,354 @@ primary key (file)
     match connection.query_row::<u64, _, _>("select file from Type where file=1", [], |row| {
         Ok(row.get_unwrap(0))
     }) {
-        Ok(_) => Ok(()),
+        Ok(_) => {
+            let stats_rows: u64 =
+                connection.query_row("select count(*) from Stats", [], |row| row.get(0))?;
+            Ok(!usage_existed || stats_rows == 0)
+        }
         Err(rusqlite::Error::QueryReturnedNoRows) => {
             connection.execute(
-                "insert into Type (file, name, type, atime, mtime, major_version, minor_version) values (1, '/', 1, 0, 0, 1, 0)",
+                "insert into Type (file, name, type, atime, mtime, major_version, minor_version, mode, size) values (1, '/', 1, 0, 0, 1, 0, ?, 0)",
+                [default_mode(VaultFileType::Directory)],
+            )?;
+            connection.execute("insert into Usage (dir, size, count) values (1, 0, 0)", [])?;
+            connection.execute(
+                "insert into Stats (files, directories, size) values (0, 0, 0)",
                 [],
             )?;
-            Ok(())
+            Ok(false)
         }
         Err(err) => Err(err.into()),
     }
 }
 
+/// Bump the count in the Generation table and return it. Call in a
+/// transaction.
+fn next_generation(connection: &rusqlite::Connection) -> VaultResult<u64> {
+    if connection.execute("update Generation set value=value+1", [])? == 0 {
+        connection.execute("insert into Generation (value) values (1)", [])?;
+    }
+    let generation = connection.query_row("select value from Generation", [], |row| row.get(0))?;
+    Ok(generation)
+}
+
+/// Return true if `table` exists in the database.
+fn has_table(connection: &rusqlite::Connection, table: &str) -> VaultResult<bool> {
+    match connection.query_row(
+        "select name from sqlite_master where type='table' and name=?",
+        [table],
+        |row| row.get::<_, String>(0),
+    ) {
+        Ok(_) => Ok(true),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Return the ancestors of `file`, from its parent up to the root.
+fn ancestors(connection: &rusqlite::Connection, file: Inode) -> VaultResult<Vec<Inode>> {
+    let mut result = vec![];
+    let mut current = file;
+    loop {
+        match connection.query_row(
+            "select parent from HasChild where child=?",
+            [current],
+            |row| row.get::<_, Inode>(0),
+        ) {
+            Ok(parent) => {
+                result.push(parent);
+                current = parent;
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(result),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// An operation that changes both the metadata and the data file of
+/// a regular file, see `Database::journal`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intent {
+    /// The data file is created before the metadata.
+    Create,
+    /// The data file is removed after the metadata.
+    Delete,
+    /// The write copy of an open file was synced to disk, it replaces
+    /// the data file on the last close, or on the next start if we
+    /// stop before that.
+    Synced,
+}
+
+fn intent2num(intent: Intent) -> i32 {
+    match intent {
+        Intent::Create => 0,
+        Intent::Delete => 1,
+        Intent::Synced => 2,
+    }
+}
+
+fn num2intent(num: i32) -> Intent {
+    match num {
+        0 => Intent::Create,
+        2 => Intent::Synced,
+        _ => Intent::Delete,
+    }
+}
+
+/// What moving a file over an entry does to the file the entry
+/// names, see `Database::move_over`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Replaced {
+    /// The entry, with its name as stored, goes; the file has other
+    /// names.
+    Name(Inode, String),
+    /// The file goes.
+    File(Inode),
+}
+
+/// Add `size` and `count` to the usage of each directory in `dirs`.
+fn bump_usage(
+    connection: &rusqlite::Connection,
+    dirs: &[Inode],
+    size: i64,
+    count: i64,
+) -> VaultResult<()> {
+    for dir in dirs {
+        connection.execute(
+            "update Usage set size=size+?, count=count+? where dir=?",
+            params![size, count, dir],
+        )?;
+    }
+    Ok(())
+}
+
+/// Add `files` regular files, `directories` directories and `size`
+/// bytes to the totals of the vault.
+fn bump_stats(
+    connection: &rusqlite::Connection,
+    files: i64,
+    directories: i64,
+    size: i64,
+) -> VaultResult<()> {
+    connection.execute(
+        "update Stats set files=files+?, directories=directories+?, size=size+?",
+        params![files, directories, size],
+    )?;
+    Ok(())
+}
+
+/// Move `file`, whose usage is `usage`, under `parent` as `name` in
+/// `transaction`, see `Database::move_file`.
+fn move_in(
+    transaction: &Transaction,
+    file: Inode,
+    parent: Inode,
+    name: &str,
+    usage: &DirInfo,
+) -> VaultResult<()> {
+    let (size, count) = (usage.size as i64, usage.count as i64 + 1);
+    bump_usage(transaction, &ancestors(transaction, file)?, -size, -count)?;
+    let moved =
+        transaction.execute("update HasChild set parent=? where child=?", [parent, file])?;
+    if moved == 0 {
+        return Err(VaultError::FileNotExist(file));
+    }
+    transaction.execute("update Type set name=? where file=?", params![name, file])?;
+    bump_usage(transaction, &ancestors(transaction, file)?, size, count)?;
+    Ok(())
+}
+
+/// Remove `name` in `parent` from the names of `file`, whose usage
+/// is `usage`, in `transaction`, see `Database::remove_name`.
+fn remove_name_in(
+    transaction: &Transaction,
+    file: Inode,
+    parent: Inode,
+    name: &str,
+    usage: &DirInfo,
+) -> VaultResult<()> {
+    let removed = transaction.execute(
+        "delete from Link where parent=? and name=? and file=?",
+        params![parent, name, file],
+    )?;
+    if removed > 0 {
+        return Ok(());
+    }
+    let current: rusqlite::Result<(Inode, String)> = transaction.query_row(
+        "select HasChild.parent, Type.name from HasChild join Type on HasChild.child = Type.file where HasChild.child=?",
+        [file],
+        |row| Ok((row.get_unwrap(0), row.get_unwrap(1))),
+    );
+    match current {
+        Ok(current) if current == (parent, name.to_string()) => (),
+        Ok(_) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(VaultError::FileNotExist(file))
+        }
+        Err(err) => return Err(err.into()),
+    }
+    let (new_parent, new_name): (Inode, String) = transaction.query_row(
+        "select parent, name from Link where file=? limit 1",
+        [file],
+        |row| Ok((row.get_unwrap(0), row.get_unwrap(1))),
+    )?;
+    transaction.execute(
+        "delete from Link where parent=? and name=?",
+        params![new_parent, new_name],
+    )?;
+    move_in(transaction, file, new_parent, &new_name, usage)
+}
+
+/// Remove `child`, of `kind` and recorded size `size`, in
+/// `transaction`, see `Database::remove_file`.
+fn remove_in(
+    transaction: &Transaction,
+    child: Inode,
+    kind: VaultFileType,
+    size: u64,
+) -> VaultResult<()> {
+    // Remove parent-child relationship and file meta.
+    let parent: Inode = transaction.query_row(
+        "select parent from HasChild where child=?",
+        [child],
+        |row| Ok(row.get_unwrap(0)),
+    )?;
+    let dirs = ancestors(transaction, child)?;
+    bump_usage(transaction, &dirs, -(size as i64), -1)?;
+    let (files, directories) = kind_counts(kind);
+    bump_stats(transaction, -files, -directories, -(size as i64))?;
+    transaction.execute(
+        "delete from HasChild where parent=? and child=?",
+        [parent, child],
+    )?;
+    transaction.execute("delete from Type where file=?", [child])?;
+    transaction.execute("delete from Link where file=?1 or parent=?1", [child])?;
+    transaction.execute("delete from Usage where dir=?", [child])?;
+    transaction.execute("delete from Manifest where file=?", [child])?;
+    transaction.execute("delete from Xattr where file=?", [child])?;
+    Ok(())
+}
+
+/// Return the numbers of regular files and directories one `kind`
+/// of file counts for in Stats.
+fn kind_counts(kind: VaultFileType) -> (i64, i64) {
+    match kind {
+        VaultFileType::File => (1, 0),
+        VaultFileType::Directory => (0, 1),
+    }
+}
+
+/// Return true if `table` has a column named `column`.
+fn has_column(connection: &rusqlite::Connection, table: &str, column: &str) -> VaultResult<bool> {
+    let mut statement = connection.prepare(&format!("pragma table_info({})", table))?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get_unwrap(1);
+        if name == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
 impl Database {
     /// The database file is created at `db_path/store.sqlite3`.
     pub fn new(db_path: &Path, db_name: &str) -> VaultResult<Database> {
         let mut connection =
-            rusqlite::Connection::open(&db_path.join(format!("{}.sqlite3", db_name)))?;
-        setup_db(&mut connection)?;
+            rusqlite::Connection::open(db_path.join(format!("{}.sqlite3", db_name)))?;
+        connection.busy_handler(Some(busy_backoff))?;
+        let usage_stale = setup_db(&mut connection)?;
 
         Ok(Database {
             db: connection,
             db_path: db_path.to_path_buf(),
+            usage_stale,
+            generation: None,
         })
     }
 
+    /// Return the generation of files added since the database was
+    /// opened, None if none was. Inodes of deleted files can be handed
+    /// out again after a restart, so each time the database is opened
+    /// the generation goes up with the first file added, and an inode
+    /// and its generation together tell files apart for good, like
+    /// the generation numbers of NFS file handles. Opening it only to
+    /// read, like `du` does, leaves the generation alone.
+    pub fn generation(&self) -> Option<u64> {
+        self.generation
+    }
+
+    /// Record that `file` is of `generation`, for files of another
+    /// vault we keep a copy of.
+    pub fn set_generation(&mut self, file: Inode, generation: u64) -> VaultResult<()> {
+        self.db.execute(
+            "update Type set generation=? where file=?",
+            params![generation, file],
+        )?;
+        Ok(())
+    }
+
+    /// Return when the vault was sealed, None if it isn't, see
+    /// src/seal.rs.
+    pub fn sealed(&self) -> VaultResult<Option<u64>> {
+        match self
+            .db
+            .query_row("select time from Seal", [], |row| row.get(0))
+        {
+            Ok(time) => Ok(Some(time)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Record that the vault was sealed at `time`, unless it already
+    /// was.
+    pub fn seal(&mut self, time: u64) -> VaultResult<()> {
+        let transaction = begin(&mut self.db)?;
+        let sealed: u64 =
+            transaction.query_row("select count(*) from Seal", [], |row| row.get(0))?;
+        if sealed == 0 {
+            transaction.execute("insert into Seal (time) values (?)", [time])?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Keep query plans good as the vault grows: refresh the
+    /// statistics sqlite plans queries with where they are out of
+    /// date, and give back the space of deleted rows (VACUUM) once a
+    /// good share of the file is free. Vacuuming rewrites the whole
+    /// database, so we don't do it for a few free pages. Return the
+    /// bytes vacuuming gave back, None if we didn't vacuum.
+    pub fn maintain(&mut self) -> VaultResult<Option<u64>> {
+        // Analyze no more than about a thousand rows per index, so a
+        // large vault doesn't keep the database busy for long.
+        self.db
+            .execute_batch("pragma analysis_limit=1000; pragma optimize;")?;
+        let page_size: u64 = self
+            .db
+            .query_row("pragma page_size", [], |row| row.get(0))?;
+        let pages: u64 = self
+            .db
+            .query_row("pragma page_count", [], |row| row.get(0))?;
+        let free: u64 = self
+            .db
+            .query_row("pragma freelist_count", [], |row| row.get(0))?;
+        if free < VACUUM_MIN_FREE_PAGES || free * VACUUM_FREE_SHARE < pages {
+            return Ok(None);
+        }
+        info!(
+            "Vacuuming {:?}: {} of {} pages are free",
+            self.db.path(),
+            free,
+            pages
+        );
+        self.db.execute_batch("vacuum")?;
+        let after: u64 = self
+            .db
+            .query_row("pragma page_count", [], |row| row.get(0))?;
+        Ok(Some(pages.saturating_sub(after) * page_size))
+    }
+
     /// Return the `db_path`, the directory in which the database file resides.
     pub fn path(&self) -> PathBuf {
         self.db_path.clone()
@@ -77,21 +601,34 @@ impl Database {
 
     /// Return the largest inode recorded in the database.
     pub fn largest_inode(&self) -> Inode {
-        match self.db.query_row(
-            "select child from HasChild order by child desc",
-            [],
-            |row| Ok(row.get_unwrap(0)),
-        ) {
-            Ok(inode) => inode,
-            _ => 1,
+        self.db
+            .query_row(
+                "select child from HasChild order by child desc",
+                [],
+                |row| Ok(row.get_unwrap(0)),
+            )
+            .unwrap_or(1)
+    }
+
+    /// Return the regular files whose content we have (major version
+    /// isn't 0) and whose atime is before `cutoff`.
+    pub fn files_accessed_before(&self, cutoff: u64) -> VaultResult<Vec<Inode>> {
+        let mut statment = self
+            .db
+            .prepare("select file from Type where type=0 and major_version>0 and atime<?")?;
+        let mut rows = statment.query([cutoff])?;
+        let mut files = vec![];
+        while let Some(row) = rows.next()? {
+            files.push(row.get_unwrap(0));
         }
+        Ok(files)
     }
 
     /// Return attributes of `file`. The `size` field is a dummy value
     /// and needs to be filled.
     pub fn attr(&self, file: Inode) -> VaultResult<FileInfo> {
         let entry = self.db.query_row(
-            "select name, type, atime, mtime, major_version, minor_version from Type where file=?",
+            "select name, type, atime, mtime, major_version, minor_version, mode, (select count(*) from Link where file=?1), uid, gid, generation from Type where file=?1",
             [file],
             |row| {
                 Ok(FileInfo {
@@ -107,6 +644,13 @@ impl Database {
                     atime: row.get_unwrap(2),
                     mtime: row.get_unwrap(3),
                     version: (row.get_unwrap(4), row.get_unwrap(5)),
+                    mode: row.get_unwrap(6),
+                    nlink: row.get_unwrap::<_, u64>(7) + 1,
+                    owner: match (row.get_unwrap(8), row.get_unwrap(9)) {
+                        (Some(uid), Some(gid)) => Some((uid, gid)),
+                        _ => None,
+                    },
+                    generation: row.get_unwrap(10),
                     // Filled by LocalVault::attr().
                     size: 0,
                 })
@@ -116,9 +660,20 @@ impl Database {
         Ok(entry)
     }
 
+    /// Return true if `file` is in the database.
+    pub fn has_file(&self, file: Inode) -> VaultResult<bool> {
+        let count: u64 =
+            self.db
+                .query_row("select count(*) from Type where file=?1", [file], |row| {
+                    row.get(0)
+                })?;
+        Ok(count > 0)
+    }
+
     /// Add a file/directory `child` to the database under `parent`
     /// with `name`. Duplication is detected by primary key
     /// constraints. But normally we shouldn't encounter that.
+    #[allow(clippy::too_many_arguments)]
     pub fn add_file(
         &mut self,
         parent: Inode,
@@ -128,29 +683,44 @@ impl Database {
         atime: u64,
         mtime: u64,
         version: (u64, u64),
+        mode: u32,
     ) -> VaultResult<()> {
         info!(
-            "add_file(parent={}, child={}, name={}, kind={:?})",
-            parent, child, name, kind
+            "add_file(parent={}, child={}, name={}, kind={:?}, mode={:o})",
+            parent, child, name, kind, mode
         );
-        // We want to count bytes, so len() is correct here.
-        if name.len() > 100 {
-            return Err(VaultError::FileNameTooLong(name.to_string()));
-        }
-        let transaction = self.db.transaction()?;
+        check_name(name)?;
+        let transaction = begin(&mut self.db)?;
+        let generation = match self.generation {
+            Some(generation) => generation,
+            None => next_generation(&transaction)?,
+        };
         let type_val = match kind {
             VaultFileType::File => 0,
             VaultFileType::Directory => 1,
         };
         transaction.execute(
-            "insert into Type (file, name, type, atime, mtime, major_version, minor_version) values (?, ?, ?, ?, ?, ?, ?)",
-            params![child, name.to_string(), type_val, atime, mtime, version.0, version.1],
+            "insert into Type (file, name, type, atime, mtime, major_version, minor_version, mode, generation) values (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![child, name.to_string(), type_val, atime, mtime, version.0, version.1, mode, generation],
         )?;
         transaction.execute(
             "insert into HasChild (parent, child) values (?, ?)",
             [parent, child],
         )?;
+        if let VaultFileType::Directory = kind {
+            transaction.execute(
+                "insert into Usage (dir, size, count) values (?, 0, 0)",
+                [child],
+            )?;
+        }
+        bump_usage(&transaction, &ancestors(&transaction, child)?, 0, 1)?;
+        let (files, directories) = kind_counts(kind);
+        bump_stats(&transaction, files, directories, 0)?;
         transaction.commit()?;
+        if self.generation.is_none() {
+            debug!("add_file() => generation {}", generation);
+            self.generation = Some(generation);
+        }
         Ok(())
     }
 
@@ -168,7 +738,7 @@ impl Database {
             "set_attr(file={}, name={:?}, atime={:?}, mtime={:?}, version={:?})",
             file, name, atime, mtime, version
         );
-        let transaction = self.db.transaction()?;
+        let transaction = begin(&mut self.db)?;
         if let Some(name) = name {
             transaction.execute("update Type set name=? where file=?", params![name, file])?;
         }
@@ -188,37 +758,482 @@ impl Database {
         Ok(())
     }
 
+    /// Set the permission bits of `file` to `mode`.
+    pub fn set_mode(&mut self, file: Inode, mode: u32) -> VaultResult<()> {
+        info!("set_mode(file={}, mode={:o})", file, mode);
+        let changed = self
+            .db
+            .execute("update Type set mode=? where file=?", params![mode, file])?;
+        if changed == 0 {
+            Err(VaultError::FileNotExist(file))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Set the owner of `file` to `uid` and `gid`.
+    pub fn set_owner(&mut self, file: Inode, uid: u32, gid: u32) -> VaultResult<()> {
+        info!("set_owner(file={}, uid={}, gid={})", file, uid, gid);
+        let changed = self.db.execute(
+            "update Type set uid=?, gid=? where file=?",
+            params![uid, gid, file],
+        )?;
+        if changed == 0 {
+            Err(VaultError::FileNotExist(file))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return the size recorded for `file`, 0 for directories.
+    fn recorded_size(&self, file: Inode) -> VaultResult<u64> {
+        let size = self.db.query_row(
+            "select size from Type where file=? and type=0",
+            [file],
+            |row| row.get::<_, Option<u64>>(0),
+        );
+        match size {
+            Ok(size) => Ok(size.unwrap_or(0)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Record that regular file `file` is now `size` bytes, and update
+    /// the usage of its ancestors accordingly.
+    pub fn set_size(&mut self, file: Inode, size: u64) -> VaultResult<()> {
+        debug!("set_size(file={}, size={})", file, size);
+        let old_size = self.recorded_size(file)?;
+        let transaction = begin(&mut self.db)?;
+        transaction.execute("update Type set size=? where file=?", params![size, file])?;
+        let dirs = ancestors(&transaction, file)?;
+        bump_usage(&transaction, &dirs, size as i64 - old_size as i64, 0)?;
+        bump_stats(&transaction, 0, 0, size as i64 - old_size as i64)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Return the cumulative usage under `file`. For a regular file,
+    /// that's its recorded size and a count of 0.
+    pub fn usage(&self, file: Inode) -> VaultResult<DirInfo> {
+        match self.attr(file)?.kind {
+            VaultFileType::File => Ok(DirInfo {
+                size: self.recorded_size(file)?,
+                count: 0,
+            }),
+            VaultFileType::Directory => Ok(self.db.query_row(
+                "select size, count from Usage where dir=?",
+                [file],
+                |row| {
+                    Ok(DirInfo {
+                        size: row.get_unwrap(0),
+                        count: row.get_unwrap(1),
+                    })
+                },
+            )?),
+        }
+    }
+
+    /// Return the totals of the vault, kept up to date as files come
+    /// and go, so nobody has to count them.
+    pub fn stats(&self) -> VaultResult<VaultStats> {
+        Ok(self
+            .db
+            .query_row("select files, directories, size from Stats", [], |row| {
+                Ok(VaultStats {
+                    files: row.get_unwrap(0),
+                    directories: row.get_unwrap(1),
+                    size: row.get_unwrap(2),
+                })
+            })?)
+    }
+
+    /// Return true if the usage and stats tables need to be rebuilt
+    /// with `rebuild_usage`.
+    pub fn usage_stale(&self) -> bool {
+        self.usage_stale
+    }
+
+    /// Recompute the usage of every directory and the totals of the
+    /// vault from scratch. `size_of` returns the actual size of a
+    /// regular file, given the file and the size recorded for it.
+    pub fn rebuild_usage(&mut self, size_of: impl Fn(Inode, u64) -> u64) -> VaultResult<()> {
+        info!("rebuild_usage()");
+        let files = {
+            let mut statement = self.db.prepare("select file, type, size from Type")?;
+            let mut rows = statement.query([])?;
+            let mut files = vec![];
+            while let Some(row) = rows.next()? {
+                files.push((
+                    row.get_unwrap::<_, Inode>(0),
+                    row.get_unwrap::<_, i32>(1),
+                    row.get_unwrap::<_, Option<u64>>(2).unwrap_or(0),
+                ));
+            }
+            files
+        };
+        let transaction = begin(&mut self.db)?;
+        transaction.execute("delete from Usage", [])?;
+        transaction.execute("delete from Stats", [])?;
+        transaction.execute(
+            "insert into Stats (files, directories, size) values (0, 0, 0)",
+            [],
+        )?;
+        for &(file, type_val, _) in files.iter() {
+            if type_val == 1 {
+                transaction.execute(
+                    "insert into Usage (dir, size, count) values (?, 0, 0)",
+                    [file],
+                )?;
+            }
+        }
+        for &(file, type_val, recorded) in files.iter() {
+            let size = if type_val == 0 {
+                size_of(file, recorded)
+            } else {
+                0
+            };
+            transaction.execute("update Type set size=? where file=?", params![size, file])?;
+            bump_usage(
+                &transaction,
+                &ancestors(&transaction, file)?,
+                size as i64,
+                1,
+            )?;
+            // The root isn't counted.
+            if file != 1 {
+                let (file_count, dir_count) = if type_val == 0 { (1, 0) } else { (0, 1) };
+                bump_stats(&transaction, file_count, dir_count, size as i64)?;
+            }
+        }
+        transaction.commit()?;
+        self.usage_stale = false;
+        Ok(())
+    }
+
+    /// Record that `intent` on `file` is about to start. The data file
+    /// and the metadata can't change together, so a crash in between
+    /// leaves one without the other; recorded intents are replayed on
+    /// the next start (see `journaled`) and settled once done.
+    pub fn journal(&mut self, file: Inode, intent: Intent) -> VaultResult<()> {
+        debug!("journal(file={}, intent={:?})", file, intent);
+        self.db.execute(
+            "insert or replace into Journal (file, intent) values (?, ?)",
+            params![file, intent2num(intent)],
+        )?;
+        Ok(())
+    }
+
+    /// Record that the operation `journal` recorded on `file` is done.
+    pub fn settle(&mut self, file: Inode) -> VaultResult<()> {
+        debug!("settle({})", file);
+        self.db
+            .execute("delete from Journal where file=?", [file])?;
+        Ok(())
+    }
+
+    /// Like `settle`, but only if the operation recorded on `file` is
+    /// `intent`.
+    pub fn settle_intent(&mut self, file: Inode, intent: Intent) -> VaultResult<()> {
+        debug!("settle_intent(file={}, intent={:?})", file, intent);
+        self.db.execute(
+            "delete from Journal where file=? and intent=?",
+            params![file, intent2num(intent)],
+        )?;
+        Ok(())
+    }
+
+    /// Return the files with an operation that isn't settled.
+    pub fn journaled(&self) -> VaultResult<Vec<(Inode, Intent)>> {
+        let mut statement = self.db.prepare("select file, intent from Journal")?;
+        let mut rows = statement.query([])?;
+        let mut files = vec![];
+        while let Some(row) = rows.next()? {
+            files.push((row.get_unwrap(0), num2intent(row.get_unwrap(1))));
+        }
+        Ok(files)
+    }
+
+    /// Return the parent of `file`, None for the root.
+    pub fn parent(&self, file: Inode) -> VaultResult<Option<Inode>> {
+        match self
+            .db
+            .query_row("select parent from HasChild where child=?", [file], |row| {
+                row.get::<_, Inode>(0)
+            }) {
+            Ok(parent) => Ok(Some(parent)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Return the path of `file` from the root of the vault, like
+    /// "/docs/notes.txt", for messages. A file with several names
+    /// gets the name it was created or last moved under. In a damaged
+    /// tree where the parents of `file` loop, the path starts with
+    /// "..." where they do.
+    pub fn path_of(&self, file: Inode) -> VaultResult<String> {
+        if !self.has_file(file)? {
+            return Err(VaultError::FileNotExist(file));
+        }
+        let mut names = vec![];
+        let mut visited = HashSet::new();
+        let mut current = file;
+        while let Some(parent) = self.parent(current)? {
+            if !visited.insert(current) {
+                names.push("...".to_string());
+                break;
+            }
+            names.push(self.attr(current)?.name);
+            current = parent;
+        }
+        names.reverse();
+        Ok(format!("/{}", names.join("/")))
+    }
+
+    /// Move `file` under `parent` and rename it to `name`, and move
+    /// its usage along. Checking that `parent` is a directory outside
+    /// of `file` is up to the caller.
+    pub fn move_file(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!("move_file(file={}, parent={}, name={})", file, parent, name);
+        check_name(name)?;
+        let usage = self.usage(file)?;
+        let transaction = begin(&mut self.db)?;
+        move_in(&transaction, file, parent, name, &usage)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Like `move_file`, but drop `replaced`, what `name` in `parent`
+    /// names now, in the same transaction, so a crash leaves either
+    /// the old entry or `file` under the name. Removing the data file
+    /// of a replaced file is up to the caller, after this returns.
+    pub fn move_over(
+        &mut self,
+        file: Inode,
+        parent: Inode,
+        name: &str,
+        replaced: Replaced,
+    ) -> VaultResult<()> {
+        info!(
+            "move_over(file={}, parent={}, name={}, replaced={:?})",
+            file, parent, name, replaced
+        );
+        check_name(name)?;
+        let usage = self.usage(file)?;
+        let transaction = match replaced {
+            Replaced::Name(old, old_name) => {
+                let old_usage = self.usage(old)?;
+                let transaction = begin(&mut self.db)?;
+                remove_name_in(&transaction, old, parent, &old_name, &old_usage)?;
+                transaction
+            }
+            Replaced::File(old) => {
+                let (kind, size) = self.removable(old)?;
+                let transaction = begin(&mut self.db)?;
+                remove_in(&transaction, old, kind, size)?;
+                transaction
+            }
+        };
+        move_in(&transaction, file, parent, name, &usage)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Add `name` in `parent` as another name of `file`.
+    pub fn add_link(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!("add_link(file={}, parent={}, name={})", file, parent, name);
+        check_name(name)?;
+        self.db.execute(
+            "insert into Link (parent, name, file) values (?, ?, ?)",
+            params![parent, name, file],
+        )?;
+        Ok(())
+    }
+
+    /// Return the links in `dir`, as (file, name).
+    pub fn links_in(&self, dir: Inode) -> VaultResult<Vec<(Inode, String)>> {
+        let mut statment = self
+            .db
+            .prepare("select file, name from Link where parent=?")?;
+        let mut rows = statment.query([dir])?;
+        let mut links = vec![];
+        while let Some(row) = rows.next()? {
+            links.push((row.get_unwrap(0), row.get_unwrap(1)));
+        }
+        Ok(links)
+    }
+
+    /// Remove `name` in `parent` from the names of `file`, which must
+    /// have another. If it is the name in Type, another name takes
+    /// its place.
+    pub fn remove_name(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!(
+            "remove_name(file={}, parent={}, name={})",
+            file, parent, name
+        );
+        let usage = self.usage(file)?;
+        let transaction = begin(&mut self.db)?;
+        remove_name_in(&transaction, file, parent, name, &usage)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
     /// Remove a file `child` from the database.
     pub fn remove_file(&mut self, child: Inode) -> VaultResult<()> {
         info!("remove_file({})", child);
-        // Check for non empty directory
+        let (kind, size) = self.removable(child)?;
+        let transaction = begin(&mut self.db)?;
+        remove_in(&transaction, child, kind, size)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Return the kind and recorded size of `child`, or an error if
+    /// it is a nonempty directory.
+    fn removable(&self, child: Inode) -> VaultResult<(VaultFileType, u64)> {
         let kind = self.attr(child)?.kind;
         match kind {
             VaultFileType::Directory => {
                 let (_, _, grandchildren) = self.readdir(child)?;
-                let empty = grandchildren.len() == 0;
+                let empty = grandchildren.is_empty();
                 if !empty {
                     return Err(VaultError::DirectoryNotEmpty(child));
                 }
             }
             VaultFileType::File => (),
         }
-        // Remove parent-child relationship and file meta.
-        let parent = self.db.query_row(
-            "select parent from HasChild where child=?",
-            [child],
-            |row| Ok(row.get_unwrap(0)),
+        Ok((kind, self.recorded_size(child)?))
+    }
+
+    /// Return the value of extended attribute `name` of `file`, if
+    /// it has one.
+    pub fn xattr(&self, file: Inode, name: &str) -> VaultResult<Option<Vec<u8>>> {
+        match self.db.query_row(
+            "select value from Xattr where file=? and name=?",
+            params![file, name],
+            |row| row.get(0),
+        ) {
+            Ok(value) => Ok(Some(value)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Return the names of the extended attributes of `file`, sorted.
+    pub fn xattr_names(&self, file: Inode) -> VaultResult<Vec<String>> {
+        let mut statment = self
+            .db
+            .prepare("select name from Xattr where file=? order by name")?;
+        let mut rows = statment.query([file])?;
+        let mut names = vec![];
+        while let Some(row) = rows.next()? {
+            names.push(row.get_unwrap(0));
+        }
+        Ok(names)
+    }
+
+    /// Set extended attribute `name` of `file` to `value`. Fail with
+    /// InvalidArgument if the attribute is too large, or if it is new
+    /// and `file` already has XATTR_COUNT_MAX attributes, see
+    /// `check_xattr`.
+    pub fn set_xattr(&mut self, file: Inode, name: &str, value: &[u8]) -> VaultResult<()> {
+        info!(
+            "set_xattr(file={}, name={}, size={})",
+            file,
+            name,
+            value.len()
+        );
+        check_xattr(name, value)?;
+        let transaction = begin(&mut self.db)?;
+        let (count, exists): (usize, bool) = transaction.query_row(
+            "select count(*), coalesce(sum(name=?2), 0) from Xattr where file=?1",
+            params![file, name],
+            |row| Ok((row.get(0)?, row.get::<_, usize>(1)? > 0)),
         )?;
-        let transaction = self.db.transaction()?;
+        if !exists && count >= XATTR_COUNT_MAX {
+            return Err(VaultError::InvalidArgument(format!(
+                "file {} already has {} extended attributes",
+                file, XATTR_COUNT_MAX
+            )));
+        }
         transaction.execute(
-            "delete from HasChild where parent=? and child=?",
-            [parent, child],
+            "insert or replace into Xattr (file, name, value) values (?, ?, ?)",
+            params![file, name, value],
         )?;
-        transaction.execute("delete from Type where file=?", [child])?;
         transaction.commit()?;
         Ok(())
     }
 
+    /// Remove extended attribute `name` of `file`. Fail with
+    /// XattrNotExist if there is no such attribute.
+    pub fn remove_xattr(&mut self, file: Inode, name: &str) -> VaultResult<()> {
+        info!("remove_xattr(file={}, name={})", file, name);
+        let changed = self.db.execute(
+            "delete from Xattr where file=? and name=?",
+            params![file, name],
+        )?;
+        if changed == 0 {
+            Err(VaultError::XattrNotExist(file, name.to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return the chunk manifest recorded for `file`, if any. It
+    /// may be for an older version of the file, or in an algorithm we
+    /// no longer know, then there is none.
+    pub fn manifest(&self, file: Inode) -> VaultResult<Option<Manifest>> {
+        match self.db.query_row(
+            "select major_version, minor_version, size, hashes, algorithm from Manifest where file=?",
+            [file],
+            |row| {
+                Ok(HashAlgorithm::from_code(row.get_unwrap(4)).map(|algorithm| Manifest {
+                    version: (row.get_unwrap(0), row.get_unwrap(1)),
+                    size: row.get_unwrap(2),
+                    algorithm,
+                    chunks: Manifest::unpack_chunks(&row.get_unwrap::<_, Vec<u8>>(3)),
+                }))
+            },
+        ) {
+            Ok(manifest) => Ok(manifest),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Record `manifest` as the chunk manifest of `file`.
+    pub fn set_manifest(&mut self, file: Inode, manifest: &Manifest) -> VaultResult<()> {
+        self.db.execute(
+            "insert or replace into Manifest (file, major_version, minor_version, size, hashes, algorithm) values (?, ?, ?, ?, ?, ?)",
+            params![
+                file,
+                manifest.version.0,
+                manifest.version.1,
+                manifest.size,
+                manifest.pack_chunks(),
+                manifest.algorithm.code()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Return `dir` and all its descendants with their kinds, in
+    /// post-order (children before their parent), so deleting in this
+    /// order never deletes a nonempty directory.
+    pub fn subtree(&self, dir: Inode) -> VaultResult<Vec<(Inode, VaultFileType)>> {
+        let mut result = vec![];
+        let kind = self.attr(dir)?.kind;
+        if let VaultFileType::Directory = kind {
+            let (_, _, children) = self.readdir(dir)?;
+            for child in children {
+                result.append(&mut self.subtree(child)?);
+            }
+        }
+        result.push((dir, kind));
+        Ok(result)
+    }
+
     /// List directory entries of `file`. Returns a 3-tuple, first
     /// element is inode for ".", second for "..", third a vector of
     /// children. If `file` is the vault root, we don't know "..", so
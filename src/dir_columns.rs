@@ -0,0 +1,183 @@
+/// Directory listings in columns, for the readdir_columns RPC. A
+/// listing sent as one FileInfo message per entry repeats the tag and
+/// length of each field for every entry, and the values themselves
+/// are mostly the same from one entry to the next: inodes created in
+/// a row, times and modes that hardly change, names sharing a prefix.
+/// Sending each field as a packed column of differences from the
+/// previous entry, and names as the part that differs from the
+/// previous name, makes listings of large directories several times
+/// smaller, which matters on slow links.
+use crate::rpc::{self, DirEntryColumns};
+use crate::types::*;
+
+/// Return the difference between `value` and `prev`, wrapping, so
+/// any two u64 values fit.
+fn delta(value: u64, prev: u64) -> i64 {
+    value.wrapping_sub(prev) as i64
+}
+
+/// The reverse of `delta`.
+fn undelta(delta: i64, prev: u64) -> u64 {
+    prev.wrapping_add(delta as u64)
+}
+
+/// Return the length in bytes of the prefix `name` shares with
+/// `prev`, that ends on a character boundary of `name`.
+fn shared_prefix(name: &str, prev: &str) -> usize {
+    let mut len = name
+        .bytes()
+        .zip(prev.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    len
+}
+
+fn kind2num(kind: VaultFileType) -> i32 {
+    match kind {
+        VaultFileType::File => rpc::VaultFileType::File as i32,
+        VaultFileType::Directory => rpc::VaultFileType::Directory as i32,
+    }
+}
+
+fn num2kind(num: i32) -> VaultResult<VaultFileType> {
+    if num == rpc::VaultFileType::File as i32 {
+        Ok(VaultFileType::File)
+    } else if num == rpc::VaultFileType::Directory as i32 {
+        Ok(VaultFileType::Directory)
+    } else {
+        Err(malformed())
+    }
+}
+
+fn malformed() -> VaultError {
+    VaultError::RemoteError("malformed directory listing".to_string())
+}
+
+/// Put `entries` in columns.
+pub(crate) fn encode(entries: &[FileInfo]) -> DirEntryColumns {
+    let mut columns = DirEntryColumns::default();
+    let mut prev: Option<&FileInfo> = None;
+    for entry in entries {
+        let (inode, name, atime, mtime, version, mode) = match prev {
+            Some(prev) => (
+                prev.inode,
+                prev.name.as_str(),
+                prev.atime,
+                prev.mtime,
+                prev.version,
+                prev.mode,
+            ),
+            None => (0, "", 0, 0, (0, 0), 0),
+        };
+        let prefix = shared_prefix(&entry.name, name);
+        columns.inode.push(delta(entry.inode, inode));
+        columns.name_prefix.push(prefix as u32);
+        columns.name_suffix.push(entry.name[prefix..].to_string());
+        columns.kind.push(kind2num(entry.kind));
+        columns.size.push(entry.size);
+        columns.atime.push(delta(entry.atime, atime));
+        columns.mtime.push(delta(entry.mtime, mtime));
+        columns.major_ver.push(delta(entry.version.0, version.0));
+        columns.minor_ver.push(delta(entry.version.1, version.1));
+        columns.mode.push(delta(entry.mode as u64, mode as u64));
+        columns.nlink.push(entry.nlink);
+        prev = Some(entry);
+    }
+    // Most listings have no owners, they cost nothing then.
+    if entries.iter().any(|entry| entry.owner.is_some()) {
+        for entry in entries {
+            let (uid, gid) = match entry.owner {
+                Some((uid, gid)) => (uid as u64 + 1, gid as u64 + 1),
+                None => (0, 0),
+            };
+            columns.uid.push(uid);
+            columns.gid.push(gid);
+        }
+    }
+    if entries.iter().any(|entry| entry.generation != 0) {
+        columns.generation = entries.iter().map(|entry| entry.generation).collect();
+    }
+    columns
+}
+
+/// The reverse of `encode`. Fail if the columns don't have the same
+/// length or a name doesn't make sense.
+pub(crate) fn decode(columns: DirEntryColumns) -> VaultResult<Vec<FileInfo>> {
+    let len = columns.inode.len();
+    let lens = [
+        columns.name_prefix.len(),
+        columns.name_suffix.len(),
+        columns.kind.len(),
+        columns.size.len(),
+        columns.atime.len(),
+        columns.mtime.len(),
+        columns.major_ver.len(),
+        columns.minor_ver.len(),
+        columns.mode.len(),
+    ];
+    if lens.iter().any(|&column_len| column_len != len) {
+        return Err(malformed());
+    }
+    // Older peers don't send link counts.
+    let nlinks = if columns.nlink.is_empty() {
+        vec![1; len]
+    } else if columns.nlink.len() == len {
+        columns.nlink
+    } else {
+        return Err(malformed());
+    };
+    let owned = !columns.uid.is_empty();
+    if owned && (columns.uid.len() != len || columns.gid.len() != len) {
+        return Err(malformed());
+    }
+    let generations = if columns.generation.is_empty() {
+        vec![0; len]
+    } else if columns.generation.len() == len {
+        columns.generation
+    } else {
+        return Err(malformed());
+    };
+    let mut entries: Vec<FileInfo> = Vec::with_capacity(len);
+    for idx in 0..len {
+        let (inode, name, atime, mtime, version, mode) = match entries.last() {
+            Some(prev) => (
+                prev.inode,
+                prev.name.as_str(),
+                prev.atime,
+                prev.mtime,
+                prev.version,
+                prev.mode,
+            ),
+            None => (0, "", 0, 0, (0, 0), 0),
+        };
+        let prefix = columns.name_prefix[idx] as usize;
+        if prefix > name.len() || !name.is_char_boundary(prefix) {
+            return Err(malformed());
+        }
+        let entry = FileInfo {
+            inode: undelta(columns.inode[idx], inode),
+            name: format!("{}{}", &name[..prefix], columns.name_suffix[idx]),
+            kind: num2kind(columns.kind[idx])?,
+            size: columns.size[idx],
+            atime: undelta(columns.atime[idx], atime),
+            mtime: undelta(columns.mtime[idx], mtime),
+            version: (
+                undelta(columns.major_ver[idx], version.0),
+                undelta(columns.minor_ver[idx], version.1),
+            ),
+            mode: undelta(columns.mode[idx], mode as u64) as u32,
+            nlink: std::cmp::max(nlinks[idx], 1),
+            owner: if owned && columns.uid[idx] > 0 && columns.gid[idx] > 0 {
+                Some(((columns.uid[idx] - 1) as u32, (columns.gid[idx] - 1) as u32))
+            } else {
+                None
+            },
+            generation: generations[idx],
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
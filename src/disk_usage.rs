@@ -0,0 +1,109 @@
+/// Where the space in db_path goes, for the "du" command. Each vault
+/// stored in db_path has its database, its data files, and copies of
+/// uploads in the graveyard. Some of it is garbage that nothing
+/// cleans up: the graveyard keeps every upload, partial downloads
+/// stay after the file is evicted or deleted, and a crash can leave
+/// data files of inodes the database no longer has.
+use crate::retire::parse_data_file;
+use crate::types::*;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Bytes a vault takes in db_path, by kind. Bytes are those taken on
+/// disk, a sparse file takes less than its size.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Data files of files in the database: the content of the local
+    /// vault, or what we cached of a peer's.
+    pub data: u64,
+    /// Write copies of open files, see `FdMap`. Left over after a
+    /// crash, they may have writes that didn't make it to the data
+    /// file.
+    pub write_copies: u64,
+    /// Chunks of interrupted downloads, see download.rs. Safe to
+    /// remove, the next download fetches them again.
+    pub partial: u64,
+    /// Data files of inodes the database doesn't have. Safe to
+    /// remove.
+    pub orphaned: u64,
+    /// Copies of uploads, see `background_worker::upload`. Safe to
+    /// remove while monovault isn't running.
+    pub graveyard: u64,
+    /// The database and the operations saved by `save_pending`.
+    pub database: u64,
+}
+
+impl DiskUsage {
+    pub fn total(&self) -> u64 {
+        self.data
+            + self.write_copies
+            + self.partial
+            + self.orphaned
+            + self.graveyard
+            + self.database
+    }
+
+    /// Return the bytes that are safe to remove while monovault
+    /// isn't running.
+    pub fn garbage(&self) -> u64 {
+        self.partial + self.orphaned + self.graveyard
+    }
+}
+
+/// Return the bytes `vault` takes in `store`. `known` tells whether
+/// an inode is in the vault's database.
+pub fn disk_usage(
+    store: &Path,
+    vault: &str,
+    known: impl Fn(Inode) -> VaultResult<bool>,
+) -> VaultResult<DiskUsage> {
+    let mut usage = DiskUsage::default();
+    let db_prefix = format!("{}.sqlite3", vault);
+    let pending = format!("{}-pending.json", vault);
+    for_each_file(&store.join("db"), |name, size| {
+        if name.starts_with(&db_prefix) || name == pending {
+            usage.database += size;
+        }
+        Ok(())
+    })?;
+    for_each_file(&store.join("data"), |name, size| {
+        match parse_data_file(name, vault) {
+            Some((_, "-write")) => usage.write_copies += size,
+            Some((_, "-partial")) => usage.partial += size,
+            Some((inode, _)) if known(inode)? => usage.data += size,
+            Some(_) => usage.orphaned += size,
+            None => (),
+        }
+        Ok(())
+    })?;
+    let graveyard_prefix = format!("vault({})", vault);
+    for_each_file(&store.join("graveyard"), |name, size| {
+        if name.starts_with(&graveyard_prefix) {
+            usage.graveyard += size;
+        }
+        Ok(())
+    })?;
+    Ok(usage)
+}
+
+/// Call `f` with the name and the bytes on disk of each file in
+/// `dir`, if it exists.
+fn for_each_file(dir: &Path, mut f: impl FnMut(&str, u64) -> VaultResult<()>) -> VaultResult<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            // Blocks are 512 bytes whatever the file system's block
+            // size.
+            f(
+                &entry.file_name().to_string_lossy(),
+                metadata.blocks() * 512,
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,316 @@
+/// Downloads of whole files in the background. When a caching vault
+/// opens a file whose copy is out-of-date, it starts a download and
+/// returns right away. Reads wait only for the chunks they need,
+/// taking them from the partial file as they arrive, and the FUSE
+/// layer shows how far the download is in an extended attribute.
+use crate::manifest::{read_chunk, Manifest, MANIFEST_CHUNK_SIZE};
+use crate::types::*;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// How many times we fetch a chunk that fails verification before
+/// giving up on the download.
+const CHUNK_RETRIES: usize = 3;
+
+/// A download of one file, shared by the thread doing it and the
+/// caching vault reading from it.
+pub struct Download {
+    /// What we are downloading.
+    pub manifest: Manifest,
+    /// Where chunks are collected.
+    pub partial_path: PathBuf,
+    state: Mutex<DownloadState>,
+    /// Notified when a chunk arrives and when the download stops.
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct DownloadState {
+    /// For each chunk, whether it's in the partial file.
+    done: Vec<bool>,
+    /// Bytes in the partial file so far.
+    bytes: u64,
+    /// Set when the download stops. The error is compressed because
+    /// everyone waiting gets a copy.
+    result: Option<Result<(), CompressedError>>,
+}
+
+impl Download {
+    fn new(manifest: Manifest, partial_path: PathBuf) -> Download {
+        Download {
+            state: Mutex::new(DownloadState {
+                done: vec![false; manifest.chunks.len()],
+                ..Default::default()
+            }),
+            manifest,
+            partial_path,
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Return (bytes downloaded, bytes in total).
+    pub fn progress(&self) -> (u64, u64) {
+        (self.state.lock().unwrap().bytes, self.manifest.size)
+    }
+
+    /// Return true if the download stopped, completed or not.
+    pub fn finished(&self) -> bool {
+        self.state.lock().unwrap().result.is_some()
+    }
+
+    /// Wait for the download to stop, return whether it completed.
+    pub fn wait(&self) -> VaultResult<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.result.is_none() {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.result.clone().unwrap().map_err(VaultError::from)
+    }
+
+    /// Wait for the chunks covering `size` bytes at `offset` and
+    /// return those bytes, read from the partial file. Fail if the
+    /// download stops without them.
+    pub fn read(&self, offset: i64, size: u64) -> VaultResult<Vec<u8>> {
+        let start = check_range(offset, size)?;
+        let end = std::cmp::min(start + size, self.manifest.size);
+        if start >= end {
+            return Ok(vec![]);
+        }
+        let chunks = self.chunk_of(start)..=self.chunk_of(end - 1);
+        let mut state = self.state.lock().unwrap();
+        while !state.done[chunks.clone()].iter().all(|&done| done) {
+            if let Some(Err(err)) = &state.result {
+                return Err(err.clone().into());
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+        drop(state);
+        // The partial file may be longer than the file, with leftovers
+        // of an older download, so read exactly what we have.
+        let mut fd = File::open(&self.partial_path)?;
+        fd.seek(SeekFrom::Start(start))?;
+        let mut data = vec![0; (end - start) as usize];
+        fd.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn chunk_of(&self, offset: u64) -> usize {
+        (offset / MANIFEST_CHUNK_SIZE) as usize
+    }
+
+    fn is_done(&self, idx: usize) -> bool {
+        self.state.lock().unwrap().done[idx]
+    }
+
+    fn chunk_done(&self, idx: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.done[idx] = true;
+        state.bytes += self.manifest.chunk_range(idx).1;
+        self.changed.notify_all();
+    }
+
+    fn stop(&self, result: VaultResult<()>) {
+        self.state.lock().unwrap().result = Some(result.map_err(CompressedError::from));
+        self.changed.notify_all();
+    }
+}
+
+/// The downloads of a caching vault in progress, or finished but not
+/// yet made our copy.
+pub type Downloads = HashMap<Inode, Arc<Download>>;
+
+/// Start downloading `file` from `remote` as described by `manifest`
+/// into `partial_path` in a new thread, and return the download.
+/// Chunks we already have aren't fetched again: those in the partial
+/// file left by an interrupted download, and those in `data_path`,
+/// our copy of an older version, whose manifest is `ours`. Before
+/// fetching from `remote`, we take what we can from the caches of
+/// `near` peers, see `take_from_near`.
+pub fn start(
+    remote: VaultRef,
+    file: Inode,
+    manifest: Manifest,
+    partial_path: PathBuf,
+    data_path: PathBuf,
+    ours: Manifest,
+    near: Vec<VaultRef>,
+) -> Arc<Download> {
+    let download = Arc::new(Download::new(manifest, partial_path));
+    let handle = Arc::clone(&download);
+    let _ = thread::spawn(move || {
+        let result = run(&remote, file, &handle, &data_path, &ours, &near);
+        match &result {
+            Ok(fetched) => debug!(
+                "download({}) => fetched {} of {} chunks",
+                file,
+                fetched,
+                handle.manifest.chunks.len()
+            ),
+            // The partial file keeps what we got for next time.
+            Err(err) => warn!("download({}) => {:?}", file, err),
+        }
+        handle.stop(result.map(|_| ()));
+    });
+    download
+}
+
+/// Fill the partial file of `download`, return the number of chunks
+/// fetched from `remote`.
+fn run(
+    remote: &VaultRef,
+    file: Inode,
+    download: &Download,
+    data_path: &Path,
+    ours: &Manifest,
+    near: &[VaultRef],
+) -> VaultResult<u64> {
+    let mut partial = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(false)
+        .open(&download.partial_path)?;
+    let mut sources = vec![
+        (
+            Manifest::of_file(&download.partial_path, (0, 0), download.manifest.algorithm)?.index(),
+            partial.try_clone()?,
+        ),
+        (ours.index(), File::open(data_path)?),
+    ];
+    if !near.is_empty() {
+        let vault = remote.lock().unwrap().name();
+        let taken = take_from_near(near, &vault, file, download, &mut partial)?;
+        debug!("download({}) => took {} chunks from peers", file, taken);
+    }
+    remote.lock().unwrap().open(file, OpenMode::R)?;
+    let result = fill_partial(remote, file, download, &mut partial, &mut sources);
+    remote.lock().unwrap().close(file)?;
+    result
+}
+
+/// Fill the chunks of `download` we can from the cache of one of
+/// `near` peers, into `partial`. `vault` is the name of the vault of
+/// `file`. Near peers are faster to reach than the owner of the file,
+/// eg, on the same LAN when the owner is across a slow WAN, so we
+/// only get the manifest from the owner. A peer's copy is used only if
+/// it has the version of the manifest, and each chunk is checked
+/// against the manifest. Return the number of chunks taken.
+fn take_from_near(
+    near: &[VaultRef],
+    vault: &str,
+    file: Inode,
+    download: &Download,
+    partial: &mut File,
+) -> VaultResult<u64> {
+    let manifest = &download.manifest;
+    for peer_ref in near {
+        let mut peer_lck = peer_ref.lock().unwrap();
+        let peer = unpack_to_remote(&mut peer_lck)?;
+        match peer.savage_offer(vault, file) {
+            Ok(Some((version, _))) if version == manifest.version => (),
+            result => {
+                debug!(
+                    "take_from_near({}) => {} offers {:?}",
+                    file,
+                    peer.name(),
+                    result
+                );
+                continue;
+            }
+        }
+        let data = match peer.savage(vault, file, Some(manifest.version)) {
+            Ok((data, _)) => data,
+            Err(err) => {
+                debug!("take_from_near({}) => {:?}", file, err);
+                continue;
+            }
+        };
+        drop(peer_lck);
+        let mut taken = 0;
+        for idx in 0..manifest.chunks.len() {
+            let (offset, len) = manifest.chunk_range(idx);
+            let chunk = match data.get(offset as usize..(offset + len) as usize) {
+                Some(chunk) if manifest.check_chunk(idx, chunk) => chunk,
+                _ => continue,
+            };
+            partial.seek(SeekFrom::Start(offset))?;
+            partial.write_all(chunk)?;
+            download.chunk_done(idx);
+            taken += 1;
+        }
+        return Ok(taken);
+    }
+    Ok(0)
+}
+
+/// Write each chunk of the download into `partial`, taking it from
+/// one of `sources` if one has it, or fetching it from `remote`. Each
+/// source is an index of a local file, see `Manifest::index`, and the
+/// file. Return the number of chunks fetched.
+fn fill_partial(
+    remote: &VaultRef,
+    file: Inode,
+    download: &Download,
+    partial: &mut File,
+    sources: &mut [(HashMap<Vec<u8>, u64>, File)],
+) -> VaultResult<u64> {
+    let manifest = &download.manifest;
+    let mut fetched = 0;
+    for (idx, hash) in manifest.chunks.iter().enumerate() {
+        if download.is_done(idx) {
+            continue;
+        }
+        let mut found = None;
+        for (index, fd) in sources.iter_mut() {
+            if let Some(&offset) = index.get(hash) {
+                // The partial file is a source and we write to it as
+                // we go, so check the content again.
+                let mut data = read_chunk(fd, offset)?;
+                data.truncate(manifest.chunk_range(idx).1 as usize);
+                if manifest.check_chunk(idx, &data) {
+                    found = Some(data);
+                    break;
+                }
+            }
+        }
+        let data = match found {
+            Some(data) => data,
+            None => {
+                fetched += 1;
+                fetch_chunk(remote, file, manifest, idx)?
+            }
+        };
+        partial.seek(SeekFrom::Start(manifest.chunk_range(idx).0))?;
+        partial.write_all(&data)?;
+        download.chunk_done(idx);
+    }
+    Ok(fetched)
+}
+
+/// Fetch chunk `idx` of `file` from `remote`, fetching again if it
+/// doesn't match `manifest`. We lock `remote` for each chunk only, so
+/// the caching vault can use it in between.
+fn fetch_chunk(
+    remote: &VaultRef,
+    file: Inode,
+    manifest: &Manifest,
+    idx: usize,
+) -> VaultResult<Vec<u8>> {
+    let (offset, len) = manifest.chunk_range(idx);
+    for _ in 0..CHUNK_RETRIES {
+        let data = remote.lock().unwrap().read(file, offset as i64, len)?;
+        if manifest.check_chunk(idx, &data) {
+            return Ok(data);
+        }
+        warn!(
+            "chunk {} of {} doesn't match the manifest, fetching again",
+            idx, file
+        );
+    }
+    Err(VaultError::ChunkMismatch(file, offset))
+}
@@ -0,0 +1,205 @@
+/// Structured events about vault activity, for external tools
+/// (indexers, backup hooks, notifications) to subscribe to, either
+/// in-process with `EventBus::subscribe` or through a Unix socket
+/// served by `serve_socket`.
+use crate::types::*;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::io::Write;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How many events we buffer for each subscriber. If a subscriber
+/// falls behind further than this, it misses events.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// Serialized as JSON with an "event" field naming the variant, eg,
+/// {"event":"deleted","vault":"alice","file":12}.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event {
+    /// A file or directory is created.
+    Created {
+        vault: String,
+        file: Inode,
+        parent: Inode,
+        name: String,
+        kind: VaultFileType,
+    },
+    /// A file is modified, `version` is its new version.
+    Modified {
+        vault: String,
+        file: Inode,
+        version: FileVersion,
+    },
+    /// A file or directory is deleted.
+    Deleted { vault: String, file: Inode },
+    /// A file or directory is moved to `parent` under `name`.
+    Renamed {
+        vault: String,
+        file: Inode,
+        parent: Inode,
+        name: String,
+    },
+    /// A regular file gets another name, `name` in `parent`.
+    Linked {
+        vault: String,
+        file: Inode,
+        parent: Inode,
+        name: String,
+    },
+    /// A name of a regular file that has others is removed.
+    Unlinked {
+        vault: String,
+        file: Inode,
+        parent: Inode,
+        name: String,
+    },
+    /// Our change to a file is uploaded to the remote vault.
+    Synced {
+        vault: String,
+        file: Inode,
+        version: FileVersion,
+    },
+    /// The remote vault refused our change to a file because it has
+    /// a newer version.
+    Conflict {
+        vault: String,
+        file: Inode,
+        version: FileVersion,
+    },
+    /// The remote vault has `version` of a file we cache, newer than
+    /// ours: a peer changed it.
+    Outdated {
+        vault: String,
+        file: Inode,
+        version: FileVersion,
+    },
+    /// `name` in `parent`, a name of a file we cache, is gone from the
+    /// remote vault: a peer renamed, unlinked or deleted it.
+    Vanished {
+        vault: String,
+        file: Inode,
+        parent: Inode,
+        name: String,
+    },
+    /// We can't reach the remote vault to upload changes.
+    Offline { vault: String },
+    /// We can reach the remote vault again.
+    Online { vault: String },
+}
+
+impl Event {
+    /// Return the name of the event, same as the "event" field in
+    /// its JSON.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Created { .. } => "created",
+            Event::Modified { .. } => "modified",
+            Event::Deleted { .. } => "deleted",
+            Event::Renamed { .. } => "renamed",
+            Event::Linked { .. } => "linked",
+            Event::Unlinked { .. } => "unlinked",
+            Event::Synced { .. } => "synced",
+            Event::Conflict { .. } => "conflict",
+            Event::Outdated { .. } => "outdated",
+            Event::Vanished { .. } => "vanished",
+            Event::Offline { .. } => "offline",
+            Event::Online { .. } => "online",
+        }
+    }
+
+    /// Return the name of the vault the event is about.
+    pub fn vault(&self) -> &str {
+        match self {
+            Event::Created { vault, .. }
+            | Event::Modified { vault, .. }
+            | Event::Deleted { vault, .. }
+            | Event::Renamed { vault, .. }
+            | Event::Linked { vault, .. }
+            | Event::Unlinked { vault, .. }
+            | Event::Synced { vault, .. }
+            | Event::Conflict { vault, .. }
+            | Event::Outdated { vault, .. }
+            | Event::Vanished { vault, .. }
+            | Event::Offline { vault }
+            | Event::Online { vault } => vault,
+        }
+    }
+}
+
+/// Delivers events to subscribers. Cloning a bus gives a handle to
+/// the same subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<SyncSender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus {
+            subscribers: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Return a receiver that gets every event emitted from now on.
+    /// Drop the receiver to unsubscribe.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Send `event` to all subscribers. This never blocks: if a
+    /// subscriber's buffer is full, it misses this event.
+    pub fn emit(&self, event: Event) {
+        debug!("emit({:?})", &event);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| match tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Event subscriber is lagging, dropping event");
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+/// Listen on Unix socket `path` and stream events to each client, one
+/// JSON object per line. This never returns unless we can't listen.
+pub fn serve_socket(bus: EventBus, path: &Path) -> VaultResult<()> {
+    // Remove the socket left by the last run.
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Serving events on {:?}", path);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let events = bus.subscribe();
+                let _ = thread::spawn(move || stream_events(events, stream));
+            }
+            Err(err) => error!("Cannot accept event subscriber: {:?}", err),
+        }
+    }
+    Ok(())
+}
+
+/// Write each event in `events` to `stream` until the client hangs up.
+fn stream_events(events: Receiver<Event>, mut stream: UnixStream) {
+    for event in events {
+        let mut line = serde_json::to_string(&event).unwrap();
+        line.push('\n');
+        if stream.write_all(line.as_bytes()).is_err() {
+            debug!("Event subscriber disconnected");
+            return;
+        }
+    }
+}
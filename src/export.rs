@@ -0,0 +1,145 @@
+/// Export a vault subtree to a plain directory on the host. The vault
+/// may be a peer we don't trust, eg, the host of a share link: names
+/// that would leave `dest` are refused, permission bits other than
+/// rwx are dropped, and a directory listed twice isn't exported
+/// again.
+use crate::types::*;
+use log::info;
+use std::collections::HashSet;
+use std::fs::{self, File, FileTimes};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time;
+
+/// Size of each read from the vault when copying data.
+const EXPORT_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// What an export did.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExportStats {
+    /// Number of regular files written.
+    pub files: u64,
+    /// Number of directories written.
+    pub directories: u64,
+    /// Number of bytes written.
+    pub bytes: u64,
+}
+
+/// Return true if `name` can be a file name on the host: not empty,
+/// "." or "..", and without "/" or NUL.
+pub fn safe_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/') && !name.contains('\0')
+}
+
+/// Return the permission bits of `mode` we set on exported files,
+/// without setuid, setgid and sticky.
+fn export_mode(mode: u32) -> u32 {
+    mode & 0o777
+}
+
+/// Return file times for `atime` and `mtime`, in seconds since epoch.
+pub(crate) fn to_file_times(atime: u64, mtime: u64) -> FileTimes {
+    FileTimes::new()
+        .set_accessed(time::UNIX_EPOCH + time::Duration::from_secs(atime))
+        .set_modified(time::UNIX_EPOCH + time::Duration::from_secs(mtime))
+}
+
+/// Copy directory `source` of `vault` and everything under it into
+/// `dest` on the host, preserving structure, permission bits and
+/// timestamps. `dest` is created if it doesn't exist, existing files
+/// in it are overwritten.
+pub fn export_tree(vault: &mut impl Vault, source: Inode, dest: &Path) -> VaultResult<ExportStats> {
+    info!("export_tree(source={}, dest={:?})", source, dest);
+    let mut stats = ExportStats::default();
+    let info = vault.attr(source)?;
+    if let VaultFileType::File = info.kind {
+        return Err(VaultError::NotDirectory(source));
+    }
+    export_dir(vault, &info, dest, &mut stats, &mut HashSet::new())?;
+    Ok(stats)
+}
+
+/// Copy regular file `file` of `vault` to `dest` on the host, like
+/// `export_tree` does for each file.
+pub fn export_one(vault: &mut impl Vault, file: Inode, dest: &Path) -> VaultResult<ExportStats> {
+    info!("export_one(file={}, dest={:?})", file, dest);
+    let info = vault.attr(file)?;
+    if let VaultFileType::Directory = info.kind {
+        return Err(VaultError::IsDirectory(file));
+    }
+    vault.open(file, OpenMode::R)?;
+    let result = export_file(vault, &info, dest);
+    vault.close(file)?;
+    Ok(ExportStats {
+        files: 1,
+        directories: 0,
+        bytes: result?,
+    })
+}
+
+/// Copy directory `dir` to `dest`, see `export_tree`. `visited` has
+/// the directories already copied, so a listing with cycles doesn't
+/// go on forever.
+fn export_dir(
+    vault: &mut impl Vault,
+    dir: &FileInfo,
+    dest: &Path,
+    stats: &mut ExportStats,
+    visited: &mut HashSet<Inode>,
+) -> VaultResult<()> {
+    if !visited.insert(dir.inode) {
+        return Err(VaultError::InvalidArgument(format!(
+            "directory {} is listed more than once",
+            dir.inode
+        )));
+    }
+    if !dest.exists() {
+        fs::create_dir(dest)?;
+    }
+    stats.directories += 1;
+    for child in vault.readdir(dir.inode)? {
+        if !safe_name(&child.name) {
+            return Err(VaultError::InvalidArgument(format!(
+                "{:?} in directory {} is not a valid file name",
+                child.name, dir.inode
+            )));
+        }
+        let path = dest.join(&child.name);
+        match child.kind {
+            VaultFileType::Directory => export_dir(vault, &child, &path, stats, visited)?,
+            VaultFileType::File => {
+                vault.open(child.inode, OpenMode::R)?;
+                let result = export_file(vault, &child, &path);
+                vault.close(child.inode)?;
+                stats.bytes += result?;
+                stats.files += 1;
+            }
+        }
+    }
+    // Set times last, writing children changes the directory's mtime.
+    fs::set_permissions(dest, fs::Permissions::from_mode(export_mode(dir.mode)))?;
+    File::open(dest)?.set_times(to_file_times(dir.atime, dir.mtime))?;
+    Ok(())
+}
+
+/// Copy the content of the opened `file` to `dest`. Return the
+/// number of bytes copied.
+fn export_file(vault: &mut impl Vault, file: &FileInfo, dest: &Path) -> VaultResult<u64> {
+    let mut fd = File::create(dest)?;
+    let mut offset = 0;
+    // Go by the recorded size rather than waiting for an empty read,
+    // reads past EOF aren't guaranteed to come back empty.
+    while offset < file.size {
+        let data = vault.read(file.inode, offset as i64, EXPORT_CHUNK_SIZE)?;
+        if data.is_empty() {
+            break;
+        }
+        let len = std::cmp::min(data.len() as u64, file.size - offset);
+        fd.write_all(&data[..len as usize])?;
+        offset += len;
+    }
+    fd.set_times(to_file_times(file.atime, file.mtime))?;
+    fd.set_permissions(fs::Permissions::from_mode(export_mode(file.mode)))?;
+    Ok(offset)
+}
@@ -0,0 +1,121 @@
+/// Inject failures into RPCs to remote vaults, so the offline,
+/// conflict and retry paths can be exercised on purpose rather than
+/// only when the network goes down. Used by tests, and by the
+/// undocumented "faults" config option.
+use crate::types::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+use std::sync::Mutex;
+use std::thread;
+use std::time;
+
+/// Rates are fractions between 0 and 1.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FaultConfig {
+    /// Fail this fraction of RPCs as if the connection dropped.
+    #[serde(default)]
+    pub drop_rate: f64,
+    /// Delay every RPC by this many milliseconds.
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Flip a byte in this fraction of received data chunks, and of
+    /// the chunks sent by submits.
+    #[serde(default)]
+    pub corrupt_rate: f64,
+    /// Reject this fraction of writes and submits with a remote
+    /// error.
+    #[serde(default)]
+    pub write_fail_rate: f64,
+    /// Seed of the random generator, the same seed gives the same
+    /// faults for the same sequence of calls.
+    #[serde(default)]
+    pub seed: u64,
+}
+
+#[derive(Debug)]
+pub struct FaultInjector {
+    config: FaultConfig,
+    /// State of the xorshift generator, never 0.
+    state: Mutex<u64>,
+    /// Number of faults injected so far.
+    injected: AtomicU64,
+}
+
+impl FaultInjector {
+    pub fn new(config: FaultConfig) -> FaultInjector {
+        let seed = if config.seed == 0 {
+            0x9e3779b97f4a7c15
+        } else {
+            config.seed
+        };
+        FaultInjector {
+            config,
+            state: Mutex::new(seed),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the number of faults injected so far, so tests can
+    /// wait for them rather than for a while.
+    pub fn injected(&self) -> u64 {
+        self.injected.load(SeqCst)
+    }
+
+    /// Return a random number.
+    fn next(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /// Return true with probability `rate`, counting it as a fault.
+    fn roll(&self, rate: f64) -> bool {
+        let hit = rate > 0.0 && (self.next() as f64 / u64::MAX as f64) < rate;
+        if hit {
+            self.injected.fetch_add(1, SeqCst);
+        }
+        hit
+    }
+
+    /// Called before each RPC named `call`. Delay it, or fail it as
+    /// a dropped connection.
+    pub fn before_call(&self, call: &str) -> VaultResult<()> {
+        if self.config.delay_ms > 0 {
+            thread::sleep(time::Duration::from_millis(self.config.delay_ms));
+        }
+        if self.roll(self.config.drop_rate) {
+            warn!("inject: drop {}", call);
+            return Err(VaultError::RpcError(format!("injected: dropped {}", call)));
+        }
+        Ok(())
+    }
+
+    /// Called before each write or submit RPC named `call`.
+    pub fn before_write(&self, call: &str) -> VaultResult<()> {
+        if self.roll(self.config.write_fail_rate) {
+            warn!("inject: fail {}", call);
+            return Err(VaultError::RemoteError(format!(
+                "injected: failed {}",
+                call
+            )));
+        }
+        Ok(())
+    }
+
+    /// Called on each received or submitted data chunk, maybe
+    /// corrupt it.
+    pub fn on_chunk(&self, data: &mut [u8]) {
+        if !data.is_empty() && self.roll(self.config.corrupt_rate) {
+            let idx = (self.next() % data.len() as u64) as usize;
+            warn!(
+                "inject: corrupt byte {} of a {} byte chunk",
+                idx,
+                data.len()
+            );
+            data[idx] ^= 0xff;
+        }
+    }
+}
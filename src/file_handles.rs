@@ -0,0 +1,122 @@
+/// File handles the FUSE layer gives out on open and create, one per
+/// open(2), so concurrent opens of the same file each keep what they
+/// were opened for. The kernel passes the handle back with every read,
+/// write, flush and release of the descriptor. File offsets stay with
+/// the kernel, which sends one with every read and write.
+use crate::types::OpenMode;
+use std::collections::{HashMap, HashSet};
+
+/// A file the kernel opened, one per open(2) (an open file
+/// description), shared by dup(2) and fork(2).
+#[derive(Debug)]
+pub struct OpenFile {
+    pub ino: u64,
+    /// What the file was opened for, from the open flags.
+    pub mode: OpenMode,
+    /// True if the file was opened with O_APPEND, its writes go to
+    /// the end of the file wherever the kernel says they go.
+    pub append: bool,
+    /// Lock owners that took locks through this file. flock(2) locks
+    /// belong to the open file, and POSIX locks to a process, but
+    /// both go once every descriptor of the file is closed.
+    pub lock_owners: HashSet<u64>,
+}
+
+/// Return what a file opened with `flags` is opened for. Vaults only
+/// know R and RW, O_WRONLY opens read-write.
+pub fn open_mode(flags: i32) -> OpenMode {
+    if flags & libc::O_ACCMODE == libc::O_RDONLY {
+        OpenMode::R
+    } else {
+        OpenMode::RW
+    }
+}
+
+/// The open files, by handle.
+#[derive(Debug)]
+pub struct FileHandles {
+    handles: HashMap<u64, OpenFile>,
+    /// The next handle to give out.
+    next: u64,
+}
+
+impl FileHandles {
+    pub fn new() -> FileHandles {
+        FileHandles {
+            handles: HashMap::new(),
+            next: 1,
+        }
+    }
+
+    /// Return a new handle for an open of `ino` with `flags`.
+    pub fn open(&mut self, ino: u64, flags: i32) -> u64 {
+        let fh = self.next;
+        self.next += 1;
+        self.handles.insert(
+            fh,
+            OpenFile {
+                ino,
+                mode: open_mode(flags),
+                append: flags & libc::O_APPEND != 0,
+                lock_owners: HashSet::new(),
+            },
+        );
+        fh
+    }
+
+    /// Return the open file of `fh`, if it is open.
+    pub fn get(&self, fh: u64) -> Option<&OpenFile> {
+        self.handles.get(&fh)
+    }
+
+    /// Return true if `fh` was opened read-only.
+    pub fn read_only(&self, fh: u64) -> bool {
+        matches!(
+            self.get(fh),
+            Some(OpenFile {
+                mode: OpenMode::R,
+                ..
+            })
+        )
+    }
+
+    /// Return true if `fh` was opened with O_APPEND.
+    pub fn append(&self, fh: u64) -> bool {
+        matches!(self.get(fh), Some(OpenFile { append: true, .. }))
+    }
+
+    /// Remember that `lock_owner` took a lock through `fh`.
+    pub fn add_lock_owner(&mut self, fh: u64, lock_owner: u64) {
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.lock_owners.insert(lock_owner);
+        }
+    }
+
+    /// A descriptor of `ino` is closed by `lock_owner`: forget it on
+    /// every handle of `ino` and return true if it took locks through
+    /// any, which close(2) releases.
+    pub fn forget_lock_owner(&mut self, ino: u64, lock_owner: u64) -> bool {
+        let mut held = false;
+        for handle in self.handles.values_mut() {
+            if handle.ino == ino {
+                held |= handle.lock_owners.remove(&lock_owner);
+            }
+        }
+        held
+    }
+
+    /// Forget `fh`, the last descriptor of it is closed. Return the
+    /// lock owners that took locks through it.
+    pub fn release(&mut self, fh: u64) -> HashSet<u64> {
+        match self.handles.remove(&fh) {
+            Some(handle) => handle.lock_owners,
+            None => HashSet::new(),
+        }
+    }
+}
+
+impl Default for FileHandles {
+    fn default() -> Self {
+        FileHandles::new()
+    }
+}
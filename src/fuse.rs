@@ -1,14 +1,26 @@
 /// Implement the FUSE API.
+use crate::background_worker::BackgroundOp;
+use crate::caching_remote;
+use crate::connections::ConnectionLog;
+use crate::database::{MAX_NAME_LEN, XATTR_NAME_MAX, XATTR_SIZE_MAX};
+use crate::events::{Event, EventBus};
+use crate::file_handles::{open_mode, FileHandles};
+use crate::interrupt;
+use crate::ioctl::{Command, SyncState, Where};
+use crate::locks::{self, FileLock, LockKind, LockOwner};
 use crate::types::*;
+use crate::vault_queue::{ReadFlights, Slot, VaultQueue};
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyOpen, ReplyWrite, Request,
+    FileAttr, FileType, Filesystem, MountOption, Notifier, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
 };
-use log::{debug, error, info, log};
-use std::boxed::Box;
-use std::collections::HashMap;
+use log::{debug, error, info, log, warn};
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use std::time;
 
 // The fuse layer does mainly two things: it translates between the
@@ -26,12 +38,196 @@ use std::time;
 // vault, we translate it into the global inode by slapping the
 // vault's prefix onto it.
 pub struct FS {
-    /// A vector of all the vaults, this is just for `readdir_vaults`.
+    /// A vector of all the vaults, this is just for `destroy`.
     vaults: Vec<VaultRef>,
-    /// Maps inode to its belonging vault.
+    /// Maps inode to its belonging vault. Besides vault roots,
+    /// entries are dropped once the kernel forgets them, or once they
+    /// are gone from their directory if the kernel never looked them
+    /// up, see `drop_inode`.
     vault_map: HashMap<u64, VaultRef>,
+    /// Maps directory inode to its parent's inode, for "..".
+    parent_map: HashMap<u64, u64>,
+    /// Maps inode to the number of lookups the kernel holds on it,
+    /// ie, the number of entries we replied minus what it forgot.
+    lookup_count: HashMap<u64, u64>,
+    /// Maps directory inode to its recent listing, so lookups don't
+    /// need a readdir each time.
+    lookup_cache: HashMap<u64, DirListing>,
+    /// Directories whose listing in `lookup_cache` lost a name since,
+    /// see `Invalidator`.
+    stale_listings: Arc<Mutex<HashSet<u64>>>,
+    /// Maps directory handle to the listing taken when reading from
+    /// offset 0. Later calls continue from this snapshot, so entries
+    /// aren't skipped or repeated if the directory changes in
+    /// between.
+    dir_handles: HashMap<u64, Vec<FileInfo>>,
+    /// The next directory handle to allocate.
+    next_dir_handle: u64,
+    /// The open files, see src/file_handles.rs.
+    file_handles: FileHandles,
     /// The base inode for each vault.
     vault_base_map: HashMap<String, u64>,
+    /// Maps the base inode of each vault to the queue of requests
+    /// its thread serves, see src/vault_queue.rs.
+    queues: HashMap<u64, VaultQueue>,
+    /// Reads queued or running, which identical reads join rather
+    /// than asking the vault again.
+    read_flights: ReadFlights<ReplyData>,
+    /// Attributes of vault roots, shared with the threads that fetch
+    /// them.
+    root_attrs: Arc<Mutex<RootAttrs>>,
+    /// The largest read and write we serve, see `Config::max_read`.
+    max_read: u32,
+    max_write: u32,
+    /// Kernel settings, see `Config::writeback_cache`.
+    writeback_cache: bool,
+    /// If true, open files bypass the page cache, see
+    /// `Config::direct_io`.
+    direct_io: bool,
+    /// Maps the base inode of each vault to how names in it are
+    /// normalized, see `normalize`.
+    normalization: HashMap<u64, NameNormalization>,
+    /// The base inodes of vaults whose names are compared ignoring
+    /// case, see `Config::case_insensitive`.
+    case_insensitive: HashSet<u64>,
+    /// Names in a directory that differ only by case that we already
+    /// warned about, see `cache_listing`.
+    case_conflicts: HashSet<(u64, String)>,
+    max_background: u16,
+    congestion_threshold: u16,
+    /// Set when the kernel starts the session, see `FS::mounted`.
+    mounted: Arc<AtomicBool>,
+    /// The name of the local vault, which tells our locks from the
+    /// locks of other hosts, see src/locks.rs.
+    host: VaultName,
+    /// Maps the root inode of each peer's vault to the peer's name
+    /// and whether we cache it, see `peer_status`.
+    peer_roots: HashMap<u64, (VaultName, bool)>,
+    /// Tells whether peers are up, see `peer_status`.
+    connections: ConnectionLog,
+    /// Tells the kernel to drop entries we delete behind its back,
+    /// see `Command::DeleteTree`.
+    invalidator: Option<Invalidator>,
+    /// Whether the mount is read-only, or the kernel checks
+    /// permissions, see `set_mount_options`. The kernel does neither
+    /// for ioctls.
+    read_only: bool,
+    default_permissions: bool,
+}
+
+/// The name -> inode mapping of a directory, as of `fetched`.
+struct DirListing {
+    fetched: time::Instant,
+    entries: HashMap<String, u64>,
+    /// In vaults that ignore case, maps names with case folded to
+    /// their inode, see `FS::name_key`.
+    folded: HashMap<String, u64>,
+}
+
+/// Attributes of vault roots. We fetch them in background threads,
+/// so a dead peer can't hang listing the mount root.
+#[derive(Default)]
+struct RootAttrs {
+    /// Maps vault root inode to its attributes and when we got them.
+    known: HashMap<u64, (time::Instant, FileInfo)>,
+    /// Roots that have a fetch in flight.
+    fetching: HashSet<u64>,
+}
+
+/// Tells the kernel to drop what it cached of files peers change,
+/// rather than serve it until `ttl` runs out. Caching vaults report
+/// what they learn from their remote as events, see `Event::Outdated`
+/// and `Event::Vanished`; a thread of ours turns them into
+/// notifications. Notifying from a vault thread could deadlock: the
+/// kernel may wait on the very request the thread serves.
+#[derive(Clone)]
+pub struct Invalidator {
+    /// Notifies the kernel of the running session, if any.
+    notifier: Arc<Mutex<Option<Notifier>>>,
+    /// Shared with each `FS`, see `FS::stale_listings`.
+    stale_listings: Arc<Mutex<HashSet<u64>>>,
+}
+
+/// How long we wait for vault roots' attributes before making do
+/// without them.
+const ROOT_ATTR_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+/// Extended attributes that we synthesize from vault metadata rather
+/// than store. They report the cumulative size and entry count under
+/// a directory (see `Vault::info`).
+const XATTR_USAGE_SIZE: &str = "user.monovault.usage.size";
+const XATTR_USAGE_COUNT: &str = "user.monovault.usage.count";
+/// Progress of the download of a remote file being fetched, as
+/// "<bytes downloaded>/<bytes in total>". Only files being downloaded
+/// have it.
+const XATTR_FETCH_PROGRESS: &str = "user.monovault.fetch.progress";
+/// Whether the peer of a vault is reachable, see `peer_status`. Only
+/// vault roots have it.
+const XATTR_STATUS: &str = "user.monovault.status";
+/// When the vault was sealed, in seconds since the epoch, see
+/// src/seal.rs. Only roots of sealed vaults have it.
+const XATTR_SEALED: &str = "user.monovault.sealed";
+/// Names with this prefix are ours, they can't be set or removed.
+const XATTR_RESERVED_PREFIX: &str = "user.monovault.";
+
+/// How long a request served by the session thread waits for the
+/// requests queued for its vault to finish before giving up with
+/// EAGAIN, so a stuck vault can't hold up the session.
+const QUEUE_WAIT: time::Duration = time::Duration::from_secs(1);
+
+/// The block size we report to statfs, capacity is counted in it.
+const STATFS_BLOCK_SIZE: u64 = 4096;
+
+/// Error for "no such attribute".
+#[cfg(target_os = "macos")]
+const ENOATTR: libc::c_int = libc::ENOATTR;
+#[cfg(not(target_os = "macos"))]
+const ENOATTR: libc::c_int = libc::ENODATA;
+
+/// Rename flag for "fail if the target exists", see renameat2(2).
+#[cfg(target_os = "macos")]
+const RENAME_NOREPLACE: u32 = libc::RENAME_EXCL as u32;
+#[cfg(not(target_os = "macos"))]
+const RENAME_NOREPLACE: u32 = libc::RENAME_NOREPLACE as u32;
+
+/// Return the kind of lock of fcntl lock type `typ`.
+// The lock types are c_short on macOS.
+#[allow(clippy::unnecessary_cast)]
+fn lock_kind(typ: i32) -> VaultResult<LockKind> {
+    if typ == libc::F_RDLCK as i32 {
+        Ok(LockKind::Read)
+    } else if typ == libc::F_WRLCK as i32 {
+        Ok(LockKind::Write)
+    } else if typ == libc::F_UNLCK as i32 {
+        Ok(LockKind::Unlock)
+    } else {
+        Err(VaultError::InvalidArgument(format!(
+            "unknown lock type {}",
+            typ
+        )))
+    }
+}
+
+/// The reverse of `lock_kind`.
+#[allow(clippy::unnecessary_cast)]
+fn lock_type(kind: LockKind) -> i32 {
+    match kind {
+        LockKind::Read => libc::F_RDLCK as i32,
+        LockKind::Write => libc::F_WRLCK as i32,
+        LockKind::Unlock => libc::F_UNLCK as i32,
+    }
+}
+
+/// Reply `data` to a getxattr/listxattr request. If `size` is 0,
+/// the kernel is asking for the size of the data.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
 }
 
 /// Return a dummy timestamp.
@@ -44,7 +240,22 @@ fn ttl() -> time::Duration {
     time::Duration::new(30, 0)
 }
 
-fn attr(ino: Inode, kind: FileType, size: u64, atime: u64, mtime: u64) -> FileAttr {
+/// How long the kernel remembers that a name doesn't exist. Shells
+/// and editors look up many names that don't exist, over and over;
+/// this is shorter than `ttl` so a file a peer creates shows up soon.
+fn negative_ttl() -> time::Duration {
+    time::Duration::new(5, 0)
+}
+
+fn attr(
+    ino: Inode,
+    kind: FileType,
+    size: u64,
+    atime: u64,
+    mtime: u64,
+    perm: u32,
+    nlink: u64,
+) -> FileAttr {
     FileAttr {
         ino,
         size,
@@ -52,38 +263,347 @@ fn attr(ino: Inode, kind: FileType, size: u64, atime: u64, mtime: u64) -> FileAt
         // Last access.
         atime: time::UNIX_EPOCH
             .checked_add(time::Duration::new(atime, 0))
-            .or(Some(ts()))
-            .unwrap(),
+            .unwrap_or_else(ts),
         // Last modification.
         mtime: time::UNIX_EPOCH
             .checked_add(time::Duration::new(mtime, 0))
-            .or(Some(ts()))
-            .unwrap(),
+            .unwrap_or_else(ts),
         // Last change.
         ctime: time::UNIX_EPOCH
             .checked_add(time::Duration::new(mtime, 0))
-            .or(Some(ts()))
-            .unwrap(),
+            .unwrap_or_else(ts),
         // Creation time (macOS only).
         crtime: ts(),
         blksize: 1,
         kind,
-        perm: match kind {
-            FileType::RegularFile => 0o666,
-            FileType::Directory => 0o777,
-            _ => 0o666,
-        },
+        perm: (perm & 0o7777) as u16,
         // Number of hard links.
-        nlink: 1,
-        uid: 1,
-        gid: 1,
+        nlink: nlink as u32,
+        uid: default_owner().0,
+        gid: default_owner().1,
         // root device
         rdev: 0,
-        /// Flags (macOS only, see chflags(2))
+        // Flags (macOS only, see chflags(2))
         flags: 0,
     }
 }
 
+/// Give new `file` of `kind` the permission bits of `mode` not in
+/// `umask`, like open(2) and mkdir(2) do, and return the bits it ends
+/// up with. If we can't set them, we try the default bits minus
+/// `umask`, so the file isn't left more open than the umask allows.
+/// Only if that fails too, eg, the remote went away after creating
+/// the file, does it keep the default bits.
+pub fn initial_mode(
+    vault: &mut GenericVault,
+    file: Inode,
+    kind: VaultFileType,
+    mode: u32,
+    umask: u32,
+) -> u32 {
+    let mode = mode & !umask & 0o7777;
+    let fallback = default_mode(kind) & !umask;
+    for mode in [mode, fallback] {
+        if mode == default_mode(kind) {
+            return mode;
+        }
+        match vault.set_mode(file, mode) {
+            Ok(_) => return mode,
+            Err(err) => warn!("set_mode(file={}, mode={:o}) => {:?}", file, mode, err),
+        }
+    }
+    default_mode(kind)
+}
+
+/// Return the owner of files without one: whoever runs monovault.
+fn default_owner() -> (u32, u32) {
+    unsafe { (libc::getuid(), libc::getgid()) }
+}
+
+/// Return the status a peer's vault root reports in XATTR_STATUS, from
+/// whether the last RPC to the peer went through (None before the
+/// first) and whether we cache the vault: "online", "cached" if the
+/// peer is down and we serve what we cached, "offline" if it is down
+/// and we have nothing to serve, or "unknown". The local vault is
+/// "local".
+pub fn peer_status(up: Option<bool>, caching: bool) -> &'static str {
+    match (up, caching) {
+        (Some(true), _) => "online",
+        (Some(false), true) => "cached",
+        (Some(false), false) => "offline",
+        (None, _) => "unknown",
+    }
+}
+
+/// Return the reply to IOC_SYNC_STATE for `file` of `vault`, whose
+/// root has `status`, see `peer_status`.
+pub fn sync_state(vault: &mut GenericVault, file: Inode, status: &str) -> SyncState {
+    let mut state = SyncState {
+        status: SyncState::status_code(status),
+        ..SyncState::default()
+    };
+    if let Ok(vault) = unpack_to_caching(vault) {
+        state.pending = vault
+            .pending()
+            .ops
+            .iter()
+            .filter(|op| match op {
+                BackgroundOp::Upload(op_file, _, _) | BackgroundOp::Delete(op_file) => {
+                    *op_file == file
+                }
+                BackgroundOp::Create(..) => false,
+            })
+            .count() as u64;
+        if let Some((fetched, total)) = vault.fetch_progress(file) {
+            state.fetched = fetched;
+            state.total = total;
+        }
+    }
+    state
+}
+
+/// Return true if user `uid`, in groups `groups`, may access `info`
+/// in the ways in `mask` (R_OK, W_OK, X_OK, or F_OK for existence),
+/// judging by its permission bits and owner like the kernel does.
+pub fn may_access(info: &FileInfo, uid: u32, groups: &[u32], mask: i32) -> bool {
+    let wanted = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u32;
+    let mode = info.mode & 0o777;
+    if uid == 0 {
+        // Root can read and write anything, but only execute what
+        // someone can.
+        let executable = matches!(info.kind, VaultFileType::Directory) || mode & 0o111 != 0;
+        return wanted & libc::X_OK as u32 == 0 || executable;
+    }
+    let (owner, group) = info.owner.unwrap_or_else(default_owner);
+    let shift = if uid == owner {
+        6
+    } else if groups.contains(&group) {
+        3
+    } else {
+        0
+    };
+    (mode >> shift) & wanted == wanted
+}
+
+/// Return true if user `uid`, in groups `groups`, may delete `entry`
+/// from directory `dir`, like the kernel decides for unlink(2) and
+/// rmdir(2): it needs write and search permission on `dir`, and if
+/// `dir` is sticky, to own `dir` or `entry`.
+pub fn may_delete(dir: &FileInfo, entry: &FileInfo, uid: u32, groups: &[u32]) -> bool {
+    if !may_access(dir, uid, groups, libc::W_OK | libc::X_OK) {
+        return false;
+    }
+    if uid == 0 || dir.mode & 0o1000 == 0 {
+        return true;
+    }
+    let owner = |info: &FileInfo| info.owner.unwrap_or_else(default_owner).0;
+    owner(dir) == uid || owner(entry) == uid
+}
+
+/// Return the groups of process `pid`, the supplementary ones
+/// included, or just `gid` if we can't tell.
+fn groups_of(pid: u32, gid: u32) -> Vec<u32> {
+    let mut groups = vec![gid];
+    if cfg!(target_os = "linux") {
+        if let Ok(status) = std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+            if let Some(line) = status.lines().find_map(|line| line.strip_prefix("Groups:")) {
+                groups.extend(
+                    line.split_whitespace()
+                        .filter_map(|group| group.parse::<u32>().ok()),
+                );
+            }
+        }
+    }
+    groups
+}
+
+/// Ask the kernel to list directories with readdirplus, which gets
+/// the attributes of the entries along, rather than looking each up
+/// after listing.
+#[cfg(target_os = "linux")]
+fn enable_readdirplus(config: &mut fuser::KernelConfig) {
+    if let Err(missing) = config.add_capabilities(fuser::consts::FUSE_DO_READDIRPLUS) {
+        info!("init() => no readdirplus support ({:#x})", missing);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_readdirplus(_config: &mut fuser::KernelConfig) {}
+
+/// Return made-up attributes for directory `ino` named `name`, for
+/// when we don't know better.
+fn made_up_dir(ino: u64, name: &str) -> FileInfo {
+    FileInfo {
+        inode: ino,
+        name: name.to_string(),
+        kind: VaultFileType::Directory,
+        size: 1,
+        atime: 0,
+        mtime: 0,
+        version: (0, 0),
+        mode: default_mode(VaultFileType::Directory),
+        nlink: 1,
+        owner: None,
+        generation: 0,
+    }
+}
+
+/// Return the "." and ".." entries of directory `ino`, whose parent
+/// is `parent`, None for the mount root and vault roots, which are
+/// under the mount root. Vaults don't return them in their listings,
+/// so we synthesize them, and always put them first. The kernel only
+/// needs their inodes.
+pub fn dot_entries(ino: u64, parent: Option<u64>) -> Vec<FileInfo> {
+    vec![
+        made_up_dir(ino, "."),
+        made_up_dir(parent.unwrap_or(1), ".."),
+    ]
+}
+
+/// Return `entries`, a directory listing from a vault, sorted by name
+/// so that the order is stable between calls, readdir relies on it
+/// for offsets. Peers running older versions still include dot
+/// entries, they go, we add our own, see `dot_entries`.
+pub fn sorted_listing(mut entries: Vec<FileInfo>) -> Vec<FileInfo> {
+    entries.retain(|entry| entry.name != "." && entry.name != "..");
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    entries
+}
+
+/// Return the entries of `snapshot`, a listing taken for a directory
+/// handle, from `offset` on, each with its offset: the one the kernel
+/// passes back to continue after it.
+pub fn snapshot_page(snapshot: &[FileInfo], offset: i64) -> impl Iterator<Item = (i64, &FileInfo)> {
+    snapshot
+        .iter()
+        .enumerate()
+        .skip(offset.max(0) as usize)
+        .map(|(idx, info)| (idx as i64 + 1, info))
+}
+
+/// Return outer inode `ino`, `file` of `vault`, with its path in the
+/// mount if the vault knows it, for messages, eg, "0x1000000000214
+/// (/alice/docs/notes.txt)".
+fn describe_file(ino: u64, vault: &GenericVault, file: Inode) -> String {
+    match vault.path_of(file) {
+        Ok(path) if path == "/" => format!("{:#x} (/{})", ino, vault.name()),
+        Ok(path) => format!("{:#x} (/{}{})", ino, vault.name(), path),
+        Err(_) => format!("{:#x}", ino),
+    }
+}
+
+/// Release every lock `lock_owner` of `host` holds on `file` of
+/// `vault`.
+fn unlock(vault: &mut GenericVault, file: Inode, host: &str, lock_owner: u64) -> VaultResult<()> {
+    let lock = FileLock {
+        start: 0,
+        end: u64::MAX,
+        kind: LockKind::Unlock,
+        owner: LockOwner {
+            host: host.to_string(),
+            id: lock_owner,
+        },
+        pid: 0,
+    };
+    vault.setlk(file, &lock)
+}
+
+/// Return the base inode of the vault whose inodes have `prefix`, see
+/// `FS`.
+fn prefix_base(prefix: u64) -> u64 {
+    prefix * 2_u64.pow(48)
+}
+
+/// Return the base inode of the vault of outer inode `ino`, see `FS`.
+fn vault_base(ino: u64) -> u64 {
+    ino & !(2_u64.pow(48) - 1)
+}
+
+/// Reply `result`, the attributes of `ino`, to a getattr request.
+fn reply_attr(ino: u64, result: VaultResult<FileInfo>, reply: ReplyAttr) {
+    match result {
+        Ok(entry) => {
+            info!(
+                "getattr({}) => (ino={:#x}, kind={:?}, size={}, atime={}, mtime={})",
+                ino,
+                ino,
+                translate_kind(entry.kind),
+                entry.size,
+                entry.atime,
+                entry.mtime,
+            );
+            reply.attr(&ttl(), &file_attr(ino, &entry))
+        }
+        Err(err) => {
+            error!("getattr({:#x}) => {:?}", ino, err);
+            reply.error(translate_error(err))
+        }
+    }
+}
+
+/// Return the attributes of `ino`, described by `info`, for the
+/// kernel.
+fn file_attr(ino: u64, info: &FileInfo) -> FileAttr {
+    let mut file_attr = attr(
+        ino,
+        translate_kind(info.kind),
+        info.size,
+        info.atime,
+        info.mtime,
+        info.mode,
+        info.nlink,
+    );
+    if let Some((uid, gid)) = info.owner {
+        file_attr.uid = uid;
+        file_attr.gid = gid;
+    }
+    file_attr
+}
+
+/// Make the user who created new `file`, `uid` and `gid`, its owner,
+/// and return its owner. Files without an owner belong to whoever
+/// runs monovault, so if that's who created it, there's nothing to
+/// record.
+fn initial_owner(vault: &mut GenericVault, file: Inode, uid: u32, gid: u32) -> (u32, u32) {
+    if (uid, gid) == default_owner() {
+        return (uid, gid);
+    }
+    match vault.set_owner(file, uid, gid) {
+        Ok(_) => (uid, gid),
+        Err(err) => {
+            warn!(
+                "set_owner(file={}, uid={}, gid={}) => {:?}",
+                file, uid, gid, err
+            );
+            default_owner()
+        }
+    }
+}
+
+/// Return the generation of `file`, just created, for the entry we
+/// reply. The kernel keeps it for the inode, a made-up 0 only matters
+/// to file handles exported over NFS.
+fn new_generation(vault: &mut GenericVault, file: Inode) -> u64 {
+    match vault.attr(file) {
+        Ok(info) => info.generation,
+        Err(err) => {
+            warn!("attr(file={}) => {:?}", file, err);
+            0
+        }
+    }
+}
+
+/// Return `time` in seconds since UNIX epoch, times before it are 0.
+fn epoch_secs(time: fuser::TimeOrNow) -> u64 {
+    let time = match time {
+        fuser::TimeOrNow::SpecificTime(time) => time,
+        fuser::TimeOrNow::Now => time::SystemTime::now(),
+    };
+    time.duration_since(time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
 fn translate_kind(kind: VaultFileType) -> FileType {
     match kind {
         VaultFileType::File => FileType::RegularFile,
@@ -96,11 +616,38 @@ fn translate_error(err: VaultError) -> libc::c_int {
         VaultError::FileNameTooLong(_) => libc::ENAMETOOLONG,
         VaultError::NoCorrespondingVault(_) => libc::ENOENT,
         VaultError::FileNotExist(_) => libc::ENOENT,
+        VaultError::FileAlreadyExist(_, _) => libc::EEXIST,
         VaultError::NotDirectory(_) => libc::ENOTDIR,
         VaultError::IsDirectory(_) => libc::EISDIR,
         VaultError::DirectoryNotEmpty(_) => libc::ENOTEMPTY,
+        VaultError::TreeTooLarge(_, _) => libc::ENOTEMPTY,
+        VaultError::InvalidArgument(_) => libc::EINVAL,
         VaultError::RemoteError(_) => libc::EREMOTE,
+        VaultError::RemoteFailure(category, _) => match category {
+            ErrorCategory::NoSpace => libc::ENOSPC,
+            ErrorCategory::PermissionDenied => libc::EACCES,
+            ErrorCategory::Transient => libc::EBUSY,
+            ErrorCategory::Permanent => libc::EREMOTE,
+        },
         VaultError::RpcError(_) => libc::ENETDOWN,
+        VaultError::PeerMismatch(_, _) => libc::ECONNREFUSED,
+        VaultError::ReadOnly(_) => libc::EROFS,
+        VaultError::StoreUnwritable(_) => libc::EROFS,
+        VaultError::XattrNotExist(_, _) => ENOATTR,
+        VaultError::LockConflict(_) => libc::EAGAIN,
+        VaultError::VaultBusy(_) => libc::EAGAIN,
+        VaultError::Interrupted(_) => libc::EINTR,
+        VaultError::DatabaseBusy => libc::EBUSY,
+        // Tell a full or read-only store apart from other failures.
+        VaultError::IOError(err) => match err.raw_os_error() {
+            Some(code @ (libc::ENOSPC | libc::EDQUOT | libc::EROFS)) => code,
+            _ => libc::EIO,
+        },
+        VaultError::SqliteError(rusqlite::Error::SqliteFailure(failure, _)) => match failure.code {
+            rusqlite::ErrorCode::DiskFull => libc::ENOSPC,
+            rusqlite::ErrorCode::ReadOnly => libc::EROFS,
+            _ => libc::EIO,
+        },
         _ => libc::EIO,
     }
 }
@@ -111,6 +658,8 @@ fn venial_error_p(err: &VaultError) -> bool {
         // VaultError::FileNameTooLong(_) => true,
         VaultError::FileNotExist(_) => true,
         VaultError::FileAlreadyExist(_, _) => true,
+        VaultError::LockConflict(_) => true,
+        VaultError::Interrupted(_) => true,
         // VaultError::NotDirectory(_) => true,
         // VaultError::IsDirectory(_) => true,
         // VaultError::DirectoryNotEmpty(_) => true,
@@ -119,21 +668,122 @@ fn venial_error_p(err: &VaultError) -> bool {
 }
 
 impl FS {
-    pub fn new(vaults: Vec<VaultRef>) -> FS {
+    /// `prefixes` maps the name of each vault in `vaults` to the
+    /// prefix of its inodes, see src/prefixes.rs.
+    pub fn new(vaults: Vec<VaultRef>, prefixes: &HashMap<VaultName, u64>, config: &Config) -> FS {
         let mut vault_map = HashMap::new();
         let mut vault_base_map = HashMap::new();
-        let mut base = 1;
+        let mut queues = HashMap::new();
+        let mut normalization = HashMap::new();
+        let mut case_insensitive = HashSet::new();
+        let mut peer_roots = HashMap::new();
         for vault_lck in vaults.iter() {
-            let vault_name = vault_lck.lock().unwrap().name();
-            let vault_base = base * (2 as u64).pow(48);
+            let vault = vault_lck.lock().unwrap();
+            let vault_name = vault.name();
+            let vault_base = prefix_base(prefixes[&vault_name]);
+            match &*vault {
+                GenericVault::Local(_) => (),
+                GenericVault::Remote(_) => {
+                    peer_roots.insert(vault_base + 1, (vault_name.clone(), false));
+                }
+                GenericVault::Caching(_) => {
+                    peer_roots.insert(vault_base + 1, (vault_name.clone(), true));
+                }
+            }
+            drop(vault);
+            queues.insert(
+                vault_base,
+                VaultQueue::new(&vault_name, config.vault_queue_depth),
+            );
+            normalization.insert(vault_base, config.name_normalization_of(&vault_name));
+            if config.case_insensitive_of(&vault_name) {
+                case_insensitive.insert(vault_base);
+            }
             vault_base_map.insert(vault_name, vault_base);
-            vault_map.insert(1 + vault_base, Arc::clone(&vault_lck));
-            base += 1;
+            vault_map.insert(1 + vault_base, Arc::clone(vault_lck));
         }
         FS {
             vaults,
             vault_map,
+            parent_map: HashMap::new(),
+            lookup_count: HashMap::new(),
+            lookup_cache: HashMap::new(),
+            stale_listings: Arc::new(Mutex::new(HashSet::new())),
+            dir_handles: HashMap::new(),
+            next_dir_handle: 1,
+            file_handles: FileHandles::new(),
             vault_base_map,
+            queues,
+            read_flights: ReadFlights::new(),
+            root_attrs: Arc::new(Mutex::new(RootAttrs::default())),
+            max_read: clamp_io_size(config.max_read),
+            max_write: clamp_io_size(config.max_write),
+            writeback_cache: config.writeback_cache,
+            direct_io: config.direct_io,
+            normalization,
+            case_insensitive,
+            case_conflicts: HashSet::new(),
+            max_background: config.max_background,
+            congestion_threshold: config.congestion_threshold,
+            mounted: Arc::new(AtomicBool::new(false)),
+            host: config.local_vault_name.clone(),
+            peer_roots,
+            connections: ConnectionLog::new(),
+            invalidator: None,
+            read_only: false,
+            default_permissions: false,
+        }
+    }
+
+    /// Return a flag that becomes true once the file system is
+    /// mounted, so whoever mounts it can tell a failure to mount from
+    /// a session that ended.
+    pub fn mounted(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.mounted)
+    }
+
+    /// Drop cached listings that `invalidator` reports stale, and
+    /// tell the kernel through it about entries ioctls delete.
+    pub fn set_invalidator(&mut self, invalidator: &Invalidator) {
+        self.stale_listings = Arc::clone(&invalidator.stale_listings);
+        self.invalidator = Some(invalidator.clone());
+    }
+
+    /// Check ioctls that change vaults against `options`, the
+    /// options we mount with: refuse them on read-only mounts, and
+    /// check permissions like the kernel does for other calls with
+    /// default_permissions.
+    pub fn set_mount_options(&mut self, options: &[MountOption]) {
+        self.read_only = options.contains(&MountOption::RO);
+        self.default_permissions = options.contains(&MountOption::DefaultPermissions);
+    }
+
+    /// Tell whether peers are up from `connections`, the log their
+    /// remote vaults report to, see `XATTR_STATUS`.
+    pub fn set_connection_log(&mut self, connections: ConnectionLog) {
+        self.connections = connections;
+    }
+
+    /// Return the status of the vault with root `root`, see
+    /// `peer_status`. Doesn't lock the vault, it could be stuck in a
+    /// request to a dead peer, which is when the status matters.
+    fn root_status(&self, root: u64) -> &'static str {
+        match self.peer_roots.get(&root) {
+            Some((peer, caching)) => peer_status(self.connections.is_up(peer), *caching),
+            None => "local",
+        }
+    }
+
+    /// Return `ino` with its path, see `describe_file`. Without the
+    /// path if its vault is busy, we don't wait for it.
+    fn describe(&self, ino: u64) -> String {
+        let vault = self
+            .vault_map
+            .get(&ino)
+            .and_then(|vault_lck| vault_lck.try_lock().ok());
+        match vault {
+            Some(vault) => describe_file(ino, &vault, ino - vault_base(ino)),
+            None => format!("{:#x}", ino),
         }
     }
 
@@ -146,26 +796,278 @@ impl FS {
     }
 
     fn readdir_vaults(&self) -> Vec<(Inode, String, FileType)> {
-        let mut result = vec![];
-        result.push((1, ".".to_string(), FileType::Directory));
-        result.push((1, "..".to_string(), FileType::Directory));
-        for vault_lck in &self.vaults {
-            let vault = vault_lck.lock().unwrap();
-            let root_inode = self.to_outer(&vault.name(), 1);
-            result.push((root_inode, vault.name(), FileType::Directory));
-        }
+        // Don't lock the vaults here, a vault could be stuck in a
+        // request to a dead peer.
+        let mut result: Vec<(Inode, String, FileType)> = self
+            .vault_base_map
+            .iter()
+            .map(|(name, base)| (base + 1, name.clone(), FileType::Directory))
+            .collect();
+        result.sort_by_key(|entry| entry.0);
         debug!("readdir_vaults: {:?}", &result);
         result
     }
 
+    fn is_vault_root(&self, ino: u64) -> bool {
+        self.vault_base_map.values().any(|base| base + 1 == ino)
+    }
+
+    /// Fetch attributes of vault roots in `roots` in parallel, and
+    /// wait at most ROOT_ATTR_TIMEOUT for them. Roots fetched within
+    /// TTL are skipped, and so are roots that have a fetch in flight
+    /// (eg, their peer isn't answering), so threads don't pile up.
+    fn fetch_root_attrs(&self, roots: &[u64]) {
+        let (tx, rx) = mpsc::channel();
+        let mut count = 0;
+        for &root in roots {
+            let vault_lck = match self.vault_map.get(&root) {
+                Some(vault_lck) => Arc::clone(vault_lck),
+                None => continue,
+            };
+            {
+                let mut root_attrs = self.root_attrs.lock().unwrap();
+                let fresh = match root_attrs.known.get(&root) {
+                    Some((fetched, _)) => fetched.elapsed() < ttl(),
+                    None => false,
+                };
+                if fresh || !root_attrs.fetching.insert(root) {
+                    continue;
+                }
+            }
+            count += 1;
+            let root_attrs = Arc::clone(&self.root_attrs);
+            let tx = tx.clone();
+            let _ = thread::spawn(move || {
+                let result = vault_lck.lock().unwrap().attr(1);
+                let mut root_attrs = root_attrs.lock().unwrap();
+                root_attrs.fetching.remove(&root);
+                match result {
+                    Ok(mut info) => {
+                        info.inode = root;
+                        root_attrs.known.insert(root, (time::Instant::now(), info));
+                    }
+                    Err(err) => debug!("fetch_root_attrs({:#x}) => {:?}", root, err),
+                }
+                let _ = tx.send(());
+            });
+        }
+        let deadline = time::Instant::now() + ROOT_ATTR_TIMEOUT;
+        for _ in 0..count {
+            let timeout = deadline.saturating_duration_since(time::Instant::now());
+            if rx.recv_timeout(timeout).is_err() {
+                info!("fetch_root_attrs() => some vaults didn't answer in time");
+                break;
+            }
+        }
+    }
+
+    /// Return the attributes of vault root `root`. If the vault
+    /// doesn't answer in time, return the last attributes we got, or
+    /// made-up ones if we never got any.
+    fn root_attr(&self, root: u64) -> FileInfo {
+        self.fetch_root_attrs(&[root]);
+        self.known_root_attr(root)
+    }
+
+    /// Like `root_attr`, without fetching.
+    fn known_root_attr(&self, root: u64) -> FileInfo {
+        match self.root_attrs.lock().unwrap().known.get(&root) {
+            Some((_, info)) => info.clone(),
+            None => made_up_dir(root, ""),
+        }
+    }
+
+    /// Record that we replied an entry for `ino` to the kernel, which
+    /// increments its lookup count.
+    fn remember(&mut self, ino: u64) {
+        *self.lookup_count.entry(ino).or_insert(0) += 1;
+    }
+
+    /// Return true if the kernel holds lookups on `ino`.
+    fn held(&self, ino: u64) -> bool {
+        self.lookup_count.get(&ino).copied().unwrap_or(0) > 0
+    }
+
+    /// Drop `ino` from our maps, along with the entries of its cached
+    /// listing that the kernel doesn't hold. Called when the kernel
+    /// no longer holds any lookup on `ino`, so it won't ask about
+    /// `ino` anymore. If the inode comes back (eg, readdir returns it
+    /// again), list_1 adds it back. Vault roots stay.
+    fn drop_inode(&mut self, ino: u64) {
+        if ino == 1 || self.is_vault_root(ino) {
+            return;
+        }
+        debug!("drop_inode({:#x})", ino);
+        self.vault_map.remove(&ino);
+        self.parent_map.remove(&ino);
+        self.lookup_count.remove(&ino);
+        if let Some(listing) = self.lookup_cache.remove(&ino) {
+            self.drop_unheld(listing.entries.into_values());
+        }
+    }
+
+    /// Drop those of `inodes` the kernel doesn't hold, see
+    /// `drop_inode`. If another listing has one of them, lookup_1
+    /// adds it back when needed.
+    fn drop_unheld(&mut self, inodes: impl Iterator<Item = u64>) {
+        for inode in inodes {
+            if !self.held(inode) {
+                self.drop_inode(inode);
+            }
+        }
+    }
+
+    /// Called after `ino` is deleted from `parent`. Drop it now if
+    /// the kernel doesn't know about it, otherwise `forget` drops it
+    /// once the kernel forgets.
+    fn unlinked(&mut self, parent: u64, ino: u64) {
+        self.lookup_cache.remove(&parent);
+        if !self.held(ino) {
+            self.drop_inode(ino);
+        }
+    }
+
+    /// Called after the tree under directory `dir` is deleted. Drop
+    /// the entries under it we know of, from the cached listings and
+    /// the directories we know the parent of, like `unlinked` does
+    /// for each.
+    fn unlinked_tree(&mut self, dir: u64) {
+        let mut tree = HashSet::new();
+        let mut queue = vec![dir];
+        while let Some(ino) = queue.pop() {
+            if !tree.insert(ino) {
+                continue;
+            }
+            if let Some(listing) = self.lookup_cache.remove(&ino) {
+                queue.extend(listing.entries.into_values());
+            }
+            queue.extend(
+                self.parent_map
+                    .iter()
+                    .filter(|(_, &parent)| parent == ino)
+                    .map(|(&child, _)| child),
+            );
+        }
+        for ino in tree {
+            if !self.held(ino) {
+                self.drop_inode(ino);
+            }
+        }
+    }
+
+    /// Return `name`, of an entry in directory `dir`, in the normal
+    /// form of the vault, see `NameNormalization`. Names from the
+    /// kernel and names listed are compared in this form, and names
+    /// of new entries are given in it.
+    fn normalize(&self, dir: u64, name: &str) -> String {
+        match self.normalization.get(&vault_base(dir)) {
+            Some(policy) => policy.apply(name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Return `name`, of an entry in directory `dir`, in the form
+    /// names are compared in: normalized, and with case folded if
+    /// the vault ignores case.
+    fn name_key(&self, dir: u64, name: &str) -> String {
+        let name = self.normalize(dir, name);
+        if self.case_insensitive.contains(&vault_base(dir)) {
+            fold_case(&name)
+        } else {
+            name
+        }
+    }
+
+    /// Remember the listing of directory `ino` for lookup_1. In a
+    /// vault that ignores case, names that differ only by case, eg,
+    /// created by a peer that doesn't, are warned about: only one of
+    /// them is found when looked up in another case.
+    fn cache_listing(&mut self, ino: u64, entries: &[(Inode, String, FileType)]) {
+        let mut folded: HashMap<String, u64> = HashMap::new();
+        if self.case_insensitive.contains(&vault_base(ino)) {
+            let mut names: HashMap<String, &str> = HashMap::new();
+            for (inode, name, _) in entries {
+                let key = self.name_key(ino, name);
+                match names.get(&key) {
+                    Some(first) => {
+                        if self.case_conflicts.insert((ino, key.clone())) {
+                            warn!(
+                                "{}: {:?} and {:?} differ only by case, other cases find {:?}",
+                                self.describe(ino),
+                                first,
+                                name,
+                                first
+                            );
+                        }
+                    }
+                    None => {
+                        names.insert(key.clone(), name);
+                        folded.insert(key, *inode);
+                    }
+                }
+            }
+        }
+        let entries: HashMap<String, u64> = entries
+            .iter()
+            .map(|(inode, name, _)| (self.normalize(ino, name), *inode))
+            .collect();
+        let kept: HashSet<u64> = entries.values().copied().collect();
+        let old = self.lookup_cache.insert(
+            ino,
+            DirListing {
+                fetched: time::Instant::now(),
+                entries,
+                folded,
+            },
+        );
+        // Entries that are gone, eg, deleted by a peer, don't need
+        // their mapping unless the kernel holds them.
+        if let Some(old) = old {
+            let gone: Vec<u64> = old
+                .entries
+                .into_values()
+                .filter(|inode| !kept.contains(inode))
+                .collect();
+            self.drop_unheld(gone.into_iter());
+        }
+    }
+
+    /// Return the vault of `inode`, once the requests queued for it
+    /// are done, so requests reach it in the order they came.
     fn get_vault(&self, inode: u64) -> VaultResult<VaultRef> {
         if let Some(vault) = self.vault_map.get(&inode) {
+            if let Some(queue) = self.queues.get(&vault_base(inode)) {
+                queue.wait_idle(QUEUE_WAIT)?;
+            }
             Ok(Arc::clone(vault))
         } else {
             Err(VaultError::NoCorrespondingVault(inode))
         }
     }
 
+    /// Return the vault of `inode`, the inode in the vault, and a
+    /// place in the vault's queue to serve the request on, see
+    /// src/vault_queue.rs.
+    fn reserve(&self, inode: u64) -> VaultResult<(VaultRef, Inode, Slot)> {
+        let base = vault_base(inode);
+        match (self.vault_map.get(&inode), self.queues.get(&base)) {
+            (Some(vault), Some(queue)) => Ok((Arc::clone(vault), inode - base, queue.reserve()?)),
+            _ => Err(VaultError::NoCorrespondingVault(inode)),
+        }
+    }
+
+    /// Like `reserve`, but for requests that must not fail because
+    /// the vault is busy, see `VaultQueue::reserve_always`.
+    fn reserve_always(&self, inode: u64) -> VaultResult<(VaultRef, Inode, Slot)> {
+        let base = vault_base(inode);
+        match (self.vault_map.get(&inode), self.queues.get(&base)) {
+            (Some(vault), Some(queue)) => {
+                Ok((Arc::clone(vault), inode - base, queue.reserve_always()))
+            }
+            _ => Err(VaultError::NoCorrespondingVault(inode)),
+        }
+    }
+
     fn getattr_1(&mut self, _req: &Request, _ino: u64) -> VaultResult<FileInfo> {
         if _ino == 1 {
             Ok(FileInfo {
@@ -176,7 +1078,13 @@ impl FS {
                 atime: 0,                       // -> TODO: track this
                 mtime: 0,                       // -> TODO: track this
                 version: (1, 0),                // -> TODO: track this
+                mode: default_mode(VaultFileType::Directory),
+                nlink: 1,
+                owner: None,
+                generation: 0,
             })
+        } else if self.is_vault_root(_ino) {
+            Ok(self.root_attr(_ino))
         } else {
             let vault_lck = self.get_vault(_ino)?;
             let mut vault = vault_lck.lock().unwrap();
@@ -193,14 +1101,44 @@ impl FS {
         _parent: u64,
         _name: &std::ffi::OsStr,
     ) -> VaultResult<FileInfo> {
-        let name = _name.to_string_lossy().into_owned();
-        let entries = self.readdir_1(_req, _parent, 0, 0)?;
-        for (inode, fname, _) in entries {
-            if fname == name {
-                return self.getattr_1(_req, inode);
+        let name = self.normalize(_parent, &_name.to_string_lossy());
+        // Use the cached listing if it's younger than TTL, remote
+        // changes within TTL aren't visible anyway since the kernel
+        // caches entries that long.
+        let stale = self.stale_listings.lock().unwrap().remove(&_parent);
+        let fresh = match self.lookup_cache.get(&_parent) {
+            Some(listing) => !stale && listing.fetched.elapsed() < ttl(),
+            None => false,
+        };
+        if !fresh {
+            // Readdir_1 refills the cache.
+            self.readdir_1(_req, _parent, 0, 0)?;
+        }
+        // The same name first, then one that differs only by case.
+        let key = self.name_key(_parent, &name);
+        let inode = self.lookup_cache.get(&_parent).and_then(|listing| {
+            listing
+                .entries
+                .get(&name)
+                .or_else(|| listing.folded.get(&key))
+                .copied()
+        });
+        match inode {
+            Some(inode) => {
+                // The entry may have been forgotten and dropped since
+                // the listing.
+                if _parent != 1 && !self.vault_map.contains_key(&inode) {
+                    let vault_lck = self.get_vault(_parent)?;
+                    self.vault_map.insert(inode, vault_lck);
+                }
+                let info = self.getattr_1(_req, inode)?;
+                if let VaultFileType::Directory = info.kind {
+                    self.parent_map.insert(inode, _parent);
+                }
+                Ok(info)
             }
+            None => Err(VaultError::FileNotExist(0)),
         }
-        Err(VaultError::FileNotExist(0))
     }
 
     fn create_1(
@@ -208,79 +1146,542 @@ impl FS {
         _req: &Request<'_>,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
+        mode: u32,
+        umask: u32,
         _flags: i32,
-    ) -> VaultResult<u64> {
+    ) -> VaultResult<(FileAttr, u64)> {
         let vault_lck = self.get_vault(parent)?;
         let mut vault = vault_lck.lock().unwrap();
         let vault_name = vault.name();
-        let inode = self.to_outer(
-            &vault_name,
-            vault.create(
-                self.to_inner(&vault_name, parent),
-                &name.to_string_lossy().into_owned(),
-                VaultFileType::File,
-            )?,
-        );
+        let file = vault.create(
+            self.to_inner(&vault_name, parent),
+            &self.normalize(parent, &name.to_string_lossy()),
+            VaultFileType::File,
+        )?;
+        let mut file_attr = attr(0, FileType::RegularFile, 0, 0, 0, 0, 1);
+        file_attr.perm = initial_mode(&mut vault, file, VaultFileType::File, mode, umask) as u16;
+        (file_attr.uid, file_attr.gid) = initial_owner(&mut vault, file, _req.uid(), _req.gid());
+        let generation = new_generation(&mut vault, file);
+        let inode = self.to_outer(&vault_name, file);
+        file_attr.ino = inode;
         self.vault_map.insert(inode, Arc::clone(&vault_lck));
-        Ok(inode)
+        self.lookup_cache.remove(&parent);
+        Ok((file_attr, generation))
     }
 
-    fn open_1(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32) -> VaultResult<()> {
+    fn open_1(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32) -> VaultResult<u64> {
         let vault_lck = self.get_vault(_ino)?;
         let mut vault = vault_lck.lock().unwrap();
         let vault_name = vault.name();
-        // TODO: open mode.
-        vault.open(self.to_inner(&vault_name, _ino), OpenMode::RW)
+        let mode = open_mode(_flags);
+        vault.open(self.to_inner(&vault_name, _ino), mode)?;
+        Ok(self.file_handles.open(_ino, _flags))
     }
 
-    fn release_1(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-    ) -> VaultResult<()> {
-        let vault_lck = self.get_vault(_ino)?;
+    /// Ask the kernel to cache writes. It then writes whole pages
+    /// back in batches, instead of sending each write(2) on its own,
+    /// and trusts its own idea of the size and mtime of files it has
+    /// cached writes for.
+    #[cfg(target_os = "linux")]
+    fn enable_writeback_cache(&self, config: &mut fuser::KernelConfig) {
+        if let Err(missing) = config.add_capabilities(fuser::consts::FUSE_WRITEBACK_CACHE) {
+            warn!(
+                "init() => no writeback cache support ({:#x}), writes go through",
+                missing
+            );
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn enable_writeback_cache(&self, _config: &mut fuser::KernelConfig) {
+        warn!("init() => writeback cache is only supported on Linux, writes go through");
+    }
+
+    /// Return the flags we reply to open and create with.
+    fn open_flags(&self) -> u32 {
+        if self.direct_io {
+            fuser::consts::FOPEN_DIRECT_IO
+        } else {
+            0
+        }
+    }
+
+    /// Return true if writes through file handle `fh` go to the end of
+    /// the file. With the writeback cache the kernel appends itself
+    /// and writes back pages where they belong.
+    fn append_handle(&self, fh: u64) -> bool {
+        !self.writeback_cache && self.file_handles.append(fh)
+    }
+
+    /// Flush `ino`, a file or a directory, see `Vault::fsync`.
+    fn fsync_1(&mut self, ino: u64) -> VaultResult<()> {
+        // The root only lists vaults, there's nothing to flush.
+        if ino == 1 {
+            return Ok(());
+        }
+        let vault_lck = self.get_vault(ino)?;
         let mut vault = vault_lck.lock().unwrap();
         let vault_name = vault.name();
-        vault.close(self.to_inner(&vault_name, _ino))
+        vault.fsync(self.to_inner(&vault_name, ino))
     }
 
-    fn read_1(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-    ) -> VaultResult<Vec<u8>> {
+    fn lseek_1(&mut self, ino: u64, offset: i64, whence: i32) -> VaultResult<Option<u64>> {
+        // The kernel handles the other kinds of seek itself.
+        let hole = match whence {
+            libc::SEEK_DATA => false,
+            libc::SEEK_HOLE => true,
+            _ => {
+                return Err(VaultError::InvalidArgument(format!(
+                    "unsupported whence {}",
+                    whence
+                )))
+            }
+        };
+        if ino == 1 {
+            return Err(VaultError::IsDirectory(ino));
+        }
         let vault_lck = self.get_vault(ino)?;
         let mut vault = vault_lck.lock().unwrap();
         let vault_name = vault.name();
-        vault.read(self.to_inner(&vault_name, ino), offset, size)
+        vault.seek(self.to_inner(&vault_name, ino), offset, hole)
+    }
+
+    /// Return the lock `lock_owner` of process `pid` asks for on `ino`,
+    /// and the vault `ino` is in.
+    #[allow(clippy::too_many_arguments)]
+    fn file_lock(
+        &self,
+        ino: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+    ) -> VaultResult<(VaultRef, FileLock)> {
+        let lock = FileLock {
+            start,
+            end,
+            kind: lock_kind(typ)?,
+            owner: LockOwner {
+                host: self.host.clone(),
+                id: lock_owner,
+            },
+            pid,
+        };
+        Ok((self.get_vault(ino)?, lock))
     }
 
-    fn write_1(
+    /// Return a lock that keeps the lock asked for from being taken,
+    /// see `Vault::getlk`.
+    #[allow(clippy::too_many_arguments)]
+    fn getlk_1(
         &mut self,
-        _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-    ) -> VaultResult<u32> {
-        let vault_lck = self.get_vault(ino)?;
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+    ) -> VaultResult<Option<FileLock>> {
+        let (vault_lck, lock) = self.file_lock(ino, lock_owner, start, end, typ, pid)?;
         let mut vault = vault_lck.lock().unwrap();
         let vault_name = vault.name();
-        vault.write(self.to_inner(&vault_name, ino), offset, data)
+        let mut conflict = vault.getlk(self.to_inner(&vault_name, ino), &lock)?;
+        // Process IDs mean nothing on other hosts.
+        if let Some(conflict) = conflict.as_mut() {
+            if !locks::is_host(&conflict.owner.host, &self.host) {
+                conflict.pid = 0;
+            }
+        }
+        Ok(conflict)
+    }
+
+    /// Take or release a lock, see `Vault::setlk`.
+    #[allow(clippy::too_many_arguments)]
+    fn setlk_1(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+    ) -> VaultResult<()> {
+        let (vault_lck, lock) = self.file_lock(ino, lock_owner, start, end, typ, pid)?;
+        {
+            let mut vault = vault_lck.lock().unwrap();
+            let vault_name = vault.name();
+            vault.setlk(self.to_inner(&vault_name, ino), &lock)?;
+        }
+        // Remember who to release when the file is closed.
+        if lock.kind != LockKind::Unlock {
+            self.file_handles.add_lock_owner(fh, lock_owner);
+        }
+        Ok(())
+    }
+
+    /// Return Ok if the user behind `req` may access `ino` in the
+    /// ways in `mask`, see `may_access`.
+    fn access_1(&mut self, req: &Request<'_>, ino: u64, mask: i32) -> VaultResult<bool> {
+        let info = self.getattr_1(req, ino)?;
+        if mask == libc::F_OK {
+            return Ok(true);
+        }
+        let groups = groups_of(req.pid(), req.gid());
+        Ok(may_access(&info, req.uid(), &groups, mask))
+    }
+
+    /// Return true if the caller of `req` may delete `ino` from its
+    /// parent `parent`, see `may_delete`.
+    fn may_delete_1(&mut self, req: &Request<'_>, parent: u64, ino: u64) -> VaultResult<bool> {
+        let dir = self.getattr_1(req, parent)?;
+        let entry = self.getattr_1(req, ino)?;
+        let groups = groups_of(req.pid(), req.gid());
+        Ok(may_delete(&dir, &entry, req.uid(), &groups))
+    }
+
+    /// Return the capacity and usage of the disk `ino` is on. The
+    /// mount root reports the disk of the local vault, which is
+    /// where the mount keeps everything.
+    fn statfs_1(&mut self, ino: u64) -> VaultResult<FsStats> {
+        if ino != 1 {
+            return self.get_vault(ino)?.lock().unwrap().statfs();
+        }
+        for vault_lck in self.vaults.iter() {
+            if let GenericVault::Local(vault) = &mut *vault_lck.lock().unwrap() {
+                return vault.statfs();
+            }
+        }
+        // No local vault, eg, mount_only.
+        Ok(FsStats::default())
+    }
+
+    fn set_mode_1(&mut self, _req: &Request<'_>, ino: u64, mode: u32) -> VaultResult<()> {
+        let vault_lck = self.get_vault(ino)?;
+        let mut vault = vault_lck.lock().unwrap();
+        let vault_name = vault.name();
+        // Only keep the permission bits, the kernel also sends the
+        // file type bits.
+        vault.set_mode(self.to_inner(&vault_name, ino), mode & 0o7777)
+    }
+
+    /// Change the owner of `ino` to `uid`, its group to `gid`, or
+    /// both, like chown(2).
+    fn set_owner_1(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> VaultResult<()> {
+        let owner = self
+            .getattr_1(_req, ino)?
+            .owner
+            .unwrap_or_else(default_owner);
+        let vault_lck = self.get_vault(ino)?;
+        let mut vault = vault_lck.lock().unwrap();
+        let vault_name = vault.name();
+        vault.set_owner(
+            self.to_inner(&vault_name, ino),
+            uid.unwrap_or(owner.0),
+            gid.unwrap_or(owner.1),
+        )
+    }
+
+    /// Set the access time, modification time, or both of `ino`,
+    /// like utimensat(2).
+    fn set_times_1(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+    ) -> VaultResult<()> {
+        let vault_lck = self.get_vault(ino)?;
+        let mut vault = vault_lck.lock().unwrap();
+        let vault_name = vault.name();
+        vault.set_times(
+            self.to_inner(&vault_name, ino),
+            atime.map(epoch_secs),
+            mtime.map(epoch_secs),
+        )
+    }
+
+    fn truncate_1(&mut self, _req: &Request<'_>, ino: u64, size: u64) -> VaultResult<()> {
+        let vault_lck = self.get_vault(ino)?;
+        self.read_flights.close(ino);
+        let mut vault = vault_lck.lock().unwrap();
+        let vault_name = vault.name();
+        vault.truncate(self.to_inner(&vault_name, ino), size)
+    }
+
+    /// Return the value of the extended attribute `name` of `ino`,
+    /// synthesized or stored, or None if there is no such attribute.
+    fn getxattr_1(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &str,
+    ) -> VaultResult<Option<Vec<u8>>> {
+        if ino == 1 {
+            return Ok(None);
+        }
+        if name == XATTR_STATUS {
+            if !self.is_vault_root(ino) {
+                return Ok(None);
+            }
+            return Ok(Some(self.root_status(ino).as_bytes().to_vec()));
+        }
+        if name == XATTR_SEALED {
+            if !self.is_vault_root(ino) {
+                return Ok(None);
+            }
+            let sealed = self.get_vault(ino)?.lock().unwrap().sealed();
+            return Ok(sealed.map(|time| time.to_string().into_bytes()));
+        }
+        if name == XATTR_FETCH_PROGRESS {
+            return Ok(self
+                .fetch_progress(ino)?
+                .map(|(done, total)| format!("{}/{}", done, total).into_bytes()));
+        }
+        let vault_lck = self.get_vault(ino)?;
+        let mut vault = vault_lck.lock().unwrap();
+        let vault_name = vault.name();
+        if name != XATTR_USAGE_SIZE && name != XATTR_USAGE_COUNT {
+            return vault.xattr(self.to_inner(&vault_name, ino), name);
+        }
+        let info = vault.info(self.to_inner(&vault_name, ino))?;
+        let value = if name == XATTR_USAGE_SIZE {
+            info.size
+        } else {
+            info.count
+        };
+        Ok(Some(value.to_string().into_bytes()))
+    }
+
+    /// Return the names of the extended attributes of `ino`, each
+    /// followed by a null byte, like listxattr(2) wants them.
+    fn listxattr_1(&mut self, _req: &Request<'_>, ino: u64) -> VaultResult<Vec<u8>> {
+        let mut names = vec![];
+        if ino == 1 {
+            return Ok(names);
+        }
+        for name in [XATTR_USAGE_SIZE, XATTR_USAGE_COUNT] {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        if self.is_vault_root(ino) {
+            names.extend_from_slice(XATTR_STATUS.as_bytes());
+            names.push(0);
+        }
+        if let Ok(Some(_)) = self.fetch_progress(ino) {
+            names.extend_from_slice(XATTR_FETCH_PROGRESS.as_bytes());
+            names.push(0);
+        }
+        let vault_lck = self.get_vault(ino)?;
+        let mut vault = vault_lck.lock().unwrap();
+        if self.is_vault_root(ino) && vault.sealed().is_some() {
+            names.extend_from_slice(XATTR_SEALED.as_bytes());
+            names.push(0);
+        }
+        let vault_name = vault.name();
+        for name in vault.xattr_names(self.to_inner(&vault_name, ino))? {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    /// Set extended attribute `name` of `ino` to `value`. `flags`
+    /// may ask that the attribute exists (XATTR_REPLACE) or doesn't
+    /// (XATTR_CREATE) yet. Return an errno on failure.
+    fn setxattr_1(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        flags: i32,
+    ) -> Result<(), libc::c_int> {
+        if ino == 1 || name.starts_with(XATTR_RESERVED_PREFIX) {
+            return Err(libc::EPERM);
+        }
+        // The vault checks these too, we check them first to fail
+        // with the errors Linux uses.
+        if name.is_empty() || name.len() > XATTR_NAME_MAX {
+            return Err(libc::ERANGE);
+        }
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(libc::E2BIG);
+        }
+        let vault_lck = self.get_vault(ino).map_err(translate_error)?;
+        let mut vault = vault_lck.lock().unwrap();
+        let vault_name = vault.name();
+        let file = self.to_inner(&vault_name, ino);
+        if flags & (libc::XATTR_CREATE | libc::XATTR_REPLACE) != 0 {
+            let exists = vault.xattr(file, name).map_err(translate_error)?.is_some();
+            if exists && flags & libc::XATTR_CREATE != 0 {
+                return Err(libc::EEXIST);
+            }
+            if !exists && flags & libc::XATTR_REPLACE != 0 {
+                return Err(ENOATTR);
+            }
+        }
+        vault.set_xattr(file, name, value).map_err(translate_error)
+    }
+
+    fn removexattr_1(&mut self, _req: &Request<'_>, ino: u64, name: &str) -> VaultResult<()> {
+        let vault_lck = self.get_vault(ino)?;
+        let mut vault = vault_lck.lock().unwrap();
+        let vault_name = vault.name();
+        vault.remove_xattr(self.to_inner(&vault_name, ino), name)
+    }
+
+    /// If `ino` is a remote file being downloaded, return (bytes
+    /// downloaded, bytes in total).
+    fn fetch_progress(&mut self, ino: u64) -> VaultResult<Option<(u64, u64)>> {
+        let vault_lck = self.get_vault(ino)?;
+        let mut vault = vault_lck.lock().unwrap();
+        let vault_name = vault.name();
+        let inner = self.to_inner(&vault_name, ino);
+        Ok(match unpack_to_caching(&mut vault) {
+            Ok(vault) => vault.fetch_progress(inner),
+            Err(_) => None,
+        })
+    }
+
+    /// Serve IOC_WHERE on `ino`. Return the data to reply.
+    fn ioctl_where(&self, ino: u64) -> VaultResult<Vec<u8>> {
+        if !self.vault_map.contains_key(&ino) {
+            return Err(VaultError::NoCorrespondingVault(ino));
+        }
+        let vault = self
+            .vault_base_map
+            .iter()
+            .find(|(_, &base)| base == vault_base(ino))
+            .map(|(name, _)| name.clone())
+            .ok_or(VaultError::NoCorrespondingVault(ino))?;
+        let inode = self.to_inner(&vault, ino);
+        Where { vault, inode }.encode()
+    }
+
+    /// Serve IOC_REFRESH on `ino`: drop the listing of a directory
+    /// and the listing it was looked up in, and tell the kernel to
+    /// look it up again.
+    fn ioctl_refresh(&mut self, ino: u64) {
+        self.lookup_cache.remove(&ino);
+        if let Some(&parent) = self.parent_map.get(&ino) {
+            let name = self.entry_name(parent, ino);
+            self.lookup_cache.remove(&parent);
+            if let (Some(invalidator), Some(name)) = (&self.invalidator, name) {
+                invalidator.forget_entry(parent, name);
+            }
+        }
+    }
+
+    /// Serve IOC_SYNC_STATE on `ino` on the queue of its vault, which
+    /// may be stuck on a peer.
+    fn ioctl_sync_state(&self, ino: u64, reply: ReplyIoctl) {
+        let status = self.root_status(vault_base(ino) + 1);
+        let (vault_lck, file, slot) = match self.reserve(ino) {
+            Ok(reserved) => reserved,
+            Err(err) => {
+                error!("ioctl({:#x}, SyncState) => {:?}", ino, err);
+                reply.error(translate_error(err));
+                return;
+            }
+        };
+        slot.run(move || {
+            let state = sync_state(&mut vault_lck.lock().unwrap(), file, status);
+            reply.ioctl(0, &state.encode());
+        });
+    }
+
+    /// Serve IOC_DELETE_TREE on `ino` on the queue of its vault.
+    fn ioctl_delete_tree(&mut self, req: &Request<'_>, ino: u64, reply: ReplyIoctl) {
+        if self.read_only {
+            error!(
+                "ioctl({}, DeleteTree) => read-only mount",
+                self.describe(ino)
+            );
+            reply.error(libc::EROFS);
+            return;
+        }
+        let reserved = self.tree_parent(ino).and_then(|parent| {
+            if self.default_permissions && !self.may_delete_1(req, parent, ino)? {
+                return Err(VaultError::RemoteFailure(
+                    ErrorCategory::PermissionDenied,
+                    format!(
+                        "uid {} may not delete from directory {:#x}",
+                        req.uid(),
+                        parent
+                    ),
+                ));
+            }
+            Ok((parent, self.reserve(ino)?))
+        });
+        let (parent, (vault_lck, file, slot)) = match reserved {
+            Ok(reserved) => reserved,
+            Err(err) => {
+                error!("ioctl({}, DeleteTree) => {:?}", self.describe(ino), err);
+                reply.error(translate_error(err));
+                return;
+            }
+        };
+        // The name to tell the kernel to drop, if we know it.
+        let name = self.entry_name(parent, ino);
+        // Forget the tree now, the vault thread can't tell us once
+        // it's deleted. Lookups wait for the queue, so they don't
+        // find it again before it's gone, and if deleting it fails,
+        // they find what is left.
+        self.unlinked_tree(ino);
+        self.lookup_cache.remove(&parent);
+        let invalidator = self.invalidator.clone();
+        slot.run(move || {
+            // Remote vaults delete the whole tree in one request
+            // rather than one request per entry.
+            let result = vault_lck.lock().unwrap().delete_tree(file, MAX_TREE_DELETE);
+            match result {
+                Ok(count) => {
+                    debug!("delete_tree({:#x}) => {} entries", ino, count);
+                    if let (Some(invalidator), Some(name)) = (invalidator, name) {
+                        invalidator.forget_entry(parent, name);
+                    }
+                    reply.ioctl(0, &[]);
+                }
+                Err(err) => {
+                    error!("ioctl({:#x}, DeleteTree) => {:?}", ino, err);
+                    reply.error(translate_error(err));
+                }
+            }
+        });
+    }
+
+    /// Return the parent of directory `ino`, whose tree IOC_DELETE_TREE
+    /// deletes. Vault roots can't be deleted.
+    fn tree_parent(&self, ino: u64) -> VaultResult<u64> {
+        if ino == 1 || self.is_vault_root(ino) {
+            return Err(VaultError::InvalidArgument(
+                "can't delete the tree of a vault root".to_string(),
+            ));
+        }
+        self.parent_map
+            .get(&ino)
+            .copied()
+            .ok_or(VaultError::NotDirectory(ino))
+    }
+
+    /// Return the name of `ino` in the cached listing of `parent`, if
+    /// we have one.
+    fn entry_name(&self, parent: u64, ino: u64) -> Option<String> {
+        self.lookup_cache.get(&parent).and_then(|listing| {
+            listing
+                .entries
+                .iter()
+                .find(|(_, &child)| child == ino)
+                .map(|(name, _)| name.clone())
+        })
     }
 
     fn unlink_1(
@@ -290,12 +1691,16 @@ impl FS {
         _name: &std::ffi::OsStr,
         req_kind: FileType,
     ) -> VaultResult<()> {
-        let name = _name.to_string_lossy().into_owned();
+        let name = self.normalize(_parent, &_name.to_string_lossy());
+        let key = self.name_key(_parent, &name);
         match self.readdir_1(_req, _parent, 0, 0) {
-            Ok(entries) => {
+            Ok(mut entries) => {
+                // The same name first, then one that differs only by
+                // case.
+                entries.sort_by_key(|(_, fname, _)| self.normalize(_parent, fname) != name);
                 // Find the child with NAME and return information of it.
                 for (inode, fname, kind) in entries {
-                    if fname == name {
+                    if self.name_key(_parent, &fname) == key {
                         return match (req_kind, kind) {
                             (FileType::RegularFile, FileType::Directory) => {
                                 Err(VaultError::IsDirectory(inode))
@@ -308,14 +1713,34 @@ impl FS {
                                 let vault_lck = self.get_vault(inode)?;
                                 let mut vault = vault_lck.lock().unwrap();
                                 let vault_name = vault.name();
-                                vault.delete(self.to_inner(&vault_name, inode))
+                                let inner = self.to_inner(&vault_name, inode);
+                                if vault.attr(inner)?.nlink > 1 {
+                                    // The file lives on under its
+                                    // other names.
+                                    vault.unlink(
+                                        inner,
+                                        self.to_inner(&vault_name, _parent),
+                                        &fname,
+                                    )?;
+                                    self.lookup_cache.remove(&_parent);
+                                } else {
+                                    vault.delete(inner)?;
+                                    self.unlinked(_parent, inode);
+                                }
+                                Ok(())
                             }
                             (FileType::Directory, FileType::Directory) => {
                                 // Actually do the work.
                                 let vault_lck = self.get_vault(inode)?;
                                 let mut vault = vault_lck.lock().unwrap();
                                 let vault_name = vault.name();
-                                vault.delete(self.to_inner(&vault_name, inode))
+                                let inner = self.to_inner(&vault_name, inode);
+                                // Fails with DirectoryNotEmpty unless
+                                // empty, see IOC_DELETE_TREE for
+                                // deleting a whole tree.
+                                vault.delete(inner)?;
+                                self.unlinked(_parent, inode);
+                                Ok(())
                             }
                             // Other types are impossible.
                             _ => Ok(()),
@@ -323,33 +1748,186 @@ impl FS {
                     }
                 }
                 // No entry with the requested name, return error.
-                return Err(VaultError::FileNotExist(0));
+                Err(VaultError::FileNotExist(0))
             }
             Err(err) => Err(err),
         }
     }
 
+    fn rename_1(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> VaultResult<()> {
+        let vault_lck = self.get_vault(parent)?;
+        let file = self.lookup_1(_req, parent, name)?;
+        let target = match self.lookup_1(_req, newparent, newname) {
+            Ok(info) => Some(info),
+            Err(VaultError::FileNotExist(_)) => None,
+            Err(err) => return Err(err),
+        };
+        if let Some(target) = &target {
+            if flags & RENAME_NOREPLACE != 0 && target.inode != file.inode {
+                return Err(VaultError::FileAlreadyExist(
+                    newparent,
+                    newname.to_string_lossy().into_owned(),
+                ));
+            }
+        }
+        if matches!(file.kind, VaultFileType::File) && file.nlink > 1 {
+            // Vaults move the name a file was created with, so move
+            // this name by hand: a new link, then the old one goes.
+            if let Some(target) = &target {
+                if target.inode == file.inode {
+                    // Both are names of the same file, like rename(2)
+                    // we do nothing.
+                    return Ok(());
+                }
+                if let VaultFileType::Directory = target.kind {
+                    return Err(VaultError::IsDirectory(target.inode));
+                }
+            }
+            let mut vault = vault_lck.lock().unwrap();
+            let vault_name = vault.name();
+            let inner = self.to_inner(&vault_name, file.inode);
+            let inner_newparent = self.to_inner(&vault_name, newparent);
+            let newname = self.normalize(newparent, &newname.to_string_lossy());
+            // The target makes way for the link, but so that it can
+            // come back if linking fails: a target with other names
+            // only loses this one, one without is moved aside.
+            let replaced = match &target {
+                Some(target) => {
+                    let inner_target = self.to_inner(&vault_name, target.inode);
+                    if target.nlink > 1 {
+                        vault.unlink(inner_target, inner_newparent, &newname)?;
+                        Some((inner_target, None))
+                    } else {
+                        let aside = format!(".monovault-replaced-{}", inner_target);
+                        vault.rename(inner_target, inner_newparent, &aside)?;
+                        Some((inner_target, Some(aside)))
+                    }
+                }
+                None => None,
+            };
+            if let Err(err) = vault.link(inner, inner_newparent, &newname) {
+                let restored = match &replaced {
+                    Some((inner_target, None)) => {
+                        vault.link(*inner_target, inner_newparent, &newname)
+                    }
+                    Some((inner_target, Some(_))) => {
+                        vault.rename(*inner_target, inner_newparent, &newname)
+                    }
+                    None => Ok(()),
+                };
+                if let Err(restore_err) = restored {
+                    error!(
+                        "rename({}) => cannot put back {}: {:?}",
+                        self.describe(file.inode),
+                        newname,
+                        restore_err
+                    );
+                }
+                drop(vault);
+                self.lookup_cache.remove(&newparent);
+                return Err(err);
+            }
+            if let Some((inner_target, Some(_))) = &replaced {
+                vault.delete(*inner_target)?;
+            }
+            vault.unlink(
+                inner,
+                self.to_inner(&vault_name, parent),
+                &self.normalize(parent, &name.to_string_lossy()),
+            )?;
+            drop(vault);
+            self.lookup_cache.remove(&parent);
+            self.lookup_cache.remove(&newparent);
+            if let Some(target) = target {
+                if target.nlink <= 1 {
+                    self.unlinked(newparent, target.inode);
+                }
+            }
+            return Ok(());
+        }
+        {
+            let mut vault = vault_lck.lock().unwrap();
+            let vault_name = vault.name();
+            vault.rename(
+                self.to_inner(&vault_name, file.inode),
+                self.to_inner(&vault_name, newparent),
+                &self.normalize(newparent, &newname.to_string_lossy()),
+            )?;
+        }
+        self.lookup_cache.remove(&parent);
+        self.lookup_cache.remove(&newparent);
+        if let VaultFileType::Directory = file.kind {
+            self.parent_map.insert(file.inode, newparent);
+        }
+        if let Some(target) = target {
+            // A replaced file with other names lives on.
+            if target.inode != file.inode && target.nlink <= 1 {
+                self.unlinked(newparent, target.inode);
+            }
+        }
+        Ok(())
+    }
+
+    fn link_1(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> VaultResult<FileInfo> {
+        let vault_lck = self.get_vault(ino)?;
+        {
+            let mut vault = vault_lck.lock().unwrap();
+            let vault_name = vault.name();
+            vault.link(
+                self.to_inner(&vault_name, ino),
+                self.to_inner(&vault_name, newparent),
+                &self.normalize(newparent, &newname.to_string_lossy()),
+            )?;
+        }
+        self.lookup_cache.remove(&newparent);
+        self.getattr_1(_req, ino)
+    }
+
     fn mkdir_1(
         &mut self,
         _req: &Request<'_>,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-    ) -> VaultResult<Inode> {
+        mode: u32,
+        umask: u32,
+    ) -> VaultResult<(FileAttr, u64)> {
         let vault_lck = self.get_vault(parent)?;
         let mut vault = vault_lck.lock().unwrap();
         let vault_name = vault.name();
         let inode = vault.create(
             self.to_inner(&vault_name, parent),
-            &name.to_string_lossy().into_owned(),
+            &self.normalize(parent, &name.to_string_lossy()),
             VaultFileType::Directory,
         )?;
+        let mut file_attr = attr(0, FileType::Directory, 1, 0, 0, 0, 1);
+        file_attr.perm =
+            initial_mode(&mut vault, inode, VaultFileType::Directory, mode, umask) as u16;
+        (file_attr.uid, file_attr.gid) = initial_owner(&mut vault, inode, _req.uid(), _req.gid());
+        let generation = new_generation(&mut vault, inode);
         let outer_inode = self.to_outer(&vault.name(), inode);
+        file_attr.ino = outer_inode;
         self.vault_map.insert(outer_inode, Arc::clone(&vault_lck));
-        Ok(outer_inode)
+        self.parent_map.insert(outer_inode, parent);
+        self.lookup_cache.remove(&parent);
+        Ok((file_attr, generation))
     }
 
+    /// Return the entries of directory `ino`, sorted by name, without
+    /// "." and "..".
     fn readdir_1(
         &mut self,
         _req: &Request<'_>,
@@ -357,35 +1935,157 @@ impl FS {
         _fh: u64,
         _offset: i64,
     ) -> VaultResult<Vec<(u64, String, FileType)>> {
+        Ok(self
+            .list_1(_req, ino)?
+            .into_iter()
+            .map(|info| (info.inode, info.name, translate_kind(info.kind)))
+            .collect())
+    }
+
+    /// Take a snapshot of directory `ino` for handle `fh` when listing
+    /// from the start (this is also how rewinddir gets fresh
+    /// entries), later calls continue from the snapshot.
+    fn snapshot_dir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+    ) -> VaultResult<()> {
+        if offset == 0 || !self.dir_handles.contains_key(&fh) {
+            // Vault roots have no parent in the map, and we always
+            // learn the parent of other directories before the kernel
+            // can list them.
+            let mut inode_list = dot_entries(ino, self.parent_map.get(&ino).copied());
+            inode_list.extend(self.list_1(_req, ino)?);
+            self.dir_handles.insert(fh, inode_list);
+        }
+        Ok(())
+    }
+
+    /// Like `readdir_1`, with the attributes of each entry, under
+    /// our inodes.
+    fn list_1(&mut self, _req: &Request<'_>, ino: u64) -> VaultResult<Vec<FileInfo>> {
         // If inode = 1, it refers to the root dir, list vaults.
         if ino == 1 {
-            return Ok(self.readdir_vaults());
+            let vaults = self.readdir_vaults();
+            // Fetch the roots' attributes now, in parallel, the
+            // kernel is going to ask for them one by one next.
+            let roots: Vec<u64> = vaults.iter().map(|entry| entry.0).collect();
+            self.fetch_root_attrs(&roots);
+            self.cache_listing(ino, &vaults);
+            return Ok(vaults
+                .into_iter()
+                .map(|(root, name, _)| FileInfo {
+                    name,
+                    ..self.known_root_attr(root)
+                })
+                .collect());
         }
         let vault_lck = self.get_vault(ino)?;
         let mut vault = vault_lck.lock().unwrap();
         let name = vault.name();
-        let entries = vault.readdir(self.to_inner(&name, ino))?;
+        let entries = sorted_listing(vault.readdir(self.to_inner(&name, ino))?);
         // Translate DirEntry to the tuple we return.
-        let mut entries: Vec<(u64, String, FileType)> = entries
+        let mut result = vec![];
+        for entry in entries {
+            // Remember the mapping from each entry to its vault.
+            // When fuse starts up, it only has mappings for vault
+            // roots, so any newly discovered files need to be added
+            // to the map.
+            let outer_inode = self.to_outer(&name, entry.inode);
+            self.vault_map.insert(outer_inode, Arc::clone(&vault_lck));
+            if let VaultFileType::Directory = entry.kind {
+                self.parent_map.insert(outer_inode, ino);
+            }
+            result.push(FileInfo {
+                inode: outer_inode,
+                ..entry
+            });
+        }
+        drop(vault);
+        let listing: Vec<(Inode, String, FileType)> = result
             .iter()
-            .map(|entry| {
-                // Remember the mapping from each entry to its vault.
-                // When fuse starts up, it only has mappings for vault
-                // roots, so any newly discovered files need to be
-                // added to the map.
-                let outer_inode = self.to_outer(&vault.name(), entry.inode);
-                if outer_inode != 1 {
-                    self.vault_map.insert(outer_inode, Arc::clone(&vault_lck));
-                }
-                (outer_inode, entry.name.clone(), translate_kind(entry.kind))
-            })
+            .map(|info| (info.inode, info.name.clone(), translate_kind(info.kind)))
+            .collect();
+        self.cache_listing(ino, &listing);
+        Ok(result)
+    }
+}
+
+impl Invalidator {
+    /// Start turning the events on `events` into notifications, once
+    /// a session is attached. `prefixes` maps the name of each vault
+    /// to the prefix of its inodes, like for `FS::new`.
+    pub fn start(events: &EventBus, prefixes: &HashMap<VaultName, u64>) -> Invalidator {
+        let invalidator = Invalidator {
+            notifier: Arc::new(Mutex::new(None)),
+            stale_listings: Arc::new(Mutex::new(HashSet::new())),
+        };
+        let bases: HashMap<VaultName, u64> = prefixes
+            .iter()
+            .map(|(name, prefix)| (name.clone(), prefix_base(*prefix)))
             .collect();
-        // If the directory is vault root, we need to add parent dir
-        // for it.
-        if self.to_inner(&vault.name(), ino) == 1 {
-            entries.push((1, "..".to_string(), FileType::Directory))
+        let receiver = events.subscribe();
+        let worker = invalidator.clone();
+        let _ = thread::spawn(move || {
+            for event in receiver {
+                if let Some(base) = bases.get(event.vault()) {
+                    worker.invalidate(*base, &event);
+                }
+            }
+        });
+        invalidator
+    }
+
+    /// Notify the kernel of `session` from now on.
+    pub fn attach<F: Filesystem>(&self, session: &fuser::Session<F>) {
+        *self.notifier.lock().unwrap() = Some(session.notifier());
+    }
+
+    /// Stop notifying, the session ended.
+    pub fn detach(&self) {
+        *self.notifier.lock().unwrap() = None;
+    }
+
+    /// Tell the kernel that entry `name` of directory `parent` is
+    /// gone. From another thread: the kernel may wait on the request
+    /// we serve before it handles the notification.
+    fn forget_entry(&self, parent: u64, name: String) {
+        let notifier = Arc::clone(&self.notifier);
+        let _ = thread::spawn(move || {
+            if let Some(notifier) = &*notifier.lock().unwrap() {
+                if let Err(err) = notifier.inval_entry(parent, OsStr::new(&name)) {
+                    debug!("forget_entry({:#x}, {:?}) => {}", parent, name, err);
+                }
+            }
+        });
+    }
+
+    /// Tell the kernel what `event`, of the vault with `base`, makes
+    /// stale.
+    fn invalidate(&self, base: u64, event: &Event) {
+        if let Event::Vanished { parent, .. } = event {
+            self.stale_listings.lock().unwrap().insert(base + parent);
+        }
+        let notifier = self.notifier.lock().unwrap();
+        let notifier = match &*notifier {
+            Some(notifier) => notifier,
+            None => return,
+        };
+        let result = match event {
+            // Offset 0 and length 0 drop the attributes and all the
+            // data.
+            Event::Outdated { file, .. } => notifier.inval_inode(base + file, 0, 0),
+            Event::Vanished { parent, name, .. } => {
+                notifier.inval_entry(base + parent, OsStr::new(name))
+            }
+            _ => return,
+        };
+        // ENOENT if the kernel doesn't have it cached.
+        if let Err(err) = result {
+            debug!("invalidate({:?}) => {}", event, err);
         }
-        Ok(entries)
     }
 }
 
@@ -393,43 +2093,83 @@ impl Filesystem for FS {
     fn init(
         &mut self,
         _req: &Request<'_>,
-        _config: &mut fuser::KernelConfig,
+        config: &mut fuser::KernelConfig,
     ) -> Result<(), libc::c_int> {
         info!("init()");
+        self.mounted.store(true, SeqCst);
+        // Max read is a mount option, see `mount_options`; here we
+        // only limit readahead to it.
+        if let Err(max) = config.set_max_write(self.max_write) {
+            info!(
+                "init() => max_write {} too large, use {}",
+                self.max_write, max
+            );
+            self.max_write = max;
+            let _ = config.set_max_write(max);
+        }
+        if let Err(max) = config.set_max_readahead(self.max_read) {
+            info!(
+                "init() => max_readahead {} too large, use {}",
+                self.max_read, max
+            );
+            let _ = config.set_max_readahead(max);
+        }
+        // Send fcntl locks to us, so peers see them.
+        if let Err(missing) = config.add_capabilities(fuser::consts::FUSE_POSIX_LOCKS) {
+            warn!(
+                "init() => no POSIX lock support ({:#x}), locks only work on this host",
+                missing
+            );
+        }
+        // And flock locks.
+        if let Err(missing) = config.add_capabilities(fuser::consts::FUSE_FLOCK_LOCKS) {
+            warn!(
+                "init() => no flock support ({:#x}), flock only works on this host",
+                missing
+            );
+        }
+        // Setting either only fails on 0, which means the default.
+        if self.max_background > 0 {
+            let _ = config.set_max_background(self.max_background);
+        }
+        if self.congestion_threshold > 0 {
+            let _ = config.set_congestion_threshold(self.congestion_threshold);
+        }
+        if self.writeback_cache {
+            self.enable_writeback_cache(config);
+        }
+        enable_readdirplus(config);
         Ok(())
     }
 
     fn destroy(&mut self) {
         info!("destroy()");
+        for queue in self.queues.values() {
+            if let Err(err) = queue.wait_idle(QUEUE_WAIT) {
+                error!("destroy() => {:?}", err);
+            }
+        }
         for vault_lck in &self.vaults {
-            match vault_lck.lock() {
-                Ok(mut vault) => match vault.tear_down() {
-                    Ok(_) => (),
-                    Err(err) => error!("destroy() => vault {} {:?}", vault.name(), err),
-                },
-                Err(_) => (),
+            if let Ok(mut vault) = vault_lck.lock() {
+                if let Err(err) = vault.tear_down() {
+                    error!("destroy() => vault {} {:?}", vault.name(), err)
+                }
             }
         }
     }
 
     fn lookup(&mut self, _req: &Request, _parent: u64, _name: &std::ffi::OsStr, reply: ReplyEntry) {
+        let _serving = interrupt::serving(_req.pid());
         info!(
             "lookup(parent={:#x}, name={})",
             _parent,
             _name.to_string_lossy()
         );
         match self.lookup_1(_req, _parent, _name) {
-            Ok(info) => reply.entry(
-                &ttl(),
-                &attr(
-                    info.inode,
-                    translate_kind(info.kind),
-                    info.size,
-                    info.atime,
-                    info.mtime,
-                ),
-                0,
-            ),
+            Ok(info) => {
+                self.remember(info.inode);
+                reply.entry(&ttl(), &file_attr(info.inode, &info), info.generation)
+            }
             Err(err) => {
                 // NOTE: If you see lookup warning on werid stuff like
                 // ._., ._xxx, etc, they are turd files (Apple double
@@ -447,64 +2187,266 @@ impl Filesystem for FS {
                     _name.to_string_lossy(),
                     err
                 );
+                if let VaultError::FileNotExist(_) = err {
+                    // An entry with inode 0 is a negative entry: the
+                    // kernel answers lookups of the name with ENOENT
+                    // itself until it expires, or until the name is
+                    // created through the mount.
+                    let attr = attr(0, FileType::RegularFile, 0, 0, 0, 0, 1);
+                    reply.entry(&negative_ttl(), &attr, 0);
+                } else {
+                    reply.error(translate_error(err));
+                }
+            }
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        info!("forget(ino={:#x}, nlookup={})", ino, nlookup);
+        if let Some(count) = self.lookup_count.get_mut(&ino) {
+            *count = count.saturating_sub(nlookup);
+            if *count == 0 {
+                self.lookup_count.remove(&ino);
+                // The kernel won't ask about it again without a
+                // lookup first.
+                self.drop_inode(ino);
+            }
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, _ino: u64, reply: ReplyAttr) {
+        let _serving = interrupt::serving(_req.pid());
+        // We know the attributes of the roots without asking vaults.
+        if _ino == 1 || self.is_vault_root(_ino) {
+            let result = self.getattr_1(_req, _ino);
+            reply_attr(_ino, result, reply);
+            return;
+        }
+        match self.reserve(_ino) {
+            Ok((vault_lck, file, slot)) => slot.run(move || {
+                let result = vault_lck.lock().unwrap().attr(file);
+                reply_attr(_ino, result, reply)
+            }),
+            Err(err) => reply_attr(_ino, Err(err), reply),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<time::SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<time::SystemTime>,
+        _chgtime: Option<time::SystemTime>,
+        _bkuptime: Option<time::SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let _serving = interrupt::serving(_req.pid());
+        info!(
+            "setattr(ino={:#x}, mode={:?}, uid={:?}, gid={:?}, size={:?})",
+            ino, mode, uid, gid, size
+        );
+        if let Some(mode) = mode {
+            if let Err(err) = self.set_mode_1(_req, ino, mode) {
+                error!(
+                    "setattr(ino={}, mode={:o}) => {:?}",
+                    self.describe(ino),
+                    mode,
+                    err
+                );
                 reply.error(translate_error(err));
+                return;
+            }
+        }
+        if uid.is_some() || gid.is_some() {
+            if let Err(err) = self.set_owner_1(_req, ino, uid, gid) {
+                error!(
+                    "setattr(ino={:#x}, uid={:?}, gid={:?}) => {:?}",
+                    ino, uid, gid, err
+                );
+                reply.error(translate_error(err));
+                return;
+            }
+        }
+        // This is also how O_TRUNC reaches us. ftruncate(2) comes with
+        // the handle, which must be open for writing.
+        if let (Some(_), Some(fh)) = (size, _fh) {
+            if self.file_handles.read_only(fh) {
+                error!("setattr(ino={:#x}, fh={}) => opened read-only", ino, fh);
+                reply.error(libc::EBADF);
+                return;
+            }
+        }
+        if let Some(size) = size {
+            if let Err(err) = self.truncate_1(_req, ino, size) {
+                error!(
+                    "setattr(ino={}, size={}) => {:?}",
+                    self.describe(ino),
+                    size,
+                    err
+                );
+                reply.error(translate_error(err));
+                return;
+            }
+        }
+        // After truncating, so touch -t on a truncated file sticks.
+        if atime.is_some() || mtime.is_some() {
+            if let Err(err) = self.set_times_1(_req, ino, atime, mtime) {
+                error!(
+                    "setattr(ino={:#x}, atime={:?}, mtime={:?}) => {:?}",
+                    ino, atime, mtime, err
+                );
+                reply.error(translate_error(err));
+                return;
+            }
+        }
+        self.getattr(_req, ino, reply)
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let _serving = interrupt::serving(_req.pid());
+        info!("getxattr(ino={:#x}, name={})", ino, name.to_string_lossy());
+        match self.getxattr_1(_req, ino, &name.to_string_lossy()) {
+            Ok(Some(value)) => reply_xattr(reply, size, &value),
+            Ok(None) => reply.error(ENOATTR),
+            Err(err) => {
+                error!(
+                    "getxattr(ino={:#x}, name={}) => {:?}",
+                    ino,
+                    name.to_string_lossy(),
+                    err
+                );
+                reply.error(translate_error(err))
+            }
+        }
+    }
+
+    fn ioctl(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        _in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        let _serving = interrupt::serving(_req.pid());
+        info!("ioctl(ino={:#x}, cmd={:#x})", ino, cmd);
+        let command = match Command::of_request(cmd) {
+            Some(command) => command,
+            // Not ours, like ioctls of other file systems.
+            None => {
+                reply.error(libc::ENOTTY);
+                return;
+            }
+        };
+        if (out_size as usize) < command.reply_size() {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        match command {
+            Command::Where => match self.ioctl_where(ino) {
+                Ok(data) => reply.ioctl(0, &data),
+                Err(err) => {
+                    error!("ioctl({}, {:?}) => {:?}", self.describe(ino), command, err);
+                    reply.error(translate_error(err))
+                }
+            },
+            Command::Refresh => {
+                self.ioctl_refresh(ino);
+                reply.ioctl(0, &[])
             }
+            // These lock the vault, serve them on its queue.
+            Command::SyncState => self.ioctl_sync_state(ino, reply),
+            Command::DeleteTree => self.ioctl_delete_tree(_req, ino, reply),
         }
     }
 
-    fn getattr(&mut self, _req: &Request, _ino: u64, reply: ReplyAttr) {
-        match self.getattr_1(_req, _ino) {
-            Ok(entry) => {
-                info!(
-                    "getattr({}) => (ino={:#x}, kind={:?}, size={}, atime={}, mtime={})",
-                    _ino,
-                    _ino,
-                    translate_kind(entry.kind),
-                    entry.size,
-                    entry.atime,
-                    entry.mtime,
-                );
-                reply.attr(
-                    &ttl(),
-                    &attr(
-                        _ino,
-                        translate_kind(entry.kind),
-                        entry.size,
-                        entry.atime,
-                        entry.mtime,
-                    ),
-                )
-            }
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let _serving = interrupt::serving(_req.pid());
+        info!("listxattr(ino={:#x})", ino);
+        match self.listxattr_1(_req, ino) {
+            Ok(names) => reply_xattr(reply, size, &names),
             Err(err) => {
-                error!("getattr({:#x}) => {:?}", _ino, err);
+                error!("listxattr(ino={}) => {:?}", self.describe(ino), err);
                 reply.error(translate_error(err))
             }
         }
     }
 
-    fn setattr(
+    fn setxattr(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<time::SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<time::SystemTime>,
-        _chgtime: Option<time::SystemTime>,
-        _bkuptime: Option<time::SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
     ) {
+        let _serving = interrupt::serving(_req.pid());
+        let name = name.to_string_lossy();
         info!(
-            "setattr(ino={:#x}, uid={:?}, gid={:?}, size={:?})",
-            ino, uid, gid, size
+            "setxattr(ino={:#x}, name={}, size={}, flags={:#x})",
+            ino,
+            name,
+            value.len(),
+            flags
         );
-        self.getattr(_req, ino, reply)
+        // Only macOS resource forks are written at an offset.
+        if position != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        match self.setxattr_1(_req, ino, &name, value, flags) {
+            Ok(_) => reply.ok(),
+            Err(errno) => {
+                if errno != libc::EEXIST && errno != ENOATTR {
+                    error!("setxattr(ino={:#x}, name={}) => {}", ino, name, errno);
+                }
+                reply.error(errno)
+            }
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _serving = interrupt::serving(_req.pid());
+        let name = name.to_string_lossy();
+        info!("removexattr(ino={:#x}, name={})", ino, name);
+        if ino == 1 || name.starts_with(XATTR_RESERVED_PREFIX) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        match self.removexattr_1(_req, ino, &name) {
+            Ok(_) => reply.ok(),
+            Err(VaultError::XattrNotExist(_, _)) => reply.error(ENOATTR),
+            Err(err) => {
+                error!(
+                    "removexattr(ino={}, name={}) => {:?}",
+                    self.describe(ino),
+                    name,
+                    err
+                );
+                reply.error(translate_error(err))
+            }
+        }
     }
 
     fn create(
@@ -517,21 +2459,24 @@ impl Filesystem for FS {
         flags: i32,
         reply: ReplyCreate,
     ) {
+        let _serving = interrupt::serving(_req.pid());
         match self.create_1(_req, parent, name, mode, umask, flags) {
-            Ok(inode) => {
+            Ok((file_attr, generation)) => {
                 info!(
                     "create(parent={:#x}, name={}) => {}",
                     parent,
                     name.to_string_lossy(),
-                    inode
+                    file_attr.ino
                 );
+                self.remember(file_attr.ino);
+                let fh = self.file_handles.open(file_attr.ino, flags);
                 reply.created(
                     &ttl(),
                     // TODO: use current time for atime and mtime instead.
-                    &attr(inode, FileType::RegularFile, 0, 0, 0),
-                    0,
-                    0,
-                    0,
+                    &file_attr,
+                    generation,
+                    fh,
+                    self.open_flags(),
                 )
             }
             Err(err) => {
@@ -547,11 +2492,12 @@ impl Filesystem for FS {
     }
 
     fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
+        let _serving = interrupt::serving(_req.pid());
         info!("open({:#x})", _ino);
         match self.open_1(_req, _ino, _flags) {
-            Ok(_) => reply.opened(0, 0),
+            Ok(fh) => reply.opened(fh, self.open_flags()),
             Err(err) => {
-                error!("open({:#x}) => {:?}", _ino, err);
+                error!("open({}) => {:?}", self.describe(_ino), err);
                 reply.error(translate_error(err))
             }
         }
@@ -568,37 +2514,102 @@ impl Filesystem for FS {
         reply: ReplyEmpty,
     ) {
         info!("release({:#x})", _ino);
-        match self.release_1(_req, _ino, _fh, _flags, _lock_owner, _flush) {
-            Ok(_) => reply.ok(),
+        // The last descriptor of the file is closed, its locks go.
+        let mut owners = self.file_handles.release(_fh);
+        owners.extend(_lock_owner);
+        // Closing can't be tried again, so it waits for the vault
+        // however busy, after the reads and writes before it.
+        let (vault_lck, file, slot) = match self.reserve_always(_ino) {
+            Ok(reserved) => reserved,
             Err(err) => {
                 error!("release({:#x}) => {:?}", _ino, err);
-                reply.error(translate_error(err))
+                reply.error(translate_error(err));
+                return;
             }
-        }
+        };
+        let host = self.host.clone();
+        slot.run(move || {
+            let mut vault = vault_lck.lock().unwrap();
+            for owner in owners {
+                if let Err(err) = unlock(&mut vault, file, &host, owner) {
+                    warn!(
+                        "release({:#x}) => cannot release locks of {}: {:?}",
+                        _ino, owner, err
+                    );
+                }
+            }
+            match vault.close(file) {
+                Ok(_) => reply.ok(),
+                Err(err) => {
+                    error!(
+                        "release({}) => {:?}",
+                        describe_file(_ino, &vault, file),
+                        err
+                    );
+                    reply.error(translate_error(err))
+                }
+            }
+        });
     }
 
     fn read(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        fh: u64,
+        _fh: u64,
         offset: i64,
         size: u32,
-        flags: i32,
-        lock_owner: Option<u64>,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
+        let _serving = interrupt::serving(_req.pid());
         info!("read(ino={:#x}, offset={}, size={})", ino, offset, size);
-        match self.read_1(_req, ino, fh, offset, size, flags, lock_owner) {
-            Ok(data) => reply.data(&data),
+        // The kernel shouldn't ask for more than we advertised, but
+        // don't trust it to.
+        let size = std::cmp::min(size, self.max_read);
+        let flight = match self.read_flights.join((ino, offset, size.into()), reply) {
+            Some(flight) => flight,
+            None => {
+                debug!(
+                    "read(ino={:#x}, offset={}, size={}) => joined the same read in flight",
+                    ino, offset, size
+                );
+                return;
+            }
+        };
+        let (vault_lck, file, slot) = match self.reserve(ino) {
+            Ok(reserved) => reserved,
             Err(err) => {
                 error!(
                     "read(ino={:#x}, offset={}, size={}) => {:?}",
                     ino, offset, size, err
                 );
-                reply.error(translate_error(err))
+                let errno = translate_error(err);
+                for reply in flight.land() {
+                    reply.error(errno);
+                }
+                return;
             }
-        }
+        };
+        slot.run(move || {
+            let result = caching_remote::read_unlocked(&vault_lck, file, offset, size.into());
+            let result = result.map_err(|err| {
+                error!(
+                    "read(ino={:#x}, offset={}, size={}) => {:?}",
+                    ino, offset, size, err
+                );
+                translate_error(err)
+            });
+            // Reads that joined while we were queued or reading get
+            // the same answer.
+            for reply in flight.land() {
+                match &result {
+                    Ok(data) => reply.data(data),
+                    Err(errno) => reply.error(*errno),
+                }
+            }
+        });
     }
 
     fn write(
@@ -608,21 +2619,160 @@ impl Filesystem for FS {
         fh: u64,
         offset: i64,
         data: &[u8],
-        write_flags: u32,
-        flags: i32,
-        lock_owner: Option<u64>,
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
+        let _serving = interrupt::serving(_req.pid());
         info!(
             "write(ino={:#x}, offset={}, size={})",
             ino,
             offset,
             data.len()
         );
-        match self.write_1(_req, ino, fh, offset, data, write_flags, flags, lock_owner) {
-            Ok(size) => reply.written(size),
+        // The kernel checks this too, but don't trust it to.
+        if self.file_handles.read_only(fh) {
+            error!("write(ino={:#x}, fh={}) => opened read-only", ino, fh);
+            reply.error(libc::EBADF);
+            return;
+        }
+        let (vault_lck, file, slot) = match self.reserve(ino) {
+            Ok(reserved) => reserved,
+            Err(err) => {
+                error!("write(ino={:#x}, offset={}) => {:?}", ino, offset, err);
+                reply.error(translate_error(err));
+                return;
+            }
+        };
+        // Reads from now on see the write, see `ReadFlights::close`.
+        self.read_flights.close(ino);
+        // Write what fits and report a short write for the rest.
+        let data = data[..std::cmp::min(data.len(), self.max_write as usize)].to_vec();
+        let append = self.append_handle(fh);
+        slot.run(move || {
+            let mut vault = vault_lck.lock().unwrap();
+            let result = if append {
+                vault.append(file, &data)
+            } else {
+                vault.write(file, offset, &data)
+            };
+            match result {
+                // No more than `max_write`, which fits.
+                Ok(size) => reply.written(size as u32),
+                Err(err) => {
+                    error!(
+                        "write(ino={}, offset={}) => {:?}",
+                        describe_file(ino, &vault, file),
+                        offset,
+                        err
+                    );
+                    reply.error(translate_error(err))
+                }
+            }
+        });
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        let _serving = interrupt::serving(_req.pid());
+        info!(
+            "getlk(ino={:#x}, start={}, end={}, typ={})",
+            ino, start, end, typ
+        );
+        match self.getlk_1(ino, lock_owner, start, end, typ, pid) {
+            Ok(Some(lock)) => reply.locked(lock.start, lock.end, lock_type(lock.kind), lock.pid),
+            Ok(None) => reply.locked(start, end, lock_type(LockKind::Unlock), pid),
+            Err(err) => {
+                error!("getlk(ino={}) => {:?}", self.describe(ino), err);
+                reply.error(translate_error(err))
+            }
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        _sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _serving = interrupt::serving(_req.pid());
+        info!(
+            "setlk(ino={:#x}, start={}, end={}, typ={})",
+            ino, start, end, typ
+        );
+        // We don't wait for a conflicting lock to go away even if
+        // asked to (F_SETLKW): the FUSE loop is single-threaded, and a
+        // lock taken after the process gave up would be held forever.
+        match self.setlk_1(ino, fh, lock_owner, start, end, typ, pid) {
+            Ok(_) => reply.ok(),
+            Err(err) => {
+                log!(
+                    if venial_error_p(&err) {
+                        log::Level::Info
+                    } else {
+                        log::Level::Error
+                    },
+                    "setlk(ino={:#x}) => {:?}",
+                    ino,
+                    err
+                );
+                reply.error(translate_error(err))
+            }
+        }
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let _serving = interrupt::serving(req.pid());
+        debug!("access(ino={:#x}, mask={:o})", ino, mask);
+        match self.access_1(req, ino, mask) {
+            Ok(true) => reply.ok(),
+            Ok(false) => reply.error(libc::EACCES),
+            Err(err) => {
+                error!(
+                    "access(ino={}, mask={:o}) => {:?}",
+                    self.describe(ino),
+                    mask,
+                    err
+                );
+                reply.error(translate_error(err))
+            }
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        let _serving = interrupt::serving(_req.pid());
+        info!("statfs({:#x})", ino);
+        match self.statfs_1(ino) {
+            Ok(stats) => reply.statfs(
+                stats.total / STATFS_BLOCK_SIZE,
+                stats.free / STATFS_BLOCK_SIZE,
+                stats.available / STATFS_BLOCK_SIZE,
+                stats.files,
+                stats.free_files,
+                STATFS_BLOCK_SIZE as u32,
+                MAX_NAME_LEN as u32,
+                STATFS_BLOCK_SIZE as u32,
+            ),
             Err(err) => {
-                error!("write(ino={:#x}, offset={}) =? {:?}", ino, offset, err);
+                error!("statfs({:#x}) => {:?}", ino, err);
                 reply.error(translate_error(err))
             }
         }
@@ -633,14 +2783,94 @@ impl Filesystem for FS {
         _req: &Request<'_>,
         ino: u64,
         _fh: u64,
-        _lock_owner: u64,
+        lock_owner: u64,
         reply: ReplyEmpty,
     ) {
         info!("flush({:#x})", ino);
-        reply.ok();
+        // Only owners we saw take a lock cost a request.
+        if !self.file_handles.forget_lock_owner(ino, lock_owner) {
+            reply.ok();
+            return;
+        }
+        // Like release, releasing locks waits for the vault however
+        // busy.
+        let (vault_lck, file, slot) = match self.reserve_always(ino) {
+            Ok(reserved) => reserved,
+            Err(err) => {
+                error!("flush({:#x}) => {:?}", ino, err);
+                reply.error(translate_error(err));
+                return;
+            }
+        };
+        let host = self.host.clone();
+        slot.run(move || {
+            let mut vault = vault_lck.lock().unwrap();
+            match unlock(&mut vault, file, &host, lock_owner) {
+                Ok(_) => reply.ok(),
+                Err(err) => {
+                    error!("flush({}) => {:?}", describe_file(ino, &vault, file), err);
+                    reply.error(translate_error(err))
+                }
+            }
+        });
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _serving = interrupt::serving(_req.pid());
+        info!("fsync({:#x})", ino);
+        // With caching, fsync may upload the file, which can take
+        // long, so it goes on the vault's thread like writes do.
+        let (vault_lck, file, slot) = match self.reserve(ino) {
+            Ok(reserved) => reserved,
+            Err(err) => {
+                error!("fsync({:#x}) => {:?}", ino, err);
+                reply.error(translate_error(err));
+                return;
+            }
+        };
+        slot.run(move || {
+            let mut vault = vault_lck.lock().unwrap();
+            match vault.fsync(file) {
+                Ok(_) => reply.ok(),
+                Err(err) => {
+                    error!("fsync({}) => {:?}", describe_file(ino, &vault, file), err);
+                    reply.error(translate_error(err))
+                }
+            }
+        });
+    }
+
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        let _serving = interrupt::serving(_req.pid());
+        debug!("lseek({:#x}, offset={}, whence={})", ino, offset, whence);
+        match self.lseek_1(ino, offset, whence) {
+            Ok(Some(offset)) => reply.offset(offset as i64),
+            // Nothing more of that kind before the end of the file.
+            Ok(None) => reply.error(libc::ENXIO),
+            Err(err) => {
+                error!("lseek({}) => {:?}", self.describe(ino), err);
+                reply.error(translate_error(err))
+            }
+        }
     }
 
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _serving = interrupt::serving(_req.pid());
         info!(
             "unlink(parent={:#x}, name={})",
             parent,
@@ -660,9 +2890,82 @@ impl Filesystem for FS {
         }
     }
 
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _serving = interrupt::serving(_req.pid());
+        info!(
+            "rename(parent={:#x}, name={}, newparent={:#x}, newname={}, flags={:#x})",
+            parent,
+            name.to_string_lossy(),
+            newparent,
+            newname.to_string_lossy(),
+            flags
+        );
+        if parent == 1 || newparent == 1 {
+            // Vault directories can't be renamed, and nothing can
+            // become one.
+            error!(
+                "rename(parent={:#x}, newparent={:#x}) => EBUSY",
+                parent, newparent
+            );
+            reply.error(libc::EBUSY);
+            return;
+        }
+        if flags & !RENAME_NOREPLACE != 0 {
+            // Exchange and whiteout aren't supported.
+            error!("rename(flags={:#x}) => EINVAL", flags);
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let same_vault = match (self.get_vault(parent), self.get_vault(newparent)) {
+            (Ok(from), Ok(to)) => Arc::ptr_eq(&from, &to),
+            // Let rename_1 report the missing vault.
+            _ => true,
+        };
+        if !same_vault {
+            // Moving between vaults is a copy and a delete, leave
+            // that to mv(1).
+            info!(
+                "rename(parent={:#x}, newparent={:#x}) => EXDEV",
+                parent, newparent
+            );
+            reply.error(libc::EXDEV);
+            return;
+        }
+        match self.rename_1(_req, parent, name, newparent, newname, flags) {
+            Ok(_) => reply.ok(),
+            Err(err) => {
+                log!(
+                    if venial_error_p(&err) {
+                        log::Level::Info
+                    } else {
+                        log::Level::Error
+                    },
+                    "rename(parent={:#x}, name={}, newparent={:#x}, newname={}) => {:?}",
+                    parent,
+                    name.to_string_lossy(),
+                    newparent,
+                    newname.to_string_lossy(),
+                    err
+                );
+                reply.error(translate_error(err))
+            }
+        }
+    }
+
     fn opendir(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
-        info!("opendir({:#x})", _ino);
-        reply.opened(0, 0);
+        let fh = self.next_dir_handle;
+        self.next_dir_handle += 1;
+        info!("opendir({:#x}) => {}", _ino, fh);
+        reply.opened(fh, 0);
     }
 
     fn releasedir(
@@ -674,9 +2977,82 @@ impl Filesystem for FS {
         reply: ReplyEmpty,
     ) {
         info!("releasedir({:#x})", _ino);
+        self.dir_handles.remove(&_fh);
         reply.ok();
     }
 
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _serving = interrupt::serving(_req.pid());
+        info!("fsyncdir({:#x})", ino);
+        match self.fsync_1(ino) {
+            Ok(_) => reply.ok(),
+            Err(err) => {
+                error!("fsyncdir({:#x}) => {:?}", ino, err);
+                reply.error(translate_error(err))
+            }
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let _serving = interrupt::serving(_req.pid());
+        info!(
+            "link(ino={:#x}, newparent={:#x}, newname={})",
+            ino,
+            newparent,
+            newname.to_string_lossy()
+        );
+        let same_vault = match (self.get_vault(ino), self.get_vault(newparent)) {
+            (Ok(from), Ok(to)) => Arc::ptr_eq(&from, &to),
+            _ => false,
+        };
+        if newparent == 1 || self.is_vault_root(ino) || !same_vault {
+            // Links don't cross vaults, see link(2) for EXDEV.
+            info!("link(ino={:#x}, newparent={:#x}) => EXDEV", ino, newparent);
+            reply.error(libc::EXDEV);
+            return;
+        }
+        match self.link_1(_req, ino, newparent, newname) {
+            Ok(info) => {
+                self.remember(info.inode);
+                reply.entry(&ttl(), &file_attr(info.inode, &info), info.generation)
+            }
+            Err(VaultError::IsDirectory(_)) => {
+                // Directories can't have hard links.
+                info!("link(ino={:#x}) => EPERM", ino);
+                reply.error(libc::EPERM)
+            }
+            Err(err) => {
+                log!(
+                    if venial_error_p(&err) {
+                        log::Level::Info
+                    } else {
+                        log::Level::Error
+                    },
+                    "link(ino={:#x}, newparent={:#x}, newname={}) => {:?}",
+                    ino,
+                    newparent,
+                    newname.to_string_lossy(),
+                    err
+                );
+                reply.error(translate_error(err))
+            }
+        }
+    }
+
     fn mkdir(
         &mut self,
         _req: &Request<'_>,
@@ -686,21 +3062,23 @@ impl Filesystem for FS {
         umask: u32,
         reply: ReplyEntry,
     ) {
+        let _serving = interrupt::serving(_req.pid());
         info!(
             "mkdir(parent={:#x}, name={})",
             parent,
             name.to_string_lossy()
         );
         match self.mkdir_1(_req, parent, name, mode, umask) {
-            Ok(inode) => {
+            Ok((file_attr, generation)) => {
                 info!(
                     "mkdir(parent={:#x}, name={}) => {}",
                     parent,
                     name.to_string_lossy(),
-                    inode
+                    file_attr.ino
                 );
+                self.remember(file_attr.ino);
                 // TODO: Use current time for atime and mtime.
-                reply.entry(&ttl(), &attr(inode, FileType::Directory, 1, 0, 0), 0)
+                reply.entry(&ttl(), &file_attr, generation)
             }
             Err(err) => {
                 let level = if venial_error_p(&err) {
@@ -728,39 +3106,69 @@ impl Filesystem for FS {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
+        let _serving = interrupt::serving(_req.pid());
         info!("readdir(ino={:#x}, offset={})", ino, offset);
-        match self.readdir_1(_req, ino, fh, offset) {
-            Ok(inode_list) => {
-                if (offset as usize) < inode_list.len() {
-                    for idx in (offset as usize)..inode_list.len() {
-                        let (inode, name, ty) = inode_list[idx].clone();
-                        info!(
-                            "reply.add(inode={:#x}, offset={}, name={})",
-                            inode,
-                            idx + 1,
-                            &name
-                        );
-                        // If return true, the reply buffer is full.
-                        if reply.add(inode, idx as i64 + 1, ty, name) {
-                            break;
-                        }
-                    }
-                    // Added enough entries, return.
-                    reply.ok();
-                } else {
-                    // Offset too large, no more entries.
-                    debug!("readdir: return empty");
-                    reply.ok();
-                }
+        if let Err(err) = self.snapshot_dir(_req, ino, fh, offset) {
+            error!("readdir(ino={:#x}, offset={}) => {:?}", ino, offset, err);
+            reply.error(translate_error(err));
+            return;
+        }
+        // Past the end, the page is empty.
+        for (next, info) in snapshot_page(&self.dir_handles[&fh], offset) {
+            info!(
+                "reply.add(inode={:#x}, offset={}, name={})",
+                info.inode, next, info.name
+            );
+            // If return true, the reply buffer is full.
+            let kind = translate_kind(info.kind);
+            if reply.add(info.inode, next, kind, &info.name) {
+                break;
             }
-            Err(err) => {
-                error!("readdir(ino={:#x}, offset={}) => {:?}", ino, offset, err);
-                reply.error(translate_error(err))
+        }
+        reply.ok();
+    }
+
+    /// Like readdir, with the attributes of each entry, so the kernel
+    /// doesn't look up entries one by one after listing.
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let _serving = interrupt::serving(_req.pid());
+        info!("readdirplus(ino={:#x}, offset={})", ino, offset);
+        if let Err(err) = self.snapshot_dir(_req, ino, fh, offset) {
+            error!(
+                "readdirplus(ino={:#x}, offset={}) => {:?}",
+                ino, offset, err
+            );
+            reply.error(translate_error(err));
+            return;
+        }
+        let mut added = vec![];
+        for (next, info) in snapshot_page(&self.dir_handles[&fh], offset) {
+            let attr = file_attr(info.inode, info);
+            // If return true, the reply buffer is full.
+            if reply.add(info.inode, next, &info.name, &ttl(), &attr, info.generation) {
+                break;
+            }
+            // Each entry is a lookup, except the dot entries, which
+            // the kernel skips.
+            if info.name != "." && info.name != ".." {
+                added.push(info.inode);
             }
         }
+        for inode in added {
+            self.remember(inode);
+        }
+        reply.ok();
     }
 
     fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _serving = interrupt::serving(_req.pid());
         info!(
             "rmdir(parent={:#x}, name={})",
             parent,
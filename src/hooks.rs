@@ -0,0 +1,64 @@
+/// Run user-configured commands on vault events.
+use crate::events::{Event, EventBus};
+use crate::types::*;
+use log::{error, info};
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Maps vault name to a map of event name to shell command, see
+/// `Config::hooks`.
+pub type HookTable = HashMap<VaultName, HashMap<String, String>>;
+
+/// Start a thread that runs the command in `hooks` for each matching
+/// event on `bus`.
+pub fn start(bus: &EventBus, hooks: HookTable) {
+    let events = bus.subscribe();
+    let _ = thread::spawn(move || {
+        for event in events {
+            if let Some(command) = hooks
+                .get(event.vault())
+                .and_then(|table| table.get(event.name()))
+            {
+                run_hook(command, &event);
+            }
+        }
+    });
+}
+
+/// Run `command` with `sh -c`, feeding `event` as JSON to its stdin.
+/// We don't wait for the command, a slow hook shouldn't hold up
+/// others.
+fn run_hook(command: &str, event: &Event) {
+    info!("run_hook({}, {})", command, event.name());
+    let json = serde_json::to_string(event).unwrap();
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            error!("Cannot run hook {}: {:?}", command, err);
+            return;
+        }
+    };
+    let command = command.to_string();
+    let _ = thread::spawn(move || {
+        // Dropping stdin closes it so the hook sees EOF.
+        if let Some(mut stdin) = child.stdin.take() {
+            if let Err(err) = stdin.write_all(json.as_bytes()) {
+                error!("Cannot send event to hook {}: {:?}", command, err);
+            }
+        }
+        match child.wait() {
+            Ok(status) if !status.success() => {
+                error!("Hook {} exited with {}", command, status)
+            }
+            Ok(_) => (),
+            Err(err) => error!("Cannot wait for hook {}: {:?}", command, err),
+        }
+    });
+}
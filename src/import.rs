@@ -0,0 +1,150 @@
+/// Import an existing directory tree on the host into a local vault.
+use crate::local_vault::LocalVault;
+use crate::types::*;
+use log::{info, warn};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time;
+
+/// Size of each read from the source file when copying data.
+const IMPORT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// What an import did.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImportStats {
+    /// Number of regular files copied.
+    pub files: u64,
+    /// Number of directories created.
+    pub directories: u64,
+    /// Number of bytes copied.
+    pub bytes: u64,
+    /// Number of files that were already imported by a previous run.
+    pub unchanged: u64,
+    /// Number of entries we can't import (symlinks, sockets, etc).
+    pub skipped: u64,
+}
+
+/// Return the inode of `path` in `vault`. `path` is relative to the
+/// vault root, an empty path means the root.
+pub fn resolve_path(vault: &mut impl Vault, path: &Path) -> VaultResult<Inode> {
+    let mut current = 1;
+    for component in path.iter() {
+        let name = component.to_string_lossy();
+        current = vault
+            .readdir(current)?
+            .into_iter()
+            .find(|info| info.name == name)
+            .ok_or(VaultError::FileNotExist(current))?
+            .inode;
+    }
+    Ok(current)
+}
+
+/// Return the seconds since epoch of `time`, or 0 if it's before the
+/// epoch.
+fn epoch_secs(time: std::io::Result<time::SystemTime>) -> u64 {
+    time.ok()
+        .and_then(|time| time.duration_since(time::UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Copy the directory tree at `source` into directory `dest` of
+/// `vault`, preserving structure, permission bits and mtimes.
+///
+/// Import can be resumed: existing directories are reused, and files
+/// that already exist with the same size and mtime are skipped. We set
+/// a file's mtime only after its content is fully copied, so a file
+/// interrupted half way is copied again next time.
+pub fn import_tree(vault: &mut LocalVault, source: &Path, dest: Inode) -> VaultResult<ImportStats> {
+    info!("import_tree(source={:?}, dest={})", source, dest);
+    let mut stats = ImportStats::default();
+    import_dir(vault, source, dest, &mut stats)?;
+    Ok(stats)
+}
+
+fn import_dir(
+    vault: &mut LocalVault,
+    source: &Path,
+    dest: Inode,
+    stats: &mut ImportStats,
+) -> VaultResult<()> {
+    let existing = vault.readdir(dest)?;
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let meta = std::fs::symlink_metadata(&path)?;
+        let mtime = epoch_secs(meta.modified());
+        let atime = epoch_secs(meta.accessed());
+        let mode = meta.permissions().mode() & 0o7777;
+        let old = existing.iter().find(|info| info.name == name);
+
+        if meta.is_dir() {
+            let dir = match old {
+                Some(info) => match info.kind {
+                    VaultFileType::Directory => info.inode,
+                    VaultFileType::File => {
+                        return Err(VaultError::NotDirectory(info.inode));
+                    }
+                },
+                None => {
+                    stats.directories += 1;
+                    vault.create(dest, &name, VaultFileType::Directory)?
+                }
+            };
+            import_dir(vault, &path, dir, stats)?;
+            vault.set_mode(dir, mode)?;
+            vault.set_times(dir, Some(atime), Some(mtime))?;
+        } else if meta.is_file() {
+            let file = match old {
+                Some(info) => match info.kind {
+                    VaultFileType::File => {
+                        if info.size == meta.len() && info.mtime == mtime {
+                            stats.unchanged += 1;
+                            continue;
+                        }
+                        vault.open(info.inode, OpenMode::RW)?;
+                        info.inode
+                    }
+                    VaultFileType::Directory => {
+                        return Err(VaultError::IsDirectory(info.inode));
+                    }
+                },
+                // Create also opens the file.
+                None => vault.create(dest, &name, VaultFileType::File)?,
+            };
+            let result = import_file(vault, &path, file);
+            vault.close(file)?;
+            stats.bytes += result?;
+            stats.files += 1;
+            vault.set_mode(file, mode)?;
+            vault.set_times(file, Some(atime), Some(mtime))?;
+        } else {
+            warn!("import: skipping {:?}, not a file or directory", path);
+            stats.skipped += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the content of `source` into the opened `file`. Return the
+/// number of bytes copied.
+fn import_file(vault: &mut LocalVault, source: &Path, file: Inode) -> VaultResult<u64> {
+    let mut fd = File::open(source)?;
+    let mut buf = vec![0; IMPORT_CHUNK_SIZE];
+    let mut offset = 0;
+    // Drop the old content of an existing file.
+    vault.truncate(file, 0)?;
+    loop {
+        let len = fd.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        vault.write(file, offset as i64, &buf[..len])?;
+        offset += len as u64;
+    }
+    Ok(offset)
+}
@@ -0,0 +1,103 @@
+/// Interrupting requests stuck on a peer that doesn't answer. The
+/// kernel tells a file system that a process waiting on a request got
+/// a signal with an INTERRUPT request, but fuser answers those itself
+/// with ENOSYS, and the kernel then stops sending them: a process
+/// waiting on us stays stuck even after Ctrl-C, until the RPC to a
+/// dead peer gives up. So we watch for ourselves: while a request
+/// waits on an RPC, we check now and then whether the process that
+/// made it has a signal pending that ends it, and give up on the RPC
+/// if so, failing the request with VaultError::Interrupted (EINTR).
+///
+/// The session thread and vault queue threads mark which process they
+/// serve with `serving`, and `RemoteVault` watches that process while
+/// it waits on an RPC, see `remote_vault::block_on`. Uploads and
+/// other work of the background worker serve no process and aren't
+/// interrupted. We learn of signals from /proc, so only on Linux.
+use std::cell::Cell;
+use std::fs;
+use std::time::Duration;
+
+/// How often we check the process a request waits for.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Signals that end a process unless it ignores or catches them:
+/// SIGHUP, SIGINT, SIGQUIT, SIGKILL, SIGTERM, as bits of the masks in
+/// /proc/<pid>/status (bit n - 1 for signal n).
+const ENDING_SIGNALS: u64 = (1 << (libc::SIGHUP - 1))
+    | (1 << (libc::SIGINT - 1))
+    | (1 << (libc::SIGQUIT - 1))
+    | (1 << (libc::SIGKILL - 1))
+    | (1 << (libc::SIGTERM - 1));
+
+thread_local! {
+    /// The process whose request this thread serves, see `serving`.
+    static REQUESTER: Cell<Option<u32>> = Cell::new(None);
+}
+
+/// Marks the thread as serving a process until dropped, see
+/// `serving`.
+#[derive(Debug)]
+pub struct Serving {
+    previous: Option<u32>,
+}
+
+/// Mark this thread as serving a request of process `pid` until the
+/// returned guard is dropped, so RPCs it waits on are given up if the
+/// process gets a signal that ends it. `pid` 0, requests the kernel
+/// makes on its own, eg, writing back cached pages, aren't watched.
+pub fn serving(pid: u32) -> Serving {
+    let pid = if pid == 0 { None } else { Some(pid) };
+    Serving {
+        previous: REQUESTER.with(|requester| requester.replace(pid)),
+    }
+}
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        REQUESTER.with(|requester| requester.set(self.previous));
+    }
+}
+
+/// Return the process whose request this thread serves, if any.
+pub fn requester() -> Option<u32> {
+    REQUESTER.with(|requester| requester.get())
+}
+
+/// Return true if `status`, the content of /proc/<pid>/status, shows a
+/// signal pending that ends the process: one of ENDING_SIGNALS, not
+/// blocked or ignored. SIGKILL can't be either. A caught signal
+/// interrupts the request too, like the kernel's INTERRUPT does.
+pub fn ending_signal_pending(status: &str) -> bool {
+    let mask = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| u64::from_str_radix(value.trim(), 16).ok())
+            .unwrap_or(0)
+    };
+    // Signals to the thread and to the whole process.
+    let pending = mask("SigPnd:") | mask("ShdPnd:");
+    let kill: u64 = 1 << (libc::SIGKILL - 1);
+    let stopped = mask("SigBlk:") | mask("SigIgn:");
+    pending & ENDING_SIGNALS & (!stopped | kill) != 0
+}
+
+/// Return true if process `pid` has a signal pending that ends it,
+/// see `ending_signal_pending`. False if we can't tell.
+pub fn interrupted(pid: u32) -> bool {
+    match fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => ending_signal_pending(&status),
+        Err(_) => false,
+    }
+}
+
+/// Return once process `pid` has a signal pending that ends it,
+/// checking every CHECK_INTERVAL.
+pub async fn wait_interrupted(pid: u32) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        if interrupted(pid) {
+            return;
+        }
+    }
+}
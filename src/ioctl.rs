@@ -0,0 +1,160 @@
+/// Commands scripts send with ioctl(2) on a file or directory open in
+/// the mount, to ask monovault about it without going through the
+/// vault server, see `FS::ioctl`. FUSE only passes ioctls whose
+/// request number tells the size of the data, like _IOR and _IO do,
+/// so each command has a fixed layout, of u64s in the host's byte
+/// order. Commands that lock the vault are served on its queue, like
+/// reads and writes.
+use crate::types::*;
+
+/// The type byte of our request numbers.
+pub const IOCTL_TYPE: u8 = b'M';
+
+/// The direction bits of _IOR: Linux and macOS put them differently.
+const IOC_READ: u32 = if cfg!(target_os = "linux") { 2 } else { 1 };
+
+/// Return the request number of command `nr` that returns `size`
+/// bytes, like _IOR, or nothing if `size` is 0, like _IO.
+const fn request(nr: u8, size: usize) -> u32 {
+    let dir = if size == 0 { 0 } else { IOC_READ };
+    (dir << 30) | ((size as u32) << 16) | ((IOCTL_TYPE as u32) << 8) | nr as u32
+}
+
+/// Size of the reply to IOC_WHERE: the inode in the vault, then the
+/// vault name padded with null bytes.
+pub const WHERE_SIZE: usize = 256;
+/// Size of the reply to IOC_SYNC_STATE, see `SyncState`.
+pub const SYNC_STATE_SIZE: usize = 32;
+
+/// Return the vault of the file and its inode there, see `Where`.
+/// Inodes in the mount carry the prefix of their vault, the inode in
+/// the vault is what peers, the log of the vault server and the
+/// "path" command know it by.
+pub const IOC_WHERE: u32 = request(1, WHERE_SIZE);
+/// Return whether the file has changes yet to reach the peer, and
+/// how far its download is, see `SyncState`.
+pub const IOC_SYNC_STATE: u32 = request(2, SYNC_STATE_SIZE);
+/// Drop what the mount and the kernel cached about the directory, or
+/// about the directory of the file, so the next lookup asks the vault
+/// again rather than wait for the cache to expire.
+pub const IOC_REFRESH: u32 = request(3, 0);
+/// Delete the directory and everything under it, in one request to
+/// the vault rather than one per entry. Trees with more than
+/// MAX_TREE_DELETE entries are refused with ENOTEMPTY, read-only
+/// mounts with EROFS, and callers who couldn't unlink the directory
+/// with EACCES, see `fuse::may_delete`.
+pub const IOC_DELETE_TREE: u32 = request(4, 0);
+
+/// A command, from its request number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Where,
+    SyncState,
+    Refresh,
+    DeleteTree,
+}
+
+impl Command {
+    /// Return the command of request number `cmd`, None if it isn't
+    /// ours.
+    pub fn of_request(cmd: u32) -> Option<Command> {
+        match cmd {
+            IOC_WHERE => Some(Command::Where),
+            IOC_SYNC_STATE => Some(Command::SyncState),
+            IOC_REFRESH => Some(Command::Refresh),
+            IOC_DELETE_TREE => Some(Command::DeleteTree),
+            _ => None,
+        }
+    }
+
+    /// Return the size of the reply.
+    pub fn reply_size(self) -> usize {
+        match self {
+            Command::Where => WHERE_SIZE,
+            Command::SyncState => SYNC_STATE_SIZE,
+            Command::Refresh | Command::DeleteTree => 0,
+        }
+    }
+}
+
+/// Reply to IOC_WHERE.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Where {
+    pub vault: VaultName,
+    pub inode: Inode,
+}
+
+impl Where {
+    /// Fails with FileNameTooLong if the vault name doesn't fit.
+    pub fn encode(&self) -> VaultResult<Vec<u8>> {
+        // Keep a null byte at the end, for C.
+        if self.vault.len() >= WHERE_SIZE - 8 {
+            return Err(VaultError::FileNameTooLong(self.vault.clone()));
+        }
+        let mut data = self.inode.to_ne_bytes().to_vec();
+        data.extend_from_slice(self.vault.as_bytes());
+        data.resize(WHERE_SIZE, 0);
+        Ok(data)
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Where> {
+        if data.len() != WHERE_SIZE {
+            return None;
+        }
+        let name = &data[8..];
+        let end = name.iter().position(|&byte| byte == 0)?;
+        Some(Where {
+            inode: u64::from_ne_bytes(data[..8].try_into().unwrap()),
+            vault: String::from_utf8(name[..end].to_vec()).ok()?,
+        })
+    }
+}
+
+/// Reply to IOC_SYNC_STATE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SyncState {
+    /// The status of the vault, see `fuse::peer_status`: 0 for local,
+    /// 1 online, 2 cached, 3 offline, 4 unknown.
+    pub status: u64,
+    /// Number of uploads and deletions of the file the background
+    /// worker has yet to send to the peer. Only caching vaults have
+    /// any.
+    pub pending: u64,
+    /// Bytes of the file downloaded and to download, both 0 unless a
+    /// download is going on.
+    pub fetched: u64,
+    pub total: u64,
+}
+
+impl SyncState {
+    /// Return the code of `status`, a `fuse::peer_status` or "local".
+    pub fn status_code(status: &str) -> u64 {
+        match status {
+            "local" => 0,
+            "online" => 1,
+            "cached" => 2,
+            "offline" => 3,
+            _ => 4,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        [self.status, self.pending, self.fetched, self.total]
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect()
+    }
+
+    pub fn decode(data: &[u8]) -> Option<SyncState> {
+        if data.len() != SYNC_STATE_SIZE {
+            return None;
+        }
+        let value = |idx: usize| u64::from_ne_bytes(data[idx * 8..idx * 8 + 8].try_into().unwrap());
+        Some(SyncState {
+            status: value(0),
+            pending: value(1),
+            fetched: value(2),
+            total: value(3),
+        })
+    }
+}
@@ -1,9 +1,44 @@
 pub mod background_worker;
+pub mod bandwidth;
+pub mod bans;
+pub mod bench;
+pub mod cache_policy;
 pub mod caching_remote;
+pub mod clock;
+pub mod config;
+pub mod connections;
 pub mod database;
+pub mod dir_columns;
+pub mod disk_usage;
+pub mod download;
+pub mod events;
+pub mod export;
+pub mod faults;
+pub mod file_handles;
 pub mod fuse;
+pub mod hooks;
+pub mod import;
+pub mod interrupt;
+pub mod ioctl;
 pub mod local_vault;
+pub mod locks;
+pub mod log_filter;
+pub mod manifest;
+pub mod mounts;
+pub mod prefixes;
+pub mod proxy;
+pub mod read_cache;
 pub mod remote_vault;
+pub mod retire;
+// Generated by tonic-build, see build.rs.
+#[allow(non_camel_case_types, clippy::all)]
 mod rpc;
+pub mod salvage;
+pub mod seal;
+pub mod share;
+pub mod store_health;
+pub mod store_lock;
 pub mod types;
+pub mod vault_queue;
 pub mod vault_server;
+pub mod version;
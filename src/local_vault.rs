@@ -1,10 +1,20 @@
+use crate::connections::ConnectionLog;
 /// Implementation of Vault trait that actually stores files to disk.
-use crate::database::Database;
+use crate::database::{check_name, Database, Intent, Replaced};
+use crate::events::{Event, EventBus};
+use crate::locks::{FileLock, LockKind, LockTable};
+use crate::manifest::Manifest;
+use crate::seal::sealed_stats;
+use crate::store_health::StoreHealth;
 use crate::types::*;
-use log::{debug, info};
-use std::collections::HashMap;
+use crate::version::VersionTracker;
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicU64, Ordering::SeqCst},
@@ -33,6 +43,11 @@ pub struct FdMap {
     /// expect it to be dropped and the file closed.
     read_map: Mutex<HashMap<Inode, Arc<Mutex<File>>>>,
     write_map: Mutex<HashMap<Inode, Arc<Mutex<File>>>>,
+    /// True if data files are copies of a remote vault's files, so a
+    /// lost one can be fetched again.
+    cache: bool,
+    /// What `attr` does when a data file is missing.
+    missing_data: MissingDataPolicy,
 }
 
 /// Local vault delegates metadata work to the database, and mainly
@@ -42,8 +57,6 @@ pub struct FdMap {
 pub struct LocalVault {
     /// Name of this vault.
     name: String,
-    /// Directory in which we store data files.
-    data_file_dir: PathBuf,
     /// Database for metadata.
     database: Database,
     /// File descriptor map.
@@ -52,20 +65,46 @@ pub struct LocalVault {
     /// of that file reaches 0, the file handler can be closed, and
     /// the file can be deleted from disk (if requested).
     ref_count: RefCounter,
-    /// Records whether an opened file is modified (written).
-    mod_track: RefCounter,
-    /// Records which file was forked, ie, copied by another host. If
-    /// it is forked, next change to the file bumps the major version
-    /// rather than the minor version.
-    fork_track: RefCounter,
+    /// Records whether an opened file is modified (written), and
+    /// which file was forked, ie, copied by another host.
+    versions: VersionTracker,
     /// The next allocated inode is current_inode + 1.
     current_inode: AtomicU64,
     /// Files waiting to be deleted.
     pending_delete: Vec<Inode>,
+    /// Byte-range locks on our files, taken by us and by peers.
+    locks: LockTable,
+    /// Tells which peers went away, see `set_connection_log`.
+    connections: ConnectionLog,
+    /// Where we report creations, modifications and deletions.
+    events: EventBus,
+    /// If true, refuse changes, see `set_read_only`.
+    read_only: bool,
+    /// When the vault was sealed, if it was, see `seal`.
+    sealed: Option<u64>,
+    /// Whether the store takes writes, see `set_store_health`.
+    health: StoreHealth,
+    /// When reads update access times, see `set_atime_policy`.
+    atime_policy: AtimePolicy,
+    /// How names are normalized, see `set_name_normalization`.
+    normalization: NameNormalization,
+    /// Whether names are compared ignoring case, see
+    /// `set_case_insensitive`.
+    case_insensitive: bool,
+    /// The algorithm of manifests, see `set_hash_algorithm`.
+    hash_algorithm: HashAlgorithm,
+    /// Open files read since their first open, see `note_read`.
+    read_since_open: HashSet<Inode>,
 }
 
 /*** RefCounter */
 
+impl Default for RefCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RefCounter {
     pub fn new() -> RefCounter {
         RefCounter {
@@ -128,12 +167,19 @@ impl RefCounter {
 /*** FdMap */
 
 impl FdMap {
-    pub fn new(vault_name: &str, data_file_dir: &Path) -> FdMap {
+    pub fn new(
+        vault_name: &str,
+        data_file_dir: &Path,
+        cache: bool,
+        missing_data: MissingDataPolicy,
+    ) -> FdMap {
         FdMap {
             name: vault_name.to_string(),
             data_file_dir: data_file_dir.to_path_buf(),
             read_map: Mutex::new(HashMap::new()),
             write_map: Mutex::new(HashMap::new()),
+            cache,
+            missing_data,
         }
     }
 
@@ -143,15 +189,24 @@ impl FdMap {
         self.data_file_dir.join(format!(
             "{}-{}{}",
             self.name,
-            file.to_string(),
+            file,
             if write { "-write" } else { "" }
         ))
     }
 
+    /// Get the path where a download of `file` collects its chunks
+    /// before replacing the data file. It stays around if the
+    /// download is interrupted, so the next one can reuse them.
+    pub fn partial_path(&self, file: Inode) -> PathBuf {
+        self.data_file_dir
+            .join(format!("{}-{}-partial", self.name, file))
+    }
+
     /// Open and get the file handler for `file`. `file` is created if
     /// not already exists. When this function returns successfully,
     /// the data file must exist on disk (and `check_data_file_exists`
-    /// returns true).
+    /// returns true). The write copy starts as a copy of the data
+    /// file, so writes in the middle of a file keep the rest.
     pub fn get(&self, file: Inode, write: bool) -> VaultResult<Arc<Mutex<File>>> {
         let mut map = if write {
             self.write_map.lock().unwrap()
@@ -163,13 +218,17 @@ impl FdMap {
             None => {
                 let path = self.compose_path(file, write);
                 info!("get_file, path={:?}", &path);
+                let data_path = self.compose_path(file, false);
+                if write && data_path.exists() {
+                    std::fs::copy(&data_path, &path)?;
+                }
                 // If create is true, either write or append must be
                 // true.
                 let mut fd = OpenOptions::new()
                     .create(true)
                     .read(true)
                     .write(true)
-                    .truncate(write)
+                    .truncate(false)
                     .open(&path)?;
                 // Make sure file is created.
                 fd.flush()?;
@@ -180,13 +239,73 @@ impl FdMap {
         }
     }
 
+    /// Return the size of the data file of `file`, 0 if it doesn't
+    /// exist.
+    pub fn data_size(&self, file: Inode) -> u64 {
+        std::fs::metadata(self.compose_path(file, false))
+            .map(|meta| meta.len())
+            .unwrap_or(0)
+    }
+
+    /// Replace the content of `file` with `data`, eg, with content
+    /// fetched from a remote. `file` shouldn't be open for writing.
+    pub fn replace(&self, file: Inode, data: &[u8]) -> VaultResult<()> {
+        std::fs::write(self.compose_path(file, false), data)?;
+        Ok(())
+    }
+
     pub fn take_over(&self, file: Inode) {
         let write_map = self.write_map.lock().unwrap();
-        let write_fd = Arc::clone(&write_map.get(&file).unwrap());
+        let write_fd = Arc::clone(write_map.get(&file).unwrap());
         drop(write_map);
         self.read_map.lock().unwrap().insert(file, write_fd);
     }
 
+    /// Return true if a file is open for writing.
+    pub fn has_writers(&self) -> bool {
+        !self.write_map.lock().unwrap().is_empty()
+    }
+
+    /// Return true if `file` has a write copy open.
+    pub fn writing(&self, file: Inode) -> bool {
+        self.write_map.lock().unwrap().contains_key(&file)
+    }
+
+    /// Flush what we wrote to the copies of `file` we have open to
+    /// disk, and the directory of data files, so new data files stay
+    /// too. The write copy only replaces the data file on close, the
+    /// caller journals `Intent::Synced` so `recover_synced` makes it
+    /// the data file if we stop before that.
+    pub fn sync(&self, file: Inode) -> VaultResult<()> {
+        let write_fd = self.write_map.lock().unwrap().get(&file).cloned();
+        let read_fd = self.read_map.lock().unwrap().get(&file).cloned();
+        for fd in write_fd.iter().chain(read_fd.iter()) {
+            fd.lock().unwrap().sync_all()?;
+        }
+        File::open(&self.data_file_dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Return the capacity and usage of the disk data files are on.
+    // The field types of statvfs differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn disk_stats(&self) -> VaultResult<FsStats> {
+        let path = CString::new(self.data_file_dir.as_os_str().as_bytes())
+            .map_err(|err| VaultError::InvalidArgument(err.to_string()))?;
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let block = stats.f_frsize as u64;
+        Ok(FsStats {
+            total: stats.f_blocks as u64 * block,
+            free: stats.f_bfree as u64 * block,
+            available: stats.f_bavail as u64 * block,
+            files: stats.f_files as u64,
+            free_files: stats.f_ffree as u64,
+        })
+    }
+
     /// Drop `file` (and thus saving it to disk).
     pub fn close(&self, file: Inode, modified: bool) -> VaultResult<()> {
         self.read_map.lock().unwrap().remove(&file);
@@ -208,7 +327,15 @@ impl FdMap {
 /*** Attr/read/write routine shared by local vault and caching remote  */
 
 /// The attr function used by both LocalVault and CachingRemote.
-pub fn attr(file: Inode, database: &mut Database, fd_map: &FdMap) -> VaultResult<FileInfo> {
+/// `unsynced` tells whether `file` has changes not yet committed, or
+/// not yet uploaded, which repairing a missing data file would lose,
+/// see `repair_data_file`.
+pub fn attr(
+    file: Inode,
+    database: &mut Database,
+    fd_map: &FdMap,
+    unsynced: bool,
+) -> VaultResult<FileInfo> {
     // It is entirely valid (and possible) for the userspace to
     // refer to a file that doesn't exist in the database: when a
     // remote host deletes a file in our local vault, the
@@ -223,68 +350,168 @@ pub fn attr(file: Inode, database: &mut Database, fd_map: &FdMap) -> VaultResult
         Err(err) => Err(err),
     }?;
     let size = match info.kind {
-        VaultFileType::File => {
-            let meta = std::fs::metadata(fd_map.compose_path(file, false))?;
-            meta.len()
-        }
+        VaultFileType::File => match std::fs::metadata(fd_map.compose_path(file, false)) {
+            Ok(meta) => meta.len(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                repair_data_file(file, &mut info, database, fd_map, unsynced)?;
+                0
+            }
+            Err(err) => return Err(VaultError::IOError(err)),
+        },
         VaultFileType::Directory => 1,
     };
     info.size = size;
     Ok(info)
 }
 
-/// The `read` function that is used by LocalVault and CachingRemote.
-pub fn read(file: Inode, offset: i64, size: u32, fd_map: &FdMap) -> VaultResult<Vec<u8>> {
-    let fd_lck = fd_map.get(file, false)?;
-    let mut fd = fd_lck.lock().unwrap();
-    let mut buf = vec![0; size as usize];
-    if offset >= 0 {
-        fd.seek(SeekFrom::Start(offset as u64))?;
-    } else {
-        fd.seek(SeekFrom::End(offset))?;
-    }
-    // Read exactly SIZE bytes, if not enough, read to EOF but don't
-    // error.
-    match fd.read_exact(&mut buf) {
-        Ok(()) => Ok(buf),
-        Err(err) => {
-            if err.kind() == std::io::ErrorKind::UnexpectedEof {
-                fd.read_to_end(&mut buf)?;
-                Ok(buf)
-            } else {
-                Err(VaultError::IOError(err))
+/// Handle a `file` whose metadata exists but data file doesn't,
+/// according to the policy of `fd_map`. When repairing a cached file,
+/// we reset its version to 0 so the next open fetches the content
+/// again. Files with `unsynced` changes are never repaired, an empty
+/// file would replace the changes on the next upload.
+fn repair_data_file(
+    file: Inode,
+    info: &mut FileInfo,
+    database: &mut Database,
+    fd_map: &FdMap,
+    unsynced: bool,
+) -> VaultResult<()> {
+    match fd_map.missing_data {
+        MissingDataPolicy::Repair if unsynced => {
+            error!(
+                "{}: data file of {} ({}) is missing, not repairing it, it has unsynced changes",
+                fd_map.name,
+                file,
+                database.path_of(file).unwrap_or_default()
+            );
+            Err(VaultError::DataFileMissing(file))
+        }
+        MissingDataPolicy::Error => {
+            error!(
+                "{}: data file of {} ({}) is missing",
+                fd_map.name,
+                file,
+                database.path_of(file).unwrap_or_default()
+            );
+            Err(VaultError::DataFileMissing(file))
+        }
+        MissingDataPolicy::Repair => {
+            warn!(
+                "{}: data file of {} ({}) is missing, recreating an empty one",
+                fd_map.name,
+                file,
+                database.path_of(file).unwrap_or_default()
+            );
+            File::create(fd_map.compose_path(file, false))?;
+            database.set_size(file, 0)?;
+            if fd_map.cache {
+                database.set_attr(file, None, None, None, Some((0, 0)))?;
+                info.version = (0, 0);
             }
+            Ok(())
         }
     }
 }
 
-pub fn write(file: Inode, offset: i64, data: &[u8], fd_map: &FdMap) -> VaultResult<u32> {
+/// The `read` function that is used by LocalVault and CachingRemote.
+pub fn read(file: Inode, offset: i64, size: u64, fd_map: &FdMap) -> VaultResult<Vec<u8>> {
+    let offset = check_range(offset, size)?;
+    let fd_lck = fd_map.get(file, false)?;
+    let mut fd = fd_lck.lock().unwrap();
+    fd.seek(SeekFrom::Start(offset))?;
+    // Read SIZE bytes, or to EOF if there aren't that many.
+    let mut buf = vec![];
+    (&mut *fd).take(size).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn write(file: Inode, offset: i64, data: &[u8], fd_map: &FdMap) -> VaultResult<u64> {
+    let offset = check_range(offset, data.len() as u64)?;
+    let fd_lck = fd_map.get(file, true)?;
+    let mut fd = fd_lck.lock().unwrap();
+    fd.seek(SeekFrom::Start(offset))?;
+    fd.write_all(data)?;
+    // fd_map.take_over(file);
+    Ok(data.len() as u64)
+}
+
+/// The `append` function that is used by LocalVault and CachingRemote.
+/// Finding the end and writing happen under the lock of the write
+/// copy, so appends don't overwrite each other.
+pub fn append(file: Inode, data: &[u8], fd_map: &FdMap) -> VaultResult<u64> {
     let fd_lck = fd_map.get(file, true)?;
     let mut fd = fd_lck.lock().unwrap();
+    let offset = fd.seek(SeekFrom::End(0))?;
+    check_range(offset as i64, data.len() as u64)?;
+    fd.write_all(data)?;
+    Ok(data.len() as u64)
+}
 
-    if offset >= 0 {
-        fd.seek(SeekFrom::Start(offset as u64))?;
+/// Find data, or a hole if `hole` is true, at or after `offset` in
+/// the data file of `file`, see `Vault::seek`. The file system the
+/// data files are on knows where the holes are.
+pub fn seek(file: Inode, offset: i64, hole: bool, fd_map: &FdMap) -> VaultResult<Option<u64>> {
+    let offset = check_range(offset, 0)?;
+    let fd_lck = fd_map.get(file, false)?;
+    let fd = fd_lck.lock().unwrap();
+    let whence = if hole {
+        libc::SEEK_HOLE
+    } else {
+        libc::SEEK_DATA
+    };
+    let result = unsafe { libc::lseek(fd.as_raw_fd(), offset as libc::off_t, whence) };
+    if result >= 0 {
+        return Ok(Some(result as u64));
+    }
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::ENXIO) {
+        Ok(None)
     } else {
-        fd.seek(SeekFrom::End(offset))?;
+        Err(err.into())
     }
-    fd.write_all(data)?;
-    // fd_map.take_over(file);
-    Ok(data.len() as u32)
 }
 
-pub fn readdir(dir: Inode, database: &mut Database, fd_map: &FdMap) -> VaultResult<Vec<FileInfo>> {
-    let (this, parent, entries) = database.readdir(dir)?;
-    let mut result = vec![];
-    for file in entries {
-        result.push(attr(file, database, fd_map)?)
+/// The `truncate` function that is used by LocalVault and
+/// CachingRemote. Like `write`, this changes the write copy, which
+/// replaces the data file on the last close.
+pub fn truncate(file: Inode, size: u64, fd_map: &FdMap) -> VaultResult<()> {
+    check_range(0, size)?;
+    let fd_lck = fd_map.get(file, true)?;
+    let fd = fd_lck.lock().unwrap();
+    fd.set_len(size)?;
+    Ok(())
+}
+
+/// The `readdir` function that is used by LocalVault and
+/// CachingRemote. Only returns the children of `dir`, no "." or "..".
+/// `unsynced` is given to `attr` for each child.
+pub fn readdir(
+    dir: Inode,
+    database: &mut Database,
+    fd_map: &FdMap,
+    unsynced: impl Fn(Inode) -> bool,
+) -> VaultResult<Vec<FileInfo>> {
+    let (_, _, entries) = database.readdir(dir)?;
+    let mut names: Vec<(Inode, Option<String>)> =
+        entries.into_iter().map(|file| (file, None)).collect();
+    for (file, name) in database.links_in(dir)? {
+        names.push((file, Some(name)));
     }
-    let mut current_dir = attr(this, database, fd_map)?;
-    current_dir.name = ".".to_string();
-    result.push(current_dir);
-    if parent != 0 {
-        let mut parrent_dir = attr(parent, database, fd_map)?;
-        parrent_dir.name = "..".to_string();
-        result.push(parrent_dir);
+    let mut result = vec![];
+    for (file, name) in names {
+        // Leave out a broken file rather than failing the whole
+        // listing, `attr` already logged it.
+        match attr(file, database, fd_map, unsynced(file)) {
+            Ok(mut info) => {
+                // A link shows under its own name.
+                if let Some(name) = name {
+                    info.name = name;
+                }
+                result.push(info)
+            }
+            Err(VaultError::DataFileMissing(_)) => (),
+            Err(err) => return Err(err),
+        }
     }
     Ok(result)
 }
@@ -299,26 +526,67 @@ pub fn has_file(file: Inode, database: &mut Database) -> VaultResult<bool> {
     }
 }
 
-/// Bump `version` according to `modified` and `fork_track`, possibly
-/// updating `fork_track`. Return the new version. If not modified,
-/// version doesn't change, if forked, bump major version and reset
-/// fork_track, if not, bump minor version.
-pub fn calculate_version(
+/// Make the write copy of `file`, synced by an fsync before we
+/// stopped with the file open, its data file, like the last close
+/// would have. Return the new version of `file`, None if there is no
+/// write copy or `file` is gone.
+pub fn recover_synced(
     file: Inode,
-    version: FileVersion,
-    modified: bool,
-    fork_track: &mut RefCounter,
-) -> FileVersion {
-    if modified {
-        if fork_track.nonzero(file) {
-            fork_track.zero(file);
-            (version.0 + 1, 0)
-        } else {
-            (version.0, version.1 + 1)
+    database: &mut Database,
+    fd_map: &FdMap,
+) -> VaultResult<Option<FileVersion>> {
+    let write_path = fd_map.compose_path(file, true);
+    let mtime = match std::fs::metadata(&write_path) {
+        Ok(meta) => meta
+            .modified()?
+            .duration_since(time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if !has_file(file, database)? {
+        return Ok(None);
+    }
+    warn!(
+        "{}: recovering the synced write copy of {}, left open when we stopped",
+        fd_map.name, file
+    );
+    std::fs::rename(&write_path, fd_map.compose_path(file, false))?;
+    File::open(&fd_map.data_file_dir)?.sync_all()?;
+    let version = database.attr(file)?.version;
+    let version = (version.0, version.1 + 1);
+    database.set_attr(file, None, None, Some(mtime), Some(version))?;
+    database.set_size(file, fd_map.data_size(file))?;
+    Ok(Some(version))
+}
+
+/// Finish the operations recorded in the journal of `database` that
+/// were cut short, eg, by a crash. Data files only stay if their
+/// metadata made it to the database: a create that didn't record the
+/// file is undone, and a delete that did remove it is finished. A
+/// synced write copy of a file left open replaces its data file.
+fn replay_journal(database: &mut Database, fd_map: &FdMap) -> VaultResult<()> {
+    for (file, intent) in database.journaled()? {
+        if intent == Intent::Synced {
+            recover_synced(file, database, fd_map)?;
+        } else if !has_file(file, database)? {
+            warn!(
+                "{}: removing the data file of {}, left by an interrupted {:?}",
+                fd_map.name, file, intent
+            );
+            for write in [false, true] {
+                match std::fs::remove_file(fd_map.compose_path(file, write)) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(err.into())
+                    }
+                    _ => (),
+                }
+            }
         }
-    } else {
-        version
+        database.settle(file)?;
     }
+    Ok(())
 }
 
 /*** LocalVault methods  */
@@ -327,8 +595,15 @@ impl LocalVault {
     /// `name` is the name of the vault, also the directory name of
     /// the vault root. `store_path` is the directory for database and
     /// data files. `store_path/db` contains databases and
-    /// `store_path/data` contains data files.
-    pub fn new(name: &str, store_path: &Path) -> VaultResult<LocalVault> {
+    /// `store_path/data` contains data files. Changes to the vault
+    /// are reported to `events`. `missing_data` decides what to do
+    /// with files whose data file is lost.
+    pub fn new(
+        name: &str,
+        store_path: &Path,
+        events: EventBus,
+        missing_data: MissingDataPolicy,
+    ) -> VaultResult<LocalVault> {
         let data_file_dir = store_path.join("data");
         if !data_file_dir.exists() {
             std::fs::create_dir(&data_file_dir)?
@@ -337,22 +612,212 @@ impl LocalVault {
         if !db_dir.exists() {
             std::fs::create_dir(&db_dir)?
         }
-        let database = Database::new(&db_dir, name)?;
+        let mut database = Database::new(&db_dir, name)?;
+        let fd_map = FdMap::new(name, &data_file_dir, false, missing_data);
+        replay_journal(&mut database, &fd_map)?;
+        if database.usage_stale() {
+            database.rebuild_usage(|file, _| fd_map.data_size(file))?;
+        }
         let current_inode = { database.largest_inode() };
         info!("vault {} next_inode={}", name, current_inode);
+        let sealed = database.sealed()?;
+        if let Some(time) = sealed {
+            info!("vault {} is sealed since {}", name, time);
+        }
         Ok(LocalVault {
             name: name.to_string(),
             database,
-            fd_map: FdMap::new(name, &data_file_dir),
-            data_file_dir,
+            fd_map,
             ref_count: RefCounter::new(),
-            mod_track: RefCounter::new(),
-            fork_track: RefCounter::new(),
+            versions: VersionTracker::new(),
             current_inode: AtomicU64::new(current_inode),
             pending_delete: vec![],
+            locks: LockTable::new(),
+            connections: ConnectionLog::new(),
+            events,
+            read_only: false,
+            sealed,
+            health: StoreHealth::new(store_path),
+            atime_policy: AtimePolicy::default(),
+            normalization: NameNormalization::default(),
+            case_insensitive: false,
+            hash_algorithm: HashAlgorithm::default(),
+            read_since_open: HashSet::new(),
         })
     }
 
+    /// When `read_only` is true, refuse every change to the vault
+    /// with VaultError::ReadOnly. Reading, opening and closing files
+    /// still work.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Seal the vault, see src/seal.rs: refuse every change from now
+    /// on, for good. Fails if a file is open for writing, what is
+    /// written to it couldn't be saved. Return when the vault was
+    /// sealed, sealing it again changes nothing.
+    pub fn seal(&mut self) -> VaultResult<u64> {
+        if let Some(time) = self.sealed {
+            return Ok(time);
+        }
+        self.health.check(&self.name)?;
+        if self.fd_map.has_writers() {
+            return Err(VaultError::InvalidArgument(
+                "files are open for writing, close them first".to_string(),
+            ));
+        }
+        let time = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)?
+            .as_secs();
+        self.health.note(self.database.seal(time))?;
+        info!("vault {} sealed at {}", self.name, time);
+        self.sealed = Some(time);
+        Ok(time)
+    }
+
+    /// Return when the vault was sealed, None if it isn't.
+    pub fn sealed(&self) -> Option<u64> {
+        self.sealed
+    }
+
+    /// Drop the locks of peers once `connections`, the log our remote
+    /// vaults report to, says the connection to them went down after
+    /// they took the locks, see `LockTable::forget_departed`.
+    pub fn set_connection_log(&mut self, connections: ConnectionLog) {
+        self.connections = connections;
+    }
+
+    /// Refuse changes while `health` says the store doesn't take
+    /// writes, and tell it when a change fails that way. Vaults in
+    /// the same store should share one.
+    pub fn set_store_health(&mut self, health: StoreHealth) {
+        self.health = health;
+    }
+
+    /// Return the health of our store, see `set_store_health`.
+    pub fn store_health(&self) -> &StoreHealth {
+        &self.health
+    }
+
+    /// Return the path of `file` in the vault, see
+    /// `Database::path_of`.
+    pub fn path_of(&self, file: Inode) -> VaultResult<String> {
+        self.database.path_of(file)
+    }
+
+    /// Refresh the statistics of the database and vacuum it if
+    /// needed, see `Database::maintain`.
+    pub fn maintain_database(&mut self) -> VaultResult<Option<u64>> {
+        self.database.maintain()
+    }
+
+    /// Update access times on reads as `policy` says from now on. The
+    /// default is relatime.
+    pub fn set_atime_policy(&mut self, policy: AtimePolicy) {
+        self.atime_policy = policy;
+    }
+
+    /// Normalize the names of files created, renamed and linked from
+    /// now on as `policy` says, and compare names in their normal
+    /// form, so a name matches names already there in another form.
+    /// Names already there keep their form. The default is to keep
+    /// names as they come.
+    pub fn set_name_normalization(&mut self, policy: NameNormalization) {
+        self.normalization = policy;
+    }
+
+    /// Compare names ignoring case from now on, if `case_insensitive`
+    /// is true: a name matches names already there that differ only
+    /// by case, so creating one of them fails and looking one up
+    /// finds it. Names keep the case they are given. The default is
+    /// to compare case.
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+    }
+
+    /// Compute manifests in `algorithm` from now on, for peers that
+    /// know it, see `manifest`.
+    pub fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) {
+        self.hash_algorithm = algorithm;
+    }
+
+    /// Return `name` in the form names are compared in, see
+    /// `set_name_normalization` and `set_case_insensitive`.
+    fn name_key(&self, name: &str) -> String {
+        let name = self.normalization.apply(name);
+        if self.case_insensitive {
+            fold_case(&name)
+        } else {
+            name
+        }
+    }
+
+    /// Return the entry of directory `parent` whose name is `name`,
+    /// compared in normal form, see `set_name_normalization`. If
+    /// case is ignored and names differing only by case are there,
+    /// eg, from before, the one of the same case wins.
+    fn entry_named(&mut self, parent: Inode, name: &str) -> VaultResult<Option<FileInfo>> {
+        let policy = self.normalization;
+        let normal = policy.apply(name);
+        let mut entries = self.readdir(parent)?;
+        if let Some(idx) = entries
+            .iter()
+            .position(|info| policy.apply(&info.name) == normal)
+        {
+            return Ok(Some(entries.swap_remove(idx)));
+        }
+        if !self.case_insensitive {
+            return Ok(None);
+        }
+        let key = self.name_key(name);
+        Ok(entries
+            .into_iter()
+            .find(|info| self.name_key(&info.name) == key))
+    }
+
+    /// Update the access time of `file`, just read, if the atime
+    /// policy says so. Only the first read after the file is opened
+    /// counts, so reading doesn't cost a database query each time.
+    fn note_read(&mut self, file: Inode) {
+        if self.atime_policy == AtimePolicy::Noatime || !self.read_since_open.insert(file) {
+            return;
+        }
+        let now = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+            Ok(now) => now.as_secs(),
+            Err(_) => return,
+        };
+        // The file may be deleted but still open, reading it goes on
+        // without an access time.
+        let result = match self.database.attr(file) {
+            Ok(info) if self.atime_policy.should_update(info.atime, info.mtime, now) => {
+                self.database.set_attr(file, None, Some(now), None, None)
+            }
+            Ok(_) => Ok(()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            debug!("note_read({}) => {:?}", file, err);
+        }
+    }
+
+    fn check_writable(&self) -> VaultResult<()> {
+        if self.read_only || self.sealed.is_some() {
+            Err(VaultError::ReadOnly(self.name.clone()))
+        } else {
+            self.health.check(&self.name)
+        }
+    }
+
+    /// Fail with FileNotExist if `file` isn't in the vault.
+    fn check_exists(&mut self, file: Inode) -> VaultResult<()> {
+        if has_file(file, &mut self.database)? {
+            Ok(())
+        } else {
+            Err(VaultError::FileNotExist(file))
+        }
+    }
+
     /// Return a new inode.
     fn new_inode(&self) -> Inode {
         self.current_inode
@@ -379,26 +844,93 @@ impl LocalVault {
         }
     }
 
-    /// Mark `file` as forked, so next change will bump major version.
-    fn mark_forked(&mut self, file: Inode) {
-        self.fork_track.incf(file);
+    /// Return the (version, size) of `file` we would give a savage
+    /// request, without giving it.
+    pub fn cached_version(&mut self, file: Inode) -> VaultResult<(FileVersion, u64)> {
+        let info = attr(
+            file,
+            &mut self.database,
+            &self.fd_map,
+            self.versions.dirty(file),
+        )?;
+        Ok((info.version, info.size))
     }
 
     /// Serve savage request by searching in "cache".
     pub fn search_in_cache(&mut self, file: Inode) -> VaultResult<(Vec<u8>, FileVersion)> {
-        let info = attr(file, &mut self.database, &mut self.fd_map)?;
-        let data = read(file, 0, info.size as u32, &mut self.fd_map)?;
-        self.mark_forked(file);
+        let info = attr(
+            file,
+            &mut self.database,
+            &self.fd_map,
+            self.versions.dirty(file),
+        )?;
+        let data = read(file, 0, info.size, &self.fd_map)?;
+        self.versions.fork(file);
         Ok((data, info.version))
     }
 
-    /// Handle submission.
-    pub fn submit(&mut self, file: Inode, data: &[u8], version: FileVersion) -> VaultResult<bool> {
+    /// Return the chunk manifest of the committed content of `file`,
+    /// in our algorithm if it is in `known`, the algorithms whoever
+    /// asks knows, see `HashAlgorithm::negotiate`. Manifests are
+    /// computed when first asked for and recorded in the database
+    /// until the file changes, or is asked for in another algorithm.
+    pub fn manifest(&mut self, file: Inode, known: &[HashAlgorithm]) -> VaultResult<Manifest> {
+        self.check_is_regular_file(file)?;
+        self.check_data_file_exists(file)?;
+        // Whoever asks is about to copy the file, like savage.
+        self.versions.fork(file);
+        let version = self.database.attr(file)?.version;
+        let algorithm = self.hash_algorithm.negotiate(known);
+        if let Some(manifest) = self.database.manifest(file)? {
+            if manifest.version == version
+                && manifest.size == self.fd_map.data_size(file)
+                && manifest.algorithm == algorithm
+            {
+                return Ok(manifest);
+            }
+        }
+        debug!(
+            "manifest({}) => computing for version {:?} in {:?}",
+            file, version, algorithm
+        );
+        let manifest =
+            Manifest::of_file(&self.fd_map.compose_path(file, false), version, algorithm)?;
+        self.database.set_manifest(file, &manifest)?;
+        Ok(manifest)
+    }
+
+    /// Return true if `file` is `dir` or somewhere under it.
+    pub fn is_within(&self, file: Inode, dir: Inode) -> VaultResult<bool> {
+        self.database.attr(file)?;
+        let mut current = file;
+        loop {
+            if current == dir {
+                return Ok(true);
+            }
+            if current == 1 {
+                return Ok(false);
+            }
+            current = self.database.readdir(current)?.1;
+        }
+    }
+
+    /// Handle submission. The file gets modification time `mtime`,
+    /// if given, the time it arrives otherwise.
+    pub fn submit(
+        &mut self,
+        file: Inode,
+        data: &[u8],
+        version: FileVersion,
+        mtime: Option<u64>,
+    ) -> VaultResult<bool> {
+        self.check_writable()?;
         let local_version = self.database.attr(file)?.version;
         if local_version.0 <= version.0 {
-            // Accept.
-            self.write(file, 0, data)?;
-            self.mark_forked(file);
+            // Accept. Write straight to the data file rather than the
+            // write copy, which only reaches the data file when the
+            // file is closed, and nobody may have it open.
+            std::fs::write(self.fd_map.compose_path(file, false), data)?;
+            self.versions.fork(file);
             let current_time = time::SystemTime::now()
                 .duration_since(time::UNIX_EPOCH)?
                 .as_secs();
@@ -406,14 +938,52 @@ impl LocalVault {
                 file,
                 None,
                 Some(current_time),
-                Some(current_time),
+                Some(mtime.unwrap_or(current_time)),
                 Some(version),
             )?;
+            self.database.set_size(file, data.len() as u64)?;
+            self.events.emit(Event::Modified {
+                vault: self.name(),
+                file,
+                version,
+            });
             Ok(true)
         } else {
             Ok(false)
         }
     }
+
+    /// Finish deleting `file` of `kind` once the database no longer
+    /// has it: remove its data file unless it is open, and tell
+    /// listeners.
+    fn deleted(&mut self, file: Inode, kind: VaultFileType) -> VaultResult<()> {
+        // NOTE: Make sure we remove metadata before removing data
+        // file, to ensure consistency.
+        match kind {
+            VaultFileType::File => {
+                self.check_data_file_exists(file)?;
+                if self.ref_count.count(file) == 0 {
+                    std::fs::remove_file(self.fd_map.compose_path(file, false))?;
+                    self.database.settle(file)?;
+                    self.locks.forget(file);
+                } else {
+                    // If there are other references to the file,
+                    // don't delete yet. The journal finishes the
+                    // delete if we crash before.
+                    let queue = &mut self.pending_delete;
+                    if !queue.contains(&file) {
+                        queue.push(file)
+                    }
+                }
+            }
+            VaultFileType::Directory => (),
+        }
+        self.events.emit(Event::Deleted {
+            vault: self.name(),
+            file,
+        });
+        Ok(())
+    }
 }
 
 /*** Vault implementation of LocalVault */
@@ -425,9 +995,10 @@ impl Vault for LocalVault {
 
     fn tear_down(&mut self) -> VaultResult<()> {
         info!("tear_down()");
-        let queue = &self.pending_delete;
-        for &file in queue.iter() {
+        // Tear down runs again if the file system is mounted again.
+        for file in std::mem::take(&mut self.pending_delete) {
             std::fs::remove_file(self.fd_map.compose_path(file, false))?;
+            self.database.settle(file)?;
         }
         Ok(())
     }
@@ -435,7 +1006,12 @@ impl Vault for LocalVault {
     fn attr(&mut self, file: Inode) -> VaultResult<FileInfo> {
         debug!("attr({})", file);
 
-        let info = attr(file, &mut self.database, &mut self.fd_map)?;
+        let info = attr(
+            file,
+            &mut self.database,
+            &self.fd_map,
+            self.versions.dirty(file),
+        )?;
 
         debug!(
             "(inode={}, name={}, size={}, atime={}, mtime={}, kind={:?})",
@@ -444,7 +1020,7 @@ impl Vault for LocalVault {
         Ok(info)
     }
 
-    fn read(&mut self, file: Inode, offset: i64, size: u32) -> VaultResult<Vec<u8>> {
+    fn read(&mut self, file: Inode, offset: i64, size: u64) -> VaultResult<Vec<u8>> {
         info!("read(file={}, offset={}, size={})", file, offset, size);
         // We don't access database during read because delete() will
         // remove the file from the database but before the last
@@ -453,10 +1029,12 @@ impl Vault for LocalVault {
         //
         // self.check_is_regular_file(file)?;
         self.check_data_file_exists(file)?;
-        read(file, offset, size, &mut self.fd_map)
+        let data = read(file, offset, size, &self.fd_map)?;
+        self.note_read(file);
+        Ok(data)
     }
 
-    fn write(&mut self, file: Inode, offset: i64, data: &[u8]) -> VaultResult<u32> {
+    fn write(&mut self, file: Inode, offset: i64, data: &[u8]) -> VaultResult<u64> {
         info!(
             "write(file={}, offset={}, size={})",
             file,
@@ -469,16 +1047,47 @@ impl Vault for LocalVault {
         // and write.
         //
         // self.check_is_regular_file(file)?;
+        self.check_writable()?;
+        self.check_data_file_exists(file)?;
+        let size = write(file, offset, data, &self.fd_map)?;
+        self.versions.write(file);
+        Ok(size)
+    }
+
+    fn append(&mut self, file: Inode, data: &[u8]) -> VaultResult<u64> {
+        info!("append(file={}, size={})", file, data.len());
+        self.check_writable()?;
         self.check_data_file_exists(file)?;
-        let size = write(file, offset, data, &mut self.fd_map)?;
-        self.mod_track.incf(file)?;
-        Ok(size as u32)
+        let size = append(file, data, &self.fd_map)?;
+        self.versions.write(file);
+        Ok(size)
+    }
+
+    fn truncate(&mut self, file: Inode, size: u64) -> VaultResult<()> {
+        info!("truncate(file={}, size={})", file, size);
+        self.check_writable()?;
+        // Truncate(2) doesn't need the file open, open it ourselves
+        // so the last close commits the change.
+        self.open(file, OpenMode::RW)?;
+        let result = truncate(file, size, &self.fd_map);
+        if result.is_ok() {
+            self.versions.write(file);
+        }
+        self.close(file)?;
+        result
     }
 
     fn create(&mut self, parent: Inode, name: &str, kind: VaultFileType) -> VaultResult<Inode> {
         info!("create(parent={}, name={}, kind={:?})", parent, name, kind);
-        let already_has_file = self.readdir(parent)?.iter().any(|info| info.name == name);
-        if already_has_file {
+        self.check_writable()?;
+        let name = &self.normalization.apply(name);
+        if let Some(existing) = self.entry_named(parent, name)? {
+            if existing.name != *name {
+                info!(
+                    "create({}) => {} is there, names are compared ignoring case",
+                    name, existing.name
+                );
+            }
             return Err(VaultError::FileAlreadyExist(parent, name.to_string()));
         }
         let inode = self.new_inode();
@@ -486,6 +1095,7 @@ impl Vault for LocalVault {
         // file. We need to call get_file to ensure the data file is
         // created.
         if let VaultFileType::File = kind {
+            self.database.journal(inode, Intent::Create)?;
             self.fd_map.get(inode, false)?;
         }
         // NOTE: Make sure we create data file before creating
@@ -501,13 +1111,24 @@ impl Vault for LocalVault {
             current_time,
             current_time,
             (1, 0),
+            default_mode(kind),
         )?;
+        if let VaultFileType::File = kind {
+            self.database.settle(inode)?;
+        }
         self.ref_count.incf(inode)?;
+        self.events.emit(Event::Created {
+            vault: self.name(),
+            file: inode,
+            parent,
+            name: name.to_string(),
+            kind,
+        });
         info!("created {}", inode);
         Ok(inode)
     }
 
-    fn open(&mut self, file: Inode, mode: OpenMode) -> VaultResult<()> {
+    fn open(&mut self, file: Inode, _mode: OpenMode) -> VaultResult<()> {
         info!(
             "open({}) ref_count {}->{}",
             file,
@@ -537,60 +1158,351 @@ impl Vault for LocalVault {
             self.ref_count.count(file)
         );
         if count == 0 {
-            // Update mtime and version.
+            self.read_since_open.remove(&file);
+            // Update mtime and version. Reads took care of atime.
             let current_time = time::SystemTime::now()
                 .duration_since(time::UNIX_EPOCH)?
                 .as_secs();
-            let modified = self.mod_track.nonzero(file);
             let version = self.database.attr(file)?.version;
-            let new_version = calculate_version(file, version, modified, &mut self.fork_track);
-            self.database.set_attr(
-                file,
-                None,
-                Some(current_time),
-                if modified { Some(current_time) } else { None },
-                if modified { Some(new_version) } else { None },
-            )?;
+            let new_version = self.versions.commit(file, version);
+            let modified = new_version.is_some();
+            if modified {
+                self.database
+                    .set_attr(file, None, None, Some(current_time), new_version)?;
+            }
             // When the file is dropped it is automatically closed. We
             // never store the file elsewhere and ref_count is 0 so
             // this is when the file is dropped.
             self.fd_map.close(file, modified)?;
-            self.mod_track.zero(file);
+            if let Some(version) = new_version {
+                self.database.settle_intent(file, Intent::Synced)?;
+                self.database.set_size(file, self.fd_map.data_size(file))?;
+                self.events.emit(Event::Modified {
+                    vault: self.name(),
+                    file,
+                    version,
+                });
+            }
         }
         Ok(())
     }
 
+    fn fsync(&mut self, file: Inode) -> VaultResult<()> {
+        info!("fsync({})", file);
+        // The database commits each change as it's made, the data
+        // files are what's left.
+        self.fd_map.sync(file)?;
+        // A file deleted while open has its delete journaled.
+        if self.fd_map.writing(file) && !self.pending_delete.contains(&file) {
+            self.database.journal(file, Intent::Synced)?;
+        }
+        Ok(())
+    }
+
+    fn seek(&mut self, file: Inode, offset: i64, hole: bool) -> VaultResult<Option<u64>> {
+        debug!("seek(file={}, offset={}, hole={})", file, offset, hole);
+        // Like read, this works on files deleted while open.
+        self.check_data_file_exists(file)?;
+        seek(file, offset, hole, &self.fd_map)
+    }
+
+    fn set_mode(&mut self, file: Inode, mode: u32) -> VaultResult<()> {
+        info!("set_mode(file={}, mode={:o})", file, mode);
+        self.check_writable()?;
+        self.database.set_mode(file, mode)
+    }
+
+    fn set_owner(&mut self, file: Inode, uid: u32, gid: u32) -> VaultResult<()> {
+        info!("set_owner(file={}, uid={}, gid={})", file, uid, gid);
+        self.check_writable()?;
+        self.database.set_owner(file, uid, gid)
+    }
+
+    fn set_times(
+        &mut self,
+        file: Inode,
+        atime: Option<u64>,
+        mtime: Option<u64>,
+    ) -> VaultResult<()> {
+        info!(
+            "set_times(file={}, atime={:?}, mtime={:?})",
+            file, atime, mtime
+        );
+        self.check_writable()?;
+        // Fail on missing files rather than updating nothing.
+        self.database.attr(file)?;
+        self.database.set_attr(file, None, atime, mtime, None)
+    }
+
     fn delete(&mut self, file: Inode) -> VaultResult<()> {
         info!("delete({})", file);
+        self.check_writable()?;
         // Prefetch kind and store it, because we won't be able to
         // get it after deleting the file.
         let kind = self.database.attr(file)?.kind;
+        // Links in a directory are entries too.
+        if let VaultFileType::Directory = kind {
+            if !self.database.links_in(file)?.is_empty() {
+                return Err(VaultError::DirectoryNotEmpty(file));
+            }
+        }
+        if let VaultFileType::File = kind {
+            self.database.journal(file, Intent::Delete)?;
+        }
         // Database will check for nonempty directory for us.
         self.database.remove_file(file)?;
-        // NOTE: Make sure we remove metadata before removing data
-        // file, to ensure consistency.
-        match kind {
-            VaultFileType::File => {
-                self.check_data_file_exists(file)?;
-                if self.ref_count.count(file) == 0 {
-                    std::fs::remove_file(self.fd_map.compose_path(file, false))?;
-                } else {
-                    // If there are other references to the file,
-                    // don't delete yet.
-                    let queue = &mut self.pending_delete;
-                    if !queue.contains(&file) {
-                        queue.push(file)
+        self.deleted(file, kind)
+    }
+
+    fn delete_tree(&mut self, dir: Inode, limit: u64) -> VaultResult<u64> {
+        info!("delete_tree(dir={}, limit={})", dir, limit);
+        self.check_writable()?;
+        if dir == 1 {
+            return Err(VaultError::InvalidArgument(
+                "cannot delete vault root".to_string(),
+            ));
+        }
+        let tree = self.database.subtree(dir)?;
+        let count = tree.len() as u64;
+        if count > limit {
+            return Err(VaultError::TreeTooLarge(dir, count));
+        }
+        // Links in the tree go first. A file that has names outside
+        // the tree keeps them.
+        for (file, kind) in tree.iter() {
+            if let VaultFileType::Directory = kind {
+                for (linked, name) in self.database.links_in(*file)? {
+                    self.unlink(linked, *file, &name)?;
+                }
+            }
+        }
+        // Subtree is in post-order, so directories are empty by the
+        // time we delete them.
+        for (file, kind) in tree {
+            match (kind, self.database.parent(file)?) {
+                (VaultFileType::File, Some(parent)) => {
+                    let name = self.database.attr(file)?.name;
+                    self.unlink(file, parent, &name)?;
+                }
+                _ => self.delete(file)?,
+            }
+        }
+        Ok(count)
+    }
+
+    fn rename(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!("rename(file={}, parent={}, name={})", file, parent, name);
+        self.check_writable()?;
+        let name = &self.normalization.apply(name);
+        check_name(name)?;
+        if file == 1 {
+            return Err(VaultError::InvalidArgument(
+                "cannot move vault root".to_string(),
+            ));
+        }
+        let kind = self.database.attr(file)?.kind;
+        if let VaultFileType::File = self.database.attr(parent)?.kind {
+            return Err(VaultError::NotDirectory(parent));
+        }
+        // A directory moved under itself would be cut off from the
+        // tree.
+        if self.is_within(parent, file)? {
+            return Err(VaultError::InvalidArgument(format!(
+                "cannot move {} under itself",
+                file
+            )));
+        }
+        let mut replacing = None;
+        if let Some(replaced) = self.entry_named(parent, name)? {
+            if replaced.inode == file && replaced.name == *name {
+                return Ok(());
+            }
+            // Otherwise only the case changes, see
+            // `set_case_insensitive`.
+            if replaced.inode != file {
+                match (kind, replaced.kind) {
+                    (VaultFileType::File, VaultFileType::Directory) => {
+                        return Err(VaultError::IsDirectory(replaced.inode))
+                    }
+                    (VaultFileType::Directory, VaultFileType::File) => {
+                        return Err(VaultError::NotDirectory(replaced.inode))
+                    }
+                    // Links in a directory are entries too.
+                    (VaultFileType::Directory, VaultFileType::Directory) => {
+                        if !self.database.links_in(replaced.inode)?.is_empty() {
+                            return Err(VaultError::DirectoryNotEmpty(replaced.inode));
+                        }
                     }
+                    (VaultFileType::File, VaultFileType::File) => (),
                 }
+                replacing = Some(replaced);
             }
-            VaultFileType::Directory => (),
         }
+        // The replaced entry goes in the same transaction as the
+        // move, so the name never names nothing, and the data file
+        // of a replaced file only after that.
+        match &replacing {
+            None => self.database.move_file(file, parent, name)?,
+            // Only this name of the replaced file goes.
+            Some(replaced) if replaced.nlink > 1 => self.database.move_over(
+                file,
+                parent,
+                name,
+                Replaced::Name(replaced.inode, replaced.name.clone()),
+            )?,
+            // The database refuses nonempty directories.
+            Some(replaced) => {
+                if let VaultFileType::File = replaced.kind {
+                    self.database.journal(replaced.inode, Intent::Delete)?;
+                }
+                self.database
+                    .move_over(file, parent, name, Replaced::File(replaced.inode))?
+            }
+        }
+        match replacing {
+            Some(replaced) if replaced.nlink > 1 => self.events.emit(Event::Unlinked {
+                vault: self.name(),
+                file: replaced.inode,
+                parent,
+                name: replaced.name,
+            }),
+            Some(replaced) => self.deleted(replaced.inode, replaced.kind)?,
+            None => (),
+        }
+        self.events.emit(Event::Renamed {
+            vault: self.name(),
+            file,
+            parent,
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    fn link(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!("link(file={}, parent={}, name={})", file, parent, name);
+        self.check_writable()?;
+        if let VaultFileType::Directory = self.database.attr(file)?.kind {
+            return Err(VaultError::IsDirectory(file));
+        }
+        if let VaultFileType::File = self.database.attr(parent)?.kind {
+            return Err(VaultError::NotDirectory(parent));
+        }
+        let name = &self.normalization.apply(name);
+        if self.entry_named(parent, name)?.is_some() {
+            return Err(VaultError::FileAlreadyExist(parent, name.to_string()));
+        }
+        self.database.add_link(file, parent, name)?;
+        self.events.emit(Event::Linked {
+            vault: self.name(),
+            file,
+            parent,
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    fn unlink(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!("unlink(file={}, parent={}, name={})", file, parent, name);
+        self.check_writable()?;
+        let info = self.database.attr(file)?;
+        if let VaultFileType::Directory = info.kind {
+            return Err(VaultError::IsDirectory(file));
+        }
+        // The name as stored, which may be in another form or case.
+        let wanted = self.name_key(name);
+        let name = match self
+            .readdir(parent)?
+            .into_iter()
+            .find(|entry| entry.inode == file && self.name_key(&entry.name) == wanted)
+        {
+            Some(entry) => entry.name,
+            None => return Err(VaultError::FileNotExist(file)),
+        };
+        if info.nlink == 1 {
+            return self.delete(file);
+        }
+        self.database.remove_name(file, parent, &name)?;
+        self.events.emit(Event::Unlinked {
+            vault: self.name(),
+            file,
+            parent,
+            name: name.to_string(),
+        });
         Ok(())
     }
 
+    fn xattr(&mut self, file: Inode, name: &str) -> VaultResult<Option<Vec<u8>>> {
+        debug!("xattr(file={}, name={})", file, name);
+        self.check_exists(file)?;
+        self.database.xattr(file, name)
+    }
+
+    fn xattr_names(&mut self, file: Inode) -> VaultResult<Vec<String>> {
+        debug!("xattr_names({})", file);
+        self.check_exists(file)?;
+        self.database.xattr_names(file)
+    }
+
+    fn set_xattr(&mut self, file: Inode, name: &str, value: &[u8]) -> VaultResult<()> {
+        info!("set_xattr(file={}, name={})", file, name);
+        self.check_writable()?;
+        self.check_exists(file)?;
+        self.database.set_xattr(file, name, value)
+    }
+
+    fn remove_xattr(&mut self, file: Inode, name: &str) -> VaultResult<()> {
+        info!("remove_xattr(file={}, name={})", file, name);
+        self.check_writable()?;
+        self.check_exists(file)?;
+        self.database.remove_xattr(file, name)
+    }
+
+    fn info(&mut self, dir: Inode) -> VaultResult<DirInfo> {
+        debug!("info({})", dir);
+        match self.database.usage(dir) {
+            Err(VaultError::SqliteError(rusqlite::Error::QueryReturnedNoRows)) => {
+                Err(VaultError::FileNotExist(dir))
+            }
+            result => result,
+        }
+    }
+
+    fn getlk(&mut self, file: Inode, lock: &FileLock) -> VaultResult<Option<FileLock>> {
+        debug!("getlk(file={}, lock={:?})", file, lock);
+        self.check_exists(file)?;
+        let connections = &self.connections;
+        self.locks
+            .forget_departed(|peer| connections.last_down(peer));
+        Ok(self.locks.conflict(file, lock))
+    }
+
+    fn setlk(&mut self, file: Inode, lock: &FileLock) -> VaultResult<()> {
+        info!("setlk(file={}, lock={:?})", file, lock);
+        // A file deleted while open is still unlocked on close.
+        if lock.kind != LockKind::Unlock {
+            self.check_exists(file)?;
+        }
+        let connections = &self.connections;
+        self.locks
+            .forget_departed(|peer| connections.last_down(peer));
+        self.locks.set(file, lock.clone())
+    }
+
+    fn statfs(&mut self) -> VaultResult<FsStats> {
+        debug!("statfs()");
+        let stats = self.fd_map.disk_stats()?;
+        if self.sealed.is_some() {
+            Ok(sealed_stats(stats))
+        } else {
+            Ok(stats)
+        }
+    }
+
     fn readdir(&mut self, dir: Inode) -> VaultResult<Vec<FileInfo>> {
         debug!("readdir({})", dir);
-        let result = readdir(dir, &mut self.database, &mut self.fd_map)?;
+        let result = readdir(dir, &mut self.database, &self.fd_map, |file| {
+            self.versions.dirty(file)
+        })?;
         debug!("readdir(dir={}) => {:?}", dir, &result);
         Ok(result)
     }
@@ -0,0 +1,235 @@
+/// POSIX byte-range locks, the ones fcntl(2) takes with F_SETLK and
+/// tests with F_GETLK, which sqlite and LibreOffice rely on. Locks
+/// only work if everyone asks the same table, so the host that owns a
+/// file keeps the locks on it: its local vault has a table, and peers
+/// take locks through the lock RPCs. A lock belongs to a lock owner
+/// on a host (a process, as the kernel sees it), and an owner's locks
+/// don't conflict with each other.
+use crate::connections::now_ms;
+use crate::rpc::{self, file_lock};
+use crate::types::*;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Shared, others can read-lock the range too.
+    Read,
+    /// Exclusive.
+    Write,
+    /// Release the range.
+    Unlock,
+}
+
+/// Who holds a lock: the lock owner the kernel gave us on host
+/// `host`, the local vault name of the host.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LockOwner {
+    pub host: VaultName,
+    pub id: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileLock {
+    /// First byte of the range.
+    pub start: u64,
+    /// Last byte of the range, inclusive. The kernel uses the largest
+    /// offset for up to the end of the file, however long it gets.
+    pub end: u64,
+    pub kind: LockKind,
+    pub owner: LockOwner,
+    /// The process that asked, reported to F_GETLK.
+    pub pid: u32,
+}
+
+impl FileLock {
+    fn overlaps(&self, other: &FileLock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    /// Return true if `self` and `other` can't be held at the same
+    /// time.
+    fn conflicts(&self, other: &FileLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other)
+            && (self.kind == LockKind::Write || other.kind == LockKind::Write)
+    }
+}
+
+/// Return `lock` on `file` as a message.
+pub(crate) fn pack(file: Inode, lock: &FileLock) -> rpc::FileLock {
+    let kind = match lock.kind {
+        LockKind::Read => file_lock::LockKind::Read,
+        LockKind::Write => file_lock::LockKind::Write,
+        LockKind::Unlock => file_lock::LockKind::Unlock,
+    };
+    rpc::FileLock {
+        file,
+        start: lock.start,
+        end: lock.end,
+        kind: kind as i32,
+        host: lock.owner.host.clone(),
+        owner: lock.owner.id,
+        pid: lock.pid,
+    }
+}
+
+/// The reverse of `pack`, return (file, lock).
+pub(crate) fn unpack(lock: rpc::FileLock) -> VaultResult<(Inode, FileLock)> {
+    let kind = match file_lock::LockKind::from_i32(lock.kind) {
+        Some(file_lock::LockKind::Read) => LockKind::Read,
+        Some(file_lock::LockKind::Write) => LockKind::Write,
+        Some(file_lock::LockKind::Unlock) => LockKind::Unlock,
+        None => {
+            return Err(VaultError::InvalidArgument(format!(
+                "unknown lock kind {}",
+                lock.kind
+            )))
+        }
+    };
+    if lock.start > lock.end {
+        return Err(VaultError::InvalidArgument(format!(
+            "lock range {}..{} is backwards",
+            lock.start, lock.end
+        )));
+    }
+    Ok((
+        lock.file,
+        FileLock {
+            start: lock.start,
+            end: lock.end,
+            kind,
+            owner: LockOwner {
+                host: lock.host,
+                id: lock.owner,
+            },
+            pid: lock.pid,
+        },
+    ))
+}
+
+/// Return the host to record for a lock owner that a peer connected
+/// from `addr` names `claimed`: the address it connected from, then
+/// the name. A peer can name any host, so the name alone would let it
+/// take or release the locks of others; with the address it can only
+/// pose as hosts on its own machine, and never as us.
+pub(crate) fn peer_host(addr: Option<SocketAddr>, claimed: &str) -> VaultName {
+    match addr {
+        Some(addr) => format!("{}/{}", addr.ip(), claimed),
+        None => format!("?/{}", claimed),
+    }
+}
+
+/// Return true if `host`, the host of a lock owner, is the host
+/// named `name`, whether it recorded the lock itself or a peer did,
+/// see `peer_host`.
+pub fn is_host(host: &str, name: &str) -> bool {
+    host == name
+        || host
+            .rsplit_once('/')
+            .map_or(false, |(_, claimed)| claimed == name)
+}
+
+/// The locks on the files of a vault. Locks aren't saved, they go
+/// away when the host restarts, like on any file system, and the
+/// locks of a peer go away when it does, see `forget_departed`.
+#[derive(Debug, Default)]
+pub struct LockTable {
+    locks: HashMap<Inode, Vec<FileLock>>,
+    /// Maps each host that took locks to when it took the first one
+    /// since we last dropped its locks, in milliseconds since UNIX
+    /// epoch.
+    since: HashMap<VaultName, u64>,
+}
+
+impl LockTable {
+    pub fn new() -> LockTable {
+        LockTable::default()
+    }
+
+    /// Return a lock on `file` someone else holds that keeps `lock`
+    /// from being taken, like F_GETLK.
+    pub fn conflict(&self, file: Inode, lock: &FileLock) -> Option<FileLock> {
+        if lock.kind == LockKind::Unlock {
+            return None;
+        }
+        self.locks
+            .get(&file)?
+            .iter()
+            .find(|held| held.conflicts(lock))
+            .cloned()
+    }
+
+    /// Take or release `lock` on `file` without waiting, like F_SETLK.
+    /// Fail with LockConflict if someone else holds a conflicting
+    /// lock. What the owner held in the range is replaced, so a lock
+    /// can be turned from read to write, and back, and unlocking the
+    /// middle of a range keeps both ends.
+    pub fn set(&mut self, file: Inode, lock: FileLock) -> VaultResult<()> {
+        if self.conflict(file, &lock).is_some() {
+            return Err(VaultError::LockConflict(file));
+        }
+        let held = self.locks.entry(file).or_default();
+        let mut kept = Vec::with_capacity(held.len() + 1);
+        for old in held.drain(..) {
+            if old.owner != lock.owner || !old.overlaps(&lock) {
+                kept.push(old);
+                continue;
+            }
+            if old.start < lock.start {
+                kept.push(FileLock {
+                    end: lock.start - 1,
+                    ..old.clone()
+                });
+            }
+            if old.end > lock.end {
+                kept.push(FileLock {
+                    start: lock.end + 1,
+                    ..old
+                });
+            }
+        }
+        if lock.kind != LockKind::Unlock {
+            self.since
+                .entry(lock.owner.host.clone())
+                .or_insert_with(now_ms);
+            kept.push(lock);
+        }
+        if kept.is_empty() {
+            self.locks.remove(&file);
+        } else {
+            *held = kept;
+        }
+        Ok(())
+    }
+
+    /// Drop the locks of peers whose connection went down after they
+    /// took them: `last_down` returns when the connection to a peer,
+    /// by name, last went down, see `ConnectionLog::last_down`. Their
+    /// programs are gone, or can't tell us they're done, so nobody
+    /// would release the locks. Our own locks stay.
+    pub fn forget_departed(&mut self, last_down: impl Fn(&str) -> Option<u64>) {
+        let departed: Vec<VaultName> = self
+            .since
+            .iter()
+            .filter(|(host, &since)| {
+                host.rsplit_once('/')
+                    .and_then(|(_, claimed)| last_down(claimed))
+                    .map_or(false, |down| down >= since)
+            })
+            .map(|(host, _)| host.clone())
+            .collect();
+        for host in departed {
+            self.since.remove(&host);
+            for held in self.locks.values_mut() {
+                held.retain(|lock| lock.owner.host != host);
+            }
+        }
+        self.locks.retain(|_, held| !held.is_empty());
+    }
+
+    /// Drop the locks on `file`, eg, when it's deleted.
+    pub fn forget(&mut self, file: Inode) {
+        self.locks.remove(&file);
+    }
+}
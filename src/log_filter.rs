@@ -0,0 +1,186 @@
+/// Log filters that can change while monovault runs. They start from
+/// RUST_LOG, like env_logger's, and the "log" command changes them
+/// through the vault server, so a module can log at debug level while
+/// we diagnose something, without a restart.
+use crate::types::*;
+use env_logger::filter::{self, Filter};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::RwLock;
+
+/// A module path, or None for every module, and the most verbose
+/// level it logs at.
+pub type Directive = (Option<String>, LevelFilter);
+
+struct Filters {
+    /// What RUST_LOG said, to go back to.
+    startup: Vec<Directive>,
+    directives: Vec<Directive>,
+    filter: Filter,
+}
+
+impl Default for Filters {
+    fn default() -> Filters {
+        Filters {
+            startup: vec![],
+            directives: vec![],
+            filter: filter::Builder::new().build(),
+        }
+    }
+}
+
+/// The filters in effect, None until `init` or `set`.
+static FILTERS: RwLock<Option<Filters>> = RwLock::new(None);
+
+/// Logs what passes FILTERS with env_logger's format.
+struct RuntimeLogger {
+    output: env_logger::Logger,
+}
+
+impl Log for RuntimeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match &*FILTERS.read().unwrap() {
+            Some(filters) => filters.filter.enabled(metadata),
+            None => false,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.output.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.output.flush();
+    }
+}
+
+/// Parse `spec`, comma-separated directives in RUST_LOG syntax:
+/// "level", "module" (every level) or "module=level". Unlike
+/// env_logger we refuse bad directives instead of skipping them, and
+/// there is no "/regex" suffix.
+pub fn parse(spec: &str) -> VaultResult<Vec<Directive>> {
+    let mut directives = vec![];
+    for part in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+    {
+        let bad = || VaultError::InvalidArgument(format!("bad log filter {}", part));
+        let directive: Directive = match part.split_once('=') {
+            Some((module, level)) => {
+                if module.is_empty() || module.contains('/') {
+                    return Err(bad());
+                }
+                (Some(module.to_string()), level.parse().map_err(|_| bad())?)
+            }
+            None => match part.parse() {
+                Ok(level) => (None, level),
+                Err(_) if !part.contains('/') => (Some(part.to_string()), LevelFilter::Trace),
+                Err(_) => return Err(bad()),
+            },
+        };
+        merge(&mut directives, vec![directive]);
+    }
+    Ok(directives)
+}
+
+/// Add `new` to `directives`, replacing those for the same module.
+pub fn merge(directives: &mut Vec<Directive>, new: Vec<Directive>) {
+    for (module, level) in new {
+        match directives
+            .iter_mut()
+            .find(|directive| directive.0 == module)
+        {
+            Some(directive) => directive.1 = level,
+            None => directives.push((module, level)),
+        }
+    }
+}
+
+/// Return `directives` in RUST_LOG syntax.
+pub fn format(directives: &[Directive]) -> String {
+    directives
+        .iter()
+        .map(|(module, level)| {
+            let level = level.to_string().to_lowercase();
+            match module {
+                Some(module) => format!("{}={}", module, level),
+                None => level,
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Put `directives` in effect. No directive at all means errors only,
+/// like env_logger.
+fn apply(filters: &mut Filters, directives: Vec<Directive>) {
+    let mut builder = filter::Builder::new();
+    for (module, level) in directives.iter() {
+        match module {
+            Some(module) => builder.filter_module(module, *level),
+            None => builder.filter_level(*level),
+        };
+    }
+    filters.filter = builder.build();
+    filters.directives = directives;
+    log::set_max_level(filters.filter.filter());
+}
+
+/// Install the logger with the filters in RUST_LOG, replaces
+/// `env_logger::init`. Bad filters in RUST_LOG are ignored.
+pub fn init() {
+    let spec = std::env::var("RUST_LOG").unwrap_or_default();
+    // Drop the regex, if any.
+    let spec = spec.split('/').next().unwrap_or_default();
+    let startup = parse(spec).unwrap_or_else(|err| {
+        eprintln!("monovault: ignoring RUST_LOG: {:?}", err);
+        vec![]
+    });
+    reset_to(startup);
+    let output = env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    // Fails if a logger is installed already, keep that one.
+    let _ = log::set_boxed_logger(Box::new(RuntimeLogger { output }));
+}
+
+fn reset_to(startup: Vec<Directive>) {
+    let mut filters = FILTERS.write().unwrap();
+    let filters = filters.get_or_insert_with(Filters::default);
+    filters.startup = startup.clone();
+    apply(filters, startup);
+}
+
+/// Return the filters in effect, in RUST_LOG syntax.
+pub fn current() -> String {
+    match &*FILTERS.read().unwrap() {
+        Some(filters) => format(&filters.directives),
+        None => String::new(),
+    }
+}
+
+/// Add the directives in `spec` to the filters in effect, replacing
+/// those for the same modules, and return the result. For example,
+/// "monovault::remote_vault=debug" turns on debug logging for remote
+/// vaults and leaves other modules as they were.
+pub fn set(spec: &str) -> VaultResult<String> {
+    let new = parse(spec)?;
+    let mut filters = FILTERS.write().unwrap();
+    let filters = filters.get_or_insert_with(Filters::default);
+    let mut directives = filters.directives.clone();
+    merge(&mut directives, new);
+    apply(filters, directives);
+    Ok(format(&filters.directives))
+}
+
+/// Go back to the filters in RUST_LOG, return them.
+pub fn reset() -> String {
+    let startup = match &*FILTERS.read().unwrap() {
+        Some(filters) => filters.startup.clone(),
+        None => vec![],
+    };
+    reset_to(startup);
+    current()
+}
@@ -1,18 +1,628 @@
 use clap::{Arg, Command};
 use fuser::{self, MountOption};
+use log::{error, info, warn};
 use monovault::{
-    caching_remote::CachingVault, fuse::FS, local_vault::LocalVault, remote_vault::RemoteVault,
-    types::*, vault_server::run_server,
+    background_worker::BackgroundOp,
+    bandwidth::BandwidthMeter,
+    bans::PeerGuard,
+    bench::{self, BenchResult},
+    caching_remote::{self, CachingVault},
+    config::{self, CONFIG_VERSION},
+    connections::ConnectionLog,
+    database::Database,
+    disk_usage, events, export,
+    faults::FaultInjector,
+    fuse::{Invalidator, FS},
+    hooks, import,
+    local_vault::LocalVault,
+    log_filter,
+    mounts::{self, MountState},
+    prefixes, proxy,
+    remote_vault::RemoteVault,
+    retire::{self, Retirements},
+    salvage,
+    seal::SEAL_COMMAND,
+    share::{self, ShareKey, SharedVault, SHARE_KEY_FILE},
+    store_health::StoreHealth,
+    store_lock::StoreLock,
+    types::*,
+    vault_server,
 };
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use tokio::runtime::Builder;
+use std::time;
+use tokio::runtime::{Builder, Runtime};
+
+/// Seconds between each eviction of stale cached files.
+const EVICTION_INTERVAL: u64 = 60 * 60;
+/// Seconds between each refresh of database statistics, see
+/// `Database::maintain`.
+const DB_MAINTENANCE_INTERVAL: u64 = 6 * 60 * 60;
+/// Seconds between each save of bandwidth counters.
+const BANDWIDTH_SAVE_INTERVAL: u64 = 60;
+/// Seconds to wait before mounting again after the file system
+/// session died, times the number of attempts so far.
+const REMOUNT_DELAY: u64 = 2;
+/// A session that ran this many seconds before dying resets the
+/// count of remount attempts.
+const REMOUNT_RESET: u64 = 60;
+/// How long we wait for the file system session to end after SIGINT
+/// or SIGTERM before exiting anyway, see `stop_on_signal`.
+const STOP_GRACE: time::Duration = time::Duration::from_secs(10);
+
+/// Escape `value` for use in a mount option: options are separated
+/// by commas, so commas (and the escape character itself) in values
+/// need a backslash.
+fn escape_option(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,")
+}
+
+/// Return the mount option `option` of `Config::mount_options`, as
+/// mount(8) spells it.
+fn parse_mount_option(option: &str) -> MountOption {
+    match option {
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "auto_unmount" => MountOption::AutoUnmount,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "dirsync" => MountOption::DirSync,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        _ => match option.strip_prefix("fsname=") {
+            Some(name) => MountOption::FSName(name.to_string()),
+            None => MountOption::CUSTOM(option.to_string()),
+        },
+    }
+}
+
+/// Return true if the mount option `option`, given in
+/// `Config::mount_options`, replaces our default `default`: it is the
+/// same option, its opposite, or sets the same value.
+fn overrides(option: &MountOption, default: &MountOption) -> bool {
+    use MountOption::*;
+    match (option, default) {
+        (AllowOther, AllowRoot) | (AllowRoot, AllowOther) => true,
+        (RO, RW) | (RW, RO) | (Dev, NoDev) | (NoDev, Dev) => true,
+        (FSName(_), FSName(_)) => true,
+        (CUSTOM(option), CUSTOM(default)) => option.split('=').next() == default.split('=').next(),
+        (option, default) => option == default,
+    }
+}
+
+/// Return true if `a` is inside `b` or `b` is inside `a` (or they
+/// are the same).
+fn overlaps(a: &Path, b: &Path) -> bool {
+    let (a, b) = (resolve_path(a), resolve_path(b));
+    a.starts_with(&b) || b.starts_with(&a)
+}
+
+/// Split `address`, with or without a scheme, into a lowercase host,
+/// a port and a path prefix (see proxy.rs).
+fn host_port_and_path(address: &str) -> (String, String, Option<String>) {
+    let address = address
+        .split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or(address);
+    let (address, path) = match address.split_once('/') {
+        Some((address, path)) => (address.to_lowercase(), proxy::normalize_prefix(path)),
+        None => (address.to_lowercase(), None),
+    };
+    match address.rsplit_once(':') {
+        Some((host, port)) => (
+            host.trim_matches(|ch| ch == '[' || ch == ']').to_string(),
+            port.to_string(),
+            path,
+        ),
+        None => (address, String::new(), path),
+    }
+}
+
+/// Return true if `host` surely means this host.
+fn is_this_host(host: &str) -> bool {
+    host == "localhost"
+        || host
+            .parse::<std::net::IpAddr>()
+            .map(|ip| ip.is_loopback() || ip.is_unspecified())
+            .unwrap_or(false)
+}
+
+/// Return true if `a` and `b` are the same server. We don't resolve
+/// names, only catch the same address and this host written in
+/// different ways. Peers behind one reverse proxy differ by path.
+fn same_address(a: &str, b: &str) -> bool {
+    let (a_host, a_port, a_path) = host_port_and_path(a);
+    let (b_host, b_port, b_path) = host_port_and_path(b);
+    a_port == b_port
+        && a_path == b_path
+        && (a_host == b_host || (is_this_host(&a_host) && is_this_host(&b_host)))
+}
+
+/// Check that no peer is us, by name or by address, and that no two
+/// peers share an address, otherwise we would talk to ourselves, or
+/// to one vault under two names.
+fn check_peers(config: &Config) -> Result<(), String> {
+    let mut peers: Vec<(&VaultName, &VaultAddress)> = config
+        .peers
+        .iter()
+        .flat_map(|(name, peer)| peer.addresses().map(move |address| (name, address)))
+        .collect();
+    peers.sort();
+    for (idx, (name, address)) in peers.iter().enumerate() {
+        if **name == config.local_vault_name {
+            return Err(format!("Peer {} has the name of the local vault", name));
+        }
+        if same_address(address, &config.my_address) {
+            return Err(format!(
+                "Peer {} is at {}, which is my_address",
+                name, address
+            ));
+        }
+        for (other, other_address) in &peers[idx + 1..] {
+            if other != name && same_address(address, other_address) {
+                return Err(format!(
+                    "Peers {} and {} are both at {}",
+                    name, other, address
+                ));
+            }
+        }
+    }
+    for name in &config.near_peers {
+        if !config.peers.contains_key(name) {
+            return Err(format!("Near peer {} isn't in peers", name));
+        }
+    }
+    Ok(())
+}
+
+/// Return the mount options for `config`.
+fn mount_options(config: &Config) -> Vec<MountOption> {
+    let mount_point_name = Path::new(&config.mount_point)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "monovault".to_string());
+    let volume_name = config.volume_name.as_ref().unwrap_or(&mount_point_name);
+    let mut options = vec![
+        MountOption::FSName(escape_option(&mount_point_name)),
+        MountOption::CUSTOM(format!("volname={}", escape_option(volume_name))),
+        // Auto unmount on process exit (doesn't seem to work).
+        MountOption::AutoUnmount,
+        // Allow root user to access this file system.
+        MountOption::AllowRoot,
+        // Disable special character and block devices
+        MountOption::NoDev,
+        // The kernel refuses changes to a read-only mount with EROFS
+        // before they reach us.
+        if config.read_only {
+            MountOption::RO
+        } else {
+            MountOption::RW
+        },
+        // Prevents Apple from generating ._ files.
+        MountOption::CUSTOM("noapplexattr".to_string()),
+        MountOption::CUSTOM("noappledouble".to_string()),
+    ];
+    // macFUSE doesn't take max_read, reads there are bounded by
+    // readahead, which we set in `FS::init`.
+    if !cfg!(target_os = "macos") {
+        options.push(MountOption::CUSTOM(format!(
+            "max_read={}",
+            clamp_io_size(config.max_read)
+        )));
+    }
+    // The kernel checks permissions itself, with the modes and
+    // owners we report.
+    if config.enforce_permissions {
+        options.push(MountOption::DefaultPermissions);
+    }
+    if cfg!(target_os = "macos") {
+        if let Some(icon) = &config.volume_icon {
+            options.push(MountOption::CUSTOM(format!(
+                "volicon={}",
+                escape_option(icon)
+            )));
+        }
+        // Show the volume as a local disk, so Finder lists it in the
+        // sidebar and Spotlight indexes it.
+        if config.local_volume {
+            options.push(MountOption::CUSTOM("local".to_string()));
+        }
+    }
+    for option in config
+        .mount_options
+        .iter()
+        .map(|option| parse_mount_option(option))
+    {
+        options.retain(|default| !overrides(&option, default));
+        options.push(option);
+    }
+    options
+}
+
+/// Set by SIGINT and SIGTERM.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP.store(true, SeqCst);
+}
+
+/// Why we couldn't start (or keep) the file system running. Each
+/// exits with its own status, so supervisors and installers can react
+/// without parsing messages.
+#[derive(Debug, Clone, Copy)]
+enum Failure {
+    /// Bad command line arguments, clap's usual status.
+    Usage,
+    /// The configuration file can't be read.
+    ConfigUnreadable,
+    /// The configuration file isn't valid.
+    ConfigInvalid,
+    /// The mount point is missing or overlaps with db_path.
+    MountPoint,
+    /// The store in db_path can't be created or opened.
+    Store,
+    /// The vault server can't listen on "my_address".
+    Bind,
+    /// The file system can't be mounted.
+    Mount,
+    /// Another instance uses the store in db_path.
+    StoreLocked,
+}
+
+impl Failure {
+    fn exit_code(self) -> i32 {
+        match self {
+            Failure::Usage => 2,
+            Failure::ConfigUnreadable => 3,
+            Failure::ConfigInvalid => 4,
+            Failure::MountPoint => 5,
+            Failure::Store => 6,
+            Failure::Bind => 7,
+            Failure::Mount => 8,
+            Failure::StoreLocked => 9,
+        }
+    }
+
+    /// Name of the failure in JSON reports.
+    fn name(self) -> &'static str {
+        match self {
+            Failure::Usage => "usage",
+            Failure::ConfigUnreadable => "config_unreadable",
+            Failure::ConfigInvalid => "config_invalid",
+            Failure::MountPoint => "mount_point",
+            Failure::Store => "store",
+            Failure::Bind => "bind",
+            Failure::Mount => "mount",
+            Failure::StoreLocked => "store_locked",
+        }
+    }
+}
+
+/// Report `failure` with `message` on stderr and exit. With `json`,
+/// the report is a line of JSON: {"error": name, "code": exit code,
+/// "message": message}.
+fn fail(failure: Failure, message: &str, json: bool) -> ! {
+    if json {
+        eprintln!(
+            "{}",
+            serde_json::json!({
+                "error": failure.name(),
+                "code": failure.exit_code(),
+                "message": message,
+            })
+        );
+    } else {
+        eprintln!("monovault: {}", message);
+    }
+    process::exit(failure.exit_code())
+}
+
+/// Lock the store in `db_path` for this instance, see
+/// src/store_lock.rs, or report why we can't and exit.
+fn lock_store(db_path: &Path, takeover: bool, json: bool) -> StoreLock {
+    StoreLock::acquire(db_path, takeover).unwrap_or_else(|err| match err {
+        VaultError::StoreLocked(Some(pid), true) => fail(
+            Failure::StoreLocked,
+            &format!(
+                "{} is in use by another instance (pid {})",
+                db_path.display(),
+                pid
+            ),
+            json,
+        ),
+        VaultError::StoreLocked(Some(pid), false) => fail(
+            Failure::StoreLocked,
+            &format!(
+                "{} is locked, but its owner (pid {}) is gone, \
+                 use --takeover if nothing else uses it",
+                db_path.display(),
+                pid
+            ),
+            json,
+        ),
+        VaultError::StoreLocked(None, _) => fail(
+            Failure::StoreLocked,
+            &format!("{} is in use by another instance", db_path.display()),
+            json,
+        ),
+        err => fail(
+            Failure::Store,
+            &format!("Cannot lock {}: {:?}", db_path.display(), err),
+            json,
+        ),
+    })
+}
+
+/// Make sure nothing FUSE is mounted on `mount_point`: a mount left
+/// by a crashed instance, or a live one. With `force_unmount`, unmount
+/// it, otherwise report it and exit.
+fn check_mount_point(mount_point: &str, force_unmount: bool, json: bool) {
+    let state = mounts::mount_state(Path::new(mount_point));
+    if state == MountState::Free {
+        return;
+    }
+    if !force_unmount {
+        let what = match state {
+            MountState::Stale => "left mounted by an instance that is gone".to_string(),
+            MountState::Fuse(fstype) => format!("already mounted ({})", fstype),
+            MountState::Free => unreachable!(),
+        };
+        fail(
+            Failure::MountPoint,
+            &format!(
+                "Mount point {} is {}, unmount it or use --force-unmount",
+                mount_point, what
+            ),
+            json,
+        );
+    }
+    warn!("Unmounting {} ({:?})", mount_point, state);
+    mounts::unmount_stale(mount_point);
+    if mounts::mount_state(Path::new(mount_point)) != MountState::Free {
+        fail(
+            Failure::MountPoint,
+            &format!("Cannot unmount {}", mount_point),
+            json,
+        );
+    }
+}
+
+/// Save what we would lose on exit: operations caching vaults haven't
+/// performed on peers yet, and bandwidth counters.
+fn save_state(vaults: &[VaultRef], meter: &BandwidthMeter) {
+    for vault_lck in vaults {
+        if let GenericVault::Caching(vault) = &*vault_lck.lock().unwrap() {
+            if let Err(err) = vault.save_pending() {
+                // Log them, so they aren't lost with the store.
+                error!(
+                    "Saving pending operations of {} failed: {:?}, they are: {}",
+                    vault.name(),
+                    err,
+                    serde_json::to_string(&vault.pending().ops).unwrap_or_default()
+                );
+            }
+        }
+    }
+    if let Err(err) = meter.save() {
+        error!("Saving bandwidth counters failed: {:?}", err);
+    }
+}
+
+/// On SIGINT or SIGTERM, save what we would lose on exit, see
+/// `save_state`, then unmount `mount_point`, which ends the session
+/// and the remount loop. If the session doesn't end in STOP_GRACE, eg,
+/// a file in the mount stays open, exit anyway.
+fn stop_on_signal(mount_point: &str, vaults: &[VaultRef], meter: &BandwidthMeter) {
+    // Only set a flag in the handlers, we stop from a thread.
+    unsafe {
+        libc::signal(libc::SIGINT, request_stop as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_stop as libc::sighandler_t);
+    }
+    let mount_point = mount_point.to_string();
+    let vaults = vaults.to_vec();
+    let meter = meter.clone();
+    thread::spawn(move || {
+        while !STOP.load(SeqCst) {
+            thread::sleep(time::Duration::from_millis(200));
+        }
+        info!("Stopping");
+        save_state(&vaults, &meter);
+        mounts::unmount_stale(&mount_point);
+        thread::sleep(STOP_GRACE);
+        warn!("File system session didn't end, exiting");
+        process::exit(0);
+    });
+}
+
+fn unix_now() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Return the record of retired vaults in `db_path`, brought up to
+/// date with the peers in `config`.
+fn load_retirements(config: &Config, db_path: &Path) -> VaultResult<Retirements> {
+    let peers: Vec<VaultName> = config.peers.keys().cloned().collect();
+    let mut retirements = Retirements::load(db_path)?;
+    retirements.update(db_path, &peers, &config.local_vault_name, unix_now())?;
+    Ok(retirements)
+}
+
+/// Retire the stores of peers removed from `config`, remove those
+/// retired longer than the grace period, and return the names of the
+/// others.
+fn retire_stores(config: &Config, db_path: &Path) -> VaultResult<Vec<VaultName>> {
+    let mut retirements = load_retirements(config, db_path)?;
+    for name in retirements.expired(config.retired_grace_days, unix_now()) {
+        info!(
+            "Grace period of retired vault {} is over, removing it",
+            name
+        );
+        retirements.purge(db_path, &name)?;
+    }
+    retirements.save(db_path)?;
+    Ok(retirements.retired.keys().cloned().collect())
+}
+
+/// Return a client of the vault server of the running instance, for
+/// commands that talk to it.
+fn running_instance(config: &Config, bandwidth_path: &Path) -> RemoteVault {
+    let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
+    RemoteVault::new(
+        &format!("http://{}", config.my_address),
+        &config.local_vault_name,
+        runtime,
+        BandwidthMeter::new(bandwidth_path).expect("Cannot read bandwidth counters"),
+    )
+    .expect("Cannot create remote vault instance")
+}
+
+/// Return the HTTP/2 keepalive interval in `config`, if any.
+fn keepalive(config: &Config) -> Option<time::Duration> {
+    if config.http2_keepalive_secs > 0 {
+        Some(time::Duration::from_secs(config.http2_keepalive_secs))
+    } else {
+        None
+    }
+}
+
+/// Start the vault server serving `vault_map` in a thread, return
+/// its handle. Binds before returning, so a taken address stops us
+/// before mounting. The server reports `connections` to the
+/// "connections" command.
+fn start_server(
+    config: &Config,
+    vault_map: HashMap<VaultName, VaultRef>,
+    runtime: Arc<Runtime>,
+    connections: ConnectionLog,
+    db_path: &Path,
+    json_errors: bool,
+) -> thread::JoinHandle<()> {
+    let addr = config.my_address.clone();
+    let local_vault_name = config.local_vault_name.clone();
+    let guard = PeerGuard::new(config.bans.clone());
+    let share_key = ShareKey::load_or_create(&db_path.join(SHARE_KEY_FILE)).unwrap_or_else(|err| {
+        fail(
+            Failure::Store,
+            &format!("Cannot read the share key: {:?}", err),
+            json_errors,
+        )
+    });
+    let listener = vault_server::bind(&addr).unwrap_or_else(|err| {
+        fail(
+            Failure::Bind,
+            &format!("Cannot listen on {}: {:?}", addr, err),
+            json_errors,
+        )
+    });
+    let http = vault_server::HttpSettings {
+        path_prefix: config
+            .rpc_path_prefix
+            .as_deref()
+            .and_then(proxy::normalize_prefix),
+        keepalive: keepalive(config),
+        forwarded: proxy::ForwardedFor {
+            header: config
+                .forwarded_header
+                .as_ref()
+                .map(|header| header.to_ascii_lowercase()),
+            proxies: config.trusted_proxies.clone(),
+        },
+    };
+    thread::spawn(move || {
+        vault_server::serve(
+            listener,
+            &local_vault_name,
+            vault_map,
+            runtime,
+            guard,
+            Some(share_key),
+            connections,
+            http,
+        )
+    })
+}
+
+/// Share `local_vault` and nothing else until SIGINT or SIGTERM, then
+/// tear it down and exit.
+fn serve_only(config: &Config, local_vault: VaultRef, db_path: &Path, json_errors: bool) -> ! {
+    let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
+    let mut vault_map = HashMap::new();
+    vault_map.insert(config.local_vault_name.clone(), Arc::clone(&local_vault));
+    // We have no remote vaults, so no connections to report.
+    let server = start_server(
+        config,
+        vault_map,
+        runtime,
+        ConnectionLog::new(),
+        db_path,
+        json_errors,
+    );
+    // Only set a flag in the handlers, we stop from here.
+    unsafe {
+        libc::signal(libc::SIGINT, request_stop as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_stop as libc::sighandler_t);
+    }
+    info!(
+        "Sharing {} on {}, not mounting",
+        config.local_vault_name, config.my_address
+    );
+    let mut status = 0;
+    while !STOP.load(SeqCst) {
+        if server.is_finished() {
+            error!("Vault server stopped");
+            status = 1;
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(200));
+    }
+    info!("Stopping");
+    if let Err(err) = local_vault.lock().unwrap().tear_down() {
+        error!("Tearing down {} failed: {:?}", config.local_vault_name, err);
+    }
+    process::exit(status)
+}
+
+/// Print the results of benchmarks run on `target`.
+fn print_bench(target: &str, results: &[BenchResult]) {
+    for result in results {
+        if result.bytes > 0 {
+            println!(
+                "{} {}: {:.0} ops/s, {:.1} MiB/s",
+                target,
+                result.name,
+                result.ops_per_sec(),
+                result.mib_per_sec()
+            );
+        } else {
+            println!(
+                "{} {}: {:.0} ops/s",
+                target,
+                result.name,
+                result.ops_per_sec()
+            );
+        }
+    }
+}
 
 fn main() {
-    env_logger::init();
+    log_filter::init();
 
     let matches = Command::new("monovault")
         .version("0.1.0")
@@ -24,47 +634,896 @@ fn main() {
                 .help("configuration file path")
                 .required(true),
         )
-        .get_matches();
+        .arg(
+            Arg::new("server-only")
+                .long("server-only")
+                .help("only share the local vault: don't mount, don't connect to peers"),
+        )
+        .arg(
+            Arg::new("mount-only")
+                .long("mount-only")
+                .conflicts_with("server-only")
+                .help("mount remote vaults read-only: don't share, don't upload"),
+        )
+        .arg(
+            Arg::new("json-errors")
+                .long("json-errors")
+                .help("report startup failures as a line of JSON on stderr"),
+        )
+        .arg(
+            Arg::new("takeover")
+                .long("takeover")
+                .help("take over the lock on db_path if the instance holding it is gone"),
+        )
+        .arg(
+            Arg::new("force-unmount")
+                .long("force-unmount")
+                .help("unmount what is left mounted on the mount point before mounting"),
+        )
+        .subcommand(
+            Command::new("upgrade-config").about(
+                "Print the configuration file upgraded to the current config_version",
+            ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Copy a directory tree on this host into the local vault")
+                .arg(
+                    Arg::new("source")
+                        .required(true)
+                        .help("directory to import"),
+                )
+                .arg(
+                    Arg::new("into")
+                        .long("into")
+                        .takes_value(true)
+                        .help("directory in the vault to import into"),
+                ),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Copy a directory in the local vault to a directory on this host")
+                .arg(
+                    Arg::new("dest")
+                        .required(true)
+                        .help("directory to export to"),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .takes_value(true)
+                        .help("directory in the vault to export"),
+                ),
+        )
+        .subcommand(
+            Command::new("salvage")
+                .about("Copy what can be read of a damaged store to a directory, without writing to the store")
+                .arg(
+                    Arg::new("dest")
+                        .required(true)
+                        .help("directory to copy to"),
+                )
+                .arg(
+                    Arg::new("vault")
+                        .long("vault")
+                        .takes_value(true)
+                        .help("vault to salvage, the local vault if left out"),
+                ),
+        )
+        .subcommand(
+            Command::new("pending")
+                .about("Show what the running instance has yet to send to peers")
+                .arg(
+                    Arg::new("pause")
+                        .long("pause")
+                        .help("stop sending, only collect operations (dry run)"),
+                )
+                .arg(
+                    Arg::new("resume")
+                        .long("resume")
+                        .conflicts_with("pause")
+                        .help("resume sending"),
+                ),
+        )
+        .subcommand(
+            Command::new("bandwidth")
+                .about("Show bytes of the requests this host made to each peer"),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Show the number of files and their total size in each stored vault"),
+        )
+        .subcommand(
+            Command::new("du")
+                .about("Show what takes the space in db_path for each stored vault, and what can be removed"),
+        )
+        .subcommand(
+            Command::new("share")
+                .about("Print a share link token granting read access to a file or directory in the local vault")
+                .arg(
+                    Arg::new("path")
+                        .required(true)
+                        .help("path of the file or directory in the vault"),
+                )
+                .arg(
+                    Arg::new("ttl")
+                        .long("ttl")
+                        .takes_value(true)
+                        .help("seconds until the token expires (default 86400)"),
+                ),
+        )
+        .subcommand(
+            Command::new("fetch")
+                .about("Download what a share link token grants")
+                .arg(
+                    Arg::new("address")
+                        .required(true)
+                        .help("address of the host that shared it, eg, http://example.com:7771"),
+                )
+                .arg(Arg::new("token").required(true).help("the token"))
+                .arg(
+                    Arg::new("dest")
+                        .required(true)
+                        .help("where to save the file or directory"),
+                ),
+        )
+        .subcommand(
+            Command::new("bans")
+                .about("Show peers banned by the running instance")
+                .arg(
+                    Arg::new("clear")
+                        .long("clear")
+                        .takes_value(true)
+                        .help("lift the ban on this address"),
+                )
+                .arg(
+                    Arg::new("clear-all")
+                        .long("clear-all")
+                        .conflicts_with("clear")
+                        .help("lift all bans"),
+                ),
+        )
+        .subcommand(
+            Command::new("connections")
+                .about("Show when the running instance connected to and lost each peer")
+                .arg(
+                    Arg::new("peer")
+                        .long("peer")
+                        .takes_value(true)
+                        .help("only show this peer"),
+                ),
+        )
+        .subcommand(
+            Command::new("log")
+                .about("Show or change the log filters of the running instance")
+                .arg(
+                    Arg::new("filters")
+                        .help("filters to add, like RUST_LOG, e.g. monovault::remote_vault=debug"),
+                )
+                .arg(
+                    Arg::new("reset")
+                        .long("reset")
+                        .help("go back to the filters in RUST_LOG first"),
+                ),
+        )
+        .subcommand(
+            Command::new("path")
+                .about("Show the path of an inode in a vault of the running instance")
+                .arg(Arg::new("vault").required(true).help("name of the vault"))
+                .arg(
+                    Arg::new("inode")
+                        .required(true)
+                        .help("the inode, in decimal or in hex like 0x1000000000214 as in the log"),
+                ),
+        )
+        .subcommand(
+            Command::new("seal")
+                .about("Make the local vault of the running instance read-only for good")
+                .arg(
+                    Arg::new("yes")
+                        .long("yes")
+                        .help("seal it, there is no undoing it"),
+                ),
+        )
+        .subcommand(
+            Command::new("retired")
+                .about("Show stores of peers removed from the configuration")
+                .arg(
+                    Arg::new("keep")
+                        .long("keep")
+                        .takes_value(true)
+                        .help("keep the store of this retired vault after the grace period"),
+                )
+                .arg(
+                    Arg::new("release")
+                        .long("release")
+                        .takes_value(true)
+                        .conflicts_with("keep")
+                        .help("stop keeping the store of this retired vault"),
+                )
+                .arg(
+                    Arg::new("purge")
+                        .long("purge")
+                        .takes_value(true)
+                        .conflicts_with_all(&["keep", "release"])
+                        .help("remove the store of this vault now"),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Measure metadata operations and throughput of a local and a loopback remote vault")
+                .arg(
+                    Arg::new("files")
+                        .long("files")
+                        .takes_value(true)
+                        .help("number of small files (default 1000)"),
+                )
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .takes_value(true)
+                        .help("size of the large file in MiB (default 64)"),
+                ),
+        )
+        .try_get_matches()
+        .unwrap_or_else(|err| {
+            // Help and version go to stdout and aren't failures.
+            if err.use_stderr() && std::env::args().any(|arg| arg == "--json-errors") {
+                fail(Failure::Usage, err.to_string().trim(), true)
+            }
+            err.exit()
+        });
 
+    let json_errors = matches.is_present("json-errors");
     let config_path = matches.value_of("config").unwrap();
-    let config_file_content =
-        &fs::read_to_string(config_path).expect("Cannot read the configuration file");
-    let config: Config =
-        serde_json::from_str(config_file_content).expect("Cannot parse the configuration file");
+    let config_file_content = &fs::read_to_string(config_path).unwrap_or_else(|err| {
+        fail(
+            Failure::ConfigUnreadable,
+            &format!(
+                "Cannot read the configuration file {}: {}",
+                config_path, err
+            ),
+            json_errors,
+        )
+    });
+    if let Some(("upgrade-config", _)) = matches.subcommand() {
+        match config::upgrade(config_file_content) {
+            Ok(upgraded) => println!("{}", upgraded),
+            Err(err) => fail(
+                Failure::ConfigInvalid,
+                &format!(
+                    "Cannot upgrade the configuration file {}: {}",
+                    config_path, err
+                ),
+                json_errors,
+            ),
+        }
+        return;
+    }
+    let (mut config, config_version) = config::parse(config_file_content).unwrap_or_else(|err| {
+        fail(
+            Failure::ConfigInvalid,
+            &format!(
+                "Cannot parse the configuration file {}: {}",
+                config_path, err
+            ),
+            json_errors,
+        )
+    });
+    if config_version < CONFIG_VERSION {
+        info!(
+            "The configuration file {} is config_version {}, \"upgrade-config\" prints it upgraded to {}",
+            config_path, config_version, CONFIG_VERSION
+        );
+    }
+    // Paths in the configuration are relative to it.
+    config.resolve_paths(
+        Path::new(config_path)
+            .parent()
+            .unwrap_or_else(|| Path::new("")),
+    );
+
+    if matches.is_present("server-only") {
+        config.server_only = true;
+    }
+    if matches.is_present("mount-only") {
+        config.mount_only = true;
+    }
+    if config.server_only && config.mount_only {
+        fail(
+            Failure::ConfigInvalid,
+            "server_only and mount_only can't be both true",
+            json_errors,
+        );
+    }
+    // A read-only mount makes every vault read-only, like `read_only`.
+    if config.mount_options.iter().any(|option| option == "ro") {
+        config.read_only = true;
+    }
+    if config.read_only && config.mount_options.iter().any(|option| option == "rw") {
+        fail(
+            Failure::ConfigInvalid,
+            "read_only can't be true with the mount option rw",
+            json_errors,
+        );
+    }
+    if config.mount_only {
+        // Cache what we read, share nothing, send nothing. Pending
+        // operations from earlier runs are kept for a normal run.
+        config.caching = true;
+        for peer in config.peers.values_mut() {
+            peer.caching = None;
+        }
+        config.share_local_vault = false;
+        config.background_dry_run = true;
+    }
 
-    // TODO: Check for duplicate vault name.
+    if let Err(message) = check_peers(&config) {
+        fail(Failure::ConfigInvalid, &message, json_errors);
+    }
 
-    // Make sure mount point exists.
-    let mount_point = Path::new(&config.mount_point);
-    if !mount_point.exists() {
-        panic!("Mount point doesn't exist");
+    // Before anything touches db_path, which may be damaged or
+    // mounted read-only.
+    if let Some(("salvage", sub_matches)) = matches.subcommand() {
+        let db_path = Path::new(&config.db_path);
+        let vault = sub_matches
+            .value_of("vault")
+            .unwrap_or(&config.local_vault_name);
+        let dest = Path::new(sub_matches.value_of("dest").unwrap());
+        if overlaps(dest, db_path) || overlaps(dest, Path::new(&config.mount_point)) {
+            panic!("Cannot salvage to a directory that overlaps with db_path or mount point");
+        }
+        let stats = salvage::salvage(db_path, vault, dest).expect("Cannot salvage the store");
+        for problem in stats.problems.iter() {
+            println!("Skipped: {}", problem);
+        }
+        println!(
+            "Salvaged {} files ({} bytes) and {} directories, {} in {}, skipped {}",
+            stats.files,
+            stats.bytes,
+            stats.directories,
+            stats.lost,
+            salvage::LOST_AND_FOUND,
+            stats.problems.len()
+        );
+        return;
     }
 
     // Make sure db_path exists.
     let db_path = Path::new(&config.db_path);
     if !db_path.exists() {
-        fs::create_dir(&db_path).expect("Cannot create directory for database");
+        fs::create_dir(db_path).unwrap_or_else(|err| {
+            fail(
+                Failure::Store,
+                &format!("Cannot create db_path {}: {}", config.db_path, err),
+                json_errors,
+            )
+        });
+    }
+
+    let takeover = matches.is_present("takeover");
+    let force_unmount = matches.is_present("force-unmount");
+
+    if let Some(("import", sub_matches)) = matches.subcommand() {
+        let _lock = lock_store(db_path, takeover, json_errors);
+        let mut vault = LocalVault::new(
+            &config.local_vault_name,
+            db_path,
+            events::EventBus::new(),
+            config.missing_data,
+        )
+        .expect("Cannot create local vault instance");
+        vault.set_name_normalization(config.name_normalization);
+        vault.set_case_insensitive(config.case_insensitive);
+        vault.set_hash_algorithm(config.hash_algorithm);
+        let source = Path::new(sub_matches.value_of("source").unwrap());
+        // Importing the store into itself never ends.
+        if overlaps(source, db_path) || overlaps(source, Path::new(&config.mount_point)) {
+            panic!("Cannot import from a directory that overlaps with db_path or mount point");
+        }
+        let into = Path::new(sub_matches.value_of("into").unwrap_or(""));
+        let dest = import::resolve_path(&mut vault, into)
+            .expect("Cannot find the directory to import into");
+        let stats =
+            import::import_tree(&mut vault, source, dest).expect("Error importing the directory");
+        println!(
+            "Imported {} files ({} bytes) and {} directories, {} unchanged, {} skipped",
+            stats.files, stats.bytes, stats.directories, stats.unchanged, stats.skipped
+        );
+        return;
+    }
+
+    if let Some(("export", sub_matches)) = matches.subcommand() {
+        let _lock = lock_store(db_path, takeover, json_errors);
+        let mut vault = LocalVault::new(
+            &config.local_vault_name,
+            db_path,
+            events::EventBus::new(),
+            config.missing_data,
+        )
+        .expect("Cannot create local vault instance");
+        let dest = Path::new(sub_matches.value_of("dest").unwrap());
+        if overlaps(dest, db_path) || overlaps(dest, Path::new(&config.mount_point)) {
+            panic!("Cannot export to a directory that overlaps with db_path or mount point");
+        }
+        let from = Path::new(sub_matches.value_of("from").unwrap_or(""));
+        let source =
+            import::resolve_path(&mut vault, from).expect("Cannot find the directory to export");
+        let stats =
+            export::export_tree(&mut vault, source, dest).expect("Error exporting the directory");
+        println!(
+            "Exported {} files ({} bytes) and {} directories",
+            stats.files, stats.bytes, stats.directories
+        );
+        return;
+    }
+
+    if let Some(("share", sub_matches)) = matches.subcommand() {
+        let _lock = lock_store(db_path, takeover, json_errors);
+        let mut vault = LocalVault::new(
+            &config.local_vault_name,
+            db_path,
+            events::EventBus::new(),
+            config.missing_data,
+        )
+        .expect("Cannot create local vault instance");
+        let path = Path::new(sub_matches.value_of("path").unwrap());
+        let file = import::resolve_path(&mut vault, path).expect("Cannot find the file to share");
+        let subtree = matches!(
+            vault
+                .attr(file)
+                .expect("Cannot find the file to share")
+                .kind,
+            VaultFileType::Directory
+        );
+        let ttl: u64 = sub_matches
+            .value_of("ttl")
+            .unwrap_or("86400")
+            .parse()
+            .expect("--ttl must be a number");
+        let key = ShareKey::load_or_create(&db_path.join(SHARE_KEY_FILE))
+            .expect("Cannot read the share key");
+        let token = key
+            .mint_for(&config.local_vault_name, file, subtree, ttl)
+            .expect("Cannot create the token");
+        println!("{}", token);
+        return;
+    }
+
+    // Bandwidth counters are kept across restarts in this file.
+    let bandwidth_path = db_path.join("bandwidth.json");
+
+    if let Some(("bandwidth", _)) = matches.subcommand() {
+        let counters =
+            BandwidthMeter::load(&bandwidth_path).expect("Cannot read bandwidth counters");
+        let mut peers: Vec<_> = counters.into_iter().collect();
+        peers.sort_by(|a, b| a.0.cmp(&b.0));
+        for (peer, traffic) in peers {
+            println!(
+                "{}: {} bytes sent, {} bytes received in our requests",
+                peer, traffic.sent, traffic.received
+            );
+        }
+        return;
+    }
+
+    if let Some(("bench", sub_matches)) = matches.subcommand() {
+        let count: u64 = sub_matches
+            .value_of("files")
+            .unwrap_or("1000")
+            .parse()
+            .expect("--files must be a number");
+        let size_mib: u64 = sub_matches
+            .value_of("size")
+            .unwrap_or("64")
+            .parse()
+            .expect("--size must be a number");
+        // Work in a scratch store, the real vaults aren't touched.
+        let store = db_path.join("bench");
+        if store.exists() {
+            fs::remove_dir_all(&store).expect("Cannot remove the old benchmark store");
+        }
+        fs::create_dir(&store).expect("Cannot create the benchmark store");
+        let mut local = LocalVault::new(
+            "bench",
+            &store,
+            events::EventBus::new(),
+            config.missing_data,
+        )
+        .expect("Cannot create local vault instance");
+        let results = bench::run_all(&mut local, count, size_mib << 20).expect("Benchmark failed");
+        print_bench("local", &results);
+        // Same again through RPC to a server on localhost.
+        let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
+        let meter = BandwidthMeter::new(&store.join("bandwidth.json"))
+            .expect("Cannot read bandwidth counters");
+        let mut remote =
+            bench::loopback(local, runtime, meter).expect("Cannot start the benchmark server");
+        let results = bench::run_all(&mut remote, count, size_mib << 20).expect("Benchmark failed");
+        print_bench("remote", &results);
+        let _ = fs::remove_dir_all(&store);
+        return;
+    }
+
+    if let Some(("pending", sub_matches)) = matches.subcommand() {
+        let mut server = running_instance(&config, &bandwidth_path);
+        if sub_matches.is_present("pause") {
+            server.set_dry_run(true).expect("Cannot pause");
+        } else if sub_matches.is_present("resume") {
+            server.set_dry_run(false).expect("Cannot resume");
+        }
+        for pending in server.pending().expect("Cannot get pending operations") {
+            println!(
+                "{}: {} operations, {} bytes to upload{}",
+                pending.vault,
+                pending.ops.len(),
+                pending.upload_size,
+                if pending.dry_run { " (dry run)" } else { "" }
+            );
+            for op in pending.ops {
+                match op {
+                    BackgroundOp::Upload(file, name, version) => println!(
+                        "  upload {} (inode {}, version {}.{})",
+                        name, file, version.0, version.1
+                    ),
+                    BackgroundOp::Create(parent, name, kind) => {
+                        println!("  create {:?} {} in inode {}", kind, name, parent)
+                    }
+                    BackgroundOp::Delete(file) => println!("  delete inode {}", file),
+                }
+            }
+        }
+        return;
+    }
+
+    if let Some(("fetch", sub_matches)) = matches.subcommand() {
+        let token = sub_matches.value_of("token").unwrap();
+        let claim = share::peek(token).expect("Cannot read the token");
+        let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
+        let remote = RemoteVault::new(
+            sub_matches.value_of("address").unwrap(),
+            &claim.vault,
+            runtime,
+            BandwidthMeter::new(&bandwidth_path).expect("Cannot read bandwidth counters"),
+        )
+        .expect("Cannot create remote vault instance");
+        let mut vault = SharedVault::new(remote, token);
+        let dest = Path::new(sub_matches.value_of("dest").unwrap());
+        let stats = if claim.subtree {
+            export::export_tree(&mut vault, claim.file, dest)
+        } else {
+            export::export_one(&mut vault, claim.file, dest)
+        }
+        .expect("Error downloading");
+        println!(
+            "Downloaded {} files ({} bytes) and {} directories",
+            stats.files, stats.bytes, stats.directories
+        );
+        return;
+    }
+
+    if let Some(("bans", sub_matches)) = matches.subcommand() {
+        let mut server = running_instance(&config, &bandwidth_path);
+        if let Some(addr) = sub_matches.value_of("clear") {
+            let addr = addr.parse().expect("--clear must be an IP address");
+            let count = server.clear_bans(Some(addr)).expect("Cannot clear the ban");
+            println!("Lifted {} ban(s)", count);
+        } else if sub_matches.is_present("clear-all") {
+            let count = server.clear_bans(None).expect("Cannot clear bans");
+            println!("Lifted {} ban(s)", count);
+        }
+        for ban in server.bans().expect("Cannot get bans") {
+            println!(
+                "{}: {} ({} seconds left)",
+                ban.addr, ban.reason, ban.remaining_secs
+            );
+        }
+        return;
+    }
+
+    if let Some(("connections", sub_matches)) = matches.subcommand() {
+        let mut server = running_instance(&config, &bandwidth_path);
+        let peer = sub_matches.value_of("peer");
+        for event in server.connections().expect("Cannot get connection events") {
+            if peer.is_some() && peer != Some(event.peer.as_str()) {
+                continue;
+            }
+            println!(
+                "{}.{:03} {}: {}{}",
+                event.time_ms / 1000,
+                event.time_ms % 1000,
+                event.peer,
+                event.kind.name(),
+                if event.detail.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", event.detail)
+                }
+            );
+        }
+        return;
+    }
+
+    if let Some(("log", sub_matches)) = matches.subcommand() {
+        let mut server = running_instance(&config, &bandwidth_path);
+        let spec = server
+            .log_filter(
+                sub_matches.value_of("filters").unwrap_or_default(),
+                sub_matches.is_present("reset"),
+            )
+            .expect("Cannot change log filters");
+        println!("{}", spec);
+        return;
+    }
+
+    if let Some(("path", sub_matches)) = matches.subcommand() {
+        let inode = sub_matches.value_of("inode").unwrap();
+        let inode = match inode.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => inode.parse(),
+        }
+        .expect("Bad inode");
+        // The log shows inodes of the mount, drop the prefix of
+        // their vault, see `fuse::FS`.
+        let inode = inode & (2_u64.pow(48) - 1);
+        let mut server = running_instance(&config, &bandwidth_path);
+        let path = server
+            .path_of(sub_matches.value_of("vault").unwrap(), inode)
+            .expect("Cannot find the path");
+        println!("{}", path);
+        return;
+    }
+
+    if let Some(("seal", sub_matches)) = matches.subcommand() {
+        if !sub_matches.is_present("yes") {
+            eprintln!(
+                "Sealing makes {} read-only for good, there is no undoing it, run again with --yes",
+                config.local_vault_name
+            );
+            process::exit(1);
+        }
+        let key = ShareKey::load_or_create(&db_path.join(SHARE_KEY_FILE))
+            .expect("Cannot read the share key");
+        let mut server = running_instance(&config, &bandwidth_path);
+        let time = server
+            .seal(&key.prove(SEAL_COMMAND))
+            .expect("Cannot seal the vault");
+        println!("{} is sealed since {}", config.local_vault_name, time);
+        return;
+    }
+
+    if let Some(("retired", sub_matches)) = matches.subcommand() {
+        let mut retirements =
+            load_retirements(&config, db_path).expect("Cannot read retired vaults");
+        if let Some(name) = sub_matches.value_of("keep") {
+            retirements
+                .keep(name, true)
+                .expect("No retired vault with this name");
+        } else if let Some(name) = sub_matches.value_of("release") {
+            retirements
+                .keep(name, false)
+                .expect("No retired vault with this name");
+        } else if let Some(name) = sub_matches.value_of("purge") {
+            if config.peers.contains_key(name) || name == config.local_vault_name {
+                eprintln!("{} is in the configuration, remove it first", name);
+                process::exit(1);
+            }
+            // A running instance may still use the store.
+            let _lock = lock_store(db_path, takeover, json_errors);
+            let count = retirements
+                .purge(db_path, name)
+                .expect("Cannot remove the store");
+            println!("Removed {} files of {}", count, name);
+        }
+        retirements
+            .save(db_path)
+            .expect("Cannot save retired vaults");
+        let now = unix_now();
+        for (name, retired) in retirements.retired.iter() {
+            let days = now.saturating_sub(retired.since) / (24 * 60 * 60);
+            let fate = if retired.keep || config.retired_grace_days == 0 {
+                "kept".to_string()
+            } else {
+                format!(
+                    "removed in {} days",
+                    config.retired_grace_days.saturating_sub(days)
+                )
+            };
+            println!("{}: retired {} days ago, {}", name, days, fate);
+        }
+        // Stores we don't know to be of a peer aren't retired, but
+        // can be purged by hand.
+        let stored = retire::stored_vaults(db_path).expect("Cannot list stores");
+        for name in stored {
+            let configured = config.peers.contains_key(&name) || name == config.local_vault_name;
+            if !configured && !retirements.retired.contains_key(&name) {
+                println!("{}: not configured, not retired", name);
+            }
+        }
+        return;
     }
 
-    // Create local vault.
+    if let Some(("status", _)) = matches.subcommand() {
+        // The totals are kept in each database, nothing is counted
+        // here.
+        let stored = retire::stored_vaults(db_path).expect("Cannot list stores");
+        for name in stored {
+            let database =
+                Database::new(&db_path.join("db"), &name).expect("Cannot open the database");
+            if database.usage_stale() {
+                println!("{}: not counted yet, mount it once to count", name);
+                continue;
+            }
+            let stats = database.stats().expect("Cannot read the totals");
+            let kind = if name == config.local_vault_name {
+                "local"
+            } else {
+                "cached"
+            };
+            println!(
+                "{} ({}): {} files, {} directories, {} bytes",
+                name, kind, stats.files, stats.directories, stats.size
+            );
+        }
+        return;
+    }
+
+    if let Some(("du", _)) = matches.subcommand() {
+        let stored = retire::stored_vaults(db_path).expect("Cannot list stores");
+        for name in stored {
+            let database =
+                Database::new(&db_path.join("db"), &name).expect("Cannot open the database");
+            let usage = disk_usage::disk_usage(db_path, &name, |file| database.has_file(file))
+                .expect("Cannot measure the store");
+            let (kind, data) = if name == config.local_vault_name {
+                ("local", "data")
+            } else {
+                ("cached", "cache")
+            };
+            println!(
+                "{} ({}): {} bytes, {} bytes of garbage",
+                name,
+                kind,
+                usage.total(),
+                usage.garbage()
+            );
+            println!("  {}: {} bytes", data, usage.data);
+            println!("  database: {} bytes", usage.database);
+            println!("  write copies: {} bytes", usage.write_copies);
+            println!("  partial downloads: {} bytes (garbage)", usage.partial);
+            println!("  orphaned data files: {} bytes (garbage)", usage.orphaned);
+            println!("  graveyard: {} bytes (garbage)", usage.graveyard);
+        }
+        println!("Garbage is safe to remove while monovault isn't running.");
+        return;
+    }
+
+    // Held until we exit. Taken before looking at the mount point,
+    // so a running instance is reported as such, and --force-unmount
+    // only ever unmounts what a dead one left.
+    let _lock = lock_store(db_path, takeover, json_errors);
+
+    // A mount point still mounted looks missing, check that first.
+    if !config.server_only {
+        check_mount_point(&config.mount_point, force_unmount, json_errors);
+    }
+    // Make sure mount point exists.
+    let mount_point = Path::new(&config.mount_point);
+    if !config.server_only && !mount_point.exists() {
+        fail(
+            Failure::MountPoint,
+            &format!("Mount point {} doesn't exist", config.mount_point),
+            json_errors,
+        );
+    }
+    // Writes to the mount point end up in db_path, if one is inside
+    // the other, writes go in circles.
+    if !config.server_only && overlaps(mount_point, db_path) {
+        fail(
+            Failure::MountPoint,
+            "Mount point and db_path can't be inside each other",
+            json_errors,
+        );
+    }
+
+    // Vaults report their changes here.
+    let event_bus = events::EventBus::new();
+    if let Some(path) = &config.event_socket {
+        let bus = event_bus.clone();
+        let path = path.clone();
+        let _ = thread::spawn(move || {
+            if let Err(err) = events::serve_socket(bus, Path::new(&path)) {
+                error!("Cannot serve events on {}: {:?}", path, err);
+            }
+        });
+    }
+
+    if !config.hooks.is_empty() {
+        hooks::start(&event_bus, config.hooks.clone());
+    }
+
+    // Create local vault. It learns which peers went away, and drops
+    // their locks, from the connection log of the remote vaults.
+    let connections = ConnectionLog::new();
     let mut vaults: Vec<VaultRef> = vec![];
-    let local_vault = Arc::new(Mutex::new(GenericVault::Local(
-        LocalVault::new(&config.local_vault_name, &db_path)
-            .expect("Cannot create local vault instance"),
-    )));
+    let mut local = LocalVault::new(
+        &config.local_vault_name,
+        db_path,
+        event_bus.clone(),
+        config.missing_data,
+    )
+    .unwrap_or_else(|err| {
+        fail(
+            Failure::Store,
+            &format!("Cannot open the local vault: {:?}", err),
+            json_errors,
+        )
+    });
+    local.set_atime_policy(config.atime);
+    local.set_name_normalization(config.name_normalization);
+    local.set_case_insensitive(config.case_insensitive);
+    local.set_hash_algorithm(config.hash_algorithm);
+    local.set_connection_log(connections.clone());
+    // Vaults in db_path refuse changes together when it stops taking
+    // writes.
+    let health = StoreHealth::new(db_path);
+    local.set_store_health(health.clone());
+    let local_vault = Arc::new(Mutex::new(GenericVault::Local(local)));
     vaults.push(Arc::clone(&local_vault));
 
+    if config.server_only {
+        serve_only(&config, local_vault, db_path, json_errors);
+    }
+
     let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
 
-    // Create remote vaults.
+    // Create remote vaults. They share a bandwidth meter, which we
+    // save periodically, and the connection log.
+    let meter = BandwidthMeter::new(&bandwidth_path).unwrap_or_else(|err| {
+        fail(
+            Failure::Store,
+            &format!("Cannot read bandwidth counters: {:?}", err),
+            json_errors,
+        )
+    });
+    {
+        let meter = meter.clone();
+        let _ = thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_secs(BANDWIDTH_SAVE_INTERVAL));
+            if let Err(err) = meter.save() {
+                error!("Saving bandwidth counters failed: {:?}", err);
+            }
+        });
+    }
     let remote_vaults: Vec<VaultRef> = config
         .peers
         .iter()
-        .map(|(name, address)| {
-            Arc::new(Mutex::new(GenericVault::Remote(
-                RemoteVault::new(&address, &name, Arc::clone(&runtime))
-                    .expect("Cannot create remote vault instance"),
-            )))
+        .map(|(name, peer)| {
+            let mut remote =
+                RemoteVault::new(&peer.address, name, Arc::clone(&runtime), meter.clone())
+                    .unwrap_or_else(|err| {
+                        fail(
+                            Failure::ConfigInvalid,
+                            &format!("Bad address {} of peer {}: {:?}", peer.address, name, err),
+                            json_errors,
+                        )
+                    });
+            remote.set_connection_log(connections.clone());
+            remote.set_fallback_addresses(peer.fallback_addresses.clone());
+            if peer.timeout_secs > 0 {
+                remote.set_timeout(Some(time::Duration::from_secs(peer.timeout_secs)));
+            }
+            remote.set_keepalive(keepalive(&config));
+            remote.set_bandwidth_limit(peer.bandwidth_limit_kib * 1024);
+            remote.set_hash_algorithm(config.hash_algorithm);
+            if !config.caching_of(name) {
+                remote.set_read_cache(config.read_cache_mib * 1024 * 1024);
+            }
+            if let Some(faults) = &config.faults {
+                warn!("Injecting faults into RPCs to {}: {:?}", name, faults);
+                remote.set_faults(Some(FaultInjector::new(faults.clone())));
+            }
+            Arc::new(Mutex::new(GenericVault::Remote(remote)))
         })
         .collect();
 
@@ -77,27 +1536,114 @@ fn main() {
 
     // Generate the vaults for FUSE and vault server.
     let store_path = Path::new(&config.db_path);
-    let mut vaults_for_fs = if config.caching {
-        remote_vaults
-            .iter()
-            .map(|remote| {
-                Arc::new(Mutex::new(GenericVault::Caching(
-                    CachingVault::new(
-                        &remote.lock().unwrap().name(),
-                        remote_map.clone(),
-                        &store_path,
-                        config.allow_disconnected_delete,
-                        config.allow_disconnected_create,
+    let mut vaults_for_fs: Vec<VaultRef> = remote_vaults
+        .iter()
+        .map(|remote| {
+            let name = remote.lock().unwrap().name();
+            if !config.caching_of(&name) {
+                return Arc::clone(remote);
+            }
+            Arc::new(Mutex::new(GenericVault::Caching(
+                CachingVault::new(
+                    &name,
+                    remote_map.clone(),
+                    store_path,
+                    config.allow_disconnected_delete,
+                    config.allow_disconnected_create,
+                    *config
+                        .vault_cache_max_age_days
+                        .get(&name)
+                        .unwrap_or(&config.cache_max_age_days),
+                    config.background_dry_run,
+                    event_bus.clone(),
+                    config.missing_data,
+                    config.cache_policies.clone(),
+                )
+                .unwrap_or_else(|err| {
+                    fail(
+                        Failure::Store,
+                        &format!("Cannot open the cache: {:?}", err),
+                        json_errors,
                     )
-                    .expect("Cannot create caching remote instance"),
-                )))
-            })
-            .collect()
-    } else {
-        remote_vaults
-    };
+                }),
+            )))
+        })
+        .collect();
+    for vault_lck in vaults_for_fs.iter() {
+        let mut vault = vault_lck.lock().unwrap();
+        let read_only =
+            config.read_only || config.mount_only || config.peers[&vault.name()].read_only;
+        match &mut *vault {
+            GenericVault::Caching(vault) => {
+                vault.set_near_peers(config.near_peers.clone());
+                vault.set_read_only(read_only);
+                vault.set_store_health(health.clone());
+            }
+            GenericVault::Remote(vault) => vault.set_read_only(read_only),
+            GenericVault::Local(_) => (),
+        }
+    }
     vaults_for_fs.push(local_vault);
 
+    // Fill new caches with their remote's tree, in the background so
+    // we can mount meanwhile.
+    if config.bootstrap_cache {
+        for vault_lck in vaults_for_fs.iter() {
+            if !matches!(&*vault_lck.lock().unwrap(), GenericVault::Caching(_)) {
+                continue;
+            }
+            let vault_lck = Arc::clone(vault_lck);
+            let _ = thread::spawn(move || {
+                let name = vault_lck.lock().unwrap().name();
+                match caching_remote::bootstrap(&vault_lck) {
+                    Ok(0) => (),
+                    Ok(count) => info!("Recorded {} files of {} in the cache", count, name),
+                    Err(err) => warn!(
+                        "Cannot fetch the tree of {}, the cache fills as directories are listed: {:?}",
+                        name, err
+                    ),
+                }
+            });
+        }
+    }
+
+    // Periodically evict stale cached files.
+    if config.peers.keys().any(|name| config.caching_of(name)) {
+        let caching_vaults = vaults_for_fs.clone();
+        let _ = thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_secs(EVICTION_INTERVAL));
+            for vault_lck in caching_vaults.iter() {
+                if let GenericVault::Caching(vault) = &mut *vault_lck.lock().unwrap() {
+                    if let Err(err) = vault.evict_stale() {
+                        error!("Evicting stale files in {} failed: {:?}", vault.name(), err);
+                    }
+                }
+            }
+        });
+    }
+
+    // Periodically keep the databases of the local vault and caches
+    // fast as they grow.
+    let maintained_vaults = vaults_for_fs.clone();
+    let _ = thread::spawn(move || loop {
+        thread::sleep(time::Duration::from_secs(DB_MAINTENANCE_INTERVAL));
+        for vault_lck in maintained_vaults.iter() {
+            let mut vault = vault_lck.lock().unwrap();
+            if let GenericVault::Remote(_) = &*vault {
+                continue;
+            }
+            match vault.maintain_database() {
+                Ok(Some(freed)) => info!("Vacuuming {} gave back {} bytes", vault.name(), freed),
+                Ok(None) => (),
+                Err(err) => error!(
+                    "Maintaining the database of {} failed: {:?}",
+                    vault.name(),
+                    err
+                ),
+            }
+        }
+    });
+
     // Run vault server. TODO: Add restart?
     if config.share_local_vault {
         // Vault server uses the same caching remote that FS uses, so
@@ -107,36 +1653,111 @@ fn main() {
             let vault_name = vault.lock().unwrap().name();
             maybe_caching_vault_map.insert(vault_name, Arc::clone(vault));
         }
-        let addr = config.my_address.clone();
-        let _ = thread::spawn(move || {
-            run_server(
-                &addr,
-                &config.local_vault_name,
-                maybe_caching_vault_map,
-                Arc::clone(&runtime),
-            )
-        });
+        let _ = start_server(
+            &config,
+            maybe_caching_vault_map,
+            Arc::clone(&runtime),
+            connections.clone(),
+            db_path,
+            json_errors,
+        );
     }
 
-    // Configure and start FS.
-    let mount_point_name = Path::new(&config.mount_point)
-        .file_name()
-        .unwrap()
-        .to_string_lossy();
-    let options = vec![
-        MountOption::FSName(mount_point_name.clone().into_owned()),
-        MountOption::CUSTOM(format!("volname={}", mount_point_name)),
-        // Auto unmount on process exit (doesn't seem to work).
-        MountOption::AutoUnmount,
-        // Allow root user to access this file system.
-        MountOption::AllowRoot,
-        // Disable special character and block devices
-        MountOption::NoDev,
-        MountOption::RW,
-        // Prevents Apple from generating ._ files.
-        MountOption::CUSTOM("noapplexattr".to_string()),
-        MountOption::CUSTOM("noappledouble".to_string()),
-    ];
-    let fs = FS::new(vaults_for_fs);
-    fuser::mount2(fs, &config.mount_point, &options).expect("Error running the file system");
+    // Keep the stores of peers removed from the configuration,
+    // read-only, until their grace period is over. The vault server
+    // doesn't serve them.
+    let retired = retire_stores(&config, db_path).unwrap_or_else(|err| {
+        fail(
+            Failure::Store,
+            &format!("Cannot retire stores of removed peers: {:?}", err),
+            json_errors,
+        )
+    });
+    for name in retired {
+        match LocalVault::new(&name, db_path, event_bus.clone(), config.missing_data) {
+            Ok(mut vault) => {
+                info!("Mounting retired vault {} read-only", name);
+                vault.set_read_only(true);
+                vaults_for_fs.push(Arc::new(Mutex::new(GenericVault::Local(vault))));
+            }
+            Err(err) => error!("Cannot open retired vault {}: {:?}", name, err),
+        }
+    }
+
+    // Configure and start FS. If the session dies on an error (the
+    // FUSE driver is updated, the kernel hiccups), mount again with
+    // the same vaults, so caches and pending operations carry over.
+    let options = mount_options(&config);
+    let mut attempts = 0;
+    let mut ever_mounted = false;
+    // Keep the inodes of each vault from run to run.
+    let names: Vec<VaultName> = vaults_for_fs
+        .iter()
+        .map(|vault| vault.lock().unwrap().name())
+        .collect();
+    let prefixes = prefixes::load_and_assign(store_path, &names).unwrap_or_else(|err| {
+        fail(
+            Failure::Store,
+            &format!("Cannot assign inode prefixes: {:?}", err),
+            json_errors,
+        )
+    });
+    // Tell the kernel about changes the caches learn of.
+    let invalidator = Invalidator::start(&event_bus, &prefixes);
+    stop_on_signal(&config.mount_point, &vaults_for_fs, &meter);
+    loop {
+        let mut fs = FS::new(vaults_for_fs.clone(), &prefixes, &config);
+        fs.set_invalidator(&invalidator);
+        fs.set_mount_options(&options);
+        fs.set_connection_log(connections.clone());
+        let mounted = fs.mounted();
+        let start = time::Instant::now();
+        let result = fuser::Session::new(fs, mount_point, &options).and_then(|mut session| {
+            invalidator.attach(&session);
+            let result = session.run();
+            invalidator.detach();
+            result
+        });
+        let err = match result {
+            // Unmounted by the user.
+            Ok(()) => break,
+            // Or by `stop_on_signal`.
+            Err(_) if STOP.load(SeqCst) => break,
+            Err(err) => err,
+        };
+        // Never mounted: no FUSE, no permission, not a hiccup.
+        if !ever_mounted && !mounted.load(SeqCst) {
+            // Keep the pending operations we loaded on start.
+            save_state(&vaults_for_fs, &meter);
+            fail(
+                Failure::Mount,
+                &format!("Cannot mount {}: {}", config.mount_point, err),
+                json_errors,
+            );
+        }
+        ever_mounted = true;
+        if start.elapsed() > time::Duration::from_secs(REMOUNT_RESET) {
+            attempts = 0;
+        }
+        attempts += 1;
+        if attempts > config.remount_attempts {
+            save_state(&vaults_for_fs, &meter);
+            fail(
+                Failure::Mount,
+                &format!(
+                    "File system session ended: {}, giving up after {} attempts to mount again",
+                    err, config.remount_attempts
+                ),
+                json_errors,
+            );
+        }
+        let delay = REMOUNT_DELAY * attempts as u64;
+        warn!(
+            "File system session ended: {}, mounting again in {}s ({}/{})",
+            err, delay, attempts, config.remount_attempts
+        );
+        mounts::unmount_stale(&config.mount_point);
+        thread::sleep(time::Duration::from_secs(delay));
+    }
+    save_state(&vaults_for_fs, &meter);
 }
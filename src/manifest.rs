@@ -0,0 +1,131 @@
+/// Chunk manifests: the hash of each chunk of a file's content at
+/// one version, see `HashAlgorithm`. A caching vault downloads a file
+/// chunk by chunk against the manifest, so it can check every chunk
+/// it receives, pick up an interrupted download where it stopped, and
+/// skip chunks it already has from an older version of the file.
+use crate::types::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Files are hashed in chunks of this size, the same size we stream
+/// data in.
+pub const MANIFEST_CHUNK_SIZE: u64 = GRPC_DATA_CHUNK_SIZE as u64;
+
+/// Length of a chunk hash, the same in every algorithm.
+pub const HASH_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// The version of the file the manifest describes.
+    pub version: FileVersion,
+    pub size: u64,
+    /// The algorithm of the hashes in `chunks`.
+    pub algorithm: HashAlgorithm,
+    /// The hash of each chunk, in order. The last chunk may be
+    /// shorter than MANIFEST_CHUNK_SIZE, an empty file has none.
+    pub chunks: Vec<Vec<u8>>,
+}
+
+/// Return the hash of `data` in `algorithm`.
+pub fn hash_chunk(algorithm: HashAlgorithm, data: &[u8]) -> Vec<u8> {
+    match algorithm {
+        HashAlgorithm::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+        HashAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+    }
+}
+
+/// Read the chunk of `fd` at `offset`, at most MANIFEST_CHUNK_SIZE
+/// bytes, less at EOF.
+pub fn read_chunk(fd: &mut File, offset: u64) -> VaultResult<Vec<u8>> {
+    let mut data = vec![];
+    fd.seek(SeekFrom::Start(offset))?;
+    fd.by_ref()
+        .take(MANIFEST_CHUNK_SIZE)
+        .read_to_end(&mut data)?;
+    Ok(data)
+}
+
+impl Manifest {
+    /// Return the manifest of `data` at `version`, in `algorithm`.
+    pub fn of_data(data: &[u8], version: FileVersion, algorithm: HashAlgorithm) -> Manifest {
+        Manifest {
+            version,
+            size: data.len() as u64,
+            algorithm,
+            chunks: data
+                .chunks(MANIFEST_CHUNK_SIZE as usize)
+                .map(|chunk| hash_chunk(algorithm, chunk))
+                .collect(),
+        }
+    }
+
+    /// Return the manifest of the content in `path` at `version`, in
+    /// `algorithm`.
+    pub fn of_file(
+        path: &Path,
+        version: FileVersion,
+        algorithm: HashAlgorithm,
+    ) -> VaultResult<Manifest> {
+        let mut fd = File::open(path)?;
+        let mut manifest = Manifest {
+            version,
+            size: 0,
+            algorithm,
+            chunks: vec![],
+        };
+        loop {
+            let data = read_chunk(&mut fd, manifest.size)?;
+            if data.is_empty() {
+                return Ok(manifest);
+            }
+            manifest.size += data.len() as u64;
+            manifest.chunks.push(hash_chunk(algorithm, &data));
+        }
+    }
+
+    /// Return true if there is a hash of HASH_LEN bytes for every
+    /// chunk of the file, and no more. Downloads size their state by
+    /// the hashes and their copy by `size`, see src/download.rs.
+    pub fn is_well_formed(&self) -> bool {
+        let count = (self.size + MANIFEST_CHUNK_SIZE - 1) / MANIFEST_CHUNK_SIZE;
+        self.chunks.len() as u64 == count && self.chunks.iter().all(|hash| hash.len() == HASH_LEN)
+    }
+
+    /// Return the offset and length of chunk `idx`.
+    pub fn chunk_range(&self, idx: usize) -> (u64, u64) {
+        let offset = idx as u64 * MANIFEST_CHUNK_SIZE;
+        (
+            offset,
+            std::cmp::min(MANIFEST_CHUNK_SIZE, self.size.saturating_sub(offset)),
+        )
+    }
+
+    /// Return true if `data` is chunk `idx` of the file.
+    pub fn check_chunk(&self, idx: usize, data: &[u8]) -> bool {
+        idx < self.chunks.len()
+            && data.len() as u64 == self.chunk_range(idx).1
+            && hash_chunk(self.algorithm, data) == self.chunks[idx]
+    }
+
+    /// Map the hash of each chunk to the chunk's offset.
+    pub fn index(&self) -> HashMap<Vec<u8>, u64> {
+        let mut index = HashMap::new();
+        for (idx, hash) in self.chunks.iter().enumerate() {
+            index.entry(hash.clone()).or_insert(self.chunk_range(idx).0);
+        }
+        index
+    }
+
+    /// Return the hashes concatenated, for storing.
+    pub fn pack_chunks(&self) -> Vec<u8> {
+        self.chunks.concat()
+    }
+
+    /// Inverse of `pack_chunks`.
+    pub fn unpack_chunks(packed: &[u8]) -> Vec<Vec<u8>> {
+        packed.chunks(HASH_LEN).map(|hash| hash.to_vec()).collect()
+    }
+}
@@ -0,0 +1,136 @@
+/// What is mounted on the mount point before we mount it. A crash
+/// leaves the FUSE mount behind with nobody serving it, and every
+/// access to it fails with ENOTCONN (ENXIO on macOS), including the
+/// checks we make before mounting, so we look for that first. Other
+/// mounts on the mount point, like a bind mount of a directory, are
+/// fine to mount over.
+use crate::types::*;
+use log::info;
+use std::path::Path;
+use std::process;
+
+/// What is on a mount point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MountState {
+    /// A FUSE mount whose process is gone.
+    Stale,
+    /// A live FUSE mount, of this type (eg, "fuse", "macfuse").
+    Fuse(String),
+    /// Nothing, or a mount that isn't FUSE.
+    Free,
+}
+
+/// Return true if file system type `fstype` is FUSE.
+pub fn is_fuse(fstype: &str) -> bool {
+    fstype == "fuse"
+        || fstype == "fuseblk"
+        || fstype.starts_with("fuse.")
+        || fstype == "macfuse"
+        || fstype == "osxfuse"
+}
+
+/// Return the type of the topmost file system mounted on `path` in
+/// `mountinfo`, in the format of /proc/self/mountinfo, if any.
+pub fn mounted_type_in(mountinfo: &str, path: &Path) -> Option<String> {
+    let mut found = None;
+    for line in mountinfo.lines() {
+        // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw
+        let (fields, rest) = match line.split_once(" - ") {
+            Some(split) => split,
+            None => continue,
+        };
+        let mount_point = match fields.split(' ').nth(4) {
+            Some(mount_point) => unescape(mount_point),
+            None => continue,
+        };
+        if Path::new(&mount_point) == path {
+            found = rest.split(' ').next().map(|fstype| fstype.to_string());
+        }
+    }
+    found
+}
+
+/// Undo the octal escapes (\040 for space and so on) of mountinfo.
+fn unescape(field: &str) -> String {
+    let mut result = Vec::new();
+    let bytes = field.as_bytes();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let digits = bytes.get(idx + 1..idx + 4).unwrap_or(&[]);
+        if bytes[idx] == b'\\'
+            && digits.len() == 3
+            && digits.iter().all(|digit| (b'0'..=b'7').contains(digit))
+        {
+            let value = digits
+                .iter()
+                .fold(0u32, |value, digit| value * 8 + (digit - b'0') as u32);
+            result.push(value as u8);
+            idx += 4;
+            continue;
+        }
+        result.push(bytes[idx]);
+        idx += 1;
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Return the type of the file system mounted on `path`, if any.
+#[cfg(target_os = "linux")]
+fn mounted_type(path: &Path) -> Option<String> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mounted_type_in(&mountinfo, path)
+}
+
+/// Return the type of the file system mounted on `path`, if any.
+#[cfg(target_os = "macos")]
+fn mounted_type(path: &Path) -> Option<String> {
+    use std::ffi::{CStr, CString, OsStr};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    let c_path = CString::new(path.as_os_str().to_os_string().into_vec()).ok()?;
+    let mut stats: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    let mounted_on = unsafe { CStr::from_ptr(stats.f_mntonname.as_ptr()) };
+    if Path::new(OsStr::from_bytes(mounted_on.to_bytes())) != path {
+        return None;
+    }
+    let fstype = unsafe { CStr::from_ptr(stats.f_fstypename.as_ptr()) };
+    Some(fstype.to_string_lossy().into_owned())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn mounted_type(_path: &Path) -> Option<String> {
+    None
+}
+
+/// Return what is on `mount_point`.
+pub fn mount_state(mount_point: &Path) -> MountState {
+    let path = resolve_path(mount_point);
+    if let Err(err) = std::fs::metadata(&path) {
+        if let Some(libc::ENOTCONN) | Some(libc::ENXIO) | Some(libc::ECONNABORTED) =
+            err.raw_os_error()
+        {
+            return MountState::Stale;
+        }
+    }
+    match mounted_type(&path) {
+        Some(fstype) if is_fuse(&fstype) => MountState::Fuse(fstype),
+        _ => MountState::Free,
+    }
+}
+
+/// Unmount `mount_point` if a dead session left it mounted, so we can
+/// mount it again. It may not be mounted, so failing is fine.
+pub fn unmount_stale(mount_point: &str) {
+    let status = if cfg!(target_os = "macos") {
+        process::Command::new("umount")
+            .args(["-f", mount_point])
+            .status()
+    } else {
+        process::Command::new("fusermount")
+            .args(["-uz", mount_point])
+            .status()
+    };
+    info!("unmount_stale({}) => {:?}", mount_point, status);
+}
@@ -0,0 +1,82 @@
+/// The prefix of each vault's inodes under the mount (see `FS`), kept
+/// in "prefixes.json" under db_path. A vault keeps its prefix from
+/// run to run, whatever order the configuration lists peers in and
+/// whoever is added or removed, so the inodes tools record stay the
+/// same. Prefixes of vaults no longer mounted stay reserved, a peer
+/// that comes back gets its prefix back.
+use crate::types::*;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+/// The file under db_path the prefixes are kept in.
+pub const PREFIXES_FILE: &str = "prefixes.json";
+
+/// The largest prefix, the first 16 bits of an inode. Prefix 0 is the
+/// mount root's.
+pub const MAX_PREFIX: u64 = u16::MAX as u64;
+
+/// Return the prefixes saved in `path`, or nothing if `path` doesn't
+/// exist.
+pub fn load(path: &Path) -> VaultResult<HashMap<VaultName, u64>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let content = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content).map_err(std::io::Error::from)?)
+}
+
+/// Save `prefixes` to `path`, through a temporary file so a crash
+/// doesn't leave a half-written file.
+pub fn save(path: &Path, prefixes: &HashMap<VaultName, u64>) -> VaultResult<()> {
+    let content = serde_json::to_string_pretty(prefixes).map_err(std::io::Error::from)?;
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, content)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Give each vault in `names` a prefix: the one in `saved` if it has
+/// one, otherwise the smallest free one, new vaults taken by name.
+/// Return all the prefixes, `saved` included.
+pub fn assign(
+    names: &[VaultName],
+    saved: &HashMap<VaultName, u64>,
+) -> VaultResult<HashMap<VaultName, u64>> {
+    let mut prefixes = saved.clone();
+    let mut taken: BTreeSet<u64> = prefixes.values().copied().collect();
+    let mut names = names.to_vec();
+    names.sort();
+    let mut next = 1;
+    for name in names {
+        if prefixes.contains_key(&name) {
+            continue;
+        }
+        while taken.contains(&next) {
+            next += 1;
+        }
+        if next > MAX_PREFIX {
+            return Err(VaultError::InvalidArgument(format!(
+                "no prefix left for vault {}, remove {}",
+                name, PREFIXES_FILE
+            )));
+        }
+        taken.insert(next);
+        prefixes.insert(name, next);
+    }
+    Ok(prefixes)
+}
+
+/// Load the prefixes under `db_path`, give the vaults in `names` that
+/// don't have one theirs, and save them back.
+pub fn load_and_assign(
+    db_path: &Path,
+    names: &[VaultName],
+) -> VaultResult<HashMap<VaultName, u64>> {
+    let path = db_path.join(PREFIXES_FILE);
+    let saved = load(&path)?;
+    let prefixes = assign(names, &saved)?;
+    if prefixes != saved {
+        save(&path, &prefixes)?;
+    }
+    Ok(prefixes)
+}
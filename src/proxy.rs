@@ -0,0 +1,237 @@
+/// Vault servers behind HTTP reverse proxies, like nginx or caddy,
+/// that route requests by path. A peer behind one is configured with
+/// an address with a path, eg, http://example.com/monovault/bob; we
+/// connect to the origin and put the path in front of the path of
+/// each request. The server, configured with the same prefix
+/// ("rpc_path_prefix"), takes it off before routing the request, and
+/// still serves requests without it, so peers on the LAN can skip
+/// the proxy.
+///
+/// Behind a proxy, requests come from the proxy's address, so the
+/// server takes the client's address from a header the proxy adds,
+/// if configured ("forwarded_header" and "trusted_proxies"), see
+/// `ForwardedFor`. TLS between peers isn't supported, the proxy
+/// terminates it.
+use crate::types::*;
+use std::net::{IpAddr, SocketAddr};
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, Service};
+use tonic::transport::Channel;
+use tower_layer::Layer;
+
+/// Return `path` as a prefix: starting with "/" and not ending with
+/// one, or None if there's nothing left.
+pub fn normalize_prefix(path: &str) -> Option<String> {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        None
+    } else {
+        Some(format!("/{}", path))
+    }
+}
+
+/// Split `address` into the origin (scheme, host and port) to connect
+/// to and the path prefix, if any.
+pub fn split_address(address: &str) -> VaultResult<(String, Option<String>)> {
+    let uri: http::Uri = address
+        .parse()
+        .map_err(|err| VaultError::InvalidArgument(format!("address {}: {}", address, err)))?;
+    match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme), Some(authority)) => Ok((
+            format!("{}://{}", scheme, authority),
+            normalize_prefix(uri.path()),
+        )),
+        _ => Err(VaultError::InvalidArgument(format!(
+            "address {} needs a scheme and a host",
+            address
+        ))),
+    }
+}
+
+/// Return `uri` with its path replaced by `path`.
+fn with_path(uri: &http::Uri, path: &str) -> http::Uri {
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    http::Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+}
+
+/// A channel that puts `prefix` in front of the path of each request.
+/// It also sends ERRORS_HEADER, telling the server we decode all of
+/// its errors.
+#[derive(Debug, Clone)]
+pub struct PrefixedChannel {
+    channel: Channel,
+    prefix: Option<String>,
+}
+
+impl PrefixedChannel {
+    pub fn new(channel: Channel, prefix: Option<String>) -> PrefixedChannel {
+        PrefixedChannel { channel, prefix }
+    }
+}
+
+impl Service<http::Request<BoxBody>> for PrefixedChannel {
+    type Response = <Channel as Service<http::Request<BoxBody>>>::Response;
+    type Error = <Channel as Service<http::Request<BoxBody>>>::Error;
+    type Future = <Channel as Service<http::Request<BoxBody>>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::poll_ready(&mut self.channel, cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<BoxBody>) -> Self::Future {
+        if let Some(prefix) = &self.prefix {
+            let path = format!("{}{}", prefix, request.uri().path());
+            *request.uri_mut() = with_path(request.uri(), &path);
+        }
+        request
+            .headers_mut()
+            .insert(ERRORS_HEADER, http::HeaderValue::from_static("1"));
+        self.channel.call(request)
+    }
+}
+
+/// Headers reverse proxies commonly add to the requests they pass
+/// on, telling a request came through one.
+const PROXY_HEADERS: [&str; 4] = ["forwarded", "x-forwarded-for", "x-real-ip", "via"];
+
+/// Who sent a request to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Client {
+    /// The address the request came from, as a trusted proxy reports
+    /// it if it came through one.
+    pub addr: Option<SocketAddr>,
+    /// True if the request came through a reverse proxy, or looks
+    /// like it did.
+    pub proxied: bool,
+}
+
+/// Tells who sent the requests that reach the server through reverse
+/// proxies. Only the proxies at `proxies` are believed, and only
+/// about the address in `header`, eg, "x-forwarded-for".
+#[derive(Debug, Clone, Default)]
+pub struct ForwardedFor {
+    /// The header, in lower case.
+    pub header: Option<String>,
+    pub proxies: Vec<IpAddr>,
+}
+
+impl ForwardedFor {
+    /// Return who sent `request`, see `resolve`.
+    pub fn client<T>(&self, request: &tonic::Request<T>) -> Client {
+        let prefixed = request.extensions().get::<Prefixed>().is_some();
+        let headers = request.metadata().clone().into_headers();
+        self.resolve(request.remote_addr(), &headers, prefixed)
+    }
+
+    /// Return who sent a request with `headers` on a connection from
+    /// `remote`. `prefixed` is true if its path had the prefix
+    /// proxies route on. A request is proxied if it comes from a
+    /// trusted proxy, has the prefix, or has a header proxies add;
+    /// only a trusted proxy tells us where it came from.
+    pub fn resolve(
+        &self,
+        remote: Option<SocketAddr>,
+        headers: &http::HeaderMap,
+        prefixed: bool,
+    ) -> Client {
+        let trusted = remote.map_or(false, |addr| self.proxies.contains(&addr.ip()));
+        let proxied = trusted
+            || prefixed
+            || PROXY_HEADERS
+                .iter()
+                .copied()
+                .chain(self.header.as_deref())
+                .any(|name| headers.contains_key(name));
+        let forwarded = match &self.header {
+            Some(header) if trusted => headers
+                .get(header.as_str())
+                .and_then(|value| value.to_str().ok())
+                .and_then(last_address),
+            _ => None,
+        };
+        Client {
+            addr: forwarded.or(remote),
+            proxied,
+        }
+    }
+}
+
+/// Return the last address in `value`, a list like "203.0.113.7,
+/// 10.0.0.1". That is the one the proxy added, the ones before it
+/// came with the request and can be made up.
+fn last_address(value: &str) -> Option<SocketAddr> {
+    let last = value.rsplit(',').next()?.trim();
+    last.parse::<SocketAddr>()
+        .ok()
+        .or_else(|| last.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0)))
+}
+
+/// Marks requests that had the path prefix, see `ForwardedFor`.
+#[derive(Debug, Clone, Copy)]
+struct Prefixed;
+
+/// Wraps the server's routes in `StripPrefix`.
+#[derive(Debug, Clone)]
+pub struct StripPrefixLayer {
+    prefix: Option<String>,
+}
+
+impl StripPrefixLayer {
+    pub fn new(prefix: Option<String>) -> StripPrefixLayer {
+        StripPrefixLayer { prefix }
+    }
+}
+
+impl<S> Layer<S> for StripPrefixLayer {
+    type Service = StripPrefix<S>;
+
+    fn layer(&self, inner: S) -> StripPrefix<S> {
+        StripPrefix {
+            inner,
+            prefix: self.prefix.clone(),
+        }
+    }
+}
+
+/// The server side of `PrefixedChannel`: takes `prefix` off the path
+/// of requests that have it.
+#[derive(Debug, Clone)]
+pub struct StripPrefix<S> {
+    inner: S,
+    prefix: Option<String>,
+}
+
+impl<S, B> Service<http::Request<B>> for StripPrefix<S>
+where
+    S: Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        if let Some(prefix) = &self.prefix {
+            let rest = request
+                .uri()
+                .path()
+                .strip_prefix(prefix.as_str())
+                .filter(|rest| rest.starts_with('/'))
+                .map(|rest| rest.to_string());
+            if let Some(rest) = rest {
+                *request.uri_mut() = with_path(request.uri(), &rest);
+                request.extensions_mut().insert(Prefixed);
+            }
+        }
+        self.inner.call(request)
+    }
+}
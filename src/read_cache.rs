@@ -0,0 +1,121 @@
+/// An in-memory cache of blocks read from a remote vault, for remote
+/// vaults without caching (the "read_cache_mib" setting). Programs
+/// that read the same part of a file again, like a media player
+/// seeking back or a compiler rereading headers, then don't go to the
+/// network each time. Blocks are kept from open to open as long as
+/// the file's version stays the same, and the least recently used
+/// blocks are dropped when the cache is full.
+use crate::types::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Size of a cached block in bytes. Reads are rounded out to blocks.
+pub const READ_CACHE_BLOCK_SIZE: u64 = 64 * 1024;
+
+struct Block {
+    data: Vec<u8>,
+    /// When the block was last used, see `ReadCache::clock`.
+    used: u64,
+}
+
+pub struct ReadCache {
+    /// The most bytes of blocks we keep.
+    capacity: u64,
+    /// Bytes of blocks we keep now.
+    size: u64,
+    /// Maps (file, block index) to the block.
+    blocks: HashMap<(Inode, u64), Block>,
+    /// Maps last use to the block used then, oldest first.
+    lru: BTreeMap<u64, (Inode, u64)>,
+    /// The version of each file when we cached its blocks.
+    versions: HashMap<Inode, FileVersion>,
+    /// Counts uses, so each use gets a distinct time.
+    clock: u64,
+}
+
+impl ReadCache {
+    /// Return a cache that keeps at most `capacity` bytes.
+    pub fn new(capacity: u64) -> ReadCache {
+        ReadCache {
+            capacity,
+            size: 0,
+            blocks: HashMap::new(),
+            lru: BTreeMap::new(),
+            versions: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Note that `file` is at `version` now, and drop its blocks if
+    /// they are from another version.
+    pub fn validate(&mut self, file: Inode, version: FileVersion) {
+        if self.versions.insert(file, version) != Some(version) {
+            self.invalidate(file);
+            self.versions.insert(file, version);
+        }
+    }
+
+    /// Drop the blocks of `file`, eg, after we wrote to it.
+    pub fn invalidate(&mut self, file: Inode) {
+        let dropped: Vec<(Inode, u64)> = self
+            .blocks
+            .keys()
+            .filter(|key| key.0 == file)
+            .copied()
+            .collect();
+        for key in dropped {
+            self.remove(key);
+        }
+        self.versions.remove(&file);
+    }
+
+    /// Drop every block.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.lru.clear();
+        self.versions.clear();
+        self.size = 0;
+    }
+
+    /// Return block `index` of `file`, if cached.
+    pub fn get(&mut self, file: Inode, index: u64) -> Option<&[u8]> {
+        self.clock += 1;
+        let block = self.blocks.get_mut(&(file, index))?;
+        self.lru.remove(&block.used);
+        block.used = self.clock;
+        self.lru.insert(self.clock, (file, index));
+        Some(&block.data)
+    }
+
+    /// Cache `data` as block `index` of `file`. The last block of a
+    /// file can be shorter than READ_CACHE_BLOCK_SIZE.
+    pub fn insert(&mut self, file: Inode, index: u64, data: Vec<u8>) {
+        if data.len() as u64 > self.capacity {
+            return;
+        }
+        self.remove((file, index));
+        self.clock += 1;
+        self.size += data.len() as u64;
+        self.lru.insert(self.clock, (file, index));
+        self.blocks.insert(
+            (file, index),
+            Block {
+                data,
+                used: self.clock,
+            },
+        );
+        while self.size > self.capacity {
+            let oldest = match self.lru.values().next() {
+                Some(key) => *key,
+                None => break,
+            };
+            self.remove(oldest);
+        }
+    }
+
+    fn remove(&mut self, key: (Inode, u64)) {
+        if let Some(block) = self.blocks.remove(&key) {
+            self.lru.remove(&block.used);
+            self.size -= block.data.len() as u64;
+        }
+    }
+}
@@ -3,69 +3,557 @@ use std::sync::Arc;
 /// Basically a gRPC client that makes requests to remote vault
 /// servers. This does not mask network error into FileNotFind errors:
 /// caching remote uses this as a backend.
+use crate::background_worker::{BackgroundOp, PendingOps};
+use crate::bandwidth::{BandwidthMeter, Throttle};
+use crate::bans::Ban;
+use crate::clock::{self, TimedChannel};
+use crate::connections::{ConnectionEvent, ConnectionEventKind, ConnectionLog};
+use crate::dir_columns;
+use crate::faults::FaultInjector;
+use crate::interrupt;
+use crate::locks::{self, FileLock};
+use crate::manifest::{hash_chunk, Manifest};
+use crate::proxy::{self, PrefixedChannel};
+use crate::read_cache::{ReadCache, READ_CACHE_BLOCK_SIZE};
 use crate::rpc;
 use crate::rpc::vault_rpc_client::VaultRpcClient;
-use crate::rpc::{FileToWrite, Grail};
+use crate::rpc::FileToWrite;
+use crate::seal::SealState;
 use crate::types::*;
-use log::{debug, info};
-use tokio::runtime::{Builder, Runtime};
+use log::{debug, error, info, warn};
+use prost::Message;
+use std::future::Future;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::runtime::Runtime;
 use tokio_stream::StreamExt;
-use tonic::transport::Channel;
+use tonic::transport::Endpoint;
 use tonic::{Request, Status};
 
 #[derive(Debug)]
 pub struct RemoteVault {
     rt: Arc<Runtime>,
     addr: String,
+    /// Addresses to try after `addr`, see `set_fallback_addresses`.
+    fallback_addrs: Vec<String>,
     client: Option<VaultRpcClient<Channel>>,
     name: String,
+    /// Counts bytes of our requests to this remote and its responses.
+    meter: BandwidthMeter,
+    /// If set, fail, delay or corrupt RPCs on purpose.
+    faults: Option<FaultInjector>,
+    /// Notes the connection to this remote going up and down.
+    connection: Connection,
+    /// Blocks we read recently, if enabled, see `set_read_cache`.
+    read_cache: Option<ReadCache>,
+    /// Whether the remote lists directories in columns, false once it
+    /// told us it can't (it runs an older version).
+    readdir_columns: bool,
+    /// Whether the remote takes sizes past 4 GiB in one RPC, see
+    /// `max_transfer`.
+    wide_sizes: bool,
+    /// The vaults the remote keeps copies of, None if it runs an
+    /// older version that doesn't tell, see `savage_refusal`.
+    copies: Option<Vec<VaultName>>,
+    /// The hash algorithm we prefer, see `set_hash_algorithm`.
+    hash_algorithm: HashAlgorithm,
+    /// The hash algorithms the remote knows, empty if it runs an
+    /// older version that only knows SHA-256.
+    hash_algorithms: Vec<HashAlgorithm>,
+    /// Whether we warned that the remote can't serve savage requests.
+    savage_warned: bool,
+    /// If set, give up on connecting and on RPCs after this long.
+    timeout: Option<Duration>,
+    /// If set, ping the remote after the connection is idle this
+    /// long.
+    keepalive: Option<Duration>,
+    /// If set, keeps the traffic with this remote under a limit.
+    throttle: Option<Throttle>,
+    /// If true, refuse changes, see `set_read_only`.
+    read_only: bool,
+    /// Whether the remote sealed its vault, see `seal_state`.
+    seal_state: SealState,
+}
+
+/// A tree being received from a remote, see `RemoteVault::tree`. It
+/// doesn't hold the remote, others may use it between batches.
+pub struct TreeStream {
+    stream: tonic::Streaming<rpc::TreeEntries>,
+}
+
+/// What our RPCs to a remote go through.
+type Channel = TimedChannel<PrefixedChannel>;
+
+/// Our connection to a remote, as the connection log sees it.
+#[derive(Debug)]
+struct Connection {
+    log: ConnectionLog,
+    peer: VaultName,
+}
+
+impl Connection {
+    /// Log how an RPC went, see `ConnectionLog::observe`.
+    fn observe<T>(&self, result: VaultResult<T>) -> VaultResult<T> {
+        self.log.observe(&self.peer, result)
+    }
+
+    /// Like `translate_result`, but log how the RPC went.
+    fn translate<T>(&self, res: Result<T, Status>) -> VaultResult<T> {
+        self.observe(translate_result(res))
+    }
 }
 
 fn kind2num(v: VaultFileType) -> i32 {
-    let k = match v {
+    match v {
         VaultFileType::File => 1,
         VaultFileType::Directory => 2,
-    };
-    return k;
+    }
 }
 
 fn num2kind(k: i32) -> VaultFileType {
     if k == 1 {
-        return VaultFileType::File;
+        VaultFileType::File
     } else {
-        return VaultFileType::Directory;
+        VaultFileType::Directory
     }
 }
 
 impl RemoteVault {
-    pub fn new(addr: &str, name: &str, runtime: Arc<Runtime>) -> VaultResult<RemoteVault> {
-        return Ok(RemoteVault {
+    pub fn new(
+        addr: &str,
+        name: &str,
+        runtime: Arc<Runtime>,
+        meter: BandwidthMeter,
+    ) -> VaultResult<RemoteVault> {
+        Ok(RemoteVault {
             rt: runtime,
             addr: addr.to_string(),
+            fallback_addrs: vec![],
             client: None,
             name: name.to_string(),
-        });
+            meter,
+            faults: None,
+            connection: Connection {
+                log: ConnectionLog::new(),
+                peer: name.to_string(),
+            },
+            read_cache: None,
+            readdir_columns: true,
+            wide_sizes: false,
+            copies: None,
+            hash_algorithm: HashAlgorithm::default(),
+            hash_algorithms: vec![],
+            savage_warned: false,
+            timeout: None,
+            keepalive: None,
+            throttle: None,
+            read_only: false,
+            seal_state: SealState::new(),
+        })
+    }
+
+    /// If we can't connect to the remote's address, try `addrs` in
+    /// order. We keep using the first address that works until we
+    /// restart.
+    pub fn set_fallback_addresses(&mut self, addrs: Vec<String>) {
+        self.fallback_addrs = addrs;
+    }
+
+    /// Give up on connecting, and on each RPC, after `timeout`, or
+    /// wait forever if it's None. Takes effect on the next connection.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Ping the remote after the connection is idle for `interval`,
+    /// so proxies in between don't drop it, or never if it's None.
+    /// Takes effect on the next connection.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) {
+        self.keepalive = interval;
+    }
+
+    /// Keep the traffic with the remote, both ways, under
+    /// `bytes_per_sec` on average, or don't limit it if it's 0.
+    pub fn set_bandwidth_limit(&mut self, bytes_per_sec: u64) {
+        self.throttle = if bytes_per_sec == 0 {
+            None
+        } else {
+            Some(Throttle::new(bytes_per_sec))
+        };
+    }
+
+    /// When `read_only` is true, refuse every change to the vault
+    /// with VaultError::ReadOnly.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    fn check_writable(&self) -> VaultResult<()> {
+        if self.read_only || self.seal_state.sealed().is_some() {
+            Err(VaultError::ReadOnly(self.name.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return whether the remote sealed its vault, as far as we know,
+    /// see src/seal.rs. We learn it when we connect. The caching
+    /// vault in front of us shares it.
+    pub fn seal_state(&self) -> SealState {
+        self.seal_state.clone()
+    }
+
+    /// Return when the remote sealed its vault, None if it didn't or
+    /// we don't know yet.
+    pub fn sealed(&self) -> Option<u64> {
+        self.seal_state.sealed()
+    }
+
+    /// Inject failures into RPCs to this remote from now on, or stop
+    /// if `faults` is None.
+    pub fn set_faults(&mut self, faults: Option<FaultInjector>) {
+        self.faults = faults;
+    }
+
+    /// Return the faults injected into RPCs to this remote, if any.
+    pub fn faults(&self) -> Option<&FaultInjector> {
+        self.faults.as_ref()
+    }
+
+    /// Log connection events of this remote in `log` from now on,
+    /// instead of a log of its own.
+    pub fn set_connection_log(&mut self, log: ConnectionLog) {
+        self.connection.log = log;
+        // The channel notes the remote's clock in the log it was
+        // made with.
+        self.client = None;
+    }
+
+    /// Return how far ahead of ours the remote's clock is, in
+    /// milliseconds, as we move its times by, see `clock::correction`.
+    /// 0 if it never told us.
+    fn clock_offset(&self) -> i64 {
+        clock::correction(self.connection.log.clock_offset(&self.name).unwrap_or(0))
+    }
+
+    /// Return `info`, with its times moved from the remote's clock to
+    /// ours.
+    fn localize(&self, mut info: FileInfo) -> FileInfo {
+        let offset = self.clock_offset();
+        info.atime = clock::shift(info.atime, -offset);
+        info.mtime = clock::shift(info.mtime, -offset);
+        info
+    }
+
+    /// Return the most bytes the remote reads or writes in one RPC.
+    /// Peers running older versions take sizes as 32 bits and cut
+    /// larger ones short, we split what we send them.
+    fn max_transfer(&self) -> u64 {
+        if self.wide_sizes {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        }
+    }
+
+    /// Read from the remote, bypassing the read cache.
+    fn read_remote(&mut self, file: Inode, offset: i64, size: u64) -> VaultResult<Vec<u8>> {
+        let mut result: Vec<u8> = Vec::new();
+        self.inject("read")?;
+        self.get_client()?;
+        // Servers answer at most MAX_READ_SIZE, shorter looks like EOF.
+        let limit = std::cmp::min(self.max_transfer(), MAX_READ_SIZE);
+        loop {
+            let done = result.len() as u64;
+            let wanted = std::cmp::min(size - done, limit);
+            self.read_piece(file, offset + done as i64, wanted, &mut result)?;
+            // Stop at EOF.
+            if result.len() as u64 - done < wanted || result.len() as u64 >= size {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Read `size` bytes at `offset` of `file` in one RPC and add them
+    /// to `result`.
+    fn read_piece(
+        &mut self,
+        file: Inode,
+        offset: i64,
+        size: u64,
+        result: &mut Vec<u8>,
+    ) -> VaultResult<()> {
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::FileToRead { file, offset, size };
+        let sent = request.encoded_len();
+        let value = self
+            .connection
+            .translate(block_on(&self.rt, client.read(request))?)?;
+        let mut stream = value.into_inner();
+        let mut received_len = 0;
+        while let Some(received) = block_on(&self.rt, stream.next())? {
+            let mut value = self.connection.translate(received)?;
+            received_len += value.encoded_len();
+            if let Some(faults) = &self.faults {
+                faults.on_chunk(&mut value.payload);
+            }
+            result.extend(&value.payload);
+        }
+        self.record(sent, received_len);
+        Ok(())
+    }
+
+    /// Write `data` at `offset` of `file`, or at the end if `append`,
+    /// in one RPC.
+    fn write_piece(
+        &mut self,
+        file: Inode,
+        offset: i64,
+        data: &[u8],
+        append: bool,
+    ) -> VaultResult<u64> {
+        let client = self.client.as_mut().unwrap();
+        // Write is for direct writing, so we don't care about the version.
+        let algorithm = self.hash_algorithm.negotiate(&self.hash_algorithms);
+        let mut chunks =
+            WriteIterator::new(file, data, offset, GRPC_DATA_CHUNK_SIZE, (1, 0), algorithm);
+        chunks.append = append;
+        let request = Request::new(tokio_stream::iter(chunks));
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.write(request))?)?
+            .into_inner();
+        self.record(data.len(), response.encoded_len());
+        Ok(response.value)
+    }
+
+    /// Write `data` at the end of `file` in one RPC.
+    fn append_piece(&mut self, file: Inode, data: &[u8]) -> VaultResult<u64> {
+        // Peers running older versions ignore the append flag and
+        // write at the offset, make it the end as we last saw it.
+        let size = self.attr(file)?.size;
+        self.write_piece(file, size as i64, data, true)
+    }
+
+    /// Read through the read cache: serve the blocks we have and
+    /// fetch the others in one request.
+    fn read_cached(&mut self, file: Inode, offset: u64, size: u64) -> VaultResult<Vec<u8>> {
+        let end = check_range(offset as i64, size)? + size;
+        let first = offset / READ_CACHE_BLOCK_SIZE;
+        let last = (end + READ_CACHE_BLOCK_SIZE - 1) / READ_CACHE_BLOCK_SIZE;
+        let cache = self.read_cache.as_mut().unwrap();
+        let mut blocks: Vec<Option<Vec<u8>>> = vec![];
+        for index in first..last {
+            let block = cache.get(file, index).map(|data| data.to_vec());
+            // A short block is the end of the file.
+            let eof = matches!(&block, Some(data) if (data.len() as u64) < READ_CACHE_BLOCK_SIZE);
+            blocks.push(block);
+            if eof {
+                break;
+            }
+        }
+        let missing = (
+            blocks.iter().position(Option::is_none),
+            blocks.iter().rposition(Option::is_none),
+        );
+        if let (Some(from), Some(to)) = missing {
+            debug!(
+                "read_cached({}): fetching blocks {}..={}",
+                file,
+                first + from as u64,
+                first + to as u64
+            );
+            let data = self.read_remote(
+                file,
+                ((first + from as u64) * READ_CACHE_BLOCK_SIZE) as i64,
+                (to - from + 1) as u64 * READ_CACHE_BLOCK_SIZE,
+            )?;
+            let cache = self.read_cache.as_mut().unwrap();
+            let mut chunks = data.chunks(READ_CACHE_BLOCK_SIZE as usize);
+            for (idx, slot) in blocks[from..=to].iter_mut().enumerate() {
+                // Blocks past the end of the file are empty.
+                let block = chunks.next().unwrap_or(&[]).to_vec();
+                cache.insert(file, first + (from + idx) as u64, block.clone());
+                *slot = Some(block);
+            }
+        }
+        let mut result = vec![];
+        for block in blocks.into_iter().flatten() {
+            let eof = (block.len() as u64) < READ_CACHE_BLOCK_SIZE;
+            result.extend(block);
+            if eof {
+                break;
+            }
+        }
+        let skip = (offset - first * READ_CACHE_BLOCK_SIZE) as usize;
+        Ok(result.into_iter().skip(skip).take(size as usize).collect())
+    }
+
+    /// Hash uploads and ask for manifests in `algorithm` from now
+    /// on, if the remote knows it, see `HashAlgorithm::negotiate`.
+    pub fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) {
+        self.hash_algorithm = algorithm;
+    }
+
+    /// Keep up to `capacity` bytes of blocks read from this remote in
+    /// memory, or stop if `capacity` is 0. Only for remotes without a
+    /// caching vault in front, which caches whole files already.
+    pub fn set_read_cache(&mut self, capacity: u64) {
+        self.read_cache = if capacity == 0 {
+            None
+        } else {
+            Some(ReadCache::new(capacity))
+        };
+    }
+
+    /// Maybe delay or fail RPC `call`, see `FaultInjector`.
+    fn inject(&self, call: &str) -> VaultResult<()> {
+        match &self.faults {
+            // An injected drop is the connection going down.
+            Some(faults) => match faults.before_call(call) {
+                Ok(()) => Ok(()),
+                err => self.connection.observe(err),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Like `inject`, for RPCs that write.
+    fn inject_write(&self, call: &str) -> VaultResult<()> {
+        self.inject(call)?;
+        match &self.faults {
+            Some(faults) => faults.before_write(call),
+            None => Ok(()),
+        }
+    }
+
+    /// Record the size of an RPC's request and response.
+    /// Record the size of an RPC's request and response. With a
+    /// bandwidth limit, wait until they fit in it.
+    fn record(&self, sent: usize, received: usize) {
+        self.meter.record(&self.name, sent as u64, received as u64);
+        if let Some(throttle) = &self.throttle {
+            throttle.pass((sent + received) as u64);
+        }
+    }
+
+    /// Connect to `addr`. A path in `addr` goes in front of the path
+    /// of each request, see proxy.rs.
+    fn connect(&self, addr: &str) -> VaultResult<VaultRpcClient<Channel>> {
+        let (origin, prefix) = proxy::split_address(addr)?;
+        let mut endpoint = Endpoint::from_shared(origin)?;
+        if let Some(timeout) = self.timeout {
+            endpoint = endpoint.timeout(timeout);
+        }
+        if let Some(interval) = self.keepalive {
+            endpoint = endpoint
+                .http2_keep_alive_interval(interval)
+                .keep_alive_while_idle(true);
+        }
+        let connecting = endpoint.connect();
+        let channel = match self.timeout {
+            Some(timeout) => block_on(&self.rt, async {
+                tokio::time::timeout(timeout, connecting).await
+            })?
+            .map_err(|_| VaultError::RpcError(format!("timed out connecting to {}", addr)))??,
+            None => block_on(&self.rt, connecting)??,
+        };
+        Ok(VaultRpcClient::new(TimedChannel::new(
+            PrefixedChannel::new(channel, prefix),
+            self.connection.log.clone(),
+            &self.name,
+        )))
     }
 
     fn get_client(&mut self) -> VaultResult<()> {
-        let addr = self.addr.clone();
-        match &self.client {
-            Some(_) => Ok(()),
-            None => {
-                self.client = Some(self.rt.block_on(VaultRpcClient::connect(addr.clone()))?);
-                info!("Connected to {}", addr);
-                Ok(())
+        if self.client.is_some() {
+            return Ok(());
+        }
+        // From now on `addr` is the address we connected to. If
+        // none works, it stays, so the next try starts from it again.
+        let mut result = self.connect(&self.addr);
+        let mut tried = self.addr.clone();
+        for addr in self.fallback_addrs.clone() {
+            if result.is_ok() {
+                break;
+            }
+            debug!(
+                "Cannot connect to {} at {}, trying {}",
+                self.name, tried, addr
+            );
+            result = self.connect(&addr);
+            if result.is_ok() {
+                self.addr = addr;
+            } else {
+                tried = addr;
             }
         }
+        let mut client = self.connection.observe(result)?;
+        let identity = self.check_identity(&mut client)?;
+        self.wide_sizes = identity.wide_sizes;
+        if identity.sealed != 0 {
+            self.seal_state.seal(identity.sealed);
+        }
+        self.hash_algorithms = identity
+            .hash_algorithms
+            .iter()
+            .filter_map(|&code| HashAlgorithm::from_code(code))
+            .collect();
+        self.copies = if identity.lists_copies {
+            Some(identity.copies)
+        } else {
+            None
+        };
+        self.client = Some(client);
+        info!("Connected to {}", self.addr);
+        Ok(())
+    }
+
+    /// Make sure the server behind `client` serves the vault we
+    /// think it does, so a wrong address in the config doesn't make
+    /// us silently use someone else's vault. Return what it told
+    /// about itself.
+    fn check_identity(&self, client: &mut VaultRpcClient<Channel>) -> VaultResult<rpc::Identity> {
+        match block_on(&self.rt, client.identity(rpc::Empty {}))? {
+            Ok(response) => {
+                let identity = response.into_inner();
+                if identity.vault != self.name {
+                    error!(
+                        "{} is configured as vault {} but serves vault {}, refusing to use it",
+                        self.addr, self.name, identity.vault
+                    );
+                    return Err(VaultError::PeerMismatch(self.name.clone(), identity.vault));
+                }
+                Ok(identity)
+            }
+            // Peers running older versions can't tell us.
+            Err(status) if status.code() == tonic::Code::Unimplemented => {
+                warn!(
+                    "{} doesn't report its vault name, can't verify it is {}",
+                    self.addr, self.name
+                );
+                Ok(rpc::Identity::default())
+            }
+            Err(status) => Err(unpack_status(status)),
+        }
     }
 }
 
 struct WriteIterator {
     file: u64,
     data: Vec<u8>,
-    offset: usize,
+    /// File offset of the start of `data`.
+    offset: i64,
+    /// How much of `data` we've sent.
+    sent: usize,
     block_size: usize,
     version: FileVersion,
+    /// Ask the receiver to write at the end of the file.
+    append: bool,
+    /// The algorithm chunks are hashed in.
+    algorithm: HashAlgorithm,
+    /// The modification time the receiver gives the file, 0 for none.
+    mtime: u64,
 }
 
 impl WriteIterator {
@@ -73,16 +561,21 @@ impl WriteIterator {
     fn new(
         file: u64,
         data: &[u8],
-        offset: usize,
+        offset: i64,
         block_size: usize,
         version: FileVersion,
+        algorithm: HashAlgorithm,
     ) -> WriteIterator {
         WriteIterator {
             file,
             data: data.to_vec(),
             offset,
+            sent: 0,
             block_size,
             version,
+            append: false,
+            algorithm,
+            mtime: 0,
         }
     }
 }
@@ -92,21 +585,30 @@ impl Iterator for WriteIterator {
 
     fn next(&mut self) -> Option<Self::Item> {
         debug!(
-            "write.iter.next(offset={}, blk_size={}, len={})",
+            "write.iter.next(offset={}, sent={}, blk_size={}, len={})",
             self.offset,
+            self.sent,
             self.block_size,
             self.data.len()
         );
-        if self.offset < self.data.len() {
-            let end = std::cmp::min(self.offset + self.block_size, self.data.len());
+        // Always send at least one chunk, even for empty data, so
+        // the server knows which file it is.
+        if self.sent < self.data.len() || (self.sent == 0 && self.data.is_empty()) {
+            let end = std::cmp::min(self.sent + self.block_size, self.data.len());
+            let data = self.data[self.sent..end].to_vec();
             let stuff = FileToWrite {
                 file: self.file,
-                offset: self.offset as i64,
-                data: self.data[self.offset..end].to_vec(),
+                offset: self.offset + self.sent as i64,
+                hash: hash_chunk(self.algorithm, &data),
+                data,
                 major_ver: self.version.0,
                 minor_ver: self.version.1,
+                append: self.append,
+                hash_algorithm: self.algorithm.code(),
+                mtime: self.mtime,
             };
-            self.offset = end;
+            // Make sure an empty chunk is sent only once.
+            self.sent = std::cmp::max(end, 1);
             Some(stuff)
         } else {
             None
@@ -114,6 +616,43 @@ impl Iterator for WriteIterator {
     }
 }
 
+/// Translate rpc message to FileInfo.
+fn unpack_info(info: rpc::FileInfo) -> FileInfo {
+    FileInfo {
+        inode: info.inode,
+        name: info.name,
+        kind: num2kind(info.kind),
+        size: info.size,
+        atime: info.atime,
+        mtime: info.mtime,
+        version: (info.major_ver, info.minor_ver),
+        mode: info.mode,
+        nlink: std::cmp::max(info.nlink, 1),
+        owner: if info.has_owner {
+            Some((info.uid, info.gid))
+        } else {
+            None
+        },
+        generation: info.generation,
+    }
+}
+
+/// Run `rpc` on `rt` and return its result. If the thread serves a
+/// request (see interrupt.rs), give up on the RPC once the process
+/// that made it is killed, so a peer that stopped answering doesn't
+/// keep it waiting until the connection times out.
+fn block_on<F: Future>(rt: &Runtime, rpc: F) -> VaultResult<F::Output> {
+    match interrupt::requester() {
+        Some(pid) => rt.block_on(async {
+            tokio::select! {
+                output = rpc => Ok(output),
+                _ = interrupt::wait_interrupted(pid) => Err(VaultError::Interrupted(pid)),
+            }
+        }),
+        None => Ok(rt.block_on(rpc)),
+    }
+}
+
 fn translate_result<T>(res: Result<T, Status>) -> VaultResult<T> {
     match res {
         Ok(val) => Ok(val),
@@ -123,121 +662,597 @@ fn translate_result<T>(res: Result<T, Status>) -> VaultResult<T> {
 
 fn unpack_status(status: Status) -> VaultError {
     match status.code() {
-        tonic::Code::NotFound => {
-            let compressed: CompressedError = serde_json::from_str(status.message()).unwrap();
-            let err: VaultError = compressed.into();
-            err
-        }
+        // Errors from newer peers we can't decode still come with a
+        // message.
+        tonic::Code::NotFound => match serde_json::from_str::<CompressedError>(status.message()) {
+            Ok(compressed) => compressed.into(),
+            Err(_) => VaultError::RemoteError(status.message().to_string()),
+        },
         tonic::Code::Unavailable => VaultError::RpcError(status.message().to_string()),
+        // A remote that doesn't answer in time (see `set_timeout`) is
+        // as good as unreachable.
+        tonic::Code::Cancelled | tonic::Code::DeadlineExceeded => {
+            VaultError::RpcError(status.message().to_string())
+        }
         _ => VaultError::RemoteError(status.message().to_string()),
     }
 }
 
+/// Return why we don't send savage requests for `vault` to a peer
+/// that keeps copies of `copies`, or None if we do. A peer that keeps
+/// no copy of `vault`, eg, doesn't cache, can't serve them. Neither
+/// can we tell for a peer running an older version, which doesn't
+/// list its copies (None), so it isn't asked either.
+pub fn savage_refusal(copies: Option<&[VaultName]>, vault: &str) -> Option<&'static str> {
+    match copies {
+        Some(copies) if copies.iter().any(|copy| copy == vault) => None,
+        Some(_) => Some("keeps no copy"),
+        None => Some("doesn't list the copies it keeps"),
+    }
+}
+
+/// Translate rpc message to a background operation.
+fn unpack_op(op: rpc::PendingOp) -> BackgroundOp {
+    match rpc::pending_op::OpKind::from_i32(op.op) {
+        Some(rpc::pending_op::OpKind::Create) => {
+            BackgroundOp::Create(op.file, op.name, num2kind(op.kind))
+        }
+        Some(rpc::pending_op::OpKind::Upload) => {
+            BackgroundOp::Upload(op.file, op.name, (op.major_ver, op.minor_ver))
+        }
+        _ => BackgroundOp::Delete(op.file),
+    }
+}
+
 impl RemoteVault {
-    /// Savage for `file` in `vault` in remote's local cache. If found, return (data, version).
-    pub fn savage(&mut self, vault: &str, file: Inode) -> VaultResult<(Vec<u8>, FileVersion)> {
-        info!("savage(vault={}, file={})", vault, file);
+    /// Ask the remote host which operations its background workers
+    /// haven't performed yet, for each of its caching vaults.
+    pub fn pending(&mut self) -> VaultResult<Vec<PendingOps>> {
+        info!("pending()");
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.pending(rpc::Empty {}))?)?;
+        Ok(response
+            .into_inner()
+            .list
+            .into_iter()
+            .map(|pending| PendingOps {
+                vault: pending.vault,
+                dry_run: pending.dry_run,
+                ops: pending.ops.into_iter().map(unpack_op).collect(),
+                upload_size: pending.upload_size,
+            })
+            .collect())
+    }
+
+    /// Pause (`flag` = true) or resume the remote host's background
+    /// workers.
+    pub fn set_dry_run(&mut self, flag: bool) -> VaultResult<()> {
+        info!("set_dry_run({})", flag);
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        self.connection.translate(block_on(
+            &self.rt,
+            client.set_dry_run(rpc::DryRun { flag }),
+        )?)?;
+        Ok(())
+    }
+
+    /// Ask the remote host which peers its vault server bans.
+    pub fn bans(&mut self) -> VaultResult<Vec<Ban>> {
+        info!("bans()");
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.bans(rpc::Empty {}))?)?;
+        response
+            .into_inner()
+            .list
+            .into_iter()
+            .map(|ban| {
+                Ok(Ban {
+                    addr: ban.addr.parse().map_err(|_| {
+                        VaultError::RemoteError(format!("bad address {}", ban.addr))
+                    })?,
+                    reason: ban.reason,
+                    remaining_secs: ban.remaining_secs,
+                })
+            })
+            .collect()
+    }
+
+    /// Ask the remote host to lift the ban on `addr`, or all bans if
+    /// None. Return the number of bans lifted.
+    pub fn clear_bans(&mut self, addr: Option<IpAddr>) -> VaultResult<u64> {
+        info!("clear_bans({:?})", addr);
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::BanToClear {
+            addr: addr.map(|addr| addr.to_string()).unwrap_or_default(),
+        };
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.clear_bans(request))?)?;
+        Ok(response.into_inner().value)
+    }
+
+    /// Ask the remote host to add the log filter directives in `spec`,
+    /// after going back to its RUST_LOG if `reset`. Return the filters
+    /// in effect.
+    pub fn log_filter(&mut self, spec: &str, reset: bool) -> VaultResult<String> {
+        info!("log_filter({}, {})", spec, reset);
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::LogFilter {
+            spec: spec.to_string(),
+            reset,
+        };
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.log_filter(request))?)?;
+        Ok(response.into_inner().spec)
+    }
+
+    /// Ask the remote host the path of `file` in its vault named
+    /// `vault`, see `Database::path_of`.
+    pub fn path_of(&mut self, vault: &str, file: Inode) -> VaultResult<String> {
+        info!("path_of({}, {})", vault, file);
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::FileOfVault {
+            vault: vault.to_string(),
+            file,
+        };
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.path_of(request))?)?;
+        Ok(response.into_inner().path)
+    }
+
+    /// Ask the remote host to seal its local vault, see
+    /// `LocalVault::seal`, with `proof` from its share key, see
+    /// `ShareKey::prove`. Return when it was sealed.
+    pub fn seal(&mut self, proof: &str) -> VaultResult<u64> {
+        info!("seal()");
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::SealRequest {
+            proof: proof.to_string(),
+        };
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.seal(request))?)?;
+        Ok(response.into_inner().time)
+    }
+
+    /// Return the connection events the remote host logged with its
+    /// peers, oldest first.
+    pub fn connections(&mut self) -> VaultResult<Vec<ConnectionEvent>> {
+        info!("connections()");
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.connections(rpc::Empty {}))?)?;
+        response
+            .into_inner()
+            .list
+            .into_iter()
+            .map(|event| {
+                Ok(ConnectionEvent {
+                    kind: ConnectionEventKind::from_name(&event.kind).ok_or_else(|| {
+                        VaultError::RemoteError(format!("bad connection event {}", event.kind))
+                    })?,
+                    peer: event.peer,
+                    time_ms: event.time_ms,
+                    detail: event.detail,
+                })
+            })
+            .collect()
+    }
+
+    /// Return the attributes of `file`, with share link `token`.
+    pub fn attr_shared(&mut self, token: &str, file: Inode) -> VaultResult<FileInfo> {
+        debug!("attr_shared({})", file);
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::SharedFile {
+            token: token.to_string(),
+            file,
+        };
+        let sent = request.encoded_len();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.attr_shared(request))?)?
+            .into_inner();
+        self.record(sent, response.encoded_len());
+        Ok(self.localize(unpack_info(response)))
+    }
+
+    /// List `dir`, with share link `token`.
+    pub fn readdir_shared(&mut self, token: &str, dir: Inode) -> VaultResult<Vec<FileInfo>> {
+        debug!("readdir_shared({})", dir);
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::SharedFile {
+            token: token.to_string(),
+            file: dir,
+        };
+        let sent = request.encoded_len();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.readdir_shared(request))?)?
+            .into_inner();
+        self.record(sent, response.encoded_len());
+        Ok(response
+            .list
+            .into_iter()
+            .map(|info| self.localize(unpack_info(info)))
+            .collect())
+    }
+
+    /// Read `size` bytes at `offset` of `file`, with share link
+    /// `token`. The file doesn't need to be opened.
+    pub fn read_shared(
+        &mut self,
+        token: &str,
+        file: Inode,
+        offset: i64,
+        size: u64,
+    ) -> VaultResult<Vec<u8>> {
+        info!(
+            "read_shared(file={}, offset={}, size={})",
+            file, offset, size
+        );
+        self.get_client()?;
+        // Older peers would cut larger sizes down to 32 bits, a short
+        // read is better than a wrong one. Newer ones answer at most
+        // MAX_READ_SIZE.
+        let size = std::cmp::min(size, std::cmp::min(self.max_transfer(), MAX_READ_SIZE));
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::SharedRead {
+            token: token.to_string(),
+            file,
+            offset,
+            size,
+        };
+        let sent = request.encoded_len();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.read_shared(request))?)?;
+        let mut stream = response.into_inner();
+        let mut result = vec![];
+        let mut received_len = 0;
+        while let Some(received) = block_on(&self.rt, stream.next())? {
+            let value = self.connection.translate(received)?;
+            received_len += value.encoded_len();
+            result.extend(&value.payload);
+        }
+        self.record(sent, received_len);
+        Ok(result)
+    }
+
+    /// Fail without asking if the remote can't serve savage requests
+    /// for `vault`, see `savage_refusal`. Warn the first time, savage
+    /// requests then quietly skip it.
+    fn check_savage(&mut self, vault: &str) -> VaultResult<()> {
+        match savage_refusal(self.copies.as_deref(), vault) {
+            Some(reason) => {
+                if !self.savage_warned {
+                    warn!(
+                        "{} {}, not asking it for files of {}",
+                        self.name, reason, vault
+                    );
+                    self.savage_warned = true;
+                }
+                Err(VaultError::WrongTypeOfVault("caching".to_string()))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Ask what version of `file` in `vault` the remote has in its
+    /// local cache, without fetching it. Return (version, size), or
+    /// None if the remote runs an older version that can't tell, in
+    /// which case `savage` it without a version.
+    pub fn savage_offer(
+        &mut self,
+        vault: &str,
+        file: Inode,
+    ) -> VaultResult<Option<(FileVersion, u64)>> {
+        info!("savage_offer(vault={}, file={})", vault, file);
+        self.inject("savage_offer")?;
+        self.get_client()?;
+        self.check_savage(vault)?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::Grail {
+            vault: vault.to_string(),
+            file,
+            major_ver: 0,
+            minor_ver: 0,
+        };
+        let sent = request.encoded_len();
+        match block_on(&self.rt, client.savage_offer(request))? {
+            Err(status) if status.code() == tonic::Code::Unimplemented => Ok(None),
+            result => {
+                let response = self.connection.translate(result)?.into_inner();
+                self.record(sent, response.encoded_len());
+                Ok(Some((
+                    (response.major_ver, response.minor_ver),
+                    response.size,
+                )))
+            }
+        }
+    }
+
+    /// Savage for `file` in `vault` in remote's local cache. If found,
+    /// return (data, version). With `version`, the remote only sends
+    /// that version, which it offered earlier.
+    pub fn savage(
+        &mut self,
+        vault: &str,
+        file: Inode,
+        version: Option<FileVersion>,
+    ) -> VaultResult<(Vec<u8>, FileVersion)> {
+        info!(
+            "savage(vault={}, file={}, version={:?})",
+            vault, file, version
+        );
+        self.inject("savage")?;
         self.get_client()?;
+        self.check_savage(vault)?;
         let client = self.client.as_mut().unwrap();
-        let response = translate_result(self.rt.block_on(client.savage(rpc::Grail {
+        let (major_ver, minor_ver) = version.unwrap_or((0, 0));
+        let request = rpc::Grail {
             vault: vault.to_string(),
             file,
-        })))?;
+            major_ver,
+            minor_ver,
+        };
+        let sent = request.encoded_len();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.savage(request))?)?;
         let mut stream = response.into_inner();
         let mut data = vec![];
         let mut version = (1, 0);
-        while let Some(received) = self.rt.block_on(stream.next()) {
-            let value = translate_result(received)?;
+        let mut received_len = 0;
+        while let Some(received) = block_on(&self.rt, stream.next())? {
+            let mut value = self.connection.translate(received)?;
+            received_len += value.encoded_len();
+            if let Some(faults) = &self.faults {
+                faults.on_chunk(&mut value.payload);
+            }
             data.extend(&value.payload);
             version = (value.major_ver, value.minor_ver);
         }
+        self.record(sent, received_len);
         Ok((data, version))
     }
 
-    pub fn submit(&mut self, file: Inode, data: &[u8], version: FileVersion) -> VaultResult<bool> {
+    /// Ask for every file under `dir` in one streamed request, see
+    /// `next_entries`. Return None if the remote runs an older
+    /// version that can't.
+    pub fn tree(&mut self, dir: Inode) -> VaultResult<Option<TreeStream>> {
+        info!("tree({})", dir);
+        self.inject("tree")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::Inode { value: dir };
+        let sent = request.encoded_len();
+        let response = match block_on(&self.rt, client.tree(request))? {
+            Err(status) if status.code() == tonic::Code::Unimplemented => return Ok(None),
+            result => self.connection.translate(result)?,
+        };
+        self.record(sent, 0);
+        Ok(Some(TreeStream {
+            stream: response.into_inner(),
+        }))
+    }
+
+    /// Return the next batch of files of `tree`, each with the
+    /// directory it is listed in, parents before their children.
+    /// Return None once they all came.
+    pub fn next_entries(
+        &mut self,
+        tree: &mut TreeStream,
+    ) -> VaultResult<Option<Vec<(Inode, FileInfo)>>> {
+        let value = match block_on(&self.rt, tree.stream.next())? {
+            Some(received) => self.connection.translate(received)?,
+            None => return Ok(None),
+        };
+        self.record(0, value.encoded_len());
+        let mut result = vec![];
+        for entry in value.list {
+            if let Some(info) = entry.info {
+                result.push((entry.parent, self.localize(unpack_info(info))));
+            }
+        }
+        Ok(Some(result))
+    }
+
+    /// Return the chunk manifest of `file`, in our hash algorithm if
+    /// the remote knows it.
+    pub fn manifest(&mut self, file: Inode) -> VaultResult<Manifest> {
+        info!("manifest({})", file);
+        self.inject("manifest")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::ManifestRequest {
+            file,
+            algorithms: self
+                .hash_algorithm
+                .preferred()
+                .into_iter()
+                .map(HashAlgorithm::code)
+                .collect(),
+        };
+        let sent = request.encoded_len();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.manifest(request))?)?
+            .into_inner();
+        self.record(sent, response.encoded_len());
+        let algorithm = HashAlgorithm::from_code(response.algorithm).ok_or_else(|| {
+            VaultError::RemoteError(format!("unknown hash algorithm {}", response.algorithm))
+        })?;
+        let manifest = Manifest {
+            version: (response.major_ver, response.minor_ver),
+            size: response.size,
+            algorithm,
+            chunks: response.chunks,
+        };
+        if !manifest.is_well_formed() {
+            return Err(VaultError::RemoteError(format!(
+                "manifest of {} has {} hashes for {} bytes",
+                file,
+                manifest.chunks.len(),
+                manifest.size
+            )));
+        }
+        Ok(manifest)
+    }
+
+    /// Send `data`, the whole content of `file` at `version`, with
+    /// its modification time `mtime` (0 for when it arrives). Return
+    /// false if the remote has a newer version.
+    pub fn submit(
+        &mut self,
+        file: Inode,
+        data: &[u8],
+        version: FileVersion,
+        mtime: u64,
+    ) -> VaultResult<bool> {
         info!(
-            "submit(file={}, size={}, version={:?})",
+            "submit(file={}, size={}, version={:?}, mtime={})",
             file,
             data.len(),
-            version
+            version,
+            mtime
         );
+        self.check_writable()?;
+        self.inject_write("submit")?;
         self.get_client()?;
+        // Chunks are hashed before they are corrupted, so the server
+        // catches it.
+        let algorithm = self.hash_algorithm.negotiate(&self.hash_algorithms);
+        let mut chunks =
+            WriteIterator::new(file, data, 0, GRPC_DATA_CHUNK_SIZE, version, algorithm);
+        chunks.mtime = mtime;
+        let chunks: Vec<FileToWrite> = chunks
+            .map(|mut chunk| {
+                if let Some(faults) = &self.faults {
+                    faults.on_chunk(&mut chunk.data);
+                }
+                chunk
+            })
+            .collect();
         let client = self.client.as_mut().unwrap();
-        let request = Request::new(tokio_stream::iter(WriteIterator::new(
-            file,
-            data,
-            0,
-            GRPC_DATA_CHUNK_SIZE,
-            version,
-        )));
-        let response = translate_result(self.rt.block_on(client.submit(request)))?;
-        Ok(response.into_inner().flag)
+        let request = Request::new(tokio_stream::iter(chunks));
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.submit(request))?)?
+            .into_inner();
+        self.record(data.len(), response.encoded_len());
+        Ok(response.flag)
     }
 }
 
 impl Vault for RemoteVault {
     fn name(&self) -> String {
-        return self.name.clone();
+        self.name.clone()
     }
 
     fn attr(&mut self, file: Inode) -> VaultResult<FileInfo> {
         debug!("attr({})", file);
+        self.inject("attr")?;
         self.get_client()?;
         let client = self.client.as_mut().unwrap();
-        let value = translate_result(self.rt.block_on(client.attr(rpc::Inode { value: file })))?;
+        let request = rpc::Inode { value: file };
+        let sent = request.encoded_len();
+        let value = self
+            .connection
+            .translate(block_on(&self.rt, client.attr(request))?)?;
         let v = value.into_inner();
-        Ok(FileInfo {
-            inode: v.inode,
-            name: v.name.to_string(),
-            kind: num2kind(v.kind),
-            size: v.size,
-            atime: v.atime,
-            mtime: v.mtime,
-            version: (v.major_ver, v.minor_ver),
-        })
+        self.record(sent, v.encoded_len());
+        Ok(self.localize(unpack_info(v)))
     }
 
-    fn read(&mut self, file: Inode, offset: i64, size: u32) -> VaultResult<Vec<u8>> {
+    fn read(&mut self, file: Inode, offset: i64, size: u64) -> VaultResult<Vec<u8>> {
         info!("read(file={}, offset={}, size={})", file, offset, size);
-        let mut result: Vec<u8> = Vec::new();
-        self.get_client()?;
-        let client = self.client.as_mut().unwrap();
-        let value = translate_result(self.rt.block_on(client.read(rpc::FileToRead {
-            file,
-            offset,
-            size,
-        })))?;
-        let mut stream = value.into_inner();
-        while let Some(received) = self.rt.block_on(stream.next()) {
-            let value = translate_result(received)?;
-            result.extend(&value.payload);
+        match &self.read_cache {
+            Some(_) if offset >= 0 => self.read_cached(file, offset as u64, size),
+            _ => self.read_remote(file, offset, size),
         }
-        return Ok(result);
     }
 
-    fn write(&mut self, file: Inode, offset: i64, data: &[u8]) -> VaultResult<u32> {
+    fn write(&mut self, file: Inode, offset: i64, data: &[u8]) -> VaultResult<u64> {
         info!(
             "write(file={}, offset={}, size={})",
             file,
             offset,
             data.len()
         );
+        if let Some(cache) = &mut self.read_cache {
+            cache.invalidate(file);
+        }
+        self.check_writable()?;
+        self.inject_write("write")?;
+        self.get_client()?;
+        let limit = self.max_transfer();
+        if data.len() as u64 <= limit {
+            return self.write_piece(file, offset, data, false);
+        }
+        let mut written = 0;
+        for piece in data.chunks(limit as usize) {
+            written += self.write_piece(file, offset + written as i64, piece, false)?;
+        }
+        Ok(written)
+    }
+
+    fn append(&mut self, file: Inode, data: &[u8]) -> VaultResult<u64> {
+        info!("append(file={}, size={})", file, data.len());
+        if let Some(cache) = &mut self.read_cache {
+            cache.invalidate(file);
+        }
+        self.check_writable()?;
+        self.inject_write("append")?;
+        self.get_client()?;
+        let limit = self.max_transfer();
+        if data.len() as u64 <= limit {
+            return self.append_piece(file, data);
+        }
+        let mut written = 0;
+        for piece in data.chunks(limit as usize) {
+            written += self.append_piece(file, piece)?;
+        }
+        Ok(written)
+    }
+
+    fn truncate(&mut self, file: Inode, size: u64) -> VaultResult<()> {
+        info!("truncate(file={}, size={})", file, size);
+        if let Some(cache) = &mut self.read_cache {
+            cache.invalidate(file);
+        }
+        self.check_writable()?;
+        self.inject_write("truncate")?;
         self.get_client()?;
         let client = self.client.as_mut().unwrap();
-        let request = Request::new(tokio_stream::iter(WriteIterator::new(
-            file,
-            data,
-            offset as usize,
-            GRPC_DATA_CHUNK_SIZE,
-            // Write is for direct writing, so we don't care about the version.
-            (1, 0),
-        )));
-        let response = translate_result(self.rt.block_on(client.write(request)))?;
-        Ok(response.into_inner().value)
+        let request = rpc::FileSize { file, size };
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.truncate(request))?)?;
+        self.record(sent, 0);
+        Ok(())
     }
 
     fn create(&mut self, parent: Inode, name: &str, kind: VaultFileType) -> VaultResult<Inode> {
         info!("create(parent={}, name={}, kind={:?})", parent, name, kind);
+        self.check_writable()?;
+        self.inject("create")?;
         self.get_client()?;
         let client = self.client.as_mut().unwrap();
         let request = rpc::FileToCreate {
@@ -245,12 +1260,18 @@ impl Vault for RemoteVault {
             name: name.to_string(),
             kind: kind2num(kind),
         };
-        let response = translate_result(self.rt.block_on(client.create(request)))?.into_inner();
-        return Ok(response.value);
+        let sent = request.encoded_len();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.create(request))?)?
+            .into_inner();
+        self.record(sent, response.encoded_len());
+        Ok(response.value)
     }
 
     fn open(&mut self, file: Inode, mode: OpenMode) -> VaultResult<()> {
         info!("open(file={}, mode={:?})", file, mode);
+        self.inject("open")?;
         self.get_client()?;
         let client = self.client.as_mut().unwrap();
         let mut request = rpc::FileToOpen {
@@ -260,47 +1281,365 @@ impl Vault for RemoteVault {
         if matches!(mode, OpenMode::R) {
             request.mode = 0;
         }
-        translate_result(self.rt.block_on(client.open(request)))?;
-        return Ok(());
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.open(request))?)?;
+        self.record(sent, 0);
+        // Cached blocks of another version of the file are stale.
+        if self.read_cache.is_some() {
+            let version = self.attr(file).map(|info| info.version);
+            let cache = self.read_cache.as_mut().unwrap();
+            match version {
+                Ok(version) => cache.validate(file, version),
+                Err(_) => cache.invalidate(file),
+            }
+        }
+        Ok(())
     }
 
     fn close(&mut self, file: Inode) -> VaultResult<()> {
         info!("close({})", file);
+        self.inject("close")?;
         self.get_client()?;
         let client = self.client.as_mut().unwrap();
-        translate_result(self.rt.block_on(client.close(rpc::Inode { value: file })))?;
+        let request = rpc::Inode { value: file };
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.close(request))?)?;
+        self.record(sent, 0);
+        Ok(())
+    }
 
-        return Ok(());
+    fn set_mode(&mut self, file: Inode, mode: u32) -> VaultResult<()> {
+        info!("set_mode(file={}, mode={:o})", file, mode);
+        self.check_writable()?;
+        self.inject("set_mode")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::FileMode { file, mode };
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.set_mode(request))?)?;
+        self.record(sent, 0);
+        Ok(())
+    }
+
+    fn set_owner(&mut self, file: Inode, uid: u32, gid: u32) -> VaultResult<()> {
+        info!("set_owner(file={}, uid={}, gid={})", file, uid, gid);
+        self.check_writable()?;
+        self.inject_write("set_owner")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::FileOwner { file, uid, gid };
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.set_owner(request))?)?;
+        self.record(sent, 0);
+        Ok(())
+    }
+
+    fn set_times(
+        &mut self,
+        file: Inode,
+        atime: Option<u64>,
+        mtime: Option<u64>,
+    ) -> VaultResult<()> {
+        info!(
+            "set_times(file={}, atime={:?}, mtime={:?})",
+            file, atime, mtime
+        );
+        self.check_writable()?;
+        self.inject_write("set_times")?;
+        self.get_client()?;
+        // Times we send are on the remote's clock.
+        let offset = self.clock_offset();
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::FileTimes {
+            file,
+            has_atime: atime.is_some(),
+            atime: clock::shift(atime.unwrap_or(0), offset),
+            has_mtime: mtime.is_some(),
+            mtime: clock::shift(mtime.unwrap_or(0), offset),
+        };
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.set_times(request))?)?;
+        self.record(sent, 0);
+        Ok(())
     }
 
     fn delete(&mut self, file: Inode) -> VaultResult<()> {
         info!("delete({})", file);
+        if let Some(cache) = &mut self.read_cache {
+            cache.invalidate(file);
+        }
+        self.check_writable()?;
+        self.inject("delete")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::Inode { value: file };
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.delete(request))?)?;
+        self.record(sent, 0);
+        Ok(())
+    }
+
+    fn rename(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!("rename(file={}, parent={}, name={})", file, parent, name);
+        self.check_writable()?;
+        self.inject_write("rename")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::FileToRename {
+            file,
+            parent,
+            name: name.to_string(),
+        };
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.rename(request))?)?;
+        self.record(sent, 0);
+        Ok(())
+    }
+
+    fn link(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!("link(file={}, parent={}, name={})", file, parent, name);
+        self.check_writable()?;
+        self.inject_write("link")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::FileToLink {
+            file,
+            parent,
+            name: name.to_string(),
+        };
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.link(request))?)?;
+        self.record(sent, 0);
+        Ok(())
+    }
+
+    fn unlink(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        info!("unlink(file={}, parent={}, name={})", file, parent, name);
+        if let Some(cache) = &mut self.read_cache {
+            cache.invalidate(file);
+        }
+        self.check_writable()?;
+        self.inject_write("unlink")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::FileToLink {
+            file,
+            parent,
+            name: name.to_string(),
+        };
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.unlink(request))?)?;
+        self.record(sent, 0);
+        Ok(())
+    }
+
+    fn xattr(&mut self, file: Inode, name: &str) -> VaultResult<Option<Vec<u8>>> {
+        debug!("xattr(file={}, name={})", file, name);
+        self.inject("xattr")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::Xattr {
+            file,
+            name: name.to_string(),
+            value: vec![],
+        };
+        let sent = request.encoded_len();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.xattr(request))?)?
+            .into_inner();
+        self.record(sent, response.encoded_len());
+        Ok(if response.present {
+            Some(response.value)
+        } else {
+            None
+        })
+    }
+
+    fn xattr_names(&mut self, file: Inode) -> VaultResult<Vec<String>> {
+        debug!("xattr_names({})", file);
+        self.inject("xattr_names")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::Inode { value: file };
+        let sent = request.encoded_len();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.xattr_names(request))?)?
+            .into_inner();
+        self.record(sent, response.encoded_len());
+        Ok(response.names)
+    }
+
+    fn set_xattr(&mut self, file: Inode, name: &str, value: &[u8]) -> VaultResult<()> {
+        info!("set_xattr(file={}, name={})", file, name);
+        self.check_writable()?;
+        self.inject_write("set_xattr")?;
         self.get_client()?;
         let client = self.client.as_mut().unwrap();
-        translate_result(self.rt.block_on(client.delete(rpc::Inode { value: file })))?;
-        return Ok(());
+        let request = rpc::Xattr {
+            file,
+            name: name.to_string(),
+            value: value.to_vec(),
+        };
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.set_xattr(request))?)?;
+        self.record(sent, 0);
+        Ok(())
+    }
+
+    fn remove_xattr(&mut self, file: Inode, name: &str) -> VaultResult<()> {
+        info!("remove_xattr(file={}, name={})", file, name);
+        self.check_writable()?;
+        self.inject_write("remove_xattr")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::Xattr {
+            file,
+            name: name.to_string(),
+            value: vec![],
+        };
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.remove_xattr(request))?)?;
+        self.record(sent, 0);
+        Ok(())
+    }
+
+    fn delete_tree(&mut self, dir: Inode, limit: u64) -> VaultResult<u64> {
+        info!("delete_tree(dir={}, limit={})", dir, limit);
+        // We don't know which files are in the tree.
+        if let Some(cache) = &mut self.read_cache {
+            cache.clear();
+        }
+        self.check_writable()?;
+        self.inject("delete_tree")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::TreeToDelete { dir, limit };
+        let sent = request.encoded_len();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.delete_tree(request))?)?
+            .into_inner();
+        self.record(sent, response.encoded_len());
+        Ok(response.value)
+    }
+
+    fn info(&mut self, dir: Inode) -> VaultResult<DirInfo> {
+        debug!("info({})", dir);
+        self.inject("info")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = rpc::Inode { value: dir };
+        let sent = request.encoded_len();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.info(request))?)?
+            .into_inner();
+        self.record(sent, response.encoded_len());
+        Ok(DirInfo {
+            size: response.size,
+            count: response.count,
+        })
+    }
+
+    fn getlk(&mut self, file: Inode, lock: &FileLock) -> VaultResult<Option<FileLock>> {
+        debug!("getlk(file={}, lock={:?})", file, lock);
+        self.inject("getlk")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = locks::pack(file, lock);
+        let sent = request.encoded_len();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.getlk(request))?)?
+            .into_inner();
+        self.record(sent, response.encoded_len());
+        match response.lock {
+            Some(lock) if response.found => Ok(Some(locks::unpack(lock)?.1)),
+            _ => Ok(None),
+        }
+    }
+
+    fn setlk(&mut self, file: Inode, lock: &FileLock) -> VaultResult<()> {
+        info!("setlk(file={}, lock={:?})", file, lock);
+        self.inject("setlk")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let request = locks::pack(file, lock);
+        let sent = request.encoded_len();
+        self.connection
+            .translate(block_on(&self.rt, client.setlk(request))?)?;
+        self.record(sent, 0);
+        Ok(())
+    }
+
+    fn statfs(&mut self) -> VaultResult<FsStats> {
+        debug!("statfs()");
+        self.inject("statfs")?;
+        self.get_client()?;
+        let client = self.client.as_mut().unwrap();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.statfs(rpc::Empty {}))?)?
+            .into_inner();
+        self.record(0, response.encoded_len());
+        Ok(FsStats {
+            total: response.total,
+            free: response.free,
+            available: response.available,
+            files: response.files,
+            free_files: response.free_files,
+        })
     }
 
     fn readdir(&mut self, dir: Inode) -> VaultResult<Vec<FileInfo>> {
         debug!("readdir({})", dir);
+        self.inject("readdir")?;
         self.get_client()?;
         let client = self.client.as_mut().unwrap();
-        let response =
-            translate_result(self.rt.block_on(client.readdir(rpc::Inode { value: dir })))?
-                .into_inner()
-                .list;
-        let result: Vec<FileInfo> = response
-            .iter()
-            .map(|info| FileInfo {
-                inode: info.inode,
-                name: info.name.clone(),
-                kind: num2kind(info.kind),
-                size: info.size,
-                atime: info.atime,
-                mtime: info.mtime,
-                version: (info.major_ver, info.minor_ver),
-            })
-            .collect();
-        return Ok(result);
+        let request = rpc::Inode { value: dir };
+        let sent = request.encoded_len();
+        if self.readdir_columns {
+            match block_on(&self.rt, client.readdir_columns(request.clone()))? {
+                Err(status) if status.code() == tonic::Code::Unimplemented => {
+                    warn!(
+                        "{} doesn't list directories in columns, falling back to entries",
+                        self.addr
+                    );
+                    self.readdir_columns = false;
+                }
+                result => {
+                    let response = self.connection.translate(result)?.into_inner();
+                    self.record(sent, response.encoded_len());
+                    return Ok(dir_columns::decode(response)?
+                        .into_iter()
+                        .map(|info| self.localize(info))
+                        .collect());
+                }
+            }
+        }
+        let client = self.client.as_mut().unwrap();
+        let response = self
+            .connection
+            .translate(block_on(&self.rt, client.readdir(request))?)?
+            .into_inner();
+        self.record(sent, response.encoded_len());
+        Ok(response
+            .list
+            .into_iter()
+            .map(|info| self.localize(unpack_info(info)))
+            .collect())
     }
 }
@@ -0,0 +1,197 @@
+/// Stores of peers removed from the configuration. A caching vault
+/// keeps its database and data files in db_path, and nothing removes
+/// them when the peer goes away. On start, the stores of peers that
+/// left the configuration are marked retired: they stay mounted,
+/// read-only, for a grace period, then they are removed. Only stores
+/// of vaults that were once configured peers are retired
+/// automatically, so a renamed local vault is never removed behind
+/// our back.
+use crate::types::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+/// Name of the file in db_path recording peers and retired vaults.
+pub const RETIRED_FILE: &str = "retired.json";
+
+/// A retired vault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retired {
+    /// When the vault was retired, in seconds since UNIX epoch.
+    pub since: u64,
+    /// If true, the store is kept after the grace period.
+    pub keep: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Retirements {
+    /// Every peer we have seen in the configuration.
+    peers: BTreeSet<VaultName>,
+    pub retired: BTreeMap<VaultName, Retired>,
+}
+
+/// Return the names of the vaults that have a database in `store`.
+pub fn stored_vaults(store: &Path) -> VaultResult<BTreeSet<VaultName>> {
+    let mut names = BTreeSet::new();
+    let db_dir = store.join("db");
+    if !db_dir.exists() {
+        return Ok(names);
+    }
+    for entry in fs::read_dir(db_dir)? {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(name) = file_name.strip_suffix(".sqlite3") {
+            names.insert(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// If `file_name` in the data directory belongs to `vault`, return
+/// its inode and suffix. Data files are named "<vault>-<inode>", with
+/// an optional "-write" or "-partial" suffix. Vault names can contain
+/// "-", so check that the rest is an inode.
+pub fn parse_data_file<'a>(file_name: &'a str, vault: &str) -> Option<(Inode, &'a str)> {
+    let rest = file_name
+        .strip_prefix(vault)
+        .and_then(|rest| rest.strip_prefix('-'))?;
+    let (inode, suffix) = match rest.find('-') {
+        Some(idx) => rest.split_at(idx),
+        None => (rest, ""),
+    };
+    if !matches!(suffix, "" | "-write" | "-partial")
+        || inode.is_empty()
+        || !inode.chars().all(|ch| ch.is_ascii_digit())
+    {
+        return None;
+    }
+    Some((inode.parse().ok()?, suffix))
+}
+
+/// Remove the files in `dir` whose names satisfy `belongs`, return
+/// how many.
+fn remove_matching(dir: &Path, belongs: impl Fn(&str) -> bool) -> VaultResult<u64> {
+    let mut count = 0;
+    if !dir.exists() {
+        return Ok(count);
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if belongs(&entry.file_name().to_string_lossy()) {
+            fs::remove_file(entry.path())?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Remove everything `vault` has in `store`: its database, saved
+/// pending operations, data files and graveyard copies. Return the
+/// number of files removed.
+pub fn remove_store(store: &Path, vault: &str) -> VaultResult<u64> {
+    let db_prefix = format!("{}.sqlite3", vault);
+    let pending = format!("{}-pending.json", vault);
+    let graveyard_prefix = format!("vault({})", vault);
+    let count = remove_matching(&store.join("db"), |name| {
+        name.starts_with(&db_prefix) || name == pending
+    })? + remove_matching(&store.join("data"), |name| {
+        parse_data_file(name, vault).is_some()
+    })? + remove_matching(&store.join("graveyard"), |name| {
+        name.starts_with(&graveyard_prefix)
+    })?;
+    info!("remove_store({}) => {} files", vault, count);
+    Ok(count)
+}
+
+impl Retirements {
+    /// Load the record in `store`, an empty one if there isn't one.
+    pub fn load(store: &Path) -> VaultResult<Retirements> {
+        let path = store.join(RETIRED_FILE);
+        if !path.exists() {
+            return Ok(Retirements::default());
+        }
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content).map_err(std::io::Error::from)?)
+    }
+
+    pub fn save(&self, store: &Path) -> VaultResult<()> {
+        let content = serde_json::to_string_pretty(self).map_err(std::io::Error::from)?;
+        fs::write(store.join(RETIRED_FILE), content)?;
+        Ok(())
+    }
+
+    /// Bring the record up to date with the configured `peers` and
+    /// `local` vault at `now`. Peers we saw before that aren't
+    /// configured anymore and still have a store are retired.
+    /// Configured vaults aren't retired, so adding a peer back cancels
+    /// its retirement.
+    pub fn update(
+        &mut self,
+        store: &Path,
+        peers: &[VaultName],
+        local: &str,
+        now: u64,
+    ) -> VaultResult<()> {
+        let stored = stored_vaults(store)?;
+        self.retired.remove(local);
+        for peer in peers {
+            self.peers.insert(peer.clone());
+            if self.retired.remove(peer).is_some() {
+                info!("{} is a peer again, not retired anymore", peer);
+            }
+        }
+        for peer in self.peers.iter() {
+            let configured = peers.contains(peer) || peer == local;
+            if !configured && stored.contains(peer) && !self.retired.contains_key(peer) {
+                info!("{} isn't a peer anymore, retiring its store", peer);
+                self.retired.insert(
+                    peer.clone(),
+                    Retired {
+                        since: now,
+                        keep: false,
+                    },
+                );
+            }
+        }
+        // Removed by hand.
+        self.retired.retain(|name, _| stored.contains(name));
+        Ok(())
+    }
+
+    /// Return the retired vaults whose `grace_days` are over at
+    /// `now`. A grace of 0 days means forever.
+    pub fn expired(&self, grace_days: u64, now: u64) -> Vec<VaultName> {
+        if grace_days == 0 {
+            return vec![];
+        }
+        self.retired
+            .iter()
+            .filter(|(_, retired)| {
+                !retired.keep && retired.since + grace_days * 24 * 60 * 60 <= now
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Keep the store of retired `vault` after the grace period, or
+    /// stop keeping it.
+    pub fn keep(&mut self, vault: &str, keep: bool) -> VaultResult<()> {
+        match self.retired.get_mut(vault) {
+            Some(retired) => {
+                retired.keep = keep;
+                Ok(())
+            }
+            None => Err(VaultError::CannotFindVaultByName(vault.to_string())),
+        }
+    }
+
+    /// Remove the store of `vault` from `store` now and forget it.
+    /// Return the number of files removed.
+    pub fn purge(&mut self, store: &Path, vault: &str) -> VaultResult<u64> {
+        let count = remove_store(store, vault)?;
+        self.retired.remove(vault);
+        self.peers.remove(vault);
+        Ok(count)
+    }
+}
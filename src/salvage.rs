@@ -0,0 +1,348 @@
+/// Recover what we can of a damaged store, for the "salvage" command.
+/// Unlike opening the vault, nothing in db_path is written: the
+/// database is opened read-only and as is, without replaying the
+/// journal or upgrading its tables, and data files are only read. We
+/// go around what is damaged rather than stop at it: a row that can't
+/// be read, a data file that is gone or unreadable, is skipped and
+/// reported, and the rest is copied to a plain directory.
+use crate::export::{safe_name, to_file_times};
+use crate::types::*;
+use log::{info, warn};
+use rusqlite::{Connection, OpenFlags};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+const ROOT: Inode = 1;
+
+/// The directory in the destination for files we know of but can't
+/// place in the tree, eg, because the row of their directory is
+/// damaged. They are named by their inode.
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// What a salvage recovered, and what it couldn't.
+#[derive(Debug, Default, Clone)]
+pub struct SalvageStats {
+    /// Number of regular files copied.
+    pub files: u64,
+    /// Number of directories created.
+    pub directories: u64,
+    /// Number of bytes copied.
+    pub bytes: u64,
+    /// Files copied to LOST_AND_FOUND.
+    pub lost: u64,
+    /// What we skipped and why, one line each.
+    pub problems: Vec<String>,
+}
+
+/// A file as far as the database tells.
+#[derive(Debug, Clone)]
+struct Salvaged {
+    name: String,
+    is_dir: bool,
+    mtime: u64,
+    atime: u64,
+    mode: u32,
+    /// False for files a cache listed but never downloaded (major
+    /// version 0), their data file is empty rather than their content.
+    downloaded: bool,
+}
+
+/// Copy the files of vault `vault` stored in `store` (db_path) to
+/// `dest`, preserving structure, permission bits and timestamps, and
+/// skipping what is damaged. `dest` is created if it doesn't exist.
+pub fn salvage(store: &Path, vault: &str, dest: &Path) -> VaultResult<SalvageStats> {
+    info!(
+        "salvage(store={:?}, vault={}, dest={:?})",
+        store, vault, dest
+    );
+    let mut stats = SalvageStats::default();
+    let db_file = store.join("db").join(format!("{}.sqlite3", vault));
+    if !db_file.exists() {
+        return Err(VaultError::CannotFindVaultByName(vault.to_string()));
+    }
+    // immutable: don't lock, don't touch the journal, and don't fail
+    // on a store mounted read-only. Changes cut short by a crash are
+    // then seen half done rather than rolled back.
+    let connection = Connection::open_with_flags(
+        format!("file:{}?immutable=1", db_file.to_string_lossy()),
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+    )?;
+    let files = read_files(&connection, &mut stats);
+    let mut children: HashMap<Inode, Vec<(Inode, String)>> = HashMap::new();
+    for (parent, child) in read_has_child(&connection, &mut stats) {
+        if let Some(file) = files.get(&child) {
+            children
+                .entry(parent)
+                .or_default()
+                .push((child, file.name.clone()));
+        }
+    }
+    // Hard links, the table is missing in old databases.
+    let mut links = vec![];
+    if let Ok(mut statement) = connection.prepare("select parent, name, file from Link") {
+        match statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))) {
+            Ok(rows) => {
+                for row in rows {
+                    match row {
+                        Ok(link) => links.push(link),
+                        Err(err) => {
+                            stats.problems.push(format!("Links: {}", err));
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(err) => stats.problems.push(format!("Links: {}", err)),
+        }
+    }
+    for (parent, name, file) in links {
+        children.entry(parent).or_default().push((file, name));
+    }
+
+    let data_dir = store.join("data");
+    let mut placed = HashSet::new();
+    let root = files.get(&ROOT).cloned().unwrap_or(Salvaged {
+        name: String::new(),
+        is_dir: true,
+        mtime: 0,
+        atime: 0,
+        mode: default_mode(VaultFileType::Directory),
+        downloaded: true,
+    });
+    let mut copier = Copier {
+        vault,
+        data_dir: &data_dir,
+        files: &files,
+        children: &children,
+        stats: &mut stats,
+    };
+    copier.dir(ROOT, &root, dest, "/", &mut placed);
+
+    // Files that are in no directory we could walk.
+    let lost_dir = dest.join(LOST_AND_FOUND);
+    let mut lost: Vec<&Inode> = files
+        .keys()
+        .filter(|file| !placed.contains(*file))
+        .collect();
+    lost.sort();
+    for &file in lost {
+        if placed.contains(&file) {
+            continue;
+        }
+        if !lost_dir.exists() {
+            fs::create_dir(&lost_dir)?;
+        }
+        let info = &files[&file];
+        let path = lost_dir.join(file.to_string());
+        let label = format!("/{}/{}", LOST_AND_FOUND, file);
+        copier.stats.lost += 1;
+        if info.is_dir {
+            copier.dir(file, info, &path, &label, &mut placed);
+        } else {
+            placed.insert(file);
+            copier.file(file, info, &path, &label);
+        }
+    }
+    Ok(stats)
+}
+
+/// Return what the Type table has, up to the first row that can't be
+/// read.
+fn read_files(connection: &Connection, stats: &mut SalvageStats) -> HashMap<Inode, Salvaged> {
+    let mut files = HashMap::new();
+    let mut statement = match connection
+        .prepare("select file, name, type, atime, mtime, mode, major_version from Type")
+    {
+        Ok(statement) => statement,
+        Err(err) => {
+            stats.problems.push(format!("Files: {}", err));
+            return files;
+        }
+    };
+    let rows = statement.query_map([], |row| {
+        Ok((
+            row.get::<_, Inode>(0)?,
+            Salvaged {
+                name: row.get(1)?,
+                is_dir: row.get::<_, i32>(2)? != 0,
+                atime: row.get::<_, Option<u64>>(3)?.unwrap_or(0),
+                mtime: row.get::<_, Option<u64>>(4)?.unwrap_or(0),
+                mode: row.get::<_, Option<u32>>(5)?.unwrap_or(0),
+                downloaded: row.get::<_, Option<u64>>(6)?.unwrap_or(0) != 0,
+            },
+        ))
+    });
+    match rows {
+        Ok(rows) => {
+            for row in rows {
+                match row {
+                    Ok((file, mut info)) => {
+                        if info.mode == 0 {
+                            info.mode = default_mode(if info.is_dir {
+                                VaultFileType::Directory
+                            } else {
+                                VaultFileType::File
+                            });
+                        }
+                        files.insert(file, info);
+                    }
+                    // A damaged page ends the scan, keep what we read
+                    // so far.
+                    Err(err) => {
+                        stats.problems.push(format!("Files: {}", err));
+                        break;
+                    }
+                }
+            }
+        }
+        Err(err) => stats.problems.push(format!("Files: {}", err)),
+    }
+    files
+}
+
+/// Return the (parent, child) pairs the HasChild table has, up to the
+/// first row that can't be read.
+fn read_has_child(connection: &Connection, stats: &mut SalvageStats) -> Vec<(Inode, Inode)> {
+    let mut pairs = vec![];
+    let mut statement = match connection.prepare("select parent, child from HasChild") {
+        Ok(statement) => statement,
+        Err(err) => {
+            stats.problems.push(format!("Directories: {}", err));
+            return pairs;
+        }
+    };
+    match statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?))) {
+        Ok(rows) => {
+            for row in rows {
+                match row {
+                    Ok(pair) => pairs.push(pair),
+                    Err(err) => {
+                        stats.problems.push(format!("Directories: {}", err));
+                        break;
+                    }
+                }
+            }
+        }
+        Err(err) => stats.problems.push(format!("Directories: {}", err)),
+    }
+    pairs
+}
+
+struct Copier<'a> {
+    vault: &'a str,
+    data_dir: &'a Path,
+    files: &'a HashMap<Inode, Salvaged>,
+    children: &'a HashMap<Inode, Vec<(Inode, String)>>,
+    stats: &'a mut SalvageStats,
+}
+
+impl<'a> Copier<'a> {
+    /// Create `dest` for directory `dir` and copy what is under it.
+    /// `label` is its path in the vault, for problems. `placed` has
+    /// the files already copied, so a damaged tree with cycles
+    /// doesn't go on forever.
+    fn dir(
+        &mut self,
+        dir: Inode,
+        info: &Salvaged,
+        dest: &Path,
+        label: &str,
+        placed: &mut HashSet<Inode>,
+    ) {
+        if !placed.insert(dir) {
+            return;
+        }
+        if !dest.exists() {
+            if let Err(err) = fs::create_dir(dest) {
+                self.stats
+                    .problems
+                    .push(format!("{}: cannot create {:?}: {}", label, dest, err));
+                return;
+            }
+        }
+        self.stats.directories += 1;
+        let mut entries = self.children.get(&dir).cloned().unwrap_or_default();
+        entries.sort();
+        for (child, name) in entries {
+            let info = match self.files.get(&child) {
+                Some(info) => info,
+                None => continue,
+            };
+            let child_label = format!("{}{}{}", label, if label == "/" { "" } else { "/" }, name);
+            if !safe_name(&name) {
+                self.stats
+                    .problems
+                    .push(format!("{}: not a valid file name here", child_label));
+                continue;
+            }
+            let path = dest.join(&name);
+            if info.is_dir {
+                self.dir(child, info, &path, &child_label, placed);
+            } else {
+                // Hard links are copied once per name.
+                placed.insert(child);
+                self.file(child, info, &path, &child_label);
+            }
+        }
+        // Set times last, writing children changes the directory's
+        // mtime.
+        let _ = fs::set_permissions(dest, fs::Permissions::from_mode(info.mode));
+        if let Ok(fd) = File::open(dest) {
+            let _ = fd.set_times(to_file_times(info.atime, info.mtime));
+        }
+    }
+
+    /// Copy the data file of `file` to `dest`. If it is gone, copy the
+    /// write copy left by a crash instead, if any. Files a cache never
+    /// downloaded are reported rather than copied empty.
+    fn file(&mut self, file: Inode, info: &Salvaged, dest: &Path, label: &str) {
+        if !info.downloaded {
+            self.stats
+                .problems
+                .push(format!("{}: not downloaded", label));
+            return;
+        }
+        let data_file = self.data_file(file, "");
+        let source = if data_file.exists() {
+            data_file
+        } else {
+            let write_copy = self.data_file(file, "-write");
+            if !write_copy.exists() {
+                self.stats
+                    .problems
+                    .push(format!("{}: no data file {:?}", label, data_file));
+                return;
+            }
+            warn!("salvage: {} has only a write copy, copying it", label);
+            write_copy
+        };
+        match fs::copy(&source, dest) {
+            Ok(bytes) => {
+                self.stats.files += 1;
+                self.stats.bytes += bytes;
+            }
+            Err(err) => {
+                self.stats
+                    .problems
+                    .push(format!("{}: cannot copy {:?}: {}", label, source, err));
+                // Don't leave what was copied before the error as if
+                // it were the file.
+                let _ = fs::remove_file(dest);
+                return;
+            }
+        }
+        // Set times first, the mode may not let us open the file for
+        // writing.
+        if let Ok(fd) = File::options().write(true).open(dest) {
+            let _ = fd.set_times(to_file_times(info.atime, info.mtime));
+        }
+        let _ = fs::set_permissions(dest, fs::Permissions::from_mode(info.mode));
+    }
+
+    fn data_file(&self, file: Inode, suffix: &str) -> PathBuf {
+        self.data_dir
+            .join(format!("{}-{}{}", self.vault, file, suffix))
+    }
+}
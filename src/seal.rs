@@ -0,0 +1,58 @@
+/// Sealing a vault: making it read-only for good, eg, an archive that
+/// must not change. The owner seals its local vault with the "seal"
+/// command, which proves it can read the share key of the store, see
+/// `ShareKey::prove`. The time is recorded in its database, and from
+/// then on the vault refuses every change, from this host and from
+/// peers, with VaultError::ReadOnly. Unlike `read_only` in the
+/// configuration there is no going back. Peers learn it when they connect, see
+/// `Identity.sealed` in rpc.proto, and their remote and caching
+/// vaults refuse changes too, rather than collect changes they could
+/// never upload. Sealed vaults report no free space in statfs, and
+/// their roots have the time they were sealed in XATTR_SEALED.
+use crate::types::FsStats;
+use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+use std::sync::Arc;
+
+/// What the proof of a seal request is for, see `ShareKey::prove`.
+pub const SEAL_COMMAND: &str = "seal";
+
+/// Whether a peer's vault is sealed, as far as we know. Cloning gives
+/// a handle to the same state, so the remote vault, which learns it
+/// from the peer, and the caching vault in front of it agree.
+#[derive(Debug, Clone, Default)]
+pub struct SealState {
+    /// When the vault was sealed, in seconds since the epoch, 0 if it
+    /// isn't.
+    time: Arc<AtomicU64>,
+}
+
+impl SealState {
+    pub fn new() -> SealState {
+        SealState::default()
+    }
+
+    /// Return when the vault was sealed, None if it isn't.
+    pub fn sealed(&self) -> Option<u64> {
+        match self.time.load(SeqCst) {
+            0 => None,
+            time => Some(time),
+        }
+    }
+
+    /// Record that the vault was sealed at `time`. A vault stays
+    /// sealed, the first time recorded is kept.
+    pub fn seal(&self, time: u64) {
+        let _ = self.time.compare_exchange(0, time.max(1), SeqCst, SeqCst);
+    }
+}
+
+/// Return `stats` of a sealed vault: nothing can be added to it, so
+/// no space or files are free.
+pub fn sealed_stats(stats: FsStats) -> FsStats {
+    FsStats {
+        free: 0,
+        available: 0,
+        free_files: 0,
+        ..stats
+    }
+}
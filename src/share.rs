@@ -0,0 +1,303 @@
+/// Share links: tokens that let someone who isn't a configured peer
+/// read one file, or everything under one directory, of our local
+/// vault until the token expires. A token is its claim (JSON, hex
+/// encoded), a dot, and an HMAC-SHA256 of the claim with a key only
+/// this host knows, so tokens can't be forged or extended. Replacing
+/// the key revokes every token minted with it.
+use crate::locks::FileLock;
+use crate::remote_vault::RemoteVault;
+use crate::types::*;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time;
+
+/// Name of the key file in db_path.
+pub const SHARE_KEY_FILE: &str = "share.key";
+
+const KEY_LEN: usize = 32;
+
+/// What a token grants.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareClaim {
+    /// The vault `file` is in, so the receiver knows whom to ask.
+    pub vault: VaultName,
+    pub file: Inode,
+    /// If true, grant everything under `file` too.
+    pub subtree: bool,
+    /// Seconds since UNIX epoch.
+    pub expires: u64,
+}
+
+#[derive(Clone)]
+pub struct ShareKey {
+    key: Vec<u8>,
+}
+
+fn now() -> VaultResult<u64> {
+    Ok(time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)?
+        .as_secs())
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&text[idx..idx + 2], 16).ok())
+        .collect()
+}
+
+fn invalid_token() -> VaultError {
+    VaultError::InvalidArgument("invalid share token".to_string())
+}
+
+/// Return the claim of `token` without checking its signature, so
+/// the receiver knows where to redeem it. Only the host that minted
+/// it can tell whether it is genuine.
+pub fn peek(token: &str) -> VaultResult<ShareClaim> {
+    let (claim, _) = token.split_once('.').ok_or_else(invalid_token)?;
+    let claim = decode_hex(claim).ok_or_else(invalid_token)?;
+    serde_json::from_slice(&claim).map_err(|_| invalid_token())
+}
+
+impl ShareKey {
+    pub fn new(key: &[u8]) -> ShareKey {
+        ShareKey { key: key.to_vec() }
+    }
+
+    /// Load the key in `path`, or create a random one there if it
+    /// doesn't exist.
+    pub fn load_or_create(path: &Path) -> VaultResult<ShareKey> {
+        if path.exists() {
+            return Ok(ShareKey::new(&fs::read(path)?));
+        }
+        let mut key = vec![0; KEY_LEN];
+        fs::File::open("/dev/urandom")?.read_exact(&mut key)?;
+        // Anyone who can read the key can mint tokens.
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(&key)?;
+        Ok(ShareKey::new(&key))
+    }
+
+    fn sign(&self, claim: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(claim);
+        mac
+    }
+
+    /// Return a token for `claim`.
+    pub fn mint(&self, claim: &ShareClaim) -> String {
+        let claim = serde_json::to_vec(claim).unwrap();
+        let tag = self.sign(&claim).finalize().into_bytes();
+        format!("{}.{}", encode_hex(&claim), encode_hex(&tag))
+    }
+
+    /// Return the claim of `token` if we minted it and it hasn't
+    /// expired.
+    pub fn verify(&self, token: &str) -> VaultResult<ShareClaim> {
+        let (claim_hex, tag) = token.split_once('.').ok_or_else(invalid_token)?;
+        let claim = decode_hex(claim_hex).ok_or_else(invalid_token)?;
+        let tag = decode_hex(tag).ok_or_else(invalid_token)?;
+        self.sign(&claim)
+            .verify_slice(&tag)
+            .map_err(|_| invalid_token())?;
+        let claim: ShareClaim = serde_json::from_slice(&claim).map_err(|_| invalid_token())?;
+        if claim.expires <= now()? {
+            return Err(VaultError::InvalidArgument(
+                "share token expired".to_string(),
+            ));
+        }
+        Ok(claim)
+    }
+
+    /// Return a proof that we can read the key, for `command`. The
+    /// key is only readable by the owner of the store, so admin
+    /// commands that can't be undone, like "seal", ask for one on
+    /// top of coming from the host itself.
+    pub fn prove(&self, command: &str) -> String {
+        encode_hex(&self.sign(command.as_bytes()).finalize().into_bytes())
+    }
+
+    /// Return true if `proof` is our proof for `command`, see
+    /// `prove`.
+    pub fn check_proof(&self, command: &str, proof: &str) -> bool {
+        match decode_hex(proof) {
+            Some(tag) => self.sign(command.as_bytes()).verify_slice(&tag).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Return a token for `file` in `vault` (and everything under it
+    /// if `subtree`) that expires in `ttl` seconds.
+    pub fn mint_for(
+        &self,
+        vault: &str,
+        file: Inode,
+        subtree: bool,
+        ttl: u64,
+    ) -> VaultResult<String> {
+        let expires = now()?
+            .checked_add(ttl)
+            .ok_or_else(|| VaultError::InvalidArgument(format!("ttl {} is too long", ttl)))?;
+        Ok(self.mint(&ShareClaim {
+            vault: vault.to_string(),
+            file,
+            subtree,
+            expires,
+        }))
+    }
+}
+
+/// The part of a remote vault a share link grants, seen as a
+/// read-only vault, so export can download it.
+pub struct SharedVault {
+    remote: RemoteVault,
+    token: String,
+}
+
+impl SharedVault {
+    /// `remote` should be the vault the token's claim names.
+    pub fn new(remote: RemoteVault, token: &str) -> SharedVault {
+        SharedVault {
+            remote,
+            token: token.to_string(),
+        }
+    }
+}
+
+fn read_only() -> VaultError {
+    VaultError::InvalidArgument("share links are read-only".to_string())
+}
+
+impl Vault for SharedVault {
+    fn name(&self) -> String {
+        self.remote.name()
+    }
+
+    fn attr(&mut self, file: Inode) -> VaultResult<FileInfo> {
+        self.remote.attr_shared(&self.token, file)
+    }
+
+    fn read(&mut self, file: Inode, offset: i64, size: u64) -> VaultResult<Vec<u8>> {
+        self.remote.read_shared(&self.token, file, offset, size)
+    }
+
+    fn write(&mut self, _file: Inode, _offset: i64, _data: &[u8]) -> VaultResult<u64> {
+        Err(read_only())
+    }
+
+    fn append(&mut self, _file: Inode, _data: &[u8]) -> VaultResult<u64> {
+        Err(read_only())
+    }
+
+    fn truncate(&mut self, _file: Inode, _size: u64) -> VaultResult<()> {
+        Err(read_only())
+    }
+
+    fn create(&mut self, _parent: Inode, _name: &str, _kind: VaultFileType) -> VaultResult<Inode> {
+        Err(read_only())
+    }
+
+    // The server opens and closes the file around each shared read.
+    fn open(&mut self, _file: Inode, _mode: OpenMode) -> VaultResult<()> {
+        Ok(())
+    }
+
+    fn close(&mut self, _file: Inode) -> VaultResult<()> {
+        Ok(())
+    }
+
+    fn set_mode(&mut self, _file: Inode, _mode: u32) -> VaultResult<()> {
+        Err(read_only())
+    }
+
+    fn set_owner(&mut self, _file: Inode, _uid: u32, _gid: u32) -> VaultResult<()> {
+        Err(read_only())
+    }
+
+    fn set_times(
+        &mut self,
+        _file: Inode,
+        _atime: Option<u64>,
+        _mtime: Option<u64>,
+    ) -> VaultResult<()> {
+        Err(read_only())
+    }
+
+    fn delete(&mut self, _file: Inode) -> VaultResult<()> {
+        Err(read_only())
+    }
+
+    fn delete_tree(&mut self, _dir: Inode, _limit: u64) -> VaultResult<u64> {
+        Err(read_only())
+    }
+
+    fn rename(&mut self, _file: Inode, _parent: Inode, _name: &str) -> VaultResult<()> {
+        Err(read_only())
+    }
+
+    fn link(&mut self, _file: Inode, _parent: Inode, _name: &str) -> VaultResult<()> {
+        Err(read_only())
+    }
+
+    fn unlink(&mut self, _file: Inode, _parent: Inode, _name: &str) -> VaultResult<()> {
+        Err(read_only())
+    }
+
+    // Share links don't carry extended attributes.
+    fn xattr(&mut self, _file: Inode, _name: &str) -> VaultResult<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn xattr_names(&mut self, _file: Inode) -> VaultResult<Vec<String>> {
+        Ok(vec![])
+    }
+
+    fn set_xattr(&mut self, _file: Inode, _name: &str, _value: &[u8]) -> VaultResult<()> {
+        Err(read_only())
+    }
+
+    fn remove_xattr(&mut self, _file: Inode, _name: &str) -> VaultResult<()> {
+        Err(read_only())
+    }
+
+    fn info(&mut self, _dir: Inode) -> VaultResult<DirInfo> {
+        Err(VaultError::InvalidArgument(
+            "share links don't report usage".to_string(),
+        ))
+    }
+
+    // Share links can't be written, there's nothing to lock against.
+    fn getlk(&mut self, _file: Inode, _lock: &FileLock) -> VaultResult<Option<FileLock>> {
+        Ok(None)
+    }
+
+    fn setlk(&mut self, _file: Inode, _lock: &FileLock) -> VaultResult<()> {
+        Ok(())
+    }
+
+    fn statfs(&mut self) -> VaultResult<FsStats> {
+        Err(VaultError::InvalidArgument(
+            "share links don't report usage".to_string(),
+        ))
+    }
+
+    fn readdir(&mut self, dir: Inode) -> VaultResult<Vec<FileInfo>> {
+        self.remote.readdir_shared(&self.token, dir)
+    }
+}
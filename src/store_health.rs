@@ -0,0 +1,136 @@
+/// Notice when db_path stops taking writes: its disk is full, it is
+/// remounted read-only, or its drive is pulled. Vaults kept in the
+/// store then refuse changes with VaultError::StoreUnwritable and go
+/// on serving what they have, rather than fail each change halfway
+/// through. Peers get it as a transient failure and retry their
+/// uploads later. Now and then we probe the store, and take changes again
+/// once it takes writes.
+use crate::types::*;
+use log::{error, info};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+/// How long after a failed write or probe we probe the store again.
+const PROBE_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+/// The file we write to probe the store, in db_path.
+const PROBE_FILE: &str = "probe";
+
+/// Whether a store takes writes. Cloning gives a handle to the same
+/// state, so vaults sharing a store degrade together.
+#[derive(Debug, Clone)]
+pub struct StoreHealth {
+    store: PathBuf,
+    /// Set while the store doesn't take writes.
+    broken: Arc<Mutex<Option<Broken>>>,
+}
+
+#[derive(Debug)]
+struct Broken {
+    /// The error of the last failed write or probe.
+    reason: String,
+    /// When we last wrote or probed.
+    checked: time::Instant,
+}
+
+/// Return true if `err` means the store doesn't take writes.
+pub fn unwritable_io(err: &std::io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EROFS) | Some(libc::ENOSPC) | Some(libc::EDQUOT)
+    )
+}
+
+/// Return true if `err` means the store doesn't take writes.
+pub fn unwritable(err: &VaultError) -> bool {
+    match err {
+        VaultError::IOError(err) => unwritable_io(err),
+        VaultError::SqliteError(rusqlite::Error::SqliteFailure(failure, _)) => matches!(
+            failure.code,
+            rusqlite::ErrorCode::ReadOnly
+                | rusqlite::ErrorCode::DiskFull
+                | rusqlite::ErrorCode::SystemIoFailure
+        ),
+        _ => false,
+    }
+}
+
+impl StoreHealth {
+    /// Return the health of the store in `store`, assumed to take
+    /// writes.
+    pub fn new(store: &Path) -> StoreHealth {
+        StoreHealth {
+            store: store.to_path_buf(),
+            broken: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Return `result`, of a change to the store. If it failed
+    /// because the store doesn't take writes, refuse changes from now
+    /// on, see `check`.
+    pub fn note<T>(&self, result: VaultResult<T>) -> VaultResult<T> {
+        if let Err(err) = &result {
+            if unwritable(err) {
+                let mut broken = self.broken.lock().unwrap();
+                if broken.is_none() {
+                    error!(
+                        "{:?} doesn't take writes ({:?}), refusing changes until it does",
+                        self.store, err
+                    );
+                }
+                *broken = Some(Broken {
+                    reason: format!("{:?}", err),
+                    checked: time::Instant::now(),
+                });
+            }
+        }
+        result
+    }
+
+    /// Fail with VaultError::StoreUnwritable for `vault` if the store doesn't
+    /// take writes. Probe the store first if we haven't in a while.
+    pub fn check(&self, vault: &str) -> VaultResult<()> {
+        let mut broken = self.broken.lock().unwrap();
+        let state = match &mut *broken {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        if state.checked.elapsed() < PROBE_INTERVAL {
+            return Err(VaultError::StoreUnwritable(vault.to_string()));
+        }
+        match probe(&self.store) {
+            Ok(()) => {
+                info!("{:?} takes writes again", self.store);
+                *broken = None;
+                Ok(())
+            }
+            Err(err) => {
+                state.reason = format!("{:?}", err);
+                state.checked = time::Instant::now();
+                Err(VaultError::StoreUnwritable(vault.to_string()))
+            }
+        }
+    }
+
+    /// Return why the store doesn't take writes, None if it does, as
+    /// far as we know.
+    pub fn broken(&self) -> Option<String> {
+        self.broken
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|state| state.reason.clone())
+    }
+}
+
+/// Write, sync and remove a file in `store`.
+fn probe(store: &Path) -> std::io::Result<()> {
+    let path = store.join(PROBE_FILE);
+    let mut file = File::create(&path)?;
+    file.write_all(PROBE_FILE.as_bytes())?;
+    file.sync_all()?;
+    fs::remove_file(&path)
+}
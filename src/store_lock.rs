@@ -0,0 +1,111 @@
+/// The lock on a store (db_path). Two instances using the same store
+/// would each think they own the data files and corrupt them, so each
+/// instance holds an exclusive flock(2) on a lock file in the store
+/// while it runs, and writes its pid in it. The kernel drops the lock
+/// when the process dies, so a crash doesn't leave the store locked.
+/// The lock can still outlive its owner if a process the owner
+/// started inherited it; after checking the recorded owner is gone,
+/// `takeover` replaces the lock file.
+use crate::types::*;
+use log::{info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Name of the lock file in the store.
+pub const LOCK_FILE: &str = "monovault.lock";
+
+/// Held while we use the store, dropping it releases the lock.
+#[derive(Debug)]
+pub struct StoreLock {
+    file: File,
+    path: PathBuf,
+}
+
+/// Return true if a process with `pid` exists.
+fn alive(pid: u32) -> bool {
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    // EPERM means it exists but isn't ours.
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Return the pid recorded in the lock file `file`, if any.
+fn owner(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+/// Try to lock `file`, return false if someone else holds it.
+fn try_lock(file: &File) -> VaultResult<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(err.into())
+    }
+}
+
+fn open(path: &Path) -> VaultResult<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?)
+}
+
+impl StoreLock {
+    /// Lock the store at `store`. If it is locked by a process that
+    /// is gone and `takeover` is true, replace its lock with ours.
+    /// Fail with StoreLocked otherwise.
+    pub fn acquire(store: &Path, takeover: bool) -> VaultResult<StoreLock> {
+        let path = store.join(LOCK_FILE);
+        let mut file = open(&path)?;
+        if !try_lock(&file)? {
+            let pid = owner(&mut file);
+            let owner_alive = pid.map(alive).unwrap_or(true);
+            if owner_alive || !takeover {
+                return Err(VaultError::StoreLocked(pid, owner_alive));
+            }
+            warn!(
+                "Taking over the lock on {} from pid {}, which is gone",
+                store.display(),
+                pid.unwrap()
+            );
+            // Whoever holds the old lock file keeps a lock on a file
+            // nobody else opens.
+            fs::remove_file(&path)?;
+            file = open(&path)?;
+            if !try_lock(&file)? {
+                let pid = owner(&mut file);
+                return Err(VaultError::StoreLocked(pid, true));
+            }
+        } else if let Some(pid) = owner(&mut file) {
+            info!(
+                "The last instance using the store (pid {}) didn't exit cleanly",
+                pid
+            );
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        Ok(StoreLock { file, path })
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        // An empty lock file tells the next instance we exited
+        // cleanly. The lock itself goes with the file descriptor.
+        if let Err(err) = self.file.set_len(0) {
+            warn!("Cannot clear {}: {}", self.path.display(), err);
+        }
+    }
+}
@@ -1,10 +1,17 @@
+use crate::bans::BanConfig;
+use crate::cache_policy::CacheRule;
 use crate::caching_remote::CachingVault;
+use crate::faults::FaultConfig;
 use crate::local_vault::LocalVault;
+use crate::locks::FileLock;
 use crate::remote_vault::RemoteVault;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time;
+use unicode_normalization::UnicodeNormalization;
 
 pub type VaultName = String;
 pub type VaultAddress = String;
@@ -13,18 +20,96 @@ pub type VaultRef = Arc<Mutex<GenericVault>>;
 pub type VaultResult<T> = std::result::Result<T, VaultError>;
 pub type FileVersion = (u64, u64);
 
-/// 100 network MB. Packets are split into packets on wire, this chunk
-/// size limit is just for saving memory. (Once we implement chunked
-/// read & write.)
-pub const GRPC_DATA_CHUNK_SIZE: usize = 1000000 * 100;
+/// 1 MiB. File content is streamed in messages of at most this size.
+/// Tonic refuses messages larger than 4 MiB by default, so this must
+/// stay below that.
+pub const GRPC_DATA_CHUNK_SIZE: usize = 1 << 20;
+
+/// 64 MiB. The most bytes a vault server reads for one request: it
+/// holds them in memory while streaming them, a peer mustn't make it
+/// hold gigabytes. Clients ask for at most this much in one RPC and
+/// take a shorter answer as the end of the file.
+pub const MAX_READ_SIZE: u64 = 64 << 20;
+
+/// `delete_tree` refuses to delete trees with more entries than this,
+/// so a typo can't wipe out a whole vault in one request.
+pub const MAX_TREE_DELETE: u64 = 100000;
+
+fn default_max_io() -> u32 {
+    GRPC_DATA_CHUNK_SIZE as u32
+}
+
+fn default_remount_attempts() -> u32 {
+    5
+}
+
+fn default_retired_grace_days() -> u64 {
+    30
+}
+
+/// Clamp a configured `max_read` or `max_write` to between a page and
+/// GRPC_DATA_CHUNK_SIZE.
+pub fn clamp_io_size(size: u32) -> u32 {
+    size.clamp(4096, GRPC_DATA_CHUNK_SIZE as u32)
+}
+
+/// Check that `len` bytes at `offset` is a valid range in a file,
+/// and return the offset as unsigned. Offsets are never negative
+/// (FUSE doesn't send them, and we don't seek from the end), and the
+/// range must not go past the largest file offset.
+pub fn check_range(offset: i64, len: u64) -> VaultResult<u64> {
+    if offset < 0 {
+        return Err(VaultError::InvalidArgument(format!(
+            "negative offset {}",
+            offset
+        )));
+    }
+    match (offset as u64).checked_add(len) {
+        Some(end) if end <= i64::MAX as u64 => Ok(offset as u64),
+        _ => Err(VaultError::InvalidArgument(format!(
+            "range out of bounds: offset {}, length {}",
+            offset, len
+        ))),
+    }
+}
+
+/// Return `path` made absolute with symlinks, "." and ".." resolved.
+/// If `path` doesn't exist yet, resolve its parent instead.
+pub fn resolve_path(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            resolve_path(parent).join(name)
+        }
+        (Some(parent), None) if path.ends_with("..") => {
+            let parent = resolve_path(parent);
+            parent.parent().map(Path::to_path_buf).unwrap_or(parent)
+        }
+        _ => std::env::current_dir().unwrap().join(path),
+    }
+}
+
+/// Permission bits we give to new files and directories, and to
+/// entries that predate permission tracking.
+pub fn default_mode(kind: VaultFileType) -> u32 {
+    match kind {
+        VaultFileType::File => 0o666,
+        VaultFileType::Directory => 0o777,
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Config {
+    /// The version of the schema the file was written for, see
+    /// config.rs.
+    #[serde(default)]
+    pub config_version: u32,
     /// The address our vault server listens on.
     pub my_address: VaultAddress,
-    /// A map of peer name to addresses. Addresses should include
-    /// address scheme (http://).
-    pub peers: HashMap<VaultName, VaultAddress>,
+    /// A map of peer name to how to reach and use the peer.
+    pub peers: HashMap<VaultName, PeerConfig>,
     /// Mount point of the file system.
     pub mount_point: String,
     /// Path to the directory that stores the database.
@@ -36,21 +121,417 @@ pub struct Config {
     /// If false, don't run a vault server that shares the local vault
     /// with peers.
     pub share_local_vault: bool,
+    /// If true, only run the vault server sharing the local vault: no
+    /// mount, no peers, eg, on a NAS that shares but doesn't browse.
+    #[serde(default)]
+    pub server_only: bool,
+    /// If true, mount the remote vaults read-only, cached, and never
+    /// run the vault server or upload anything, eg, on a kiosk.
+    #[serde(default)]
+    pub mount_only: bool,
+    /// If true, mount every vault read-only, the local vault
+    /// included. Unlike `mount_only`, peers still change the local
+    /// vault through the vault server, and caches still upload what
+    /// was left pending.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Mount options as mount(8) spells them, like "allow_other",
+    /// "noexec" or "uid=1000", added to ours. An option replaces ours
+    /// of the same name, its opposite, or the same value, eg,
+    /// "allow_other" replaces "allow_root".
+    #[serde(default)]
+    pub mount_options: Vec<String>,
     /// Whether allow disconnected delete.
     pub allow_disconnected_delete: bool,
     /// Whether to allow disconnected create.
     pub allow_disconnected_create: bool,
+    /// If true, the background workers start in dry-run mode: they
+    /// collect operations but don't send them to remotes until
+    /// resumed with the "pending --resume" command.
+    #[serde(default)]
+    pub background_dry_run: bool,
+    /// Maps vault name to a map of event name (eg, "synced") to a
+    /// shell command, which we run on that event of that vault with
+    /// the event as JSON on its stdin.
+    #[serde(default)]
+    pub hooks: HashMap<VaultName, HashMap<String, String>>,
+    /// Volume name shown by the OS, default to the name of the mount
+    /// point.
+    #[serde(default)]
+    pub volume_name: Option<String>,
+    /// macOS only: path to an .icns file used as the volume icon.
+    #[serde(default)]
+    pub volume_icon: Option<String>,
+    /// If true, the kernel checks permission bits and owners before
+    /// letting anyone access a file, like on a local file system.
+    /// Otherwise anyone who can reach the mount point can access
+    /// everything in it.
+    #[serde(default)]
+    pub enforce_permissions: bool,
+    /// macOS only: mark the volume as a local disk rather than a
+    /// network one, Finder and Spotlight treat local volumes better.
+    #[serde(default)]
+    pub local_volume: bool,
+    /// If set, serve vault events on a Unix socket at this path.
+    #[serde(default)]
+    pub event_socket: Option<String>,
+    /// Evict cached content of remote files not accessed in this many
+    /// days. 0 means never evict.
+    #[serde(default)]
+    pub cache_max_age_days: u64,
+    /// If true, a cache that is new fetches the whole tree of its
+    /// remote at start, see `caching_remote::bootstrap`.
+    #[serde(default)]
+    pub bootstrap_cache: bool,
+    /// Overrides `cache_max_age_days` for specific vaults.
+    #[serde(default)]
+    pub vault_cache_max_age_days: HashMap<VaultName, u64>,
+    /// With caching enabled, files matching these rules (first
+    /// match wins) aren't cached whole, see `CachePolicy`.
+    #[serde(default)]
+    pub cache_policies: Vec<CacheRule>,
+    /// What to do when a file's metadata exists but its data file
+    /// doesn't, see `MissingDataPolicy`.
+    #[serde(default)]
+    pub missing_data: MissingDataPolicy,
+    /// When reads update the access time of local files, see
+    /// `AtimePolicy`.
+    #[serde(default)]
+    pub atime: AtimePolicy,
+    /// How names of files in the local vault are normalized, and by
+    /// default how names are compared in peers' vaults, see
+    /// `NameNormalization`.
+    #[serde(default)]
+    pub name_normalization: NameNormalization,
+    /// If true, names in the local vault, and by default in peers'
+    /// vaults, are compared ignoring case, and keep the case they are
+    /// given, like on macOS, see `fold_case`.
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// The hash of chunks in manifests and uploads, see
+    /// `HashAlgorithm`.
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    /// Without caching, keep this many MiB of blocks recently read
+    /// from each remote vault in memory, see read_cache.rs. 0 means
+    /// no read cache.
+    #[serde(default)]
+    pub read_cache_mib: u64,
+    /// The largest read, in bytes, the kernel sends us. Capped at
+    /// GRPC_DATA_CHUNK_SIZE, so a read from a remote vault fits in a
+    /// single message.
+    #[serde(default = "default_max_io")]
+    pub max_read: u32,
+    /// The largest write, in bytes, the kernel sends us. Capped like
+    /// `max_read`.
+    #[serde(default = "default_max_io")]
+    pub max_write: u32,
+    /// Linux only: if true, the kernel keeps writes in its page cache
+    /// and sends them to us in large batches, see README.
+    #[serde(default)]
+    pub writeback_cache: bool,
+    /// If true, reads and writes of open files bypass the kernel's
+    /// page cache and always reach the vault, see README.
+    #[serde(default)]
+    pub direct_io: bool,
+    /// The most requests, like readahead and cached writes, the
+    /// kernel sends us in the background at once. 0 means the
+    /// default (16).
+    #[serde(default)]
+    pub max_background: u16,
+    /// How many background requests make the kernel slow down the
+    /// processes behind them. 0 means 3/4 of `max_background`.
+    #[serde(default)]
+    pub congestion_threshold: u16,
+    /// If the file system session ends on an error, mount it again
+    /// up to this many times in a row before giving up.
+    #[serde(default = "default_remount_attempts")]
+    pub remount_attempts: u32,
+    /// Keep the stores of peers removed from `peers` this many days,
+    /// mounted read-only, before removing them, see retire.rs. 0
+    /// means keep them forever.
+    #[serde(default = "default_retired_grace_days")]
+    pub retired_grace_days: u64,
+    /// With caching enabled, peers faster to reach than the others,
+    /// eg, on the same LAN. We download a file from their cache when
+    /// they have the version its owner has, and only ask the owner
+    /// for the file's manifest.
+    #[serde(default)]
+    pub near_peers: Vec<VaultName>,
+    /// Which peers our vault server serves, and when to ban them.
+    #[serde(default)]
+    pub bans: BanConfig,
+    /// For testing only: inject failures into RPCs to peers, see
+    /// `FaultConfig`.
+    #[serde(default)]
+    pub faults: Option<FaultConfig>,
+    /// If set, the vault server also serves requests whose path
+    /// starts with this prefix, for a reverse proxy that routes on
+    /// it, see proxy.rs.
+    #[serde(default)]
+    pub rpc_path_prefix: Option<String>,
+    /// Send HTTP/2 pings on connections to and from peers after this
+    /// many idle seconds, so proxies don't drop them. 0 means never.
+    #[serde(default)]
+    pub http2_keepalive_secs: u64,
+    /// The header reverse proxies in `trusted_proxies` put the
+    /// address of the client in, eg, "x-forwarded-for", see
+    /// `proxy::ForwardedFor`.
+    #[serde(default)]
+    pub forwarded_header: Option<String>,
+    /// The addresses of the reverse proxies whose `forwarded_header`
+    /// we believe.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// The most FUSE requests to a vault that wait for it at once,
+    /// more fail with EAGAIN, see vault_queue.rs. 0 means the default
+    /// (64).
+    #[serde(default)]
+    pub vault_queue_depth: usize,
     /// Wait this long between each background synchronization to
     /// remote vaults.
     pub background_update_interval: u8,
 }
 
+/// A peer in `Config::peers`. Settings left out follow the global
+/// ones.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PeerConfig {
+    /// Address of the peer's vault server, with the address scheme
+    /// (http://).
+    pub address: VaultAddress,
+    /// Addresses to try in order when `address` doesn't answer, eg,
+    /// the peer's public address when `address` is on the LAN.
+    #[serde(default)]
+    pub fallback_addresses: Vec<VaultAddress>,
+    /// Overrides `Config::caching` for this peer.
+    #[serde(default)]
+    pub caching: Option<bool>,
+    /// If true, mount the peer's vault read-only.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Give up on connecting to the peer, and on each request to it,
+    /// after this many seconds. 0 means wait forever.
+    #[serde(default)]
+    pub timeout_secs: u64,
+    /// Keep the traffic with the peer, both ways, under this many
+    /// KiB per second on average. 0 means no limit.
+    #[serde(default)]
+    pub bandwidth_limit_kib: u64,
+    /// Overrides `Config::name_normalization` for this peer's vault.
+    #[serde(default)]
+    pub name_normalization: Option<NameNormalization>,
+    /// Overrides `Config::case_insensitive` for this peer's vault.
+    #[serde(default)]
+    pub case_insensitive: Option<bool>,
+}
+
+impl PeerConfig {
+    /// Return `address` and then the fallback addresses.
+    pub fn addresses(&self) -> impl Iterator<Item = &VaultAddress> {
+        std::iter::once(&self.address).chain(self.fallback_addresses.iter())
+    }
+}
+
+impl Config {
+    /// Return true if we cache the vault of peer `name`.
+    pub fn caching_of(&self, name: &str) -> bool {
+        self.peers
+            .get(name)
+            .and_then(|peer| peer.caching)
+            .unwrap_or(self.caching)
+    }
+
+    /// Return how names in the vault `name`, ours or a peer's, are
+    /// normalized.
+    pub fn name_normalization_of(&self, name: &str) -> NameNormalization {
+        self.peers
+            .get(name)
+            .and_then(|peer| peer.name_normalization)
+            .unwrap_or(self.name_normalization)
+    }
+
+    /// Return true if names in the vault `name`, ours or a peer's,
+    /// are compared ignoring case.
+    pub fn case_insensitive_of(&self, name: &str) -> bool {
+        self.peers
+            .get(name)
+            .and_then(|peer| peer.case_insensitive)
+            .unwrap_or(self.case_insensitive)
+    }
+
+    /// Make the paths in the configuration absolute and resolve
+    /// symlinks in them, so they mean the same thing whatever the
+    /// current directory and however they are spelled. Relative
+    /// paths are relative to `base`, the directory of the
+    /// configuration file. The volume name defaults to the name of
+    /// the mount point as configured, not of where it leads.
+    pub fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &str| -> String {
+            if path.is_empty() {
+                return String::new();
+            }
+            resolve_path(&base.join(path))
+                .to_string_lossy()
+                .into_owned()
+        };
+        if self.volume_name.is_none() {
+            self.volume_name = Path::new(&self.mount_point)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned());
+        }
+        self.mount_point = resolve(&self.mount_point);
+        self.db_path = resolve(&self.db_path);
+        self.event_socket = self.event_socket.as_deref().map(resolve);
+        self.volume_icon = self.volume_icon.as_deref().map(resolve);
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 pub enum VaultFileType {
     File,
     Directory,
 }
 
+/// What to do when the database has a file but its data file is
+/// gone, eg, deleted by hand or lost in a crash.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingDataPolicy {
+    /// Recreate an empty data file. A cached file is fetched from
+    /// the remote again on next open; a local file's content is lost.
+    Repair,
+    /// Report an error for that file, and leave the store alone for
+    /// the user to inspect.
+    #[default]
+    Error,
+}
+
+/// How a vault normalizes the Unicode in file names. macOS hands us
+/// names in NFD (an "é" is an "e" and a combining accent) and Linux
+/// in whatever form programs use, usually NFC, so without a common
+/// form the same name typed on two hosts can name two files.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NameNormalization {
+    /// Keep names as they come, compare them byte for byte.
+    #[default]
+    None,
+    /// Store names in NFC and compare them in NFC.
+    Nfc,
+    /// Store names in NFD and compare them in NFD.
+    Nfd,
+}
+
+impl NameNormalization {
+    /// Return `name` in the normal form of the policy.
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            NameNormalization::None => name.to_string(),
+            NameNormalization::Nfc => name.nfc().collect(),
+            NameNormalization::Nfd => name.nfd().collect(),
+        }
+    }
+}
+
+/// The hash of chunks in manifests and uploads, see manifest.rs. We
+/// compute manifests and hash uploads in the one configured, unless
+/// the peer on the other end doesn't know it, then in one it knows:
+/// peers running older versions only know SHA-256.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// Faster, the default.
+    #[default]
+    Blake3,
+    /// For peers and tools that only know SHA-256.
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Every algorithm we know.
+    pub const ALL: [HashAlgorithm; 2] = [HashAlgorithm::Blake3, HashAlgorithm::Sha256];
+
+    /// Return the code of the algorithm in RPCs and in the database.
+    /// SHA-256 is 0, what peers and databases from before algorithms
+    /// were recorded leave.
+    pub fn code(self) -> i32 {
+        match self {
+            HashAlgorithm::Sha256 => 0,
+            HashAlgorithm::Blake3 => 1,
+        }
+    }
+
+    /// The reverse of `code`, None for algorithms we don't know.
+    pub fn from_code(code: i32) -> Option<HashAlgorithm> {
+        match code {
+            0 => Some(HashAlgorithm::Sha256),
+            1 => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Return every algorithm we know, `self` first.
+    pub fn preferred(self) -> Vec<HashAlgorithm> {
+        let mut all = vec![self];
+        all.extend(HashAlgorithm::ALL.iter().filter(|&&other| other != self));
+        all
+    }
+
+    /// Return the algorithm to use with a peer that knows
+    /// `known`, `self` if the peer knows it. An empty `known` is a
+    /// peer from before algorithms were negotiated.
+    pub fn negotiate(self, known: &[HashAlgorithm]) -> HashAlgorithm {
+        if known.is_empty() {
+            HashAlgorithm::Sha256
+        } else if known.contains(&self) {
+            self
+        } else {
+            known[0]
+        }
+    }
+}
+
+/// Return `name` with its case folded, so names that differ only by
+/// case compare equal in vaults that ignore case. Names are folded
+/// after normalization, see `NameNormalization::apply`.
+pub fn fold_case(name: &str) -> String {
+    name.to_lowercase()
+}
+
+/// How long relatime lets the access time lag behind, in seconds.
+pub const RELATIME_INTERVAL: u64 = 24 * 60 * 60;
+
+/// When reading a file updates its access time, like the atime mount
+/// options.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AtimePolicy {
+    /// Only if the access time is older than the modification time,
+    /// or than RELATIME_INTERVAL, so it tells whether the file was
+    /// read since it changed, and roughly when.
+    #[default]
+    Relatime,
+    /// Every time the file is opened and read.
+    Strictatime,
+    /// Never.
+    Noatime,
+}
+
+impl AtimePolicy {
+    /// Return true if reading a file with access time `atime` and
+    /// modification time `mtime` at `now` updates its access time.
+    pub fn should_update(self, atime: u64, mtime: u64, now: u64) -> bool {
+        match self {
+            AtimePolicy::Relatime => {
+                atime <= mtime || now >= atime.saturating_add(RELATIME_INTERVAL)
+            }
+            AtimePolicy::Strictatime => true,
+            AtimePolicy::Noatime => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FileInfo {
     pub inode: Inode,
@@ -60,6 +541,55 @@ pub struct FileInfo {
     pub atime: u64,
     pub mtime: u64,
     pub version: (u64, u64),
+    /// Permission bits (eg, 0o755).
+    pub mode: u32,
+    /// Number of names (hard links) of the file, 1 for directories.
+    pub nlink: u64,
+    /// Owner of the file as (uid, gid). None for files nobody set an
+    /// owner on, they belong to whoever runs monovault.
+    pub owner: Option<(u32, u32)>,
+    /// Tells apart files that had the same inode at different times,
+    /// see `Database::generation`. 0 for files added before we
+    /// stored it, and for made-up entries.
+    pub generation: u64,
+}
+
+/// Cumulative usage under a directory.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirInfo {
+    /// Total size of regular files in bytes.
+    pub size: u64,
+    /// Number of files and directories, not counting the directory
+    /// itself.
+    pub count: u64,
+}
+
+/// Totals of a vault, see `Database::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VaultStats {
+    /// Number of regular files, each counted once however many
+    /// names it has.
+    pub files: u64,
+    /// Number of directories, not counting the root.
+    pub directories: u64,
+    /// Total size of regular files in bytes.
+    pub size: u64,
+}
+
+/// Capacity and usage of the disk a vault keeps its data on, like
+/// statvfs(3) but in bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsStats {
+    /// Size of the disk.
+    pub total: u64,
+    /// Free space.
+    pub free: u64,
+    /// Free space unprivileged users can use.
+    pub available: u64,
+    /// Number of files the disk can hold.
+    pub files: u64,
+    /// Number of files it can still hold.
+    pub free_files: u64,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -77,10 +607,20 @@ pub enum VaultError {
     IsDirectory(Inode),
     DirectoryNotEmpty(Inode),
     FileAlreadyExist(Inode, String),
+    InvalidArgument(String),
+    TreeTooLarge(Inode, u64),
+    /// The file has no extended attribute with this name.
+    XattrNotExist(Inode, String),
+    /// Someone else holds a lock on the file that conflicts with the
+    /// one asked for, see src/locks.rs.
+    LockConflict(Inode),
     // Error that are returned from remote vault.
     RpcError(String),
     RemoteError(String),
-    // All errors below are squashed into a RemoteError if returned
+    /// An error on the peer without a variant of its own, see
+    /// `ErrorCategory`: (category, message).
+    RemoteFailure(ErrorCategory, String),
+    // All errors below are squashed into a RemoteFailure if returned
     // from a remove vault. They are returned normally if from a local
     // vault.
     NoCorrespondingVault(Inode),
@@ -89,6 +629,32 @@ pub enum VaultError {
     U64Overflow(u64),
     U64Underflow(u64),
     WriteConflict(Inode, u64, u64),
+    /// The peer we connected to serves a different vault than
+    /// configured: (configured name, advertised name).
+    PeerMismatch(String, String),
+    /// The database has this file but its data file is gone.
+    DataFileMissing(Inode),
+    /// The chunk of this file at this offset doesn't match its hash,
+    /// it was corrupted on the way.
+    ChunkMismatch(Inode, u64),
+    /// This vault can't be changed, see `LocalVault::set_read_only`
+    /// and `CachingVault::set_read_only`.
+    ReadOnly(VaultName),
+    /// The store of this vault doesn't take writes for now, see
+    /// src/store_health.rs.
+    StoreUnwritable(VaultName),
+    /// Another instance holds the lock on our store, see
+    /// src/store_lock.rs: (its pid if recorded, whether it is alive).
+    StoreLocked(Option<u32>, bool),
+    /// The vault has too many requests waiting, see
+    /// src/vault_queue.rs.
+    VaultBusy(VaultName),
+    /// The process that made the request was killed while we waited
+    /// on a peer, see src/interrupt.rs: its pid.
+    Interrupted(u32),
+    /// Another connection kept the database locked after we retried,
+    /// see `database::busy_backoff`.
+    DatabaseBusy,
     SqliteError(rusqlite::Error),
     SystemTimeError(time::SystemTimeError),
     IOError(std::io::Error),
@@ -96,7 +662,17 @@ pub enum VaultError {
 
 impl From<rusqlite::Error> for VaultError {
     fn from(err: rusqlite::Error) -> Self {
-        VaultError::SqliteError(err)
+        match &err {
+            rusqlite::Error::SqliteFailure(failure, _)
+                if matches!(
+                    failure.code,
+                    rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+                ) =>
+            {
+                VaultError::DatabaseBusy
+            }
+            _ => VaultError::SqliteError(err),
+        }
     }
 }
 
@@ -118,6 +694,84 @@ impl From<tonic::transport::Error> for VaultError {
     }
 }
 
+/// What kind of failure an error is, for errors that reach peers
+/// without a variant of their own, so they can tell a full disk from
+/// a bug and know whether to try again.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// Out of disk space or quota.
+    NoSpace,
+    /// The operating system refused access to the store.
+    PermissionDenied,
+    /// Likely to pass if tried again: the database is busy, the
+    /// store is locked, a peer further along is unreachable.
+    Transient,
+    /// Trying again won't help.
+    Permanent,
+}
+
+impl ErrorCategory {
+    /// Return true if an operation that failed this way should be
+    /// kept and tried again later rather than given up.
+    pub fn retryable(self) -> bool {
+        matches!(self, ErrorCategory::NoSpace | ErrorCategory::Transient)
+    }
+}
+
+/// Return the category of `err`.
+fn io_category(err: &std::io::Error) -> ErrorCategory {
+    match err.raw_os_error() {
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) => return ErrorCategory::NoSpace,
+        Some(libc::EBUSY) | Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOMEM) => {
+            return ErrorCategory::Transient
+        }
+        _ => (),
+    }
+    match err.kind() {
+        std::io::ErrorKind::PermissionDenied => ErrorCategory::PermissionDenied,
+        std::io::ErrorKind::Interrupted
+        | std::io::ErrorKind::WouldBlock
+        | std::io::ErrorKind::TimedOut => ErrorCategory::Transient,
+        _ => ErrorCategory::Permanent,
+    }
+}
+
+/// Return the category of `err`.
+fn sqlite_category(err: &rusqlite::Error) -> ErrorCategory {
+    match err {
+        rusqlite::Error::SqliteFailure(err, _) => match err.code {
+            rusqlite::ErrorCode::DiskFull => ErrorCategory::NoSpace,
+            rusqlite::ErrorCode::PermissionDenied | rusqlite::ErrorCode::ReadOnly => {
+                ErrorCategory::PermissionDenied
+            }
+            rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked => {
+                ErrorCategory::Transient
+            }
+            _ => ErrorCategory::Permanent,
+        },
+        _ => ErrorCategory::Permanent,
+    }
+}
+
+impl VaultError {
+    /// Return the category of this error, see `ErrorCategory`.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            VaultError::IOError(err) => io_category(err),
+            VaultError::SqliteError(err) => sqlite_category(err),
+            VaultError::RemoteFailure(category, _) => *category,
+            VaultError::ReadOnly(_) => ErrorCategory::PermissionDenied,
+            VaultError::RpcError(_)
+            | VaultError::StoreLocked(_, _)
+            | VaultError::StoreUnwritable(_)
+            | VaultError::VaultBusy(_)
+            | VaultError::Interrupted(_)
+            | VaultError::DatabaseBusy => ErrorCategory::Transient,
+            _ => ErrorCategory::Permanent,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum CompressedError {
     FileNameTooLong(String),
@@ -127,6 +781,15 @@ pub enum CompressedError {
     DirectoryNotEmpty(Inode),
     CannotFindVaultByName(String),
     FileAlreadyExist(Inode, String),
+    InvalidArgument(String),
+    TreeTooLarge(Inode, u64),
+    ChunkMismatch(Inode, u64),
+    XattrNotExist(Inode, String),
+    LockConflict(Inode),
+    ReadOnly(VaultName),
+    /// Errors without a variant of their own.
+    Failure(ErrorCategory, String),
+    /// Like `Failure`, from peers that don't send categories.
     Misc(String),
 }
 
@@ -142,23 +805,70 @@ impl From<VaultError> for CompressedError {
             VaultError::FileAlreadyExist(inode, name) => {
                 CompressedError::FileAlreadyExist(inode, name)
             }
+            VaultError::InvalidArgument(msg) => CompressedError::InvalidArgument(msg),
+            VaultError::TreeTooLarge(inode, count) => CompressedError::TreeTooLarge(inode, count),
+            VaultError::ChunkMismatch(inode, offset) => {
+                CompressedError::ChunkMismatch(inode, offset)
+            }
+            VaultError::XattrNotExist(inode, name) => CompressedError::XattrNotExist(inode, name),
+            VaultError::LockConflict(inode) => CompressedError::LockConflict(inode),
 
-            VaultError::SqliteError(err) => CompressedError::Misc(format!("{}", err)),
-            VaultError::NoCorrespondingVault(err) => CompressedError::Misc(format!("{}", err)),
-            VaultError::U64Overflow(err) => CompressedError::Misc(format!("{}", err)),
-            VaultError::U64Underflow(err) => CompressedError::Misc(format!("{}", err)),
-            VaultError::RemoteError(err) => CompressedError::Misc(format!("{}", err)),
-            VaultError::SystemTimeError(err) => CompressedError::Misc(format!("{}", err)),
-            VaultError::IOError(err) => CompressedError::Misc(format!("{}", err)),
-            VaultError::RpcError(err) => CompressedError::Misc(format!("{}", err)),
-            VaultError::WrongTypeOfVault(expecting) => CompressedError::Misc(expecting),
-            VaultError::WriteConflict(err0, err1, err2) => {
-                CompressedError::Misc(format!("{}, {}, {}", err0, err1, err2))
+            VaultError::ReadOnly(vault) => CompressedError::ReadOnly(vault),
+            VaultError::RemoteFailure(category, msg) => CompressedError::Failure(category, msg),
+
+            err => {
+                let category = err.category();
+                let msg = match err {
+                    VaultError::SqliteError(err) => format!("{}", err),
+                    VaultError::NoCorrespondingVault(err) => format!("{}", err),
+                    VaultError::U64Overflow(err) => format!("{}", err),
+                    VaultError::U64Underflow(err) => format!("{}", err),
+                    VaultError::RemoteError(err) => err,
+                    VaultError::SystemTimeError(err) => format!("{}", err),
+                    VaultError::IOError(err) => format!("{}", err),
+                    VaultError::RpcError(err) => err,
+                    VaultError::WrongTypeOfVault(expecting) => expecting,
+                    VaultError::PeerMismatch(expected, advertised) => {
+                        format!("{}, {}", expected, advertised)
+                    }
+                    VaultError::DataFileMissing(inode) => {
+                        format!("data file of {} is missing", inode)
+                    }
+                    VaultError::StoreLocked(pid, _) => format!("store is locked by {:?}", pid),
+                    VaultError::StoreUnwritable(vault) => {
+                        format!("store of vault {} doesn't take writes", vault)
+                    }
+                    VaultError::DatabaseBusy => "database is busy".to_string(),
+                    VaultError::WriteConflict(err0, err1, err2) => {
+                        format!("{}, {}, {}", err0, err1, err2)
+                    }
+                    err => format!("{:?}", err),
+                };
+                CompressedError::Failure(category, msg)
             }
         }
     }
 }
 
+/// The request header of peers that decode `CompressedError::ReadOnly`
+/// and `CompressedError::Failure`. Older peers fail to decode them,
+/// they get `CompressedError::for_older_peers` instead.
+pub const ERRORS_HEADER: &str = "monovault-errors";
+
+impl CompressedError {
+    /// Return this error with the variants older peers don't know
+    /// turned into `Misc`, which they do.
+    pub fn for_older_peers(self) -> CompressedError {
+        match self {
+            CompressedError::ReadOnly(vault) => {
+                CompressedError::Misc(format!("vault {} is read-only", vault))
+            }
+            CompressedError::Failure(_, msg) => CompressedError::Misc(msg),
+            err => err,
+        }
+    }
+}
+
 impl From<CompressedError> for VaultError {
     fn from(err: CompressedError) -> Self {
         match err {
@@ -171,6 +881,15 @@ impl From<CompressedError> for VaultError {
             CompressedError::FileAlreadyExist(inode, name) => {
                 VaultError::FileAlreadyExist(inode, name)
             }
+            CompressedError::InvalidArgument(msg) => VaultError::InvalidArgument(msg),
+            CompressedError::TreeTooLarge(inode, count) => VaultError::TreeTooLarge(inode, count),
+            CompressedError::ChunkMismatch(inode, offset) => {
+                VaultError::ChunkMismatch(inode, offset)
+            }
+            CompressedError::XattrNotExist(inode, name) => VaultError::XattrNotExist(inode, name),
+            CompressedError::LockConflict(inode) => VaultError::LockConflict(inode),
+            CompressedError::ReadOnly(vault) => VaultError::ReadOnly(vault),
+            CompressedError::Failure(category, msg) => VaultError::RemoteFailure(category, msg),
             CompressedError::Misc(err) => VaultError::RemoteError(err),
         }
     }
@@ -186,9 +905,20 @@ pub trait Vault: Send {
     fn attr(&mut self, file: Inode) -> VaultResult<FileInfo>;
     /// Read `file` from `offset`, reads `size` bytes. If there aren't
     /// enough bytes to read, read to EOF.
-    fn read(&mut self, file: Inode, offset: i64, size: u32) -> VaultResult<Vec<u8>>;
+    fn read(&mut self, file: Inode, offset: i64, size: u64) -> VaultResult<Vec<u8>>;
     /// Write `data` into `file` at `offset`.
-    fn write(&mut self, file: Inode, offset: i64, data: &[u8]) -> VaultResult<u32>;
+    fn write(&mut self, file: Inode, offset: i64, data: &[u8]) -> VaultResult<u64>;
+    /// Write `data` at the end of `file`, wherever the end is when the
+    /// data lands, like a write(2) to a file opened with O_APPEND.
+    /// Vaults that can't find the end and write in one step find the
+    /// size first.
+    fn append(&mut self, file: Inode, data: &[u8]) -> VaultResult<u64> {
+        let size = self.attr(file)?.size;
+        self.write(file, size as i64, data)
+    }
+    /// Set the size of `file` to `size`, cutting off or zero-filling
+    /// its end. `file` doesn't need to be open.
+    fn truncate(&mut self, file: Inode, size: u64) -> VaultResult<()>;
     /// Create a file or directory under `parent` with `name` and open
     /// it. Return its inode.
     fn create(&mut self, parent: Inode, name: &str, kind: VaultFileType) -> VaultResult<Inode>;
@@ -196,10 +926,78 @@ pub trait Vault: Send {
     fn open(&mut self, file: Inode, mode: OpenMode) -> VaultResult<()>;
     /// Close `file`. `file` should be a regular file.
     fn close(&mut self, file: Inode) -> VaultResult<()>;
+    /// Make what we wrote to `file`, a regular file or a directory,
+    /// durable before returning, like fsync(2). Vaults that keep
+    /// nothing themselves have nothing to do.
+    fn fsync(&mut self, _file: Inode) -> VaultResult<()> {
+        Ok(())
+    }
+    /// Return where the data, or the hole if `hole` is true, at or
+    /// after `offset` in regular file `file` starts, like lseek(2)
+    /// with SEEK_DATA and SEEK_HOLE. Return None if there is none,
+    /// ie, `offset` is at or past the end. Vaults that don't keep
+    /// track of holes report the whole file as data, followed by the
+    /// hole at its end.
+    fn seek(&mut self, file: Inode, offset: i64, hole: bool) -> VaultResult<Option<u64>> {
+        let offset = check_range(offset, 0)?;
+        let info = self.attr(file)?;
+        if let VaultFileType::Directory = info.kind {
+            return Err(VaultError::IsDirectory(file));
+        }
+        if offset >= info.size {
+            return Ok(None);
+        }
+        Ok(Some(if hole { info.size } else { offset }))
+    }
+    /// Set the permission bits of `file` to `mode`.
+    fn set_mode(&mut self, file: Inode, mode: u32) -> VaultResult<()>;
+    /// Set the owner of `file` to user `uid` and group `gid`.
+    fn set_owner(&mut self, file: Inode, uid: u32, gid: u32) -> VaultResult<()>;
+    /// Set the access and modification time of `file`, in seconds
+    /// since UNIX epoch. None leaves that time alone.
+    fn set_times(&mut self, file: Inode, atime: Option<u64>, mtime: Option<u64>)
+        -> VaultResult<()>;
     /// Delete `file`. `file` can a regular file or a directory.
     fn delete(&mut self, file: Inode) -> VaultResult<()>;
-    /// List directory entries of `dir`. The listing includes "." and
-    /// "..", but if `dir` is vault root, ".." is not included.
+    /// Delete `dir` and everything under it. Refuses to delete the
+    /// vault root, and trees with more than `limit` entries (in which
+    /// case nothing is deleted). Return the number of deleted entries.
+    fn delete_tree(&mut self, dir: Inode, limit: u64) -> VaultResult<u64>;
+    /// Move `file` into directory `parent` under `name`. An entry
+    /// already there with `name` is replaced, like rename(2), if it
+    /// is a file and `file` is too, or if it is an empty directory
+    /// and `file` is a directory. Not atomic like rename(2): the
+    /// replaced entry goes first, then `file` moves.
+    fn rename(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()>;
+    /// Add `name` in directory `parent` as another name of regular
+    /// file `file`, like link(2).
+    fn link(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()>;
+    /// Remove `name` in directory `parent`, a name of regular file
+    /// `file`. `file` is deleted if that was its last name, like
+    /// unlink(2).
+    fn unlink(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()>;
+    /// Return the value of extended attribute `name` of `file`, or
+    /// None if it doesn't have one.
+    fn xattr(&mut self, file: Inode, name: &str) -> VaultResult<Option<Vec<u8>>>;
+    /// Return the names of the extended attributes of `file`.
+    fn xattr_names(&mut self, file: Inode) -> VaultResult<Vec<String>>;
+    /// Set extended attribute `name` of `file` to `value`, adding it
+    /// if `file` doesn't have it yet.
+    fn set_xattr(&mut self, file: Inode, name: &str, value: &[u8]) -> VaultResult<()>;
+    /// Remove extended attribute `name` of `file`.
+    fn remove_xattr(&mut self, file: Inode, name: &str) -> VaultResult<()>;
+    /// Return a lock on `file` someone else holds that keeps `lock`
+    /// from being taken, like F_GETLK.
+    fn getlk(&mut self, file: Inode, lock: &FileLock) -> VaultResult<Option<FileLock>>;
+    /// Take or release `lock` on `file` without waiting, like
+    /// F_SETLK, see `LockTable::set`.
+    fn setlk(&mut self, file: Inode, lock: &FileLock) -> VaultResult<()>;
+    /// Return the cumulative size and entry count under `dir`.
+    fn info(&mut self, dir: Inode) -> VaultResult<DirInfo>;
+    /// Return the capacity and usage of the disk the vault is on.
+    fn statfs(&mut self) -> VaultResult<FsStats>;
+    /// List directory entries of `dir`. The listing doesn't include
+    /// "." and "..", the FUSE layer adds them.
     fn readdir(&mut self, dir: Inode) -> VaultResult<Vec<FileInfo>>;
 }
 
@@ -230,6 +1028,54 @@ pub fn unpack_to_remote(vault: &mut GenericVault) -> VaultResult<&mut RemoteVaul
     }
 }
 
+impl GenericVault {
+    /// Return the path of `file` in the vault, for messages. Remote
+    /// vaults don't keep paths.
+    pub fn path_of(&self, file: Inode) -> VaultResult<String> {
+        match self {
+            GenericVault::Local(vault) => vault.path_of(file),
+            GenericVault::Remote(_) => {
+                Err(VaultError::WrongTypeOfVault("local or caching".to_string()))
+            }
+            GenericVault::Caching(vault) => vault.path_of(file),
+        }
+    }
+
+    /// Return when the vault was sealed, None if it isn't or, for a
+    /// peer's vault, we don't know yet, see src/seal.rs.
+    pub fn sealed(&self) -> Option<u64> {
+        match self {
+            GenericVault::Local(vault) => vault.sealed(),
+            GenericVault::Remote(vault) => vault.sealed(),
+            GenericVault::Caching(vault) => vault.sealed(),
+        }
+    }
+
+    /// Refresh the statistics of the vault's database and vacuum it
+    /// if needed, see `Database::maintain`. Remote vaults have no
+    /// database.
+    pub fn maintain_database(&mut self) -> VaultResult<Option<u64>> {
+        match self {
+            GenericVault::Local(vault) => vault.maintain_database(),
+            GenericVault::Remote(_) => {
+                Err(VaultError::WrongTypeOfVault("local or caching".to_string()))
+            }
+            GenericVault::Caching(vault) => vault.maintain_database(),
+        }
+    }
+
+    /// Return `result`, of a change to the vault, after telling the
+    /// health of the store the vault is kept in, if any, see
+    /// `StoreHealth::note`.
+    fn noted<T>(&self, result: VaultResult<T>) -> VaultResult<T> {
+        match self {
+            GenericVault::Local(vault) => vault.store_health().note(result),
+            GenericVault::Remote(_) => result,
+            GenericVault::Caching(vault) => vault.store_health().note(result),
+        }
+    }
+}
+
 impl Vault for GenericVault {
     fn name(&self) -> String {
         match self {
@@ -247,7 +1093,7 @@ impl Vault for GenericVault {
         }
     }
 
-    fn read(&mut self, file: Inode, offset: i64, size: u32) -> VaultResult<Vec<u8>> {
+    fn read(&mut self, file: Inode, offset: i64, size: u64) -> VaultResult<Vec<u8>> {
         match self {
             GenericVault::Local(vault) => vault.read(file, offset, size),
             GenericVault::Remote(vault) => vault.read(file, offset, size),
@@ -255,43 +1101,217 @@ impl Vault for GenericVault {
         }
     }
 
-    fn write(&mut self, file: Inode, offset: i64, data: &[u8]) -> VaultResult<u32> {
-        match self {
+    fn write(&mut self, file: Inode, offset: i64, data: &[u8]) -> VaultResult<u64> {
+        let result = match self {
             GenericVault::Local(vault) => vault.write(file, offset, data),
             GenericVault::Remote(vault) => vault.write(file, offset, data),
             GenericVault::Caching(vault) => vault.write(file, offset, data),
-        }
+        };
+        self.noted(result)
+    }
+
+    fn append(&mut self, file: Inode, data: &[u8]) -> VaultResult<u64> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.append(file, data),
+            GenericVault::Remote(vault) => vault.append(file, data),
+            GenericVault::Caching(vault) => vault.append(file, data),
+        };
+        self.noted(result)
+    }
+
+    fn truncate(&mut self, file: Inode, size: u64) -> VaultResult<()> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.truncate(file, size),
+            GenericVault::Remote(vault) => vault.truncate(file, size),
+            GenericVault::Caching(vault) => vault.truncate(file, size),
+        };
+        self.noted(result)
     }
 
     fn create(&mut self, parent: Inode, name: &str, kind: VaultFileType) -> VaultResult<Inode> {
-        match self {
+        let result = match self {
             GenericVault::Local(vault) => vault.create(parent, name, kind),
             GenericVault::Remote(vault) => vault.create(parent, name, kind),
             GenericVault::Caching(vault) => vault.create(parent, name, kind),
-        }
+        };
+        self.noted(result)
     }
 
     fn open(&mut self, file: Inode, mode: OpenMode) -> VaultResult<()> {
-        match self {
+        let result = match self {
             GenericVault::Local(vault) => vault.open(file, mode),
             GenericVault::Remote(vault) => vault.open(file, mode),
             GenericVault::Caching(vault) => vault.open(file, mode),
-        }
+        };
+        self.noted(result)
     }
 
     fn close(&mut self, file: Inode) -> VaultResult<()> {
-        match self {
+        let result = match self {
             GenericVault::Local(vault) => vault.close(file),
             GenericVault::Remote(vault) => vault.close(file),
             GenericVault::Caching(vault) => vault.close(file),
+        };
+        self.noted(result)
+    }
+
+    fn fsync(&mut self, file: Inode) -> VaultResult<()> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.fsync(file),
+            GenericVault::Remote(vault) => vault.fsync(file),
+            GenericVault::Caching(vault) => vault.fsync(file),
+        };
+        self.noted(result)
+    }
+
+    fn seek(&mut self, file: Inode, offset: i64, hole: bool) -> VaultResult<Option<u64>> {
+        match self {
+            GenericVault::Local(vault) => vault.seek(file, offset, hole),
+            GenericVault::Remote(vault) => vault.seek(file, offset, hole),
+            GenericVault::Caching(vault) => vault.seek(file, offset, hole),
         }
     }
 
+    fn set_mode(&mut self, file: Inode, mode: u32) -> VaultResult<()> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.set_mode(file, mode),
+            GenericVault::Remote(vault) => vault.set_mode(file, mode),
+            GenericVault::Caching(vault) => vault.set_mode(file, mode),
+        };
+        self.noted(result)
+    }
+
+    fn set_owner(&mut self, file: Inode, uid: u32, gid: u32) -> VaultResult<()> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.set_owner(file, uid, gid),
+            GenericVault::Remote(vault) => vault.set_owner(file, uid, gid),
+            GenericVault::Caching(vault) => vault.set_owner(file, uid, gid),
+        };
+        self.noted(result)
+    }
+
+    fn set_times(
+        &mut self,
+        file: Inode,
+        atime: Option<u64>,
+        mtime: Option<u64>,
+    ) -> VaultResult<()> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.set_times(file, atime, mtime),
+            GenericVault::Remote(vault) => vault.set_times(file, atime, mtime),
+            GenericVault::Caching(vault) => vault.set_times(file, atime, mtime),
+        };
+        self.noted(result)
+    }
+
     fn delete(&mut self, file: Inode) -> VaultResult<()> {
-        match self {
+        let result = match self {
             GenericVault::Local(vault) => vault.delete(file),
             GenericVault::Remote(vault) => vault.delete(file),
             GenericVault::Caching(vault) => vault.delete(file),
+        };
+        self.noted(result)
+    }
+
+    fn delete_tree(&mut self, dir: Inode, limit: u64) -> VaultResult<u64> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.delete_tree(dir, limit),
+            GenericVault::Remote(vault) => vault.delete_tree(dir, limit),
+            GenericVault::Caching(vault) => vault.delete_tree(dir, limit),
+        };
+        self.noted(result)
+    }
+
+    fn rename(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.rename(file, parent, name),
+            GenericVault::Remote(vault) => vault.rename(file, parent, name),
+            GenericVault::Caching(vault) => vault.rename(file, parent, name),
+        };
+        self.noted(result)
+    }
+
+    fn link(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.link(file, parent, name),
+            GenericVault::Remote(vault) => vault.link(file, parent, name),
+            GenericVault::Caching(vault) => vault.link(file, parent, name),
+        };
+        self.noted(result)
+    }
+
+    fn unlink(&mut self, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.unlink(file, parent, name),
+            GenericVault::Remote(vault) => vault.unlink(file, parent, name),
+            GenericVault::Caching(vault) => vault.unlink(file, parent, name),
+        };
+        self.noted(result)
+    }
+
+    fn xattr(&mut self, file: Inode, name: &str) -> VaultResult<Option<Vec<u8>>> {
+        match self {
+            GenericVault::Local(vault) => vault.xattr(file, name),
+            GenericVault::Remote(vault) => vault.xattr(file, name),
+            GenericVault::Caching(vault) => vault.xattr(file, name),
+        }
+    }
+
+    fn xattr_names(&mut self, file: Inode) -> VaultResult<Vec<String>> {
+        match self {
+            GenericVault::Local(vault) => vault.xattr_names(file),
+            GenericVault::Remote(vault) => vault.xattr_names(file),
+            GenericVault::Caching(vault) => vault.xattr_names(file),
+        }
+    }
+
+    fn set_xattr(&mut self, file: Inode, name: &str, value: &[u8]) -> VaultResult<()> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.set_xattr(file, name, value),
+            GenericVault::Remote(vault) => vault.set_xattr(file, name, value),
+            GenericVault::Caching(vault) => vault.set_xattr(file, name, value),
+        };
+        self.noted(result)
+    }
+
+    fn remove_xattr(&mut self, file: Inode, name: &str) -> VaultResult<()> {
+        let result = match self {
+            GenericVault::Local(vault) => vault.remove_xattr(file, name),
+            GenericVault::Remote(vault) => vault.remove_xattr(file, name),
+            GenericVault::Caching(vault) => vault.remove_xattr(file, name),
+        };
+        self.noted(result)
+    }
+
+    fn info(&mut self, dir: Inode) -> VaultResult<DirInfo> {
+        match self {
+            GenericVault::Local(vault) => vault.info(dir),
+            GenericVault::Remote(vault) => vault.info(dir),
+            GenericVault::Caching(vault) => vault.info(dir),
+        }
+    }
+
+    fn getlk(&mut self, file: Inode, lock: &FileLock) -> VaultResult<Option<FileLock>> {
+        match self {
+            GenericVault::Local(vault) => vault.getlk(file, lock),
+            GenericVault::Remote(vault) => vault.getlk(file, lock),
+            GenericVault::Caching(vault) => vault.getlk(file, lock),
+        }
+    }
+
+    fn setlk(&mut self, file: Inode, lock: &FileLock) -> VaultResult<()> {
+        match self {
+            GenericVault::Local(vault) => vault.setlk(file, lock),
+            GenericVault::Remote(vault) => vault.setlk(file, lock),
+            GenericVault::Caching(vault) => vault.setlk(file, lock),
+        }
+    }
+
+    fn statfs(&mut self) -> VaultResult<FsStats> {
+        match self {
+            GenericVault::Local(vault) => vault.statfs(),
+            GenericVault::Remote(vault) => vault.statfs(),
+            GenericVault::Caching(vault) => vault.statfs(),
         }
     }
 
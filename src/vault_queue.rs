@@ -0,0 +1,282 @@
+/// A queue of FUSE requests for one vault, served by a thread of its
+/// own. The FUSE session has a single thread, so a vault stuck on a
+/// dead peer used to hold up requests to every other vault behind it.
+/// With a queue per vault, the session thread hands reads, writes and
+/// the like to the vault's thread and goes on to the next request. A
+/// queue takes at most so many requests, past that we reply EAGAIN
+/// right away rather than piling up requests the vault can't serve.
+///
+/// Programs reading the same file at once, or the kernel's readahead
+/// racing a program, often ask for the same bytes while the first read
+/// is still queued or on the network. Such reads join the first one,
+/// see `ReadFlights`, and are answered with its result.
+use crate::interrupt;
+use crate::types::*;
+use log::{error, info};
+use std::collections::HashMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time;
+
+/// How many requests a queue takes by default, see
+/// `Config::vault_queue_depth`.
+pub const DEFAULT_QUEUE_DEPTH: usize = 64;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The number of requests queued or running, with a condition
+/// variable to wait for it to drop to 0.
+type Pending = Arc<(Mutex<usize>, Condvar)>;
+
+pub struct VaultQueue {
+    vault: VaultName,
+    depth: usize,
+    tx: mpsc::Sender<Job>,
+    pending: Pending,
+}
+
+/// A place in a queue, see `VaultQueue::reserve`.
+pub struct Slot {
+    tx: mpsc::Sender<Job>,
+    pending: Pending,
+    used: bool,
+}
+
+impl VaultQueue {
+    /// Return a queue for `vault` that takes at most `depth`
+    /// requests (0 means `DEFAULT_QUEUE_DEPTH`), and start its
+    /// thread. The thread exits once the queue is dropped.
+    pub fn new(vault: &str, depth: usize) -> VaultQueue {
+        let depth = if depth == 0 {
+            DEFAULT_QUEUE_DEPTH
+        } else {
+            depth
+        };
+        let (tx, rx) = mpsc::channel::<Job>();
+        let pending: Pending = Arc::new((Mutex::new(0), Condvar::new()));
+        let worker_pending = Arc::clone(&pending);
+        let name = vault.to_string();
+        let _ = thread::Builder::new()
+            .name(format!("vault-{}", vault))
+            .spawn(move || {
+                for job in rx {
+                    // A job that panics drops its reply, which
+                    // replies EIO; keep serving the others.
+                    if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        error!("vault_queue({}) => request panicked", name);
+                    }
+                    finish(&worker_pending);
+                }
+                info!("vault_queue({}) => exit", name);
+            });
+        VaultQueue {
+            vault: vault.to_string(),
+            depth,
+            tx,
+            pending,
+        }
+    }
+
+    /// Return a place for a request in the queue, or VaultBusy if the
+    /// queue is full.
+    pub fn reserve(&self) -> VaultResult<Slot> {
+        let (count, _) = &*self.pending;
+        let mut count = count.lock().unwrap();
+        if *count >= self.depth {
+            return Err(VaultError::VaultBusy(self.vault.clone()));
+        }
+        *count += 1;
+        Ok(Slot {
+            tx: self.tx.clone(),
+            pending: Arc::clone(&self.pending),
+            used: false,
+        })
+    }
+
+    /// Return a place in the queue even if it is full, for requests
+    /// that must not fail, like closing a file: the queue only grows
+    /// by as many as the kernel sends.
+    pub fn reserve_always(&self) -> Slot {
+        let (count, _) = &*self.pending;
+        *count.lock().unwrap() += 1;
+        Slot {
+            tx: self.tx.clone(),
+            pending: Arc::clone(&self.pending),
+            used: false,
+        }
+    }
+
+    /// Return the number of requests queued or running.
+    pub fn pending(&self) -> usize {
+        *self.pending.0.lock().unwrap()
+    }
+
+    /// Wait up to `timeout` for the requests in the queue to finish,
+    /// fail with VaultBusy if they don't.
+    pub fn wait_idle(&self, timeout: time::Duration) -> VaultResult<()> {
+        let (count, idle) = &*self.pending;
+        let count = count.lock().unwrap();
+        let (count, _) = idle
+            .wait_timeout_while(count, timeout, |count| *count > 0)
+            .unwrap();
+        if *count > 0 {
+            return Err(VaultError::VaultBusy(self.vault.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl Slot {
+    /// Run `job` on the queue's thread, after the requests before it.
+    /// It serves the same process as this thread, see
+    /// `interrupt::serving`.
+    pub fn run(mut self, job: impl FnOnce() + Send + 'static) {
+        self.used = true;
+        let requester = interrupt::requester().unwrap_or(0);
+        let job = move || {
+            let _serving = interrupt::serving(requester);
+            job()
+        };
+        if let Err(mpsc::SendError(job)) = self.tx.send(Box::new(job)) {
+            // The thread is gone, dropping the job replies EIO.
+            error!("vault_queue => no thread to run the request");
+            drop(job);
+            finish(&self.pending);
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if !self.used {
+            finish(&self.pending);
+        }
+    }
+}
+
+/// Count a request off `pending`.
+fn finish(pending: &Pending) {
+    let (count, idle) = &**pending;
+    let mut count = count.lock().unwrap();
+    *count -= 1;
+    if *count == 0 {
+        idle.notify_all();
+    }
+}
+
+/// What a read asks for: (inode, offset, size).
+pub type ReadKey = (u64, i64, u64);
+
+/// The replies waiting for each flight, see `ReadFlights`.
+struct Flights<R> {
+    /// Flights reads can join, with the ID of each.
+    open: HashMap<ReadKey, (u64, Vec<R>)>,
+    /// Flights of files written to since they started, by ID, see
+    /// `ReadFlights::close`.
+    closed: HashMap<u64, Vec<R>>,
+    next_id: u64,
+}
+
+/// Reads queued or running, each with the replies waiting for it.
+/// Replies are of type `R`, ReplyData in the FUSE layer.
+pub struct ReadFlights<R> {
+    flights: Arc<Mutex<Flights<R>>>,
+}
+
+/// A read in flight, see `ReadFlights::join`. Dropping it without
+/// landing drops the replies waiting for it, eg, when the read
+/// panics.
+pub struct Flight<R> {
+    flights: Arc<Mutex<Flights<R>>>,
+    key: ReadKey,
+    /// None once landed.
+    id: Option<u64>,
+}
+
+impl<R> ReadFlights<R> {
+    pub fn new() -> ReadFlights<R> {
+        ReadFlights {
+            flights: Arc::new(Mutex::new(Flights {
+                open: HashMap::new(),
+                closed: HashMap::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    /// If a read of `key` is in flight, add `reply` to it and return
+    /// None. Otherwise start a flight with `reply` and return it; the
+    /// caller does the read and lands the flight.
+    pub fn join(&self, key: ReadKey, reply: R) -> Option<Flight<R>> {
+        let mut flights = self.flights.lock().unwrap();
+        if let Some((_, replies)) = flights.open.get_mut(&key) {
+            replies.push(reply);
+            return None;
+        }
+        let id = flights.next_id;
+        flights.next_id += 1;
+        flights.open.insert(key, (id, vec![reply]));
+        Some(Flight {
+            flights: Arc::clone(&self.flights),
+            key,
+            id: Some(id),
+        })
+    }
+
+    /// `ino` is being written to or truncated: reads of it from now on
+    /// start a new flight, queued after the change, rather than join
+    /// one that may answer with what was there before. Flights in the
+    /// air still answer the reads that joined them.
+    pub fn close(&self, ino: u64) {
+        let mut flights = self.flights.lock().unwrap();
+        let keys: Vec<ReadKey> = flights
+            .open
+            .keys()
+            .filter(|key| key.0 == ino)
+            .copied()
+            .collect();
+        for key in keys {
+            if let Some((id, replies)) = flights.open.remove(&key) {
+                flights.closed.insert(id, replies);
+            }
+        }
+    }
+}
+
+impl<R> Default for ReadFlights<R> {
+    fn default() -> Self {
+        ReadFlights::new()
+    }
+}
+
+impl<R> Flight<R> {
+    /// End the flight, return the replies waiting for it, the first
+    /// one first. Reads of the same bytes from now on start a new
+    /// flight.
+    pub fn land(mut self) -> Vec<R> {
+        self.take()
+    }
+
+    fn take(&mut self) -> Vec<R> {
+        let id = match self.id.take() {
+            Some(id) => id,
+            None => return vec![],
+        };
+        let mut flights = self.flights.lock().unwrap();
+        match flights.open.get(&self.key) {
+            Some((open_id, _)) if *open_id == id => flights
+                .open
+                .remove(&self.key)
+                .map(|(_, replies)| replies)
+                .unwrap_or_default(),
+            _ => flights.closed.remove(&id).unwrap_or_default(),
+        }
+    }
+}
+
+impl<R> Drop for Flight<R> {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
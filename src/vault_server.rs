@@ -1,38 +1,146 @@
+use crate::background_worker::BackgroundOp;
+use crate::bans::{canonical, PeerGuard};
+use crate::clock::ServerTimeLayer;
+use crate::connections::ConnectionLog;
+use crate::dir_columns;
+use crate::locks;
+use crate::log_filter;
+use crate::manifest::hash_chunk;
+use crate::proxy::{Client, ForwardedFor, StripPrefixLayer};
 use crate::rpc::vault_rpc_server::VaultRpc;
+use crate::rpc::{
+    pending_op, BanEntry, BanList, BanToClear, ConnectionEvent, ConnectionEventList, Count,
+    DataChunk, DirEntryColumns, DirEntryList, DirInfo, DryRun, Empty, FileInfo, FileLock, FileMode,
+    FileOfVault, FileOwner, FilePath, FileSize, FileTimes, FileToCreate, FileToLink, FileToOpen,
+    FileToRead, FileToRename, FileToWrite, FsStats, Grail, Identity, Inode, LockConflict,
+    LogFilter, Manifest, ManifestRequest, PendingList, PendingOp, SavageOffer, SealRequest,
+    SealTime, SharedFile, SharedRead, Size, TreeEntries, TreeEntry, TreeToDelete, VaultPending,
+    Xattr, XattrNames, XattrValue,
+};
 /// A gRPC server that receives requests and uses local_vault to do the
 /// actual work.
 use crate::rpc::{vault_rpc_server, Acceptance};
-use crate::rpc::{
-    DataChunk, DirEntryList, Empty, FileInfo, FileToCreate, FileToOpen, FileToRead, FileToWrite,
-    Grail, Inode, Size,
-};
+use crate::seal::SEAL_COMMAND;
+use crate::share::ShareKey;
 use crate::types::{
-    unpack_to_local, CompressedError, FileVersion, GenericVault, OpenMode, Vault, VaultError,
-    VaultFileType, VaultRef, VaultResult, GRPC_DATA_CHUNK_SIZE,
+    self, check_range, unpack_to_local, CompressedError, FileVersion, GenericVault, HashAlgorithm,
+    OpenMode, Vault, VaultError, VaultFileType, VaultName, VaultRef, VaultResult, ERRORS_HEADER,
+    GRPC_DATA_CHUNK_SIZE, MAX_READ_SIZE, MAX_TREE_DELETE,
 };
 use async_trait::async_trait;
 use log::{debug, info};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
+use tonic::codegen::{http, BoxFuture, Service};
 use tonic::{Request, Response, Status, Streaming};
+use tower_layer::Layer;
 
+/// Listen on `address` and serve our vaults, see `serve`.
 pub fn run_server(
     address: &str,
     local_name: &str,
     vault_map: HashMap<String, VaultRef>,
     runtime: Arc<Runtime>,
+    guard: PeerGuard,
+    share_key: Option<ShareKey>,
+    connections: ConnectionLog,
 ) {
-    let service = vault_rpc_server::VaultRpcServer::new(
-        VaultServer::new(local_name, vault_map).expect("Cannot create server instance"),
-    );
-    let server = tonic::transport::Server::builder().add_service(service.clone());
-    let incoming = match runtime.block_on(TcpListener::bind(address)) {
-        Ok(lis) => tokio_stream::wrappers::TcpListenerStream::new(lis),
-        Err(err) => panic!("Cannot listen to address: {:?}", err),
+    let listener = bind(address).expect("Cannot listen to address");
+    serve(
+        listener,
+        local_name,
+        vault_map,
+        runtime,
+        guard,
+        share_key,
+        connections,
+        HttpSettings::default(),
+    )
+}
+
+/// How the server speaks HTTP.
+#[derive(Debug, Clone, Default)]
+pub struct HttpSettings {
+    /// Also serve requests with this path prefix, see proxy.rs.
+    pub path_prefix: Option<String>,
+    /// Ping peers after the connection is idle this long.
+    pub keepalive: Option<Duration>,
+    /// Who to believe about the address of clients behind proxies.
+    pub forwarded: ForwardedFor,
+}
+
+/// Return a listener on `address` for `serve`. Binding separately
+/// lets the caller report a taken address before serving in the
+/// background.
+pub fn bind(address: &str) -> VaultResult<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Serve the vaults in `vault_map` on `listener` until the server
+/// fails. `local_name` is the vault we serve to peers, `connections`
+/// the log our remote vaults write to.
+#[allow(clippy::too_many_arguments)]
+pub fn serve(
+    listener: std::net::TcpListener,
+    local_name: &str,
+    vault_map: HashMap<String, VaultRef>,
+    runtime: Arc<Runtime>,
+    guard: PeerGuard,
+    share_key: Option<ShareKey>,
+    connections: ConnectionLog,
+    http: HttpSettings,
+) {
+    let forwarded = http.forwarded.clone();
+    let server = VaultServer::new(
+        local_name,
+        vault_map,
+        guard.clone(),
+        share_key,
+        connections,
+        http.forwarded,
+    )
+    .expect("Cannot create server instance");
+    // Refuse denied and banned peers before doing any work. Share
+    // link calls only serve what their token grants, so anyone may
+    // make them.
+    let service =
+        vault_rpc_server::VaultRpcServer::with_interceptor(server, move |request: Request<()>| {
+            if let Some(addr) = forwarded.client(&request).addr {
+                let admitted = if request.extensions().get::<ShareCall>().is_some() {
+                    guard.admit_share(addr.ip())
+                } else {
+                    guard.admit(addr.ip())
+                };
+                if let Err(reason) = admitted {
+                    debug!("Refused request: {}", reason);
+                    return Err(Status::permission_denied(reason));
+                }
+            }
+            Ok(request)
+        });
+    let server = tonic::transport::Server::builder()
+        .http2_keepalive_interval(http.keepalive)
+        .layer(StripPrefixLayer::new(http.path_prefix))
+        .layer(ServerTimeLayer)
+        .layer(ShareCallLayer)
+        .layer(PeerErrorsLayer)
+        .add_service(service.clone());
+    let incoming = {
+        // Tokio listeners need a runtime to register with.
+        let _guard = runtime.enter();
+        match TcpListener::from_std(listener) {
+            Ok(lis) => tokio_stream::wrappers::TcpListenerStream::new(lis),
+            Err(err) => panic!("Cannot listen to address: {:?}", err),
+        }
     };
     info!("Server started");
     runtime
@@ -40,48 +148,358 @@ pub fn run_server(
         .expect("Error serving requests");
 }
 
+/// RPCs that redeem share links, see `VaultServer::check_share`.
+const SHARE_CALLS: [&str; 3] = ["attr_shared", "readdir_shared", "read_shared"];
+
+/// Marks requests to SHARE_CALLS for the interceptor, which doesn't
+/// see the path.
+#[derive(Debug, Clone, Copy)]
+struct ShareCall;
+
+#[derive(Debug, Clone)]
+struct ShareCallLayer;
+
+impl<S> Layer<S> for ShareCallLayer {
+    type Service = MarkShareCall<S>;
+
+    fn layer(&self, inner: S) -> MarkShareCall<S> {
+        MarkShareCall { inner }
+    }
+}
+
+/// Puts `ShareCall` in the extensions of requests to SHARE_CALLS.
+#[derive(Debug, Clone)]
+struct MarkShareCall<S> {
+    inner: S,
+}
+
+impl<S, B> Service<http::Request<B>> for MarkShareCall<S>
+where
+    S: Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        // The path is /<package>.<service>/<method>.
+        let method = request.uri().path().rsplit('/').next().unwrap_or("");
+        if SHARE_CALLS.contains(&method) {
+            request.extensions_mut().insert(ShareCall);
+        }
+        self.inner.call(request)
+    }
+}
+
+tokio::task_local! {
+    /// True while serving a request of a peer that sent ERRORS_HEADER,
+    /// see `pack_status`.
+    static NEWER_ERRORS: bool;
+}
+
+/// Return true if the peer we serve decodes every `CompressedError`.
+fn newer_errors() -> bool {
+    NEWER_ERRORS.try_with(|newer| *newer).unwrap_or(false)
+}
+
+#[derive(Debug, Clone)]
+struct PeerErrorsLayer;
+
+impl<S> Layer<S> for PeerErrorsLayer {
+    type Service = PeerErrors<S>;
+
+    fn layer(&self, inner: S) -> PeerErrors<S> {
+        PeerErrors { inner }
+    }
+}
+
+/// Serves each request with NEWER_ERRORS set to whether it has
+/// ERRORS_HEADER.
+#[derive(Debug, Clone)]
+struct PeerErrors<S> {
+    inner: S,
+}
+
+impl<S, B, R> Service<http::Request<B>> for PeerErrors<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>> + 'static,
+    S::Future: Send + 'static,
+    B: 'static,
+    R: 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<S::Response, S::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let newer = request.headers().contains_key(ERRORS_HEADER);
+        let inner = &mut self.inner;
+        let response = NEWER_ERRORS.sync_scope(newer, || inner.call(request));
+        Box::pin(NEWER_ERRORS.scope(newer, response))
+    }
+}
+
 pub struct VaultServer {
     vault_map: HashMap<String, VaultRef>,
     local_name: String,
+    guard: PeerGuard,
+    /// Checks share link tokens, share links are refused if None.
+    share_key: Option<ShareKey>,
+    /// Connection events of our remote vaults.
+    connections: ConnectionLog,
+    /// The vaults we keep copies of, which `savage_source` serves.
+    copies: Vec<VaultName>,
+    /// Tells who sent requests, see `ForwardedFor`.
+    forwarded: ForwardedFor,
 }
 
 impl VaultServer {
     /// `vault_map` should contain all the remote and local vault.
-    pub fn new(local_name: &str, vault_map: HashMap<String, VaultRef>) -> VaultResult<VaultServer> {
-        if vault_map.get(local_name).is_none() {
+    pub fn new(
+        local_name: &str,
+        vault_map: HashMap<String, VaultRef>,
+        guard: PeerGuard,
+        share_key: Option<ShareKey>,
+        connections: ConnectionLog,
+        forwarded: ForwardedFor,
+    ) -> VaultResult<VaultServer> {
+        if !vault_map.contains_key(local_name) {
             return Err(VaultError::CannotFindVaultByName(local_name.to_string()));
         }
+        let mut copies: Vec<VaultName> = vault_map
+            .iter()
+            .filter(|(_, vault)| !matches!(&*vault.lock().unwrap(), GenericVault::Remote(_)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        copies.sort();
         Ok(VaultServer {
             local_name: local_name.to_string(),
             vault_map,
+            guard,
+            share_key,
+            connections,
+            copies,
+            forwarded,
         })
     }
 
     fn local(&self) -> &VaultRef {
         self.vault_map.get(&self.local_name).unwrap()
     }
+
+    /// Return our vault named `vault` if it can serve savage requests
+    /// for its files, ie, it keeps copies of them.
+    fn savage_source(&self, vault: &str, file: types::Inode) -> VaultResult<VaultRef> {
+        match self.vault_map.get(vault) {
+            None => {
+                debug!("We don't know this vault");
+                Err(VaultError::FileNotExist(file))
+            }
+            Some(vault_ref) => match &*vault_ref.lock().unwrap() {
+                GenericVault::Remote(_) => {
+                    debug!("Cannot serve savage request because we are not caching");
+                    Err(VaultError::WrongTypeOfVault("caching/local".to_string()))
+                }
+                _ => Ok(Arc::clone(vault_ref)),
+            },
+        }
+    }
+
+    /// Like `translate_result`, but count an invalid argument as a
+    /// malformed request from the peer at `addr`.
+    #[allow(clippy::result_large_err)]
+    fn check<T>(&self, addr: Option<SocketAddr>, res: VaultResult<T>) -> Result<T, Status> {
+        if let (Some(addr), Err(VaultError::InvalidArgument(msg))) = (addr, &res) {
+            self.guard.strike(addr.ip(), msg);
+        }
+        translate_result(res)
+    }
+
+    /// Check that share link `token` grants `file` of our local vault.
+    #[allow(clippy::result_large_err)]
+    fn check_share(&self, addr: Option<SocketAddr>, token: &str, file: u64) -> Result<(), Status> {
+        let key = match &self.share_key {
+            Some(key) => key,
+            None => return Err(Status::unimplemented("share links are disabled")),
+        };
+        let claim = self.check(addr, key.verify(token))?;
+        let granted = if claim.vault != self.local_name {
+            false
+        } else if claim.subtree {
+            let mut vault = self.local().lock().unwrap();
+            translate_result(
+                translate_result(unpack_to_local(&mut vault))?.is_within(file, claim.file),
+            )?
+        } else {
+            file == claim.file
+        };
+        if !granted {
+            // Probing for other files with a token is abuse too.
+            if let Some(addr) = addr {
+                self.guard
+                    .strike(addr.ip(), "share token used for another file");
+            }
+            return Err(Status::permission_denied(format!(
+                "share token doesn't grant file {}",
+                file
+            )));
+        }
+        Ok(())
+    }
+
+    /// Only serve admin commands to the host itself, and never
+    /// through a reverse proxy: behind one on the same host, every
+    /// request comes from loopback.
+    #[allow(clippy::result_large_err)]
+    fn check_admin(&self, client: Client) -> Result<(), Status> {
+        match client.addr {
+            Some(addr) if canonical(addr.ip()).is_loopback() && !client.proxied => Ok(()),
+            Some(addr) => Err(Status::permission_denied(format!(
+                "{} can't use admin commands",
+                addr
+            ))),
+            None => Err(Status::permission_denied(
+                "unknown clients can't use admin commands",
+            )),
+        }
+    }
+
+    /// Return the address `request` came from, see `ForwardedFor`.
+    fn client_addr<T>(&self, request: &Request<T>) -> Option<SocketAddr> {
+        self.forwarded.client(request).addr
+    }
 }
 
 /// Translate VaultFileType to rpc message field.
 fn kind2num(v: VaultFileType) -> i32 {
-    let k = match v {
+    match v {
         VaultFileType::File => 1,
         VaultFileType::Directory => 2,
-    };
-    return k;
+    }
 }
 
 /// Translate rpc message field to VaultFileType.
 fn num2kind(k: i32) -> VaultFileType {
     if k == 1 {
-        return VaultFileType::File;
+        VaultFileType::File
     } else {
-        return VaultFileType::Directory;
+        VaultFileType::Directory
+    }
+}
+
+/// Translate FileInfo to rpc message.
+fn pack_info(info: types::FileInfo) -> FileInfo {
+    FileInfo {
+        inode: info.inode,
+        name: info.name,
+        kind: kind2num(info.kind),
+        size: info.size,
+        atime: info.atime,
+        mtime: info.mtime,
+        major_ver: info.version.0,
+        minor_ver: info.version.1,
+        mode: info.mode,
+        nlink: info.nlink,
+        has_owner: info.owner.is_some(),
+        uid: info.owner.map(|(uid, _)| uid).unwrap_or(0),
+        gid: info.owner.map(|(_, gid)| gid).unwrap_or(0),
+        generation: info.generation,
+    }
+}
+
+/// Check that the `version` of a file we have is the one the
+/// requester of a savage picked, if it picked one. Otherwise the file
+/// changed since we offered it.
+fn check_offer(version: FileVersion, wanted: FileVersion, file: types::Inode) -> VaultResult<()> {
+    if wanted != (0, 0) && version != wanted {
+        debug!(
+            "savage({}) => have version {:?}, not {:?}",
+            file, version, wanted
+        );
+        return Err(VaultError::FileNotExist(file));
+    }
+    Ok(())
+}
+
+/// Return a stream that sends `data` in chunks, each tagged with
+/// `version`. Data is sent by a separate task, so we don't lock the
+/// vault while transferring on wire.
+fn stream_data(data: Vec<u8>, version: FileVersion) -> ReceiverStream<Result<DataChunk, Status>> {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut offset = 0;
+        let blk_size = GRPC_DATA_CHUNK_SIZE;
+        while offset < data.len() {
+            let end = std::cmp::min(offset + blk_size, data.len());
+            let reply = DataChunk {
+                payload: data[offset..end].to_vec(),
+                major_ver: version.0,
+                minor_ver: version.1,
+            };
+            tx.send(Ok(reply)).await.unwrap();
+            offset = end;
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// How many entries go in each message of the tree stream.
+const TREE_BATCH_SIZE: usize = 1024;
+
+/// Send every file under `dir` of `vault` on `tx`, breadth first, so
+/// parents come before their children. The vault is locked for one
+/// directory at a time, the walk of a large vault doesn't keep
+/// others from it.
+fn walk_tree(vault: VaultRef, dir: types::Inode, tx: mpsc::Sender<Result<TreeEntries, Status>>) {
+    let mut dirs = VecDeque::from([dir]);
+    let mut seen = HashSet::new();
+    let mut batch = vec![];
+    while let Some(dir) = dirs.pop_front() {
+        if !seen.insert(dir) {
+            continue;
+        }
+        let entries = match vault.lock().unwrap().readdir(dir) {
+            Ok(entries) => entries,
+            // Deleted since we listed its parent.
+            Err(VaultError::FileNotExist(_)) => continue,
+            Err(err) => {
+                let _ = tx.blocking_send(Err(pack_status(err)));
+                return;
+            }
+        };
+        for info in entries {
+            if let VaultFileType::Directory = info.kind {
+                dirs.push_back(info.inode);
+            }
+            batch.push(TreeEntry {
+                parent: dir,
+                info: Some(pack_info(info)),
+            });
+        }
+        if batch.len() >= TREE_BATCH_SIZE {
+            let list = std::mem::take(&mut batch);
+            // The receiver gave up.
+            if tx.blocking_send(Ok(TreeEntries { list })).is_err() {
+                return;
+            }
+        }
+    }
+    if !batch.is_empty() {
+        let _ = tx.blocking_send(Ok(TreeEntries { list: batch }));
     }
 }
 
 /// Translate some of the errors to status code and others to a
 /// catch-all status.
+#[allow(clippy::result_large_err)]
 fn translate_result<T>(res: VaultResult<T>) -> Result<T, Status> {
     match res {
         Ok(val) => Ok(val),
@@ -90,70 +508,191 @@ fn translate_result<T>(res: VaultResult<T>) -> Result<T, Status> {
 }
 
 fn pack_status(err: VaultError) -> Status {
-    let compressed_err: CompressedError = err.into();
+    let mut compressed_err: CompressedError = err.into();
+    if !newer_errors() {
+        compressed_err = compressed_err.for_older_peers();
+    }
     let encoded = serde_json::to_string(&compressed_err).unwrap();
     Status::not_found(encoded)
 }
 
+/// The chunks of a write stream received so far. The first chunk
+/// tells the file and the offset; each next chunk must be for the
+/// same file and continue right after the data we got, chunks that
+/// jump around would otherwise be written in the wrong place. Chunks
+/// that carry a hash must match it.
+#[derive(Debug, Default)]
+pub struct WriteStream {
+    pub file: u64,
+    pub offset: i64,
+    pub data: Vec<u8>,
+    /// Number of chunks received.
+    pub chunks: usize,
+    /// If true, the stream must start at offset 0.
+    from_start: bool,
+}
+
+impl WriteStream {
+    /// A stream that writes wherever its first chunk says.
+    pub fn new() -> WriteStream {
+        WriteStream::default()
+    }
+
+    /// A stream of a whole file, like submit sends.
+    pub fn from_start() -> WriteStream {
+        WriteStream {
+            from_start: true,
+            ..WriteStream::default()
+        }
+    }
+
+    /// Add the chunk with `data` for `file` at `offset`, hashed in
+    /// `algorithm` to `hash` unless `hash` is empty. Fail with
+    /// InvalidArgument if it doesn't continue the stream or is out of
+    /// bounds, and ChunkMismatch if the hash doesn't match.
+    pub fn push(
+        &mut self,
+        file: u64,
+        offset: i64,
+        mut data: Vec<u8>,
+        hash: &[u8],
+        algorithm: i32,
+    ) -> VaultResult<()> {
+        // The first chunk starts the stream.
+        let (start_file, start) = match (self.chunks, self.from_start) {
+            (0, false) => (file, offset),
+            (0, true) => (file, 0),
+            _ => (self.file, self.offset),
+        };
+        if file != start_file {
+            return Err(VaultError::InvalidArgument(format!(
+                "chunk for file {} in a stream for file {}",
+                file, start_file
+            )));
+        }
+        let expected = check_range(start, self.data.len() as u64)? + self.data.len() as u64;
+        if offset < 0 || offset as u64 != expected {
+            return Err(VaultError::InvalidArgument(format!(
+                "chunk at offset {}, expecting {}",
+                offset, expected
+            )));
+        }
+        check_range(offset, data.len() as u64)?;
+        if !hash.is_empty() {
+            let algorithm = HashAlgorithm::from_code(algorithm).ok_or_else(|| {
+                VaultError::InvalidArgument(format!("unknown hash algorithm {}", algorithm))
+            })?;
+            if hash != hash_chunk(algorithm, &data) {
+                return Err(VaultError::ChunkMismatch(file, expected));
+            }
+        }
+        self.file = start_file;
+        self.offset = start;
+        self.chunks += 1;
+        self.data.append(&mut data);
+        Ok(())
+    }
+}
+
+/// Translate a background operation to rpc message.
+fn pack_op(op: BackgroundOp) -> PendingOp {
+    let (kind, file, name, file_kind, version) = match op {
+        BackgroundOp::Delete(file) => (
+            pending_op::OpKind::Delete,
+            file,
+            String::new(),
+            VaultFileType::File,
+            (0, 0),
+        ),
+        BackgroundOp::Create(parent, name, kind) => {
+            (pending_op::OpKind::Create, parent, name, kind, (0, 0))
+        }
+        BackgroundOp::Upload(file, name, version) => (
+            pending_op::OpKind::Upload,
+            file,
+            name,
+            VaultFileType::File,
+            version,
+        ),
+    };
+    PendingOp {
+        op: kind as i32,
+        file,
+        name,
+        kind: kind2num(file_kind),
+        major_ver: version.0,
+        minor_ver: version.1,
+    }
+}
+
 #[async_trait]
 impl VaultRpc for VaultServer {
+    async fn identity(&self, _request: Request<Empty>) -> Result<Response<Identity>, Status> {
+        info!("identity()");
+        Ok(Response::new(Identity {
+            vault: self.local_name.clone(),
+            wide_sizes: true,
+            copies: self.copies.clone(),
+            lists_copies: true,
+            hash_algorithms: HashAlgorithm::ALL
+                .iter()
+                .map(|algorithm| algorithm.code())
+                .collect(),
+            sealed: self.local().lock().unwrap().sealed().unwrap_or(0),
+        }))
+    }
+
     async fn attr(&self, request: Request<Inode>) -> Result<Response<FileInfo>, Status> {
         let inner = request.into_inner();
         info!("attr({})", inner.value);
         let res = translate_result(self.local().lock().unwrap().attr(inner.value))?;
-        Ok(Response::new(FileInfo {
-            inode: res.inode,
-            name: res.name,
-            kind: kind2num(res.kind),
-            size: res.size,
-            atime: res.atime,
-            mtime: res.mtime,
-            major_ver: res.version.0,
-            minor_ver: res.version.1,
-        }))
+        Ok(Response::new(pack_info(res)))
     }
     type readStream = ReceiverStream<Result<DataChunk, Status>>;
     type savageStream = ReceiverStream<Result<DataChunk, Status>>;
+    type read_sharedStream = ReceiverStream<Result<DataChunk, Status>>;
+    type treeStream = ReceiverStream<Result<TreeEntries, Status>>;
 
     async fn read(
         &self,
         request: Request<FileToRead>,
     ) -> Result<Response<Self::readStream>, Status> {
+        let addr = self.client_addr(&request);
         let request_inner = request.into_inner();
         info!(
             "read(file={}, offset={}, size={})",
             request_inner.file, request_inner.offset, request_inner.size
         );
+        self.check(addr, check_range(request_inner.offset, request_inner.size))?;
+        let size = std::cmp::min(request_inner.size, MAX_READ_SIZE);
         // Don't lock the vault when transferring data on wire. Get
         // data and version from local vault.
         let (data, version) = {
             let mut vault = self.local().lock().unwrap();
-            let data = translate_result(vault.read(
-                request_inner.file,
-                request_inner.offset,
-                request_inner.size,
-            ))?;
+            let data =
+                translate_result(vault.read(request_inner.file, request_inner.offset, size))?;
             let version = translate_result(vault.attr(request_inner.file))?.version;
             (data, version)
         };
-        // Create the stream that sends messages.
-        let (tx, rx) = mpsc::channel(1);
-        tokio::spawn(async move {
-            let mut offset = request_inner.offset as usize;
-            let blk_size = GRPC_DATA_CHUNK_SIZE;
-            while offset < data.len() {
-                let end = std::cmp::min(offset + blk_size, data.len());
-                let reply = DataChunk {
-                    payload: data[offset..end].to_vec(),
-                    major_ver: version.0,
-                    minor_ver: version.1,
-                };
-                tx.send(Ok(reply)).await.unwrap();
-                offset = end;
-            }
-        });
-        // Return the stream.
-        Ok(Response::new(ReceiverStream::new(rx)))
+        Ok(Response::new(stream_data(data, version)))
+    }
+
+    async fn savage_offer(&self, request: Request<Grail>) -> Result<Response<SavageOffer>, Status> {
+        let req = request.into_inner();
+        info!("savage_offer(vault={}, file={})", req.vault, req.file);
+        let vault = translate_result(self.savage_source(&req.vault, req.file))?;
+        let mut vault = vault.lock().unwrap();
+        let result = match &mut *vault {
+            GenericVault::Local(vault) => vault.cached_version(req.file),
+            GenericVault::Caching(vault) => vault.cached_version(req.file),
+            GenericVault::Remote(_) => unreachable!(),
+        };
+        let (version, size) = translate_result(result)?;
+        Ok(Response::new(SavageOffer {
+            major_ver: version.0,
+            minor_ver: version.1,
+            size,
+        }))
     }
 
     async fn savage(
@@ -161,76 +700,83 @@ impl VaultRpc for VaultServer {
         request: Request<Grail>,
     ) -> Result<Response<Self::savageStream>, Status> {
         let req = request.into_inner();
-        info!("savage(vault={}, file={})", req.vault, req.file);
+        info!(
+            "savage(vault={}, file={}, version=({}, {}))",
+            req.vault, req.file, req.major_ver, req.minor_ver
+        );
+        let wanted = (req.major_ver, req.minor_ver);
         // Get data and version from the caching remote vault.
-        let result: VaultResult<(Vec<u8>, FileVersion)> = {
-            match self.vault_map.get(&req.vault) {
-                None => {
-                    debug!("We don't know this vault");
-                    Err(VaultError::FileNotExist(req.file))
-                }
-                Some(vault) => {
-                    let mut vault = vault.lock().unwrap();
-                    match &mut *vault {
-                        GenericVault::Local(vault) => vault.search_in_cache(req.file),
-                        GenericVault::Caching(vault) => vault.search_in_cache(req.file),
-                        GenericVault::Remote(_) => {
-                            debug!("Cannot serve savage request because we are not caching");
-                            Err(VaultError::WrongTypeOfVault("caching/local".to_string()))
-                        }
+        let result: VaultResult<(Vec<u8>, FileVersion)> =
+            self.savage_source(&req.vault, req.file).and_then(|vault| {
+                let mut vault = vault.lock().unwrap();
+                match &mut *vault {
+                    GenericVault::Local(vault) => {
+                        check_offer(vault.cached_version(req.file)?.0, wanted, req.file)?;
+                        vault.search_in_cache(req.file)
                     }
+                    GenericVault::Caching(vault) => {
+                        check_offer(vault.cached_version(req.file)?.0, wanted, req.file)?;
+                        vault.search_in_cache(req.file)
+                    }
+                    GenericVault::Remote(_) => unreachable!(),
                 }
-            }
-        };
+            });
         if let Err(VaultError::FileNotExist(_)) = result {
             debug!("We can't find the file in cache");
         }
         let (data, version) = translate_result(result)?;
         debug!("We find the file in cache!");
-        let (sender, recver) = mpsc::channel(1);
-        tokio::spawn(async move {
-            let mut offset = 0;
-            let blk_size = GRPC_DATA_CHUNK_SIZE;
-            while offset < data.len() {
-                let end = std::cmp::min(offset + blk_size, data.len());
-                let reply = DataChunk {
-                    payload: data[offset..end].to_vec(),
-                    major_ver: version.0,
-                    minor_ver: version.1,
-                };
-                sender.send(Ok(reply)).await.unwrap();
-                offset = end;
-            }
-        });
-        Ok(Response::new(ReceiverStream::new(recver)))
+        Ok(Response::new(stream_data(data, version)))
     }
 
     async fn write(
         &self,
         request: Request<Streaming<FileToWrite>>,
     ) -> Result<Response<Size>, Status> {
+        let addr = self.client_addr(&request);
         let mut stream = request.into_inner();
-        let mut counter = 0;
-        let mut data: Vec<u8> = vec![];
-        let mut inode = 0;
-        let mut offset = 0;
-        while let Some(mut file) = stream.message().await? {
+        let mut chunks = WriteStream::new();
+        let mut append = false;
+        while let Some(file) = stream.message().await? {
             info!(
-                "write[{}](file={}, offset={}, size={})",
-                counter,
+                "write[{}](file={}, offset={}, size={}, append={})",
+                chunks.chunks,
                 file.file,
                 file.offset,
-                file.data.len()
+                file.data.len(),
+                file.append
+            );
+            if chunks.chunks == 0 {
+                append = file.append;
+            }
+            self.check(
+                addr,
+                chunks.push(
+                    file.file,
+                    file.offset,
+                    file.data,
+                    &file.hash,
+                    file.hash_algorithm,
+                ),
+            )?;
+        }
+        if chunks.chunks == 0 {
+            return self.check(
+                addr,
+                Err(VaultError::InvalidArgument(
+                    "empty write stream".to_string(),
+                )),
             );
-            counter += 1;
-            inode = file.file;
-            offset = file.offset;
-            data.append(&mut file.data);
         }
         // FIXME: write to tmp file by chunk so we don't eat memory.
         // This way we don't lock the vault when transferring packets on wire.
         let mut vault = self.local().lock().unwrap();
-        let size = translate_result(vault.write(inode, offset, &data))?;
+        let result = if append {
+            vault.append(chunks.file, &chunks.data)
+        } else {
+            vault.write(chunks.file, chunks.offset, &chunks.data)
+        };
+        let size = translate_result(result)?;
         Ok(Response::new(Size { value: size }))
     }
 
@@ -238,35 +784,61 @@ impl VaultRpc for VaultServer {
         &self,
         request: Request<Streaming<FileToWrite>>,
     ) -> Result<Response<Acceptance>, Status> {
+        let addr = self.client_addr(&request);
         let mut stream = request.into_inner();
-        let mut counter = 0;
-        let mut data: Vec<u8> = vec![];
-        let mut inode = 0;
-        let mut offset = 0;
+        // Submit always sends the whole file.
+        let mut chunks = WriteStream::from_start();
         let mut version = (1, 0);
-        while let Some(mut file) = stream.message().await? {
+        let mut mtime = 0;
+        while let Some(file) = stream.message().await? {
             info!(
                 "submit[{}](file={}, offset={}, size={})",
-                counter,
+                chunks.chunks,
                 file.file,
                 file.offset,
                 file.data.len()
             );
-            counter += 1;
-            inode = file.file;
-            offset = file.offset;
-            data.append(&mut file.data);
             version = (file.major_ver, file.minor_ver);
+            mtime = file.mtime;
+            self.check(
+                addr,
+                chunks.push(
+                    file.file,
+                    file.offset,
+                    file.data,
+                    &file.hash,
+                    file.hash_algorithm,
+                ),
+            )?;
+        }
+        if chunks.chunks == 0 {
+            return self.check(
+                addr,
+                Err(VaultError::InvalidArgument(
+                    "empty submit stream".to_string(),
+                )),
+            );
         }
         // FIXME: write to tmp file by chunk so we don't eat memory.
         // This way we don't lock the vault when transferring packets on wire.
         let mut vault = self.local().lock().unwrap();
-        let success = translate_result(
-            translate_result(unpack_to_local(&mut vault))?.submit(inode, &data, version),
-        )?;
+        let success = translate_result(translate_result(unpack_to_local(&mut vault))?.submit(
+            chunks.file,
+            &chunks.data,
+            version,
+            if mtime == 0 { None } else { Some(mtime) },
+        ))?;
         Ok(Response::new(Acceptance { flag: success }))
     }
 
+    async fn truncate(&self, request: Request<FileSize>) -> Result<Response<Empty>, Status> {
+        let inner = request.into_inner();
+        info!("truncate(file={}, size={})", inner.file, inner.size);
+        let mut vault = self.local().lock().unwrap();
+        translate_result(vault.truncate(inner.file, inner.size))?;
+        Ok(Response::new(Empty {}))
+    }
+
     async fn create(&self, request: Request<FileToCreate>) -> Result<Response<Inode>, Status> {
         let request_inner = request.into_inner();
         info!(
@@ -304,6 +876,46 @@ impl VaultRpc for VaultServer {
         Ok(Response::new(Empty {}))
     }
 
+    async fn set_mode(&self, request: Request<FileMode>) -> Result<Response<Empty>, Status> {
+        let inner = request.into_inner();
+        info!("set_mode(file={}, mode={:o})", inner.file, inner.mode);
+        let mut vault = self.local().lock().unwrap();
+        translate_result(vault.set_mode(inner.file, inner.mode))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn set_owner(&self, request: Request<FileOwner>) -> Result<Response<Empty>, Status> {
+        let inner = request.into_inner();
+        info!(
+            "set_owner(file={}, uid={}, gid={})",
+            inner.file, inner.uid, inner.gid
+        );
+        let mut vault = self.local().lock().unwrap();
+        translate_result(vault.set_owner(inner.file, inner.uid, inner.gid))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn set_times(&self, request: Request<FileTimes>) -> Result<Response<Empty>, Status> {
+        let inner = request.into_inner();
+        let atime = if inner.has_atime {
+            Some(inner.atime)
+        } else {
+            None
+        };
+        let mtime = if inner.has_mtime {
+            Some(inner.mtime)
+        } else {
+            None
+        };
+        info!(
+            "set_times(file={}, atime={:?}, mtime={:?})",
+            inner.file, atime, mtime
+        );
+        let mut vault = self.local().lock().unwrap();
+        translate_result(vault.set_times(inner.file, atime, mtime))?;
+        Ok(Response::new(Empty {}))
+    }
+
     async fn delete(&self, request: Request<Inode>) -> Result<Response<Empty>, Status> {
         let inner = request.into_inner();
         info!("delete({})", inner.value);
@@ -312,6 +924,288 @@ impl VaultRpc for VaultServer {
         Ok(Response::new(Empty {}))
     }
 
+    async fn rename(&self, request: Request<FileToRename>) -> Result<Response<Empty>, Status> {
+        let inner = request.into_inner();
+        info!(
+            "rename(file={}, parent={}, name={})",
+            inner.file, inner.parent, inner.name
+        );
+        let mut vault = self.local().lock().unwrap();
+        translate_result(vault.rename(inner.file, inner.parent, &inner.name))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn link(&self, request: Request<FileToLink>) -> Result<Response<Empty>, Status> {
+        let inner = request.into_inner();
+        info!(
+            "link(file={}, parent={}, name={})",
+            inner.file, inner.parent, inner.name
+        );
+        let mut vault = self.local().lock().unwrap();
+        translate_result(vault.link(inner.file, inner.parent, &inner.name))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn unlink(&self, request: Request<FileToLink>) -> Result<Response<Empty>, Status> {
+        let inner = request.into_inner();
+        info!(
+            "unlink(file={}, parent={}, name={})",
+            inner.file, inner.parent, inner.name
+        );
+        let mut vault = self.local().lock().unwrap();
+        translate_result(vault.unlink(inner.file, inner.parent, &inner.name))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn xattr(&self, request: Request<Xattr>) -> Result<Response<XattrValue>, Status> {
+        let inner = request.into_inner();
+        debug!("xattr(file={}, name={})", inner.file, inner.name);
+        let mut vault = self.local().lock().unwrap();
+        let value = translate_result(vault.xattr(inner.file, &inner.name))?;
+        Ok(Response::new(XattrValue {
+            present: value.is_some(),
+            value: value.unwrap_or_default(),
+        }))
+    }
+
+    async fn xattr_names(&self, request: Request<Inode>) -> Result<Response<XattrNames>, Status> {
+        let inner = request.into_inner();
+        debug!("xattr_names({})", inner.value);
+        let mut vault = self.local().lock().unwrap();
+        let names = translate_result(vault.xattr_names(inner.value))?;
+        Ok(Response::new(XattrNames { names }))
+    }
+
+    async fn set_xattr(&self, request: Request<Xattr>) -> Result<Response<Empty>, Status> {
+        let inner = request.into_inner();
+        info!("set_xattr(file={}, name={})", inner.file, inner.name);
+        let mut vault = self.local().lock().unwrap();
+        translate_result(vault.set_xattr(inner.file, &inner.name, &inner.value))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn remove_xattr(&self, request: Request<Xattr>) -> Result<Response<Empty>, Status> {
+        let inner = request.into_inner();
+        info!("remove_xattr(file={}, name={})", inner.file, inner.name);
+        let mut vault = self.local().lock().unwrap();
+        translate_result(vault.remove_xattr(inner.file, &inner.name))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn delete_tree(&self, request: Request<TreeToDelete>) -> Result<Response<Count>, Status> {
+        let inner = request.into_inner();
+        info!("delete_tree(dir={}, limit={})", inner.dir, inner.limit);
+        // Don't let peers ask for more than we are willing to delete.
+        let limit = std::cmp::min(inner.limit, MAX_TREE_DELETE);
+        let mut vault = self.local().lock().unwrap();
+        let count = translate_result(vault.delete_tree(inner.dir, limit))?;
+        Ok(Response::new(Count { value: count }))
+    }
+
+    async fn info(&self, request: Request<Inode>) -> Result<Response<DirInfo>, Status> {
+        let inner = request.into_inner();
+        info!("info({})", inner.value);
+        let mut vault = self.local().lock().unwrap();
+        let info = translate_result(vault.info(inner.value))?;
+        Ok(Response::new(DirInfo {
+            size: info.size,
+            count: info.count,
+        }))
+    }
+
+    async fn getlk(&self, request: Request<FileLock>) -> Result<Response<LockConflict>, Status> {
+        let addr = self.client_addr(&request);
+        let (file, mut lock) = self.check(addr, locks::unpack(request.into_inner()))?;
+        lock.owner.host = locks::peer_host(addr, &lock.owner.host);
+        debug!("getlk(file={}, lock={:?})", file, lock);
+        let mut vault = self.local().lock().unwrap();
+        let conflict = translate_result(vault.getlk(file, &lock))?;
+        Ok(Response::new(LockConflict {
+            found: conflict.is_some(),
+            lock: conflict.map(|lock| locks::pack(file, &lock)),
+        }))
+    }
+
+    async fn setlk(&self, request: Request<FileLock>) -> Result<Response<Empty>, Status> {
+        let addr = self.client_addr(&request);
+        let (file, mut lock) = self.check(addr, locks::unpack(request.into_inner()))?;
+        lock.owner.host = locks::peer_host(addr, &lock.owner.host);
+        info!("setlk(file={}, lock={:?})", file, lock);
+        let mut vault = self.local().lock().unwrap();
+        translate_result(vault.setlk(file, &lock))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn statfs(&self, _request: Request<Empty>) -> Result<Response<FsStats>, Status> {
+        info!("statfs()");
+        let mut vault = self.local().lock().unwrap();
+        let stats = translate_result(vault.statfs())?;
+        Ok(Response::new(FsStats {
+            total: stats.total,
+            free: stats.free,
+            available: stats.available,
+            files: stats.files,
+            free_files: stats.free_files,
+        }))
+    }
+
+    async fn manifest(
+        &self,
+        request: Request<ManifestRequest>,
+    ) -> Result<Response<Manifest>, Status> {
+        let inner = request.into_inner();
+        info!("manifest({}, {:?})", inner.file, inner.algorithms);
+        let known: Vec<HashAlgorithm> = inner
+            .algorithms
+            .iter()
+            .filter_map(|&code| HashAlgorithm::from_code(code))
+            .collect();
+        let mut vault = self.local().lock().unwrap();
+        let manifest = translate_result(
+            translate_result(unpack_to_local(&mut vault))?.manifest(inner.file, &known),
+        )?;
+        Ok(Response::new(Manifest {
+            major_ver: manifest.version.0,
+            minor_ver: manifest.version.1,
+            size: manifest.size,
+            chunks: manifest.chunks,
+            algorithm: manifest.algorithm.code(),
+        }))
+    }
+
+    async fn pending(&self, request: Request<Empty>) -> Result<Response<PendingList>, Status> {
+        info!("pending()");
+        self.check_admin(self.forwarded.client(&request))?;
+        let mut list = vec![];
+        for vault_lck in self.vault_map.values() {
+            if let GenericVault::Caching(vault) = &*vault_lck.lock().unwrap() {
+                let pending = vault.pending();
+                list.push(VaultPending {
+                    vault: pending.vault,
+                    dry_run: pending.dry_run,
+                    ops: pending.ops.into_iter().map(pack_op).collect(),
+                    upload_size: pending.upload_size,
+                });
+            }
+        }
+        Ok(Response::new(PendingList { list }))
+    }
+
+    async fn set_dry_run(&self, request: Request<DryRun>) -> Result<Response<Empty>, Status> {
+        self.check_admin(self.forwarded.client(&request))?;
+        let flag = request.into_inner().flag;
+        info!("set_dry_run({})", flag);
+        for vault_lck in self.vault_map.values() {
+            if let GenericVault::Caching(vault) = &*vault_lck.lock().unwrap() {
+                vault.set_dry_run(flag);
+            }
+        }
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn bans(&self, request: Request<Empty>) -> Result<Response<BanList>, Status> {
+        info!("bans()");
+        self.check_admin(self.forwarded.client(&request))?;
+        Ok(Response::new(BanList {
+            list: self
+                .guard
+                .bans()
+                .into_iter()
+                .map(|ban| BanEntry {
+                    addr: ban.addr.to_string(),
+                    reason: ban.reason,
+                    remaining_secs: ban.remaining_secs,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn clear_bans(&self, request: Request<BanToClear>) -> Result<Response<Count>, Status> {
+        self.check_admin(self.forwarded.client(&request))?;
+        let inner = request.into_inner();
+        info!("clear_bans({})", inner.addr);
+        let addr = if inner.addr.is_empty() {
+            None
+        } else {
+            Some(translate_result(inner.addr.parse().map_err(|_| {
+                VaultError::InvalidArgument(format!("bad address {}", inner.addr))
+            }))?)
+        };
+        Ok(Response::new(Count {
+            value: self.guard.clear(addr) as u64,
+        }))
+    }
+
+    async fn log_filter(&self, request: Request<LogFilter>) -> Result<Response<LogFilter>, Status> {
+        self.check_admin(self.forwarded.client(&request))?;
+        let inner = request.into_inner();
+        info!("log_filter({}, {})", inner.spec, inner.reset);
+        if inner.reset {
+            log_filter::reset();
+        }
+        if !inner.spec.is_empty() {
+            translate_result(log_filter::set(&inner.spec))?;
+        }
+        Ok(Response::new(LogFilter {
+            spec: log_filter::current(),
+            reset: false,
+        }))
+    }
+
+    async fn connections(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ConnectionEventList>, Status> {
+        info!("connections()");
+        self.check_admin(self.forwarded.client(&request))?;
+        Ok(Response::new(ConnectionEventList {
+            list: self
+                .connections
+                .events()
+                .into_iter()
+                .map(|event| ConnectionEvent {
+                    peer: event.peer,
+                    kind: event.kind.name().to_string(),
+                    time_ms: event.time_ms,
+                    detail: event.detail,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn path_of(&self, request: Request<FileOfVault>) -> Result<Response<FilePath>, Status> {
+        self.check_admin(self.forwarded.client(&request))?;
+        let inner = request.into_inner();
+        info!("path_of({}, {})", inner.vault, inner.file);
+        let vault_lck = translate_result(
+            self.vault_map
+                .get(&inner.vault)
+                .ok_or(VaultError::CannotFindVaultByName(inner.vault)),
+        )?;
+        let path = translate_result(vault_lck.lock().unwrap().path_of(inner.file))?;
+        Ok(Response::new(FilePath { path }))
+    }
+
+    async fn seal(&self, request: Request<SealRequest>) -> Result<Response<SealTime>, Status> {
+        self.check_admin(self.forwarded.client(&request))?;
+        info!("seal()");
+        // There is no unsealing, so coming from the host isn't
+        // enough, it takes the owner of the store.
+        let proof = request.into_inner().proof;
+        let owner = match &self.share_key {
+            Some(key) => key.check_proof(SEAL_COMMAND, &proof),
+            None => false,
+        };
+        if !owner {
+            return Err(Status::permission_denied(
+                "sealing takes a proof from the owner of the store",
+            ));
+        }
+        let mut vault = self.local().lock().unwrap();
+        let time = translate_result(translate_result(unpack_to_local(&mut vault))?.seal())?;
+        Ok(Response::new(SealTime { time }))
+    }
+
     async fn readdir(&self, request: Request<Inode>) -> Result<Response<DirEntryList>, Status> {
         let inner = request.into_inner();
         info!("readdir({})", inner.value);
@@ -319,19 +1213,89 @@ impl VaultRpc for VaultServer {
         let entries = translate_result(vault.readdir(inner.value))?;
 
         Ok(Response::new(DirEntryList {
-            list: entries
-                .into_iter()
-                .map(|e| FileInfo {
-                    inode: e.inode,
-                    name: e.name,
-                    kind: kind2num(e.kind),
-                    size: e.size,
-                    atime: e.atime,
-                    mtime: e.mtime,
-                    major_ver: e.version.0,
-                    minor_ver: e.version.1,
-                })
-                .collect(),
+            list: entries.into_iter().map(pack_info).collect(),
+        }))
+    }
+
+    async fn readdir_columns(
+        &self,
+        request: Request<Inode>,
+    ) -> Result<Response<DirEntryColumns>, Status> {
+        let inner = request.into_inner();
+        info!("readdir_columns({})", inner.value);
+        let mut vault = self.local().lock().unwrap();
+        let entries = translate_result(vault.readdir(inner.value))?;
+        Ok(Response::new(dir_columns::encode(&entries)))
+    }
+
+    async fn tree(&self, request: Request<Inode>) -> Result<Response<Self::treeStream>, Status> {
+        let inner = request.into_inner();
+        info!("tree({})", inner.value);
+        // Fail now rather than in the stream if there's no such
+        // directory.
+        let info = translate_result(self.local().lock().unwrap().attr(inner.value))?;
+        if let VaultFileType::File = info.kind {
+            return Err(pack_status(VaultError::NotDirectory(inner.value)));
+        }
+        let vault = Arc::clone(self.local());
+        let (tx, rx) = mpsc::channel(4);
+        let newer = newer_errors();
+        tokio::task::spawn_blocking(move || {
+            NEWER_ERRORS.sync_scope(newer, || walk_tree(vault, inner.value, tx))
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn attr_shared(
+        &self,
+        request: Request<SharedFile>,
+    ) -> Result<Response<FileInfo>, Status> {
+        let addr = self.client_addr(&request);
+        let inner = request.into_inner();
+        info!("attr_shared({})", inner.file);
+        self.check_share(addr, &inner.token, inner.file)?;
+        let res = translate_result(self.local().lock().unwrap().attr(inner.file))?;
+        Ok(Response::new(pack_info(res)))
+    }
+
+    async fn readdir_shared(
+        &self,
+        request: Request<SharedFile>,
+    ) -> Result<Response<DirEntryList>, Status> {
+        let addr = self.client_addr(&request);
+        let inner = request.into_inner();
+        info!("readdir_shared({})", inner.file);
+        self.check_share(addr, &inner.token, inner.file)?;
+        let entries = translate_result(self.local().lock().unwrap().readdir(inner.file))?;
+        Ok(Response::new(DirEntryList {
+            list: entries.into_iter().map(pack_info).collect(),
         }))
     }
+
+    async fn read_shared(
+        &self,
+        request: Request<SharedRead>,
+    ) -> Result<Response<Self::read_sharedStream>, Status> {
+        let addr = self.client_addr(&request);
+        let inner = request.into_inner();
+        info!(
+            "read_shared(file={}, offset={}, size={})",
+            inner.file, inner.offset, inner.size
+        );
+        self.check_share(addr, &inner.token, inner.file)?;
+        self.check(addr, check_range(inner.offset, inner.size))?;
+        let size = std::cmp::min(inner.size, MAX_READ_SIZE);
+        // The receiver isn't a peer and doesn't open files, open it
+        // for the duration of the read.
+        let (data, version) = {
+            let mut vault = self.local().lock().unwrap();
+            translate_result(vault.open(inner.file, OpenMode::R))?;
+            let result = vault.read(inner.file, inner.offset, size);
+            translate_result(vault.close(inner.file))?;
+            let data = translate_result(result)?;
+            let version = translate_result(vault.attr(inner.file))?.version;
+            (data, version)
+        };
+        Ok(Response::new(stream_data(data, version)))
+    }
 }
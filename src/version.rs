@@ -0,0 +1,140 @@
+/// Track which files are modified or forked and compute their next
+/// version on close. Both LocalVault and CachingVault use this.
+use crate::types::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// A file's version is (major, minor). Closing a modified file bumps
+// the minor version, unless someone copied the file since the last
+// bump (a "fork": another host savaged it from us, or submitted its
+// copy to us), in which case we bump the major version and reset the
+// minor version. Hosts compare major versions to decide whether to
+// fetch, so a fork must always produce a new major version.
+//
+// Each file is in one of four states:
+//
+//     Clean --write--> Dirty --fork--> DirtyForked
+//       |                                   ^
+//       +---fork---> Forked ----write-------+
+//
+// and `commit` (called on the last close) brings Dirty and
+// DirtyForked back to Clean, returning the new version. Clean and
+// Forked files are unchanged by commit.
+
+/// Modification state of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileState {
+    /// Not modified since the last version bump.
+    Clean,
+    /// Modified.
+    Dirty,
+    /// Not modified, but copied by another host.
+    Forked,
+    /// Modified and copied by another host.
+    DirtyForked,
+}
+
+impl FileState {
+    /// State after the file is written.
+    pub fn write(self) -> FileState {
+        match self {
+            FileState::Clean | FileState::Dirty => FileState::Dirty,
+            FileState::Forked | FileState::DirtyForked => FileState::DirtyForked,
+        }
+    }
+
+    /// State after the file is copied by another host.
+    pub fn fork(self) -> FileState {
+        match self {
+            FileState::Clean | FileState::Forked => FileState::Forked,
+            FileState::Dirty | FileState::DirtyForked => FileState::DirtyForked,
+        }
+    }
+
+    /// Return true if the file is modified.
+    pub fn dirty(self) -> bool {
+        matches!(self, FileState::Dirty | FileState::DirtyForked)
+    }
+
+    /// Return the state after commit and the new version, if
+    /// `version` is the current version.
+    pub fn commit(self, version: FileVersion) -> (FileState, FileVersion) {
+        match self {
+            FileState::Clean | FileState::Forked => (self, version),
+            FileState::Dirty => (FileState::Clean, (version.0, version.1 + 1)),
+            FileState::DirtyForked => (FileState::Clean, (version.0 + 1, 0)),
+        }
+    }
+}
+
+/// Tracks the state of each file. All transitions happen under a
+/// single lock, so a fork can't slip between reading the state and
+/// resetting it on close.
+#[derive(Debug)]
+pub struct VersionTracker {
+    states: Mutex<HashMap<Inode, FileState>>,
+}
+
+impl Default for VersionTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VersionTracker {
+    pub fn new() -> VersionTracker {
+        VersionTracker {
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the state of `file`.
+    pub fn state(&self, file: Inode) -> FileState {
+        match self.states.lock().unwrap().get(&file) {
+            Some(&state) => state,
+            None => FileState::Clean,
+        }
+    }
+
+    /// Apply `transition` to the state of `file`.
+    fn update(&self, file: Inode, transition: impl FnOnce(FileState) -> FileState) {
+        let mut map = self.states.lock().unwrap();
+        let state = transition(map.get(&file).copied().unwrap_or(FileState::Clean));
+        if state == FileState::Clean {
+            map.remove(&file);
+        } else {
+            map.insert(file, state);
+        }
+    }
+
+    /// Record that `file` is written.
+    pub fn write(&self, file: Inode) {
+        self.update(file, FileState::write)
+    }
+
+    /// Record that `file` is copied by another host, so next change
+    /// bumps the major version.
+    pub fn fork(&self, file: Inode) {
+        self.update(file, FileState::fork)
+    }
+
+    /// Return true if `file` is modified.
+    pub fn dirty(&self, file: Inode) -> bool {
+        self.state(file).dirty()
+    }
+
+    /// Called on the last close of `file`. If `file` is modified,
+    /// mark it clean and return its new version computed from
+    /// `version`, otherwise return None.
+    pub fn commit(&self, file: Inode, version: FileVersion) -> Option<FileVersion> {
+        let mut new_version = None;
+        self.update(file, |state| {
+            let (next, next_version) = state.commit(version);
+            if state.dirty() {
+                new_version = Some(next_version);
+            }
+            next
+        });
+        new_version
+    }
+}
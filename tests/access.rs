@@ -0,0 +1,104 @@
+/// Permission checks for access(2), see `fuse::may_access`, and for
+/// deleting trees, see `fuse::may_delete`.
+use monovault::fuse::{may_access, may_delete};
+use monovault::types::*;
+
+const R: i32 = libc::R_OK;
+const W: i32 = libc::W_OK;
+const X: i32 = libc::X_OK;
+
+fn info(kind: VaultFileType, mode: u32) -> FileInfo {
+    FileInfo {
+        inode: 2,
+        name: "note".to_string(),
+        kind,
+        size: 0,
+        atime: 0,
+        mtime: 0,
+        version: (1, 0),
+        mode,
+        nlink: 1,
+        owner: Some((1000, 100)),
+        generation: 1,
+    }
+}
+
+#[test]
+fn owner_group_and_others() {
+    let file = info(VaultFileType::File, 0o640);
+    assert!(may_access(&file, 1000, &[100], R | W));
+    assert!(!may_access(&file, 1000, &[100], X));
+    // Group members, by primary or supplementary group.
+    assert!(may_access(&file, 1001, &[100], R));
+    assert!(may_access(&file, 1001, &[20, 100], R));
+    assert!(!may_access(&file, 1001, &[100], W));
+    // Others.
+    assert!(!may_access(&file, 1001, &[20], R));
+    assert!(may_access(&file, 1001, &[20], libc::F_OK));
+
+    let dir = info(VaultFileType::Directory, 0o750);
+    assert!(may_access(&dir, 1001, &[100], R | X));
+    assert!(!may_access(&dir, 1001, &[20], X));
+}
+
+#[test]
+fn root_executes_only_executables() {
+    let file = info(VaultFileType::File, 0o600);
+    assert!(may_access(&file, 0, &[0], R | W));
+    assert!(!may_access(&file, 0, &[0], X));
+    assert!(may_access(&info(VaultFileType::File, 0o700), 0, &[0], X));
+    assert!(may_access(
+        &info(VaultFileType::Directory, 0o000),
+        0,
+        &[0],
+        X
+    ));
+}
+
+#[test]
+fn deleting_takes_write_and_search_on_the_parent() {
+    let entry = info(VaultFileType::Directory, 0o755);
+    assert!(may_delete(
+        &info(VaultFileType::Directory, 0o755),
+        &entry,
+        1000,
+        &[100]
+    ));
+    // Group members can't write, others can't search.
+    assert!(!may_delete(
+        &info(VaultFileType::Directory, 0o755),
+        &entry,
+        1001,
+        &[100]
+    ));
+    assert!(!may_delete(
+        &info(VaultFileType::Directory, 0o776),
+        &entry,
+        1001,
+        &[20]
+    ));
+    assert!(may_delete(
+        &info(VaultFileType::Directory, 0o777),
+        &entry,
+        1001,
+        &[20]
+    ));
+    assert!(may_delete(
+        &info(VaultFileType::Directory, 0o500),
+        &entry,
+        0,
+        &[0]
+    ));
+}
+
+#[test]
+fn sticky_parents_take_an_owner() {
+    let parent = info(VaultFileType::Directory, 0o1777);
+    let mut entry = info(VaultFileType::Directory, 0o755);
+    entry.owner = Some((1002, 100));
+    // The owner of the directory, of the parent, or root.
+    assert!(may_delete(&parent, &entry, 1002, &[100]));
+    assert!(may_delete(&parent, &entry, 1000, &[100]));
+    assert!(may_delete(&parent, &entry, 0, &[0]));
+    assert!(!may_delete(&parent, &entry, 1001, &[100]));
+}
@@ -0,0 +1,52 @@
+/// Writes to the end of a file, see `Vault::append`.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn append(vault: &VaultRef, file: Inode, data: &[u8]) {
+    assert_eq!(
+        vault.lock().unwrap().append(file, data).unwrap(),
+        data.len() as u64
+    );
+}
+
+#[test]
+fn appenders_over_the_network() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let file = create_file(&alice.local, ROOT, "log", b"start\n");
+    let remote = cluster.node("bob").remote_of("alice");
+    for vault in [&alice.local, &remote] {
+        vault.lock().unwrap().open(file, OpenMode::RW).unwrap();
+    }
+    // Neither appender overwrites what the other wrote.
+    for _ in 0..3 {
+        append(&alice.local, file, b"a\n");
+        append(&remote, file, b"b\n");
+    }
+    for vault in [&alice.local, &remote] {
+        vault.lock().unwrap().close(file).unwrap();
+    }
+    assert_eq!(
+        read_file(&alice.local, file).unwrap(),
+        b"start\na\nb\na\nb\na\nb\n"
+    );
+}
+
+#[test]
+fn cache_appends_to_its_copy() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "log", b"start\n");
+    let cache = bob.cache_of("alice");
+    cache.lock().unwrap().open(file, OpenMode::RW).unwrap();
+    append(&cache, file, b"b\n");
+    append(&cache, file, b"b\n");
+    cache.lock().unwrap().close(file).unwrap();
+    assert!(bob.wait_synced("alice"));
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"start\nb\nb\n");
+}
@@ -0,0 +1,62 @@
+/// Access times updated by reads, see `AtimePolicy`.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+const DAY: u64 = RELATIME_INTERVAL;
+
+fn atime(vault: &VaultRef, file: Inode) -> u64 {
+    vault.lock().unwrap().attr(file).unwrap().atime
+}
+
+#[test]
+fn relatime_policy() {
+    let policy = AtimePolicy::Relatime;
+    // Not read since it changed.
+    assert!(policy.should_update(100, 200, 300));
+    // Read since, and recently.
+    assert!(!policy.should_update(300, 200, 400));
+    assert!(policy.should_update(300, 200, 300 + DAY));
+    assert!(AtimePolicy::Strictatime.should_update(300, 200, 400));
+    assert!(!AtimePolicy::Noatime.should_update(100, 200, 300));
+}
+
+#[test]
+fn reads_update_atime() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let local = &cluster.node("alice").local;
+    let file = create_file(local, ROOT, "note", b"hello");
+    local
+        .lock()
+        .unwrap()
+        .set_times(file, Some(1000), Some(2000))
+        .unwrap();
+    // Opening without reading leaves it alone.
+    local.lock().unwrap().open(file, OpenMode::R).unwrap();
+    local.lock().unwrap().close(file).unwrap();
+    assert_eq!(atime(local, file), 1000);
+
+    read_file(local, file).unwrap();
+    let read_at = atime(local, file);
+    assert!(read_at > 2000);
+    // Read since it changed, recently: relatime leaves it alone.
+    local
+        .lock()
+        .unwrap()
+        .set_times(file, Some(read_at - 10), None)
+        .unwrap();
+    read_file(local, file).unwrap();
+    assert_eq!(atime(local, file), read_at - 10);
+
+    // Reads through a peer count too.
+    local
+        .lock()
+        .unwrap()
+        .set_times(file, Some(1000), None)
+        .unwrap();
+    let remote = cluster.node("bob").remote_of("alice");
+    read_file(&remote, file).unwrap();
+    assert!(atime(local, file) > 2000);
+}
@@ -0,0 +1,139 @@
+/// Peer bans, see src/bans.rs. The cluster runs on localhost, which
+/// is never banned, so the guard is exercised directly here.
+mod common;
+
+use common::*;
+use monovault::bans::{BanConfig, PeerGuard};
+use monovault::types::*;
+use std::net::IpAddr;
+
+fn addr(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn malformed_requests_get_a_peer_banned() {
+    let guard = PeerGuard::new(BanConfig {
+        max_strikes: 3,
+        ..BanConfig::default()
+    });
+    let peer = addr("10.0.0.2");
+    for _ in 0..2 {
+        guard.strike(peer, "negative offset");
+    }
+    assert!(guard.admit(peer).is_ok());
+    guard.strike(peer, "negative offset");
+    assert!(guard.admit(peer).is_err());
+    // Others are still served.
+    assert!(guard.admit(addr("10.0.0.3")).is_ok());
+    let bans = guard.bans();
+    assert_eq!(bans.len(), 1);
+    assert_eq!(bans[0].addr, peer);
+    assert!(bans[0].reason.contains("negative offset"));
+}
+
+#[test]
+fn too_many_requests_get_a_peer_banned() {
+    let guard = PeerGuard::new(BanConfig {
+        max_requests: 5,
+        ..BanConfig::default()
+    });
+    let peer = addr("10.0.0.2");
+    for _ in 0..5 {
+        assert!(guard.admit(peer).is_ok());
+    }
+    assert!(guard.admit(peer).is_err());
+    assert_eq!(guard.bans().len(), 1);
+}
+
+#[test]
+fn clear_lifts_bans() {
+    let guard = PeerGuard::new(BanConfig {
+        max_strikes: 1,
+        ..BanConfig::default()
+    });
+    guard.strike(addr("10.0.0.2"), "bad chunk");
+    guard.strike(addr("10.0.0.3"), "bad chunk");
+    assert_eq!(guard.clear(Some(addr("10.0.0.2"))), 1);
+    assert!(guard.admit(addr("10.0.0.2")).is_ok());
+    assert!(guard.admit(addr("10.0.0.3")).is_err());
+    assert_eq!(guard.clear(None), 1);
+    assert!(guard.bans().is_empty());
+}
+
+#[test]
+fn allow_and_deny_lists() {
+    let guard = PeerGuard::new(BanConfig {
+        allow: vec![addr("10.0.0.2"), addr("10.0.0.3")],
+        deny: vec![addr("10.0.0.3")],
+        ..BanConfig::default()
+    });
+    assert!(guard.admit(addr("10.0.0.2")).is_ok());
+    assert!(guard.admit(addr("10.0.0.3")).is_err());
+    assert!(guard.admit(addr("10.0.0.4")).is_err());
+    // Share links are for people who aren't peers.
+    assert!(guard.admit_share(addr("10.0.0.4")).is_ok());
+    assert!(guard.admit_share(addr("10.0.0.3")).is_err());
+}
+
+#[test]
+fn mapped_addresses_are_the_same_peer() {
+    let guard = PeerGuard::new(BanConfig {
+        deny: vec![addr("::ffff:10.0.0.3")],
+        max_strikes: 1,
+        ..BanConfig::default()
+    });
+    // Denied whichever way it connects.
+    assert!(guard.admit(addr("10.0.0.3")).is_err());
+    assert!(guard.admit(addr("::ffff:10.0.0.3")).is_err());
+    // A ban holds both ways too.
+    guard.strike(addr("::ffff:10.0.0.2"), "bad chunk");
+    assert!(guard.admit(addr("10.0.0.2")).is_err());
+    assert_eq!(guard.bans()[0].addr, addr("10.0.0.2"));
+    assert_eq!(guard.clear(Some(addr("::ffff:10.0.0.2"))), 1);
+    // Mapped loopback is loopback.
+    guard.strike(addr("::ffff:127.0.0.1"), "bad chunk");
+    assert!(guard.bans().is_empty());
+}
+
+#[test]
+fn records_of_past_peers_go() {
+    let guard = PeerGuard::new(BanConfig {
+        max_strikes: 1,
+        window_secs: 0,
+        ban_secs: 0,
+        ..BanConfig::default()
+    });
+    for host in 0..100u8 {
+        let peer = addr(&format!("10.0.1.{}", host));
+        guard.admit(peer).unwrap();
+        guard.strike(peer, "bad chunk");
+    }
+    // Their windows and bans are over, only the last one's record
+    // or ban is left.
+    assert!(guard.tracked() <= 1);
+}
+
+#[test]
+fn localhost_is_never_banned() {
+    let guard = PeerGuard::new(BanConfig {
+        max_strikes: 1,
+        ..BanConfig::default()
+    });
+    guard.strike(addr("127.0.0.1"), "bad chunk");
+    assert!(guard.admit(addr("127.0.0.1")).is_ok());
+    assert!(guard.bans().is_empty());
+}
+
+#[test]
+fn admin_commands_are_served_to_localhost() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let remote = cluster.node("bob").remote_of("alice");
+    let mut remote = remote.lock().unwrap();
+    let server = match &mut *remote {
+        GenericVault::Remote(server) => server,
+        _ => unreachable!(),
+    };
+    assert!(server.bans().unwrap().is_empty());
+    assert_eq!(server.clear_bans(None).unwrap(), 0);
+}
@@ -0,0 +1,73 @@
+/// Filling a new cache with the remote's tree, see
+/// `caching_remote::bootstrap`.
+mod common;
+
+use common::*;
+use monovault::caching_remote::bootstrap;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn new_cache_browses_offline() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let docs = alice
+        .local
+        .lock()
+        .unwrap()
+        .create(ROOT, "docs", VaultFileType::Directory)
+        .unwrap();
+    let sub = alice
+        .local
+        .lock()
+        .unwrap()
+        .create(docs, "sub", VaultFileType::Directory)
+        .unwrap();
+    create_file(&alice.local, docs, "a", b"hello");
+    let deep = create_file(&alice.local, sub, "b", b"world");
+    create_file(&alice.local, ROOT, "note", b"top");
+
+    let cache = bob.cache_of("alice");
+    assert_eq!(bootstrap(&cache).unwrap(), 5);
+    // Only a new cache is filled.
+    assert_eq!(bootstrap(&cache).unwrap(), 0);
+
+    cluster.cut("bob", "alice");
+    assert_eq!(find(&cache, ROOT, "docs").unwrap(), Some(docs));
+    assert!(find(&cache, ROOT, "note").unwrap().is_some());
+    assert_eq!(find(&cache, docs, "sub").unwrap(), Some(sub));
+    assert_eq!(find(&cache, sub, "b").unwrap(), Some(deep));
+
+    // Contents are fetched on open as usual.
+    cluster.heal("bob", "alice");
+    assert_eq!(read_file(&cache, deep).unwrap(), b"world");
+}
+
+#[test]
+fn large_trees_come_in_batches() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let many = alice
+        .local
+        .lock()
+        .unwrap()
+        .create(ROOT, "many", VaultFileType::Directory)
+        .unwrap();
+    // More than the server sends in one message.
+    let mut last = 0;
+    for idx in 0..1500 {
+        last = alice
+            .local
+            .lock()
+            .unwrap()
+            .create(many, &format!("f{}", idx), VaultFileType::File)
+            .unwrap();
+    }
+    let cache = bob.cache_of("alice");
+    assert_eq!(bootstrap(&cache).unwrap(), 1501);
+    cluster.cut("bob", "alice");
+    assert_eq!(find(&cache, many, "f1499").unwrap(), Some(last));
+}
@@ -0,0 +1,95 @@
+/// Files cached by chunk or not cached at all, see
+/// src/cache_policy.rs.
+mod common;
+
+use common::*;
+use monovault::cache_policy::{glob_match, policy_for, CachePolicy, CacheRule};
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn rules() -> Vec<CacheRule> {
+    vec![
+        CacheRule {
+            pattern: "*.db".to_string(),
+            policy: CachePolicy::Passthrough,
+        },
+        CacheRule {
+            pattern: "*.img".to_string(),
+            policy: CachePolicy::Chunked,
+        },
+    ]
+}
+
+/// Return true if a peer can savage `file` of `vault` from us.
+fn in_cache(vault: &VaultRef, file: Inode) -> bool {
+    unpack_to_caching(&mut vault.lock().unwrap())
+        .unwrap()
+        .search_in_cache(file)
+        .is_ok()
+}
+
+#[test]
+fn patterns() {
+    assert!(glob_match("*.db", "notes.db"));
+    assert!(glob_match("*.db", ".db"));
+    assert!(!glob_match("*.db", "notes.db-journal"));
+    assert!(glob_match("disk-?.img", "disk-1.img"));
+    assert!(!glob_match("disk-?.img", "disk-10.img"));
+    assert!(glob_match("*a*b*", "xxaxxbxx"));
+    assert!(glob_match("*", ""));
+    assert_eq!(policy_for(&rules(), "mail.db"), CachePolicy::Passthrough);
+    assert_eq!(policy_for(&rules(), "vm.img"), CachePolicy::Chunked);
+    assert_eq!(policy_for(&rules(), "notes.txt"), CachePolicy::Whole);
+}
+
+#[test]
+fn passthrough_file_goes_to_remote() {
+    let mut cluster = Cluster::with_cache_rules(&["alice", "bob"], &rules());
+    cluster.start("alice");
+    cluster.start("bob");
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "mail.db", b"hello");
+
+    let cache = bob.cache_of("alice");
+    assert_eq!(find(&cache, ROOT, "mail.db").unwrap(), Some(file));
+    assert_eq!(read_at(&cache, file, 0, 100), b"hello");
+    write_file(&cache, file, b"HELLO").unwrap();
+    // Nothing to upload, the write went to alice.
+    assert!(bob.synced("alice"));
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"HELLO");
+    assert!(!in_cache(&cache, file));
+}
+
+#[test]
+fn chunked_file_fetches_what_is_read() {
+    let mut cluster = Cluster::with_cache_rules(&["alice", "bob"], &rules());
+    cluster.start("alice");
+    cluster.start("bob");
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let content: Vec<u8> = (0..3 * GRPC_DATA_CHUNK_SIZE + 100)
+        .map(|idx| (idx % 251) as u8)
+        .collect();
+    let file = create_file(&alice.local, ROOT, "vm.img", &content);
+
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "vm.img").unwrap().unwrap();
+    let offset = 2 * GRPC_DATA_CHUNK_SIZE - 10;
+    assert_eq!(
+        read_at(&cache, file, offset as i64, 20),
+        &content[offset..offset + 20]
+    );
+    assert_eq!(read_at(&cache, file, 0, content.len() as u64 + 10), content);
+    assert!(!in_cache(&cache, file));
+
+    // A change on alice is picked up on next open.
+    write_file(&alice.local, file, b"new").unwrap();
+    assert_eq!(read_at(&cache, file, 0, 3), b"new");
+    // Writes go to alice.
+    write_file(&cache, file, b"NEW").unwrap();
+    assert!(bob.synced("alice"));
+    assert_eq!(read_at(&alice.local, file, 0, 3), b"NEW");
+    assert_eq!(read_at(&cache, file, 0, 3), b"NEW");
+}
@@ -0,0 +1,74 @@
+/// Names compared ignoring case, see `LocalVault::set_case_insensitive`.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn names_keep_their_case() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let local = &cluster.node("alice").local;
+    unpack_to_local(&mut local.lock().unwrap())
+        .unwrap()
+        .set_case_insensitive(true);
+    // A Linux peer can't create what a Mac would see as the same
+    // file.
+    let remote = cluster.node("bob").remote_of("alice");
+    let file = create_file(&remote, ROOT, "Readme.md", b"hello");
+    assert!(matches!(
+        remote
+            .lock()
+            .unwrap()
+            .create(ROOT, "README.md", VaultFileType::File),
+        Err(VaultError::FileAlreadyExist(..))
+    ));
+    assert_eq!(names(local, ROOT), vec!["Readme.md"]);
+
+    // Renaming to another case of the same name changes the case.
+    remote
+        .lock()
+        .unwrap()
+        .rename(file, ROOT, "README.md")
+        .unwrap();
+    assert_eq!(names(local, ROOT), vec!["README.md"]);
+
+    // Other names are compared ignoring case too.
+    remote.lock().unwrap().link(file, ROOT, "notes").unwrap();
+    remote.lock().unwrap().unlink(file, ROOT, "NOTES").unwrap();
+    assert_eq!(names(local, ROOT), vec!["README.md"]);
+}
+
+#[test]
+fn case_is_compared_by_default() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    create_file(local, ROOT, "Readme.md", b"hello");
+    create_file(local, ROOT, "README.md", b"hello");
+    assert_eq!(names(local, ROOT), vec!["README.md", "Readme.md"]);
+}
+
+#[test]
+fn config_per_peer() {
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "my_address": "127.0.0.1:7771",
+        "peers": {
+            "bob": {"address": "http://127.0.0.1:7772", "case_insensitive": false},
+            "carol": {"address": "http://127.0.0.1:7773"},
+        },
+        "mount_point": "mnt",
+        "db_path": "db",
+        "local_vault_name": "alice",
+        "caching": false,
+        "share_local_vault": true,
+        "allow_disconnected_delete": false,
+        "allow_disconnected_create": false,
+        "background_update_interval": 1,
+        "case_insensitive": true,
+    }))
+    .unwrap();
+    assert!(config.case_insensitive_of("alice"));
+    assert!(!config.case_insensitive_of("bob"));
+    assert!(config.case_insensitive_of("carol"));
+}
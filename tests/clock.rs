@@ -0,0 +1,65 @@
+/// Clocks of peers, see src/clock.rs.
+mod common;
+
+use common::*;
+use monovault::clock::*;
+use monovault::connections::{ConnectionEventKind, ConnectionLog, MAX_CLOCK_SKEW_MS};
+
+const ROOT: monovault::types::Inode = 1;
+
+#[test]
+fn offsets_and_shifts() {
+    // The peer answered halfway through.
+    assert_eq!(estimate_offset(1000, 6100, 1200), Some(5000));
+    assert_eq!(estimate_offset(1000, 100, 1200), Some(-1000));
+    // Too slow to tell, or the clock went back.
+    assert_eq!(
+        estimate_offset(1000, 6100, 1000 + MAX_CLOCK_SAMPLE_RTT_MS + 1),
+        None
+    );
+    assert_eq!(estimate_offset(1000, 6100, 900), None);
+
+    assert_eq!(shift(100, 5400), 105);
+    assert_eq!(shift(100, -5600), 94);
+    // No time stays no time.
+    assert_eq!(shift(0, 5000), 0);
+    assert_eq!(shift(3, -5000), 1);
+
+    // Peers in sync aren't shifted.
+    assert_eq!(correction(800), 0);
+    assert_eq!(correction(-MAX_CLOCK_SKEW_MS), 0);
+    assert_eq!(correction(MAX_CLOCK_SKEW_MS + 1), MAX_CLOCK_SKEW_MS + 1);
+    assert_eq!(correction(-60_000), -60_000);
+}
+
+#[test]
+fn skewed_peer_is_logged_once() {
+    let log = ConnectionLog::new();
+    log.note_clock("alice", 300);
+    log.note_clock("alice", 60_000);
+    log.note_clock("alice", 61_000);
+    assert_eq!(log.clock_offset("alice"), Some(61_000));
+    let events = log.events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind, ConnectionEventKind::Skewed);
+    assert_eq!(events[0].detail, "clock 60.000s ahead");
+    // Back in sync and off again.
+    log.note_clock("alice", 0);
+    log.note_clock("alice", -7000);
+    assert_eq!(log.events().len(), 2);
+    assert_eq!(log.clock_offset("bob"), None);
+}
+
+#[test]
+fn responses_tell_the_time() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let bob = cluster.node("bob");
+    bob.remote_of("alice")
+        .lock()
+        .unwrap()
+        .readdir(ROOT)
+        .unwrap();
+    // Same host, same clock.
+    let offset = bob.connections.clock_offset("alice").unwrap();
+    assert!(offset.abs() <= MAX_CLOCK_SAMPLE_RTT_MS as i64);
+}
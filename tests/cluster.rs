@@ -0,0 +1,430 @@
+/// Sync, savage, disconnection and conflict scenarios, run on an
+/// in-process cluster (see common/mod.rs).
+mod common;
+
+use common::*;
+use monovault::caching_remote::CachingVault;
+use monovault::events::{Event, EventBus};
+use monovault::remote_vault::savage_refusal;
+use monovault::types::*;
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Root of every vault.
+const ROOT: Inode = 1;
+
+#[test]
+fn peer_reads_local_file() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    create_file(&alice.local, ROOT, "note", b"from alice");
+
+    let cache = bob.cache_of("alice");
+    let file = find(&cache, ROOT, "note").unwrap().unwrap();
+    assert_eq!(read_file(&cache, file).unwrap(), b"from alice");
+}
+
+#[test]
+fn cached_change_is_uploaded() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let bob = cluster.node("bob");
+    let cache = bob.cache_of("alice");
+    create_file(&cache, ROOT, "note", b"from bob");
+    assert!(bob.wait_synced("alice"));
+
+    let alice = cluster.node("alice");
+    let file = find(&alice.local, ROOT, "note").unwrap().unwrap();
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"from bob");
+}
+
+#[test]
+fn peer_comes_up_later() {
+    let mut cluster = Cluster::new(&["alice", "bob"]);
+    cluster.start("bob");
+    create_file(&cluster.node("alice").local, ROOT, "note", b"hello");
+    let cache = cluster.node("bob").cache_of("alice");
+    assert_eq!(find(&cache, ROOT, "note").unwrap(), None);
+
+    cluster.start("alice");
+    let file = find(&cache, ROOT, "note").unwrap().unwrap();
+    assert_eq!(read_file(&cache, file).unwrap(), b"hello");
+}
+
+#[test]
+fn offline_change_is_uploaded_later() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let cache = bob.cache_of("alice");
+    assert_eq!(find(&cache, ROOT, "note").unwrap(), Some(file));
+    read_file(&cache, file).unwrap();
+
+    cluster.cut("bob", "alice");
+    write_file(&cache, file, b"offline").unwrap();
+    assert!(!bob.synced("alice"));
+
+    cluster.heal("bob", "alice");
+    assert!(bob.wait_synced("alice"));
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"offline");
+}
+
+#[test]
+fn pending_change_survives_restart() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+    read_file(&cache, file).unwrap();
+
+    cluster.cut("bob", "alice");
+    write_file(&cache, file, b"offline").unwrap();
+    // Stop, as far as the old caching vault is concerned.
+    let saved = {
+        let mut cache = cache.lock().unwrap();
+        let cache = unpack_to_caching(&mut cache).unwrap();
+        cache.set_dry_run(true);
+        cache.save_pending().unwrap()
+    };
+    assert_eq!(saved, 1);
+
+    // A new caching vault on the same store picks up the upload.
+    let mut remotes = HashMap::new();
+    remotes.insert("alice".to_string(), bob.remote_of("alice"));
+    let restarted = CachingVault::new(
+        "alice",
+        remotes,
+        bob.store(),
+        true,
+        true,
+        0,
+        false,
+        EventBus::new(),
+        MissingDataPolicy::Repair,
+        vec![],
+    )
+    .unwrap();
+    assert_eq!(restarted.pending().ops.len(), 1);
+    // Loading doesn't drop the saved operations, we could die before
+    // saving them again.
+    let pending_path = bob.store().join("db").join("alice-pending.json");
+    assert!(pending_path.exists());
+    cluster.heal("bob", "alice");
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !restarted.pending().ops.is_empty() {
+        assert!(Instant::now() < deadline, "upload didn't happen");
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"offline");
+    // Saving nothing removes them.
+    assert_eq!(restarted.save_pending().unwrap(), 0);
+    assert!(!pending_path.exists());
+}
+
+#[test]
+fn missing_data_with_pending_upload_isnt_repaired() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let kept = create_file(&alice.local, ROOT, "kept", b"hello");
+    let synced = create_file(&alice.local, ROOT, "synced", b"hello");
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "kept").unwrap();
+    read_file(&cache, kept).unwrap();
+    read_file(&cache, synced).unwrap();
+
+    cluster.cut("bob", "alice");
+    write_file(&cache, kept, b"offline").unwrap();
+    let data_file = |file: Inode| bob.store().join("data").join(format!("alice-{}", file));
+    std::fs::remove_file(data_file(kept)).unwrap();
+    std::fs::remove_file(data_file(synced)).unwrap();
+    // Resetting it would upload an empty file over our change.
+    assert!(matches!(
+        cache.lock().unwrap().attr(kept),
+        Err(VaultError::DataFileMissing(file)) if file == kept
+    ));
+    assert!(!data_file(kept).exists());
+    // Nothing to lose for the other one, it's fetched again.
+    assert_eq!(cache.lock().unwrap().attr(synced).unwrap().size, 0);
+    assert!(data_file(synced).exists());
+    assert!(!names(&cache, ROOT).contains(&"kept".to_string()));
+}
+
+#[test]
+fn savage_from_another_cache() {
+    let cluster = Cluster::running(&["alice", "bob", "carol"]);
+    let alice = cluster.node("alice");
+    create_file(&alice.local, ROOT, "note", b"keep me");
+    // Bob caches the content, Carol only knows the file exists.
+    let bob_cache = cluster.node("bob").cache_of("alice");
+    let file = find(&bob_cache, ROOT, "note").unwrap().unwrap();
+    read_file(&bob_cache, file).unwrap();
+    let carol_cache = cluster.node("carol").cache_of("alice");
+    assert_eq!(find(&carol_cache, ROOT, "note").unwrap(), Some(file));
+
+    cluster.cut("carol", "alice");
+    assert_eq!(read_file(&carol_cache, file).unwrap(), b"keep me");
+}
+
+#[test]
+fn savage_from_the_newest_cache() {
+    let cluster = Cluster::running(&["alice", "bob", "carol", "dave"]);
+    let alice = cluster.node("alice");
+    let dave = cluster.node("dave");
+    let old = vec![1; 100_000];
+    let file = create_file(&alice.local, ROOT, "note", &old);
+    // Bob caches the first version, Carol the second.
+    let bob_cache = cluster.node("bob").cache_of("alice");
+    assert_eq!(find(&bob_cache, ROOT, "note").unwrap(), Some(file));
+    read_file(&bob_cache, file).unwrap();
+    write_file(&alice.local, file, b"new").unwrap();
+    let carol_cache = cluster.node("carol").cache_of("alice");
+    assert_eq!(find(&carol_cache, ROOT, "note").unwrap(), Some(file));
+    assert_eq!(read_file(&carol_cache, file).unwrap(), b"new");
+    let dave_cache = dave.cache_of("alice");
+    assert_eq!(find(&dave_cache, ROOT, "note").unwrap(), Some(file));
+
+    cluster.cut("dave", "alice");
+    let from_bob = dave.received_from("bob");
+    assert_eq!(read_file(&dave_cache, file).unwrap(), b"new");
+    // Bob only told us what he has.
+    assert!(dave.received_from("bob") - from_bob < 100);
+}
+
+#[test]
+fn savage_skips_peers_without_a_copy() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let remote = bob.remote_of("alice");
+    let mut remote = remote.lock().unwrap();
+    let remote = unpack_to_remote(&mut remote).unwrap();
+    assert!(remote.savage_offer("alice", file).unwrap().is_some());
+    // Alice told us she keeps no copy of carol, we don't ask.
+    let before = bob.received_from("alice");
+    assert!(matches!(
+        remote.savage_offer("carol", file),
+        Err(VaultError::WrongTypeOfVault(_))
+    ));
+    assert!(matches!(
+        remote.savage("carol", file, None),
+        Err(VaultError::WrongTypeOfVault(_))
+    ));
+    assert_eq!(bob.received_from("alice"), before);
+}
+
+#[test]
+fn savage_skips_older_peers() {
+    let alice = vec!["alice".to_string()];
+    assert_eq!(savage_refusal(Some(&alice), "alice"), None);
+    assert!(savage_refusal(Some(&alice), "carol").is_some());
+    assert!(savage_refusal(Some(&[]), "alice").is_some());
+    // Peers that don't list their copies might have none.
+    assert!(savage_refusal(None, "alice").is_some());
+}
+
+#[test]
+fn download_from_near_peer() {
+    let cluster = Cluster::running(&["alice", "bob", "carol"]);
+    let alice = cluster.node("alice");
+    let carol = cluster.node("carol");
+    let data: Vec<u8> = (0..3 * GRPC_DATA_CHUNK_SIZE + 10)
+        .map(|idx| (idx % 251) as u8)
+        .collect();
+    let file = create_file(&alice.local, ROOT, "big", &data);
+    let bob_cache = cluster.node("bob").cache_of("alice");
+    assert_eq!(find(&bob_cache, ROOT, "big").unwrap(), Some(file));
+    read_file(&bob_cache, file).unwrap();
+    let cache = carol.cache_of("alice");
+    unpack_to_caching(&mut cache.lock().unwrap())
+        .unwrap()
+        .set_near_peers(vec!["bob".to_string()]);
+    assert_eq!(find(&cache, ROOT, "big").unwrap(), Some(file));
+
+    // Bob has the version Alice has: only the manifest comes from
+    // Alice.
+    let (from_alice, from_bob) = (carol.received_from("alice"), carol.received_from("bob"));
+    assert_eq!(read_file(&cache, file).unwrap(), data);
+    assert!(carol.received_from("alice") - from_alice < 10_000);
+    assert!(carol.received_from("bob") - from_bob > data.len() as u64);
+
+    // Bob's copy is out-of-date now, so it comes from Alice.
+    let data = [&b"new"[..], &data].concat();
+    write_file(&alice.local, file, &data).unwrap();
+    let from_bob = carol.received_from("bob");
+    assert_eq!(read_file(&cache, file).unwrap(), data);
+    assert!(carol.received_from("bob") - from_bob < 100);
+}
+
+#[test]
+fn fsync_uploads_right_away() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let cache = cluster.node("bob").cache_of("alice");
+    let file = create_file(&cache, ROOT, "note", b"hello");
+    cache.lock().unwrap().fsync(file).unwrap();
+    // The background worker would wait a few seconds.
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"hello");
+
+    // Without the remote, the change stays with us for later.
+    cluster.cut("bob", "alice");
+    write_file(&cache, file, b"HELLO").unwrap();
+    cache.lock().unwrap().fsync(file).unwrap();
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"hello");
+    cluster.heal("bob", "alice");
+    cache.lock().unwrap().fsync(file).unwrap();
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"HELLO");
+
+    // Nothing to upload from a local vault.
+    alice.local.lock().unwrap().fsync(file).unwrap();
+    alice.local.lock().unwrap().fsync(ROOT).unwrap();
+}
+
+#[test]
+fn stale_upload_is_a_conflict() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let bob = cluster.node("bob");
+    let events = bob.events.subscribe();
+    let cache = bob.cache_of("alice");
+    let file = create_file(&cache, ROOT, "note", b"one");
+    assert!(bob.wait_synced("alice"));
+
+    // Alice changes the file after taking Bob's version, while Bob
+    // can't see it.
+    cluster.cut("bob", "alice");
+    let alice = cluster.node("alice");
+    write_file(&alice.local, file, b"two").unwrap();
+    write_file(&cache, file, b"three").unwrap();
+    cluster.heal("bob", "alice");
+    assert!(bob.wait_synced("alice"));
+
+    // The worker emits the event before it drops the operation.
+    assert!(events
+        .try_iter()
+        .any(|event| matches!(event, Event::Conflict { file: f, .. } if f == file)));
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"two");
+}
+
+#[test]
+fn cached_create_releases_the_peer_handle() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let cache = bob.cache_of("alice");
+    let file = create_file(&cache, ROOT, "note", b"from bob");
+    assert!(bob.wait_synced("alice"));
+
+    // A handle left open by the create would keep alice from
+    // removing the data file.
+    alice.local.lock().unwrap().delete(file).unwrap();
+    let data_file = alice.store().join("data").join(format!("alice-{}", file));
+    assert!(!data_file.exists());
+}
+
+#[test]
+fn submitted_data_reaches_the_data_file() {
+    let cluster = Cluster::running(&["alice"]);
+    let alice = cluster.node("alice");
+    let file = create_file(&alice.local, ROOT, "note", b"one");
+    // Nobody has the file open, nothing would move a write copy to
+    // the data file.
+    let accepted = unpack_to_local(&mut alice.local.lock().unwrap())
+        .unwrap()
+        .submit(file, b"two", (2, 0), None)
+        .unwrap();
+    assert!(accepted);
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"two");
+}
+
+#[test]
+fn read_only_cache_reads_but_sends_nothing() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"from alice");
+    let cache = bob.cache_of("alice");
+    unpack_to_caching(&mut cache.lock().unwrap())
+        .unwrap()
+        .set_read_only(true);
+
+    assert_eq!(find(&cache, ROOT, "note").unwrap(), Some(file));
+    assert_eq!(read_file(&cache, file).unwrap(), b"from alice");
+    assert!(matches!(
+        write_file(&cache, file, b"from bob"),
+        Err(VaultError::ReadOnly(_))
+    ));
+    assert!(matches!(
+        cache
+            .lock()
+            .unwrap()
+            .create(ROOT, "new", VaultFileType::File),
+        Err(VaultError::ReadOnly(_))
+    ));
+    assert!(matches!(
+        cache.lock().unwrap().delete(file),
+        Err(VaultError::ReadOnly(_))
+    ));
+    assert!(bob.synced("alice"));
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"from alice");
+}
+
+#[test]
+fn peer_changes_are_reported() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let events = bob.events.subscribe();
+    let file = create_file(&alice.local, ROOT, "note", b"one");
+    let cache = bob.cache_of("alice");
+    assert_eq!(find(&cache, ROOT, "note").unwrap(), Some(file));
+    read_file(&cache, file).unwrap();
+
+    // Reported once per version.
+    write_file(&alice.local, file, b"two").unwrap();
+    let version = alice.local.lock().unwrap().attr(file).unwrap().version;
+    cache.lock().unwrap().attr(file).unwrap();
+    cache.lock().unwrap().attr(file).unwrap();
+    let outdated: Vec<FileVersion> = events
+        .try_iter()
+        .filter_map(|event| match event {
+            Event::Outdated {
+                file: f, version, ..
+            } if f == file => Some(version),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(outdated, vec![version]);
+    // Not once we have it.
+    assert_eq!(read_file(&cache, file).unwrap(), b"two");
+    cache.lock().unwrap().attr(file).unwrap();
+    assert!(!events
+        .try_iter()
+        .any(|event| matches!(event, Event::Outdated { .. })));
+
+    alice
+        .local
+        .lock()
+        .unwrap()
+        .rename(file, ROOT, "renamed")
+        .unwrap();
+    cache.lock().unwrap().readdir(ROOT).unwrap();
+    assert!(events.try_iter().any(|event| matches!(
+        event,
+        Event::Vanished { file: f, parent: ROOT, name, .. } if f == file && name == "note"
+    )));
+
+    alice.local.lock().unwrap().delete(file).unwrap();
+    assert!(matches!(
+        cache.lock().unwrap().attr(file),
+        Err(VaultError::FileNotExist(_))
+    ));
+    assert!(events.try_iter().any(|event| matches!(
+        event,
+        Event::Vanished { file: f, parent: ROOT, name, .. } if f == file && name == "renamed"
+    )));
+}
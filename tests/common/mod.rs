@@ -0,0 +1,435 @@
+#![allow(dead_code)]
+/// A cluster of vault nodes running in one process, for integration
+/// tests. Each node has a local vault, a vault server, and a caching
+/// vault for every other node, like a real host with caching
+/// enabled. Nothing is mounted, tests drive the Vault API directly.
+///
+/// Nodes don't serve until started, so a test can begin with some
+/// nodes down. Links between running nodes can be cut and healed
+/// with `Cluster::cut` and `Cluster::heal`.
+use monovault::bandwidth::BandwidthMeter;
+use monovault::bans::{BanConfig, PeerGuard};
+use monovault::cache_policy::CacheRule;
+use monovault::caching_remote::CachingVault;
+use monovault::connections::ConnectionLog;
+use monovault::events::EventBus;
+use monovault::faults::{FaultConfig, FaultInjector};
+use monovault::local_vault::LocalVault;
+use monovault::locks::{FileLock, LockKind, LockOwner};
+use monovault::remote_vault::RemoteVault;
+use monovault::share::{ShareKey, SHARE_KEY_FILE};
+use monovault::types::*;
+use monovault::vault_server::run_server;
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::runtime::{Builder, Runtime};
+
+/// How long `wait_synced` waits for background workers, they run
+/// every few seconds.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Node {
+    pub name: String,
+    /// The address this node's server listens on once started.
+    pub address: String,
+    pub local: VaultRef,
+    /// Maps the name of each other node to our caching vault of it.
+    pub caching: HashMap<String, VaultRef>,
+    /// Maps the name of each other node to our remote vault of it,
+    /// the caching vault's backend.
+    remotes: HashMap<String, VaultRef>,
+    /// Where the vaults report events.
+    pub events: EventBus,
+    /// Signs share links to our local vault.
+    pub share_key: ShareKey,
+    /// Where our remote vaults log connection events.
+    pub connections: ConnectionLog,
+    started: bool,
+    runtime: Arc<Runtime>,
+    meter: BandwidthMeter,
+    /// Where the node's vaults keep their data, removed when the
+    /// node is dropped.
+    store: TempDir,
+}
+
+pub struct Cluster {
+    nodes: Vec<Node>,
+}
+
+/// Return an address on localhost with a free port.
+fn free_address() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("127.0.0.1:{}", listener.local_addr().unwrap().port())
+}
+
+impl Cluster {
+    /// Create a node for each of `names`, none of them started.
+    pub fn new(names: &[&str]) -> Cluster {
+        Cluster::with_cache_rules(names, &[])
+    }
+
+    /// Like `new`, but caching vaults cache files by `rules`.
+    pub fn with_cache_rules(names: &[&str], rules: &[CacheRule]) -> Cluster {
+        let addresses: HashMap<String, String> = names
+            .iter()
+            .map(|name| (name.to_string(), free_address()))
+            .collect();
+        let nodes = names
+            .iter()
+            .map(|name| Node::new(name, &addresses, rules))
+            .collect();
+        Cluster { nodes }
+    }
+
+    /// Create and start a node for each of `names`.
+    pub fn running(names: &[&str]) -> Cluster {
+        let mut cluster = Cluster::new(names);
+        for name in names {
+            cluster.start(name);
+        }
+        cluster
+    }
+
+    pub fn node(&self, name: &str) -> &Node {
+        self.nodes.iter().find(|node| node.name == name).unwrap()
+    }
+
+    /// Start serving `name`'s vaults, and return once it accepts
+    /// connections.
+    pub fn start(&mut self, name: &str) {
+        let node = self
+            .nodes
+            .iter_mut()
+            .find(|node| node.name == name)
+            .unwrap();
+        node.start();
+    }
+
+    /// Make `from` unable to reach `to`, as if the network between
+    /// them went down. `to` can still reach `from`.
+    pub fn cut(&self, from: &str, to: &str) {
+        // Nothing listens on a fresh free address.
+        self.node(from).point_remote(to, &free_address());
+    }
+
+    /// Undo `cut`.
+    pub fn heal(&self, from: &str, to: &str) {
+        let address = self.node(to).address.clone();
+        self.node(from).point_remote(to, &address);
+    }
+}
+
+impl Node {
+    fn new(name: &str, addresses: &HashMap<String, String>, rules: &[CacheRule]) -> Node {
+        let store = tempfile::tempdir().unwrap();
+        let runtime = runtime();
+        let events = EventBus::new();
+        let meter = BandwidthMeter::new(&store.path().join("bandwidth.json")).unwrap();
+        let connections = ConnectionLog::new();
+        let mut local = LocalVault::new(
+            name,
+            store.path(),
+            events.clone(),
+            MissingDataPolicy::Repair,
+        )
+        .unwrap();
+        local.set_connection_log(connections.clone());
+        let local = Arc::new(Mutex::new(GenericVault::Local(local)));
+        let mut remotes = HashMap::new();
+        for (peer, address) in addresses {
+            if peer != name {
+                let mut remote = RemoteVault::new(
+                    &format!("http://{}", address),
+                    peer,
+                    Arc::clone(&runtime),
+                    meter.clone(),
+                )
+                .unwrap();
+                remote.set_connection_log(connections.clone());
+                remotes.insert(
+                    peer.clone(),
+                    Arc::new(Mutex::new(GenericVault::Remote(remote))),
+                );
+            }
+        }
+        let caching = remotes
+            .keys()
+            .map(|peer| {
+                let vault = CachingVault::new(
+                    peer,
+                    remotes.clone(),
+                    store.path(),
+                    true,
+                    true,
+                    0,
+                    false,
+                    events.clone(),
+                    MissingDataPolicy::Repair,
+                    rules.to_vec(),
+                )
+                .unwrap();
+                (
+                    peer.clone(),
+                    Arc::new(Mutex::new(GenericVault::Caching(vault))),
+                )
+            })
+            .collect();
+        Node {
+            name: name.to_string(),
+            address: addresses.get(name).unwrap().clone(),
+            local,
+            caching,
+            remotes,
+            events,
+            share_key: ShareKey::load_or_create(&store.path().join(SHARE_KEY_FILE)).unwrap(),
+            connections,
+            started: false,
+            runtime,
+            meter,
+            store,
+        }
+    }
+
+    fn start(&mut self) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        // Like main, the server serves the caching vaults too, so
+        // others can savage from us.
+        let mut vault_map = HashMap::new();
+        vault_map.insert(self.name.clone(), Arc::clone(&self.local));
+        for (peer, vault) in &self.caching {
+            vault_map.insert(peer.clone(), Arc::clone(vault));
+        }
+        let address = self.address.clone();
+        let name = self.name.clone();
+        let runtime = Arc::clone(&self.runtime);
+        let share_key = self.share_key.clone();
+        let connections = self.connections.clone();
+        let _ = thread::spawn(move || {
+            run_server(
+                &address,
+                &name,
+                vault_map,
+                runtime,
+                PeerGuard::new(BanConfig::default()),
+                Some(share_key),
+                connections,
+            )
+        });
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(&self.address).is_err() {
+            assert!(Instant::now() < deadline, "{} didn't start", self.name);
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Replace our remote vault of `peer` with one that connects to
+    /// `address`. The caching vault and its background worker share
+    /// the remote, so they pick up the change.
+    fn point_remote(&self, peer: &str, address: &str) {
+        let mut remote = RemoteVault::new(
+            &format!("http://{}", address),
+            peer,
+            Arc::clone(&self.runtime),
+            self.meter.clone(),
+        )
+        .unwrap();
+        remote.set_connection_log(self.connections.clone());
+        *self.remotes.get(peer).unwrap().lock().unwrap() = GenericVault::Remote(remote);
+    }
+
+    /// Inject `faults` into our RPCs to `peer`, or stop if None. A
+    /// `Cluster::cut` or `Cluster::heal` clears them.
+    pub fn set_faults(&self, peer: &str, faults: Option<FaultConfig>) {
+        let mut remote = self.remotes.get(peer).unwrap().lock().unwrap();
+        unpack_to_remote(&mut remote)
+            .unwrap()
+            .set_faults(faults.map(FaultInjector::new));
+    }
+
+    /// Return the number of faults injected into RPCs to `peer`
+    /// since `set_faults`.
+    pub fn faults_injected(&self, peer: &str) -> u64 {
+        let mut remote = self.remotes.get(peer).unwrap().lock().unwrap();
+        unpack_to_remote(&mut remote)
+            .unwrap()
+            .faults()
+            .map(|faults| faults.injected())
+            .unwrap_or(0)
+    }
+
+    /// Return our remote vault of `peer`, without caching.
+    pub fn remote_of(&self, peer: &str) -> VaultRef {
+        Arc::clone(self.remotes.get(peer).unwrap())
+    }
+
+    /// Return the directory our vaults store their data in.
+    pub fn store(&self) -> &Path {
+        self.store.path()
+    }
+
+    /// Return our caching vault of `peer`.
+    pub fn cache_of(&self, peer: &str) -> VaultRef {
+        Arc::clone(self.caching.get(peer).unwrap())
+    }
+
+    /// Return the number of bytes we received from `peer` so far.
+    pub fn received_from(&self, peer: &str) -> u64 {
+        self.meter
+            .snapshot()
+            .get(peer)
+            .map(|traffic| traffic.received)
+            .unwrap_or(0)
+    }
+
+    /// Return true if the background worker of our caching vault of
+    /// `peer` has no operation left to perform.
+    pub fn synced(&self, peer: &str) -> bool {
+        match &*self.cache_of(peer).lock().unwrap() {
+            GenericVault::Caching(vault) => vault.pending().ops.is_empty(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Wait until `synced` returns true for `peer`. Return false if
+    /// it doesn't in SYNC_TIMEOUT.
+    pub fn wait_synced(&self, peer: &str) -> bool {
+        wait_until(|| self.synced(peer))
+    }
+}
+
+/// Wait until `done` returns true. Return false if it doesn't in
+/// SYNC_TIMEOUT.
+pub fn wait_until(done: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + SYNC_TIMEOUT;
+    while Instant::now() < deadline {
+        if done() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+/// Create file `name` in directory `parent` of `vault` with
+/// `content`, and return its inode.
+pub fn create_file(vault: &VaultRef, parent: Inode, name: &str, content: &[u8]) -> Inode {
+    let mut vault = vault.lock().unwrap();
+    // Create also opens the file.
+    let file = vault.create(parent, name, VaultFileType::File).unwrap();
+    vault.write(file, 0, content).unwrap();
+    vault.close(file).unwrap();
+    file
+}
+
+/// Overwrite `file` of `vault` with `content`.
+pub fn write_file(vault: &VaultRef, file: Inode, content: &[u8]) -> VaultResult<()> {
+    let mut vault = vault.lock().unwrap();
+    vault.open(file, OpenMode::RW)?;
+    vault.write(file, 0, content)?;
+    vault.close(file)
+}
+
+/// Return the whole content of `file` of `vault`.
+pub fn read_file(vault: &VaultRef, file: Inode) -> VaultResult<Vec<u8>> {
+    let mut vault = vault.lock().unwrap();
+    vault.open(file, OpenMode::R)?;
+    let size = vault.attr(file)?.size;
+    let data = vault.read(file, 0, size);
+    vault.close(file)?;
+    data
+}
+
+/// Return the inode of `name` in directory `dir` of `vault`, if any.
+pub fn find(vault: &VaultRef, dir: Inode, name: &str) -> VaultResult<Option<Inode>> {
+    Ok(vault
+        .lock()
+        .unwrap()
+        .readdir(dir)?
+        .into_iter()
+        .find(|entry| entry.name == name)
+        .map(|entry| entry.inode))
+}
+
+/// Return the names in directory `dir` of `vault`, sorted.
+pub fn names(vault: &VaultRef, dir: Inode) -> Vec<String> {
+    let mut names: Vec<String> = vault
+        .lock()
+        .unwrap()
+        .readdir(dir)
+        .unwrap()
+        .into_iter()
+        .map(|info| info.name)
+        .collect();
+    names.sort();
+    names
+}
+
+/// Return `list` as vault names.
+pub fn vault_names(list: &[&str]) -> Vec<VaultName> {
+    list.iter().map(|name| name.to_string()).collect()
+}
+
+/// Open local vault "alice" kept in `store`, failing on missing data
+/// files.
+pub fn open_vault(store: &Path) -> LocalVault {
+    LocalVault::new("alice", store, EventBus::new(), MissingDataPolicy::Error).unwrap()
+}
+
+/// Return a runtime for remote vaults.
+pub fn runtime() -> Arc<Runtime> {
+    Arc::new(Builder::new_multi_thread().enable_all().build().unwrap())
+}
+
+/// Return a remote vault of `peer` at `url`, keeping its bandwidth
+/// counters in `store`.
+pub fn remote(url: &str, peer: &str, runtime: &Arc<Runtime>, store: &Path) -> RemoteVault {
+    let meter = BandwidthMeter::new(&store.join("bandwidth.json")).unwrap();
+    RemoteVault::new(url, peer, Arc::clone(runtime), meter).unwrap()
+}
+
+/// Create directory `name` in `parent` of `vault`.
+pub fn mkdir(vault: &VaultRef, parent: Inode, name: &str) -> Inode {
+    vault
+        .lock()
+        .unwrap()
+        .create(parent, name, VaultFileType::Directory)
+        .unwrap()
+}
+
+/// Read `size` bytes at `offset` of `file`, opening and closing it.
+pub fn read_at(vault: &VaultRef, file: Inode, offset: i64, size: u64) -> Vec<u8> {
+    let mut vault = vault.lock().unwrap();
+    vault.open(file, OpenMode::R).unwrap();
+    let data = vault.read(file, offset, size).unwrap();
+    vault.close(file).unwrap();
+    data
+}
+
+/// Return `size` bytes that differ from block to block, and from
+/// chunk to chunk.
+pub fn content(size: u64) -> Vec<u8> {
+    (0..size).map(|idx| (idx % 251) as u8).collect()
+}
+
+/// Return a lock of `kind` on bytes `start` to `end`, inclusive, held
+/// by owner `id` on `host`.
+pub fn lock(host: &str, id: u64, start: u64, end: u64, kind: LockKind) -> FileLock {
+    FileLock {
+        start,
+        end,
+        kind,
+        owner: LockOwner {
+            host: host.to_string(),
+            id,
+        },
+        pid: 42,
+    }
+}
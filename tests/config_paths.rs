@@ -0,0 +1,62 @@
+/// Resolving the paths in the configuration, see
+/// `Config::resolve_paths`.
+use monovault::types::*;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+fn with_paths(mount_point: &str, db_path: &str) -> Config {
+    serde_json::from_value(serde_json::json!({
+        "my_address": "127.0.0.1:0",
+        "peers": {},
+        "mount_point": mount_point,
+        "db_path": db_path,
+        "local_vault_name": "alice",
+        "caching": false,
+        "share_local_vault": false,
+        "allow_disconnected_delete": false,
+        "allow_disconnected_create": false,
+        "background_update_interval": 3
+    }))
+    .unwrap()
+}
+
+#[test]
+fn relative_paths_are_relative_to_the_configuration() {
+    let dir = tempfile::tempdir().unwrap();
+    let base = dir.path().canonicalize().unwrap();
+    fs::create_dir(base.join("mnt")).unwrap();
+    let mut config = with_paths("mnt/", "./store/db");
+    config.event_socket = Some("events.sock".to_string());
+    config.resolve_paths(dir.path());
+    assert_eq!(Path::new(&config.mount_point), base.join("mnt"));
+    // Paths that don't exist yet resolve too.
+    assert_eq!(Path::new(&config.db_path), base.join("store/db"));
+    assert_eq!(
+        Path::new(config.event_socket.as_deref().unwrap()),
+        base.join("events.sock")
+    );
+    assert_eq!(config.volume_name.as_deref(), Some("mnt"));
+}
+
+#[test]
+fn symlinks_and_dot_dot_are_resolved() {
+    let dir = tempfile::tempdir().unwrap();
+    let base = dir.path().canonicalize().unwrap();
+    fs::create_dir_all(base.join("real/mnt")).unwrap();
+    symlink(base.join("real/mnt"), base.join("Vault")).unwrap();
+    let absolute = format!("{}/", base.join("Vault").display());
+    let db_path = format!("{}/real/../db", base.display());
+    let mut config = with_paths(&absolute, &db_path);
+    config.resolve_paths(Path::new("/elsewhere"));
+    assert_eq!(Path::new(&config.mount_point), base.join("real/mnt"));
+    assert_eq!(Path::new(&config.db_path), base.join("db"));
+    // The volume is named after the link, not where it leads.
+    assert_eq!(config.volume_name.as_deref(), Some("Vault"));
+
+    // A configured volume name stays.
+    let mut config = with_paths("mnt", "db");
+    config.volume_name = Some("Photos".to_string());
+    config.resolve_paths(dir.path());
+    assert_eq!(config.volume_name.as_deref(), Some("Photos"));
+}
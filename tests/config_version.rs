@@ -0,0 +1,93 @@
+/// Versions of the configuration file, see src/config.rs.
+use monovault::config::{self, CONFIG_VERSION};
+use monovault::types::MissingDataPolicy;
+use serde_json::{json, Value};
+
+/// A configuration written before "config_version" existed.
+fn version_0() -> Value {
+    json!({
+        "my_address": "127.0.0.1:0",
+        "peers": {"bob": "http://127.0.0.1:7771"},
+        "mount_point": "mnt",
+        "db_path": "db",
+        "local_vault_name": "alice",
+        "caching": true,
+        "share_local_vault": false,
+        "allow_disconnected_delete": false,
+        "allow_disconnected_create": false,
+        "background_update_interval": 3
+    })
+}
+
+#[test]
+fn older_versions_parse() {
+    let (config, version) = config::parse(&version_0().to_string()).unwrap();
+    assert_eq!(version, 0);
+    assert_eq!(config.config_version, CONFIG_VERSION);
+    assert_eq!(config.local_vault_name, "alice");
+    // Peers were plain addresses before version 2.
+    assert_eq!(config.peers["bob"].address, "http://127.0.0.1:7771");
+    assert!(config.peers["bob"].caching.is_none());
+    assert!(config.caching);
+    // Settings left out take their defaults.
+    assert_eq!(config.remount_attempts, 5);
+    assert_eq!(config.missing_data, MissingDataPolicy::Error);
+
+    let mut current = version_0();
+    current["config_version"] = json!(CONFIG_VERSION);
+    current["peers"]["bob"] = json!({
+        "address": "http://127.0.0.1:7771",
+        "fallback_addresses": ["http://bob.example.com:7771"],
+        "caching": false,
+        "read_only": true,
+        "timeout_secs": 10,
+        "bandwidth_limit_kib": 512
+    });
+    let (config, version) = config::parse(&current.to_string()).unwrap();
+    assert_eq!(version, CONFIG_VERSION);
+    let bob = &config.peers["bob"];
+    assert_eq!(
+        bob.addresses().collect::<Vec<_>>(),
+        ["http://127.0.0.1:7771", "http://bob.example.com:7771"]
+    );
+    assert!(bob.read_only);
+    assert_eq!((bob.timeout_secs, bob.bandwidth_limit_kib), (10, 512));
+    // The peer's setting wins over the global one.
+    assert!(config.caching);
+    assert!(!config.caching_of("bob"));
+    // A plain address is no longer a peer.
+    current["peers"]["bob"] = json!("http://127.0.0.1:7771");
+    assert!(config::parse(&current.to_string()).is_err());
+}
+
+#[test]
+fn upgrade_keeps_settings() {
+    let upgraded = config::upgrade(&version_0().to_string()).unwrap();
+    let upgraded: Value = serde_json::from_str(&upgraded).unwrap();
+    let mut expected = version_0();
+    expected["config_version"] = json!(CONFIG_VERSION);
+    expected["peers"]["bob"] = json!({"address": "http://127.0.0.1:7771"});
+    // Defaults aren't written out, so they can change later.
+    assert_eq!(upgraded, expected);
+    // Upgrading again changes nothing.
+    let again = config::upgrade(&upgraded.to_string()).unwrap();
+    assert_eq!(serde_json::from_str::<Value>(&again).unwrap(), expected);
+}
+
+#[test]
+fn bad_versions() {
+    let mut newer = version_0();
+    newer["config_version"] = json!(CONFIG_VERSION + 1);
+    let err = config::parse(&newer.to_string()).unwrap_err();
+    assert!(err.contains("newer"), "{}", err);
+    assert!(config::upgrade(&newer.to_string()).is_err());
+
+    let mut garbled = version_0();
+    garbled["config_version"] = json!("1");
+    assert!(config::parse(&garbled.to_string()).is_err());
+    assert!(config::parse("[]").is_err());
+    // Upgrading doesn't hide a broken configuration.
+    let mut broken = version_0();
+    broken.as_object_mut().unwrap().remove("peers");
+    assert!(config::upgrade(&broken.to_string()).is_err());
+}
@@ -0,0 +1,112 @@
+/// Connection events logged by remote vaults, see
+/// src/connections.rs.
+mod common;
+
+use common::*;
+use monovault::connections::ConnectionEventKind;
+use monovault::faults::FaultConfig;
+use monovault::fuse::peer_status;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn kinds(node: &Node, peer: &str) -> Vec<ConnectionEventKind> {
+    node.connections
+        .events()
+        .into_iter()
+        .filter(|event| event.peer == peer)
+        .map(|event| event.kind)
+        .collect()
+}
+
+#[test]
+fn flapping_peer_is_logged() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let bob = cluster.node("bob");
+    let remote = bob.remote_of("alice");
+    remote.lock().unwrap().readdir(ROOT).unwrap();
+    remote.lock().unwrap().readdir(ROOT).unwrap();
+    assert_eq!(kinds(bob, "alice"), vec![ConnectionEventKind::Connected]);
+
+    bob.set_faults(
+        "alice",
+        Some(FaultConfig {
+            drop_rate: 1.0,
+            ..FaultConfig::default()
+        }),
+    );
+    assert!(remote.lock().unwrap().readdir(ROOT).is_err());
+    // Only changes are logged.
+    assert!(remote.lock().unwrap().readdir(ROOT).is_err());
+    bob.set_faults("alice", None);
+    remote.lock().unwrap().readdir(ROOT).unwrap();
+    assert_eq!(
+        kinds(bob, "alice"),
+        vec![
+            ConnectionEventKind::Connected,
+            ConnectionEventKind::Disconnected,
+            ConnectionEventKind::Connected,
+        ]
+    );
+    let events = bob.connections.events();
+    assert!(events[1].detail.contains("dropped"));
+    assert!(events[0].time_ms <= events[2].time_ms);
+    // Alice logged nothing about bob.
+    assert!(cluster.node("alice").connections.events().is_empty());
+
+    // The running instance reports its log to the "connections"
+    // command.
+    let server = cluster.node("alice").remote_of("bob");
+    let mut server = server.lock().unwrap();
+    let reported = unpack_to_remote(&mut server)
+        .unwrap()
+        .connections()
+        .unwrap();
+    assert_eq!(reported, bob.connections.events());
+}
+
+#[test]
+fn peer_never_up_is_unreachable() {
+    let mut cluster = Cluster::new(&["alice", "bob"]);
+    cluster.start("bob");
+    let bob = cluster.node("bob");
+    let remote = bob.remote_of("alice");
+    assert!(remote.lock().unwrap().readdir(ROOT).is_err());
+    assert_eq!(kinds(bob, "alice"), vec![ConnectionEventKind::Unreachable]);
+
+    cluster.start("alice");
+    let bob = cluster.node("bob");
+    remote.lock().unwrap().readdir(ROOT).unwrap();
+    assert_eq!(
+        kinds(bob, "alice"),
+        vec![
+            ConnectionEventKind::Unreachable,
+            ConnectionEventKind::Connected
+        ]
+    );
+}
+
+#[test]
+fn roots_report_peer_status() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let bob = cluster.node("bob");
+    assert_eq!(peer_status(bob.connections.is_up("alice"), true), "unknown");
+    bob.remote_of("alice")
+        .lock()
+        .unwrap()
+        .readdir(ROOT)
+        .unwrap();
+    assert_eq!(bob.connections.is_up("alice"), Some(true));
+    assert_eq!(peer_status(Some(true), true), "online");
+
+    cluster.cut("bob", "alice");
+    assert!(bob
+        .remote_of("alice")
+        .lock()
+        .unwrap()
+        .readdir(ROOT)
+        .is_err());
+    assert_eq!(bob.connections.is_up("alice"), Some(false));
+    assert_eq!(peer_status(Some(false), true), "cached");
+    assert_eq!(peer_status(Some(false), false), "offline");
+}
@@ -0,0 +1,46 @@
+/// Waiting for other connections to the database, see
+/// `database::busy_backoff`.
+use monovault::database::Database;
+use monovault::types::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ROOT: Inode = 1;
+
+/// Open another connection to the database of alice in `store`, and
+/// lock the database until it commits.
+fn lock_database(store: &std::path::Path) -> rusqlite::Connection {
+    let connection = rusqlite::Connection::open(store.join("alice.sqlite3")).unwrap();
+    connection.execute_batch("begin exclusive").unwrap();
+    connection
+}
+
+#[test]
+fn busy_database_is_waited_for() {
+    let store = tempfile::tempdir().unwrap();
+    let mut database = Database::new(store.path(), "alice").unwrap();
+    database
+        .add_file(ROOT, 2, "note", VaultFileType::File, 0, 0, (1, 0), 0o644)
+        .unwrap();
+
+    // Someone else writes for a while.
+    let other = lock_database(store.path());
+    let writer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        other.execute_batch("commit").unwrap();
+    });
+    database.set_size(2, 42).unwrap();
+    database.set_mode(2, 0o600).unwrap();
+    writer.join().unwrap();
+    assert_eq!(database.usage(ROOT).unwrap().size, 42);
+
+    // And then doesn't let go.
+    let _other = lock_database(store.path());
+    let start = Instant::now();
+    assert!(matches!(
+        database.set_mode(2, 0o644),
+        Err(VaultError::DatabaseBusy)
+    ));
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert!(VaultError::DatabaseBusy.category().retryable());
+}
@@ -0,0 +1,66 @@
+/// Files opened for reading are only fetched when read, see
+/// `CachingVault::open`.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn open_without_read_fetches_nothing() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let content = vec![7; 1 << 20];
+    let file = create_file(&alice.local, ROOT, "big", &content);
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "big").unwrap();
+
+    let before = bob.received_from("alice");
+    cache.lock().unwrap().open(file, OpenMode::R).unwrap();
+    cache.lock().unwrap().close(file).unwrap();
+    assert!(bob.received_from("alice") - before < 1 << 16);
+
+    // Reading fetches it.
+    assert_eq!(read_file(&cache, file).unwrap(), content);
+    assert!(bob.received_from("alice") - before >= 1 << 20);
+}
+
+#[test]
+fn opening_for_writing_fetches_deferred_file() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+    {
+        let mut vault = cache.lock().unwrap();
+        vault.open(file, OpenMode::R).unwrap();
+        vault.open(file, OpenMode::RW).unwrap();
+        vault.write(file, 5, b" world").unwrap();
+        vault.close(file).unwrap();
+        vault.close(file).unwrap();
+    }
+    assert_eq!(read_file(&cache, file).unwrap(), b"hello world");
+}
+
+#[test]
+fn deferred_read_fails_offline() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+
+    cluster.cut("bob", "alice");
+    // Opening doesn't need the content, reading does.
+    cache.lock().unwrap().open(file, OpenMode::R).unwrap();
+    assert!(cache.lock().unwrap().read(file, 0, 5).is_err());
+    // And tries again.
+    cluster.heal("bob", "alice");
+    assert_eq!(cache.lock().unwrap().read(file, 0, 5).unwrap(), b"hello");
+    cache.lock().unwrap().close(file).unwrap();
+}
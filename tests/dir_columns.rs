@@ -0,0 +1,74 @@
+/// Directory listings in columns, see src/dir_columns.rs.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn fields(entries: Vec<FileInfo>) -> Vec<(Inode, String, bool, u64, u64, FileVersion, u32)> {
+    entries
+        .into_iter()
+        .map(|info| {
+            (
+                info.inode,
+                info.name,
+                matches!(info.kind, VaultFileType::Directory),
+                info.size,
+                info.mtime,
+                info.version,
+                info.mode,
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn remote_listing_matches_local() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let dir = alice
+        .local
+        .lock()
+        .unwrap()
+        .create(ROOT, "dir", VaultFileType::Directory)
+        .unwrap();
+    // Names that share part of a multi-byte character.
+    for name in ["café", "cafè", "日本", "日記", "a"] {
+        create_file(&alice.local, dir, name, name.as_bytes());
+    }
+    alice
+        .local
+        .lock()
+        .unwrap()
+        .create(dir, "sub", VaultFileType::Directory)
+        .unwrap();
+    alice.local.lock().unwrap().set_mode(dir, 0o700).unwrap();
+
+    let remote = bob.remote_of("alice");
+    for dir in [ROOT, dir] {
+        let local = alice.local.lock().unwrap().readdir(dir).unwrap();
+        let listed = remote.lock().unwrap().readdir(dir).unwrap();
+        assert_eq!(fields(listed), fields(local));
+    }
+    assert!(remote.lock().unwrap().readdir(12345).is_err());
+}
+
+#[test]
+fn large_listings_are_small() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let count = 1000;
+    for idx in 0..count {
+        create_file(&alice.local, ROOT, &format!("photo-{:05}.jpg", idx), b"");
+    }
+    let remote = bob.remote_of("alice");
+    let received = bob.received_from("alice");
+    let listed = remote.lock().unwrap().readdir(ROOT).unwrap();
+    assert_eq!(listed.len(), count);
+    // One FileInfo message per entry takes over 40 bytes for these.
+    let per_entry = (bob.received_from("alice") - received) / count as u64;
+    assert!(per_entry < 24, "{} bytes per entry", per_entry);
+}
@@ -0,0 +1,51 @@
+/// Disk usage of stores, see src/disk_usage.rs.
+use monovault::database::Database;
+use monovault::disk_usage::disk_usage;
+use monovault::events::EventBus;
+use monovault::local_vault::LocalVault;
+use monovault::types::*;
+use std::fs;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn garbage_is_told_apart() {
+    let store = tempfile::tempdir().unwrap();
+    let mut vault = LocalVault::new(
+        "alice",
+        store.path(),
+        EventBus::new(),
+        MissingDataPolicy::Error,
+    )
+    .unwrap();
+    let file = vault.create(ROOT, "note", VaultFileType::File).unwrap();
+    vault.write(file, 0, &[1; 10_000]).unwrap();
+    vault.close(file).unwrap();
+    drop(vault);
+    let data = store.path().join("data");
+    fs::write(data.join("alice-999"), [2; 20_000]).unwrap();
+    fs::write(data.join(format!("alice-{}-partial", file)), [3; 30_000]).unwrap();
+    let graveyard = store.path().join("graveyard");
+    fs::create_dir(&graveyard).unwrap();
+    fs::write(
+        graveyard.join("vault(alice)name(note)inode(2)"),
+        [4; 40_000],
+    )
+    .unwrap();
+    // Another vault's files aren't ours.
+    fs::write(data.join("alice-bob-2"), [5; 50_000]).unwrap();
+
+    let database = Database::new(&store.path().join("db"), "alice").unwrap();
+    let usage = disk_usage(store.path(), "alice", |file| database.has_file(file)).unwrap();
+    // Bytes on disk are whole blocks.
+    assert!((10_000..20_000).contains(&usage.data));
+    assert!((20_000..30_000).contains(&usage.orphaned));
+    assert!((30_000..40_000).contains(&usage.partial));
+    assert!((40_000..50_000).contains(&usage.graveyard));
+    assert_eq!(usage.write_copies, 0);
+    assert!(usage.database > 0);
+    assert_eq!(
+        usage.garbage(),
+        usage.orphaned + usage.partial + usage.graveyard
+    );
+}
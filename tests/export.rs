@@ -0,0 +1,99 @@
+/// Exporting a vault subtree to the host, see src/export.rs, from
+/// vaults that list what they shouldn't.
+mod common;
+
+use common::*;
+use monovault::export::{export_tree, safe_name};
+use monovault::share::ShareKey;
+use monovault::types::*;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+const ROOT: Inode = 1;
+
+/// Create directory "top" with directory "sub" and file "note" in
+/// it, in a vault in `store`, then change its database with `sql`,
+/// which gets the inodes of top, sub and note.
+fn damaged(store: &std::path::Path, sql: &str) -> (Inode, Inode, Inode) {
+    let mut vault = open_vault(store);
+    let top = vault.create(ROOT, "top", VaultFileType::Directory).unwrap();
+    let sub = vault.create(top, "sub", VaultFileType::Directory).unwrap();
+    let note = vault.create(sub, "note", VaultFileType::File).unwrap();
+    vault.write(note, 0, b"hello").unwrap();
+    vault.close(note).unwrap();
+    drop(vault);
+    let db = rusqlite::Connection::open(store.join("db").join("alice.sqlite3")).unwrap();
+    db.execute_batch(
+        &sql.replace("$top", &top.to_string())
+            .replace("$sub", &sub.to_string())
+            .replace("$note", &note.to_string()),
+    )
+    .unwrap();
+    (top, sub, note)
+}
+
+#[test]
+fn names_that_leave_the_destination_are_refused() {
+    for name in ["", ".", "..", "a/b", "/etc", "a\0b"] {
+        assert!(!safe_name(name), "{:?}", name);
+    }
+    assert!(safe_name("..."));
+    assert!(safe_name("note.txt"));
+
+    let store = tempfile::tempdir().unwrap();
+    let (top, _, _) = damaged(store.path(), "update Type set name='..' where file=$sub");
+    let dest = tempfile::tempdir().unwrap();
+    let mut vault = open_vault(store.path());
+    assert!(matches!(
+        export_tree(&mut vault, top, &dest.path().join("top")),
+        Err(VaultError::InvalidArgument(_))
+    ));
+    assert!(!dest.path().join("note").exists());
+}
+
+#[test]
+fn special_bits_are_dropped() {
+    let store = tempfile::tempdir().unwrap();
+    let (top, _, _) = damaged(
+        store.path(),
+        "update Type set mode=3565 where file=$note; update Type set mode=1023 where file=$sub",
+    );
+    let dest = tempfile::tempdir().unwrap();
+    let mut vault = open_vault(store.path());
+    let stats = export_tree(&mut vault, top, &dest.path().join("top")).unwrap();
+    assert_eq!(stats.files, 1);
+    let mode = |path: &[&str]| {
+        let mut full = dest.path().join("top");
+        for part in path {
+            full = full.join(part);
+        }
+        fs::metadata(full).unwrap().permissions().mode() & 0o7777
+    };
+    // 0o6755 and 0o1777.
+    assert_eq!(mode(&["sub", "note"]), 0o755);
+    assert_eq!(mode(&["sub"]), 0o777);
+}
+
+#[test]
+fn directory_cycles_end() {
+    let store = tempfile::tempdir().unwrap();
+    let (top, _, _) = damaged(
+        store.path(),
+        "update HasChild set parent=$sub where child=$top",
+    );
+    let dest = tempfile::tempdir().unwrap();
+    let mut vault = open_vault(store.path());
+    assert!(matches!(
+        export_tree(&mut vault, top, &dest.path().join("top")),
+        Err(VaultError::InvalidArgument(_))
+    ));
+}
+
+#[test]
+fn ttls_too_long_are_refused() {
+    let key = ShareKey::new(b"key");
+    assert!(matches!(
+        key.mint_for("alice", 12, false, u64::MAX),
+        Err(VaultError::InvalidArgument(_))
+    ));
+}
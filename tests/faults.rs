@@ -0,0 +1,83 @@
+/// Offline and retry paths, exercised by injecting faults into RPCs
+/// (see `monovault::faults`).
+mod common;
+
+use common::*;
+use monovault::faults::FaultConfig;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn dropped_upload_is_retried() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+    read_file(&cache, file).unwrap();
+
+    // Opening a cached copy while the remote drops every call works
+    // offline.
+    bob.set_faults(
+        "alice",
+        Some(FaultConfig {
+            drop_rate: 1.0,
+            ..Default::default()
+        }),
+    );
+    write_file(&cache, file, b"retried").unwrap();
+    // Let the background worker fail a few rounds.
+    let before = bob.faults_injected("alice");
+    assert!(wait_until(|| bob.faults_injected("alice") >= before + 2));
+    assert!(!bob.synced("alice"));
+
+    bob.set_faults("alice", None);
+    assert!(bob.wait_synced("alice"));
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"retried");
+}
+
+#[test]
+fn failed_write_is_reported() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    bob.set_faults(
+        "alice",
+        Some(FaultConfig {
+            write_fail_rate: 1.0,
+            ..Default::default()
+        }),
+    );
+    let remote = bob.remote_of("alice");
+    let mut remote = remote.lock().unwrap();
+    // Other calls go through.
+    assert_eq!(remote.attr(file).unwrap().size, 5);
+    assert!(matches!(
+        remote.write(file, 0, b"bye"),
+        Err(VaultError::RemoteError(_))
+    ));
+}
+
+#[test]
+fn corrupted_chunk_changes_content() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let remote = bob.remote_of("alice");
+    assert_eq!(remote.lock().unwrap().read(file, 0, 5).unwrap(), b"hello");
+
+    bob.set_faults(
+        "alice",
+        Some(FaultConfig {
+            corrupt_rate: 1.0,
+            ..Default::default()
+        }),
+    );
+    let data = remote.lock().unwrap().read(file, 0, 5).unwrap();
+    assert_eq!(data.len(), 5);
+    assert_ne!(data, b"hello");
+}
@@ -0,0 +1,65 @@
+/// File handles of the FUSE layer, see src/file_handles.rs.
+use monovault::file_handles::*;
+use monovault::types::OpenMode;
+
+#[test]
+fn each_open_gets_its_own_handle() {
+    let mut handles = FileHandles::new();
+    let first = handles.open(7, libc::O_RDONLY);
+    let second = handles.open(7, libc::O_RDWR | libc::O_APPEND);
+    let other = handles.open(8, libc::O_RDWR);
+    assert_ne!(first, second);
+    assert_ne!(second, other);
+    // Handles of the same file keep their own state.
+    assert!(handles.read_only(first));
+    assert!(!handles.read_only(second));
+    assert!(!handles.append(first));
+    assert!(handles.append(second));
+    assert_eq!(handles.get(second).unwrap().ino, 7);
+
+    // Closing one leaves the other.
+    handles.release(first);
+    assert!(handles.get(first).is_none());
+    assert!(handles.get(second).is_some());
+    // Handles aren't given out again.
+    assert!(handles.open(7, libc::O_RDONLY) > other);
+}
+
+#[test]
+fn lock_owners_go_with_their_handle() {
+    let mut handles = FileHandles::new();
+    let first = handles.open(7, libc::O_RDWR);
+    let second = handles.open(7, libc::O_RDWR);
+    handles.add_lock_owner(first, 100);
+    handles.add_lock_owner(second, 200);
+    // A close by an owner that took no lock costs nothing.
+    assert!(!handles.forget_lock_owner(7, 300));
+    assert!(!handles.forget_lock_owner(8, 100));
+    assert!(handles.forget_lock_owner(7, 100));
+    assert!(!handles.forget_lock_owner(7, 100));
+    assert_eq!(
+        handles.release(second).into_iter().collect::<Vec<_>>(),
+        vec![200]
+    );
+    assert!(handles.release(second).is_empty());
+}
+
+#[test]
+fn open_flags_decide_the_mode() {
+    assert!(matches!(open_mode(libc::O_RDONLY), OpenMode::R));
+    assert!(matches!(open_mode(libc::O_WRONLY), OpenMode::RW));
+    assert!(matches!(open_mode(libc::O_RDWR), OpenMode::RW));
+    // Other flags don't change the access mode.
+    assert!(matches!(
+        open_mode(libc::O_RDONLY | libc::O_NONBLOCK | libc::O_CLOEXEC),
+        OpenMode::R
+    ));
+    // Writes and ftruncate(2) through these are refused.
+    let mut handles = FileHandles::new();
+    let reader = handles.open(7, libc::O_RDONLY);
+    let writer = handles.open(7, libc::O_WRONLY);
+    assert!(handles.read_only(reader));
+    assert!(!handles.read_only(writer));
+    // Unknown handles aren't refused here, the vault decides.
+    assert!(!handles.read_only(999));
+}
@@ -0,0 +1,129 @@
+/// Fuzzing the framing of write streams, see
+/// `vault_server::WriteStream`: streams cut into chunks the way
+/// peers send them are put back together, and streams a buggy peer
+/// could send are refused rather than written in the wrong place.
+use monovault::manifest::hash_chunk;
+use monovault::types::*;
+use monovault::vault_server::WriteStream;
+use proptest::prelude::*;
+
+/// A chunk as sent on the wire: file, offset, data, hash.
+type Chunk = (u64, i64, Vec<u8>, Vec<u8>);
+
+/// Cut `data`, written at `offset` of `file`, into chunks of
+/// `block_size` bytes, like `WriteIterator` does.
+fn chunks(file: u64, offset: i64, data: &[u8], block_size: usize) -> Vec<Chunk> {
+    let algorithm = HashAlgorithm::default();
+    let mut result = vec![];
+    let mut sent = 0;
+    loop {
+        let end = std::cmp::min(sent + block_size, data.len());
+        let chunk = data[sent..end].to_vec();
+        let hash = hash_chunk(algorithm, &chunk);
+        result.push((file, offset + sent as i64, chunk, hash));
+        sent = end;
+        if sent >= data.len() {
+            return result;
+        }
+    }
+}
+
+/// Push `chunks` to a new stream, return the stream or the first
+/// error.
+fn receive(mut stream: WriteStream, chunks: Vec<Chunk>) -> VaultResult<WriteStream> {
+    let algorithm = HashAlgorithm::default().code();
+    for (file, offset, data, hash) in chunks {
+        stream.push(file, offset, data, &hash, algorithm)?;
+    }
+    Ok(stream)
+}
+
+proptest! {
+    #[test]
+    fn chunked_streams_are_put_back_together(
+        file in 1..u64::MAX,
+        offset in 0..i64::MAX / 2,
+        data in prop::collection::vec(any::<u8>(), 0..256),
+        block_size in 1..64usize,
+    ) {
+        let stream = receive(WriteStream::new(), chunks(file, offset, &data, block_size)).unwrap();
+        prop_assert_eq!(stream.file, file);
+        prop_assert_eq!(stream.offset, offset);
+        prop_assert_eq!(stream.data, data);
+    }
+
+    #[test]
+    fn reordered_or_dropped_chunks_are_refused(
+        data in prop::collection::vec(any::<u8>(), 2..256),
+        block_size in 1..64usize,
+        pick in any::<prop::sample::Index>(),
+        swap in any::<bool>(),
+    ) {
+        let mut sent = chunks(7, 0, &data, block_size);
+        prop_assume!(sent.len() >= 2);
+        // Any chunk but the last: a stream cut short at a chunk
+        // boundary looks like a shorter write.
+        let idx = pick.index(sent.len() - 1);
+        if swap {
+            sent.swap(idx, idx + 1);
+        } else {
+            sent.remove(idx);
+        }
+        prop_assert!(receive(WriteStream::new(), sent).is_err());
+    }
+
+    #[test]
+    fn corrupted_chunks_are_refused(
+        data in prop::collection::vec(any::<u8>(), 1..256),
+        block_size in 1..64usize,
+        pick in any::<prop::sample::Index>(),
+        flip in 1..=255u8,
+    ) {
+        let mut sent = chunks(7, 0, &data, block_size);
+        let idx = pick.index(data.len());
+        sent[idx / block_size].2[idx % block_size] ^= flip;
+        prop_assert!(matches!(
+            receive(WriteStream::new(), sent),
+            Err(VaultError::ChunkMismatch(7, _))
+        ));
+    }
+
+    #[test]
+    fn any_chunk_is_checked_without_panicking(
+        headers in prop::collection::vec((0..4u64, any::<i64>(), 0..64usize), 1..8),
+        whole in any::<bool>(),
+    ) {
+        let mut stream = if whole { WriteStream::from_start() } else { WriteStream::new() };
+        for (file, offset, size) in headers {
+            // No hash: the framing alone must hold.
+            if stream.push(file, offset, vec![0; size], &[], 0).is_err() {
+                break;
+            }
+            prop_assert!(offset >= 0);
+            prop_assert!(offset.checked_add(size as i64).is_some());
+        }
+        prop_assert!(stream.offset >= 0);
+        if whole {
+            prop_assert_eq!(stream.offset, 0);
+        }
+        prop_assert!(check_range(stream.offset, stream.data.len() as u64).is_ok());
+    }
+}
+
+#[test]
+fn streams_of_other_files_are_refused() {
+    let mut sent = chunks(7, 0, b"hello world", 4);
+    sent[1].0 = 8;
+    assert!(matches!(
+        receive(WriteStream::new(), sent),
+        Err(VaultError::InvalidArgument(_))
+    ));
+    // Submit sends whole files.
+    let sent = chunks(7, 4, b"hello", 4);
+    assert!(receive(WriteStream::from_start(), sent).is_err());
+    let sent = chunks(7, 0, b"hello", 4);
+    assert_eq!(
+        receive(WriteStream::from_start(), sent).unwrap().data,
+        b"hello"
+    );
+}
@@ -0,0 +1,91 @@
+/// Generations of inodes, see `Database::generation`.
+mod common;
+
+use common::*;
+use monovault::database::Database;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn reused_inodes_get_a_new_generation() {
+    let store = tempfile::tempdir().unwrap();
+    let mut vault = open_vault(store.path());
+    let kept = vault.create(ROOT, "kept", VaultFileType::File).unwrap();
+    vault.close(kept).unwrap();
+    let deleted = vault.create(ROOT, "deleted", VaultFileType::File).unwrap();
+    vault.close(deleted).unwrap();
+    let old = vault.attr(deleted).unwrap().generation;
+    assert_eq!(vault.attr(kept).unwrap().generation, old);
+    vault.delete(deleted).unwrap();
+    drop(vault);
+
+    let mut vault = open_vault(store.path());
+    let new = vault.create(ROOT, "new", VaultFileType::File).unwrap();
+    vault.close(new).unwrap();
+    // Same inode, another file.
+    assert_eq!(new, deleted);
+    assert!(vault.attr(new).unwrap().generation > old);
+    assert_eq!(vault.attr(kept).unwrap().generation, old);
+}
+
+#[test]
+fn opening_to_read_keeps_the_generation() {
+    let store = tempfile::tempdir().unwrap();
+    let mut vault = open_vault(store.path());
+    let first = vault.create(ROOT, "first", VaultFileType::File).unwrap();
+    vault.close(first).unwrap();
+    let old = vault.attr(first).unwrap().generation;
+    drop(vault);
+    // Like du and the other admin commands.
+    for _ in 0..3 {
+        let database = Database::new(&store.path().join("db"), "alice").unwrap();
+        assert_eq!(database.generation(), None);
+    }
+    let mut vault = open_vault(store.path());
+    let second = vault.create(ROOT, "second", VaultFileType::File).unwrap();
+    vault.close(second).unwrap();
+    assert_eq!(vault.attr(second).unwrap().generation, old + 1);
+}
+
+#[test]
+fn files_created_through_a_cache() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let cache = cluster.node("bob").cache_of("alice");
+    let file = create_file(&cache, ROOT, "note", b"hello");
+    let generation = alice.local.lock().unwrap().attr(file).unwrap().generation;
+    cluster.cut("bob", "alice");
+    assert_eq!(
+        cache.lock().unwrap().attr(file).unwrap().generation,
+        generation
+    );
+}
+
+#[test]
+fn peers_and_caches_report_generations() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let generation = alice.local.lock().unwrap().attr(file).unwrap().generation;
+    assert!(generation > 0);
+
+    let remote = bob.remote_of("alice");
+    assert_eq!(
+        remote.lock().unwrap().attr(file).unwrap().generation,
+        generation
+    );
+    let listed = remote.lock().unwrap().readdir(ROOT).unwrap();
+    let entry = listed.iter().find(|info| info.inode == file).unwrap();
+    assert_eq!(entry.generation, generation);
+
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+    // Offline, the cache answers from its copy.
+    cluster.cut("bob", "alice");
+    assert_eq!(
+        cache.lock().unwrap().attr(file).unwrap().generation,
+        generation
+    );
+}
@@ -0,0 +1,74 @@
+/// Interrupting requests of killed processes, see src/interrupt.rs.
+use monovault::interrupt::*;
+use monovault::vault_queue::VaultQueue;
+use std::sync::mpsc;
+
+fn status(pending: &str, blocked: &str, ignored: &str) -> String {
+    format!(
+        "Name:\tcat\nState:\tS (sleeping)\nShdPnd:\t{}\nSigBlk:\t{}\nSigIgn:\t{}\nSigCgt:\t0000000000000000\n",
+        pending, blocked, ignored
+    )
+}
+
+#[test]
+fn ending_signals_are_recognized() {
+    let none = "0000000000000000";
+    let sigint = "0000000000000002";
+    let sigkill = "0000000000000100";
+    // SIGCHLD ends nothing.
+    let sigchld = "0000000000010000";
+    assert!(!ending_signal_pending(&status(none, none, none)));
+    assert!(ending_signal_pending(&status(sigint, none, none)));
+    assert!(!ending_signal_pending(&status(sigchld, none, none)));
+    assert!(!ending_signal_pending(&status(sigint, sigint, none)));
+    assert!(!ending_signal_pending(&status(sigint, none, sigint)));
+    // SIGKILL can't be blocked.
+    assert!(ending_signal_pending(&status(sigkill, sigkill, none)));
+    assert!(!ending_signal_pending(""));
+}
+
+#[test]
+fn queued_requests_serve_the_same_process() {
+    let queue = VaultQueue::new("alice", 2);
+    let (tx, rx) = mpsc::channel();
+    {
+        let _serving = serving(4242);
+        let tx = tx.clone();
+        queue
+            .reserve()
+            .unwrap()
+            .run(move || tx.send(requester()).unwrap());
+    }
+    assert_eq!(requester(), None);
+    queue
+        .reserve()
+        .unwrap()
+        .run(move || tx.send(requester()).unwrap());
+    assert_eq!(rx.recv().unwrap(), Some(4242));
+    assert_eq!(rx.recv().unwrap(), None);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn signal_to_stopped_process_is_pending() {
+    use std::process::Command;
+    use std::thread;
+    use std::time::Duration;
+
+    let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+    let pid = child.id();
+    // A stopped process leaves the signals it gets pending.
+    unsafe { libc::kill(pid as i32, libc::SIGSTOP) };
+    thread::sleep(Duration::from_millis(100));
+    assert!(!interrupted(pid));
+    unsafe { libc::kill(pid as i32, libc::SIGTERM) };
+    let mut waited = 0;
+    while !interrupted(pid) && waited < 50 {
+        thread::sleep(Duration::from_millis(20));
+        waited += 1;
+    }
+    assert!(interrupted(pid));
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(!interrupted(pid));
+}
@@ -0,0 +1,91 @@
+/// The layouts of ioctl commands, see src/ioctl.rs, and what
+/// IOC_SYNC_STATE reports, see `fuse::sync_state`.
+mod common;
+
+use common::*;
+use monovault::fuse::sync_state;
+use monovault::ioctl::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn request_numbers_tell_their_size() {
+    for (cmd, command) in [
+        (IOC_WHERE, Command::Where),
+        (IOC_SYNC_STATE, Command::SyncState),
+        (IOC_REFRESH, Command::Refresh),
+        (IOC_DELETE_TREE, Command::DeleteTree),
+    ] {
+        assert_eq!(Command::of_request(cmd), Some(command));
+        assert_eq!(((cmd >> 16) & 0x1fff) as usize, command.reply_size());
+        assert_eq!((cmd >> 8) & 0xff, IOCTL_TYPE as u32);
+    }
+    assert_eq!(Command::of_request(0x5401), None);
+    #[cfg(target_os = "linux")]
+    {
+        // _IOR('M', 1, char[256]), _IO('M', 3) and _IO('M', 4).
+        assert_eq!(IOC_WHERE, 0x8100_4d01);
+        assert_eq!(IOC_REFRESH, 0x4d03);
+        assert_eq!(IOC_DELETE_TREE, 0x4d04);
+    }
+}
+
+#[test]
+fn replies_decode() {
+    let place = Where {
+        vault: "alice".to_string(),
+        inode: 0x214,
+    };
+    let data = place.encode().unwrap();
+    assert_eq!(data.len(), WHERE_SIZE);
+    assert_eq!(Where::decode(&data), Some(place));
+    assert!(Where {
+        vault: "x".repeat(WHERE_SIZE),
+        inode: 1,
+    }
+    .encode()
+    .is_err());
+
+    let state = SyncState {
+        status: SyncState::status_code("cached"),
+        pending: 2,
+        fetched: 10,
+        total: 30,
+    };
+    assert_eq!(state.status, 2);
+    let data = state.encode();
+    assert_eq!(data.len(), SYNC_STATE_SIZE);
+    assert_eq!(SyncState::decode(&data), Some(state));
+    assert_eq!(SyncState::decode(&data[1..]), None);
+}
+
+#[test]
+fn sync_state_counts_pending_changes() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let state = sync_state(&mut alice.local.lock().unwrap(), file, "local");
+    assert_eq!(state, SyncState::default());
+
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+    read_file(&cache, file).unwrap();
+    cluster.cut("bob", "alice");
+    write_file(&cache, file, b"offline").unwrap();
+    let state = sync_state(&mut cache.lock().unwrap(), file, "cached");
+    assert_eq!(state.status, SyncState::status_code("cached"));
+    assert!(state.pending >= 1);
+    // Other files have nothing pending.
+    assert_eq!(
+        sync_state(&mut cache.lock().unwrap(), ROOT, "cached").pending,
+        0
+    );
+
+    cluster.heal("bob", "alice");
+    assert!(bob.wait_synced("alice"));
+    let state = sync_state(&mut cache.lock().unwrap(), file, "online");
+    assert_eq!(state.pending, 0);
+    assert_eq!((state.fetched, state.total), (0, 0));
+}
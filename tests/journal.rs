@@ -0,0 +1,93 @@
+/// Operations on data files cut short by a crash, see
+/// `Database::journal`.
+mod common;
+
+use common::*;
+use monovault::database::{Database, Intent};
+use monovault::types::*;
+use std::fs;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn interrupted_operations_are_finished_on_start() {
+    let store = tempfile::tempdir().unwrap();
+    let data = |file: Inode| store.path().join("data").join(format!("alice-{}", file));
+    let mut vault = open_vault(store.path());
+    let kept = vault.create(ROOT, "kept", VaultFileType::File).unwrap();
+    vault.close(kept).unwrap();
+    let deleted = vault.create(ROOT, "deleted", VaultFileType::File).unwrap();
+    vault.close(deleted).unwrap();
+    drop(vault);
+
+    let mut database = Database::new(&store.path().join("db"), "alice").unwrap();
+    assert!(database.journaled().unwrap().is_empty());
+    // A create that made the data file but not the metadata.
+    let orphan = deleted + 1;
+    database.journal(orphan, Intent::Create).unwrap();
+    fs::write(data(orphan), b"stale").unwrap();
+    // A delete that removed the metadata but not the data file.
+    database.journal(deleted, Intent::Delete).unwrap();
+    database.remove_file(deleted).unwrap();
+    // A delete that didn't get to remove anything.
+    database.journal(kept, Intent::Delete).unwrap();
+    drop(database);
+
+    let mut vault = open_vault(store.path());
+    assert!(!data(orphan).exists());
+    assert!(!data(deleted).exists());
+    assert!(data(kept).exists());
+    assert_eq!(vault.attr(kept).unwrap().name, "kept");
+    // Inodes without metadata are handed out again, without the old
+    // data.
+    let new = vault.create(ROOT, "new", VaultFileType::File).unwrap();
+    assert_eq!(new, deleted);
+    assert_eq!(vault.read(new, 0, 100).unwrap(), b"");
+    vault.close(new).unwrap();
+    drop(vault);
+
+    let database = Database::new(&store.path().join("db"), "alice").unwrap();
+    assert!(database.journaled().unwrap().is_empty());
+}
+
+#[test]
+fn synced_writes_survive_a_crash() {
+    let store = tempfile::tempdir().unwrap();
+    let mut vault = open_vault(store.path());
+    let synced = vault.create(ROOT, "synced", VaultFileType::File).unwrap();
+    vault.write(synced, 0, b"hello").unwrap();
+    vault.close(synced).unwrap();
+    let version = vault.attr(synced).unwrap().version;
+    let unsynced = vault.create(ROOT, "unsynced", VaultFileType::File).unwrap();
+    vault.write(unsynced, 0, b"hello").unwrap();
+    vault.close(unsynced).unwrap();
+
+    // Stop with both open and changed, only one synced.
+    vault.open(synced, OpenMode::RW).unwrap();
+    vault.write(synced, 0, b"HELLO world").unwrap();
+    vault.fsync(synced).unwrap();
+    vault.open(unsynced, OpenMode::RW).unwrap();
+    vault.write(unsynced, 0, b"HELLO world").unwrap();
+    drop(vault);
+
+    let mut vault = open_vault(store.path());
+    assert_eq!(vault.read(synced, 0, 100).unwrap(), b"HELLO world");
+    let info = vault.attr(synced).unwrap();
+    assert_eq!(info.size, 11);
+    assert_eq!(info.version, (version.0, version.1 + 1));
+    assert_eq!(vault.read(unsynced, 0, 100).unwrap(), b"hello");
+    drop(vault);
+
+    // Closing settles the sync.
+    let database = Database::new(&store.path().join("db"), "alice").unwrap();
+    assert!(database.journaled().unwrap().is_empty());
+    drop(database);
+    let mut vault = open_vault(store.path());
+    vault.open(synced, OpenMode::RW).unwrap();
+    vault.write(synced, 0, b"bye").unwrap();
+    vault.fsync(synced).unwrap();
+    vault.close(synced).unwrap();
+    drop(vault);
+    let database = Database::new(&store.path().join("db"), "alice").unwrap();
+    assert!(database.journaled().unwrap().is_empty());
+}
@@ -0,0 +1,47 @@
+/// Sizes past 4 GiB, see `RemoteVault::max_transfer`.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+const GIB: u64 = 1 << 30;
+
+#[test]
+fn reads_past_32_bits() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    find(&bob.cache_of("alice"), ROOT, "note").unwrap();
+    // Cut down to 32 bits this would be a read of nothing.
+    for vault in [
+        &alice.local,
+        &bob.remote_of("alice"),
+        &bob.cache_of("alice"),
+    ] {
+        let mut vault = vault.lock().unwrap();
+        vault.open(file, OpenMode::R).unwrap();
+        assert_eq!(vault.read(file, 0, 4 * GIB).unwrap(), b"hello");
+        vault.close(file).unwrap();
+    }
+}
+
+#[test]
+#[ignore = "needs 5 GiB on file systems without sparse files"]
+fn sparse_file_past_4_gib() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let file = create_file(&alice.local, ROOT, "disk", b"");
+    let remote = cluster.node("bob").remote_of("alice");
+    let mut vault = remote.lock().unwrap();
+    vault.truncate(file, 5 * GIB).unwrap();
+    vault.open(file, OpenMode::RW).unwrap();
+    assert_eq!(vault.write(file, (5 * GIB) as i64, b"end").unwrap(), 3);
+    assert_eq!(vault.attr(file).unwrap().size, 5 * GIB + 3);
+    assert_eq!(
+        vault.read(file, (5 * GIB - 2) as i64, 16).unwrap(),
+        b"\0\0end"
+    );
+    vault.close(file).unwrap();
+}
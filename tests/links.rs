@@ -0,0 +1,115 @@
+/// Hard links within a vault.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn nlink(vault: &VaultRef, file: Inode) -> u64 {
+    vault.lock().unwrap().attr(file).unwrap().nlink
+}
+
+#[test]
+fn links_share_content_and_count() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    let dir = mkdir(local, ROOT, "dir");
+    let file = create_file(local, ROOT, "note", b"hello");
+    assert_eq!(nlink(local, file), 1);
+
+    local.lock().unwrap().link(file, dir, "alias").unwrap();
+    assert_eq!(nlink(local, file), 2);
+    assert_eq!(find(local, dir, "alias").unwrap(), Some(file));
+    assert_eq!(find(local, ROOT, "note").unwrap(), Some(file));
+    write_file(local, file, b"HELLO").unwrap();
+    assert_eq!(read_file(local, file).unwrap(), b"HELLO");
+    // A file is counted once.
+    assert_eq!(local.lock().unwrap().info(ROOT).unwrap().count, 2);
+
+    // Removing the first name keeps the file under the other.
+    local.lock().unwrap().unlink(file, ROOT, "note").unwrap();
+    assert_eq!(find(local, ROOT, "note").unwrap(), None);
+    assert_eq!(find(local, dir, "alias").unwrap(), Some(file));
+    assert_eq!(nlink(local, file), 1);
+    assert_eq!(read_file(local, file).unwrap(), b"HELLO");
+    // Removing the last one deletes it.
+    local.lock().unwrap().unlink(file, dir, "alias").unwrap();
+    assert!(local.lock().unwrap().attr(file).is_err());
+}
+
+#[test]
+fn link_errors_and_trees() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    let dir = mkdir(local, ROOT, "dir");
+    let file = create_file(local, ROOT, "note", b"hello");
+    let mut vault = local.lock().unwrap();
+    assert!(matches!(
+        vault.link(dir, ROOT, "again"),
+        Err(VaultError::IsDirectory(_))
+    ));
+    assert!(matches!(
+        vault.link(file, ROOT, "dir"),
+        Err(VaultError::FileAlreadyExist(_, _))
+    ));
+    assert!(matches!(
+        vault.unlink(file, dir, "note"),
+        Err(VaultError::FileNotExist(_))
+    ));
+
+    // A directory with a link in it isn't empty.
+    vault.link(file, dir, "alias").unwrap();
+    assert!(matches!(
+        vault.delete(dir),
+        Err(VaultError::DirectoryNotEmpty(_))
+    ));
+    // Deleting the tree removes the link but not the file.
+    assert_eq!(vault.delete_tree(dir, 10).unwrap(), 1);
+    assert_eq!(vault.attr(file).unwrap().nlink, 1);
+
+    // A file whose first name is in a deleted tree keeps its others.
+    let dir = vault.create(ROOT, "dir", VaultFileType::Directory).unwrap();
+    let inside = vault.create(dir, "inside", VaultFileType::File).unwrap();
+    vault.close(inside).unwrap();
+    vault.link(inside, ROOT, "outside").unwrap();
+    vault.delete_tree(dir, 10).unwrap();
+    let info = vault.attr(inside).unwrap();
+    assert_eq!((info.name.as_str(), info.nlink), ("outside", 1));
+}
+
+#[test]
+fn links_through_cache() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let cache = cluster.node("bob").cache_of("alice");
+    let dir = mkdir(&alice.local, ROOT, "dir");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    find(&cache, ROOT, "note").unwrap();
+    find(&cache, dir, "none").unwrap();
+
+    cache.lock().unwrap().link(file, dir, "alias").unwrap();
+    assert_eq!(find(&alice.local, dir, "alias").unwrap(), Some(file));
+    assert_eq!(find(&cache, dir, "alias").unwrap(), Some(file));
+    assert_eq!(nlink(&cache, file), 2);
+
+    // Changes on the remote show up in listings.
+    alice
+        .local
+        .lock()
+        .unwrap()
+        .unlink(file, dir, "alias")
+        .unwrap();
+    assert_eq!(find(&cache, dir, "alias").unwrap(), None);
+    alice
+        .local
+        .lock()
+        .unwrap()
+        .link(file, dir, "other")
+        .unwrap();
+    assert_eq!(find(&cache, dir, "other").unwrap(), Some(file));
+
+    cache.lock().unwrap().unlink(file, ROOT, "note").unwrap();
+    assert_eq!(find(&alice.local, ROOT, "note").unwrap(), None);
+    assert_eq!(read_file(&cache, file).unwrap(), b"hello");
+}
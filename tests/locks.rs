@@ -0,0 +1,194 @@
+/// Byte-range locks, see src/locks.rs.
+mod common;
+
+use common::*;
+use monovault::locks::{LockKind, LockTable};
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn ranges_and_owners() {
+    let mut table = LockTable::new();
+    table
+        .set(1, lock("alice", 1, 0, 99, LockKind::Read))
+        .unwrap();
+    // Readers share, writers don't.
+    table
+        .set(1, lock("bob", 1, 50, 149, LockKind::Read))
+        .unwrap();
+    assert!(table
+        .conflict(1, &lock("bob", 2, 90, 99, LockKind::Write))
+        .is_some());
+    assert!(table
+        .conflict(1, &lock("bob", 2, 150, 199, LockKind::Write))
+        .is_none());
+    // The same id on another host is someone else.
+    assert!(matches!(
+        table.set(1, lock("bob", 1, 0, 9, LockKind::Write)),
+        Err(VaultError::LockConflict(1))
+    ));
+    // Other files are apart.
+    table.set(2, lock("bob", 1, 0, 9, LockKind::Write)).unwrap();
+
+    // Unlocking the middle keeps both ends.
+    table
+        .set(1, lock("alice", 1, 10, 19, LockKind::Unlock))
+        .unwrap();
+    let writer = |start, end| lock("carol", 1, start, end, LockKind::Write);
+    assert!(table.conflict(1, &writer(10, 19)).is_none());
+    assert!(table.conflict(1, &writer(0, 9)).is_some());
+    assert!(table.conflict(1, &writer(20, 29)).is_some());
+    // An owner can turn its read lock into a write lock, if nobody
+    // else reads the range.
+    table
+        .set(1, lock("alice", 1, 0, 9, LockKind::Write))
+        .unwrap();
+    let conflict = table.conflict(1, &lock("bob", 1, 5, 5, LockKind::Read));
+    assert_eq!(conflict.unwrap().kind, LockKind::Write);
+    assert!(table
+        .set(1, lock("alice", 1, 60, 69, LockKind::Write))
+        .is_err());
+
+    table
+        .set(1, lock("alice", 1, 0, u64::MAX, LockKind::Unlock))
+        .unwrap();
+    table
+        .set(1, lock("bob", 1, 0, u64::MAX, LockKind::Unlock))
+        .unwrap();
+    assert!(table.conflict(1, &writer(0, u64::MAX)).is_none());
+}
+
+#[test]
+fn releasing_an_owner_keeps_the_others() {
+    // Like two open files holding shared flock(2) locks.
+    let mut table = LockTable::new();
+    table
+        .set(1, lock("alice", 1, 0, u64::MAX, LockKind::Read))
+        .unwrap();
+    table
+        .set(1, lock("alice", 2, 0, u64::MAX, LockKind::Read))
+        .unwrap();
+    let writer = lock("alice", 3, 0, u64::MAX, LockKind::Write);
+    // Closing the first file releases what it held.
+    table
+        .set(1, lock("alice", 1, 0, u64::MAX, LockKind::Unlock))
+        .unwrap();
+    assert_eq!(table.conflict(1, &writer).unwrap().owner.id, 2);
+    // Releasing an owner that holds nothing is fine.
+    table
+        .set(1, lock("alice", 1, 0, u64::MAX, LockKind::Unlock))
+        .unwrap();
+    table
+        .set(1, lock("alice", 2, 0, u64::MAX, LockKind::Unlock))
+        .unwrap();
+    table.set(1, writer).unwrap();
+}
+
+#[test]
+fn peers_contend_on_the_owners_table() {
+    let cluster = Cluster::running(&["alice", "bob", "carol"]);
+    let alice = cluster.node("alice");
+    let file = create_file(&alice.local, ROOT, "db.sqlite", b"");
+    let bob = cluster.node("bob").cache_of("alice");
+    let carol = cluster.node("carol").remote_of("alice");
+    assert_eq!(find(&bob, ROOT, "db.sqlite").unwrap(), Some(file));
+
+    bob.lock()
+        .unwrap()
+        .setlk(file, &lock("bob", 7, 0, u64::MAX, LockKind::Write))
+        .unwrap();
+    // Alice and Carol both see Bob's lock.
+    let wanted = lock("alice", 7, 100, 199, LockKind::Read);
+    let held = alice.local.lock().unwrap().getlk(file, &wanted).unwrap();
+    // The owner records where Bob connected from, not only the name
+    // it gave.
+    assert_eq!(held.unwrap().owner.host, "127.0.0.1/bob");
+    let wanted = lock("carol", 7, 0, 0, LockKind::Write);
+    let held = carol.lock().unwrap().getlk(file, &wanted).unwrap();
+    assert_eq!(held.unwrap().owner.host, "127.0.0.1/bob");
+    assert!(matches!(
+        carol.lock().unwrap().setlk(file, &wanted),
+        Err(VaultError::LockConflict(_))
+    ));
+
+    bob.lock()
+        .unwrap()
+        .setlk(file, &lock("bob", 7, 0, u64::MAX, LockKind::Unlock))
+        .unwrap();
+    carol.lock().unwrap().setlk(file, &wanted).unwrap();
+    // A peer can't pose as the owner to release its locks.
+    let mine = lock("alice", 8, 500, 599, LockKind::Write);
+    alice.local.lock().unwrap().setlk(file, &mine).unwrap();
+    carol
+        .lock()
+        .unwrap()
+        .setlk(file, &lock("alice", 8, 500, 599, LockKind::Unlock))
+        .unwrap();
+    let held = alice
+        .local
+        .lock()
+        .unwrap()
+        .getlk(file, &lock("dave", 1, 500, 500, LockKind::Read))
+        .unwrap();
+    assert_eq!(held.unwrap().owner.host, "alice");
+    // Unknown files can't be locked.
+    assert!(carol.lock().unwrap().setlk(12345, &wanted).is_err());
+}
+
+#[test]
+fn departed_peers_lose_their_locks() {
+    let mut table = LockTable::new();
+    table
+        .set(1, lock("alice", 1, 0, 9, LockKind::Write))
+        .unwrap();
+    table
+        .set(1, lock("10.0.0.2/bob", 1, 10, 19, LockKind::Write))
+        .unwrap();
+    table
+        .set(2, lock("10.0.0.3/carol", 1, 0, 9, LockKind::Write))
+        .unwrap();
+    // Bob went away after locking, Carol before, and we never lost
+    // Alice, which is us.
+    table.forget_departed(|peer| match peer {
+        "bob" => Some(u64::MAX),
+        "carol" => Some(0),
+        _ => None,
+    });
+    let wanted = |start| lock("dave", 1, start, start, LockKind::Read);
+    assert!(table.conflict(1, &wanted(0)).is_some());
+    assert!(table.conflict(1, &wanted(10)).is_none());
+    assert!(table.conflict(2, &wanted(0)).is_some());
+
+    // Locks Bob takes once it is back count from then on.
+    table
+        .set(1, lock("10.0.0.2/bob", 1, 10, 19, LockKind::Write))
+        .unwrap();
+    table.forget_departed(|_| Some(1));
+    assert!(table.conflict(1, &wanted(10)).is_some());
+}
+
+#[test]
+fn locks_go_with_the_connection() {
+    let cluster = Cluster::running(&["alice", "bob", "carol"]);
+    let alice = cluster.node("alice");
+    let file = create_file(&alice.local, ROOT, "db.sqlite", b"");
+    let bob = cluster.node("bob").remote_of("alice");
+    let carol = cluster.node("carol").remote_of("alice");
+    // Alice talks to Bob.
+    find(&alice.remote_of("bob"), ROOT, "anything").unwrap();
+    bob.lock()
+        .unwrap()
+        .setlk(file, &lock("bob", 7, 0, u64::MAX, LockKind::Write))
+        .unwrap();
+    let wanted = lock("carol", 7, 0, 0, LockKind::Write);
+    assert!(matches!(
+        carol.lock().unwrap().setlk(file, &wanted),
+        Err(VaultError::LockConflict(_))
+    ));
+
+    // Bob goes away, as far as Alice can tell, and its locks with it.
+    cluster.cut("alice", "bob");
+    assert!(find(&alice.remote_of("bob"), ROOT, "anything").is_err());
+    carol.lock().unwrap().setlk(file, &wanted).unwrap();
+}
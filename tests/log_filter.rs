@@ -0,0 +1,54 @@
+/// Changing log filters at runtime, see src/log_filter.rs.
+mod common;
+
+use common::*;
+use log::LevelFilter;
+use monovault::log_filter;
+use monovault::types::*;
+
+#[test]
+fn parse_and_merge() {
+    let mut directives = log_filter::parse("warn, monovault::fuse=info,hyper").unwrap();
+    assert_eq!(
+        directives,
+        vec![
+            (None, LevelFilter::Warn),
+            (Some("monovault::fuse".to_string()), LevelFilter::Info),
+            (Some("hyper".to_string()), LevelFilter::Trace),
+        ]
+    );
+    log_filter::merge(
+        &mut directives,
+        log_filter::parse("monovault::fuse=debug,monovault::remote_vault=debug").unwrap(),
+    );
+    assert_eq!(
+        log_filter::format(&directives),
+        "warn,monovault::fuse=debug,hyper=trace,monovault::remote_vault=debug"
+    );
+    assert!(log_filter::parse("").unwrap().is_empty());
+    assert!(log_filter::parse("monovault::fuse=loud").is_err());
+    assert!(log_filter::parse("=debug").is_err());
+    assert!(log_filter::parse("info/regex").is_err());
+}
+
+#[test]
+fn filters_change_through_the_server() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let remote = cluster.node("bob").remote_of("alice");
+    let mut remote = remote.lock().unwrap();
+    let server = match &mut *remote {
+        GenericVault::Remote(server) => server,
+        _ => unreachable!(),
+    };
+    // The nodes of the cluster share this process, and its filters.
+    let startup = server.log_filter("", true).unwrap();
+    let spec = server
+        .log_filter("monovault::remote_vault=debug", false)
+        .unwrap();
+    assert!(spec.ends_with("monovault::remote_vault=debug"));
+    assert_eq!(log_filter::current(), spec);
+    assert!(log::max_level() >= LevelFilter::Debug);
+    assert!(server.log_filter("monovault::fuse=loud", false).is_err());
+    assert_eq!(server.log_filter("", false).unwrap(), spec);
+    assert_eq!(server.log_filter("", true).unwrap(), startup);
+}
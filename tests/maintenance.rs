@@ -0,0 +1,44 @@
+/// Upkeep of vault databases, see `Database::maintain`.
+use monovault::events::EventBus;
+use monovault::local_vault::LocalVault;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn space_of_deleted_rows_is_given_back() {
+    let store = tempfile::tempdir().unwrap();
+    let mut vault = LocalVault::new(
+        "alice",
+        store.path(),
+        EventBus::new(),
+        MissingDataPolicy::Error,
+    )
+    .unwrap();
+    // Nothing to give back in a fresh database.
+    assert_eq!(vault.maintain_database().unwrap(), None);
+
+    let value = vec![b'x'; 60 * 1024];
+    let mut files = vec![];
+    for idx in 0..200 {
+        let file = vault
+            .create(ROOT, &format!("file{}", idx), VaultFileType::File)
+            .unwrap();
+        vault.close(file).unwrap();
+        vault.set_xattr(file, "user.bulk", &value).unwrap();
+        files.push(file);
+    }
+    // Most of the database is in use.
+    assert_eq!(vault.maintain_database().unwrap(), None);
+
+    for file in files {
+        vault.delete(file).unwrap();
+    }
+    let freed = vault.maintain_database().unwrap().unwrap();
+    assert!(freed > 8 * 1024 * 1024);
+    assert_eq!(vault.maintain_database().unwrap(), None);
+    // The vault still works.
+    let file = vault.create(ROOT, "after", VaultFileType::File).unwrap();
+    vault.close(file).unwrap();
+    assert_eq!(vault.path_of(file).unwrap(), "/after");
+}
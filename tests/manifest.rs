@@ -0,0 +1,276 @@
+/// Chunk manifests and verified downloads, see src/manifest.rs.
+mod common;
+
+use common::*;
+use monovault::caching_remote::read_unlocked;
+use monovault::faults::FaultConfig;
+use monovault::manifest::{Manifest, MANIFEST_CHUNK_SIZE};
+use monovault::types::*;
+use std::sync::Arc;
+use std::thread;
+
+const ROOT: Inode = 1;
+
+fn manifest_of(vault: &VaultRef, file: Inode) -> Manifest {
+    unpack_to_remote(&mut vault.lock().unwrap())
+        .unwrap()
+        .manifest(file)
+        .unwrap()
+}
+
+#[test]
+fn manifest_describes_content() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let data = content(MANIFEST_CHUNK_SIZE * 5 / 2);
+    let file = create_file(&alice.local, ROOT, "big", &data);
+    let version = alice.local.lock().unwrap().attr(file).unwrap().version;
+
+    let manifest = manifest_of(&bob.remote_of("alice"), file);
+    assert_eq!(manifest.chunks.len(), 3);
+    assert_eq!(
+        manifest,
+        Manifest::of_data(&data, version, HashAlgorithm::Blake3)
+    );
+
+    // A change gives a new manifest.
+    write_file(&alice.local, file, b"changed").unwrap();
+    let manifest = manifest_of(&bob.remote_of("alice"), file);
+    assert_ne!(manifest.version, version);
+    let mut data = data;
+    data[..7].copy_from_slice(b"changed");
+    assert_eq!(
+        manifest,
+        Manifest::of_data(&data, manifest.version, manifest.algorithm)
+    );
+}
+
+#[test]
+fn manifests_that_miss_chunks_are_refused() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let data = content(MANIFEST_CHUNK_SIZE * 5 / 2);
+    let file = create_file(&alice.local, ROOT, "big", &data);
+    let manifest = manifest_of(&bob.remote_of("alice"), file);
+    assert!(manifest.is_well_formed());
+
+    // Alice serves the manifest she stored, drop its last hash.
+    let db = rusqlite::Connection::open(alice.store().join("db").join("alice.sqlite3")).unwrap();
+    db.execute(
+        "update Manifest set hashes = substr(hashes, 1, length(hashes) - 32) where file = ?",
+        [file],
+    )
+    .unwrap();
+    let result = unpack_to_remote(&mut bob.remote_of("alice").lock().unwrap())
+        .unwrap()
+        .manifest(file);
+    assert!(matches!(result, Err(VaultError::RemoteError(_))));
+    // The cache fetches the file whole instead, like from peers
+    // that don't serve manifests.
+    assert_eq!(read_file(&bob.cache_of("alice"), file).unwrap(), data);
+}
+
+#[test]
+fn hash_algorithm_is_negotiated() {
+    assert_eq!(HashAlgorithm::Blake3.negotiate(&[]), HashAlgorithm::Sha256);
+    assert_eq!(
+        HashAlgorithm::Blake3.negotiate(&[HashAlgorithm::Sha256]),
+        HashAlgorithm::Sha256
+    );
+
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let data = content(MANIFEST_CHUNK_SIZE * 2);
+    let file = create_file(&alice.local, ROOT, "big", &data);
+    let version = alice.local.lock().unwrap().attr(file).unwrap().version;
+    let manifest = manifest_of(&bob.remote_of("alice"), file);
+    assert_eq!(manifest.algorithm, HashAlgorithm::Blake3);
+
+    // Alice prefers SHA-256 and bob knows it, the kept manifest is
+    // computed again.
+    unpack_to_local(&mut alice.local.lock().unwrap())
+        .unwrap()
+        .set_hash_algorithm(HashAlgorithm::Sha256);
+    let manifest = manifest_of(&bob.remote_of("alice"), file);
+    assert_eq!(
+        manifest,
+        Manifest::of_data(&data, version, HashAlgorithm::Sha256)
+    );
+
+    // Uploads hashed with SHA-256 are checked as such.
+    unpack_to_remote(&mut bob.remote_of("alice").lock().unwrap())
+        .unwrap()
+        .set_hash_algorithm(HashAlgorithm::Sha256);
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "big").unwrap();
+    assert_eq!(read_file(&cache, file).unwrap(), data);
+    write_file(&cache, file, b"uploaded").unwrap();
+    assert!(bob.wait_synced("alice"));
+    assert_eq!(&read_file(&alice.local, file).unwrap()[..8], b"uploaded");
+}
+
+#[test]
+fn unchanged_chunks_are_not_fetched_again() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let mut data = content(MANIFEST_CHUNK_SIZE * 3);
+    let file = create_file(&alice.local, ROOT, "big", &data);
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "big").unwrap();
+    assert_eq!(read_file(&cache, file).unwrap(), data);
+
+    // Change the last chunk only.
+    {
+        let mut local = alice.local.lock().unwrap();
+        local.open(file, OpenMode::RW).unwrap();
+        local
+            .write(file, (MANIFEST_CHUNK_SIZE * 5 / 2) as i64, b"changed")
+            .unwrap();
+        local.close(file).unwrap();
+    }
+    let offset = (MANIFEST_CHUNK_SIZE * 5 / 2) as usize;
+    data[offset..offset + 7].copy_from_slice(b"changed");
+
+    let before = bob.received_from("alice");
+    assert_eq!(read_file(&cache, file).unwrap(), data);
+    let received = bob.received_from("alice") - before;
+    assert!(received > MANIFEST_CHUNK_SIZE);
+    assert!(received < MANIFEST_CHUNK_SIZE * 2);
+}
+
+#[test]
+fn corrupted_download_is_refused() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let data = content(MANIFEST_CHUNK_SIZE * 2);
+    let file = create_file(&alice.local, ROOT, "big", &data);
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "big").unwrap();
+
+    bob.set_faults(
+        "alice",
+        Some(FaultConfig {
+            corrupt_rate: 1.0,
+            ..Default::default()
+        }),
+    );
+    assert!(matches!(
+        read_file(&cache, file),
+        Err(VaultError::ChunkMismatch(_, _))
+    ));
+
+    bob.set_faults("alice", None);
+    assert_eq!(read_file(&cache, file).unwrap(), data);
+}
+
+#[test]
+fn corrupted_upload_is_sent_again() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+    read_file(&cache, file).unwrap();
+
+    bob.set_faults(
+        "alice",
+        Some(FaultConfig {
+            corrupt_rate: 1.0,
+            ..Default::default()
+        }),
+    );
+    write_file(&cache, file, b"uploaded").unwrap();
+    // Let the background worker send a few corrupted uploads.
+    let before = bob.faults_injected("alice");
+    assert!(wait_until(|| bob.faults_injected("alice") >= before + 2));
+    assert!(!bob.synced("alice"));
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"hello");
+
+    bob.set_faults("alice", None);
+    assert!(bob.wait_synced("alice"));
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"uploaded");
+}
+
+#[test]
+fn reads_go_on_while_downloading() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let data = content(MANIFEST_CHUNK_SIZE * 5);
+    let file = create_file(&alice.local, ROOT, "big", &data);
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "big").unwrap();
+
+    // Slow enough that the download is still going after the first
+    // chunk arrives.
+    bob.set_faults(
+        "alice",
+        Some(FaultConfig {
+            delay_ms: 300,
+            ..Default::default()
+        }),
+    );
+    let mut vault = cache.lock().unwrap();
+    vault.open(file, OpenMode::R).unwrap();
+    let progress = |vault: &mut GenericVault| {
+        unpack_to_caching(vault)
+            .unwrap()
+            .fetch_progress(file)
+            .unwrap()
+    };
+    // The first read starts the download.
+    assert_eq!(vault.read(file, 10, 100).unwrap(), &data[10..110]);
+    let (done, total) = progress(&mut *vault);
+    assert_eq!(total, data.len() as u64);
+    assert!(done >= MANIFEST_CHUNK_SIZE && done < total);
+
+    assert_eq!(vault.read(file, 0, data.len() as u64).unwrap(), data);
+    assert_eq!(progress(&mut *vault), (total, total));
+    vault.close(file).unwrap();
+    drop(vault);
+
+    bob.set_faults("alice", None);
+    assert_eq!(read_file(&cache, file).unwrap(), data);
+}
+
+#[test]
+fn waiting_reads_leave_the_vault_free() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let data = content(MANIFEST_CHUNK_SIZE * 5);
+    let file = create_file(&alice.local, ROOT, "big", &data);
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "big").unwrap();
+
+    bob.set_faults(
+        "alice",
+        Some(FaultConfig {
+            delay_ms: 300,
+            ..Default::default()
+        }),
+    );
+    cache.lock().unwrap().open(file, OpenMode::R).unwrap();
+    let progress = || {
+        let mut vault = cache.lock().unwrap();
+        unpack_to_caching(&mut vault).unwrap().fetch_progress(file)
+    };
+    // The reader starts the download and waits for the last chunk.
+    let reader = {
+        let cache = Arc::clone(&cache);
+        let offset = data.len() as i64 - 10;
+        thread::spawn(move || read_unlocked(&cache, file, offset, 10))
+    };
+    assert!(wait_until(|| progress().is_some()));
+    // We got the vault while the reader waits.
+    let (done, total) = progress().unwrap();
+    assert!(done < total);
+    assert_eq!(reader.join().unwrap().unwrap(), &data[data.len() - 10..]);
+    cache.lock().unwrap().close(file).unwrap();
+}
@@ -0,0 +1,398 @@
+/// Property tests: run random sequences of operations against a vault
+/// and against an in-memory model of a file system, and check that
+/// they agree after each operation. The caching vault is checked
+/// against a peer in an in-process cluster (see common/mod.rs), and
+/// the peer's copy is checked once uploads are done.
+mod common;
+
+use common::*;
+use monovault::events::EventBus;
+use monovault::local_vault::LocalVault;
+use monovault::types::*;
+use proptest::prelude::*;
+use proptest::sample::Index;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+const ROOT: Inode = 1;
+
+/// Files and directories are picked with an `Index` into the ones
+/// that exist when the operation runs, so most operations hit
+/// something.
+#[derive(Debug, Clone)]
+enum Op {
+    /// Create a file (or a directory if true) with the name in a
+    /// directory.
+    Create(Index, String, bool),
+    /// Write data at offset of a file.
+    Write(Index, u64, Vec<u8>),
+    /// Read size bytes at offset of a file.
+    Read(Index, u64, u32),
+    /// Set the size of a file.
+    Truncate(Index, u64),
+    /// Delete a file or directory.
+    Delete(Index),
+    /// List a directory.
+    Readdir(Index),
+    /// Move a file or directory into a directory under the name.
+    Rename(Index, Index, String),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    // Few short names, so names collide often.
+    prop_oneof![
+        (any::<Index>(), "[ab]{1,2}", any::<bool>())
+            .prop_map(|(dir, name, is_dir)| Op::Create(dir, name, is_dir)),
+        (
+            any::<Index>(),
+            0..64_u64,
+            prop::collection::vec(any::<u8>(), 0..32)
+        )
+            .prop_map(|(file, offset, data)| Op::Write(file, offset, data)),
+        (any::<Index>(), 0..64_u64, 0..64_u32)
+            .prop_map(|(file, offset, size)| Op::Read(file, offset, size)),
+        (any::<Index>(), 0..64_u64).prop_map(|(file, size)| Op::Truncate(file, size)),
+        any::<Index>().prop_map(Op::Delete),
+        any::<Index>().prop_map(Op::Readdir),
+        (any::<Index>(), any::<Index>(), "[ab]{1,2}")
+            .prop_map(|(entry, dir, name)| Op::Rename(entry, dir, name)),
+    ]
+}
+
+struct Entry {
+    parent: Inode,
+    name: String,
+    /// None for directories.
+    content: Option<Vec<u8>>,
+}
+
+/// The file system we expect, keyed by the inodes the vault gave us.
+#[derive(Default)]
+struct Model {
+    entries: HashMap<Inode, Entry>,
+}
+
+impl Model {
+    /// Return all directories, including the root, in a stable order.
+    fn dirs(&self) -> Vec<Inode> {
+        let mut dirs: Vec<Inode> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.content.is_none())
+            .map(|(&inode, _)| inode)
+            .collect();
+        dirs.push(ROOT);
+        dirs.sort_unstable();
+        dirs
+    }
+
+    /// Return all regular files in a stable order.
+    fn files(&self) -> Vec<Inode> {
+        let mut files: Vec<Inode> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.content.is_some())
+            .map(|(&inode, _)| inode)
+            .collect();
+        files.sort_unstable();
+        files
+    }
+
+    /// Return (name, inode, size) of each child of `dir`, sorted.
+    /// Directories have no size.
+    fn children(&self, dir: Inode) -> Vec<(String, Inode, Option<u64>)> {
+        let mut children: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.parent == dir)
+            .map(|(&inode, entry)| {
+                let size = entry.content.as_ref().map(|data| data.len() as u64);
+                (entry.name.clone(), inode, size)
+            })
+            .collect();
+        children.sort();
+        children
+    }
+
+    /// Return true if `inode` is `dir` or somewhere under it.
+    fn is_within(&self, inode: Inode, dir: Inode) -> bool {
+        let mut current = inode;
+        while current != ROOT {
+            if current == dir {
+                return true;
+            }
+            current = self.entries[&current].parent;
+        }
+        dir == ROOT
+    }
+
+    fn is_dir(&self, inode: Inode) -> bool {
+        self.entries[&inode].content.is_none()
+    }
+
+    fn content(&mut self, file: Inode) -> &mut Vec<u8> {
+        self.entries
+            .get_mut(&file)
+            .unwrap()
+            .content
+            .as_mut()
+            .unwrap()
+    }
+}
+
+/// Perform `op` on `vault` and `model`, and check that they agree.
+fn apply(vault: &VaultRef, model: &mut Model, op: &Op) -> Result<(), TestCaseError> {
+    match op {
+        Op::Create(dir, name, is_dir) => {
+            let dirs = model.dirs();
+            let dir = dirs[dir.index(dirs.len())];
+            let kind = if *is_dir {
+                VaultFileType::Directory
+            } else {
+                VaultFileType::File
+            };
+            let result = vault.lock().unwrap().create(dir, name, kind);
+            if model.children(dir).iter().any(|child| &child.0 == name) {
+                prop_assert!(
+                    matches!(result, Err(VaultError::FileAlreadyExist(_, _))),
+                    "{:?}",
+                    result
+                );
+                return Ok(());
+            }
+            let inode = result.map_err(|err| TestCaseError::fail(format!("{:?}", err)))?;
+            prop_assert!(!model.entries.contains_key(&inode) && inode != ROOT);
+            if !*is_dir {
+                // Create also opens the file.
+                vault.lock().unwrap().close(inode).unwrap();
+            }
+            model.entries.insert(
+                inode,
+                Entry {
+                    parent: dir,
+                    name: name.clone(),
+                    content: if *is_dir { None } else { Some(vec![]) },
+                },
+            );
+        }
+        Op::Write(file, offset, data) => {
+            let files = model.files();
+            if files.is_empty() {
+                return Ok(());
+            }
+            let file = files[file.index(files.len())];
+            {
+                let mut vault = vault.lock().unwrap();
+                vault.open(file, OpenMode::RW).unwrap();
+                let written = vault.write(file, *offset as i64, data).unwrap();
+                vault.close(file).unwrap();
+                prop_assert_eq!(written as usize, data.len());
+            }
+            // Writing nothing doesn't extend the file.
+            if !data.is_empty() {
+                let content = model.content(file);
+                let end = *offset as usize + data.len();
+                if content.len() < end {
+                    content.resize(end, 0);
+                }
+                content[*offset as usize..end].copy_from_slice(data);
+            }
+        }
+        Op::Read(file, offset, size) => {
+            let files = model.files();
+            if files.is_empty() {
+                return Ok(());
+            }
+            let file = files[file.index(files.len())];
+            let data = {
+                let mut vault = vault.lock().unwrap();
+                vault.open(file, OpenMode::R).unwrap();
+                let data = vault.read(file, *offset as i64, *size).unwrap();
+                vault.close(file).unwrap();
+                data
+            };
+            let content = model.content(file);
+            let start = std::cmp::min(*offset as usize, content.len());
+            let end = std::cmp::min(*offset as usize + *size as usize, content.len());
+            prop_assert_eq!(&data, &content[start..end]);
+        }
+        Op::Truncate(file, size) => {
+            let files = model.files();
+            if files.is_empty() {
+                return Ok(());
+            }
+            let file = files[file.index(files.len())];
+            vault.lock().unwrap().truncate(file, *size).unwrap();
+            model.content(file).resize(*size as usize, 0);
+        }
+        Op::Delete(entry) => {
+            let mut entries: Vec<Inode> = model.entries.keys().copied().collect();
+            if entries.is_empty() {
+                return Ok(());
+            }
+            entries.sort_unstable();
+            let entry = entries[entry.index(entries.len())];
+            let result = vault.lock().unwrap().delete(entry);
+            if !model.children(entry).is_empty() {
+                prop_assert!(
+                    matches!(result, Err(VaultError::DirectoryNotEmpty(_))),
+                    "{:?}",
+                    result
+                );
+                return Ok(());
+            }
+            result.map_err(|err| TestCaseError::fail(format!("{:?}", err)))?;
+            model.entries.remove(&entry);
+        }
+        Op::Readdir(dir) => {
+            let dirs = model.dirs();
+            let dir = dirs[dir.index(dirs.len())];
+            check_dir(vault, model, dir)?;
+        }
+        Op::Rename(entry, dir, name) => {
+            let mut entries: Vec<Inode> = model.entries.keys().copied().collect();
+            if entries.is_empty() {
+                return Ok(());
+            }
+            entries.sort_unstable();
+            let entry = entries[entry.index(entries.len())];
+            let dirs = model.dirs();
+            let dir = dirs[dir.index(dirs.len())];
+            let result = vault.lock().unwrap().rename(entry, dir, name);
+            if model.is_within(dir, entry) {
+                prop_assert!(
+                    matches!(result, Err(VaultError::InvalidArgument(_))),
+                    "{:?}",
+                    result
+                );
+                return Ok(());
+            }
+            let replaced = model
+                .children(dir)
+                .into_iter()
+                .find(|child| &child.0 == name)
+                .map(|child| child.1)
+                .filter(|&replaced| replaced != entry);
+            if let Some(replaced) = replaced {
+                match (model.is_dir(entry), model.is_dir(replaced)) {
+                    (false, true) => {
+                        prop_assert!(
+                            matches!(result, Err(VaultError::IsDirectory(_))),
+                            "{:?}",
+                            result
+                        );
+                        return Ok(());
+                    }
+                    (true, false) => {
+                        prop_assert!(
+                            matches!(result, Err(VaultError::NotDirectory(_))),
+                            "{:?}",
+                            result
+                        );
+                        return Ok(());
+                    }
+                    (true, true) if !model.children(replaced).is_empty() => {
+                        prop_assert!(
+                            matches!(result, Err(VaultError::DirectoryNotEmpty(_))),
+                            "{:?}",
+                            result
+                        );
+                        return Ok(());
+                    }
+                    _ => {
+                        model.entries.remove(&replaced);
+                    }
+                }
+            }
+            result.map_err(|err| TestCaseError::fail(format!("{:?}", err)))?;
+            let moved = model.entries.get_mut(&entry).unwrap();
+            moved.parent = dir;
+            moved.name = name.clone();
+        }
+    }
+    Ok(())
+}
+
+/// Check that the listing of `dir` in `vault` matches `model`.
+fn check_dir(vault: &VaultRef, model: &Model, dir: Inode) -> Result<(), TestCaseError> {
+    let mut listing: Vec<_> = vault
+        .lock()
+        .unwrap()
+        .readdir(dir)
+        .unwrap()
+        .into_iter()
+        .map(|info| {
+            let size = match info.kind {
+                VaultFileType::File => Some(info.size),
+                VaultFileType::Directory => None,
+            };
+            (info.name, info.inode, size)
+        })
+        .collect();
+    listing.sort();
+    prop_assert_eq!(listing, model.children(dir));
+    Ok(())
+}
+
+/// Check every directory and the content of every file in `vault`
+/// against `model`.
+fn check_all(vault: &VaultRef, model: &Model) -> Result<(), TestCaseError> {
+    for dir in model.dirs() {
+        check_dir(vault, model, dir)?;
+    }
+    for file in model.files() {
+        // Not `read_file`: a caching vault reports the remote's size
+        // until our changes are uploaded.
+        let data = {
+            let mut vault = vault.lock().unwrap();
+            vault.open(file, OpenMode::R).unwrap();
+            let data = vault.read(file, 0, 1 << 16).unwrap();
+            vault.close(file).unwrap();
+            data
+        };
+        prop_assert_eq!(
+            Some(data.as_slice()),
+            model.entries[&file].content.as_deref()
+        );
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn local_vault_matches_model(ops in prop::collection::vec(op(), 1..40)) {
+        let store = tempfile::tempdir().unwrap();
+        let vault: VaultRef = Arc::new(Mutex::new(GenericVault::Local(
+            LocalVault::new("alice", store.path(), EventBus::new(), MissingDataPolicy::Repair)
+                .unwrap(),
+        )));
+        let mut model = Model::default();
+        for op in ops.iter() {
+            apply(&vault, &mut model, op)?;
+        }
+        check_all(&vault, &model)?;
+    }
+}
+
+proptest! {
+    // Each case starts a cluster and waits for uploads.
+    #![proptest_config(ProptestConfig::with_cases(8))]
+
+    #[test]
+    fn caching_vault_matches_model(ops in prop::collection::vec(op(), 1..20)) {
+        let cluster = Cluster::running(&["alice", "bob"]);
+        let alice = cluster.node("alice");
+        let bob = cluster.node("bob");
+        let cache = bob.cache_of("alice");
+        let mut model = Model::default();
+        for op in ops.iter() {
+            apply(&cache, &mut model, op)?;
+        }
+        check_all(&cache, &model)?;
+        // Once our changes are uploaded, alice has the same files.
+        prop_assert!(bob.wait_synced("alice"));
+        check_all(&alice.local, &model)?;
+    }
+}
@@ -0,0 +1,61 @@
+/// Permission bits of new files and directories, see
+/// `fuse::initial_mode`.
+mod common;
+
+use common::*;
+use monovault::fuse::initial_mode;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn mode(vault: &VaultRef, file: Inode) -> u32 {
+    vault.lock().unwrap().attr(file).unwrap().mode
+}
+
+#[test]
+fn mode_minus_umask() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    let file = create_file(local, ROOT, "note", b"hello");
+    let dir = local
+        .lock()
+        .unwrap()
+        .create(ROOT, "dir", VaultFileType::Directory)
+        .unwrap();
+    let mut vault = local.lock().unwrap();
+    assert_eq!(
+        initial_mode(&mut vault, file, VaultFileType::File, 0o666, 0o022),
+        0o644
+    );
+    assert_eq!(
+        initial_mode(&mut vault, dir, VaultFileType::Directory, 0o700, 0o022),
+        0o700
+    );
+    drop(vault);
+    assert_eq!(mode(local, file), 0o644);
+    assert_eq!(mode(local, dir), 0o700);
+
+    // No umask, the default bits.
+    let other = create_file(local, ROOT, "other", b"");
+    let mut vault = local.lock().unwrap();
+    assert_eq!(
+        initial_mode(&mut vault, other, VaultFileType::File, 0o666, 0),
+        0o666
+    );
+    drop(vault);
+    assert_eq!(mode(local, other), 0o666);
+}
+
+#[test]
+fn reported_mode_is_the_stored_one() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    let file = create_file(local, ROOT, "note", b"hello");
+    let mut vault = local.lock().unwrap();
+    unpack_to_local(&mut vault).unwrap().set_read_only(true);
+    // Neither the mode asked for nor the default minus the umask can
+    // be set, the file keeps the default bits, and says so.
+    let reported = initial_mode(&mut vault, file, VaultFileType::File, 0o600, 0o077);
+    assert_eq!(reported, vault.attr(file).unwrap().mode);
+    assert_eq!(reported, default_mode(VaultFileType::File));
+}
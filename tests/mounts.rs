@@ -0,0 +1,42 @@
+/// What is mounted on the mount point, see src/mounts.rs.
+use monovault::mounts::*;
+use std::path::Path;
+
+const MOUNTINFO: &str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+35 22 8:1 /home/alice/shared /home/alice/mnt rw,relatime shared:1 - ext4 /dev/sda1 rw
+36 35 0:52 / /home/alice/mnt rw,nosuid,nodev shared:30 - fuse mnt rw,user_id=1000
+37 22 0:53 / /home/alice/my\\040vault rw,nosuid,nodev shared:31 - fuse.sshfs host:/ rw
+38 22 0:54 / /home/alice/bound rw,relatime shared:1 - ext4 /dev/sda1 rw
+";
+
+#[test]
+fn topmost_mount_wins() {
+    // A FUSE mount over a bind mount.
+    assert_eq!(
+        mounted_type_in(MOUNTINFO, Path::new("/home/alice/mnt")),
+        Some("fuse".to_string())
+    );
+    assert_eq!(
+        mounted_type_in(MOUNTINFO, Path::new("/home/alice/my vault")),
+        Some("fuse.sshfs".to_string())
+    );
+    assert_eq!(
+        mounted_type_in(MOUNTINFO, Path::new("/home/alice/bound")),
+        Some("ext4".to_string())
+    );
+    assert_eq!(mounted_type_in(MOUNTINFO, Path::new("/home/alice")), None);
+}
+
+#[test]
+fn only_fuse_is_in_the_way() {
+    assert!(is_fuse("fuse"));
+    assert!(is_fuse("fuse.sshfs"));
+    assert!(is_fuse("macfuse"));
+    assert!(!is_fuse("ext4"));
+    assert!(!is_fuse("fusectl"));
+
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(mount_state(dir.path()), MountState::Free);
+    assert_eq!(mount_state(&dir.path().join("nowhere")), MountState::Free);
+}
@@ -0,0 +1,55 @@
+/// Unicode normalization of file names, see `NameNormalization`.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+/// "café" as macOS gives it, and as Linux usually does.
+const NFD: &str = "cafe\u{301}";
+const NFC: &str = "caf\u{e9}";
+
+#[test]
+fn normal_forms() {
+    assert_eq!(NameNormalization::Nfc.apply(NFD), NFC);
+    assert_eq!(NameNormalization::Nfd.apply(NFC), NFD);
+    assert_eq!(NameNormalization::None.apply(NFD), NFD);
+}
+
+#[test]
+fn names_from_peers_are_normalized() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let local = &cluster.node("alice").local;
+    unpack_to_local(&mut local.lock().unwrap())
+        .unwrap()
+        .set_name_normalization(NameNormalization::Nfc);
+    // A Mac peer creates it, a Linux peer finds it.
+    let remote = cluster.node("bob").remote_of("alice");
+    let file = create_file(&remote, ROOT, NFD, b"hello");
+    assert_eq!(names(local, ROOT), vec![NFC]);
+    assert!(matches!(
+        local.lock().unwrap().create(ROOT, NFC, VaultFileType::File),
+        Err(VaultError::FileAlreadyExist(..))
+    ));
+
+    // Other names are compared in NFC too.
+    let dir = local
+        .lock()
+        .unwrap()
+        .create(ROOT, "dir", VaultFileType::Directory)
+        .unwrap();
+    remote.lock().unwrap().link(file, dir, NFC).unwrap();
+    remote.lock().unwrap().unlink(file, dir, NFD).unwrap();
+    assert!(names(local, dir).is_empty());
+    remote.lock().unwrap().rename(file, dir, NFD).unwrap();
+    assert_eq!(names(local, dir), vec![NFC]);
+}
+
+#[test]
+fn names_are_kept_without_normalization() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    create_file(local, ROOT, NFD, b"mac");
+    create_file(local, ROOT, NFC, b"linux");
+    assert_eq!(names(local, ROOT).len(), 2);
+}
@@ -0,0 +1,61 @@
+/// Owners of files.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn owner(vault: &VaultRef, file: Inode) -> Option<(u32, u32)> {
+    vault.lock().unwrap().attr(file).unwrap().owner
+}
+
+fn listed_owner(vault: &VaultRef, dir: Inode, name: &str) -> Option<(u32, u32)> {
+    let entries = vault.lock().unwrap().readdir(dir).unwrap();
+    entries
+        .into_iter()
+        .find(|info| info.name == name)
+        .unwrap()
+        .owner
+}
+
+#[test]
+fn owners_are_stored() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    let file = create_file(local, ROOT, "note", b"hello");
+    assert_eq!(owner(local, file), None);
+    local.lock().unwrap().set_owner(file, 1000, 100).unwrap();
+    assert_eq!(owner(local, file), Some((1000, 100)));
+    // Root is an owner like any other.
+    local.lock().unwrap().set_owner(ROOT, 0, 0).unwrap();
+    assert_eq!(owner(local, ROOT), Some((0, 0)));
+    assert!(matches!(
+        local.lock().unwrap().set_owner(12345, 0, 0),
+        Err(VaultError::FileNotExist(_))
+    ));
+}
+
+#[test]
+fn owners_across_peers() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    create_file(&alice.local, ROOT, "other", b"");
+
+    let remote = bob.remote_of("alice");
+    // Listings without owners stay without.
+    assert_eq!(listed_owner(&remote, ROOT, "note"), None);
+    remote.lock().unwrap().set_owner(file, 501, 20).unwrap();
+    assert_eq!(owner(&alice.local, file), Some((501, 20)));
+    assert_eq!(owner(&remote, file), Some((501, 20)));
+    assert_eq!(listed_owner(&remote, ROOT, "note"), Some((501, 20)));
+    assert_eq!(listed_owner(&remote, ROOT, "other"), None);
+
+    // A cache learns owners with the files.
+    let cache = bob.cache_of("alice");
+    assert_eq!(listed_owner(&cache, ROOT, "note"), Some((501, 20)));
+    cache.lock().unwrap().set_owner(file, 0, 0).unwrap();
+    assert_eq!(owner(&alice.local, file), Some((0, 0)));
+}
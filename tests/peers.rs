@@ -0,0 +1,116 @@
+/// Per-peer settings in the configuration, see `PeerConfig`.
+mod common;
+
+use common::*;
+use monovault::bandwidth::Throttle;
+use monovault::types::*;
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+
+const ROOT: Inode = 1;
+
+/// Return an address nobody listens on.
+fn dead_address() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+#[test]
+fn fallback_addresses() {
+    let cluster = Cluster::running(&["alice"]);
+    let alice = cluster.node("alice");
+    create_file(&alice.local, ROOT, "note", b"hello");
+    let store = tempfile::tempdir().unwrap();
+    let mut remote = remote(
+        &format!("http://{}", dead_address()),
+        "alice",
+        &runtime(),
+        store.path(),
+    );
+    remote.set_fallback_addresses(vec![
+        format!("http://{}", dead_address()),
+        format!("http://{}", alice.address),
+    ]);
+    let listed = remote.readdir(ROOT).unwrap();
+    assert!(listed.iter().any(|info| info.name == "note"));
+}
+
+#[test]
+fn failed_fallbacks_try_the_address_again() {
+    let mut cluster = Cluster::new(&["alice"]);
+    let address = cluster.node("alice").address.clone();
+    let store = tempfile::tempdir().unwrap();
+    let mut remote = remote(
+        &format!("http://{}", address),
+        "alice",
+        &runtime(),
+        store.path(),
+    );
+    remote.set_fallback_addresses(vec![format!("http://{}", dead_address())]);
+    assert!(matches!(remote.readdir(ROOT), Err(VaultError::RpcError(_))));
+    // Nothing worked, so the configured address is tried first again,
+    // not the last fallback.
+    cluster.start("alice");
+    remote.readdir(ROOT).unwrap();
+}
+
+#[test]
+fn read_only_remote_refuses_changes() {
+    let cluster = Cluster::running(&["alice"]);
+    let alice = cluster.node("alice");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let store = tempfile::tempdir().unwrap();
+    let mut remote = remote(
+        &format!("http://{}", alice.address),
+        "alice",
+        &runtime(),
+        store.path(),
+    );
+    remote.set_read_only(true);
+    assert!(matches!(
+        remote.create(ROOT, "new", VaultFileType::File),
+        Err(VaultError::ReadOnly(_))
+    ));
+    assert!(matches!(
+        remote.write(file, 0, b"HELLO"),
+        Err(VaultError::ReadOnly(_))
+    ));
+    assert!(matches!(remote.delete(file), Err(VaultError::ReadOnly(_))));
+    // Reading still works.
+    remote.open(file, OpenMode::R).unwrap();
+    assert_eq!(remote.read(file, 0, 5).unwrap(), b"hello");
+    remote.close(file).unwrap();
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"hello");
+}
+
+#[test]
+fn unresponsive_peer_times_out() {
+    // Accepts connections but never answers.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let mut remote = remote(
+        &format!("http://{}", listener.local_addr().unwrap()),
+        "alice",
+        &runtime(),
+        store.path(),
+    );
+    remote.set_timeout(Some(Duration::from_secs(1)));
+    let start = Instant::now();
+    assert!(matches!(remote.attr(ROOT), Err(VaultError::RpcError(_))));
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn throttle_keeps_the_average() {
+    let throttle = Throttle::new(1 << 20);
+    let start = Instant::now();
+    // A second's worth after being idle goes through.
+    throttle.pass(1 << 20);
+    assert!(start.elapsed() < Duration::from_millis(500));
+    // Anything more waits for its share.
+    throttle.pass(1 << 19);
+    throttle.pass(1 << 19);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+}
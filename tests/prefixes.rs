@@ -0,0 +1,37 @@
+/// Inode prefixes of vaults, see src/prefixes.rs.
+mod common;
+
+use common::*;
+use monovault::prefixes::*;
+use std::collections::HashMap;
+
+#[test]
+fn prefixes_are_stable() {
+    // The order peers come in doesn't matter.
+    let first = assign(&vault_names(&["moon", "alice", "zeta"]), &HashMap::new()).unwrap();
+    assert_eq!(
+        first,
+        assign(&vault_names(&["zeta", "moon", "alice"]), &HashMap::new()).unwrap()
+    );
+    assert_eq!(first["alice"], 1);
+    assert_eq!(first["moon"], 2);
+    assert_eq!(first["zeta"], 3);
+
+    // A new peer doesn't move the others, and a removed one keeps its
+    // prefix for when it comes back.
+    let second = assign(&vault_names(&["alice", "bob", "zeta"]), &first).unwrap();
+    assert_eq!(second["alice"], 1);
+    assert_eq!(second["zeta"], 3);
+    assert_eq!(second["moon"], 2);
+    assert_eq!(second["bob"], 4);
+}
+
+#[test]
+fn prefixes_are_saved() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = load_and_assign(dir.path(), &vault_names(&["moon", "alice"])).unwrap();
+    assert_eq!(load(&dir.path().join(PREFIXES_FILE)).unwrap(), first);
+    let second = load_and_assign(dir.path(), &vault_names(&["moon", "bob"])).unwrap();
+    assert_eq!(second["moon"], first["moon"]);
+    assert_eq!(second["bob"], 3);
+}
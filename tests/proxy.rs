@@ -0,0 +1,162 @@
+/// Serving behind a reverse proxy that routes by path, see proxy.rs.
+mod common;
+
+use common::*;
+use monovault::bans::{BanConfig, PeerGuard};
+use monovault::connections::ConnectionLog;
+use monovault::events::EventBus;
+use monovault::local_vault::LocalVault;
+use monovault::proxy;
+use monovault::types::*;
+use monovault::vault_server::{self, HttpSettings};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+use tonic::codegen::http::HeaderMap;
+
+const ROOT: Inode = 1;
+
+/// Serve vault "alice" with `settings`, return its address, its
+/// local vault, and its store.
+fn serve_alice(runtime: &Arc<Runtime>, settings: HttpSettings) -> (String, VaultRef, TempDir) {
+    let store = tempfile::tempdir().unwrap();
+    let local = Arc::new(Mutex::new(GenericVault::Local(
+        LocalVault::new(
+            "alice",
+            store.path(),
+            EventBus::new(),
+            MissingDataPolicy::Error,
+        )
+        .unwrap(),
+    )));
+    let mut vault_map = HashMap::new();
+    vault_map.insert("alice".to_string(), Arc::clone(&local));
+    let listener = vault_server::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let runtime = Arc::clone(runtime);
+    let _ = thread::spawn(move || {
+        vault_server::serve(
+            listener,
+            "alice",
+            vault_map,
+            runtime,
+            PeerGuard::new(BanConfig::default()),
+            None,
+            ConnectionLog::new(),
+            settings,
+        )
+    });
+    (address, local, store)
+}
+
+#[test]
+fn prefixed_and_direct_requests() {
+    let runtime = runtime();
+    let settings = HttpSettings {
+        path_prefix: proxy::normalize_prefix("/monovault/alice/"),
+        keepalive: Some(Duration::from_secs(10)),
+        ..HttpSettings::default()
+    };
+    let (address, local, store) = serve_alice(&runtime, settings);
+    let file = create_file(&local, ROOT, "note", b"hello");
+    let vault = |address: &str| {
+        let mut remote = remote(address, "alice", &runtime, store.path());
+        remote.set_keepalive(Some(Duration::from_secs(10)));
+        Arc::new(Mutex::new(GenericVault::Remote(remote)))
+    };
+
+    // Through a proxy that passes the path on as is, and straight to
+    // the server on the LAN.
+    for address in [
+        format!("http://{}/monovault/alice", address),
+        format!("http://{}/monovault/alice/", address),
+        format!("http://{}", address),
+    ] {
+        assert_eq!(read_file(&vault(&address), file).unwrap(), b"hello");
+    }
+
+    let vault = vault(&format!("http://{}/elsewhere", address));
+    assert!(vault.lock().unwrap().attr(file).is_err());
+}
+
+#[test]
+fn addresses_split_into_origin_and_prefix() {
+    assert_eq!(
+        proxy::split_address("https://example.com/monovault/bob/").unwrap(),
+        (
+            "https://example.com".to_string(),
+            Some("/monovault/bob".to_string())
+        )
+    );
+    assert_eq!(
+        proxy::split_address("http://10.0.0.2:9000").unwrap(),
+        ("http://10.0.0.2:9000".to_string(), None)
+    );
+    assert!(matches!(
+        proxy::split_address("10.0.0.2:9000"),
+        Err(VaultError::InvalidArgument(_))
+    ));
+    assert_eq!(proxy::normalize_prefix("/"), None);
+}
+
+#[test]
+fn admin_commands_skip_the_proxy() {
+    let runtime = runtime();
+    let settings = HttpSettings {
+        path_prefix: proxy::normalize_prefix("/monovault/alice"),
+        ..HttpSettings::default()
+    };
+    let (address, _local, store) = serve_alice(&runtime, settings);
+    let admin = |address: String| remote(&address, "alice", &runtime, store.path()).pending();
+    assert!(admin(format!("http://{}", address)).is_ok());
+    // A proxy on the same host connects from loopback too.
+    assert!(admin(format!("http://{}/monovault/alice", address)).is_err());
+}
+
+#[test]
+fn clients_behind_trusted_proxies() {
+    let proxy: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+    let peer: SocketAddr = "10.0.0.9:40000".parse().unwrap();
+    let forwarded = proxy::ForwardedFor {
+        header: Some("x-forwarded-for".to_string()),
+        proxies: vec![proxy.ip()],
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-for", "127.0.0.1, 203.0.113.7".parse().unwrap());
+
+    // The proxy tells where the request came from, the address it
+    // added last.
+    let client = forwarded.resolve(Some(proxy), &headers, false);
+    assert_eq!(client.addr.unwrap().ip().to_string(), "203.0.113.7");
+    assert!(client.proxied);
+    // Others can't make up their address.
+    let client = forwarded.resolve(Some(peer), &headers, false);
+    assert_eq!(client.addr, Some(peer));
+    assert!(client.proxied);
+    // Without the header, the request came from the proxy.
+    let client = forwarded.resolve(Some(proxy), &HeaderMap::new(), false);
+    assert_eq!(client.addr, Some(proxy));
+    assert!(client.proxied);
+
+    // Without configuration, headers proxies add and the path prefix
+    // still tell a request was proxied.
+    let unconfigured = proxy::ForwardedFor::default();
+    let local: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client = unconfigured.resolve(Some(local), &headers, false);
+    assert_eq!(client.addr, Some(local));
+    assert!(client.proxied);
+    assert!(
+        unconfigured
+            .resolve(Some(local), &HeaderMap::new(), true)
+            .proxied
+    );
+    assert!(
+        !unconfigured
+            .resolve(Some(local), &HeaderMap::new(), false)
+            .proxied
+    );
+}
@@ -0,0 +1,60 @@
+/// The read cache of remote vaults without caching, see
+/// src/read_cache.rs.
+mod common;
+
+use common::*;
+use monovault::read_cache::{ReadCache, READ_CACHE_BLOCK_SIZE};
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn least_recently_used_blocks_go_first() {
+    let block = vec![0; READ_CACHE_BLOCK_SIZE as usize];
+    let mut cache = ReadCache::new(2 * READ_CACHE_BLOCK_SIZE);
+    cache.insert(1, 0, block.clone());
+    cache.insert(1, 1, block.clone());
+    assert!(cache.get(1, 0).is_some());
+    cache.insert(2, 0, block.clone());
+    assert!(cache.get(1, 1).is_none());
+    assert!(cache.get(1, 0).is_some());
+    assert!(cache.get(2, 0).is_some());
+
+    // A new version drops the blocks of the old one.
+    cache.validate(1, (1, 1));
+    assert!(cache.get(1, 0).is_some());
+    cache.validate(1, (2, 1));
+    assert!(cache.get(1, 0).is_none());
+    assert!(cache.get(2, 0).is_some());
+}
+
+#[test]
+fn rereads_are_served_from_memory() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let data = content(READ_CACHE_BLOCK_SIZE * 5 / 2);
+    let file = create_file(&alice.local, ROOT, "big", &data);
+    let remote = bob.remote_of("alice");
+    unpack_to_remote(&mut remote.lock().unwrap())
+        .unwrap()
+        .set_read_cache(16 * READ_CACHE_BLOCK_SIZE);
+
+    let (offset, size) = (READ_CACHE_BLOCK_SIZE as usize - 10, 100);
+    let expected = &data[offset..offset + size];
+    assert_eq!(read_at(&remote, file, offset as i64, size as u64), expected);
+    let received = bob.received_from("alice");
+    // Only the attr RPC of open goes to the network now.
+    assert_eq!(read_at(&remote, file, offset as i64, size as u64), expected);
+    assert!(bob.received_from("alice") - received < 1024);
+    // Reads past the end stop at the end.
+    assert_eq!(read_file(&remote, file).unwrap(), data);
+    assert!(read_at(&remote, file, data.len() as i64 + 10, 100).is_empty());
+
+    // A change on the remote is seen on next open.
+    write_file(&alice.local, file, b"changed").unwrap();
+    assert_eq!(read_at(&remote, file, 0, 7), b"changed");
+    // So are our own writes.
+    write_file(&remote, file, b"CHANGED").unwrap();
+    assert_eq!(read_at(&remote, file, 0, 7), b"CHANGED");
+}
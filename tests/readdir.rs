@@ -0,0 +1,108 @@
+/// Directory listings as the mount replies them, see
+/// `fuse::dot_entries`, `fuse::sorted_listing` and
+/// `fuse::snapshot_page`.
+mod common;
+
+use common::*;
+use monovault::fuse::{dot_entries, snapshot_page, sorted_listing};
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+/// Return the listing of `dir` of `vault` as a directory handle's
+/// snapshot, with `parent` as the parent of `dir`.
+fn snapshot(vault: &VaultRef, dir: Inode, parent: Option<u64>) -> Vec<FileInfo> {
+    let mut snapshot = dot_entries(dir, parent);
+    snapshot.extend(sorted_listing(vault.lock().unwrap().readdir(dir).unwrap()));
+    snapshot
+}
+
+fn entry_names(snapshot: &[FileInfo]) -> Vec<&str> {
+    snapshot.iter().map(|info| info.name.as_str()).collect()
+}
+
+#[test]
+fn dot_entries_come_first() {
+    // The mount root is its own parent, so are vault roots.
+    let entries = dot_entries(1, None);
+    assert_eq!(entry_names(&entries), [".", ".."]);
+    assert_eq!((entries[0].inode, entries[1].inode), (1, 1));
+    let entries = dot_entries(0x1000, None);
+    assert_eq!((entries[0].inode, entries[1].inode), (0x1000, 1));
+    let entries = dot_entries(0x1005, Some(0x1000));
+    assert_eq!((entries[0].inode, entries[1].inode), (0x1005, 0x1000));
+
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    let dir = local
+        .lock()
+        .unwrap()
+        .create(ROOT, "dir", VaultFileType::Directory)
+        .unwrap();
+    create_file(local, ROOT, "b", b"");
+    create_file(local, ROOT, "a", b"");
+    create_file(local, dir, "inner", b"");
+    assert_eq!(
+        entry_names(&snapshot(local, ROOT, None)),
+        [".", "..", "a", "b", "dir"]
+    );
+    assert_eq!(
+        entry_names(&snapshot(local, dir, Some(ROOT))),
+        [".", "..", "inner"]
+    );
+}
+
+#[test]
+fn dot_entries_of_older_peers_are_dropped() {
+    let entry = |inode: Inode, name: &str| FileInfo {
+        inode,
+        name: name.to_string(),
+        kind: VaultFileType::File,
+        size: 0,
+        atime: 0,
+        mtime: 0,
+        version: (1, 0),
+        mode: 0o644,
+        nlink: 1,
+        owner: None,
+        generation: 1,
+    };
+    let listing = sorted_listing(vec![
+        entry(3, "note"),
+        entry(2, ".."),
+        entry(5, "a"),
+        entry(4, "."),
+    ]);
+    assert_eq!(entry_names(&listing), ["a", "note"]);
+
+    let mut snapshot = dot_entries(4, Some(2));
+    snapshot.extend(listing);
+    assert_eq!(entry_names(&snapshot), [".", "..", "a", "note"]);
+}
+
+#[test]
+fn snapshot_offsets_stay_stable() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    for name in ["d", "c", "b", "a"] {
+        create_file(local, ROOT, name, b"");
+    }
+    let snapshot = snapshot(local, ROOT, None);
+    let all: Vec<(i64, &str)> = snapshot_page(&snapshot, 0)
+        .map(|(next, info)| (next, info.name.as_str()))
+        .collect();
+    assert_eq!(
+        all,
+        [(1, "."), (2, ".."), (3, "a"), (4, "b"), (5, "c"), (6, "d")]
+    );
+
+    // Continuing from an offset gives the rest, with the same
+    // offsets, even after the directory changes.
+    create_file(local, ROOT, "0", b"");
+    let rest: Vec<(i64, &str)> = snapshot_page(&snapshot, 3)
+        .map(|(next, info)| (next, info.name.as_str()))
+        .collect();
+    assert_eq!(rest, all[3..]);
+    assert_eq!(snapshot_page(&snapshot, 6).count(), 0);
+    assert_eq!(snapshot_page(&snapshot, 100).count(), 0);
+}
@@ -0,0 +1,180 @@
+/// Renaming and moving files within a vault.
+mod common;
+
+use common::*;
+use monovault::database::Database;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn rename(vault: &VaultRef, file: Inode, parent: Inode, name: &str) -> VaultResult<()> {
+    vault.lock().unwrap().rename(file, parent, name)
+}
+
+#[test]
+fn move_within_local_vault() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    let src = mkdir(local, ROOT, "src");
+    let dst = mkdir(local, ROOT, "dst");
+    let file = create_file(local, src, "note", b"hello");
+
+    rename(local, file, dst, "renamed").unwrap();
+    assert_eq!(find(local, src, "note").unwrap(), None);
+    assert_eq!(find(local, dst, "renamed").unwrap(), Some(file));
+    assert_eq!(read_file(local, file).unwrap(), b"hello");
+    // Usage moves along.
+    assert_eq!(local.lock().unwrap().info(src).unwrap().size, 0);
+    let info = local.lock().unwrap().info(dst).unwrap();
+    assert_eq!((info.size, info.count), (5, 1));
+
+    // Moving a directory under itself is refused.
+    let inner = mkdir(local, dst, "inner");
+    assert!(matches!(
+        rename(local, dst, inner, "loop"),
+        Err(VaultError::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        rename(local, dst, file, "x"),
+        Err(VaultError::NotDirectory(_))
+    ));
+    assert!(matches!(
+        rename(local, file, dst, &"x".repeat(200)),
+        Err(VaultError::FileNameTooLong(_))
+    ));
+}
+
+#[test]
+fn rename_replaces_target() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    let old = create_file(local, ROOT, "old", b"old");
+    let new = create_file(local, ROOT, "new", b"new");
+    let dir = mkdir(local, ROOT, "dir");
+    let full = mkdir(local, ROOT, "full");
+    create_file(local, full, "inside", b"");
+
+    rename(local, new, ROOT, "old").unwrap();
+    assert_eq!(find(local, ROOT, "old").unwrap(), Some(new));
+    assert!(local.lock().unwrap().attr(old).is_err());
+    // Renaming onto itself does nothing.
+    rename(local, new, ROOT, "old").unwrap();
+
+    assert!(matches!(
+        rename(local, new, ROOT, "dir"),
+        Err(VaultError::IsDirectory(_))
+    ));
+    assert!(matches!(
+        rename(local, dir, ROOT, "old"),
+        Err(VaultError::NotDirectory(_))
+    ));
+    assert!(matches!(
+        rename(local, dir, ROOT, "full"),
+        Err(VaultError::DirectoryNotEmpty(_))
+    ));
+    assert_eq!(find(local, ROOT, "dir").unwrap(), Some(dir));
+}
+
+#[test]
+fn replacing_drops_only_the_replaced_name() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    let dir = mkdir(local, ROOT, "dir");
+    let linked = create_file(local, ROOT, "linked", b"linked");
+    local.lock().unwrap().link(linked, dir, "other").unwrap();
+    let new = create_file(local, ROOT, "new", b"new");
+
+    // A replaced file with other names keeps them.
+    rename(local, new, dir, "other").unwrap();
+    assert_eq!(find(local, dir, "other").unwrap(), Some(new));
+    assert_eq!(find(local, ROOT, "linked").unwrap(), Some(linked));
+    assert_eq!(local.lock().unwrap().attr(linked).unwrap().nlink, 1);
+    assert_eq!(read_file(local, linked).unwrap(), b"linked");
+
+    // One without goes, its usage with it.
+    rename(local, new, ROOT, "linked").unwrap();
+    assert!(local.lock().unwrap().attr(linked).is_err());
+    assert_eq!(find(local, ROOT, "linked").unwrap(), Some(new));
+    assert_eq!(local.lock().unwrap().info(ROOT).unwrap().size, 3);
+}
+
+#[test]
+fn rename_through_cache() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let cache = cluster.node("bob").cache_of("alice");
+    let dir = mkdir(&alice.local, ROOT, "dir");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    find(&cache, ROOT, "note").unwrap();
+    find(&cache, dir, "note").unwrap();
+    assert_eq!(read_file(&cache, file).unwrap(), b"hello");
+
+    rename(&cache, file, dir, "moved").unwrap();
+    assert_eq!(find(&alice.local, dir, "moved").unwrap(), Some(file));
+    assert_eq!(find(&cache, ROOT, "note").unwrap(), None);
+    assert_eq!(find(&cache, dir, "moved").unwrap(), Some(file));
+    assert_eq!(read_file(&cache, file).unwrap(), b"hello");
+
+    // A rename on the remote shows up in the next listing.
+    rename(&alice.local, file, ROOT, "back").unwrap();
+    assert_eq!(find(&cache, ROOT, "back").unwrap(), Some(file));
+    assert_eq!(cache.lock().unwrap().attr(file).unwrap().name, "back");
+}
+
+#[test]
+fn paths_follow_renames() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let local = &alice.local;
+    let docs = mkdir(local, ROOT, "docs");
+    let file = create_file(local, docs, "note", b"hello");
+    let path_of = |vault: &VaultRef, file| vault.lock().unwrap().path_of(file);
+    assert_eq!(path_of(local, ROOT).unwrap(), "/");
+    assert_eq!(path_of(local, file).unwrap(), "/docs/note");
+    let old = mkdir(local, ROOT, "old");
+    rename(local, docs, old, "docs-2020").unwrap();
+    assert_eq!(path_of(local, file).unwrap(), "/old/docs-2020/note");
+    assert!(matches!(
+        path_of(local, 1000),
+        Err(VaultError::FileNotExist(1000))
+    ));
+
+    // A cache knows what it listed, a remote nothing.
+    let bob = cluster.node("bob");
+    let cache = bob.cache_of("alice");
+    assert_eq!(find(&cache, ROOT, "old").unwrap(), Some(old));
+    assert_eq!(path_of(&cache, old).unwrap(), "/old");
+    assert!(path_of(&cache, file).is_err());
+    assert!(path_of(&bob.remote_of("alice"), old).is_err());
+}
+
+#[test]
+fn paths_of_looping_parents_end() {
+    let store = tempfile::tempdir().unwrap();
+    let mut database = Database::new(store.path(), "alice").unwrap();
+    for (parent, child, name) in [(ROOT, 2, "a"), (2, 3, "b")] {
+        database
+            .add_file(
+                parent,
+                child,
+                name,
+                VaultFileType::Directory,
+                0,
+                0,
+                (1, 0),
+                0o755,
+            )
+            .unwrap();
+    }
+    assert_eq!(database.path_of(3).unwrap(), "/a/b");
+    drop(database);
+
+    // A damaged tree where a and b are each other's parent.
+    let connection = rusqlite::Connection::open(store.path().join("alice.sqlite3")).unwrap();
+    connection
+        .execute("update HasChild set parent=3 where child=2", [])
+        .unwrap();
+    drop(connection);
+    let database = Database::new(store.path(), "alice").unwrap();
+    assert_eq!(database.path_of(3).unwrap(), "/.../a/b");
+}
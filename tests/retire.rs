@@ -0,0 +1,110 @@
+/// Retiring the stores of peers removed from the configuration, see
+/// src/retire.rs.
+mod common;
+
+use common::*;
+use monovault::events::EventBus;
+use monovault::local_vault::LocalVault;
+use monovault::retire::{self, Retirements};
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+const DAY: u64 = 24 * 60 * 60;
+
+#[test]
+fn removed_peer_is_retired_then_collected() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let ours = create_file(&bob.local, ROOT, "mine", b"ours");
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+    read_file(&cache, file).unwrap();
+
+    let store = bob.store();
+    let mut retirements = Retirements::default();
+    retirements
+        .update(store, &vault_names(&["alice"]), "bob", 0)
+        .unwrap();
+    assert!(retirements.retired.is_empty());
+
+    // Alice is removed from the configuration.
+    retirements.update(store, &[], "bob", 100).unwrap();
+    assert_eq!(retirements.retired["alice"].since, 100);
+    // Retiring again doesn't restart the grace period.
+    retirements.update(store, &[], "bob", 200).unwrap();
+    assert_eq!(retirements.retired["alice"].since, 100);
+
+    // Until collected, the store is readable but read-only.
+    let mut retired =
+        LocalVault::new("alice", store, EventBus::new(), MissingDataPolicy::Repair).unwrap();
+    retired.set_read_only(true);
+    retired.open(file, OpenMode::R).unwrap();
+    assert_eq!(retired.read(file, 0, 100).unwrap(), b"hello");
+    assert!(matches!(
+        retired.write(file, 0, b"bye"),
+        Err(VaultError::ReadOnly(_))
+    ));
+    retired.close(file).unwrap();
+    assert!(matches!(
+        retired.create(ROOT, "new", VaultFileType::File),
+        Err(VaultError::ReadOnly(_))
+    ));
+    drop(retired);
+
+    assert!(retirements.expired(30, 100 + 29 * DAY).is_empty());
+    assert_eq!(
+        retirements.expired(30, 100 + 30 * DAY),
+        vault_names(&["alice"])
+    );
+    assert!(retirements.expired(0, 100 + 300 * DAY).is_empty());
+    retirements.purge(store, "alice").unwrap();
+    assert!(retirements.retired.is_empty());
+    let stored = retire::stored_vaults(store).unwrap();
+    assert!(!stored.contains("alice"));
+    assert!(stored.contains("bob"));
+    assert!(std::fs::read_dir(store.join("data")).unwrap().all(|entry| {
+        !entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with("alice-")
+    }));
+    // Our own files are left alone.
+    assert_eq!(read_file(&bob.local, ours).unwrap(), b"ours");
+}
+
+#[test]
+fn kept_and_readded_peers() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let bob = cluster.node("bob");
+    let store = bob.store();
+    let mut retirements = Retirements::default();
+    retirements
+        .update(store, &vault_names(&["alice"]), "bob", 0)
+        .unwrap();
+
+    retirements.update(store, &[], "bob", 0).unwrap();
+    retirements.keep("alice", true).unwrap();
+    assert!(retirements.expired(30, 365 * DAY).is_empty());
+    retirements.keep("alice", false).unwrap();
+    assert_eq!(retirements.expired(30, 365 * DAY), vault_names(&["alice"]));
+
+    // Adding the peer back cancels the retirement.
+    retirements
+        .update(store, &vault_names(&["alice"]), "bob", 0)
+        .unwrap();
+    assert!(retirements.retired.is_empty());
+    assert!(retirements.keep("alice", true).is_err());
+
+    // The local vault is never retired, even if it was a peer.
+    retirements.update(store, &[], "alice", 0).unwrap();
+    assert!(retirements.retired.is_empty());
+
+    // The record survives a restart.
+    retirements.update(store, &[], "bob", 5).unwrap();
+    retirements.save(store).unwrap();
+    let loaded = Retirements::load(store).unwrap();
+    assert_eq!(loaded.retired, retirements.retired);
+}
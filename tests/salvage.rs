@@ -0,0 +1,81 @@
+/// Recovering files from a damaged store, see src/salvage.rs.
+mod common;
+
+use common::*;
+use monovault::salvage::{salvage, LOST_AND_FOUND};
+use monovault::types::*;
+use std::fs;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn what_can_be_read_is_copied() {
+    let cluster = Cluster::running(&["alice"]);
+    let alice = cluster.node("alice");
+    let local = &alice.local;
+    let dir = local
+        .lock()
+        .unwrap()
+        .create(ROOT, "dir", VaultFileType::Directory)
+        .unwrap();
+    create_file(local, dir, "kept", b"hello");
+    let gone = create_file(local, dir, "gone", b"lost data");
+    let stray = create_file(local, ROOT, "stray", b"no parent");
+
+    // Damage the store: a data file is gone, and the entry of a file
+    // in its directory.
+    let store = alice.store();
+    fs::remove_file(store.join("data").join(format!("alice-{}", gone))).unwrap();
+    let db = rusqlite::Connection::open(store.join("db").join("alice.sqlite3")).unwrap();
+    db.execute("delete from HasChild where child=?", [stray])
+        .unwrap();
+    drop(db);
+    let before = fs::read_dir(store.join("data")).unwrap().count();
+
+    let dest = tempfile::tempdir().unwrap();
+    let stats = salvage(store, "alice", dest.path()).unwrap();
+    assert_eq!(
+        fs::read(dest.path().join("dir").join("kept")).unwrap(),
+        b"hello"
+    );
+    assert!(!dest.path().join("dir").join("gone").exists());
+    assert_eq!(
+        fs::read(dest.path().join(LOST_AND_FOUND).join(stray.to_string())).unwrap(),
+        b"no parent"
+    );
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.lost, 1);
+    assert_eq!(stats.problems.len(), 1);
+    assert!(stats.problems[0].starts_with("/dir/gone"));
+    // The store is left alone.
+    assert_eq!(fs::read_dir(store.join("data")).unwrap().count(), before);
+}
+
+#[test]
+fn unknown_vault_fails() {
+    let cluster = Cluster::running(&["alice"]);
+    let dest = tempfile::tempdir().unwrap();
+    assert!(matches!(
+        salvage(cluster.node("alice").store(), "bob", dest.path()),
+        Err(VaultError::CannotFindVaultByName(_))
+    ));
+}
+
+#[test]
+fn files_never_downloaded_are_reported() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let listed = create_file(&alice.local, ROOT, "listed", b"hello");
+    let fetched = create_file(&alice.local, ROOT, "fetched", b"bye");
+    let bob = cluster.node("bob");
+    let cache = bob.cache_of("alice");
+    assert_eq!(find(&cache, ROOT, "listed").unwrap(), Some(listed));
+    assert_eq!(read_file(&cache, fetched).unwrap(), b"bye");
+
+    let dest = tempfile::tempdir().unwrap();
+    let stats = salvage(bob.store(), "alice", dest.path()).unwrap();
+    assert_eq!(fs::read(dest.path().join("fetched")).unwrap(), b"bye");
+    assert!(!dest.path().join("listed").exists());
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.problems, vec!["/listed: not downloaded".to_string()]);
+}
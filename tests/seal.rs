@@ -0,0 +1,121 @@
+/// Vaults sealed read-only for good, see src/seal.rs.
+mod common;
+
+use common::*;
+use monovault::seal::SEAL_COMMAND;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn sealed_vault_stays_sealed() {
+    let store = tempfile::tempdir().unwrap();
+    let mut vault = open_vault(store.path());
+    let file = vault.create(ROOT, "note", VaultFileType::File).unwrap();
+    vault.write(file, 0, b"hello").unwrap();
+    // What is being written would be lost.
+    assert!(matches!(vault.seal(), Err(VaultError::InvalidArgument(_))));
+    vault.close(file).unwrap();
+
+    let time = vault.seal().unwrap();
+    assert_eq!(vault.sealed(), Some(time));
+    assert_eq!(vault.seal().unwrap(), time);
+    assert!(matches!(
+        vault.create(ROOT, "more", VaultFileType::File),
+        Err(VaultError::ReadOnly(_))
+    ));
+    assert!(matches!(vault.delete(file), Err(VaultError::ReadOnly(_))));
+    let stats = vault.statfs().unwrap();
+    assert_eq!((stats.free, stats.available, stats.free_files), (0, 0, 0));
+    // Reading still works.
+    vault.open(file, OpenMode::R).unwrap();
+    assert_eq!(vault.read(file, 0, 5).unwrap(), b"hello");
+    vault.close(file).unwrap();
+    drop(vault);
+
+    let mut vault = open_vault(store.path());
+    assert_eq!(vault.sealed(), Some(time));
+    assert!(matches!(
+        vault.set_mode(file, 0o600),
+        Err(VaultError::ReadOnly(_))
+    ));
+}
+
+#[test]
+fn peers_refuse_changes_to_sealed_vault() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let time = unpack_to_local(&mut alice.local.lock().unwrap())
+        .unwrap()
+        .seal()
+        .unwrap();
+
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+    assert_eq!(cache.lock().unwrap().sealed(), Some(time));
+    assert!(matches!(
+        cache
+            .lock()
+            .unwrap()
+            .create(ROOT, "more", VaultFileType::File),
+        Err(VaultError::ReadOnly(_))
+    ));
+    assert!(matches!(
+        write_file(&cache, file, b"changed"),
+        Err(VaultError::ReadOnly(_))
+    ));
+    assert_eq!(read_file(&cache, file).unwrap(), b"hello");
+
+    // Still refused while alice is unreachable.
+    cluster.cut("bob", "alice");
+    assert!(matches!(
+        cache
+            .lock()
+            .unwrap()
+            .create(ROOT, "offline", VaultFileType::File),
+        Err(VaultError::ReadOnly(_))
+    ));
+}
+
+#[test]
+fn sealing_takes_the_owners_proof() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    // Bob runs on the same host, but can't read alice's share key.
+    let remote = cluster.node("bob").remote_of("alice");
+    let mut remote = remote.lock().unwrap();
+    let remote = unpack_to_remote(&mut remote).unwrap();
+    assert!(remote.seal("").is_err());
+    let bobs = cluster.node("bob").share_key.prove(SEAL_COMMAND);
+    assert!(remote.seal(&bobs).is_err());
+    assert!(alice.local.lock().unwrap().sealed().is_none());
+
+    let time = remote.seal(&alice.share_key.prove(SEAL_COMMAND)).unwrap();
+    assert_eq!(alice.local.lock().unwrap().sealed(), Some(time));
+}
+
+#[test]
+fn changes_from_before_the_seal_stay_pending() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+    read_file(&cache, file).unwrap();
+
+    cluster.cut("bob", "alice");
+    write_file(&cache, file, b"offline").unwrap();
+    unpack_to_local(&mut alice.local.lock().unwrap())
+        .unwrap()
+        .seal()
+        .unwrap();
+    cluster.heal("bob", "alice");
+    // The upload is refused, and kept.
+    assert!(wait_until(|| cache.lock().unwrap().sealed().is_some()));
+    std::thread::sleep(std::time::Duration::from_secs(4));
+    assert!(!bob.synced("alice"));
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"hello");
+}
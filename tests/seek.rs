@@ -0,0 +1,57 @@
+/// SEEK_DATA and SEEK_HOLE, see `Vault::seek`.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn seek(vault: &VaultRef, file: Inode, offset: i64, hole: bool) -> VaultResult<Option<u64>> {
+    vault.lock().unwrap().seek(file, offset, hole)
+}
+
+#[test]
+fn data_and_holes() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let remote = cluster.node("bob").remote_of("alice");
+    for vault in [&alice.local, &remote] {
+        vault.lock().unwrap().open(file, OpenMode::R).unwrap();
+        assert_eq!(seek(vault, file, 0, false).unwrap(), Some(0));
+        assert_eq!(seek(vault, file, 3, false).unwrap(), Some(3));
+        // The end of a file is a hole.
+        assert_eq!(seek(vault, file, 0, true).unwrap(), Some(5));
+        assert_eq!(seek(vault, file, 5, false).unwrap(), None);
+        assert_eq!(seek(vault, file, 5, true).unwrap(), None);
+        assert!(matches!(
+            seek(vault, file, -1, false),
+            Err(VaultError::InvalidArgument(_))
+        ));
+        vault.lock().unwrap().close(file).unwrap();
+    }
+}
+
+#[test]
+fn holes_in_local_files() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    let file = create_file(local, ROOT, "sparse", b"");
+    let end = 1 << 20;
+    {
+        let mut vault = local.lock().unwrap();
+        vault.open(file, OpenMode::RW).unwrap();
+        vault.write(file, end, b"tail").unwrap();
+        vault.close(file).unwrap();
+        vault.open(file, OpenMode::R).unwrap();
+    }
+    // Whether the hole survives depends on the file system the store
+    // is on, but the tail is data either way.
+    let data = seek(local, file, 0, false).unwrap().unwrap();
+    assert!(data <= end as u64);
+    let hole = seek(local, file, 0, true).unwrap().unwrap();
+    assert!(hole == 0 || hole == end as u64 + 4);
+    assert_eq!(seek(local, file, end, false).unwrap(), Some(end as u64));
+    assert_eq!(seek(local, file, end, true).unwrap(), Some(end as u64 + 4));
+    local.lock().unwrap().close(file).unwrap();
+}
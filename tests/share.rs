@@ -0,0 +1,97 @@
+/// Share links, see src/share.rs.
+mod common;
+
+use common::*;
+use monovault::export;
+use monovault::share::{self, ShareKey, SharedVault};
+use monovault::types::*;
+use std::fs;
+
+const ROOT: Inode = 1;
+
+/// Return a vault that reads from `node` with share link `token`,
+/// like the "fetch" command.
+fn redeem(node: &Node, token: &str, store: &tempfile::TempDir) -> SharedVault {
+    let remote = remote(
+        &format!("http://{}", node.address),
+        &share::peek(token).unwrap().vault,
+        &runtime(),
+        store.path(),
+    );
+    SharedVault::new(remote, token)
+}
+
+#[test]
+fn tokens_are_checked() {
+    let key = ShareKey::new(b"key");
+    let token = key.mint_for("alice", 12, false, 60).unwrap();
+    let claim = key.verify(&token).unwrap();
+    assert_eq!((claim.vault.as_str(), claim.file), ("alice", 12));
+    assert_eq!(share::peek(&token).unwrap(), claim);
+    // Another key didn't mint it.
+    assert!(ShareKey::new(b"other").verify(&token).is_err());
+    // Changing the claim breaks the signature.
+    let forged = key.mint_for("alice", 13, false, 60).unwrap();
+    let (claim_hex, _) = forged.split_once('.').unwrap();
+    let (_, tag) = token.split_once('.').unwrap();
+    assert!(key.verify(&format!("{}.{}", claim_hex, tag)).is_err());
+    assert!(key.verify("garbage").is_err());
+    // Expired.
+    let token = key.mint_for("alice", 12, false, 0).unwrap();
+    assert!(key.verify(&token).is_err());
+}
+
+#[test]
+fn shared_file_can_be_fetched() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let file = create_file(&alice.local, ROOT, "note", b"for carol");
+    let other = create_file(&alice.local, ROOT, "secret", b"not for carol");
+    let token = alice.share_key.mint_for("alice", file, false, 60).unwrap();
+
+    let store = tempfile::tempdir().unwrap();
+    let mut vault = redeem(alice, &token, &store);
+    let dest = store.path().join("note");
+    export::export_one(&mut vault, file, &dest).unwrap();
+    assert_eq!(fs::read(&dest).unwrap(), b"for carol");
+    // The token grants nothing else.
+    assert!(vault.read(other, 0, 100).is_err());
+    assert!(vault.readdir(ROOT).is_err());
+}
+
+#[test]
+fn shared_directory_can_be_fetched() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let dir = alice
+        .local
+        .lock()
+        .unwrap()
+        .create(ROOT, "photos", VaultFileType::Directory)
+        .unwrap();
+    create_file(&alice.local, dir, "cat.jpg", b"meow");
+    let other = create_file(&alice.local, ROOT, "secret", b"not for carol");
+    let token = alice.share_key.mint_for("alice", dir, true, 60).unwrap();
+
+    let store = tempfile::tempdir().unwrap();
+    let mut vault = redeem(alice, &token, &store);
+    let dest = store.path().join("photos");
+    let stats = export::export_tree(&mut vault, dir, &dest).unwrap();
+    assert_eq!(stats.files, 1);
+    assert_eq!(fs::read(dest.join("cat.jpg")).unwrap(), b"meow");
+    assert!(vault.attr(other).is_err());
+}
+
+#[test]
+fn token_from_another_host_is_refused() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let file = create_file(&alice.local, ROOT, "note", b"for carol");
+    let token = ShareKey::new(b"forged")
+        .mint_for("alice", file, false, 60)
+        .unwrap();
+
+    let store = tempfile::tempdir().unwrap();
+    let mut vault = redeem(alice, &token, &store);
+    assert!(vault.read(file, 0, 100).is_err());
+}
@@ -0,0 +1,248 @@
+/// Exit statuses and JSON reports of startup failures. These run the
+/// binary, but fail before mounting, so they don't need FUSE.
+use monovault::bandwidth::BandwidthMeter;
+use monovault::config::CONFIG_VERSION;
+use monovault::remote_vault::RemoteVault;
+use monovault::store_lock::StoreLock;
+use monovault::types::*;
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::runtime::Builder;
+
+/// Run monovault with `config_path` and `args`, with JSON reports.
+fn run(config_path: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_monovault"))
+        .arg("-c")
+        .arg(config_path)
+        .arg("--json-errors")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Write a configuration in `dir` and return its path. The store is
+/// in `dir`, the mount point is `mount_point` under `dir`.
+fn write_config(dir: &TempDir, mount_point: &str, my_address: &str, share: bool) -> String {
+    let config = serde_json::json!({
+        "my_address": my_address,
+        "peers": {},
+        "mount_point": dir.path().join(mount_point),
+        "db_path": dir.path().join("db"),
+        "local_vault_name": "alice",
+        "caching": false,
+        "share_local_vault": share,
+        "allow_disconnected_delete": false,
+        "allow_disconnected_create": false,
+        "background_update_interval": 3
+    });
+    let path = dir.path().join("config.json");
+    fs::write(&path, config.to_string()).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+/// Check that `output` failed with `code` and reported `error`.
+fn assert_failed(output: &Output, code: i32, error: &str) {
+    assert_eq!(output.status.code(), Some(code));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line = stderr.lines().last().unwrap();
+    let report: serde_json::Value = serde_json::from_str(line).unwrap();
+    assert_eq!(report["error"], error);
+    assert_eq!(report["code"], code);
+    assert!(!report["message"].as_str().unwrap().is_empty());
+}
+
+#[test]
+fn unreadable_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run(&dir.path().join("missing.json"), &[]);
+    assert_failed(&output, 3, "config_unreadable");
+}
+
+#[test]
+fn invalid_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    fs::write(&path, "{\"peers\": ").unwrap();
+    assert_failed(&run(&path, &[]), 4, "config_invalid");
+}
+
+#[test]
+fn missing_mount_point() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_config(&dir, "nowhere", "127.0.0.1:0", false);
+    assert_failed(&run(Path::new(&path), &[]), 5, "mount_point");
+}
+
+#[test]
+fn address_in_use() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("mnt")).unwrap();
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = taken.local_addr().unwrap().to_string();
+    let path = write_config(&dir, "mnt", &address, true);
+    assert_failed(&run(Path::new(&path), &[]), 7, "bind");
+}
+
+/// Replace the peers in the configuration at `path` with `peers`.
+fn set_peers(path: &str, peers: serde_json::Value) {
+    let mut config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    config["peers"] = peers;
+    fs::write(path, config.to_string()).unwrap();
+}
+
+#[test]
+fn conflicting_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_config(&dir, "nowhere", "0.0.0.0:7771", false);
+    for peers in [
+        serde_json::json!({ "bob": "http://localhost:7771" }),
+        serde_json::json!({ "alice": "http://10.0.0.2:7771" }),
+        serde_json::json!({
+            "bob": "http://10.0.0.2:7771",
+            "carol": "http://10.0.0.2:7771/"
+        }),
+        // Fallback addresses count too.
+        serde_json::json!({
+            "bob": "http://10.0.0.2:7771",
+            "carol": {
+                "address": "http://10.0.0.3:7771",
+                "fallback_addresses": ["http://10.0.0.2:7771"]
+            }
+        }),
+    ] {
+        set_peers(&path, peers);
+        assert_failed(&run(Path::new(&path), &[]), 4, "config_invalid");
+    }
+    // Same host, different ports: a peer on this host is fine.
+    set_peers(&path, serde_json::json!({ "bob": "http://127.0.0.1:7772" }));
+    assert_failed(&run(Path::new(&path), &[]), 5, "mount_point");
+}
+
+#[test]
+fn store_in_use() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_config(&dir, "nowhere", "127.0.0.1:0", false);
+    fs::create_dir(dir.path().join("db")).unwrap();
+    let _lock = StoreLock::acquire(&dir.path().join("db"), false).unwrap();
+    let output = run(Path::new(&path), &["--server-only"]);
+    assert_failed(&output, 9, "store_locked");
+    // Our test process is alive, so there's nothing to take over.
+    let output = run(Path::new(&path), &["--server-only", "--takeover"]);
+    assert_failed(&output, 9, "store_locked");
+}
+
+#[test]
+fn paths_dont_depend_on_current_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let elsewhere = tempfile::tempdir().unwrap();
+    let config = serde_json::json!({
+        "my_address": "127.0.0.1:0",
+        "peers": {},
+        "mount_point": "mnt/",
+        "db_path": "db/",
+        "local_vault_name": "alice",
+        "caching": false,
+        "share_local_vault": false,
+        "allow_disconnected_delete": false,
+        "allow_disconnected_create": false,
+        "background_update_interval": 3
+    });
+    let path = dir.path().join("config.json");
+    fs::write(&path, config.to_string()).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_monovault"))
+        .current_dir(elsewhere.path())
+        .arg("-c")
+        .arg(&path)
+        .arg("retired")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(dir.path().join("db").is_dir());
+    assert!(!elsewhere.path().join("db").exists());
+}
+
+#[test]
+fn server_only_and_mount_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_config(&dir, "nowhere", "127.0.0.1:0", false);
+    let output = run(Path::new(&path), &["--server-only", "--mount-only"]);
+    assert_failed(&output, 2, "usage");
+}
+
+#[test]
+fn upgrade_config() {
+    let dir = tempfile::tempdir().unwrap();
+    // The mount point doesn't need to exist to print the upgrade.
+    let path = write_config(&dir, "nowhere", "127.0.0.1:0", false);
+    let output = run(Path::new(&path), &["upgrade-config"]);
+    assert!(output.status.success());
+    let upgraded: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(upgraded["config_version"], CONFIG_VERSION);
+    assert_eq!(upgraded["local_vault_name"], "alice");
+
+    let mut newer = upgraded;
+    newer["config_version"] = serde_json::json!(1000);
+    fs::write(&path, newer.to_string()).unwrap();
+    assert_failed(&run(Path::new(&path), &[]), 4, "config_invalid");
+    assert_failed(
+        &run(Path::new(&path), &["upgrade-config"]),
+        4,
+        "config_invalid",
+    );
+}
+
+#[test]
+fn plain_report_without_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_monovault"))
+        .arg("-c")
+        .arg(dir.path().join("missing.json"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("monovault: "));
+}
+
+#[test]
+fn server_only_serves_without_mounting() {
+    let dir = tempfile::tempdir().unwrap();
+    // Find a free port.
+    let address = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    // The mount point doesn't exist, server-only mode doesn't need it.
+    let path = write_config(&dir, "nowhere", &address, false);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_monovault"))
+        .arg("-c")
+        .arg(&path)
+        .arg("--server-only")
+        .spawn()
+        .unwrap();
+
+    let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
+    let meter = BandwidthMeter::new(&dir.path().join("client-bandwidth.json")).unwrap();
+    let mut remote =
+        RemoteVault::new(&format!("http://{}", address), "alice", runtime, meter).unwrap();
+    let mut served = false;
+    for _ in 0..50 {
+        if remote.readdir(1).is_ok() {
+            served = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(served);
+
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+}
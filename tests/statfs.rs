@@ -0,0 +1,27 @@
+/// Disk capacity and usage of vaults, see `Vault::statfs`.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+#[test]
+fn peers_report_the_owners_disk() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let local = alice.local.lock().unwrap().statfs().unwrap();
+    assert!(local.total > 0);
+    assert!(local.available <= local.free && local.free <= local.total);
+    assert!(local.free_files <= local.files);
+
+    // Free space changes all the time, the size doesn't.
+    let remote = bob.remote_of("alice").lock().unwrap().statfs().unwrap();
+    assert_eq!(remote.total, local.total);
+    let cache = bob.cache_of("alice");
+    assert_eq!(cache.lock().unwrap().statfs().unwrap().total, local.total);
+
+    // Without the peer, a cache reports the disk its copies are on.
+    cluster.cut("bob", "alice");
+    assert!(bob.remote_of("alice").lock().unwrap().statfs().is_err());
+    assert!(cache.lock().unwrap().statfs().unwrap().total > 0);
+}
@@ -0,0 +1,92 @@
+/// Totals of a vault kept in its database, see `Database::stats`.
+mod common;
+
+use common::*;
+use monovault::database::Database;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn stats_of(node: &Node) -> VaultStats {
+    Database::new(&node.store().join("db"), &node.name)
+        .unwrap()
+        .stats()
+        .unwrap()
+}
+
+#[test]
+fn totals_follow_changes() {
+    let cluster = Cluster::running(&["alice"]);
+    let alice = cluster.node("alice");
+    let local = &alice.local;
+    assert_eq!(stats_of(alice), VaultStats::default());
+
+    let dir = local
+        .lock()
+        .unwrap()
+        .create(ROOT, "dir", VaultFileType::Directory)
+        .unwrap();
+    let file = create_file(local, dir, "note", b"hello");
+    // A file with two names is counted once.
+    local.lock().unwrap().link(file, ROOT, "alias").unwrap();
+    let expected = VaultStats {
+        files: 1,
+        directories: 1,
+        size: 5,
+    };
+    assert_eq!(stats_of(alice), expected);
+
+    write_file(local, file, b"hello world").unwrap();
+    assert_eq!(stats_of(alice).size, 11);
+
+    local.lock().unwrap().unlink(file, ROOT, "alias").unwrap();
+    assert_eq!(stats_of(alice).files, 1);
+    local.lock().unwrap().delete(file).unwrap();
+    let expected = VaultStats {
+        files: 0,
+        directories: 1,
+        size: 0,
+    };
+    assert_eq!(stats_of(alice), expected);
+}
+
+#[test]
+fn uncounted_databases_are_rebuilt() {
+    let store = tempfile::tempdir().unwrap();
+    let mut database = Database::new(store.path(), "alice").unwrap();
+    assert!(!database.usage_stale());
+    database
+        .add_file(
+            ROOT,
+            2,
+            "dir",
+            VaultFileType::Directory,
+            0,
+            0,
+            (1, 0),
+            0o755,
+        )
+        .unwrap();
+    database
+        .add_file(2, 3, "note", VaultFileType::File, 0, 0, (1, 0), 0o644)
+        .unwrap();
+    database.set_size(3, 42).unwrap();
+    drop(database);
+
+    // Like a database from before the totals were kept.
+    let connection = rusqlite::Connection::open(store.path().join("alice.sqlite3")).unwrap();
+    connection.execute("drop table Stats", []).unwrap();
+    drop(connection);
+
+    let mut database = Database::new(store.path(), "alice").unwrap();
+    assert!(database.usage_stale());
+    database.rebuild_usage(|_, recorded| recorded).unwrap();
+    assert!(!database.usage_stale());
+    let expected = VaultStats {
+        files: 1,
+        directories: 1,
+        size: 42,
+    };
+    assert_eq!(database.stats().unwrap(), expected);
+    assert_eq!(database.usage(ROOT).unwrap().size, 42);
+}
@@ -0,0 +1,70 @@
+/// Stores that stop taking writes, see src/store_health.rs.
+mod common;
+
+use common::*;
+use monovault::store_health::{unwritable, StoreHealth};
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn os_error(code: i32) -> VaultError {
+    VaultError::IOError(std::io::Error::from_raw_os_error(code))
+}
+
+#[test]
+fn failures_that_break_the_store() {
+    assert!(unwritable(&os_error(libc::EROFS)));
+    assert!(unwritable(&os_error(libc::ENOSPC)));
+    assert!(!unwritable(&os_error(libc::ENOENT)));
+    // A bad sector fails one file, not the store.
+    assert!(!unwritable(&os_error(libc::EIO)));
+    assert!(!unwritable(&VaultError::FileNotExist(2)));
+
+    let store = tempfile::tempdir().unwrap();
+    let health = StoreHealth::new(store.path());
+    assert!(health.note(Err::<(), _>(os_error(libc::ENOENT))).is_err());
+    assert_eq!(health.broken(), None);
+    assert!(health.check("alice").is_ok());
+    assert!(health.note(Err::<(), _>(os_error(libc::EROFS))).is_err());
+    assert!(health.broken().is_some());
+    // Not probed again right away.
+    assert!(matches!(
+        health.check("alice"),
+        Err(VaultError::StoreUnwritable(name)) if name == "alice"
+    ));
+}
+
+#[test]
+fn broken_store_serves_reads() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let health = StoreHealth::new(alice.store());
+    unpack_to_local(&mut alice.local.lock().unwrap())
+        .unwrap()
+        .set_store_health(health.clone());
+    let _ = health.note(Err::<(), _>(os_error(libc::ENOSPC)));
+
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"hello");
+    assert!(matches!(
+        write_file(&alice.local, file, b"bye"),
+        Err(VaultError::StoreUnwritable(_))
+    ));
+    assert!(matches!(
+        alice
+            .local
+            .lock()
+            .unwrap()
+            .create(ROOT, "new", VaultFileType::File),
+        Err(VaultError::StoreUnwritable(_))
+    ));
+    // Peers get a failure they retry later.
+    let remote = cluster.node("bob").remote_of("alice");
+    assert_eq!(read_file(&remote, file).unwrap(), b"hello");
+    let result = remote.lock().unwrap().delete(file);
+    assert!(matches!(
+        result,
+        Err(VaultError::RemoteFailure(ErrorCategory::Transient, _))
+    ));
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"hello");
+}
@@ -0,0 +1,51 @@
+/// The lock on the store, see src/store_lock.rs.
+use monovault::store_lock::{StoreLock, LOCK_FILE};
+use monovault::types::*;
+use std::fs;
+use std::process::Command;
+
+/// Return the pid of a process that has exited.
+fn dead_pid() -> u32 {
+    let mut child = Command::new("true").spawn().unwrap();
+    child.wait().unwrap();
+    child.id()
+}
+
+#[test]
+fn one_owner_at_a_time() {
+    let dir = tempfile::tempdir().unwrap();
+    let lock = StoreLock::acquire(dir.path(), false).unwrap();
+    let me = std::process::id();
+    assert_eq!(
+        fs::read_to_string(dir.path().join(LOCK_FILE)).unwrap(),
+        me.to_string()
+    );
+    // A live owner keeps it, with or without takeover.
+    for takeover in [false, true] {
+        assert!(matches!(
+            StoreLock::acquire(dir.path(), takeover),
+            Err(VaultError::StoreLocked(Some(pid), true)) if pid == me
+        ));
+    }
+    drop(lock);
+    let _lock = StoreLock::acquire(dir.path(), false).unwrap();
+}
+
+#[test]
+fn takeover_from_dead_owner() {
+    let dir = tempfile::tempdir().unwrap();
+    // The lock is held, but the process it names is gone, like when
+    // a child of a crashed instance inherited it.
+    let _leftover = StoreLock::acquire(dir.path(), false).unwrap();
+    let dead = dead_pid();
+    fs::write(dir.path().join(LOCK_FILE), dead.to_string()).unwrap();
+    assert!(matches!(
+        StoreLock::acquire(dir.path(), false),
+        Err(VaultError::StoreLocked(Some(pid), false)) if pid == dead
+    ));
+    let _lock = StoreLock::acquire(dir.path(), true).unwrap();
+    assert!(matches!(
+        StoreLock::acquire(dir.path(), false),
+        Err(VaultError::StoreLocked(_, true))
+    ));
+}
@@ -0,0 +1,94 @@
+/// Setting access and modification times, like touch -t and tar do.
+mod common;
+
+use common::*;
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+fn times(vault: &VaultRef, file: Inode) -> (u64, u64) {
+    let info = vault.lock().unwrap().attr(file).unwrap();
+    (info.atime, info.mtime)
+}
+
+#[test]
+fn times_are_stored() {
+    let cluster = Cluster::running(&["alice"]);
+    let local = &cluster.node("alice").local;
+    let file = create_file(local, ROOT, "note", b"hello");
+    local
+        .lock()
+        .unwrap()
+        .set_times(file, Some(1000), Some(2000))
+        .unwrap();
+    assert_eq!(times(local, file), (1000, 2000));
+    // None leaves a time alone.
+    local
+        .lock()
+        .unwrap()
+        .set_times(file, None, Some(3000))
+        .unwrap();
+    assert_eq!(times(local, file), (1000, 3000));
+    local
+        .lock()
+        .unwrap()
+        .set_times(ROOT, Some(5), None)
+        .unwrap();
+    assert_eq!(times(local, ROOT).0, 5);
+    assert!(matches!(
+        local.lock().unwrap().set_times(12345, Some(0), Some(0)),
+        Err(VaultError::FileNotExist(_))
+    ));
+}
+
+#[test]
+fn times_across_peers() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+
+    let remote = bob.remote_of("alice");
+    remote
+        .lock()
+        .unwrap()
+        .set_times(file, Some(1000), Some(2000))
+        .unwrap();
+    assert_eq!(times(&alice.local, file), (1000, 2000));
+    remote
+        .lock()
+        .unwrap()
+        .set_times(file, Some(1500), None)
+        .unwrap();
+    assert_eq!(times(&alice.local, file), (1500, 2000));
+
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+    cache
+        .lock()
+        .unwrap()
+        .set_times(file, None, Some(4000))
+        .unwrap();
+    assert_eq!(times(&alice.local, file).1, 4000);
+    assert_eq!(times(&cache, file).1, 4000);
+}
+
+#[test]
+fn uploads_keep_the_modification_time() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let cache = bob.cache_of("alice");
+    // Like tar or rsync -a: write, close, then set the times, before
+    // the upload goes.
+    let file = create_file(&cache, ROOT, "note", b"hello");
+    cache
+        .lock()
+        .unwrap()
+        .set_times(file, None, Some(2000))
+        .unwrap();
+    assert!(bob.wait_synced("alice"));
+    assert_eq!(read_file(&alice.local, file).unwrap(), b"hello");
+    assert_eq!(times(&alice.local, file).1, 2000);
+    assert_eq!(times(&cache, file).1, 2000);
+}
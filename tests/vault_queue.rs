@@ -0,0 +1,120 @@
+/// Per-vault request queues, see src/vault_queue.rs.
+use monovault::types::*;
+use monovault::vault_queue::*;
+use std::sync::mpsc;
+use std::time::Duration;
+
+#[test]
+fn full_queue_fails_fast() {
+    let queue = VaultQueue::new("alice", 2);
+    let (unblock_tx, unblock_rx) = mpsc::channel::<()>();
+    let (done_tx, done_rx) = mpsc::channel();
+
+    // The first request holds up the vault's thread.
+    let done = done_tx.clone();
+    queue.reserve().unwrap().run(move || {
+        unblock_rx.recv().unwrap();
+        done.send(1).unwrap();
+    });
+    queue
+        .reserve()
+        .unwrap()
+        .run(move || done_tx.send(2).unwrap());
+    assert_eq!(queue.pending(), 2);
+    assert!(matches!(
+        queue.reserve(),
+        Err(VaultError::VaultBusy(name)) if name == "alice"
+    ));
+    assert!(matches!(
+        queue.wait_idle(Duration::from_millis(50)),
+        Err(VaultError::VaultBusy(_))
+    ));
+
+    unblock_tx.send(()).unwrap();
+    queue.wait_idle(Duration::from_secs(5)).unwrap();
+    // In the order they came.
+    assert_eq!(done_rx.try_iter().collect::<Vec<i32>>(), vec![1, 2]);
+    // A place given back unused frees it.
+    let slot = queue.reserve().unwrap();
+    assert_eq!(queue.pending(), 1);
+    drop(slot);
+    assert_eq!(queue.pending(), 0);
+}
+
+#[test]
+fn closing_waits_in_a_full_queue() {
+    let queue = VaultQueue::new("carol", 1);
+    let (unblock_tx, unblock_rx) = mpsc::channel::<()>();
+    let (done_tx, done_rx) = mpsc::channel();
+    let done = done_tx.clone();
+    queue.reserve().unwrap().run(move || {
+        unblock_rx.recv().unwrap();
+        done.send("write").unwrap();
+    });
+    assert!(queue.reserve().is_err());
+    // Release and flush take a place anyway, behind the write.
+    queue
+        .reserve_always()
+        .run(move || done_tx.send("release").unwrap());
+    assert_eq!(queue.pending(), 2);
+    unblock_tx.send(()).unwrap();
+    queue.wait_idle(Duration::from_secs(5)).unwrap();
+    assert_eq!(
+        done_rx.try_iter().collect::<Vec<&str>>(),
+        vec!["write", "release"]
+    );
+}
+
+#[test]
+fn panicking_request_keeps_the_thread() {
+    let queue = VaultQueue::new("bob", 0);
+    queue.reserve().unwrap().run(|| panic!("oops"));
+    let (tx, rx) = mpsc::channel();
+    queue.reserve().unwrap().run(move || tx.send(()).unwrap());
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    queue.wait_idle(Duration::from_secs(5)).unwrap();
+}
+
+#[test]
+fn same_reads_share_a_flight() {
+    let flights = ReadFlights::new();
+    let flight = flights.join((2, 0, 4096), "first").unwrap();
+    // Same bytes join it, others don't.
+    assert!(flights.join((2, 0, 4096), "second").is_none());
+    let other = flights.join((2, 4096, 4096), "other").unwrap();
+    assert!(flights.join((3, 0, 4096), "another file").is_some());
+    assert_eq!(flight.land(), vec!["first", "second"]);
+    assert_eq!(other.land(), vec!["other"]);
+
+    // A landed flight takes no one.
+    let flight = flights.join((2, 0, 4096), "third").unwrap();
+    assert_eq!(flight.land(), vec!["third"]);
+    // Neither does one dropped without landing, eg, by a panic.
+    let flight = flights.join((2, 0, 4096), "lost").unwrap();
+    drop(flight);
+    assert!(flights.join((2, 0, 4096), "fourth").is_some());
+}
+
+#[test]
+fn writes_close_flights() {
+    let flights = ReadFlights::new();
+    let before = flights.join((2, 0, 4096), "before").unwrap();
+    let other = flights.join((3, 0, 4096), "other file").unwrap();
+    assert!(flights.join((2, 0, 4096), "joined").is_none());
+    flights.close(2);
+    // Reads after the write don't join the read before it.
+    let after = flights.join((2, 0, 4096), "after").unwrap();
+    assert!(flights.join((3, 0, 4096), "joined other").is_none());
+    assert_eq!(before.land(), vec!["before", "joined"]);
+    assert!(flights.join((2, 0, 4096), "joined after").is_none());
+    assert_eq!(after.land(), vec!["after", "joined after"]);
+    assert_eq!(other.land(), vec!["other file", "joined other"]);
+    // A closed flight dropped without landing takes its replies.
+    let lost = flights.join((2, 0, 4096), "lost").unwrap();
+    flights.close(2);
+    drop(lost);
+    assert_eq!(
+        flights.join((2, 0, 4096), "new").unwrap().land(),
+        vec!["new"]
+    );
+}
@@ -0,0 +1,92 @@
+/// Versions bumped on close, see src/version.rs.
+use monovault::local_vault::RefCounter;
+use monovault::types::*;
+use monovault::version::{FileState, VersionTracker};
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+const FILE: Inode = 2;
+
+#[test]
+fn clean_dirty_and_back() {
+    let versions = VersionTracker::new();
+    assert_eq!(versions.state(FILE), FileState::Clean);
+    // Closing an unmodified file keeps its version.
+    assert_eq!(versions.commit(FILE, (1, 0)), None);
+
+    versions.write(FILE);
+    versions.write(FILE);
+    assert_eq!(versions.state(FILE), FileState::Dirty);
+    assert!(versions.dirty(FILE));
+    assert_eq!(versions.commit(FILE, (1, 0)), Some((1, 1)));
+    assert_eq!(versions.state(FILE), FileState::Clean);
+    assert!(!versions.dirty(FILE));
+    assert_eq!(versions.commit(FILE, (1, 1)), None);
+    // Other files aren't affected.
+    versions.write(FILE);
+    assert_eq!(versions.state(FILE + 1), FileState::Clean);
+}
+
+#[test]
+fn forks_bump_the_major_version() {
+    let versions = VersionTracker::new();
+    // Forked, then written.
+    versions.fork(FILE);
+    assert_eq!(versions.state(FILE), FileState::Forked);
+    assert!(!versions.dirty(FILE));
+    // A fork alone doesn't bump, and stays until the next change.
+    assert_eq!(versions.commit(FILE, (1, 3)), None);
+    assert_eq!(versions.state(FILE), FileState::Forked);
+    versions.write(FILE);
+    assert_eq!(versions.state(FILE), FileState::DirtyForked);
+    assert_eq!(versions.commit(FILE, (1, 3)), Some((2, 0)));
+    assert_eq!(versions.state(FILE), FileState::Clean);
+
+    // Written, then forked, like a peer copying the file while we
+    // write: the copy has the old content, ours must win.
+    versions.write(FILE);
+    versions.fork(FILE);
+    assert_eq!(versions.state(FILE), FileState::DirtyForked);
+    assert_eq!(versions.commit(FILE, (2, 0)), Some((3, 0)));
+
+    // Written after the bump, only the minor version moves.
+    versions.write(FILE);
+    assert_eq!(versions.commit(FILE, (3, 0)), Some((3, 1)));
+}
+
+#[test]
+fn concurrent_writers_bump_once() {
+    const WRITERS: usize = 8;
+    let versions = Arc::new(VersionTracker::new());
+    let refs = Arc::new(RefCounter::new());
+    // Everyone opens before anyone closes, the last close commits.
+    let opened = Arc::new(Barrier::new(WRITERS));
+    let threads: Vec<_> = (0..WRITERS)
+        .map(|idx| {
+            let versions = Arc::clone(&versions);
+            let refs = Arc::clone(&refs);
+            let opened = Arc::clone(&opened);
+            thread::spawn(move || {
+                refs.incf(FILE).unwrap();
+                opened.wait();
+                versions.write(FILE);
+                // A peer copies the file while one of us writes.
+                if idx == 0 {
+                    versions.fork(FILE);
+                }
+                if refs.decf(FILE).unwrap() == 0 {
+                    versions.commit(FILE, (1, 4))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect();
+    let bumps: Vec<FileVersion> = threads
+        .into_iter()
+        .filter_map(|thread| thread.join().unwrap())
+        .collect();
+    assert_eq!(bumps, [(2, 0)]);
+    assert_eq!(refs.count(FILE), 0);
+    assert_eq!(versions.state(FILE), FileState::Clean);
+}
@@ -0,0 +1,155 @@
+/// Extended attributes stored with files.
+mod common;
+
+use common::*;
+use monovault::database::{XATTR_COUNT_MAX, XATTR_NAME_MAX, XATTR_SIZE_MAX};
+use monovault::types::*;
+
+const ROOT: Inode = 1;
+
+#[test]
+fn set_list_and_remove() {
+    let cluster = Cluster::running(&["alice"]);
+    let mut vault = cluster.node("alice").local.lock().unwrap();
+    let file = vault.create(ROOT, "note", VaultFileType::File).unwrap();
+    vault.close(file).unwrap();
+    assert_eq!(vault.xattr(file, "user.tag").unwrap(), None);
+    assert!(vault.xattr_names(file).unwrap().is_empty());
+
+    vault.set_xattr(file, "user.tag", b"red").unwrap();
+    vault.set_xattr(file, "user.author", b"").unwrap();
+    vault.set_xattr(ROOT, "user.tag", b"root").unwrap();
+    assert_eq!(
+        vault.xattr(file, "user.tag").unwrap(),
+        Some(b"red".to_vec())
+    );
+    assert_eq!(vault.xattr(file, "user.author").unwrap(), Some(vec![]));
+    assert_eq!(
+        vault.xattr_names(file).unwrap(),
+        vec!["user.author".to_string(), "user.tag".to_string()]
+    );
+    vault.set_xattr(file, "user.tag", b"blue").unwrap();
+    assert_eq!(
+        vault.xattr(file, "user.tag").unwrap(),
+        Some(b"blue".to_vec())
+    );
+
+    vault.remove_xattr(file, "user.tag").unwrap();
+    assert_eq!(vault.xattr(file, "user.tag").unwrap(), None);
+    assert!(matches!(
+        vault.remove_xattr(file, "user.tag"),
+        Err(VaultError::XattrNotExist(_, _))
+    ));
+    assert!(matches!(
+        vault.set_xattr(12345, "user.tag", b""),
+        Err(VaultError::FileNotExist(_))
+    ));
+
+    // A new file under the same name starts without attributes.
+    vault.delete(file).unwrap();
+    let file = vault.create(ROOT, "note", VaultFileType::File).unwrap();
+    assert!(vault.xattr_names(file).unwrap().is_empty());
+    assert_eq!(
+        vault.xattr(ROOT, "user.tag").unwrap(),
+        Some(b"root".to_vec())
+    );
+}
+
+#[test]
+fn xattrs_across_peers() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+
+    let remote = bob.remote_of("alice");
+    remote
+        .lock()
+        .unwrap()
+        .set_xattr(file, "user.tag", b"red")
+        .unwrap();
+    assert_eq!(
+        alice.local.lock().unwrap().xattr(file, "user.tag").unwrap(),
+        Some(b"red".to_vec())
+    );
+    assert_eq!(
+        remote.lock().unwrap().xattr_names(file).unwrap(),
+        vec!["user.tag".to_string()]
+    );
+    assert!(matches!(
+        remote.lock().unwrap().remove_xattr(file, "user.other"),
+        Err(VaultError::XattrNotExist(_, _))
+    ));
+
+    let cache = bob.cache_of("alice");
+    find(&cache, ROOT, "note").unwrap();
+    cache
+        .lock()
+        .unwrap()
+        .set_xattr(file, "user.author", b"bob")
+        .unwrap();
+    assert_eq!(
+        alice
+            .local
+            .lock()
+            .unwrap()
+            .xattr(file, "user.author")
+            .unwrap(),
+        Some(b"bob".to_vec())
+    );
+    // Changes on the remote show up in the cache.
+    alice
+        .local
+        .lock()
+        .unwrap()
+        .remove_xattr(file, "user.tag")
+        .unwrap();
+    assert_eq!(cache.lock().unwrap().xattr(file, "user.tag").unwrap(), None);
+    assert_eq!(
+        cache.lock().unwrap().xattr_names(file).unwrap(),
+        vec!["user.author".to_string()]
+    );
+}
+
+#[test]
+fn limits_hold_for_peers_too() {
+    let cluster = Cluster::running(&["alice", "bob"]);
+    let alice = cluster.node("alice");
+    let bob = cluster.node("bob");
+    let file = create_file(&alice.local, ROOT, "note", b"hello");
+    let remote = bob.remote_of("alice");
+    let mut remote = remote.lock().unwrap();
+
+    let long_name = format!("user.{}", "x".repeat(XATTR_NAME_MAX));
+    assert!(matches!(
+        remote.set_xattr(file, &long_name, b""),
+        Err(VaultError::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        remote.set_xattr(file, "", b""),
+        Err(VaultError::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        remote.set_xattr(file, "user.big", &vec![0; XATTR_SIZE_MAX + 1]),
+        Err(VaultError::InvalidArgument(_))
+    ));
+    remote
+        .set_xattr(file, "user.big", &vec![0; XATTR_SIZE_MAX])
+        .unwrap();
+
+    for idx in 1..XATTR_COUNT_MAX {
+        remote
+            .set_xattr(file, &format!("user.{}", idx), b"")
+            .unwrap();
+    }
+    assert!(matches!(
+        remote.set_xattr(file, "user.one-more", b""),
+        Err(VaultError::InvalidArgument(_))
+    ));
+    // Changing one it has is fine.
+    remote.set_xattr(file, "user.1", b"changed").unwrap();
+    assert_eq!(
+        alice.local.lock().unwrap().xattr_names(file).unwrap().len(),
+        XATTR_COUNT_MAX
+    );
+}